@@ -1,99 +1,141 @@
+use crate::api;
+use crate::api::{configured_merger, filter_atoms, get_package_dependencies, merge_entry};
 use crate::atom::Atom;
-use crate::dep_check::DepChecker;
-use crate::depgraph::DepGraph;
-use crate::depgraph::{DepNode, DepType};
 use crate::doebuild::Ebuild;
-use crate::news::NewsManager;
+use crate::emerge_config::{EmergeOptions, SearchOptions};
+use crate::news::{NewsItem, NewsManager, NewsSystem};
 use crate::porttree::PortTree;
+use crate::search_index::{SearchIndex, SearchQuery};
 use crate::sets;
-use crate::sync::controller::sync_repository;
 use std::path::Path;
 
-pub async fn action_sync() -> i32 {
-    use tokio_stream::StreamExt;
-
-    println!("Syncing repositories...");
-
-    let mut porttree = PortTree::new("/");
-    porttree.scan_repositories();
-
-    if let Err(e) = porttree.load_sync_metadata().await {
-        eprintln!("Warning: Failed to load sync metadata: {}", e);
+/// Sync the named repositories, every auto-sync one when `repos` is empty; unless `force`,
+/// repositories synced within their sync-interval are skipped. `submodules` restricts git
+/// syncs to those --sync-submodule names
+pub async fn action_sync(config_root: &str, repos: &[String], jobs: usize, force: bool, submodules: &[String], json: bool) -> i32 {
+    let options = api::SyncOptions { config_root: config_root.to_string(), repos: repos.to_vec(), jobs, force, submodules: submodules.to_vec() };
+    if json {
+        // Only warnings, on stderr, so stdout is the report alone
+        let result = api::sync(&options, |event| {
+            if let api::SyncEvent::Warning(message) = event {
+                eprintln!("Warning: {}", message);
+            }
+        }).await;
+        return match result {
+            Ok(report) => {
+                crate::output::print_json(&report);
+                if report.success() { 0 } else { 1 }
+            }
+            Err(e) => {
+                crate::output::print_json(&error_json(&e));
+                1
+            }
+        };
     }
 
-    let repo_names: Vec<String> = porttree.repositories.keys().cloned().collect();
-    let total_count = repo_names.len();
+    println!("Syncing repositories...");
+    let report = match api::sync(&options, print_sync_event).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("!!! {}", e);
+            return 1;
+        }
+    };
 
-    if repo_names.is_empty() {
+    if report.repos.is_empty() {
         println!("No repositories to sync.");
         return 0;
     }
+    print_news_notice(&report.unread_news);
 
-    println!("Starting sync for {} repositories...\n", total_count);
-
-    let mut tasks = tokio::task::JoinSet::new();
+    println!();
+    let synced = report.repos.iter().filter(|repo| repo.succeeded()).count();
+    if !report.hooks_ok {
+        for failure in &report.hook_failures {
+            eprintln!("!!! {}", failure);
+        }
+        eprintln!("!!! One or more post-sync hooks failed.");
+        1
+    } else if report.success() {
+        println!("All repositories synced successfully.");
+        0
+    } else {
+        eprintln!("Synced {}/{} repositories.", synced, report.repos.len());
+        1
+    }
+}
 
-    for repo_name in repo_names {
-        let repo = porttree.repositories.get(&repo_name).unwrap().clone();
-        tasks.spawn(async move {
-            println!(">>> Starting sync: {}", repo_name);
-            let result = sync_repository(&repo).await;
-            (repo_name, result)
-        });
+/// An error for --json output: its message, and any package.* changes it needs
+fn error_json(error: &api::Error) -> serde_json::Value {
+    let mut value = serde_json::json!({ "error": error.to_string().trim() });
+    if let api::Error::Autounmask { changes, .. } = error {
+        value["autounmask"] = changes.changes().iter().map(|change| serde_json::json!({
+            "file": change.kind.config_name(),
+            "atom": change.atom,
+            "values": change.values,
+            "required_by": change.required_by,
+        })).collect();
     }
+    value
+}
 
-    let mut success_count = 0;
-    let mut completed_count = 0;
+/// Portage's notice of unread news, for each repository with `counts` unread items
+fn print_news_notice(counts: &[(String, usize)]) {
+    if counts.is_empty() {
+        return;
+    }
+    println!();
+    for (repo, count) in counts {
+        let items = if *count == 1 { "news item needs" } else { "news items need" };
+        println!(" * IMPORTANT: {} {} reading for repository '{}'.", count, items, repo);
+    }
+    println!(" * Use eselect news read to view new items.");
+}
 
-    while let Some(task_result) = tasks.join_next().await {
-        completed_count += 1;
-        
-        match task_result {
-            Ok((repo_name, sync_result)) => {
-                match sync_result {
-                    Ok(result) => {
-                        porttree.update_sync_metadata(&repo_name, true, None);
-
-                        match porttree.validate_repository_integrity(&repo_name).await {
-                            Ok(_) => {
-                                println!("✓ [{}/{}] Successfully synced {}: {}", 
-                                    completed_count, total_count, repo_name, result.message);
-                                success_count += 1;
-                            }
-                            Err(e) => {
-                                eprintln!("⚠ [{}/{}] Synced {} but validation failed: {}", 
-                                    completed_count, total_count, repo_name, e);
-                                success_count += 1;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        porttree.update_sync_metadata(&repo_name, false, Some(e.to_string()));
-                        eprintln!("✗ [{}/{}] Failed to sync {}: {}", 
-                            completed_count, total_count, repo_name, e);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("✗ [{}/{}] Task panicked: {}", completed_count, total_count, e);
+fn print_sync_event(event: api::SyncEvent) {
+    use api::{SyncEvent, SyncOutcome};
+    match event {
+        SyncEvent::Warning(message) => eprintln!("Warning: {}", message),
+        SyncEvent::Skipped(name, interval) => println!(
+            ">>> Skipping {}: synced within its sync-interval of {} (use --force to sync anyway)",
+            name, crate::porttree::format_interval(interval)),
+        SyncEvent::RefreshingKeys(name) => println!(">>> Refreshing OpenPGP keys for {}...", name),
+        SyncEvent::Starting(count) => println!("Starting sync for {} repositories...\n", count),
+        SyncEvent::Started(name) => println!(">>> Starting sync: {}", name),
+        SyncEvent::Finished(repo, done, total) => match &repo.outcome {
+            SyncOutcome::Synced { message, .. } => println!("✓ [{}/{}] Successfully synced {}: {}", done, total, repo.name, message),
+            SyncOutcome::Unvalidated { error, .. } => eprintln!("⚠ [{}/{}] Synced {} but validation failed: {}", done, total, repo.name, error),
+            SyncOutcome::Failed { error } => eprintln!("✗ [{}/{}] Failed to sync {}: {}", done, total, repo.name, error),
+        },
+        SyncEvent::CacheProgress { repo, done, total } => print_cache_progress(repo, done, total),
+        SyncEvent::CacheRegenerated(name, regen) => {
+            print_cache_pruned(regen);
+            if !regen.failures.is_empty() {
+                eprintln!("Warning: Failed to regenerate {} cache entries for {}", regen.failures.len(), name);
             }
         }
+        SyncEvent::UpdatingSearchIndex => println!(">>> Updating search index..."),
     }
+}
 
-    if let Err(e) = porttree.save_sync_metadata().await {
-        eprintln!("Warning: Failed to save sync metadata: {}", e);
+fn print_cache_progress(repo_name: &str, done: usize, total: usize) {
+    use std::io::Write;
+    if done == 0 {
+        println!(">>> Regenerating cache entries for {} ({} ebuilds)...", repo_name, total);
+        return;
     }
-
-    println!();
-    if success_count == total_count {
-        println!("All repositories synced successfully.");
-        0
-    } else {
-        eprintln!("Synced {}/{} repositories.", success_count, total_count);
-        1
+    print!("\r    {}/{}", done, total);
+    if done == total {
+        println!();
     }
+    let _ = std::io::stdout().flush();
 }
 
+fn print_cache_pruned(regen: &api::CacheRegen) {
+    if regen.pruned > 0 {
+        println!("    Removed {} stale cache entries", regen.pruned);
+    }
+}
 
 
 #[cfg(test)]
@@ -177,6 +219,14 @@ auto-sync = true
             auto_sync: true,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -223,6 +273,14 @@ auto-sync = true
             auto_sync: false,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -242,6 +300,14 @@ auto-sync = true
             auto_sync: true,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -258,6 +324,21 @@ auto-sync = true
         assert_eq!(porttree.needs_sync("no-auto"), false);
         assert_eq!(porttree.needs_sync("never-synced"), true);
         assert_eq!(porttree.needs_sync("nonexistent"), false);
+
+        // A sync-interval holds off a repository synced within it
+        porttree.parse_repos_conf("[throttled]\nlocation = /var/db/repos/throttled\nsync-interval = 6h\n");
+        assert_eq!(porttree.repositories["throttled"].sync_interval, Some(6 * 3600));
+        assert_eq!(porttree.needs_sync("throttled"), true);
+        porttree.update_sync_metadata("throttled", true, None);
+        assert_eq!(porttree.needs_sync("throttled"), false);
+        porttree.repositories.get_mut("throttled").unwrap().sync_metadata.last_sync = Some(1);
+        assert_eq!(porttree.needs_sync("throttled"), true);
+
+        assert_eq!(crate::porttree::parse_interval("90"), Some(90));
+        assert_eq!(crate::porttree::parse_interval("2d"), Some(2 * 86400));
+        assert_eq!(crate::porttree::parse_interval("soon"), None);
+        assert_eq!(crate::porttree::format_interval(2 * 86400), "2d");
+        assert_eq!(crate::porttree::format_interval(90), "90s");
     }
 
     #[tokio::test]
@@ -276,6 +357,67 @@ auto-sync = true
         assert!(validation_error.to_string().contains("Validation error"));
         assert!(timeout_error.to_string().contains("Timeout error"));
     }
+
+    #[tokio::test]
+    async fn test_format_system_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        fs::create_dir_all(temp_dir.path().join("etc/portage")).unwrap();
+        fs::write(temp_dir.path().join("etc/portage/make.conf"),
+            "CFLAGS=\"-O2 -pipe\"\nMAKEOPTS=\"-j8\"\nUSE=\"X -gnome\"\nUSE_EXPAND=\"L10N\"\nL10N=\"de en\"\nFEATURES=\"sandbox\"\n").unwrap();
+        for cpv in ["sys-devel/gcc-13.2.1", "sys-devel/gcc-14.1.0", "dev-lang/rust-1.80.0"] {
+            fs::create_dir_all(temp_dir.path().join("var/db/pkg").join(cpv)).unwrap();
+        }
+        let config = crate::config::Config::new(root).await.unwrap();
+        let mut porttree = PortTree::new(root);
+        porttree.parse_repos_conf("[gentoo]\nlocation = /var/db/repos/gentoo\nsync-type = git\nsync-uri = https://github.com/gentoo-mirror/gentoo.git\n");
+
+        let report = format_system_info(&config, "default/linux/amd64/23.0", &porttree, &crate::vartree::VarTree::new(root));
+        assert!(report.starts_with(&format!("emerge-rs {} (default/linux/amd64/23.0, gcc-14.1.0, glibc-unknown,", env!("CARGO_PKG_VERSION"))));
+        assert!(report.contains("sys-devel/gcc:            13.2.1, 14.1.0\n"));
+        assert!(report.contains("dev-lang/rust:            1.80.0\n"));
+        assert!(report.contains("\ngentoo\n    location: /var/db/repos/gentoo\n    sync-type: git\n"));
+        assert!(report.contains("CFLAGS=\"-O2 -pipe\"\n"));
+        assert!(report.contains("MAKEOPTS=\"-j8\"\n"));
+        assert!(report.contains("FEATURES=\"sandbox\"\n"));
+        assert!(report.contains("USE=\"X\" L10N=\"de en\"\n"));
+        assert!(report.contains("Unset:  ACCEPT_KEYWORDS,"));
+
+        let json = serde_json::to_value(system_info(&config, "default/linux/amd64/23.0", &porttree, &crate::vartree::VarTree::new(root))).unwrap();
+        assert_eq!(json["packages"]["sys-devel/gcc"], serde_json::json!(["13.2.1", "14.1.0"]));
+        assert_eq!(json["repositories"][0]["sync_type"], "git");
+        assert_eq!(json["variables"]["MAKEOPTS"], "-j8");
+        assert_eq!(json["use_expand"]["L10N"], serde_json::json!(["de", "en"]));
+    }
+
+    #[test]
+    fn test_use_changed() {
+        let list = |flags: &[&str]| flags.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        let map = |flags: &[(&str, bool)]| flags.iter().map(|(f, v)| (f.to_string(), *v)).collect::<std::collections::HashMap<_, _>>();
+        let built = crate::output::BuiltUse { iuse: list(&["ssl", "doc"]), enabled: list(&["ssl"]) };
+        let iuse = map(&[("ssl", false), ("doc", false)]);
+
+        assert!(!use_changed(&built, &iuse, &map(&[("ssl", true)])));
+        assert!(use_changed(&built, &iuse, &map(&[("ssl", false)])));
+        assert!(use_changed(&built, &map(&[("ssl", false)]), &map(&[("ssl", true)])));
+        assert!(use_changed(&built, &map(&[("ssl", false), ("gtk", false)]), &map(&[("ssl", true)])));
+    }
+
+    #[tokio::test]
+    async fn test_depclean_aborts_without_system() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().to_string_lossy().to_string();
+        for cpv in ["sys-libs/glibc-2.39", "app-shells/bash-5.2"] {
+            let dir = temp.path().join("var/db/pkg").join(cpv);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("SLOT"), "0\n").unwrap();
+        }
+        // With no profile, @system is unknown and nothing is removed
+        let options = EmergeOptions { root: root.clone(), nolock: true, ..EmergeOptions::default() };
+        assert_eq!(action_depclean(&[], &options, false).await, 1);
+        assert!(temp.path().join("var/db/pkg/sys-libs/glibc-2.39").is_dir());
+        assert!(temp.path().join("var/db/pkg/app-shells/bash-5.2").is_dir());
+    }
 }
 
 async fn check_reverse_dependencies(
@@ -329,158 +471,6 @@ async fn check_reverse_dependencies(
     Ok(blocked)
 }
 
-async fn get_package_dependencies(
-    atom: &crate::atom::Atom,
-    porttree: &PortTree,
-    with_bdeps: bool,
-) -> Result<(Vec<DepNode>, Vec<crate::dep::Atom>), Box<dyn std::error::Error + Send + Sync>> {
-    let cpv = format!("{}/{}", atom.cp(), atom.version.as_deref().unwrap_or("1.0"));
-
-    // First, try to get dependencies from binary package if available
-    let bintree = crate::bintree::BinTree::new("/");
-    if let Ok(Some(bin_info)) = bintree.parse_tbz2(&cpv).await {
-        let (deps, blockers) = parse_binary_dependencies(&bin_info, with_bdeps)?;
-        return Ok((deps, blockers));
-    }
-
-    // Fall back to ebuild-based dependency resolution
-    get_ebuild_dependencies(atom, porttree, with_bdeps).await
-}
-
-async fn get_ebuild_dependencies(
-    atom: &crate::atom::Atom,
-    porttree: &PortTree,
-    with_bdeps: bool,
-) -> Result<(Vec<DepNode>, Vec<crate::dep::Atom>), Box<dyn std::error::Error + Send + Sync>> {
-    // Use system portage tree
-    let cpv = format!("{}/{}", atom.cp(), atom.version.as_deref().unwrap_or("1.0"));
-    let ebuild_path = if let Some(path_str) = porttree.get_ebuild_path(&cpv) {
-        std::path::PathBuf::from(path_str)
-    } else {
-        return Err(format!("Ebuild not found for {}", atom.cp()).into());
-    };
-
-    if !ebuild_path.exists() {
-        return Err(format!("Ebuild file not found: {}", ebuild_path.display()).into());
-    }
-
-    let content = tokio::fs::read_to_string(&ebuild_path).await?;
-    let metadata = Ebuild::parse_metadata_with_use(&content, &std::collections::HashMap::new())?;
-
-    let mut deps = Vec::new();
-    let mut blockers = Vec::new();
-
-    // Process dependencies and separate blockers
-    // Only include build dependencies if with_bdeps is true
-    if with_bdeps {
-        for dep_atom in &metadata.depend {
-            if dep_atom.blocker.is_some() {
-                blockers.push(dep_atom.clone());
-            } else {
-                deps.push(create_dep_node(dep_atom, DepType::Build));
-            }
-        }
-    }
-
-    for dep_atom in &metadata.rdepend {
-        if dep_atom.blocker.is_some() {
-            blockers.push(dep_atom.clone());
-        } else {
-            deps.push(create_dep_node(dep_atom, DepType::Runtime));
-        }
-    }
-
-    for dep_atom in &metadata.pdepend {
-        if dep_atom.blocker.is_some() {
-            blockers.push(dep_atom.clone());
-        } else {
-            deps.push(create_dep_node(dep_atom, DepType::Post));
-        }
-    }
-
-    Ok((deps, blockers))
-}
-
-fn parse_binary_dependencies(
-    bin_info: &crate::bintree::BinPkgInfo,
-    with_bdeps: bool,
-) -> Result<(Vec<DepNode>, Vec<crate::dep::Atom>), Box<dyn std::error::Error + Send + Sync>> {
-    let mut deps = Vec::new();
-    let mut blockers = Vec::new();
-
-    // Binary packages typically only have runtime dependencies
-    // Check for DEPEND and RDEPEND in the XPAK metadata
-    // Only include build dependencies if with_bdeps is true
-    if with_bdeps {
-        if let Some(depend_str) = bin_info.metadata.get("DEPEND") {
-            if !depend_str.trim().is_empty() {
-                let depend_atoms = crate::dep::parse_dependencies(depend_str)?;
-                for dep_atom in depend_atoms {
-                    if dep_atom.blocker.is_some() {
-                        blockers.push(dep_atom);
-                    } else {
-                        deps.push(create_dep_node(&dep_atom, DepType::Build));
-                    }
-                }
-            }
-        }
-    }
-
-    if let Some(rdepend_str) = bin_info.metadata.get("RDEPEND") {
-        if !rdepend_str.trim().is_empty() {
-            let rdepend_atoms = crate::dep::parse_dependencies(rdepend_str)?;
-            for dep_atom in rdepend_atoms {
-                if dep_atom.blocker.is_some() {
-                    blockers.push(dep_atom);
-                } else {
-                    deps.push(create_dep_node(&dep_atom, DepType::Runtime));
-                }
-            }
-        }
-    }
-
-    Ok((deps, blockers))
-}
-
-fn create_dep_node(dep_atom: &crate::dep::Atom, dep_type: DepType) -> DepNode {
-    let atom = crate::atom::Atom::new(&dep_atom.cpv).unwrap_or_else(|_| crate::atom::Atom {
-        category: dep_atom
-            .cp()
-            .split('/')
-            .next()
-            .unwrap_or("unknown")
-            .to_string(),
-        package: dep_atom
-            .cp()
-            .split('/')
-            .nth(1)
-            .unwrap_or(&dep_atom.cp())
-            .to_string(),
-        version: None,
-        op: crate::atom::Operator::None,
-        slot: dep_atom.slot.clone(),
-        subslot: dep_atom.sub_slot.clone(),
-        repo: dep_atom.repo.clone(),
-        use_deps: dep_atom.use_deps.clone(),
-        blocker: dep_atom.blocker.clone(),
-    });
-
-    let blockers = if dep_atom.blocker.is_some() {
-        vec![atom.clone()] // This dependency is a blocker, so this node blocks the atom
-    } else {
-        vec![]
-    };
-
-    DepNode {
-        atom,
-        dep_type,
-        blockers,
-        use_conditional: None, // TODO: handle USE conditionals
-        slot: dep_atom.slot.clone(),
-        subslot: dep_atom.sub_slot.clone(),
-    }
-}
-
 pub async fn action_install(
     packages: &[String],
     pretend: bool,
@@ -488,7 +478,7 @@ pub async fn action_install(
     resume: bool,
     jobs: usize,
 ) -> i32 {
-    action_install_with_root(packages, pretend, ask, resume, jobs, "/", false).await
+    action_install_with_root(packages, pretend, ask, resume, jobs, "/", false, false).await
 }
 
 /// Handle set-related commands
@@ -520,7 +510,8 @@ pub async fn action_set(command: Option<&str>, set_name: Option<&str>) -> i32 {
         }
         Some("show") => {
             if let Some(name) = set_name {
-                match set_manager.resolve_set(name).await {
+                let name = name.trim_start_matches('@');
+                match set_manager.resolve_expression(name).await {
                     Ok(packages) => {
                         println!("Contents of @{} set:", name);
                         for pkg in packages {
@@ -551,6 +542,7 @@ pub async fn action_set(command: Option<&str>, set_name: Option<&str>) -> i32 {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn action_install_with_root(
     packages: &[String],
     pretend: bool,
@@ -559,343 +551,730 @@ pub async fn action_install_with_root(
     jobs: usize,
     root: &str,
     with_bdeps: bool,
+    oneshot: bool,
 ) -> i32 {
-    println!("Installing packages: {:?}", packages);
+    let options = EmergeOptions {
+        pretend,
+        ask,
+        resume,
+        jobs,
+        root: root.to_string(),
+        with_bdeps,
+        oneshot,
+        ..EmergeOptions::default()
+    };
+    action_install_with_options(packages, &options).await
+}
 
-    let pretend_mode = pretend;
-    if pretend {
+pub async fn action_install_with_options(packages: &[String], options: &EmergeOptions) -> i32 {
+    if options.resume {
+        return action_resume(options).await;
+    }
+    let (pretend, ask, jobs) = (options.pretend, options.ask, options.jobs);
+    let (root, config_root, oneshot) = (options.root.as_str(), options.config_root.as_str(), options.oneshot);
+    // --json --pretend: the plan as JSON on stdout, nothing else
+    let json = options.json && pretend;
+    if !json {
+        println!("Installing packages: {:?}", packages);
+    }
+
+    if pretend && !json {
         println!("Pretend mode: simulating installation of {:?}", packages);
     }
 
-    // Resolve sets (@world, @system, etc.) to individual packages
-    let resolved_packages = match sets::resolve_targets(packages, "/").await {
-        Ok(pkgs) => pkgs,
+    let api::Resolution { report, mut plan, depgraph, target_keys, config, mut porttree, merger } = match api::resolve(packages, options).await {
+        Ok(resolution) => resolution,
+        Err(e) if json => {
+            crate::output::print_json(&error_json(&e));
+            return 1;
+        }
+        Err(api::Error::Autounmask { explanation, changes }) => {
+            eprintln!("{}", explanation);
+            if options.autounmask_write {
+                match changes.write(config_root) {
+                    Ok(written) => {
+                        for path in written {
+                            println!(">>> Wrote autounmask changes to {}", path.display());
+                        }
+                        println!("Autounmask changes successfully written. Re-run emerge to continue.");
+                    }
+                    Err(e) => eprintln!("Failed to write autounmask changes: {}", e),
+                }
+            } else {
+                eprintln!("Use --autounmask-write to write changes to config files.");
+            }
+            return 1;
+        }
         Err(e) => {
-            eprintln!("Failed to resolve package sets: {}", e);
+            eprintln!("{}", e);
             return 1;
         }
     };
 
-    // Parse atoms from resolved packages
-    let mut atoms = Vec::new();
-    for pkg in &resolved_packages {
-        match Atom::new(pkg) {
-            Ok(atom) => atoms.push(atom),
-            Err(e) => {
-                eprintln!("Invalid atom '{}': {}", pkg, e);
+    // Show what would be merged; --tree nests dependencies under what pulled them in
+    let order: Vec<(String, usize)> = if options.tree {
+        depgraph.tree_order(&target_keys).into_iter().filter(|(key, _)| plan.entries.contains_key(key)).collect()
+    } else {
+        plan.merge_list.iter().map(|(key, _)| (key.clone(), 0)).collect()
+    };
+    let display: Vec<crate::output::MergeEntry> = order.into_iter()
+        .filter_map(|(key, depth)| plan.entries.remove(&key).map(|entry| crate::output::MergeEntry { depth, ..entry }))
+        .collect();
+    if json {
+        crate::output::print_json(&plan_json(&display, &plan.blockers, &report));
+        return 0;
+    }
+    print_resolution_report(&report);
+
+    // Check license acceptance for all packages to be installed
+    let cpv_packages = plan.cpvs();
+    let license_manager = api::license_manager(config_root, &config, &porttree);
+    match license_manager.check_and_prompt_licenses(&cpv_packages, &mut porttree).await {
+        Ok(accepted) => {
+            if !accepted {
+                eprintln!("License acceptance required. Aborting installation.");
                 return 1;
             }
         }
+        Err(e) => {
+            eprintln!("License check failed: {}", e);
+            return 1;
+        }
     }
 
-    // Create dependency graph with USE flags
-    let config = match crate::config::Config::new(root).await {
-        Ok(c) => c,
-        Err(_) => crate::config::Config {
-            root: root.to_string(),
-            make_conf: std::collections::HashMap::new(),
-            profile_settings: crate::profile::ProfileSettings::default(),
-            use_flags: vec![],
-            accept_keywords: vec![],
-            features: vec![],
-            package_use: std::collections::HashMap::new(),
-            package_keywords: std::collections::HashMap::new(),
-            package_mask: std::collections::HashSet::new(),
-            package_unmask: std::collections::HashSet::new(),
-            sets_conf: std::collections::HashMap::new(),
-            binhost: vec![],
-            binhost_mirrors: vec![],
-        },
-    };
-    let use_flags = config.get_use_flags_map();
-    let mut depgraph = DepGraph::with_use_flags(use_flags);
+    if pretend || ask {
+        print!("{}", crate::output::format_merge_list(&display, &plan.blockers, options.tree, options.verbose));
+        if options.changelog {
+            print_changelogs(&display, &porttree);
+        }
+    }
+    if ask && !pretend {
+        let prompt = if options.fetchonly {
+            "Would you like to fetch the source files for these packages?"
+        } else {
+            "Would you like to merge these packages?"
+        };
+        match crate::output::userquery(prompt, &mut std::io::stdin().lock()) {
+            Some(true) => {}
+            Some(false) => {
+                println!("\nQuitting.\n");
+                return 130;
+            }
+            None => return 130,
+        }
+    }
 
-    // Initialize portage tree for finding ebuilds
-    let mut porttree = PortTree::new(root);
-    porttree.scan_repositories();
+    if options.fetchonly {
+        return fetch_merge_list(&plan.merge_list, &porttree, &config, options, pretend).await;
+    }
 
-    for atom in &atoms {
-        let (deps, dep_blockers) = match get_package_dependencies(&atom, &porttree, with_bdeps).await {
-            Ok((deps, blockers)) => {
-                println!("Found {} dependencies and {} blockers for {}", deps.len(), blockers.len(), atom.cp());
-                (deps, blockers)
-            }
-            Err(e) => {
-                eprintln!(
-                    "Warning: Failed to get dependencies for {}: {}",
-                    atom.cp(),
-                    e
-                );
-                // Continue with empty dependencies rather than failing completely
-                (vec![], vec![])
-            }
+    // pkg_pretend checks run before anything is built
+    let global_use = config.get_use_flags_map();
+    let portdir = porttree.repository_locations().into_iter().next().unwrap_or_else(|| std::path::PathBuf::from(crate::merge::DEFAULT_PORTDIR));
+    for (_, cpv) in &plan.merge_list {
+        let Some(ebuild_path) = porttree.get_ebuild_path(cpv) else {
+            continue;
         };
-
-        // Convert dep::Atom blockers to atom::Atom
-        let blockers: Vec<crate::atom::Atom> = dep_blockers.into_iter().map(|dep_atom| {
-            crate::atom::Atom::new(&dep_atom.cpv).unwrap_or_else(|_| crate::atom::Atom {
-                category: dep_atom.cp().split('/').next().unwrap_or("unknown").to_string(),
-                package: dep_atom.cp().split('/').nth(1).unwrap_or(&dep_atom.cp()).to_string(),
-                version: None,
-                op: crate::atom::Operator::None,
-                slot: dep_atom.slot,
-                subslot: dep_atom.sub_slot,
-                repo: dep_atom.repo,
-                use_deps: dep_atom.use_deps,
-                blocker: dep_atom.blocker,
-            })
-        }).collect();
-
-        if let Err(e) = depgraph.add_node_with_blockers(&atom.cp(), deps, blockers) {
-            eprintln!("Failed to add {} to dependency graph: {}", atom.cp(), e);
+        let ebuild_content = std::fs::read_to_string(&ebuild_path).unwrap_or_default();
+        let iuse = crate::autounmask::parse_iuse_defaults(&ebuild_content);
+        let flags = crate::autounmask::effective_use(&iuse, &global_use, &config.package_use_for(cpv));
+        let phase = crate::doebuild::BuildPhase::Pretend;
+        if let Err(e) = crate::doebuild::run_pkg_phase(Path::new(&ebuild_path), &portdir, phase, flags, config.features_for(cpv), config.build_env_for(cpv)).await {
+            eprintln!("!!! pkg_pretend failed for {}: {}", cpv, e);
             return 1;
         }
     }
 
-    // Resolve dependencies
-    match depgraph.resolve(&atoms.iter().map(|a| a.cp()).collect::<Vec<_>>()) {
-        Ok(result) => {
-            if !result.blocked.is_empty() {
-                eprintln!("Blocked packages: {:?}", result.blocked);
-                return 1;
-            }
-            if !result.circular.is_empty() {
-                eprintln!("Circular dependencies: {:?}", result.circular);
-                return 1;
-            }
-
-            println!("Resolved packages to install: {:?}", result.resolved);
-
-            // Check if dependencies are satisfied
-            let mut checker = DepChecker::new(root);
-            match checker.check_dependencies(&atoms).await {
-                Ok(check_result) => {
-                    if !check_result.missing.is_empty() {
-                        eprintln!("Missing dependencies: {:?}", check_result.missing);
-                        return 1;
-                    }
-                    if !check_result.conflicts.is_empty() {
-                        eprintln!("Conflicts: {:?}", check_result.conflicts);
-                        return 1;
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Dependency check failed: {}", e);
-                    return 1;
-                }
-            }
-
-            // Convert resolved CP packages to CPV format
-            let mut cpv_packages = Vec::new();
-            let merger = crate::merge::Merger::with_binhost(root, config.binhost.clone(), config.binhost_mirrors.clone());
-
-            for cp in &result.resolved {
-                match merger.find_best_version_with_porttree(cp, Some(&porttree)).await {
-                    Ok(Some(cpv)) => {
-                        cpv_packages.push(cpv);
-                    }
-                    Ok(None) => {
-                        eprintln!("No version found for package: {}", cp);
-                        return 1;
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to find version for {}: {}", cp, e);
-                        return 1;
-                    }
-                }
-            }
-
-            // Check for masked packages
-            let mask_manager = crate::mask::MaskManager::new("/", config.accept_keywords.clone());
-            for cpv in &cpv_packages {
-                match Atom::new(cpv) {
-                    Ok(atom) => {
-                        match mask_manager.is_masked(&atom).await {
-                            Ok(Some(reason)) => {
-                                eprintln!("Package {} is masked: {}", cpv, reason);
-                                return 1;
-                            }
-                            Ok(None) => {
-                                // Package is not masked, continue
-                            }
-                            Err(e) => {
-                                eprintln!("Mask check failed for {}: {}", cpv, e);
-                                return 1;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Invalid package atom '{}': {}", cpv, e);
-                        return 1;
-                    }
-                }
-            }
-
-            // Check license acceptance for all packages to be installed
-            let license_manager = crate::license::LicenseManager::new("/");
-            match license_manager.check_and_prompt_licenses(&cpv_packages, &mut porttree).await {
-                Ok(accepted) => {
-                    if !accepted {
-                        eprintln!("License acceptance required. Aborting installation.");
-                        return 1;
-                    }
-                }
-                Err(e) => {
-                    eprintln!("License check failed: {}", e);
-                    return 1;
-                }
-            }
+    // Display unread news items
+    let news_manager = NewsManager::new(root).with_repositories(&porttree);
+    let arch = config.get_var("ARCH").cloned().unwrap_or_default();
+    let updated = if pretend { Ok(0) } else { news_manager.update(&NewsSystem::new(root, config_root, &arch)) };
+    match updated.and_then(|_| news_manager.unread_counts()) {
+        Ok(counts) => print_news_notice(&counts),
+        Err(e) => {
+            eprintln!("Warning: Failed to check for news items: {}", e);
+        }
+    }
 
-            // Display unread news items
-            let news_manager = NewsManager::new("/");
-            match news_manager.get_unread_news() {
-                Ok(unread_news) => {
-                    if !unread_news.is_empty() {
-                        println!(
-                            "\n * IMPORTANT: {} news items need reading for repository '{}'.",
-                            unread_news.len(),
-                            "gentoo"
-                        );
-                        println!(" * Use eselect news to read news items.\n");
-
-                        // In a full implementation, we might want to display news content here
-                        // For now, just notify about unread news
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Warning: Failed to check for news items: {}", e);
+    // Actual installation logic
+    if pretend {
+        println!("Pretend mode: would install {} packages.", cpv_packages.len());
+        return 0;
+    }
+    let mut scheduler = crate::scheduler::Scheduler::from_depgraph(&depgraph, &plan.merge_list, jobs)
+        .with_load_average(options.load_average)
+        .with_keep_going(options.keep_going);
+    for cpv in &plan.interactive {
+        scheduler.set_interactive(cpv);
+    }
+    let mergelist = plan.merge_list.iter().map(|(_, cpv)| {
+        let shown = display.iter().find(|entry| entry.cpv == *cpv);
+        crate::merge::ResumeEntry {
+            pkg_type: match shown.map(|entry| entry.pkg_type) {
+                Some(crate::output::PkgType::Binary) => "binary".to_string(),
+                _ => "ebuild".to_string(),
+            },
+            cpv: cpv.clone(),
+            use_flags: shown.map(|entry| entry.use_flags.clone()).unwrap_or_default(),
+            deps: scheduler.jobs().iter()
+                .find(|job| job.cpv == *cpv)
+                .map(|job| job.deps.iter().map(|&dep| scheduler.jobs()[dep].cpv.clone()).collect())
+                .unwrap_or_default(),
+            uninstall: plan.blocker_uninstalls.get(cpv).cloned().unwrap_or_default(),
+            interactive: plan.interactive.contains(cpv),
+        }
+    }).collect();
+    let favorites = if oneshot || options.onlydeps { Vec::new() } else { packages.to_vec() };
+    let merger = merger.with_blocker_uninstalls(plan.blocker_uninstalls);
+    let merged = merger.install_scheduled(scheduler, false, crate::merge::ResumeState::new(mergelist, favorites)).await;
+    print!("{}", merger.elog_summary());
+    match merged {
+        Ok(merge_result) => {
+            report_pending_config_updates(&merger.config_protect);
+            if merge_result.failed.is_empty() {
+                println!("Installation completed successfully.");
+                if !oneshot && !options.onlydeps {
+                    record_world_atoms(packages, root);
                 }
-            }
-
-            if ask {
-                println!("Would you like to proceed? (y/N)");
-                // Placeholder: in real implementation, read user input
-                println!("Proceeding with installation...");
-            }
-
-            // Actual installation logic
-            if pretend_mode {
-                println!("Pretend mode: would install {} packages.", cpv_packages.len());
                 0
             } else {
-                match merger.install_packages_parallel(&cpv_packages, false, resume, jobs).await {
-                    Ok(merge_result) => {
-                        if merge_result.failed.is_empty() {
-                            println!("Installation completed successfully.");
-                            0
-                        } else {
-                            eprintln!("Failed to install packages: {:?}", merge_result.failed);
-                            1
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Installation failed: {}", e);
-                        1
-                    }
-                }
+                eprintln!("Failed to install packages: {:?}", merge_result.failed);
+                1
             }
         }
         Err(e) => {
-            eprintln!("Dependency resolution failed: {}", e);
+            eprintln!("Installation failed: {}", e);
             1
         }
     }
 }
 
-pub fn action_news(command: Option<&str>, news_name: Option<&str>) -> i32 {
-    let news_manager = NewsManager::new("/");
+/// --changelog: what changed in each upgrade of the merge list since the installed version
+fn print_changelogs(display: &[crate::output::MergeEntry], porttree: &PortTree) {
+    for entry in display.iter().filter(|entry| entry.is_upgrade()) {
+        let (Some(installed), Some(cp), Some(version)) = (
+            entry.installed.as_deref(),
+            crate::versions::cpv_getkey(&entry.cpv),
+            crate::versions::cpv_getversion(&entry.cpv),
+        ) else {
+            continue;
+        };
+        let Some(ebuild_path) = porttree.get_ebuild_path(&entry.cpv) else {
+            continue;
+        };
+        let Some(repo) = Path::new(&ebuild_path).ancestors().nth(3) else {
+            continue;
+        };
+        let changes = crate::changelog::changes(repo, &cp, installed, &version);
+        if changes.is_empty() {
+            continue;
+        }
+        println!("\n>>> Changes in {} since {}:", entry.cpv, installed);
+        for line in changes {
+            println!("{}", line);
+        }
+    }
+}
 
-    match command {
-        Some("list") | None => {
-            // List all news items
-            match news_manager.get_news_items() {
-                Ok(news_items) => {
-                    if news_items.is_empty() {
-                        println!("No news items found.");
-                        return 0;
-                    }
+/// The merge plan for --json --pretend
+fn plan_json(display: &[crate::output::MergeEntry], blockers: &[crate::output::BlockerEntry], report: &api::ResolutionReport) -> serde_json::Value {
+    serde_json::json!({
+        "packages": display.iter().map(|entry| entry.to_json()).collect::<Vec<_>>(),
+        "blockers": blockers.iter().map(|blocker| blocker.to_json()).collect::<Vec<_>>(),
+        "any_of_choices": report.any_of_choices.iter()
+            .map(|(parent, providers)| serde_json::json!({ "parent": parent, "providers": providers }))
+            .collect::<Vec<_>>(),
+        "download_size": display.iter().filter_map(|entry| entry.download_size).sum::<u64>(),
+        "warnings": report.warnings,
+    })
+}
 
-                    println!("Available news items:");
-                    println!("{} {:<15} {:<20}", "N", "News", "Posted");
-                    println!("{}", "-".repeat(40));
+/// What the resolver found, as emerge reports it before the merge list
+fn print_resolution_report(report: &api::ResolutionReport) {
+    for warning in &report.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    for (cp, deps, blockers) in &report.dependencies {
+        println!("Found {} dependencies and {} blockers for {}", deps, blockers, cp);
+    }
+    for (parent, providers) in &report.any_of_choices {
+        println!("Selected {} for a || ( ) dependency of {}", providers.join(" "), parent);
+    }
+    println!("Resolved packages to install: {:?}", report.resolved);
+}
 
-                    for item in news_items {
-                        let read_status = if news_manager.is_read(&item.name).unwrap_or(false) {
-                            " "
-                        } else {
-                            "N"
-                        };
-                        println!("{} {:<15} {:<20}", read_status, item.name, item.posted);
-                    }
-                    0
-                }
-                Err(e) => {
-                    eprintln!("Failed to get news items: {}", e);
-                    1
-                }
-            }
+/// --resume: continue the merge list an interrupted or failed run left in the mtimedb,
+/// with --skipfirst dropping its first remaining package
+async fn action_resume(options: &EmergeOptions) -> i32 {
+    let root = options.root.as_str();
+    let config = match crate::config::Config::new(&options.config_root).await.map(|c| c.with_roots(root, &options.sysroot)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            return 1;
         }
-        Some("read") => {
-            if let Some(name) = news_name {
-                // Read specific news item
-                match news_manager.get_news_items() {
-                    Ok(news_items) => {
-                        if let Some(item) = news_items.into_iter().find(|i| i.name == name) {
-                            println!("Title: {}", item.title);
-                            println!("Author: {}", item.author);
-                            println!("Posted: {}", item.posted);
-                            if let Some(revised) = item.revised {
-                                println!("Revised: {}", revised);
-                            }
-                            println!();
-                            println!("{}", item.content);
+    };
+    let mut policy = options.binpkg_policy;
+    let mut merger = configured_merger(root, &config, options);
+    let mut state = match merger.load_resume_state() {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            println!("emerge: It seems we have nothing to resume...");
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("!!! {}", e);
+            return 1;
+        }
+    };
 
-                            // Mark as read
-                            if let Err(e) = news_manager.mark_as_read(name) {
-                                eprintln!("Warning: Failed to mark news as read: {}", e);
-                            }
-                            0
-                        } else {
-                            eprintln!("News item '{}' not found.", name);
-                            1
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to get news items: {}", e);
-                        1
-                    }
+    if options.skipfirst
+        && let Some(skipped) = state.skip_first()
+    {
+        println!(">>> Skipping {} (--skipfirst)", skipped);
+    }
+
+    // Packages that can no longer be merged are dropped, with whatever waits for them
+    let mut porttree = PortTree::new(&options.config_root);
+    porttree.scan_repositories();
+    let global_use = config.get_use_flags_map();
+    let remaining: Vec<crate::merge::ResumeEntry> = state.remaining().into_iter().cloned().collect();
+    let mut display = Vec::new();
+    for entry in remaining {
+        if !state.mergelist.contains(&entry) {
+            continue;
+        }
+        let ebuild_content = porttree.get_ebuild_path(&entry.cpv)
+            .and_then(|path| std::fs::read_to_string(path).ok());
+        let cp = crate::versions::cpv_getkey(&entry.cpv).unwrap_or_default();
+        let available = if entry.pkg_type == "binary" {
+            merger.binary_candidates(&cp).await.is_ok_and(|found| found.iter().any(|c| c.cpv == entry.cpv))
+        } else {
+            ebuild_content.is_some()
+        };
+        if !available {
+            eprintln!("!!! {} is no longer available; dropping it from the resume list", entry.cpv);
+            for dependent in state.drop_entry(&entry.cpv) {
+                eprintln!("!!! Dropping {}, which depends on it", dependent);
+            }
+            continue;
+        }
+
+        let ebuild_content = ebuild_content.unwrap_or_default();
+        let iuse = crate::autounmask::parse_iuse_defaults(&ebuild_content);
+        let current = crate::autounmask::effective_use(&iuse, &global_use, &config.package_use_for(&entry.cpv));
+        let saved: std::collections::HashMap<String, bool> = entry.use_flags.iter().cloned().collect();
+        if entry.pkg_type == "ebuild" && saved.iter().any(|(flag, enabled)| current.get(flag).is_some_and(|now| now != enabled)) {
+            eprintln!(" * USE of {} changed since the merge list was created; it is built with the current settings", entry.cpv);
+        }
+        let mut shown = merge_entry(&entry.cpv, &ebuild_content, &iuse, &saved, &merger.vartree);
+        shown.use_flags = entry.use_flags.clone();
+        shown.use_expand = config.use_expand();
+        if entry.pkg_type == "binary" {
+            shown.pkg_type = crate::output::PkgType::Binary;
+            policy.usepkg = true;
+        }
+        display.push(shown);
+    }
+    if display.is_empty() {
+        println!("emerge: It seems we have nothing to resume...");
+        if let Err(e) = merger.clear_resume_state() {
+            eprintln!("Warning: {}", e);
+        }
+        return 0;
+    }
+
+    if options.pretend || options.ask {
+        print!("{}", crate::output::format_merge_list(&display, &[], false, options.verbose));
+    }
+    if options.pretend {
+        return 0;
+    }
+    if options.ask {
+        match crate::output::userquery("Would you like to resume merging these packages?", &mut std::io::stdin().lock()) {
+            Some(true) => {}
+            Some(false) => {
+                println!("\nQuitting.\n");
+                return 130;
+            }
+            None => return 130,
+        }
+    }
+
+    println!("Resuming previous operation: {}", state.operation_id);
+    let uninstalls = state.mergelist.iter()
+        .filter(|entry| !entry.uninstall.is_empty())
+        .map(|entry| (entry.cpv.clone(), entry.uninstall.clone()))
+        .collect();
+    merger = merger.with_binpkg_policy(policy).with_blocker_uninstalls(uninstalls);
+    let favorites = state.favorites.clone();
+    let scheduler = state.scheduler(options.jobs)
+        .with_load_average(options.load_average)
+        .with_keep_going(options.keep_going);
+    let merged = merger.install_scheduled(scheduler, false, state).await;
+    print!("{}", merger.elog_summary());
+    match merged {
+        Ok(merge_result) if merge_result.failed.is_empty() => {
+            report_pending_config_updates(&merger.config_protect);
+            println!("Installation completed successfully.");
+            record_world_atoms(&favorites, root);
+            0
+        }
+        Ok(merge_result) => {
+            eprintln!("Failed to install packages: {:?}", merge_result.failed);
+            eprintln!("Run emerge --resume to continue, or emerge --resume --skipfirst to skip {}", merge_result.failed[0]);
+            1
+        }
+        Err(e) => {
+            eprintln!("Installation failed: {}", e);
+            1
+        }
+    }
+}
+
+/// Download the distfiles of every package in the merge list (--fetchonly);
+/// with --fetch-all-uri, those of USE-conditional SRC_URI branches too
+async fn fetch_merge_list(
+    merge_list: &[(String, String)],
+    porttree: &PortTree,
+    config: &crate::config::Config,
+    options: &EmergeOptions,
+    pretend: bool,
+) -> i32 {
+    let distdir = config.get_var("DISTDIR").map(std::path::PathBuf::from).unwrap_or_else(crate::doebuild::default_distdir);
+    let mirrors = std::env::var("GENTOO_MIRRORS").ok()
+        .or_else(|| config.get_var("GENTOO_MIRRORS").cloned())
+        .unwrap_or_default();
+    let manager = std::sync::Arc::new(
+        crate::fetch::FetchManager::new(&distdir, mirrors.split_whitespace().map(|m| m.to_string()).collect())
+            .with_thirdpartymirrors(&porttree.repository_locations())
+            .with_jobs(options.fetch_jobs)
+            .with_distlocks(!options.nolock && config.features.iter().any(|f| f == "distlocks")),
+    );
+
+    let global_use = config.get_use_flags_map();
+    let mut requests: Vec<crate::fetch::FetchRequest> = Vec::new();
+    let mut restricted = Vec::new();
+    for (_, cpv) in merge_list {
+        let Some(ebuild_path) = porttree.get_ebuild_path(cpv) else {
+            continue;
+        };
+        let ebuild_content = std::fs::read_to_string(&ebuild_path).unwrap_or_default();
+        let iuse = crate::autounmask::parse_iuse_defaults(&ebuild_content);
+        let flags = crate::autounmask::effective_use(&iuse, &global_use, &config.package_use_for(cpv));
+        let ebuild = match Ebuild::from_path_with_use(Path::new(&ebuild_path), &flags) {
+            Ok(ebuild) => ebuild,
+            Err(e) => {
+                eprintln!("!!! {}: {}", cpv, e);
+                return 1;
+            }
+        };
+        let package_dir = Path::new(&ebuild_path).parent().unwrap_or(Path::new("."));
+        let dist_entries = crate::manifest::load_dist_entries(package_dir).unwrap_or_default();
+        let fetch_restricted = ebuild.metadata.restrict.iter().any(|r| r == "fetch");
+
+        let uris = if options.fetch_all_uri {
+            Ebuild::all_src_uris(&ebuild_content)
+        } else {
+            ebuild.metadata.src_uri.clone()
+        };
+        for uri in &uris {
+            let mut request = crate::doebuild::fetch_request(&ebuild, uri);
+            if requests.iter().any(|r| r.filename == request.filename) {
+                continue;
+            }
+            if fetch_restricted && !distdir.join(&request.filename).exists() {
+                restricted.push((cpv.clone(), request.filename));
+                continue;
+            }
+            request.manifest = dist_entries.get(&request.filename).cloned();
+            requests.push(request);
+        }
+    }
+
+    if pretend {
+        for request in &requests {
+            println!("{}", manager.candidate_urls(request).join(" "));
+        }
+        return 0;
+    }
+
+    println!(">>> Fetching {} distfiles ({} at a time)", requests.len(), manager.jobs);
+    let mut failed: Vec<String> = Vec::new();
+    for (filename, result) in manager.fetch_all(requests).await {
+        if let Err(e) = result {
+            eprintln!("!!! {}", e);
+            failed.push(filename);
+        }
+    }
+    for (cpv, filename) in &restricted {
+        eprintln!("!!! {} is fetch-restricted for {}; place it in {} manually", filename, cpv, distdir.display());
+        failed.push(filename.clone());
+    }
+
+    if failed.is_empty() {
+        println!(">>> All distfiles fetched.");
+        0
+    } else {
+        eprintln!("!!! Failed to fetch {} distfile(s): {}", failed.len(), failed.join(", "));
+        1
+    }
+}
+
+/// Add explicitly requested atoms to the world file after a successful merge
+fn record_world_atoms(packages: &[String], root: &str) {
+    let world = crate::world::WorldManager::new(root);
+    match world.add_requested(packages) {
+        Ok(added) => {
+            for atom in added {
+                println!(">>> Recording {} in \"world\" favorites file...", atom);
+            }
+        }
+        Err(e) => eprintln!("Warning: Failed to update world file: {}", e),
+    }
+
+    // Sets go to world_sets instead, when their definition allows it
+    let set_manager = sets::PackageSetManager::new(root);
+    let candidates: Vec<String> = packages.iter()
+        .filter(|target| target.strip_prefix('@').is_some_and(|name| set_manager.is_world_candidate(name)))
+        .cloned()
+        .collect();
+    match world.add_sets(&candidates) {
+        Ok(added) => {
+            for set in added {
+                println!(">>> Recording {} in \"world_sets\" favorites file...", set);
+            }
+        }
+        Err(e) => eprintln!("Warning: Failed to update world_sets file: {}", e),
+    }
+}
+
+/// --newuse: a flag of the installed version changed state, or IUSE gained or lost flags
+fn use_changed(
+    built: &crate::output::BuiltUse,
+    iuse: &std::collections::HashMap<String, bool>,
+    flags: &std::collections::HashMap<String, bool>,
+) -> bool {
+    iuse.len() != built.iuse.len()
+        || iuse.keys().any(|flag| {
+            !built.iuse.contains(flag) || flags.get(flag).copied().unwrap_or(false) != built.enabled.contains(flag)
+        })
+}
+
+/// Tell the user about protected config files that still need merging
+fn report_pending_config_updates(config_protect: &crate::configprotect::ConfigProtect) {
+    let pending = config_protect.pending_updates();
+    if pending.is_empty() {
+        return;
+    }
+    println!("\n * IMPORTANT: {} config files in '{}' need updating.", pending.len(), config_protect.protect.join(" "));
+    println!(" * Run 'emerge --config-update' or 'emerge --dispatch-conf' to merge them.\n");
+}
+
+/// Interactively review pending ._cfg updates in CONFIG_PROTECT paths
+pub async fn action_dispatch_conf(root: &str) -> i32 {
+    let config_protect = match load_config_protect(root).await {
+        Some(cp) => cp,
+        None => return 1,
+    };
+    let pending = config_protect.pending_updates();
+    if pending.is_empty() {
+        println!("No pending configuration updates.");
+        return 0;
+    }
+
+    let mut failed = false;
+    for update in &pending {
+        loop {
+            println!(">>> {} ({} pending)", update.target.display(), update.updates.len());
+            match update.unified_diff() {
+                Ok(diff) => print!("{}", diff),
+                Err(e) => eprintln!("{}", e),
+            }
+            println!("\n>> (u)se new, (z)ap new, (s)kip, (q)uit: ");
+
+            let mut input = String::new();
+            if std::io::stdin().read_line(&mut input).is_err() {
+                return 1;
+            }
+            let result = match input.trim() {
+                "u" => crate::configprotect::ConfigProtect::accept(&update.updates).map(|_| println!("Replaced {}", update.target.display())),
+                "z" => crate::configprotect::ConfigProtect::discard(&update.updates).map(|_| println!("Kept {}", update.target.display())),
+                "s" => Ok(()),
+                "q" => return if failed { 1 } else { 0 },
+                _ => continue,
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                failed = true;
+            }
+            break;
+        }
+    }
+    if failed { 1 } else { 0 }
+}
+
+/// Merge trivial ._cfg updates automatically and list the ones needing review
+pub async fn action_config_update(root: &str, pretend: bool) -> i32 {
+    let config_protect = match load_config_protect(root).await {
+        Some(cp) => cp,
+        None => return 1,
+    };
+
+    if pretend {
+        let pending = config_protect.pending_updates();
+        for update in &pending {
+            let kind = if update.is_trivial().unwrap_or(false) { "auto-merge" } else { "review" };
+            println!("[{}] {}", kind, update.target.display());
+        }
+        println!("{} configuration files have pending updates.", pending.len());
+        return 0;
+    }
+
+    match config_protect.auto_merge() {
+        Ok(report) => {
+            for target in &report.merged {
+                println!(">>> Automerged {}", target.display());
+            }
+            for update in &report.remaining {
+                println!(">>> {} needs review", update.target.display());
+                if let Ok(diff) = update.unified_diff() {
+                    print!("{}", diff);
                 }
-            } else {
-                eprintln!("Please specify a news item name to read.");
-                1
             }
+            if !report.remaining.is_empty() {
+                println!("\n * {} config files need manual merging; run 'emerge --dispatch-conf'.", report.remaining.len());
+            }
+            0
         }
-        Some("purge") => {
-            // Mark all news as read
-            match news_manager.get_news_items() {
-                Ok(news_items) => {
-                    for item in news_items {
-                        if let Err(e) = news_manager.mark_as_read(&item.name) {
-                            eprintln!("Warning: Failed to mark '{}' as read: {}", item.name, e);
-                        }
+        Err(e) => {
+            eprintln!("Config update failed: {}", e);
+            1
+        }
+    }
+}
+
+async fn load_config_protect(root: &str) -> Option<crate::configprotect::ConfigProtect> {
+    match crate::config::Config::new(root).await {
+        Ok(config) => Some(crate::configprotect::ConfigProtect::from_config(&config.with_roots(root, "/"))),
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            None
+        }
+    }
+}
+
+/// eselect news: `command` is list, read, unread, purge or count, `targets` item numbers
+/// as listed or names, or "new" or "all"
+pub fn action_news(command: &str, targets: &[String], options: &EmergeOptions) -> i32 {
+    let mut porttree = PortTree::new(&options.config_root);
+    porttree.scan_repositories();
+    let news_manager = NewsManager::new(&options.root).with_repositories(&porttree);
+    let items = match news_manager.get_relevant_news() {
+        Ok(items) => items,
+        Err(e) => {
+            eprintln!("Failed to get news items: {}", e);
+            return 1;
+        }
+    };
+    let is_read = |item: &NewsItem| news_manager.is_read(&item.repo, &item.name).unwrap_or(false);
+
+    match command {
+        "list" => {
+            if items.is_empty() {
+                println!("No news items found.");
+                return 0;
+            }
+            println!("News items:");
+            for (number, item) in items.iter().enumerate() {
+                let status = if is_read(item) { " " } else { "N" };
+                println!("  [{}] {} {}  {}", number + 1, status, item.posted, item.title);
+            }
+            0
+        }
+        "count" => {
+            let all = targets.first().is_some_and(|target| target == "all");
+            println!("{}", items.iter().filter(|item| all || !is_read(item)).count());
+            0
+        }
+        "read" | "unread" => {
+            let default = if command == "read" { "new" } else { "all" };
+            let selected = match select_news(&items, targets, default, is_read) {
+                Ok(selected) => selected,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            };
+            if command == "read" && selected.is_empty() {
+                println!("No unread news items.");
+            }
+            for item in selected {
+                let result = if command == "read" {
+                    println!("{}", item.name);
+                    println!("  Title     {}", item.title);
+                    println!("  Author    {}", item.author);
+                    println!("  Posted    {}", item.posted);
+                    if let Some(revised) = &item.revised {
+                        println!("  Revised   {}", revised);
                     }
-                    println!("All news items marked as read.");
-                    0
+                    println!();
+                    println!("{}", item.content);
+                    println!();
+                    news_manager.mark_as_read(&item.repo, &item.name)
+                } else {
+                    news_manager.mark_as_unread(&item.repo, &item.name)
+                };
+                if let Err(e) = result {
+                    eprintln!("Warning: Failed to mark '{}' as {}: {}", item.name, command, e);
                 }
-                Err(e) => {
-                    eprintln!("Failed to get news items: {}", e);
-                    1
+            }
+            0
+        }
+        "purge" => {
+            for repo in news_manager.repositories() {
+                if let Err(e) = news_manager.purge(repo) {
+                    eprintln!("Failed to purge read news of {}: {}", repo, e);
+                    return 1;
                 }
             }
+            println!("Read news items purged.");
+            0
         }
-        Some(cmd) => {
+        cmd => {
             eprintln!("Unknown news command: {}", cmd);
-            eprintln!("Available commands: list, read <name>, purge");
+            eprintln!("Available commands: list, read, unread, purge, count");
             1
         }
     }
 }
 
+/// The news items `targets` name: numbers as listed, names, "new" or "all"; `default`
+/// when there are none
+fn select_news<'a>(items: &'a [NewsItem], targets: &[String], default: &str, is_read: impl Fn(&NewsItem) -> bool) -> Result<Vec<&'a NewsItem>, String> {
+    let targets: Vec<&str> = if targets.is_empty() { vec![default] } else { targets.iter().map(|t| t.as_str()).collect() };
+    let mut selected: Vec<&NewsItem> = Vec::new();
+    for target in targets {
+        let matching: Vec<&NewsItem> = match target {
+            "all" => items.iter().collect(),
+            "new" => items.iter().filter(|item| !is_read(item)).collect(),
+            _ => match target.parse::<usize>() {
+                Ok(number) => items.get(number.wrapping_sub(1)).into_iter().collect(),
+                Err(_) => items.iter().filter(|item| item.name == target).collect(),
+            },
+        };
+        if matching.is_empty() && target != "all" && target != "new" {
+            return Err(format!("!!! Unknown news item: {}", target));
+        }
+        for item in matching {
+            if !selected.iter().any(|known| std::ptr::eq(*known, item)) {
+                selected.push(item);
+            }
+        }
+    }
+    Ok(selected)
+}
+
 pub async fn action_profile(command: Option<&str>, profile_name: Option<&str>) -> i32 {
     let profile_manager = crate::profile::ProfileManager::new("/");
 
@@ -1088,11 +1467,15 @@ pub async fn action_profile(command: Option<&str>, profile_name: Option<&str>) -
     }
 }
 
-pub async fn action_upgrade(packages: &[String], pretend: bool, ask: bool, deep: bool, newuse: bool, with_bdeps: bool) -> i32 {
+pub async fn action_upgrade(packages: &[String], options: &EmergeOptions) -> i32 {
+    let (pretend, ask, deep, with_bdeps) = (options.pretend, options.ask, options.deep, options.with_bdeps);
+    let emptytree = options.emptytree;
+    // With no targets, update @world
+    let packages = if packages.is_empty() { &["@world".to_string()][..] } else { packages };
     println!("Upgrading packages: {:?}", packages);
 
     // Resolve sets (@world, @system, etc.) to individual packages
-    let resolved_packages = match sets::resolve_targets(packages, "/").await {
+    let resolved_packages = match sets::resolve_targets(packages, &options.root).await {
         Ok(pkgs) => pkgs,
         Err(e) => {
             eprintln!("Failed to resolve package sets: {}", e);
@@ -1101,103 +1484,125 @@ pub async fn action_upgrade(packages: &[String], pretend: bool, ask: bool, deep:
     };
 
     // Initialize components
-    let mut porttree = PortTree::new("/");
+    let mut porttree = PortTree::new(&options.config_root);
     porttree.scan_repositories();
-    let merger = crate::merge::Merger::new("/");
-    let vartree = crate::vartree::VarTree::new("/");
+    let vartree = crate::vartree::VarTree::new(&options.root);
 
     // Initialize configuration and masking
-    let config = match crate::config::Config::new("/").await {
+    let config = match crate::config::Config::new(&options.config_root).await.map(|c| c.with_roots(&options.root, &options.sysroot)) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Failed to load configuration: {}", e);
             return 1;
         }
     };
-    let mask_manager = crate::mask::MaskManager::new("/", config.accept_keywords.clone());
-
-    // Get packages to upgrade
-    let mut packages_to_upgrade = if resolved_packages.is_empty() {
-        // Upgrade all installed packages
-        match get_all_upgradable_packages(&vartree, &merger, &porttree, &mask_manager).await {
-            Ok(pkgs) => pkgs,
-            Err(e) => {
-                eprintln!("Failed to get upgradable packages: {}", e);
-                return 1;
-            }
+    let with_test_deps = options.with_test_deps || config.features.iter().any(|f| f == "test");
+    let (exclude, reinstall) = match filter_atoms(options) {
+        Ok(atoms) => atoms,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
         }
-    } else {
-        // Upgrade specific packages
-        match get_specific_upgradable_packages(&resolved_packages, &vartree, &merger, &porttree, &mask_manager).await {
-            Ok(pkgs) => pkgs,
-            Err(e) => {
-                eprintln!("Failed to get upgradable packages: {}", e);
-                return 1;
+    };
+    let keyword_filter = crate::keywords::KeywordFilter::from_config(&config);
+    let merger = crate::merge::Merger::new(&options.root).with_config_roots(&options.config_root, &options.sysroot).with_keyword_filter(keyword_filter.clone());
+    let mask_manager = crate::mask::MaskManager::new(&options.config_root, config.accept_keywords.clone())
+        .with_keyword_filter(keyword_filter)
+        .with_repositories(porttree.repository_locations());
+
+    // Walk the targets, and with --deep their whole installed dependency tree, for
+    // packages with a newer version (or, with --newuse, changed USE flags)
+    let global_use = config.get_use_flags_map();
+    let mut packages_to_upgrade = Vec::new();
+    let mut visited = std::collections::HashSet::new();
+    let mut queue: std::collections::VecDeque<String> = resolved_packages.iter()
+        .filter_map(|pkg| Atom::new(pkg).ok().map(|atom| atom.cp()))
+        .collect();
+    while let Some(cp) = queue.pop_front() {
+        if !visited.insert(cp.clone()) {
+            continue;
+        }
+        let mut installed = vartree.installed_versions(&cp);
+        installed.sort_by(|a, b| {
+            let (a, b) = (crate::versions::cpv_getversion(a).unwrap_or_default(), crate::versions::cpv_getversion(b).unwrap_or_default());
+            crate::versions::vercmp(&a, &b).unwrap_or(0).cmp(&0)
+        });
+        let Some(installed_cpv) = installed.pop() else {
+            continue;
+        };
+        let slot = vartree.slot(&installed_cpv);
+        if crate::depgraph::atoms_match_package(&exclude, &cp, slot.as_deref()) {
+            continue;
+        }
+        let reinstall = crate::depgraph::atoms_match_package(&reinstall, &cp, slot.as_deref());
+        let Ok(Some(best)) = merger.find_best_version_with_porttree(&cp, Some(&porttree)).await else {
+            continue;
+        };
+        if let Ok(atom) = Atom::new(&format!("={}", best))
+            && let Ok(Some(reason)) = mask_manager.is_masked(&atom).await
+        {
+            eprintln!("Available version {} is masked: {}", best, reason);
+            continue;
+        }
+
+        let (Some(installed_version), Some(available_version)) = (crate::versions::cpv_getversion(&installed_cpv), crate::versions::cpv_getversion(&best)) else {
+            continue;
+        };
+        let ebuild_content = porttree.get_ebuild_path(&best)
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        if crate::versions::vercmp(&installed_version, &available_version).is_some_and(|cmp| cmp < 0) || emptytree || reinstall {
+            packages_to_upgrade.push((cp.clone(), installed_version, available_version));
+        } else if options.newuse && let Some(iuse) = vartree.iuse(&installed_cpv) {
+            let iuse_defaults = crate::autounmask::parse_iuse_defaults(&ebuild_content);
+            let flags = crate::autounmask::effective_use(&iuse_defaults, &global_use, &config.package_use_for(&best));
+            let built = crate::output::BuiltUse { iuse, enabled: vartree.use_flags(&installed_cpv).unwrap_or_default() };
+            if use_changed(&built, &iuse_defaults, &flags) {
+                packages_to_upgrade.push((cp.clone(), installed_version.clone(), installed_version));
             }
         }
-    };
 
-    // If deep flag is set, also check dependencies for updates
-    if deep && !packages_to_upgrade.is_empty() {
-        let mut additional_packages = Vec::new();
+        if (deep || emptytree)
+            && let Ok(atom) = Atom::new(&best)
+            && let Ok((deps, _, any_of)) = get_package_dependencies(&atom, &porttree, with_bdeps, with_test_deps).await
+        {
+            queue.extend(deps.into_iter().map(|dep| dep.atom.cp()));
+            // Of || ( ) groups, the providers that are installed are kept up to date
+            queue.extend(any_of.into_iter().flatten().flatten()
+                .map(|dep| dep.atom.cp())
+                .filter(|cp| !vartree.installed_versions(cp).is_empty()));
+        }
+    }
 
-        // Get all CP from packages to upgrade
-        let upgrade_cps: std::collections::HashSet<String> = packages_to_upgrade.iter()
-            .map(|(cp, _, _)| cp.clone())
+    // Installed packages outside @world and its dependencies are left for --depclean
+    if deep && packages.iter().any(|p| p == "@world") {
+        let unneeded: Vec<String> = vartree.list_packages().into_iter()
+            .filter(|cpv| crate::versions::cpv_getkey(cpv).is_some_and(|cp| !visited.contains(&cp)))
             .collect();
-
-        for (cp, _, _) in &packages_to_upgrade {
-            // Get dependencies of this package
-            if let Ok(Some(cpv)) = merger.find_best_version_with_porttree(cp, Some(&porttree)).await {
-                if let Ok((deps, _)) = get_package_dependencies(&crate::atom::Atom::new(&cpv).unwrap(), &porttree, with_bdeps).await {
-                    for dep_node in deps {
-                        let dep_cp = dep_node.atom.cp();
-                        // Skip if already in upgrade list
-                        if upgrade_cps.contains(&dep_cp) {
-                            continue;
-                        }
-
-                        // Check if this dependency has an update available
-                        if let Ok(Some(dep_cpv)) = merger.find_best_version_with_porttree(&dep_cp, Some(&porttree)).await {
-                            if let Some(last_dash) = dep_cpv.rfind('-') {
-                                let available_version = &dep_cpv[last_dash + 1..];
-
-                                // Check if installed
-                                let installed = vartree.get_all_installed().await.unwrap_or_default();
-                                let mut found_installed = None;
-                                // Convert dep_cp from category/package to category-package for matching
-                                let dep_cp_hyphenated = dep_cp.replace('/', "-");
-                                for installed_cpv in &installed {
-                                    if installed_cpv.starts_with(&format!("{}-", dep_cp_hyphenated)) {
-                                        if let Some(inst_last_dash) = installed_cpv.rfind('-') {
-                                            found_installed = Some(installed_cpv[inst_last_dash + 1..].to_string());
-                                        }
-                                        break;
-                                    }
-                                }
-
-                                if let Some(installed_version) = found_installed {
-                                    // Compare versions
-                                    if let Some(cmp) = crate::versions::vercmp(&installed_version, available_version) {
-                                        if cmp < 0 {
-                                            // Dependency has update available
-                                            additional_packages.push((
-                                                dep_cp,
-                                                installed_version,
-                                                available_version.to_string(),
-                                            ));
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        if !unneeded.is_empty() {
+            println!(" * {} installed packages are not required by @world; review them with emerge --depclean", unneeded.len());
         }
+    }
 
-        // Add additional packages to upgrade list
-        packages_to_upgrade.extend(additional_packages);
+    // Slot-operator rebuilds: installed packages built against a subslot that is going away
+    let mut new_slots = std::collections::HashMap::new();
+    for (cp, _, available_version) in &packages_to_upgrade {
+        if let Some(metadata) = porttree.get_metadata(&format!("{}-{}", cp, available_version)).await
+            && let Some(slot) = metadata.get("SLOT")
+        {
+            new_slots.insert(cp.clone(), slot.clone());
+        }
+    }
+    for (cpv, atom) in vartree.slot_operator_rebuilds(&new_slots) {
+        let Some(cp) = crate::versions::cpv_getkey(&cpv) else {
+            continue;
+        };
+        if packages_to_upgrade.iter().any(|(upgrade_cp, _, _)| *upgrade_cp == cp) {
+            continue;
+        }
+        println!("Rebuilding {} (slot operator dependency {} changed subslot)", cpv, atom);
+        let version = cpv[cp.len() + 1..].to_string();
+        packages_to_upgrade.push((cp, version.clone(), version));
     }
 
     if packages_to_upgrade.is_empty() {
@@ -1218,10 +1623,9 @@ pub async fn action_upgrade(packages: &[String], pretend: bool, ask: bool, deep:
         return 0;
     }
 
-    if ask {
-        println!("Would you like to proceed? (y/N)");
-        // Placeholder: in real implementation, read user input
-        println!("Proceeding with upgrade...");
+    if ask && crate::output::userquery("Would you like to merge these packages?", &mut std::io::stdin().lock()) != Some(true) {
+        println!("\nQuitting.\n");
+        return 130;
     }
 
     // Perform the upgrades
@@ -1252,6 +1656,9 @@ pub async fn action_upgrade(packages: &[String], pretend: bool, ask: bool, deep:
 
     if success_count == packages_to_upgrade.len() {
         println!("All packages upgraded successfully.");
+        if !options.oneshot {
+            record_world_atoms(packages, &options.root);
+        }
         0
     } else {
         eprintln!(
@@ -1263,11 +1670,12 @@ pub async fn action_upgrade(packages: &[String], pretend: bool, ask: bool, deep:
     }
 }
 
-pub async fn action_remove(packages: &[String], pretend: bool, ask: bool) -> i32 {
+pub async fn action_remove(packages: &[String], options: &EmergeOptions) -> i32 {
+    let (pretend, ask) = (options.pretend, options.ask);
     println!("Removing packages: {:?}", packages);
 
     // Resolve sets (@world, @system, etc.) to individual packages
-    let resolved_packages = match sets::resolve_targets(packages, "/").await {
+    let resolved_packages = match sets::resolve_targets(packages, &options.root).await {
         Ok(pkgs) => pkgs,
         Err(e) => {
             eprintln!("Failed to resolve package sets: {}", e);
@@ -1276,8 +1684,8 @@ pub async fn action_remove(packages: &[String], pretend: bool, ask: bool) -> i32
     };
 
     // Initialize components
-    let vartree = crate::vartree::VarTree::new("/");
-    let mut porttree = PortTree::new("/");
+    let vartree = crate::vartree::VarTree::new(&options.root);
+    let mut porttree = PortTree::new(&options.config_root);
     porttree.scan_repositories();
 
     // Parse packages to remove
@@ -1316,19 +1724,19 @@ pub async fn action_remove(packages: &[String], pretend: bool, ask: bool) -> i32
         return 0;
     }
 
-    if ask {
-        println!("Would you like to proceed? (y/N)");
-        // Placeholder: in real implementation, read user input
-        println!("Proceeding with removal...");
+    if ask && crate::output::userquery("Would you like to unmerge these packages?", &mut std::io::stdin().lock()) != Some(true) {
+        println!("\nQuitting.\n");
+        return 130;
     }
 
     // Perform the removal
-    let merger = crate::merge::Merger::new("/");
+    let merger = crate::merge::Merger::new(&options.root).with_config_roots(&options.config_root, &options.sysroot).with_nolock(options.nolock);
+    let world = crate::world::WorldManager::new(&options.root);
     let mut success_count = 0;
 
     for atom in &packages_to_remove {
-        // Find the installed CPV for this atom
-        let installed = match vartree.get_all_installed().await {
+        // Find the installed CPV for this atom; the merger's index follows the removals
+        let installed = match merger.vartree.get_all_installed().await {
             Ok(installed) => installed,
             Err(e) => {
                 eprintln!("Failed to get installed packages: {}", e);
@@ -1350,6 +1758,16 @@ pub async fn action_remove(packages: &[String], pretend: bool, ask: bool) -> i32
                     if result.failed.is_empty() {
                         println!("Successfully removed {}", atom.cp());
                         success_count += 1;
+
+                        // Drop the package from the world file
+                        match world.remove_package(&atom.cp()) {
+                            Ok(removed) => {
+                                for entry in removed {
+                                    println!(">>> Removing {} from \"world\" favorites file...", entry);
+                                }
+                            }
+                            Err(e) => eprintln!("Warning: Failed to update world file: {}", e),
+                        }
                     } else {
                         eprintln!("Failed to remove {}: {:?}", atom.cp(), result.failed);
                     }
@@ -1365,6 +1783,13 @@ pub async fn action_remove(packages: &[String], pretend: bool, ask: bool) -> i32
 
     if success_count == packages_to_remove.len() {
         println!("All packages removed successfully.");
+        for set in packages.iter().filter(|target| target.starts_with('@')) {
+            match world.remove_set(set) {
+                Ok(true) => println!(">>> Removing {} from \"world_sets\" favorites file...", set),
+                Ok(false) => {}
+                Err(e) => eprintln!("Warning: Failed to update world_sets file: {}", e),
+            }
+        }
         0
     } else {
         eprintln!(
@@ -1376,130 +1801,538 @@ pub async fn action_remove(packages: &[String], pretend: bool, ask: bool) -> i32
     }
 }
 
-pub async fn action_search(pattern: &str) -> i32 {
-    println!("Searching for packages matching: {}", pattern);
+/// Unmerge the installed packages @world does not need, or with targets only those of them
+/// that match; build-time dependencies count as needed when `with_bdeps`
+pub async fn action_depclean(packages: &[String], options: &EmergeOptions, with_bdeps: bool) -> i32 {
+    let mut targets = Vec::new();
+    for pkg in packages {
+        match Atom::new(pkg) {
+            Ok(atom) => targets.push(atom),
+            Err(e) => {
+                eprintln!("Invalid package atom '{}': {}", pkg, e);
+                return 1;
+            }
+        }
+    }
+    let set_manager = sets::PackageSetManager::new(&options.root);
+    // As in Portage, never depclean without @system: everything in it would look unneeded
+    if let Err(e) = set_manager.require_system_packages().await {
+        eprintln!("!!! {}", e);
+        eprintln!("!!! depclean aborted: @system could not be resolved");
+        return 1;
+    }
+    let world = match set_manager.get_world_set_packages().await {
+        Ok(world) => world,
+        Err(e) => {
+            eprintln!("Failed to resolve @world: {}", e);
+            return 1;
+        }
+    };
 
-    // Initialize components
-    let mut porttree = PortTree::new("/");
-    porttree.scan_repositories();
+    println!("Calculating dependencies... done!");
+    let vartree = crate::vartree::VarTree::new(&options.root);
+    let mut removals = crate::depclean::find_removals(&vartree, &world, with_bdeps);
+    if !targets.is_empty() {
+        removals.retain(|removal| targets.iter().any(|atom| atom.matches(&removal.cpv)));
+    }
+    if removals.is_empty() {
+        println!(">>> No packages selected for removal by depclean");
+        return 0;
+    }
 
-    let mut candidate_cpvs = Vec::new();
-
-    // First pass: find all candidate packages
-    for repo in porttree.repositories.values() {
-        if let Ok(entries) = std::fs::read_dir(&repo.location) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_dir() {
-                            if let Some(category_name) =
-                                entry.path().file_name().and_then(|n| n.to_str())
-                            {
-                                // Skip non-category directories
-                                if category_name.starts_with('.') || category_name == "metadata" {
-                                    continue;
-                                }
+    println!("\n>>> These are the packages that would be unmerged:\n");
+    for removal in &removals {
+        println!("  {}", removal.cpv);
+        println!("      {}", removal.reason);
+    }
+    println!("\nNumber to remove: {}", removals.len());
+    if options.pretend {
+        return 0;
+    }
+    if options.ask && crate::output::userquery("Would you like to unmerge these packages?", &mut std::io::stdin().lock()) != Some(true) {
+        println!("\nQuitting.\n");
+        return 130;
+    }
 
-                                // Search packages in this category
-                                if let Ok(pkg_entries) = std::fs::read_dir(entry.path()) {
-                                    for pkg_entry in pkg_entries {
-                                        if let Ok(pkg_entry) = pkg_entry {
-                                            if let Ok(pkg_file_type) = pkg_entry.file_type() {
-                                                if pkg_file_type.is_dir() {
-                                                    if let Some(pkg_name) = pkg_entry
-                                                        .path()
-                                                        .file_name()
-                                                        .and_then(|n| n.to_str())
-                                                    {
-                                                        let cp = format!(
-                                                            "{}/{}",
-                                                            category_name, pkg_name
-                                                        );
-
-                                                        // Check if package name matches
-                                                        if cp.contains(pattern) {
-                                                            // Find best version
-                                                            let merger =
-                                                                crate::merge::Merger::new("/");
-                                                            if let Ok(Some(cpv)) =
-                                                                merger.find_best_version(&cp).await
-                                                            {
-                                                                candidate_cpvs.push(cpv);
-                                                            }
-                                                        } else {
-                                                            // For description search, we need to check metadata
-                                                            // For now, just collect all packages and check descriptions later
-                                                            let merger =
-                                                                crate::merge::Merger::new("/");
-                                                            if let Ok(Some(cpv)) =
-                                                                merger.find_best_version(&cp).await
-                                                            {
-                                                                candidate_cpvs.push(cpv);
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
+    let merger = crate::merge::Merger::new(&options.root).with_config_roots(&options.config_root, &options.sysroot).with_nolock(options.nolock);
+    let cpvs: Vec<String> = removals.into_iter().map(|removal| removal.cpv).collect();
+    match merger.remove_packages(&cpvs, false).await {
+        Ok(result) if result.failed.is_empty() => 0,
+        Ok(result) => {
+            eprintln!("Failed to remove: {}", result.failed.join(", "));
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// glsa-check: `list`, `test` or `fix` the advisories in `targets`, GLSA ids or "all" and
+/// "affected"; without targets, list shows all and test and fix the affected ones
+pub async fn action_glsa(command: &str, targets: &[String], options: &EmergeOptions) -> i32 {
+    let mut porttree = PortTree::new(&options.config_root);
+    porttree.scan_repositories();
+    let config = match crate::config::Config::new(&options.config_root).await.map(|c| c.with_roots(&options.root, &options.sysroot)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            return 1;
+        }
+    };
+    let arch = config.get_var("ARCH").cloned().unwrap_or_default();
+    let vartree = crate::vartree::VarTree::new(&options.root);
+    let applied = crate::glsa::applied(&options.root);
+
+    let mut advisories: Vec<crate::glsa::Glsa> = Vec::new();
+    for location in porttree.repository_locations() {
+        for glsa in crate::glsa::load_advisories(&location) {
+            if !advisories.iter().any(|known| known.id == glsa.id) {
+                advisories.push(glsa);
+            }
+        }
+    }
+    let default = if command == "list" { "all" } else { "affected" };
+    let targets: Vec<&str> = if targets.is_empty() { vec![default] } else { targets.iter().map(|t| t.as_str()).collect() };
+    for target in &targets {
+        if *target != "all" && *target != "affected" && !advisories.iter().any(|glsa| glsa.id == *target) {
+            eprintln!("!!! Unknown GLSA: {}", target);
+            return 1;
+        }
+    }
+    let selected: Vec<(&crate::glsa::Glsa, Vec<String>)> = advisories.iter()
+        .map(|glsa| (glsa, glsa.affected_installed(&vartree, &arch)))
+        .filter(|(glsa, affected)| targets.iter().any(|target| match *target {
+            "all" => true,
+            "affected" => !affected.is_empty() && !applied.contains(&glsa.id),
+            id => glsa.id == id,
+        }))
+        .collect();
+
+    match command {
+        "list" => {
+            for (glsa, affected) in &selected {
+                let mark = if applied.contains(&glsa.id) { "U" } else if affected.is_empty() { "N" } else { "A" };
+                let packages: Vec<&str> = glsa.affected.iter().map(|package| package.name.as_str()).collect();
+                println!("[{}] {} ({}): {}", mark, glsa.id, glsa.title, packages.join(" "));
+            }
+            0
+        }
+        "test" => {
+            let affected: Vec<&str> = selected.iter().filter(|(_, affected)| !affected.is_empty()).map(|(glsa, _)| glsa.id.as_str()).collect();
+            if affected.is_empty() {
+                println!("This system is not affected by any of the listed GLSAs");
+            } else {
+                println!("This system is affected by the following GLSAs:");
+                for id in affected {
+                    println!("{}", id);
+                }
+            }
+            0
+        }
+        _ => {
+            let merger = configured_merger(&options.root, &config, options);
+            let mut upgrades: Vec<String> = Vec::new();
+            let mut fixed = Vec::new();
+            for (glsa, affected) in selected.iter().filter(|(_, affected)| !affected.is_empty()) {
+                println!(">>> Fixing GLSA {}: {}", glsa.id, glsa.title);
+                let mut complete = true;
+                for cpv in affected {
+                    let cp = crate::versions::cpv_getkey(cpv).unwrap_or_default();
+                    let mut available = Vec::new();
+                    for candidate in merger.visible_versions(&cp, &porttree).await.unwrap_or_default() {
+                        let slot = porttree.get_metadata(&candidate).await.and_then(|metadata| metadata.get("SLOT").cloned());
+                        available.push((candidate, slot));
+                    }
+                    match glsa.minimal_upgrade(cpv, &available) {
+                        Some(upgrade) => {
+                            println!("  {} -> {}", cpv, upgrade);
+                            let atom = format!("={}", upgrade);
+                            if !upgrades.contains(&atom) {
+                                upgrades.push(atom);
                             }
                         }
+                        None => {
+                            eprintln!("!!! No version of {} fixing GLSA {} is available", cp, glsa.id);
+                            complete = false;
+                        }
+                    }
+                }
+                if complete {
+                    fixed.push(glsa.id.clone());
+                }
+            }
+            if upgrades.is_empty() {
+                println!("Nothing to fix");
+                return if fixed.len() == selected.iter().filter(|(_, affected)| !affected.is_empty()).count() { 0 } else { 1 };
+            }
+
+            let options = EmergeOptions { oneshot: true, ..options.clone() };
+            let status = action_install_with_options(&upgrades, &options).await;
+            if status == 0 && !options.pretend {
+                for id in &fixed {
+                    if let Err(e) = crate::glsa::mark_applied(&options.root, id) {
+                        eprintln!("Warning: {}", e);
                     }
                 }
             }
+            status
         }
     }
+}
 
-    // Second pass: get metadata and filter by description if needed
-    let mut matches = Vec::new();
-    for cpv in candidate_cpvs {
-                if let Some(metadata) = porttree.get_metadata(&cpv).await {
-            let cp = cpv.split('-').take(2).collect::<Vec<_>>().join("-");
-            if cp.contains(pattern) {
-                // Name match
-                matches.push((cpv, metadata));
-            } else if let Some(desc) = metadata.get("DESCRIPTION") {
-                if desc.to_lowercase().contains(&pattern.to_lowercase()) {
-                    // Description match
-                    matches.push((cpv, metadata));
-                }
+pub async fn action_search(patterns: &[String], options: &SearchOptions) -> i32 {
+    let mut queries = Vec::new();
+    for pattern in patterns {
+        let query = if options.regex_auto {
+            SearchQuery::auto(pattern)
+        } else {
+            Ok(SearchQuery::new(pattern))
+        };
+        match query {
+            Ok(query) => queries.push(query.with_fuzzy(options.fuzzy).with_description(options.search_description)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
             }
         }
     }
 
-    // Display results
-    if matches.is_empty() {
-        println!("No packages found matching '{}'", pattern);
+    let mut porttree = PortTree::new(&options.config_root);
+    porttree.scan_repositories();
+
+    let index_path = SearchIndex::path(&options.config_root);
+    let index = match SearchIndex::load(&index_path) {
+        Ok(index) if !index.is_stale(&porttree) => index,
+        _ => {
+            // Missing or out of date: walk the repositories and refresh the cache
+            let index = SearchIndex::build(&porttree);
+            // Saving needs write access to /var/cache/edb; searching works without it
+            let _ = index.save(&index_path);
+            index
+        }
+    };
+
+    // Installed versions per category/package
+    let mut installed: std::collections::HashMap<String, Vec<String>> = std::collections::HashMap::new();
+    let vartree = crate::vartree::VarTree::new(&options.root);
+    for cpv in vartree.list_packages() {
+        if let Some(parts) = crate::versions::catpkgsplit(&cpv) {
+            let version = crate::versions::cpv_getversion(&cpv).unwrap_or_default();
+            installed.entry(format!("{}/{}", parts[0], parts[1])).or_default().push(version);
+        }
+    }
+
+    let color = crate::output::color_enabled();
+    let paint = |text: &str, ranges: Vec<(usize, usize)>| {
+        if color { crate::search_index::highlight(text, &ranges) } else { text.to_string() }
+    };
+
+    let latest_installed = |cp: &str| installed.get(cp).and_then(|versions| {
+        versions.iter().max_by(|a, b| crate::versions::vercmp(a, b).unwrap_or(0).cmp(&0)).cloned()
+    });
+
+    let mut results = Vec::new();
+    for query in &queries {
+        let matches = index.search(query);
+        if options.json {
+            results.push(serde_json::json!({
+                "pattern": query.pattern,
+                "matches": matches.iter().map(|entry| serde_json::json!({
+                    "cp": entry.cp(),
+                    "version": entry.version,
+                    "installed": latest_installed(&entry.cp()),
+                    "repository": entry.repo,
+                    "description": entry.description,
+                })).collect::<Vec<_>>(),
+            }));
+            continue;
+        }
+
+        println!("[ Results for search key : {} ]", query.pattern);
+        println!("Searching...\n");
+        for entry in &matches {
+            println!("*  {}/{}", entry.category, paint(&entry.name, query.name_ranges(entry)));
+            println!("      Latest version available: {}", entry.version);
+            let latest = latest_installed(&entry.cp());
+            println!("      Latest version installed: {}", latest.as_deref().unwrap_or("[ Not Installed ]"));
+            println!("      Description:   {}", paint(&entry.description, query.description_ranges(entry)));
+            println!();
+        }
+        println!("[ Applications found : {} ]\n", matches.len());
+    }
+    if options.json {
+        crate::output::print_json(&serde_json::json!({ "results": results }));
+    }
+
+    0
+}
+
+/// Report which installed packages own the given paths (emerge --owns / qfile)
+pub async fn action_owns(patterns: &[String], root: &str) -> i32 {
+    let vartree = crate::vartree::VarTree::new(root);
+    let owners = match vartree.find_owners(patterns) {
+        Ok(owners) => owners,
+        Err(e) => {
+            eprintln!("!!! {}", e);
+            return 1;
+        }
+    };
+    if owners.is_empty() {
+        eprintln!("!!! No installed package owns: {}", patterns.join(" "));
+        return 1;
+    }
+    for (path, cpvs) in owners {
+        for cpv in cpvs {
+            println!("{} ({})", cpv, path);
+        }
+    }
+    0
+}
+
+/// Refresh PKGDIR/Packages and serve PKGDIR over HTTP as a binhost
+pub async fn action_serve_binhost(addr: &str, root: &str) -> i32 {
+    let config = match crate::config::Config::new(root).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            return 1;
+        }
+    };
+    match crate::binhost::serve(Path::new(&config.pkgdir), addr).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("!!! {}", e);
+            1
+        }
+    }
+}
+
+/// Regenerate the md5-cache of every repository (emerge --regen / egencache)
+pub async fn action_regen(jobs: usize) -> i32 {
+    let mut porttree = PortTree::new("/");
+    porttree.scan_repositories();
+
+    let mut repo_names: Vec<String> = porttree.repositories.keys().cloned().collect();
+    repo_names.sort();
+
+    let mut failures: Vec<(String, String)> = Vec::new();
+    for repo_name in repo_names {
+        let Some(cache) = porttree.md5_cache(&repo_name) else {
+            continue;
+        };
+        let regen = api::regen_cache(cache, jobs, |done, total| print_cache_progress(&repo_name, done, total)).await;
+        print_cache_pruned(&regen);
+        failures.extend(regen.failures);
+    }
+
+    if failures.is_empty() {
+        println!("Cache regeneration complete.");
+        0
     } else {
-        println!("Found {} packages:", matches.len());
-        println!();
+        eprintln!("\n!!! Failed to regenerate {} cache entries:", failures.len());
+        for (cpv, error) in &failures {
+            eprintln!("!!!   {}: {}", cpv, error);
+        }
+        1
+    }
+}
 
-        for (cpv, metadata) in matches {
-            print!("  {}", cpv);
+/// Toolchain and core packages whose installed versions emerge --info lists
+const INFO_PACKAGES: &[&str] = &[
+    "app-misc/pax-utils", "app-shells/bash", "dev-build/autoconf", "dev-build/automake",
+    "dev-build/cmake", "dev-build/libtool", "dev-build/make", "dev-build/meson",
+    "dev-lang/perl", "dev-lang/python", "dev-lang/rust", "llvm-core/clang", "llvm-core/llvm",
+    "sys-apps/baselayout", "sys-apps/openrc", "sys-apps/sandbox", "sys-apps/systemd",
+    "sys-devel/binutils", "sys-devel/binutils-config", "sys-devel/gcc", "sys-devel/gcc-config",
+    "sys-kernel/linux-headers", "sys-libs/glibc", "sys-libs/musl",
+];
+
+/// Variables emerge --info reports, in output order
+const INFO_VARS: &[&str] = &[
+    "ACCEPT_KEYWORDS", "ACCEPT_LICENSE", "CBUILD", "CFLAGS", "CHOST", "CONFIG_PROTECT",
+    "CONFIG_PROTECT_MASK", "CXXFLAGS", "DISTDIR", "EMERGE_DEFAULT_OPTS", "FCFLAGS", "FFLAGS",
+    "GENTOO_MIRRORS", "LANG", "LC_ALL", "LDFLAGS", "LINGUAS", "MAKEOPTS", "PKGDIR",
+    "PORTAGE_TMPDIR",
+];
+
+/// A repository as emerge --info lists it
+#[derive(Debug, serde::Serialize)]
+pub struct RepoInfo {
+    pub name: String,
+    pub location: String,
+    pub sync_type: Option<String>,
+    pub sync_uri: Option<String>,
+}
 
-            if let Some(desc) = metadata.get("DESCRIPTION") {
-                // Truncate long descriptions
-                let truncated = if desc.len() > 60 {
-                    format!("{}...", &desc[..57])
-                } else {
-                    desc.clone()
-                };
-                println!(" - {}", truncated);
-            } else {
-                println!();
+/// What emerge --info reports about the system
+#[derive(Debug, serde::Serialize)]
+pub struct SystemInfo {
+    pub version: String,
+    pub profile: String,
+    pub kernel: String,
+    pub arch: String,
+    /// Installed versions of the INFO_PACKAGES present
+    pub packages: std::collections::BTreeMap<String, Vec<String>>,
+    pub repositories: Vec<RepoInfo>,
+    /// The INFO_VARS that are set
+    pub variables: std::collections::BTreeMap<String, String>,
+    pub features: Vec<String>,
+    /// USE, without the flags of USE_EXPAND variables
+    pub use_flags: Vec<String>,
+    pub use_expand: std::collections::BTreeMap<String, Vec<String>>,
+    /// The INFO_VARS that are not
+    pub unset: Vec<String>,
+}
+
+pub fn system_info(config: &crate::config::Config, profile: &str, porttree: &PortTree, vartree: &crate::vartree::VarTree) -> SystemInfo {
+    let packages = INFO_PACKAGES.iter()
+        .filter_map(|cp| {
+            let mut versions: Vec<String> = vartree.installed_versions(cp).iter()
+                .filter_map(|cpv| crate::versions::Cpv::parse(cpv).ok().map(|cpv| cpv.pvr()))
+                .collect();
+            versions.sort();
+            (!versions.is_empty()).then(|| (cp.to_string(), versions))
+        })
+        .collect();
+
+    let mut repositories: Vec<RepoInfo> = porttree.repositories.values()
+        .map(|repo| RepoInfo {
+            name: repo.name.clone(),
+            location: repo.location.clone(),
+            sync_type: repo.sync_type.clone(),
+            sync_uri: repo.sync_uri.clone(),
+        })
+        .collect();
+    repositories.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut variables = std::collections::BTreeMap::new();
+    let mut unset = Vec::new();
+    for key in INFO_VARS {
+        match config.get_var(key) {
+            Some(value) => {
+                variables.insert(key.to_string(), value.clone());
             }
+            None => unset.push(key.to_string()),
         }
     }
 
-    0
+    // USE, and the USE_EXPAND variables it contains
+    let use_expand_vars = config.use_expand();
+    let mut use_flags: Vec<String> = config.use_flags.iter()
+        .filter(|f| !f.starts_with('-') && crate::config::split_use_expand(f, &use_expand_vars).is_none())
+        .cloned()
+        .collect();
+    use_flags.sort();
+    use_flags.dedup();
+    let use_expand = use_expand_vars.iter()
+        .filter_map(|var| {
+            let value = config.get_var(var).filter(|v| !v.trim().is_empty())?;
+            Some((var.clone(), value.split_whitespace().map(|v| v.to_string()).collect()))
+        })
+        .collect();
+
+    SystemInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        profile: profile.to_string(),
+        kernel: std::fs::read_to_string("/proc/sys/kernel/osrelease").map(|s| s.trim().to_string()).unwrap_or_default(),
+        arch: std::env::consts::ARCH.to_string(),
+        packages,
+        repositories,
+        variables,
+        features: config.features.clone(),
+        use_flags,
+        use_expand,
+        unset,
+    }
+}
+
+impl SystemInfo {
+    /// The report as emerge --info prints it
+    pub fn format(&self) -> String {
+        let short = |cp: &str, name: &str| {
+            let version = self.packages.get(cp).and_then(|versions| {
+                versions.iter().max_by(|a, b| crate::versions::vercmp(a, b).unwrap_or(0).cmp(&0))
+            });
+            format!("{}-{}", name, version.map_or("unknown", |v| v.as_str()))
+        };
+
+        let mut out = String::new();
+        out.push_str(&format!("emerge-rs {} ({}, {}, {}, {} {})\n", self.version, self.profile,
+            short("sys-devel/gcc", "gcc"), short("sys-libs/glibc", "glibc"), self.kernel, self.arch));
+        out.push_str(&"=".repeat(65));
+        out.push('\n');
+        out.push_str(&format!("System uname: Linux-{}-{}\n", self.kernel, self.arch));
+
+        for cp in INFO_PACKAGES {
+            if let Some(versions) = self.packages.get(*cp) {
+                out.push_str(&format!("{:<26}{}\n", format!("{}:", cp), versions.join(", ")));
+            }
+        }
+
+        out.push_str("Repositories:\n");
+        for repo in &self.repositories {
+            out.push_str(&format!("\n{}\n    location: {}\n", repo.name, repo.location));
+            if let Some(sync_type) = &repo.sync_type {
+                out.push_str(&format!("    sync-type: {}\n", sync_type));
+            }
+            if let Some(sync_uri) = &repo.sync_uri {
+                out.push_str(&format!("    sync-uri: {}\n", sync_uri));
+            }
+        }
+        out.push('\n');
+
+        for (key, value) in &self.variables {
+            out.push_str(&format!("{}=\"{}\"\n", key, value));
+        }
+        out.push_str(&format!("FEATURES=\"{}\"\n", self.features.join(" ")));
+
+        let mut use_line = format!("USE=\"{}\"", self.use_flags.join(" "));
+        for (var, values) in &self.use_expand {
+            use_line.push_str(&format!(" {}=\"{}\"", var, values.join(" ")));
+        }
+        out.push_str(&use_line);
+        out.push('\n');
+        if !self.unset.is_empty() {
+            out.push_str(&format!("Unset:  {}\n", self.unset.join(", ")));
+        }
+        out
+    }
 }
 
-pub async fn action_info(packages: &[String]) -> i32 {
-    println!("Getting info for packages: {:?}", packages);
+/// The emerge --info system report
+pub fn format_system_info(config: &crate::config::Config, profile: &str, porttree: &PortTree, vartree: &crate::vartree::VarTree) -> String {
+    system_info(config, profile, porttree, vartree).format()
+}
+
+/// emerge --info: the system report, then details of any given packages; with `json`,
+/// both as one JSON document
+pub async fn action_info(root: &str, config_root: &str, packages: &[String], json: bool) -> i32 {
+    let config = match crate::config::Config::new(config_root).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            return 1;
+        }
+    };
+    let profile = match crate::profile::ProfileManager::new(config_root).get_current_profile().await {
+        Ok(profile) => profile.name,
+        Err(_) => "unknown profile".to_string(),
+    };
+    let mut porttree = PortTree::new(config_root);
+    porttree.scan_repositories();
+    let info = system_info(&config, &profile, &porttree, &crate::vartree::VarTree::new(root));
+    if !json {
+        print!("{}", info.format());
+        if packages.is_empty() {
+            return 0;
+        }
+        println!();
+    }
 
     // Resolve sets (@world, @system, etc.) to individual packages
-    let resolved_packages = match sets::resolve_targets(packages, "/").await {
+    let resolved_packages = match sets::resolve_targets(packages, root).await {
         Ok(pkgs) => pkgs,
         Err(e) => {
             eprintln!("Failed to resolve package sets: {}", e);
@@ -1507,11 +2340,9 @@ pub async fn action_info(packages: &[String]) -> i32 {
         }
     };
 
-    // Initialize components
-    let mut porttree = PortTree::new("/");
-    porttree.scan_repositories();
-    let merger = crate::merge::Merger::new("/");
+    let merger = crate::merge::Merger::new(root).with_config_roots(config_root, "/");
 
+    let mut package_info = Vec::new();
     for pkg in &resolved_packages {
         // Try to parse as atom first, then as category/package
         let cp = if let Ok(atom) = Atom::new(pkg) {
@@ -1522,27 +2353,36 @@ pub async fn action_info(packages: &[String]) -> i32 {
         };
 
         // Find the best available version
-        match merger.find_best_version_with_porttree(&cp, Some(&porttree)).await {
-            Ok(Some(cpv)) => {
-                // Get metadata for this package
-                if let Some(metadata) = porttree.get_metadata(&cpv).await {
-                    display_package_info(&cpv, &metadata);
-                } else {
-                    eprintln!("No metadata found for {}", cpv);
-                }
-            }
-            Ok(None) => {
-                eprintln!("Package {} not found", cp);
-            }
-            Err(e) => {
-                eprintln!("Error finding package {}: {}", cp, e);
-            }
+        let found = match merger.find_best_version_with_porttree(&cp, Some(&porttree)).await {
+            Ok(Some(cpv)) => match porttree.get_metadata(&cpv).await {
+                Some(metadata) => Ok((cpv, metadata)),
+                None => Err(format!("No metadata found for {}", cpv)),
+            },
+            Ok(None) => Err(format!("Package {} not found", cp)),
+            Err(e) => Err(format!("Error finding package {}: {}", cp, e)),
+        };
+        if json {
+            package_info.push(match found {
+                Ok((cpv, metadata)) => serde_json::json!({
+                    "cpv": cpv,
+                    "metadata": metadata.into_iter().collect::<std::collections::BTreeMap<_, _>>(),
+                }),
+                Err(error) => serde_json::json!({ "package": cp, "error": error }),
+            });
+            continue;
+        }
+        match found {
+            Ok((cpv, metadata)) => display_package_info(&cpv, &metadata),
+            Err(error) => eprintln!("{}", error),
         }
 
         // Add a blank line between packages
         println!();
     }
 
+    if json {
+        crate::output::print_json(&serde_json::json!({ "system": info, "packages": package_info }));
+    }
     0
 }
 
@@ -1594,155 +2434,4 @@ fn display_package_info(cpv: &str, metadata: &std::collections::HashMap<String,
     }
 }
 
-async fn get_all_upgradable_packages(
-    vartree: &crate::vartree::VarTree,
-    merger: &crate::merge::Merger,
-    porttree: &crate::porttree::PortTree,
-    mask_manager: &crate::mask::MaskManager,
-) -> Result<Vec<(String, String, String)>, Box<dyn std::error::Error>> {
-    let mut upgradable = Vec::new();
-
-    let installed = vartree.get_all_installed().await?;
-    for cpv in installed {
-        // Extract CP from CPV (CPV is category-package-version)
-        if let Some(last_dash) = cpv.rfind('-') {
-            let cp_hyphenated = &cpv[..last_dash];
-            let installed_version = &cpv[last_dash + 1..];
-
-            // Convert back to category/package format for merger
-            let cp = cp_hyphenated.replace('-', "/");
-
-            // Check if package is masked
-            if let Ok(atom) = crate::atom::Atom::new(&cp) {
-                if let Some(mask_reason) = mask_manager.is_masked(&atom).await? {
-                    // Skip masked packages
-                    continue;
-                }
-            }
-
-            // Find best available version
-            if let Ok(Some(available_cpv)) = merger.find_best_version_with_porttree(&cp, Some(porttree)).await {
-                // Check if the available version is masked or keyword-restricted
-                if let Ok(available_atom) = crate::atom::Atom::new(&available_cpv) {
-                    if let Some(mask_reason) = mask_manager.is_masked(&available_atom).await? {
-                        // Skip masked versions
-                        continue;
-                    }
-                }
-
-                // Extract version from available CPV
-                if let Some(avail_last_dash) = available_cpv.rfind('-') {
-                    let available_version = &available_cpv[avail_last_dash + 1..];
-
-                    // Compare versions
-                    if let Some(cmp) = crate::versions::vercmp(installed_version, available_version)
-                    {
-                        if cmp < 0 {
-                            // installed < available
-                            upgradable.push((
-                                cp.to_string(),
-                                installed_version.to_string(),
-                                available_version.to_string(),
-                            ));
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(upgradable)
-}
-
-async fn get_specific_upgradable_packages(
-    packages: &[String],
-    vartree: &crate::vartree::VarTree,
-    merger: &crate::merge::Merger,
-    porttree: &crate::porttree::PortTree,
-    mask_manager: &crate::mask::MaskManager,
-) -> Result<Vec<(String, String, String)>, Box<dyn std::error::Error>> {
-    let mut upgradable = Vec::new();
-
-    for pkg in packages {
-        // Parse atom to get CP
-                match Atom::new(pkg) {
-                    Ok(atom) => {
-                        let cp = atom.cp();
-
-                        // Check if package is masked
-                        if let Some(mask_reason) = mask_manager.is_masked(&atom).await? {
-                            eprintln!("{} is masked: {}", cp, mask_reason);
-                            continue;
-                        }
-
-                        // Check if installed
-                        let installed = vartree.get_all_installed().await?;
-                        let mut found_installed = None;
-                        // Convert cp from category/package to category-package for matching
-                        let cp_hyphenated = cp.replace('/', "-");
-                        for cpv in &installed {
-                            if cpv.starts_with(&format!("{}-", cp_hyphenated)) {
-                                if let Some(last_dash) = cpv.rfind('-') {
-                                    found_installed = Some(cpv[last_dash + 1..].to_string());
-                                }
-                                break;
-                            }
-                        }
-
-                        if let Some(installed_version) = found_installed {
-                            // Find best available version
-                            if let Ok(Some(available_cpv)) = merger.find_best_version_with_porttree(&cp, Some(porttree)).await {
-                                // Check if the available version is masked or keyword-restricted
-                                let available_atom = crate::atom::Atom {
-                                    category: cp.split('/').next().unwrap_or("").to_string(),
-                                    package: cp.split('/').nth(1).unwrap_or("").to_string(),
-                                    version: Some(available_cpv.split('-').last().unwrap_or("").to_string()),
-                                    op: crate::atom::Operator::None,
-                                    slot: None,
-                                    subslot: None,
-                                    repo: None,
-                                    use_deps: vec![],
-                                    blocker: None,
-                                };
-
-                                if let Some(mask_reason) = mask_manager.is_masked(&available_atom).await? {
-                                    eprintln!("Available version {} is masked: {}", available_cpv, mask_reason);
-                                    continue;
-                                }
-
-                                if let Some(avail_last_dash) = available_cpv.rfind('-') {
-                                    let available_version = &available_cpv[avail_last_dash + 1..];
-
-                                    // Compare versions
-                                    if let Some(cmp) =
-                                        crate::versions::vercmp(&installed_version, available_version)
-                                    {
-                                        if cmp < 0 {
-                                            // installed < available
-                                            upgradable.push((
-                                                cp,
-                                                installed_version,
-                                                available_version.to_string(),
-                                            ));
-                                        } else {
-                                            println!("{} is already up to date.", cp);
-                                        }
-                                    }
-                                }
-                            } else {
-                                eprintln!("No available version found for {}", cp);
-                            }
-                        } else {
-                            eprintln!("{} is not installed.", cp);
-                        }
-                    }
-            Err(e) => {
-                eprintln!("Invalid package atom '{}': {}", pkg, e);
-            }
-        }
-    }
-
-    Ok(upgradable)
-}
-
 
@@ -0,0 +1,1247 @@
+// api.rs -- the library interface: syncing repositories and resolving merges into
+// structured reports, leaving all output to the caller
+
+use crate::atom::Atom;
+use crate::autounmask::Autounmask;
+use crate::config::Config;
+use crate::dep_check::DepChecker;
+use crate::depgraph::DepGraph;
+use crate::depgraph::{DepNode, DepType};
+use crate::doebuild::Ebuild;
+use crate::emerge_config::EmergeOptions;
+use crate::merge::Merger;
+use crate::metadata_cache::Md5Cache;
+use crate::news::{NewsManager, NewsSystem};
+use crate::output::{BlockerEntry, MergeEntry};
+use crate::porttree::PortTree;
+use crate::search_index::SearchIndex;
+use crate::sets;
+use crate::sync::SyncError;
+use crate::sync::controller::sync_repository;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// Why a library operation failed
+#[derive(Debug)]
+pub enum Error {
+    /// A target that is neither a valid atom nor a known set
+    InvalidAtom(String),
+    /// The targets cannot be merged: conflicts, cycles, missing or masked packages,
+    /// explained the way emerge does
+    Resolution(String),
+    /// The plan needs package.* changes first; `changes.write` makes them
+    Autounmask { explanation: String, changes: Autounmask },
+    /// A repository that does not exist or could not be synced
+    Sync(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidAtom(msg) | Error::Resolution(msg) | Error::Sync(msg) => write!(f, "{}", msg),
+            Error::Autounmask { explanation, .. } => write!(f, "{}", explanation),
+            Error::Io(err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<SyncError> for Error {
+    fn from(err: SyncError) -> Self {
+        Error::Sync(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What to sync
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// PORTAGE_CONFIGROOT: where repos.conf, the hooks and the sync state are found
+    pub config_root: String,
+    /// Repositories to sync; every auto-sync one when empty
+    pub repos: Vec<String>,
+    /// Cache entries regenerated in parallel after a sync
+    pub jobs: usize,
+    /// Also sync repositories synced within their sync-interval
+    pub force: bool,
+    /// The --sync-submodule names git syncs are restricted to
+    pub submodules: Vec<String>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions { config_root: "/".to_string(), repos: Vec::new(), jobs: 1, force: false, submodules: Vec::new() }
+    }
+}
+
+/// How the sync of one repository went
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SyncOutcome {
+    Synced { message: String, changes: bool },
+    /// Synced, but the tree failed its integrity check
+    Unvalidated { message: String, changes: bool, error: String },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RepoSync {
+    pub name: String,
+    #[serde(flatten)]
+    pub outcome: SyncOutcome,
+}
+
+impl RepoSync {
+    pub fn succeeded(&self) -> bool {
+        !matches!(self.outcome, SyncOutcome::Failed { .. })
+    }
+
+    /// Whether the sync changed the repository
+    pub fn changes(&self) -> bool {
+        match self.outcome {
+            SyncOutcome::Synced { changes, .. } | SyncOutcome::Unvalidated { changes, .. } => changes,
+            SyncOutcome::Failed { .. } => false,
+        }
+    }
+}
+
+/// Progress of a sync, reported as it happens
+#[derive(Debug)]
+pub enum SyncEvent<'a> {
+    /// A problem that does not stop the sync
+    Warning(&'a str),
+    /// A repository skipped because it synced within its sync-interval, in seconds
+    Skipped(&'a str, u64),
+    RefreshingKeys(&'a str),
+    /// Syncing this many repositories begins
+    Starting(usize),
+    Started(&'a str),
+    /// A repository finished, as the how-manyth of how many
+    Finished(&'a RepoSync, usize, usize),
+    /// The md5-cache of a changed repository is being regenerated
+    CacheProgress { repo: &'a str, done: usize, total: usize },
+    CacheRegenerated(&'a str, &'a CacheRegen),
+    UpdatingSearchIndex,
+}
+
+/// The result of a sync
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncReport {
+    /// The repositories synced, in the order they finished
+    pub repos: Vec<RepoSync>,
+    /// Whether every post-sync hook succeeded
+    pub hooks_ok: bool,
+    /// Why the post-sync hooks that failed did
+    pub hook_failures: Vec<String>,
+    /// News items still unread after the sync, by repository
+    pub unread_news: Vec<(String, usize)>,
+}
+
+impl SyncReport {
+    pub fn success(&self) -> bool {
+        self.hooks_ok && self.repos.iter().all(RepoSync::succeeded)
+    }
+}
+
+/// Sync the repositories `options` names, then bring the metadata cache of changed ones up
+/// to date, run the post-sync hooks, rebuild the search index and import news
+pub async fn sync(options: &SyncOptions, mut progress: impl FnMut(SyncEvent)) -> Result<SyncReport> {
+    let mut porttree = PortTree::new(&options.config_root);
+    porttree.scan_repositories();
+
+    if let Err(e) = porttree.load_sync_metadata().await {
+        progress(SyncEvent::Warning(&format!("Failed to load sync metadata: {}", e)));
+    }
+
+    let mut repo_names: Vec<String> = porttree.repositories.keys().cloned().collect();
+    repo_names.sort();
+    if !options.repos.is_empty() {
+        if let Some(unknown) = options.repos.iter().find(|name| !porttree.repositories.contains_key(*name)) {
+            return Err(Error::Sync(format!("Unknown repository: {}", unknown)));
+        }
+        repo_names.retain(|name| options.repos.contains(name));
+    } else {
+        repo_names.retain(|name| porttree.repositories[name].auto_sync);
+    }
+    if !options.force {
+        repo_names.retain(|name| {
+            let due = porttree.sync_due(name);
+            if !due && let Some(interval) = porttree.repositories[name].sync_interval {
+                progress(SyncEvent::Skipped(name, interval));
+            }
+            due
+        });
+    }
+
+    let mut report = SyncReport { repos: Vec::new(), hooks_ok: true, hook_failures: Vec::new(), unread_news: Vec::new() };
+    let total_count = repo_names.len();
+    if repo_names.is_empty() {
+        return Ok(report);
+    }
+
+    let submodule_paths: Vec<String> = crate::sync::backends::git::SUBMODULES.iter()
+        .filter(|(name, _)| options.submodules.iter().any(|s| s == name))
+        .map(|(_, path)| path.to_string())
+        .collect();
+
+    // Verify with keys refreshed on schedule
+    let root = std::path::PathBuf::from(&porttree.root);
+    for repo_name in &repo_names {
+        porttree.repositories.get_mut(repo_name).unwrap().sync_submodules = submodule_paths.clone();
+        let repo = &porttree.repositories[repo_name];
+        let signed_snapshots = matches!(repo.sync_type.as_deref(), Some("webrsync" | "sqfs" | "squashfs"));
+        if !repo.sync_verify_metamanifest && !signed_snapshots {
+            continue;
+        }
+        if crate::sync::openpgp::refresh_due(&root, repo, std::time::SystemTime::now()) {
+            progress(SyncEvent::RefreshingKeys(repo_name));
+            if let Err(e) = crate::sync::openpgp::refresh_keys(&root, repo).await {
+                progress(SyncEvent::Warning(&format!("Failed to refresh OpenPGP keys for {}: {}", repo_name, e)));
+            }
+        }
+        let key_path = crate::sync::openpgp::key_path(&root, repo);
+        porttree.repositories.get_mut(repo_name).unwrap().sync_openpgp_key_path = Some(key_path.display().to_string());
+    }
+
+    progress(SyncEvent::Starting(total_count));
+    crate::emergelog::emergelog(" === sync");
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for repo_name in repo_names {
+        let repo = porttree.repositories.get(&repo_name).unwrap().clone();
+        progress(SyncEvent::Started(&repo_name));
+        crate::emergelog::emergelog(&format!(">>> Syncing repository '{}' into '{}'...", repo_name, repo.location));
+        tasks.spawn(async move {
+            // A panicking backend fails its repository alone
+            let result = match tokio::spawn(async move { sync_repository(&repo).await }).await {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(e) => Err(format!("Sync task panicked: {}", e)),
+            };
+            (repo_name, result)
+        });
+    }
+
+    while let Some(task_result) = tasks.join_next().await {
+        let Ok((repo_name, sync_result)) = task_result else {
+            continue;
+        };
+        let outcome = match sync_result {
+            Ok(result) => {
+                porttree.update_sync_metadata(&repo_name, true, None);
+                match porttree.validate_repository_integrity(&repo_name).await {
+                    Ok(notes) if notes.is_empty() => SyncOutcome::Synced { message: result.message, changes: result.changes },
+                    Ok(notes) => SyncOutcome::Synced { message: format!("{} ({})", result.message, notes.join(", ")), changes: result.changes },
+                    Err(e) if porttree.repositories.get(&repo_name).is_some_and(|r| r.sync_verify_metamanifest) => {
+                        porttree.update_sync_metadata(&repo_name, false, Some(e.to_string()));
+                        SyncOutcome::Failed { error: format!("Manifest verification failed: {}", e) }
+                    }
+                    Err(e) => SyncOutcome::Unvalidated { message: result.message, changes: result.changes, error: e.to_string() },
+                }
+            }
+            Err(error) => {
+                porttree.update_sync_metadata(&repo_name, false, Some(error.clone()));
+                SyncOutcome::Failed { error }
+            }
+        };
+        if !matches!(outcome, SyncOutcome::Failed { .. }) {
+            crate::emergelog::emergelog(&format!("=== Sync completed for {}", repo_name));
+        }
+        report.repos.push(RepoSync { name: repo_name, outcome });
+        progress(SyncEvent::Finished(report.repos.last().unwrap(), report.repos.len(), total_count));
+    }
+
+    if let Err(e) = porttree.save_sync_metadata().await {
+        progress(SyncEvent::Warning(&format!("Failed to save sync metadata: {}", e)));
+    }
+    // Repositories that synced, and whether the sync changed them
+    let mut synced: Vec<(String, bool)> = report.repos.iter()
+        .filter(|repo| repo.succeeded())
+        .map(|repo| (repo.name.clone(), repo.changes()))
+        .collect();
+    synced.sort();
+
+    // Bring the metadata cache of changed repositories that ship one up to date
+    for (repo_name, _) in synced.iter().filter(|(_, changes)| *changes) {
+        let Some(cache) = porttree.md5_cache(repo_name) else {
+            continue;
+        };
+        if !Path::new(&porttree.repositories[repo_name].location).join("metadata/md5-cache").is_dir() {
+            continue;
+        }
+        let regen = regen_cache(cache, options.jobs, |done, total| {
+            progress(SyncEvent::CacheProgress { repo: repo_name, done, total })
+        }).await;
+        progress(SyncEvent::CacheRegenerated(repo_name, &regen));
+    }
+
+    for (repo_name, changes) in &synced {
+        report.hook_failures.extend(crate::sync::hooks::run_repo_hooks(&root, &porttree.repositories[repo_name], *changes));
+    }
+    let synced_repos: Vec<(&crate::porttree::Repository, bool)> = synced.iter()
+        .map(|(repo_name, changes)| (&porttree.repositories[repo_name], *changes))
+        .collect();
+    if crate::sync::hooks::postsync_due(&synced_repos) {
+        report.hook_failures.extend(crate::sync::hooks::run_hooks(&root.join(crate::sync::hooks::POSTSYNC_DIR), &[]));
+    }
+    report.hooks_ok = report.hook_failures.is_empty();
+
+    progress(SyncEvent::UpdatingSearchIndex);
+    if let Err(e) = SearchIndex::build(&porttree).save(&SearchIndex::path(&porttree.root)) {
+        progress(SyncEvent::Warning(&format!("Failed to update search index: {}", e)));
+    }
+
+    let arch = match Config::new(&porttree.root).await {
+        Ok(config) => config.get_var("ARCH").cloned().unwrap_or_default(),
+        Err(_) => String::new(),
+    };
+    let news_manager = NewsManager::new(&porttree.root).with_repositories(&porttree);
+    if let Err(e) = news_manager.update(&NewsSystem::new(&porttree.root, &porttree.root, &arch)) {
+        progress(SyncEvent::Warning(&format!("Failed to update news: {}", e)));
+    }
+    report.unread_news = news_manager.unread_counts().unwrap_or_default();
+
+    Ok(report)
+}
+
+/// The result of regenerating a repository's md5-cache
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheRegen {
+    /// (cpv, error) of the entries that failed
+    pub failures: Vec<(String, String)>,
+    /// Stale entries removed
+    pub pruned: usize,
+}
+
+/// Regenerate the missing and stale entries of a repository's md5-cache and prune the
+/// ones without an ebuild; `progress` gets (done, total), starting at 0
+pub async fn regen_cache(cache: Md5Cache, jobs: usize, mut progress: impl FnMut(usize, usize)) -> CacheRegen {
+    let cpvs = cache.list_ebuilds();
+    let total = cpvs.len();
+    progress(0, total);
+
+    let cache = std::sync::Arc::new(cache);
+    let mut pending = cpvs.into_iter();
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut failures = Vec::new();
+    let mut done = 0;
+    loop {
+        while tasks.len() < jobs.max(1) {
+            let Some(cpv) = pending.next() else {
+                break;
+            };
+            let cache = cache.clone();
+            tasks.spawn_blocking(move || {
+                let result = cache.regen(&cpv).map(|_| ());
+                (cpv, result)
+            });
+        }
+        let Some(task_result) = tasks.join_next().await else {
+            break;
+        };
+        done += 1;
+        match task_result {
+            Ok((_, Ok(()))) => {}
+            Ok((cpv, Err(e))) => failures.push((cpv, e.to_string())),
+            Err(e) => failures.push((cache.location.display().to_string(), e.to_string())),
+        }
+        if done % 100 == 0 || done == total {
+            progress(done, total);
+        }
+    }
+
+    CacheRegen { failures, pruned: cache.prune() }
+}
+
+/// The packages a merge builds or installs
+#[derive(Debug, Clone, Default)]
+pub struct MergePlan {
+    /// (graph key, cpv) in merge order
+    pub merge_list: Vec<(String, String)>,
+    /// How each package shows in the merge list, by graph key
+    pub entries: HashMap<String, MergeEntry>,
+    /// Installed packages soft blocked by a merged one
+    pub blockers: Vec<BlockerEntry>,
+    /// The installed cpvs unmerged right after each merged cpv
+    pub blocker_uninstalls: HashMap<String, Vec<String>>,
+    /// PROPERTIES=interactive packages, merged one at a time
+    pub interactive: Vec<String>,
+}
+
+impl MergePlan {
+    pub fn cpvs(&self) -> Vec<String> {
+        self.merge_list.iter().map(|(_, cpv)| cpv.clone()).collect()
+    }
+}
+
+/// What the resolver found on the way to a plan
+#[derive(Debug, Clone, Default)]
+pub struct ResolutionReport {
+    /// (cp, dependencies, blockers) of each target
+    pub dependencies: Vec<(String, usize, usize)>,
+    /// Graph keys in merge order
+    pub resolved: Vec<String>,
+    /// The providers chosen for || ( ) groups, by the package depending on them
+    pub any_of_choices: Vec<(String, Vec<String>)>,
+    /// Problems that did not stop resolution
+    pub warnings: Vec<String>,
+}
+
+/// A resolved merge: its report and plan, with what carrying it out needs
+pub struct Resolution {
+    pub report: ResolutionReport,
+    pub plan: MergePlan,
+    pub depgraph: DepGraph,
+    /// Graph keys of the targets
+    pub target_keys: Vec<String>,
+    pub config: Config,
+    pub porttree: PortTree,
+    pub merger: Merger,
+}
+
+/// Resolve `packages` (atoms and @sets) into a merge plan as emerge `options` ask
+pub async fn resolve(packages: &[String], options: &EmergeOptions) -> Result<Resolution> {
+    let (root, config_root, with_bdeps) = (options.root.as_str(), options.config_root.as_str(), options.with_bdeps);
+    let mut report = ResolutionReport::default();
+
+    // Resolve sets (@world, @system, etc.) to individual packages
+    let resolved_packages = sets::resolve_targets(packages, root).await
+        .map_err(|e| Error::InvalidAtom(format!("Failed to resolve package sets: {}", e)))?;
+
+    let atoms = resolved_packages.iter()
+        .map(|pkg| Atom::new(pkg).map_err(|e| Error::InvalidAtom(format!("Invalid atom '{}': {}", pkg, e))))
+        .collect::<Result<Vec<Atom>>>()?;
+
+    // Create dependency graph with USE flags
+    let config = match Config::new(config_root).await.map(|c| c.with_roots(root, &options.sysroot)) {
+        Ok(c) => c,
+        Err(_) => Config {
+            root: config_root.to_string(),
+            target_root: root.to_string(),
+            sysroot: options.sysroot.clone(),
+            make_conf: HashMap::new(),
+            profile_settings: crate::profile::ProfileSettings::default(),
+            use_flags: vec![],
+            accept_keywords: vec![],
+            features: vec![],
+            package_use: HashMap::new(),
+            package_keywords: HashMap::new(),
+            package_mask: std::collections::HashSet::new(),
+            package_unmask: std::collections::HashSet::new(),
+            package_env: HashMap::new(),
+            sets_conf: HashMap::new(),
+            binhost: vec![],
+            binhost_mirrors: vec![],
+            pkgdir: Path::new(config_root).join("usr/portage/packages").to_string_lossy().to_string(),
+            binpkg_format: crate::bintree::BinPkgFormat::default(),
+            binrepos: vec![],
+        },
+    };
+    let use_flags = config.get_use_flags_map();
+    let (exclude, reinstall) = filter_atoms(options).map_err(Error::InvalidAtom)?;
+    let mut depgraph = DepGraph::with_use_flags(use_flags)
+        .with_exclude(exclude)
+        .with_reinstall(reinstall)
+        .with_installed(crate::vartree::VarTree::new(root).list_packages())
+        .with_backtrack(options.backtrack)
+        .with_break_installed_build_cycles(!options.emptytree);
+    let with_test_deps = options.with_test_deps || config.features.iter().any(|f| f == "test");
+
+    // Initialize portage tree for finding ebuilds
+    let mut porttree = PortTree::new(config_root);
+    porttree.scan_repositories();
+
+    let mut target_keys = Vec::new();
+    let mut any_of_keys = Vec::new();
+    for atom in &atoms {
+        let (mut deps, dep_blockers, any_of) = match get_package_dependencies(atom, &porttree, with_bdeps, with_test_deps).await {
+            // --nodeps: merge the target alone, still honoring its blockers
+            Ok((_, blockers, _)) if options.nodeps => (vec![], blockers, vec![]),
+            Ok((deps, blockers, any_of)) => {
+                report.dependencies.push((atom.cp(), deps.len(), blockers.len()));
+                (deps, blockers, any_of)
+            }
+            Err(e) => {
+                report.warnings.push(format!("Failed to get dependencies for {}: {}", atom.cp(), e));
+                // Continue with empty dependencies rather than failing completely
+                (vec![], vec![], vec![])
+            }
+        };
+        let any_of = settle_any_of(&mut deps, any_of, &porttree, &config.profile_settings.virtuals);
+
+        // Convert dep::Atom blockers to atom::Atom, keeping the version and ! or !!. One that
+        // doesn't convert is dropped: without its version it would block every version
+        let mut blockers: Vec<crate::atom::Atom> = Vec::new();
+        for dep_atom in dep_blockers {
+            let versioned = format!("{}{}", dep_atom.op.as_deref().unwrap_or_default(), dep_atom.cpv);
+            match crate::atom::Atom::new(&versioned) {
+                Ok(mut atom) => {
+                    atom.slot = dep_atom.slot;
+                    atom.blocker = dep_atom.blocker;
+                    blockers.push(atom);
+                }
+                Err(e) => report.warnings.push(format!("Ignoring blocker {} of {}: {}", versioned, atom.cp(), e)),
+            }
+        }
+
+        let key = depgraph.add_target(atom, deps, blockers);
+        for group in any_of {
+            any_of_keys.extend(depgraph.add_any_of(&key, group));
+        }
+        target_keys.push(key);
+    }
+
+    // --emptytree: pull in the complete dependency tree, not just direct dependencies
+    if options.emptytree {
+        let mut expanded: std::collections::HashSet<String> = target_keys.iter().cloned().collect();
+        let mut queue: std::collections::VecDeque<String> = target_keys.iter()
+            .flat_map(|key| depgraph.edges.get(&depgraph.current_key(key)).cloned().unwrap_or_default())
+            .chain(any_of_keys)
+            .collect();
+        while let Some(key) = queue.pop_front() {
+            if !expanded.insert(key.clone()) || depgraph.is_excluded(&key) {
+                continue;
+            }
+            let Some(atom) = depgraph.nodes.get(&key).map(|node| node.atom.clone()) else {
+                continue;
+            };
+            if let Ok((mut deps, _, any_of)) = get_package_dependencies(&atom, &porttree, with_bdeps, with_test_deps).await {
+                let any_of = settle_any_of(&mut deps, any_of, &porttree, &config.profile_settings.virtuals);
+                queue.extend(depgraph.add_dependencies(&key, deps));
+                for group in any_of {
+                    queue.extend(depgraph.add_any_of(&key, group));
+                }
+            }
+        }
+    }
+
+    // Targets added without their slot may have become their "cp:slot" nodes since
+    let target_keys: Vec<String> = target_keys.iter().map(|key| depgraph.current_key(key)).collect();
+    let merger = configured_merger(root, &config, options);
+
+    // The versions the resolver may choose from, so backtracking can fall back to older ones
+    let cps: std::collections::BTreeSet<String> = depgraph.nodes.keys().map(|key| DepGraph::key_cp(key).to_string()).collect();
+    for cp in cps {
+        if let Ok(versions) = merger.visible_versions(&cp, &porttree).await
+            && !versions.is_empty()
+        {
+            depgraph.available.insert(cp, versions);
+        }
+    }
+    // A live version asked for by name (=cat/pkg-9999) is built without the ** keyword
+    // that makes live ebuilds visible otherwise
+    for atom in atoms.iter().filter(|atom| atom.op == crate::atom::Operator::Equal) {
+        let Some(version) = &atom.version else {
+            continue;
+        };
+        let cpv = format!("{}-{}", atom.cp(), version);
+        let live = porttree.get_ebuild_path(&cpv)
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .is_some_and(|content| crate::sets::is_live_ebuild(&content));
+        if !live {
+            continue;
+        }
+        let versions = depgraph.available.entry(atom.cp()).or_default();
+        if !versions.contains(&cpv) {
+            versions.insert(0, cpv);
+        }
+    }
+
+    // Resolve dependencies
+    let result = depgraph.resolve(&target_keys)
+        .map_err(|e| Error::Resolution(format!("Dependency resolution failed: {}", e)))?;
+    if !result.slot_conflicts.is_empty() || !result.blocks.is_empty() {
+        return Err(Error::Resolution(depgraph.explain_conflicts(&result)));
+    }
+    if !result.circular.is_empty() {
+        return Err(Error::Resolution(crate::depgraph::format_cycles(&result.circular).trim_end().to_string()));
+    }
+    report.any_of_choices = result.any_of_choices.clone();
+    report.resolved = result.resolved.clone();
+
+    // Check if dependencies are satisfied; --nodeps merges regardless
+    if !options.nodeps {
+        let mut checker = DepChecker::new(root);
+        let check_result = checker.check_dependencies(&atoms).await
+            .map_err(|e| Error::Resolution(format!("Dependency check failed: {}", e)))?;
+        if !check_result.missing.is_empty() {
+            return Err(Error::Resolution(format!("Missing dependencies: {:?}", check_result.missing)));
+        }
+        if !check_result.conflicts.is_empty() {
+            return Err(Error::Resolution(format!("Conflicts: {:?}", check_result.conflicts)));
+        }
+    }
+
+    let mut plan = MergePlan::default();
+    // Changes to package.* files that would unmask the plan (--autounmask-write)
+    let mut autounmask = Autounmask::new();
+    let mut masked_reports = String::new();
+    let global_use = config.get_use_flags_map();
+    let distdir = config.get_var("DISTDIR").map(std::path::PathBuf::from).unwrap_or_else(crate::doebuild::default_distdir);
+    // mirror:// resolution for the upstream URLs of distfiles the Manifest doesn't list
+    let fetch_manager = crate::fetch::FetchManager::new(&distdir, vec![])
+        .with_thirdpartymirrors(&porttree.repository_locations());
+    let mut unlisted_distfiles: Vec<(String, Vec<String>)> = Vec::new();
+
+    for key in &result.resolved {
+        // --onlydeps: the targets only contribute their dependencies
+        if options.onlydeps && target_keys.contains(key) {
+            continue;
+        }
+        let cp = DepGraph::key_cp(key);
+        let best = match result.selected.get(key) {
+            Some(cpv) => Ok(Some(cpv.clone())),
+            None => merger.find_best_version_with_porttree(cp, Some(&porttree)).await,
+        };
+        match best {
+            Ok(Some(cpv)) => {
+                let ebuild_content = porttree.get_ebuild_path(&cpv)
+                    .and_then(|path| std::fs::read_to_string(path).ok())
+                    .unwrap_or_default();
+                let iuse = crate::autounmask::parse_iuse_defaults(&ebuild_content);
+                let flags = crate::autounmask::effective_use(&iuse, &global_use, &config.package_use_for(&cpv));
+
+                // USE dependencies ([flag], [flag?]) must hold for the selected version,
+                // conditional ones per the flags of the version pulling it in
+                let parent_use = |parent: &str| match result.selected.get(parent) {
+                    Some(parent_cpv) => crate::autounmask::effective_use(&HashMap::new(), &global_use, &config.package_use_for(parent_cpv)),
+                    None => global_use.clone(),
+                };
+                for (flag, enabled, parent) in depgraph.use_requirements_with(key, parent_use) {
+                    let changes = crate::autounmask::use_changes_needed(&[(flag, enabled)], &flags);
+                    autounmask.add_use(&cpv, changes, &parent);
+                }
+
+                // So must the ebuild's own REQUIRED_USE
+                if let Some(required_use) = Ebuild::parse_metadata(&ebuild_content).ok().and_then(|m| m.required_use)
+                    && let Some(violations) = required_use_violations(&cpv, &required_use, &flags, &mut report.warnings)
+                {
+                    return Err(Error::Resolution(violations));
+                }
+
+                // Installed dependencies are only rebuilt with --emptytree or --reinstall-atoms
+                if !options.emptytree && !target_keys.contains(key) && !depgraph.is_reinstall(key) && merger.vartree.is_installed(&cpv) {
+                    continue;
+                }
+                let mut entry = merge_entry(&cpv, &ebuild_content, &iuse, &flags, &merger.vartree);
+                entry.use_expand = config.use_expand();
+                entry.target = target_keys.contains(key);
+                if let Some(path) = porttree.get_ebuild_path(&cpv) {
+                    let (size, unlisted) = download_size(Path::new(&path), &flags, &distdir, &fetch_manager);
+                    entry.download_size = size;
+                    unlisted_distfiles.extend(unlisted.into_iter().map(|urls| (key.clone(), urls)));
+                }
+                if merger.binpkg_policy.uses_binaries()
+                    && merger.binary_candidates(cp).await.is_ok_and(|found| found.iter().any(|c| c.cpv == cpv))
+                {
+                    entry.pkg_type = crate::output::PkgType::Binary;
+                }
+                plan.entries.insert(key.clone(), entry);
+                if Ebuild::parse_metadata_with_use(&ebuild_content, &flags).is_ok_and(|m| m.properties.iter().any(|p| p == "interactive")) {
+                    plan.interactive.push(cpv.clone());
+                }
+                plan.merge_list.push((key.clone(), cpv));
+            }
+            Ok(None) => {
+                let masked = merger.keyword_masked_versions(cp, &porttree).await;
+                if masked.is_empty() {
+                    return Err(Error::Resolution(format!("No version found for package: {}", cp)));
+                }
+                masked_reports.push_str(&keyword_masked_report(cp, &masked));
+                autounmask.add_keyword(&masked[0].cpv, &masked[0].suggested, &required_by(&depgraph, key, &target_keys));
+            }
+            Err(e) => return Err(Error::Resolution(format!("Failed to find version for {}: {}", cp, e))),
+        }
+    }
+
+    // --remote-sizes: servers size what the Manifest doesn't
+    if options.remote_sizes && !unlisted_distfiles.is_empty() {
+        let urls = unlisted_distfiles.iter().map(|(_, urls)| urls.clone()).collect();
+        let sizes = crate::fetch::remote_sizes(urls, options.fetch_jobs).await;
+        for ((key, _), size) in unlisted_distfiles.iter().zip(sizes) {
+            if let Some(entry) = plan.entries.get_mut(key)
+                && let Some(size) = size
+            {
+                entry.download_size = Some(entry.download_size.unwrap_or(0) + size);
+            }
+        }
+    }
+
+    // Room for the downloads and the builds
+    let tmpdir = config.get_var("PORTAGE_TMPDIR").map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    let sizes: Vec<u64> = plan.entries.values().filter_map(|entry| entry.download_size).collect();
+    report.warnings.extend(space_warnings(&sizes, &distdir, &tmpdir));
+
+    // Licenses that still need accepting
+    let license_manager = license_manager(config_root, &config, &porttree);
+    for (key, cpv) in &plan.merge_list {
+        let Some(license_str) = porttree.get_metadata(cpv).await.and_then(|m| m.get("LICENSE").cloned()) else {
+            continue;
+        };
+        match license_manager.missing_licenses(cpv, &license_str) {
+            Ok(missing) => autounmask.add_license(cpv, missing, &required_by(&depgraph, key, &target_keys)),
+            Err(e) => report.warnings.push(format!("Failed to check license of {}: {}", cpv, e)),
+        }
+    }
+
+    if !autounmask.is_empty() {
+        let explanation = format!("{}{}", masked_reports, autounmask.format());
+        return Err(Error::Autounmask { explanation, changes: autounmask });
+    }
+
+    // Check for masked packages
+    let mask_manager = crate::mask::MaskManager::new(config_root, config.accept_keywords.clone())
+        .with_keyword_filter(merger.keyword_filter.clone())
+        .with_repositories(porttree.repository_locations());
+    for (_, cpv) in &plan.merge_list {
+        let atom = Atom::new(cpv).map_err(|e| Error::Resolution(format!("Invalid package atom '{}': {}", cpv, e)))?;
+        match mask_manager.is_masked(&atom).await {
+            Ok(Some(reason)) => return Err(Error::Resolution(format!("Package {} is masked: {}", cpv, reason))),
+            Ok(None) => {}
+            Err(e) => return Err(Error::Resolution(format!("Mask check failed for {}: {}", cpv, e))),
+        }
+    }
+
+    // Installed packages soft blocked by a merged one are unmerged right after it
+    for block in &result.uninstalls {
+        let Some((_, holder)) = plan.merge_list.iter().find(|(key, _)| *key == block.holder) else {
+            continue;
+        };
+        let atom = crate::atom::Atom { blocker: None, ..block.blocker.clone() };
+        plan.blockers.push(BlockerEntry { atom: atom.to_string(), holder: holder.clone(), hard: false });
+        plan.blocker_uninstalls.entry(holder.clone()).or_default().push(block.blocked.clone());
+    }
+
+    Ok(Resolution { report, plan, depgraph, target_keys, config, porttree, merger })
+}
+
+/// The license manager of `config`, accepting its ACCEPT_LICENSE
+pub(crate) fn license_manager(config_root: &str, config: &Config, porttree: &PortTree) -> crate::license::LicenseManager {
+    let license_manager = crate::license::LicenseManager::new(config_root)
+        .with_repositories(porttree.repository_locations());
+    match config.accept_license() {
+        Some(accept_license) => license_manager.with_accept_license(&accept_license),
+        None => license_manager,
+    }
+}
+
+/// The merger for a run with these options and configuration
+pub(crate) fn configured_merger(root: &str, config: &Config, options: &EmergeOptions) -> Merger {
+    Merger::with_binhost(root, config.binhost.clone(), config.binhost_mirrors.clone())
+        .with_binrepos(config.binrepos.clone())
+        .with_pkgdir(&config.pkgdir)
+        .with_config_protect(crate::configprotect::ConfigProtect::from_config(config))
+        .with_keyword_filter(crate::keywords::KeywordFilter::from_config(config))
+        .with_binpkg_policy(options.binpkg_policy)
+        .with_job_control(options.jobs, options.load_average)
+        .with_use_config(config.use_config())
+        .with_nolock(options.nolock)
+        .with_config_roots(&options.config_root, &options.sysroot)
+}
+
+/// The atoms given to --exclude and --reinstall-atoms
+pub(crate) fn filter_atoms(options: &EmergeOptions) -> std::result::Result<(Vec<Atom>, Vec<Atom>), String> {
+    let parse = |option: &str, values: &[String]| -> std::result::Result<Vec<Atom>, String> {
+        values.iter()
+            .map(|value| Atom::new(value).map_err(|e| format!("!!! Invalid atom for {}: '{}' ({})", option, value, e)))
+            .collect()
+    };
+    Ok((parse("--exclude", &options.exclude)?, parse("--reinstall-atoms", &options.reinstall_atoms)?))
+}
+
+/// Explain that every version of `cp` is hidden by KEYWORDS
+fn keyword_masked_report(cp: &str, masked: &[crate::keywords::KeywordMasked]) -> String {
+    let mut report = format!("\n!!! All ebuilds that could satisfy \"{}\" have been masked.\n", cp);
+    report.push_str("!!! One of the following masked packages is required to complete your request:\n");
+    for version in masked {
+        report.push_str(&format!("- {} (masked by: {})\n", version.cpv, version.reason()));
+    }
+    report
+}
+
+/// REQUIRED_USE violations under the effective flags, explained like emerge does; None
+/// when it holds
+fn required_use_violations(cpv: &str, required_use: &str, flags: &HashMap<String, bool>, warnings: &mut Vec<String>) -> Option<String> {
+    let expr = match crate::required_use::RequiredUse::parse(required_use) {
+        Ok(expr) => expr,
+        Err(e) => {
+            warnings.push(format!("Invalid REQUIRED_USE in {}: {}", cpv, e));
+            return None;
+        }
+    };
+    let violations = expr.violations(flags);
+    if violations.is_empty() {
+        return None;
+    }
+
+    let enabled: Vec<&str> = expr.flag_names().into_iter()
+        .filter(|flag| flags.get(*flag).copied().unwrap_or(false))
+        .collect();
+    let mut report = format!("\n!!! The ebuild selected to satisfy \"{}\" has unmet requirements.\n", cpv);
+    report.push_str(&format!("- {} USE=\"{}\"\n", cpv, enabled.join(" ")));
+    report.push_str("\n  The following REQUIRED_USE flag constraints are unsatisfied:\n");
+    for violation in &violations {
+        report.push_str(&format!("    {}\n", violation));
+    }
+    report.push_str("\n  The above constraints are a subset of the following complete expression:\n");
+    report.push_str(&format!("    {}\n", required_use));
+    report.push_str("\n  Possible USE changes (see \"package.use\" in the portage(5) man page):");
+    for violation in &violations {
+        let alternatives: Vec<String> = violation.suggestions(flags).iter()
+            .map(|changes| format!("USE=\"{}\"", changes.join(" ")))
+            .collect();
+        report.push_str(&format!("\n    {}", alternatives.join("  or  ")));
+    }
+    Some(report)
+}
+
+/// How a package to merge shows in the merge list: what it replaces and its USE flags
+pub(crate) fn merge_entry(
+    cpv: &str,
+    ebuild_content: &str,
+    iuse: &HashMap<String, bool>,
+    flags: &HashMap<String, bool>,
+    vartree: &crate::vartree::VarTree,
+) -> MergeEntry {
+    let mut entry = MergeEntry::new(crate::output::PkgType::Ebuild, cpv);
+    let metadata = Ebuild::parse_metadata(ebuild_content).ok();
+    let slot = metadata.as_ref().map(|m| m.slot.split('/').next().unwrap_or("0").to_string());
+    entry.fetch_restrict = metadata.as_ref().is_some_and(|m| m.restrict.iter().any(|r| r == "fetch"));
+
+    let installed = crate::versions::cpv_getkey(cpv).map(|cp| vartree.installed_versions(&cp)).unwrap_or_default();
+    let same_slot = installed.iter().find(|installed| match (&slot, vartree.slot(installed)) {
+        (Some(slot), Some(installed_slot)) => installed_slot.split('/').next() == Some(slot.as_str()),
+        _ => true,
+    });
+    entry.new_slot = same_slot.is_none() && !installed.is_empty();
+    entry.installed = same_slot.and_then(|installed| crate::versions::cpv_getversion(installed));
+
+    let mut use_flags: Vec<(String, bool)> = iuse.keys().map(|flag| (flag.clone(), flags.get(flag).copied().unwrap_or(false))).collect();
+    use_flags.sort();
+    entry.use_flags = use_flags;
+    entry.built_use = same_slot.and_then(|installed| Some(crate::output::BuiltUse {
+        iuse: vartree.iuse(installed)?,
+        enabled: vartree.use_flags(installed).unwrap_or_default(),
+    }));
+    entry
+}
+
+/// Bytes of the distfiles the ebuild at `path` fetches under `flags` that are not in
+/// `distdir` yet, per its Manifest (None without one), and the upstream URLs of each
+/// missing distfile the Manifest doesn't list
+fn download_size(path: &Path, flags: &HashMap<String, bool>, distdir: &Path, fetch_manager: &crate::fetch::FetchManager) -> (Option<u64>, Vec<Vec<String>>) {
+    let Ok(ebuild) = Ebuild::from_path_with_use(path, flags) else {
+        return (None, Vec::new());
+    };
+    let dist_entries = path.parent().and_then(|dir| crate::manifest::load_dist_entries(dir).ok());
+    let mut requests: std::collections::BTreeMap<String, Vec<String>> = std::collections::BTreeMap::new();
+    for uri in &ebuild.metadata.src_uri {
+        let request = crate::doebuild::fetch_request(&ebuild, uri);
+        requests.entry(request.filename).or_default().extend(fetch_manager.expand_uri(uri));
+    }
+    let fetch_restricted = ebuild.metadata.restrict.iter().any(|r| r == "fetch");
+    let mut size = 0;
+    let mut unlisted = Vec::new();
+    for (filename, urls) in requests {
+        match dist_entries.as_ref().and_then(|entries| entries.get(&filename)) {
+            Some(entry) => size += remaining_size(distdir, &filename, entry.size),
+            None if distdir.join(&filename).exists() => {}
+            None if !fetch_restricted && !urls.is_empty() => unlisted.push(urls),
+            None => {}
+        }
+    }
+    (dist_entries.map(|_| size), unlisted)
+}
+
+/// What is left to download of a distfile the Manifest says is `size` bytes: nothing when
+/// DISTDIR has all of it, the rest of a shorter file or partial download, else all of it
+fn remaining_size(distdir: &Path, filename: &str, size: u64) -> u64 {
+    let len = |path: std::path::PathBuf| std::fs::metadata(path).ok().map(|m| m.len());
+    let have = len(distdir.join(filename)).or_else(|| len(distdir.join(format!("{}.__download__", filename))));
+    match have {
+        Some(have) if have <= size => size - have,
+        _ => size,
+    }
+}
+
+/// Unpacked sources take about this many times the size of their archives
+const UNPACK_RATIO: u64 = 4;
+
+/// Warnings for filesystems short of room: DISTDIR for all the downloads, PORTAGE_TMPDIR
+/// to unpack the largest package's; both together when they share a filesystem
+pub fn space_warnings(sizes: &[u64], distdir: &Path, tmpdir: &Path) -> Vec<String> {
+    use std::os::unix::fs::MetadataExt;
+    let downloads: u64 = sizes.iter().sum();
+    let unpacked = sizes.iter().max().copied().unwrap_or(0).saturating_mul(UNPACK_RATIO);
+    let device = |dir: &Path| std::fs::metadata(crate::util::path::first_existing(dir)).ok().map(|m| m.dev());
+    let shared = device(distdir).is_some() && device(distdir) == device(tmpdir);
+    let checks = if shared {
+        vec![("DISTDIR and PORTAGE_TMPDIR", distdir, downloads.saturating_add(unpacked))]
+    } else {
+        vec![("DISTDIR", distdir, downloads), ("PORTAGE_TMPDIR", tmpdir, unpacked)]
+    };
+    let mut warnings = Vec::new();
+    for (name, dir, needed) in checks {
+        if let Some(available) = crate::util::path::available_space(dir)
+            && needed > available
+        {
+            warnings.push(format!("Not enough space in {} ({}): {} needed, {} available",
+                name, dir.display(), crate::output::format_size(needed), crate::output::format_size(available)));
+        }
+    }
+    warnings
+}
+
+/// "# required by" label for a graph node: the argument itself or the first package pulling it in
+fn required_by(depgraph: &DepGraph, key: &str, target_keys: &[String]) -> String {
+    if target_keys.iter().any(|k| k == key) {
+        return format!("{} (argument)", DepGraph::key_cp(key));
+    }
+    depgraph.requests.get(key)
+        .and_then(|requests| requests.first())
+        .map(|(_, parent)| parent.clone())
+        .unwrap_or_else(|| DepGraph::key_cp(key).to_string())
+}
+
+/// A package's dependencies: plain ones, blockers, and the alternatives of each || ( ) group
+pub(crate) type PackageDeps = (Vec<DepNode>, Vec<crate::dep::Atom>, Vec<Vec<Vec<DepNode>>>);
+
+pub(crate) async fn get_package_dependencies(
+    atom: &crate::atom::Atom,
+    porttree: &PortTree,
+    with_bdeps: bool,
+    with_test_deps: bool,
+) -> std::result::Result<PackageDeps, Box<dyn std::error::Error + Send + Sync>> {
+    let cpv = format!("{}/{}", atom.cp(), atom.version.as_deref().unwrap_or("1.0"));
+
+    // First, try to get dependencies from binary package if available
+    let bintree = crate::bintree::BinTree::new("/");
+    if let Ok(Some(bin_info)) = bintree.parse_binpkg(&cpv).await {
+        return parse_binary_dependencies(&bin_info, with_bdeps);
+    }
+
+    // Fall back to ebuild-based dependency resolution
+    get_ebuild_dependencies(atom, porttree, with_bdeps, with_test_deps).await
+}
+
+async fn get_ebuild_dependencies(
+    atom: &crate::atom::Atom,
+    porttree: &PortTree,
+    with_bdeps: bool,
+    with_test_deps: bool,
+) -> std::result::Result<PackageDeps, Box<dyn std::error::Error + Send + Sync>> {
+    // Use system portage tree
+    let cpv = format!("{}/{}", atom.cp(), atom.version.as_deref().unwrap_or("1.0"));
+    let ebuild_path = if let Some(path_str) = porttree.get_ebuild_path(&cpv) {
+        std::path::PathBuf::from(path_str)
+    } else {
+        return Err(format!("Ebuild not found for {}", atom.cp()).into());
+    };
+
+    if !ebuild_path.exists() {
+        return Err(format!("Ebuild file not found: {}", ebuild_path.display()).into());
+    }
+
+    let content = tokio::fs::read_to_string(&ebuild_path).await?;
+    let metadata = Ebuild::parse_metadata_with_use(&content, &std::collections::HashMap::new())?;
+
+    let mut deps = Vec::new();
+    let mut blockers = Vec::new();
+
+    // Process dependencies and separate blockers
+    // Only include build dependencies if with_bdeps is true
+    if with_bdeps {
+        for dep_atom in &metadata.depend {
+            if dep_atom.blocker.is_some() {
+                blockers.push(dep_atom.clone());
+            } else {
+                deps.push(create_dep_node(dep_atom, DepType::Build));
+            }
+        }
+    }
+
+    for dep_atom in &metadata.rdepend {
+        if dep_atom.blocker.is_some() {
+            blockers.push(dep_atom.clone());
+        } else {
+            deps.push(create_dep_node(dep_atom, DepType::Runtime));
+        }
+    }
+
+    for dep_atom in &metadata.pdepend {
+        if dep_atom.blocker.is_some() {
+            blockers.push(dep_atom.clone());
+        } else {
+            deps.push(create_dep_node(dep_atom, DepType::Post));
+        }
+    }
+
+    // Dependencies that only appear with USE=test are needed to run src_test
+    if with_test_deps {
+        let test_use = std::collections::HashMap::from([("test".to_string(), true)]);
+        let test_metadata = Ebuild::parse_metadata_with_use(&content, &test_use)?;
+        let base: std::collections::HashSet<String> = metadata.depend.iter()
+            .chain(&metadata.rdepend)
+            .map(|a| a.to_string())
+            .collect();
+        for dep_atom in test_metadata.depend.iter().chain(&test_metadata.rdepend) {
+            if dep_atom.blocker.is_none() && !base.contains(&dep_atom.to_string()) {
+                deps.push(create_dep_node(dep_atom, DepType::Build));
+            }
+        }
+    }
+
+    let any_of = metadata.any_of.iter()
+        .filter(|(dep_type, _)| with_bdeps || *dep_type != DepType::Build)
+        .map(|(dep_type, group)| any_of_nodes(group, dep_type))
+        .collect();
+
+    Ok((deps, blockers, any_of))
+}
+
+/// The alternatives of a || ( ) group as dependency nodes
+fn any_of_nodes(group: &crate::dep::AnyOf, dep_type: &DepType) -> Vec<Vec<DepNode>> {
+    group.iter()
+        .map(|choice| choice.iter().map(|atom| create_dep_node(atom, dep_type.clone())).collect())
+        .collect()
+}
+
+/// Old-style virtuals no repository has an ebuild for become || ( ) groups of the profile's
+/// providers; alternatives no repository has are dropped while another one is available
+fn settle_any_of(
+    deps: &mut Vec<DepNode>,
+    mut any_of: Vec<Vec<Vec<DepNode>>>,
+    porttree: &PortTree,
+    virtuals: &HashMap<String, Vec<String>>,
+) -> Vec<Vec<Vec<DepNode>>> {
+    deps.retain(|dep| {
+        let cp = dep.atom.cp();
+        match virtuals.get(&cp) {
+            Some(providers) if !porttree.has_package(&cp) => {
+                any_of.push(providers.iter()
+                    .filter_map(|provider| crate::atom::Atom::new(provider).ok())
+                    .map(|atom| vec![DepNode { atom, slot: None, subslot: None, slot_operator: None, ..dep.clone() }])
+                    .collect());
+                false
+            }
+            _ => true,
+        }
+    });
+    let available = |choice: &Vec<DepNode>| choice.iter().all(|dep| porttree.has_package(&dep.atom.cp()));
+    for group in &mut any_of {
+        if group.iter().any(available) {
+            group.retain(available);
+        }
+    }
+    any_of
+}
+
+fn parse_binary_dependencies(
+    bin_info: &crate::bintree::BinPkgInfo,
+    with_bdeps: bool,
+) -> std::result::Result<PackageDeps, Box<dyn std::error::Error + Send + Sync>> {
+    let mut deps = Vec::new();
+    let mut blockers = Vec::new();
+    let mut any_of = Vec::new();
+
+    // Binary packages typically only have runtime dependencies
+    // Check for DEPEND and RDEPEND in the XPAK metadata
+    // Only include build dependencies if with_bdeps is true
+    if with_bdeps
+        && let Some(depend_str) = bin_info.metadata.get("DEPEND")
+        && !depend_str.trim().is_empty()
+    {
+        let (depend_atoms, groups) = crate::dep::parse_dependency_groups(depend_str, &std::collections::HashMap::new())?;
+        any_of.extend(groups.iter().map(|group| any_of_nodes(group, &DepType::Build)));
+        for dep_atom in depend_atoms {
+            if dep_atom.blocker.is_some() {
+                blockers.push(dep_atom);
+            } else {
+                deps.push(create_dep_node(&dep_atom, DepType::Build));
+            }
+        }
+    }
+
+    if let Some(rdepend_str) = bin_info.metadata.get("RDEPEND") && !rdepend_str.trim().is_empty() {
+        let (rdepend_atoms, groups) = crate::dep::parse_dependency_groups(rdepend_str, &std::collections::HashMap::new())?;
+        any_of.extend(groups.iter().map(|group| any_of_nodes(group, &DepType::Runtime)));
+        for dep_atom in rdepend_atoms {
+            if dep_atom.blocker.is_some() {
+                blockers.push(dep_atom);
+            } else {
+                deps.push(create_dep_node(&dep_atom, DepType::Runtime));
+            }
+        }
+    }
+
+    Ok((deps, blockers, any_of))
+}
+
+fn create_dep_node(dep_atom: &crate::dep::Atom, dep_type: DepType) -> DepNode {
+    let atom_str = format!("{}{}", dep_atom.op.as_deref().unwrap_or(""), dep_atom.cpv);
+    let atom = crate::atom::Atom::new(&atom_str).map(|mut atom| {
+        atom.slot = dep_atom.slot.clone();
+        atom.subslot = dep_atom.sub_slot.clone();
+        atom.use_deps = dep_atom.use_deps.clone();
+        atom
+    }).unwrap_or_else(|_| crate::atom::Atom {
+        category: dep_atom
+            .cp()
+            .split('/')
+            .next()
+            .unwrap_or("unknown")
+            .to_string(),
+        package: dep_atom
+            .cp()
+            .split('/')
+            .nth(1)
+            .unwrap_or(&dep_atom.cp())
+            .to_string(),
+        version: None,
+        op: crate::atom::Operator::None,
+        slot: dep_atom.slot.clone(),
+        subslot: dep_atom.sub_slot.clone(),
+        repo: dep_atom.repo.clone(),
+        use_deps: dep_atom.use_deps.clone(),
+        blocker: dep_atom.blocker.clone(),
+    });
+
+    let blockers = if dep_atom.blocker.is_some() {
+        vec![atom.clone()] // This dependency is a blocker, so this node blocks the atom
+    } else {
+        vec![]
+    };
+
+    DepNode {
+        atom,
+        dep_type,
+        blockers,
+        use_conditional: None, // TODO: handle USE conditionals
+        slot: dep_atom.slot.clone(),
+        subslot: dep_atom.sub_slot.clone(),
+        slot_operator: dep_atom.slot_op.as_deref().and_then(crate::depgraph::SlotOperator::parse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_download_and_space_sizes() {
+        let distdir = TempDir::new().unwrap();
+        fs::write(distdir.path().join("whole.tar.gz"), "1234").unwrap();
+        fs::write(distdir.path().join("short.tar.gz"), "12").unwrap();
+        fs::write(distdir.path().join("partial.tar.gz.__download__"), "123").unwrap();
+        assert_eq!(remaining_size(distdir.path(), "whole.tar.gz", 4), 0);
+        assert_eq!(remaining_size(distdir.path(), "short.tar.gz", 4), 2);
+        assert_eq!(remaining_size(distdir.path(), "partial.tar.gz", 4), 1);
+        assert_eq!(remaining_size(distdir.path(), "whole.tar.gz", 3), 3);
+        assert_eq!(remaining_size(distdir.path(), "missing.tar.gz", 4), 4);
+
+        let tmpdir = TempDir::new().unwrap();
+        assert!(space_warnings(&[0, 1024], distdir.path(), tmpdir.path()).is_empty());
+        let warnings = space_warnings(&[u64::MAX / 8], distdir.path(), &tmpdir.path().join("not/yet"));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Not enough space in DISTDIR and PORTAGE_TMPDIR"), "{}", warnings[0]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_unknown_repository() {
+        let options = SyncOptions { repos: vec!["no-such-repo".to_string()], jobs: 1, ..Default::default() };
+        match sync(&options, |_| {}).await {
+            Err(Error::Sync(msg)) => assert_eq!(msg, "Unknown repository: no-such-repo"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_saves_search_index_under_config_root() {
+        let temp = TempDir::new().unwrap();
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(upstream.join("app-editors/nano")).unwrap();
+        fs::write(upstream.join("app-editors/nano/nano-7.2.ebuild"), "DESCRIPTION=\"GNU GPL'd Pico clone\"\n").unwrap();
+        for args in [&["init", "--quiet"][..], &["add", "."], &["commit", "--quiet", "-m", "init"]] {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .current_dir(&upstream)
+                .env("GIT_AUTHOR_NAME", "dev").env("GIT_AUTHOR_EMAIL", "dev@example.org")
+                .env("GIT_COMMITTER_NAME", "dev").env("GIT_COMMITTER_EMAIL", "dev@example.org")
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let config_root = temp.path().join("root");
+        fs::create_dir_all(config_root.join("etc/portage")).unwrap();
+        fs::write(config_root.join("etc/portage/repos.conf"), format!(
+            "[test]\nlocation = {}\nsync-type = git\nsync-uri = file://{}\nauto-sync = yes\n",
+            temp.path().join("repo").display(), upstream.display(),
+        )).unwrap();
+
+        let options = SyncOptions { config_root: config_root.display().to_string(), ..Default::default() };
+        let report = sync(&options, |_| {}).await.unwrap();
+        assert_eq!(report.repos.len(), 1);
+        assert!(report.repos[0].succeeded(), "{:?}", report.repos[0]);
+
+        let index = SearchIndex::load(&SearchIndex::path(&options.config_root)).unwrap();
+        assert!(index.entries.iter().any(|e| e.name == "nano"));
+    }
+
+    #[test]
+    fn test_sync_report() {
+        let synced = |name: &str, changes: bool| RepoSync {
+            name: name.to_string(),
+            outcome: SyncOutcome::Synced { message: "ok".to_string(), changes },
+        };
+        let mut report = SyncReport { repos: vec![synced("gentoo", true), synced("guru", false)], hooks_ok: true, hook_failures: Vec::new(), unread_news: Vec::new() };
+        assert!(report.success());
+        assert!(report.repos[0].changes() && !report.repos[1].changes());
+
+        report.repos.push(RepoSync { name: "local".to_string(), outcome: SyncOutcome::Failed { error: "timed out".to_string() } });
+        assert!(!report.success());
+        assert!(!report.repos[2].succeeded());
+        report.repos.pop();
+        report.hooks_ok = false;
+        assert!(!report.success());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repos"][0], serde_json::json!({ "name": "gentoo", "status": "synced", "message": "ok", "changes": true }));
+        assert_eq!(json["hooks_ok"], false);
+    }
+
+    #[test]
+    fn test_settle_any_of() {
+        let temp = TempDir::new().unwrap();
+        for cp in ["app-editors/nano", "app-editors/vim", "virtual/pager"] {
+            fs::create_dir_all(temp.path().join(cp)).unwrap();
+        }
+        let mut porttree = PortTree::new("/");
+        porttree.parse_repos_conf(&format!("[gentoo]\nlocation = {}\n", temp.path().display()));
+        let virtuals = HashMap::from([
+            ("virtual/editor".to_string(), vec!["app-editors/vim".to_string(), "app-editors/nano".to_string()]),
+            ("virtual/pager".to_string(), vec!["sys-apps/less".to_string()]),
+        ]);
+        let node = |atom: &str| create_dep_node(&crate::dep::Atom::new(atom).unwrap(), DepType::Runtime);
+        let names = |groups: &[Vec<Vec<DepNode>>]| groups.iter()
+            .map(|group| group.iter().map(|choice| choice[0].atom.cp()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let mut deps = vec![node("virtual/editor"), node("virtual/pager"), node("dev-libs/bar")];
+        let any_of = settle_any_of(&mut deps, vec![vec![vec![node("app-editors/emacs")], vec![node("app-editors/nano")]]], &porttree, &virtuals);
+        assert_eq!(deps.iter().map(|d| d.atom.cp()).collect::<Vec<_>>(), vec!["virtual/pager", "dev-libs/bar"]);
+        assert_eq!(names(&any_of), vec![vec!["app-editors/nano"], vec!["app-editors/vim", "app-editors/nano"]]);
+    }
+}
@@ -33,7 +33,7 @@ pub struct Atom {
 impl Atom {
     pub fn new(atom_str: &str) -> Result<Self, InvalidAtom> {
         lazy_static! {
-            static ref ATOM_REGEX: Regex = Regex::new(r"^(?P<blocker>[!~]?)(?P<op>[<>=~]*)(?P<catpkg>[^:]+)(?P<slot>:[^/]+)?(?P<branch>\[.*\])?$").unwrap();
+            static ref ATOM_REGEX: Regex = Regex::new(r"^(?P<blocker>[!~]?)(?P<op>[<>=~]*)(?P<catpkg>[^:]+)(?P<slot>:[^\[]+)?(?P<branch>\[.*\])?$").unwrap();
         }
 
         let caps = ATOM_REGEX.captures(atom_str)
@@ -64,7 +64,10 @@ impl Atom {
 
         let blocker = caps.name("blocker").map(|m| m.as_str().to_string());
 
-        let slot_part = caps.name("slot").map(|m| m.as_str().trim_start_matches(':'));
+        // Slot operators (:=, :*, :2=) only affect rebuilds, not which slot is meant
+        let slot_part = caps.name("slot")
+            .map(|m| m.as_str().trim_start_matches(':').trim_end_matches('='))
+            .filter(|s| !s.is_empty() && *s != "*");
         let (slot, subslot) = if let Some(slot_str) = slot_part {
             if let Some(slash_pos) = slot_str.find('/') {
                 (Some(slot_str[..slash_pos].to_string()), Some(slot_str[slash_pos+1..].to_string()))
@@ -105,6 +108,11 @@ impl Atom {
         format!("{}/{}", self.category, self.package)
     }
 
+    /// A !! blocker, which an uninstall cannot resolve
+    pub fn is_hard_blocker(&self) -> bool {
+        self.blocker.as_deref() == Some("!!")
+    }
+
     pub fn cpv(&self) -> Option<String> {
         self.version.as_ref().map(|v| format!("{}/{}:{}", self.category, self.package, v))
     }
@@ -143,6 +151,35 @@ impl Atom {
     }
 }
 
+impl std::fmt::Display for Atom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(blocker) = &self.blocker {
+            write!(f, "{}", blocker)?;
+        }
+        let op = match self.op {
+            Operator::None => "",
+            Operator::Equal => "=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Tilde => "~",
+            Operator::TildeGreater => "~>",
+        };
+        write!(f, "{}{}", op, self.cp())?;
+        if let Some(version) = &self.version {
+            write!(f, "-{}", version)?;
+        }
+        if let Some(slot) = &self.slot {
+            write!(f, ":{}", slot)?;
+            if let Some(subslot) = &self.subslot {
+                write!(f, "/{}", subslot)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn isvalidatom(atom: &str) -> bool {
     Atom::new(atom).is_ok()
 }
@@ -172,6 +209,14 @@ mod tests {
         assert_eq!(atom.category, "dev-lang");
         assert_eq!(atom.package, "rust");
         assert_eq!(atom.slot, Some("1".to_string()));
+
+        // Test slot operators and subslots
+        let atom = Atom::new("dev-libs/openssl:0/3=").unwrap();
+        assert_eq!(atom.slot, Some("0".to_string()));
+        assert_eq!(atom.subslot, Some("3".to_string()));
+        assert!(Atom::new("dev-libs/openssl:=").unwrap().slot.is_none());
+        assert!(Atom::new("dev-libs/openssl:*").unwrap().slot.is_none());
+        assert_eq!(Atom::new("=dev-lang/rust-1.0.0:1").unwrap().to_string(), "=dev-lang/rust-1.0.0:1");
     }
 
     #[tokio::test]
@@ -0,0 +1,216 @@
+// autotools.rs -- built-in autotools and libtool eclasses: eautoreconf, elibtoolize and
+// replacing outdated config.sub/config.guess with the system's gnuconfig copies
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::exception::InvalidData;
+
+/// The scripts gnuconfig keeps up to date
+pub const GNUCONFIG_FILES: &[&str] = &["config.sub", "config.guess"];
+
+/// Where sys-devel/gnuconfig and automake install their copies
+const GNUCONFIG_DIRS: &[&str] = &["/usr/share/gnuconfig", "/usr/share/misc"];
+
+/// The directory holding the system's newest config.sub and config.guess
+pub fn system_gnuconfig_dir() -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = GNUCONFIG_DIRS.iter().map(PathBuf::from).collect();
+    // automake-1.16, automake-1.17, ...
+    if let Ok(entries) = fs::read_dir("/usr/share") {
+        let mut automake: Vec<PathBuf> = entries.flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with("automake-"))
+            .map(|e| e.path())
+            .collect();
+        automake.sort();
+        candidates.extend(automake.into_iter().rev());
+    }
+    candidates.into_iter()
+        .filter(|dir| GNUCONFIG_FILES.iter().all(|name| dir.join(name).is_file()))
+        .max_by_key(|dir| timestamp(&dir.join("config.sub")))
+}
+
+/// The `timestamp='YYYY-MM-DD'` a gnuconfig script carries
+pub fn timestamp(path: &Path) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    content.lines()
+        .find_map(|line| line.trim().strip_prefix("timestamp="))
+        .map(|value| value.trim_matches(|c| c == '\'' || c == '"').to_string())
+}
+
+/// Replace every config.sub and config.guess under `source_dir` older than the copy in
+/// `system_dir`; returns the replaced files
+pub fn update_gnuconfig(source_dir: &Path, system_dir: &Path) -> Result<Vec<PathBuf>, InvalidData> {
+    let mut updated = Vec::new();
+    for name in GNUCONFIG_FILES {
+        let system = system_dir.join(name);
+        let Some(newest) = timestamp(&system) else {
+            continue;
+        };
+        for path in find_files(source_dir, name) {
+            if timestamp(&path).is_some_and(|stamp| stamp >= newest) {
+                continue;
+            }
+            fs::copy(&system, &path)
+                .map_err(|e| InvalidData::new(&format!("Failed to update {}: {}", path.display(), e), None))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+                .map_err(|e| InvalidData::new(&format!("Failed to update {}: {}", path.display(), e), None))?;
+            updated.push(path);
+        }
+    }
+    updated.sort();
+    Ok(updated)
+}
+
+/// Files called `name` anywhere below `dir`
+fn find_files(dir: &Path, name: &str) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return found;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => found.extend(find_files(&path, name)),
+            Ok(kind) if kind.is_file() && entry.file_name() == name => found.push(path),
+            _ => {}
+        }
+    }
+    found
+}
+
+/// Source of the built-in libtool eclass
+pub const LIBTOOL_ECLASS: &str = r#"
+elibtoolize() {
+	local ltmain patched=0
+	while IFS= read -r -d '' ltmain; do
+		[[ -f ${ltmain%/*}/.elibtoolized ]] && continue
+		# Install against ${D}: relinking at install time would pick up the libraries
+		# already in ROOT instead of the ones just built
+		sed -i -e 's/^\([[:space:]]*\)need_relink=yes$/\1need_relink=no/' "${ltmain}" || die "Patching ${ltmain} failed"
+		touch "${ltmain%/*}/.elibtoolized"
+		patched=$((patched + 1))
+	done < <(find "${S}" -name ltmain.sh -print0)
+	einfo "elibtoolize: patched ${patched} ltmain.sh"
+}
+"#;
+
+/// Source of the built-in autotools eclass
+pub const AUTOTOOLS_ECLASS: &str = r#"
+inherit libtool
+
+autotools_run_tool() {
+	local tool=$1
+	local log="${T}/${tool}-$$.out"
+	mkdir -p "${T}"
+	einfo "Running ${*}"
+	if ! "$@" > "${log}" 2>&1; then
+		tail -n 25 "${log}" >&2
+		die "Failed running '${tool}'; see ${log}"
+	fi
+}
+
+_autotools_uses() {
+	local macro
+	for macro; do
+		grep -Eq "^[[:space:]]*${macro}([[:space:](]|$)" configure.ac configure.in 2>/dev/null && return 0
+	done
+	return 1
+}
+
+eaclocal() {
+	local include=
+	[[ -d m4 ]] && include="-I m4"
+	autotools_run_tool aclocal ${include} ${AT_M4DIR:+-I ${AT_M4DIR}} "$@"
+}
+
+_elibtoolize() {
+	autotools_run_tool libtoolize --install --copy --force "$@"
+}
+
+eautoconf() {
+	autotools_run_tool autoconf --force "$@"
+}
+
+eautoheader() {
+	_autotools_uses AC_CONFIG_HEADERS AC_CONFIG_HEADER AM_CONFIG_HEADER || return 0
+	autotools_run_tool autoheader --force "$@"
+}
+
+eautomake() {
+	[[ -f Makefile.am ]] || return 0
+	autotools_run_tool automake --add-missing --copy --force-missing "$@"
+}
+
+eautoreconf() {
+	local dir
+	for dir in $(sed -n 's/^[[:space:]]*AC_CONFIG_SUBDIRS(\[\{0,1\}\([^])]*\).*/\1/p' configure.ac configure.in 2>/dev/null); do
+		[[ -d ${dir} ]] && ( cd "${dir}" && eautoreconf )
+	done
+	_autotools_uses LT_INIT AC_PROG_LIBTOOL AM_PROG_LIBTOOL && _elibtoolize
+	eaclocal
+	eautoconf
+	eautoheader
+	eautomake
+	elibtoolize
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_update_gnuconfig() {
+        let temp = TempDir::new().unwrap();
+        let system = temp.path().join("gnuconfig");
+        fs::create_dir_all(&system).unwrap();
+        for name in GNUCONFIG_FILES {
+            fs::write(system.join(name), "#! /bin/sh\ntimestamp='2024-01-01'\n").unwrap();
+        }
+        let source = temp.path().join("foo-1.0");
+        fs::create_dir_all(source.join("build-aux")).unwrap();
+        fs::write(source.join("build-aux/config.sub"), "#! /bin/sh\ntimestamp='2009-04-17'\n").unwrap();
+        fs::write(source.join("build-aux/config.guess"), "#! /bin/sh\ntimestamp='2025-02-01'\n").unwrap();
+
+        assert_eq!(timestamp(&system.join("config.sub")).as_deref(), Some("2024-01-01"));
+        let updated = update_gnuconfig(&source, &system).unwrap();
+        assert_eq!(updated, vec![source.join("build-aux/config.sub")]);
+        assert_eq!(timestamp(&source.join("build-aux/config.sub")).as_deref(), Some("2024-01-01"));
+        assert_eq!(timestamp(&source.join("build-aux/config.guess")).as_deref(), Some("2025-02-01"));
+        assert!(update_gnuconfig(&source, &system).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_eautoreconf_order() {
+        // Stand-in tools record the order they run in; libtoolize only runs for LT_INIT
+        let temp = TempDir::new().unwrap();
+        let bin = temp.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        for tool in ["aclocal", "autoconf", "autoheader", "automake", "libtoolize"] {
+            let script = bin.join(tool);
+            fs::write(&script, format!("#!/bin/sh\necho {} >> \"$T/order\"\n", tool)).unwrap();
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let source = temp.path().join("src");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("configure.ac"), "AC_INIT([foo], [1.0])\nLT_INIT\nAC_CONFIG_HEADERS([config.h])\n").unwrap();
+        fs::write(source.join("Makefile.am"), "").unwrap();
+        fs::write(source.join("ltmain.sh"), "    need_relink=yes\n").unwrap();
+
+        let script = format!(
+            "die() {{ echo \"$*\" >&2; exit 1; }}\neinfo() {{ :; }}\ninherit() {{ :; }}\n{}\n{}\ncd \"$S\"\neautoreconf\n",
+            LIBTOOL_ECLASS, AUTOTOOLS_ECLASS);
+        let output = std::process::Command::new("bash")
+            .args(["-c", &script])
+            .env("PATH", format!("{}:/usr/bin:/bin", bin.display()))
+            .env("S", &source)
+            .env("T", temp.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(fs::read_to_string(temp.path().join("order")).unwrap(), "libtoolize\naclocal\nautoconf\nautoheader\nautomake\n");
+        assert_eq!(fs::read_to_string(source.join("ltmain.sh")).unwrap(), "    need_relink=no\n");
+    }
+}
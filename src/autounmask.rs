@@ -0,0 +1,281 @@
+// autounmask.rs -- Config changes needed to unmask a merge plan (--autounmask-write)
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::exception::InvalidData;
+
+/// File written inside /etc/portage/package.* directories
+pub const AUTOUNMASK_FILE: &str = "zz-autounmask";
+
+/// Kind of configuration change, in the order emerge prints them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChangeKind {
+    Keyword,
+    Use,
+    License,
+}
+
+impl ChangeKind {
+    /// Name of the /etc/portage file the change belongs in
+    pub fn config_name(&self) -> &'static str {
+        match self {
+            ChangeKind::Keyword => "package.accept_keywords",
+            ChangeKind::Use => "package.use",
+            ChangeKind::License => "package.license",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ChangeKind::Keyword => "keyword",
+            ChangeKind::Use => "USE",
+            ChangeKind::License => "license",
+        }
+    }
+}
+
+/// One line to add to a package.* file
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutounmaskChange {
+    pub kind: ChangeKind,
+    pub atom: String,
+    pub values: Vec<String>,
+    pub required_by: String,
+}
+
+impl AutounmaskChange {
+    pub fn line(&self) -> String {
+        format!("{} {}", self.atom, self.values.join(" "))
+    }
+}
+
+/// Collects the changes that would let a blocked merge plan proceed
+#[derive(Debug, Clone, Default)]
+pub struct Autounmask {
+    changes: Vec<AutounmaskChange>,
+}
+
+impl Autounmask {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a change; values for the same atom and kind are merged
+    fn add(&mut self, kind: ChangeKind, atom: String, values: Vec<String>, required_by: &str) {
+        if values.is_empty() {
+            return;
+        }
+        if let Some(existing) = self.changes.iter_mut().find(|c| c.kind == kind && c.atom == atom) {
+            for value in values {
+                if !existing.values.contains(&value) {
+                    existing.values.push(value);
+                }
+            }
+            return;
+        }
+        self.changes.push(AutounmaskChange { kind, atom, values, required_by: required_by.to_string() });
+    }
+
+    pub fn add_keyword(&mut self, cpv: &str, keyword: &str, required_by: &str) {
+        self.add(ChangeKind::Keyword, format!("={}", cpv), vec![keyword.to_string()], required_by);
+    }
+
+    pub fn add_use(&mut self, cpv: &str, flags: Vec<String>, required_by: &str) {
+        self.add(ChangeKind::Use, format!(">={}", cpv), flags, required_by);
+    }
+
+    pub fn add_license(&mut self, cpv: &str, licenses: Vec<String>, required_by: &str) {
+        self.add(ChangeKind::License, format!(">={}", cpv), licenses, required_by);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn changes(&self) -> &[AutounmaskChange] {
+        &self.changes
+    }
+
+    fn changes_of(&self, kind: ChangeKind) -> impl Iterator<Item = &AutounmaskChange> {
+        self.changes.iter().filter(move |c| c.kind == kind)
+    }
+
+    /// Render the changes the way emerge presents them
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        for kind in [ChangeKind::Keyword, ChangeKind::Use, ChangeKind::License] {
+            let mut changes = self.changes_of(kind).peekable();
+            if changes.peek().is_none() {
+                continue;
+            }
+            out.push_str(&format!("\nThe following {} changes are necessary to proceed:\n", kind.title()));
+            out.push_str(&format!(" (see \"{}\" in the portage(5) man page for more details)\n", kind.config_name()));
+            for change in changes {
+                out.push_str(&format!("# required by {}\n", change.required_by));
+                out.push_str(&format!("{}\n", change.line()));
+            }
+        }
+        out
+    }
+
+    /// File a change kind is written to: zz-autounmask inside the package.* directory,
+    /// or the package.* file itself when the user keeps a single file
+    pub fn target_file(root: &str, kind: ChangeKind) -> PathBuf {
+        let config_path = Path::new(root).join("etc/portage").join(kind.config_name());
+        if config_path.is_file() {
+            config_path
+        } else {
+            config_path.join(AUTOUNMASK_FILE)
+        }
+    }
+
+    /// Append the changes to the package.* files below ROOT, skipping lines already present
+    pub fn write(&self, root: &str) -> Result<Vec<PathBuf>, InvalidData> {
+        let mut written = Vec::new();
+        for kind in [ChangeKind::Keyword, ChangeKind::Use, ChangeKind::License] {
+            let changes: Vec<&AutounmaskChange> = self.changes_of(kind).collect();
+            if changes.is_empty() {
+                continue;
+            }
+            let path = Self::target_file(root, kind);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+            }
+
+            let mut content = fs::read_to_string(&path).unwrap_or_default();
+            let mut changed = false;
+            for change in changes {
+                let line = change.line();
+                if content.lines().any(|l| l.trim() == line) {
+                    continue;
+                }
+                if !content.is_empty() && !content.ends_with('\n') {
+                    content.push('\n');
+                }
+                content.push_str(&format!("# required by {}\n{}\n", change.required_by, line));
+                changed = true;
+            }
+            if changed {
+                fs::write(&path, content)
+                    .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))?;
+                written.push(path);
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// USE flags enabled or disabled by default through "+flag"/"-flag" in IUSE
+pub fn parse_iuse_defaults(content: &str) -> HashMap<String, bool> {
+    let mut defaults = HashMap::new();
+    for line in content.lines() {
+        let Some(value) = line.trim().strip_prefix("IUSE=") else {
+            continue;
+        };
+        for flag in value.trim_matches(|c| c == '"' || c == '\'' || c == '(' || c == ')').split_whitespace() {
+            let flag = flag.trim_matches(|c| c == '"' || c == '\'');
+            if let Some(name) = flag.strip_prefix('+') {
+                defaults.insert(name.to_string(), true);
+            } else if let Some(name) = flag.strip_prefix('-') {
+                defaults.insert(name.to_string(), false);
+            }
+        }
+    }
+    defaults
+}
+
+/// Effective USE for a package: IUSE defaults, then global USE, then package.use
+pub fn effective_use(
+    iuse_defaults: &HashMap<String, bool>,
+    global: &HashMap<String, bool>,
+    package_flags: &[String],
+) -> HashMap<String, bool> {
+    let mut flags = iuse_defaults.clone();
+    flags.extend(global.iter().map(|(k, v)| (k.clone(), *v)));
+    for flag in package_flags {
+        match flag.strip_prefix('-') {
+            Some(name) => flags.insert(name.to_string(), false),
+            None => flags.insert(flag.clone(), true),
+        };
+    }
+    flags
+}
+
+/// Flag changes ("flag" or "-flag") needed so `flags` satisfies the required states
+pub fn use_changes_needed(required: &[(String, bool)], flags: &HashMap<String, bool>) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (flag, enabled) in required {
+        if flags.get(flag).copied().unwrap_or(false) != *enabled {
+            let change = if *enabled { flag.clone() } else { format!("-{}", flag) };
+            if !changes.contains(&change) {
+                changes.push(change);
+            }
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_and_merge_changes() {
+        let mut autounmask = Autounmask::new();
+        autounmask.add_keyword("app-misc/foo-2.0", "~amd64", "app-misc/foo (argument)");
+        autounmask.add_use("dev-libs/bar-1.0", vec!["ssl".to_string()], "app-misc/foo-2.0");
+        autounmask.add_use("dev-libs/bar-1.0", vec!["ssl".to_string(), "-static".to_string()], "app-misc/baz-1.0");
+        autounmask.add_license("app-misc/foo-2.0", vec![], "app-misc/foo (argument)");
+
+        assert_eq!(autounmask.changes().len(), 2);
+        let formatted = autounmask.format();
+        assert!(formatted.contains("The following keyword changes are necessary to proceed:"));
+        assert!(formatted.contains("=app-misc/foo-2.0 ~amd64\n"));
+        assert!(formatted.contains("The following USE changes are necessary to proceed:"));
+        assert!(formatted.contains(">=dev-libs/bar-1.0 ssl -static\n"));
+        assert!(!formatted.contains("license"));
+    }
+
+    #[test]
+    fn test_write_changes() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().to_str().unwrap();
+        fs::create_dir_all(temp.path().join("etc/portage")).unwrap();
+        fs::write(temp.path().join("etc/portage/package.use"), "app-misc/other X\n").unwrap();
+
+        let mut autounmask = Autounmask::new();
+        autounmask.add_keyword("app-misc/foo-2.0", "~amd64", "app-misc/foo (argument)");
+        autounmask.add_use("dev-libs/bar-1.0", vec!["ssl".to_string()], "app-misc/foo-2.0");
+
+        let written = autounmask.write(root).unwrap();
+        assert_eq!(written, vec![
+            temp.path().join("etc/portage/package.accept_keywords").join(AUTOUNMASK_FILE),
+            temp.path().join("etc/portage/package.use"),
+        ]);
+        let package_use = fs::read_to_string(temp.path().join("etc/portage/package.use")).unwrap();
+        assert!(package_use.starts_with("app-misc/other X\n"));
+        assert!(package_use.contains(">=dev-libs/bar-1.0 ssl\n"));
+
+        // Writing again does not duplicate lines
+        assert!(autounmask.write(root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_use_changes_needed() {
+        let iuse = parse_iuse_defaults("IUSE=\"+ssl static -doc\"\n");
+        assert_eq!(iuse.get("ssl"), Some(&true));
+        assert_eq!(iuse.get("doc"), Some(&false));
+        assert_eq!(iuse.get("static"), None);
+
+        let mut global = HashMap::new();
+        global.insert("static".to_string(), true);
+        let flags = effective_use(&iuse, &global, &["-ssl".to_string()]);
+
+        let required = vec![("ssl".to_string(), true), ("static".to_string(), false), ("doc".to_string(), false)];
+        assert_eq!(use_changes_needed(&required, &flags), vec!["ssl".to_string(), "-static".to_string()]);
+    }
+}
@@ -0,0 +1,104 @@
+// ebuild.rs -- `ebuild` command: run individual phases of an ebuild by hand
+
+use clap::{Arg, Command};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use emerge_rs::doebuild::{default_distdir, doebuild, BuildEnv, BuildPhase, Ebuild};
+use emerge_rs::manifest;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let matches = Command::new("ebuild")
+        .version("0.1.0")
+        .author("Rust Portage Team")
+        .about("Run ebuild phases manually")
+        .arg(
+            Arg::new("ebuild")
+                .help("Path to the ebuild file")
+                .required(true),
+        )
+        .arg(
+            Arg::new("commands")
+                .help("clean, digest, manifest, setup, unpack, prepare, configure, compile, test, install, package")
+                .required(true)
+                .num_args(1..),
+        )
+        .get_matches();
+
+    let ebuild_path = PathBuf::from(matches.get_one::<String>("ebuild").unwrap());
+    let commands: Vec<String> = matches.get_many::<String>("commands").unwrap().cloned().collect();
+
+    for command in &commands {
+        if command != "clean" && command != "digest" && command != "manifest" && BuildPhase::from_name(command).is_none() {
+            eprintln!("!!! Unknown command: {}", command);
+            process::exit(1);
+        }
+    }
+
+    for command in &commands {
+        if let Err(e) = run_command(&ebuild_path, command).await {
+            eprintln!("!!! {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// USE and FEATURES from the environment
+fn env_settings() -> (HashMap<String, bool>, Vec<String>) {
+    let use_flags = std::env::var("USE").unwrap_or_default()
+        .split_whitespace()
+        .map(|flag| match flag.strip_prefix('-') {
+            Some(name) => (name.to_string(), false),
+            None => (flag.to_string(), true),
+        })
+        .collect();
+    let features = std::env::var("FEATURES").unwrap_or_default()
+        .split_whitespace()
+        .map(|f| f.to_string())
+        .collect();
+    (use_flags, features)
+}
+
+async fn run_command(ebuild_path: &Path, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (use_flags, mut features) = env_settings();
+    // Asking for the test phase by name runs it whatever FEATURES says
+    if command == "test" && !features.iter().any(|f| f == "test") {
+        features.push("test".to_string());
+    }
+    let ebuild = Ebuild::from_path_with_use(ebuild_path, &use_flags)?;
+    let distdir = default_distdir();
+    // The repository holding the ebuild, <repo>/<category>/<package>/<ebuild>
+    let portdir = ebuild_path.ancestors().nth(3).unwrap_or(Path::new("."));
+    let build_env = BuildEnv::new(&ebuild, portdir, &distdir, use_flags.clone(), features.clone(), HashMap::new());
+
+    match command {
+        "clean" => {
+            build_env.clean()?;
+            println!(">>> Cleaned {}", build_env.workdir.display());
+        }
+        "digest" | "manifest" => {
+            let distfiles: Vec<PathBuf> = ebuild.metadata.src_uri.iter()
+                .map(|uri| distdir.join(ebuild.distfile_name(uri)))
+                .filter(|path| path.is_file())
+                .collect();
+            let package_dir = ebuild_path.parent().unwrap_or(Path::new("."));
+            let count = manifest::update_dist_entries(package_dir, &distfiles)?;
+            println!(">>> Creating Manifest for {} ({} DIST entries)", package_dir.display(), count);
+        }
+        name => {
+            let phase = BuildPhase::from_name(name).ok_or_else(|| format!("Unknown command: {}", name))?;
+            // Earlier phases already run in this WORKDIR are not repeated
+            let phases: Vec<BuildPhase> = phase.prerequisites().iter()
+                .copied()
+                .filter(|p| !build_env.phase_completed(*p))
+                .chain(std::iter::once(phase))
+                .collect();
+            doebuild(ebuild_path, portdir, &phases, use_flags, features, HashMap::new()).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+// binhost.rs -- Serve PKGDIR over HTTP so other machines can use it as PORTAGE_BINHOST
+
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::binpkg::BinPkgBuilder;
+use crate::exception::InvalidData;
+
+/// HTTP date format (RFC 7231 IMF-fixdate)
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Most bytes read of a request line and headers
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+/// Regenerate the Packages index of `pkgdir` and serve it on `addr` until the process exits
+pub async fn serve(pkgdir: &Path, addr: &str) -> Result<(), InvalidData> {
+    let count = BinPkgBuilder::new(&pkgdir.to_string_lossy()).regenerate_index().await?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| InvalidData::new(&format!("Failed to listen on {}: {}", addr, e), None))?;
+    println!(">>> Serving {} binary packages from {} on http://{}/", count, pkgdir.display(), addr);
+    serve_listener(listener, pkgdir.to_path_buf()).await
+}
+
+/// Answer requests on an already bound listener
+pub async fn serve_listener(listener: TcpListener, pkgdir: PathBuf) -> Result<(), InvalidData> {
+    loop {
+        let (stream, peer) = listener.accept()
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to accept connection: {}", e), None))?;
+        let pkgdir = pkgdir.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &pkgdir).await {
+                eprintln!("!!! binhost: {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// File under `pkgdir` named by a request path; None for anything escaping it
+pub fn resolve_request_path(pkgdir: &Path, request_path: &str) -> Option<PathBuf> {
+    let path = request_path.split(['?', '#']).next()?.trim_start_matches('/');
+    let mut resolved = pkgdir.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    resolved.is_file().then_some(resolved)
+}
+
+/// Serve one request (GET or HEAD) and close the connection
+async fn handle_connection(stream: TcpStream, pkgdir: &Path) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_HEAD);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut if_modified_since = None;
+    let mut complete = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            break;
+        }
+        if header.trim().is_empty() {
+            complete = header.ends_with('\n');
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("if-modified-since")
+        {
+            if_modified_since = chrono::NaiveDateTime::parse_from_str(value.trim(), HTTP_DATE).ok().map(|t| t.and_utc());
+        }
+    }
+
+    let too_large = !complete && reader.limit() == 0;
+    let mut stream = reader.into_inner().into_inner();
+    if too_large {
+        return respond(&mut stream, "431 Request Header Fields Too Large", &[], None).await;
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", &[], None).await;
+    }
+    let Some(path) = resolve_request_path(pkgdir, target) else {
+        return respond(&mut stream, "404 Not Found", &[], None).await;
+    };
+
+    let metadata = tokio::fs::metadata(&path).await?;
+    let modified: Option<chrono::DateTime<chrono::Utc>> = metadata.modified().ok().map(|t| t.into());
+    let last_modified = modified.map(|t| t.format(HTTP_DATE).to_string());
+    // HTTP dates have whole-second precision
+    if let (Some(modified), Some(since)) = (modified, if_modified_since)
+        && modified.timestamp() <= since.timestamp()
+    {
+        return respond(&mut stream, "304 Not Modified", &[], last_modified.as_deref()).await;
+    }
+    respond(&mut stream, "200 OK", &[("Content-Length", &metadata.len().to_string())], last_modified.as_deref()).await?;
+    if method == "GET" {
+        // Streamed, and never more than the Content-Length sent should the file grow meanwhile
+        let file = tokio::fs::File::open(&path).await?;
+        tokio::io::copy(&mut file.take(metadata.len()), &mut stream).await?;
+    }
+    stream.shutdown().await
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, &str)], last_modified: Option<&str>) -> std::io::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(last_modified) = last_modified {
+        response.push_str(&format!("Last-Modified: {}\r\n", last_modified));
+    }
+    if !headers.iter().any(|(name, _)| *name == "Content-Length") {
+        response.push_str("Content-Length: 0\r\n");
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve_pkgdir() {
+        let pkgdir = TempDir::new().unwrap();
+        std::fs::write(pkgdir.path().join("Packages"), "VERSION: 0\n").unwrap();
+        assert!(resolve_request_path(pkgdir.path(), "/../etc/passwd").is_none());
+        assert_eq!(resolve_request_path(pkgdir.path(), "/Packages?x=1"), Some(pkgdir.path().join("Packages")));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, pkgdir.path().to_path_buf()));
+
+        let response = request(addr, "GET /Packages HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nVERSION: 0\n"));
+
+        let later = (chrono::Utc::now() + chrono::Duration::hours(1)).format(HTTP_DATE);
+        let response = request(addr, &format!("GET /Packages HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n", later)).await;
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified"));
+
+        // Packages larger than a read buffer: the length comes from the metadata, HEAD
+        // sends no body and GET streams all of it
+        let data = "x".repeat(200_000);
+        std::fs::create_dir_all(pkgdir.path().join("app-misc")).unwrap();
+        std::fs::write(pkgdir.path().join("app-misc/big-1.0.tbz2"), &data).unwrap();
+        let response = request(addr, "HEAD /app-misc/big-1.0.tbz2 HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("Content-Length: 200000\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+        let response = request(addr, "GET /app-misc/big-1.0.tbz2 HTTP/1.1\r\n\r\n").await;
+        assert!(response.ends_with(&format!("\r\n\r\n{}", data)));
+
+        let response = request(addr, "GET /missing.tbz2 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        // A request head that never ends is cut off at the limit
+        let head = format!("GET /Packages HTTP/1.1\r\nX-Filler: {}", "a".repeat(MAX_REQUEST_HEAD as usize));
+        let response = request(addr, &head[..MAX_REQUEST_HEAD as usize]).await;
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+    }
+}
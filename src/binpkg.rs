@@ -0,0 +1,596 @@
+// binpkg.rs -- Binary package creation and Packages index maintenance
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use crate::exception::InvalidData;
+use crate::doebuild::Ebuild;
+use crate::bintree::{BinPkgFormat, SignatureSigner};
+
+/// Build settings recorded in the XPAK metadata of every binary package
+pub const BUILD_VARS: &[&str] = &["CFLAGS", "CXXFLAGS", "LDFLAGS", "CHOST", "CBUILD", "ARCH"];
+
+/// Metadata keys copied from the XPAK block into the Packages index
+const INDEX_KEYS: &[&str] = &[
+    "BUILD_TIME", "DEPEND", "EAPI", "IUSE", "KEYWORDS", "LICENSE",
+    "PDEPEND", "RDEPEND", "SLOT", "USE",
+];
+
+/// Creates binary packages in PKGDIR and keeps its Packages index current
+#[derive(Debug)]
+pub struct BinPkgBuilder {
+    pub pkgdir: PathBuf,
+    pub format: BinPkgFormat,
+    /// Signs gpkg members when set (FEATURES=binpkg-signing)
+    pub signer: Option<Box<dyn SignatureSigner>>,
+}
+
+/// Parsed $PKGDIR/Packages file
+#[derive(Debug, Default)]
+pub struct PackagesIndex {
+    pub header: BTreeMap<String, String>,
+    pub packages: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl BinPkgBuilder {
+    pub fn new(pkgdir: &str) -> Self {
+        BinPkgBuilder {
+            pkgdir: PathBuf::from(pkgdir),
+            format: BinPkgFormat::default(),
+            signer: None,
+        }
+    }
+
+    /// Sign the members of created packages; only gpkg supports signatures
+    pub fn with_signer(mut self, signer: Box<dyn SignatureSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Select the package format (BINPKG_FORMAT)
+    pub fn with_format(mut self, format: BinPkgFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Path of the binary package for a cpv (PKGDIR/category/PF.<ext>)
+    pub fn package_path(&self, cpv: &str) -> PathBuf {
+        self.pkgdir.join(format!("{}.{}", cpv, self.format.extension()))
+    }
+
+    /// Path of the Packages index file
+    pub fn index_path(&self) -> PathBuf {
+        self.pkgdir.join("Packages")
+    }
+
+    /// Collect the XPAK metadata for an ebuild built with the given USE flags
+    pub fn build_metadata(ebuild: &Ebuild, use_flags: &HashMap<String, bool>, extra: &HashMap<String, String>) -> HashMap<String, Vec<u8>> {
+        let mut metadata: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut insert = |key: &str, value: String| {
+            metadata.insert(key.to_string(), value.into_bytes());
+        };
+
+        insert("CATEGORY", ebuild.category.clone());
+        insert("PF", format!("{}-{}", ebuild.package, ebuild.version));
+        insert("SLOT", ebuild.metadata.slot.clone());
+
+        if let Some(description) = &ebuild.metadata.description {
+            insert("DESCRIPTION", description.clone());
+        }
+        if let Some(homepage) = &ebuild.metadata.homepage {
+            insert("HOMEPAGE", homepage.clone());
+        }
+        if let Some(license) = &ebuild.metadata.license {
+            insert("LICENSE", license.clone());
+        }
+        if !ebuild.metadata.keywords.is_empty() {
+            insert("KEYWORDS", ebuild.metadata.keywords.join(" "));
+        }
+        if !ebuild.metadata.iuse.is_empty() {
+            insert("IUSE", ebuild.metadata.iuse.join(" "));
+        }
+
+        // USE holds the enabled flags, restricted to IUSE when the ebuild declares it
+        let iuse: Vec<&str> = ebuild.metadata.iuse.iter()
+            .map(|flag| flag.trim_start_matches(['+', '-']))
+            .collect();
+        let mut enabled: Vec<&String> = use_flags.iter()
+            .filter(|&(flag, &on)| on && (iuse.is_empty() || iuse.contains(&flag.as_str())))
+            .map(|(flag, _)| flag)
+            .collect();
+        enabled.sort();
+        insert("USE", enabled.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(" "));
+
+        for (key, dep_type, deps) in [
+            ("DEPEND", crate::depgraph::DepType::Build, &ebuild.metadata.depend),
+            ("RDEPEND", crate::depgraph::DepType::Runtime, &ebuild.metadata.rdepend),
+            ("PDEPEND", crate::depgraph::DepType::Post, &ebuild.metadata.pdepend),
+        ] {
+            let mut parts: Vec<String> = deps.iter().map(|d| d.to_string()).collect();
+            parts.extend(ebuild.metadata.any_of.iter()
+                .filter(|(group_type, _)| *group_type == dep_type)
+                .map(|(_, group)| crate::dep::format_any_of(group)));
+            if !parts.is_empty() {
+                insert(key, parts.join(" "));
+            }
+        }
+
+        insert("BUILD_TIME", chrono::Utc::now().timestamp().to_string());
+
+        // Caller-supplied values (CFLAGS, CONTENTS, FEATURES, repository, ...) take precedence
+        for (key, value) in extra {
+            insert(key, value.clone());
+        }
+
+        metadata
+    }
+
+    /// Create PKGDIR/category/PF.<ext> from an image directory and register it in the index
+    pub async fn create(&self, ebuild: &Ebuild, image: &Path, use_flags: &HashMap<String, bool>, extra: &HashMap<String, String>) -> Result<PathBuf, InvalidData> {
+        let cpv = ebuild.cpv();
+        let pkg_path = self.package_path(&cpv);
+
+        if let Some(parent) = pkg_path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+        }
+
+        if self.signer.is_some() && self.format != BinPkgFormat::Gpkg {
+            return Err(InvalidData::new("binpkg-signing requires BINPKG_FORMAT=gpkg: xpak packages cannot carry signatures", None));
+        }
+
+        let mut metadata = Self::build_metadata(ebuild, use_flags, extra);
+        metadata.insert("SIZE".to_string(), image_size(image)?.to_string().into_bytes());
+
+        match self.format {
+            BinPkgFormat::Xpak => Self::write_tbz2(&pkg_path, image, &metadata).await?,
+            BinPkgFormat::Gpkg => Self::write_gpkg(&pkg_path, &format!("{}-{}", ebuild.package, ebuild.version), image, &metadata, self.signer.as_deref()).await?,
+        }
+
+        self.update_index(&cpv, &metadata).await?;
+
+        println!("Created binary package: {}", pkg_path.display());
+        Ok(pkg_path)
+    }
+
+    /// Write a tar.bz2 of the image with the XPAK block appended
+    async fn write_tbz2(pkg_path: &Path, image: &Path, metadata: &HashMap<String, Vec<u8>>) -> Result<(), InvalidData> {
+        run_tar(tokio::process::Command::new("tar")
+            .arg("-cjf")
+            .arg(pkg_path)
+            .arg("-C")
+            .arg(image)
+            .arg(".")).await?;
+
+        // Only the XPAK segment and its trailer are written; the archive stays on disk as is
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(pkg_path)
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to open {}: {}", pkg_path.display(), e), None))?;
+        file.write_all(&crate::xpak::xpak_mem(metadata))
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to append XPAK data: {}", e), None))?;
+        file.flush()
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to append XPAK data: {}", e), None))
+    }
+
+    /// Write a gpkg: an uncompressed tar holding PF/gpkg-1, PF/metadata.tar.xz and PF/image.tar.xz,
+    /// plus a detached .sig for each member when signing
+    async fn write_gpkg(pkg_path: &Path, pf: &str, image: &Path, metadata: &HashMap<String, Vec<u8>>, signer: Option<&dyn SignatureSigner>) -> Result<(), InvalidData> {
+        let temp = tempfile::TempDir::new()
+            .map_err(|e| InvalidData::new(&format!("Failed to create temp dir: {}", e), None))?;
+        let pkg_dir = temp.path().join(pf);
+        let metadata_dir = temp.path().join("metadata");
+        for dir in [&pkg_dir, &metadata_dir] {
+            fs::create_dir_all(dir)
+                .await
+                .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", dir.display(), e), None))?;
+        }
+
+        fs::write(pkg_dir.join("gpkg-1"), b"")
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to write gpkg-1: {}", e), None))?;
+
+        for (key, value) in metadata {
+            fs::write(metadata_dir.join(key), value)
+                .await
+                .map_err(|e| InvalidData::new(&format!("Failed to write metadata {}: {}", key, e), None))?;
+        }
+        run_tar(tokio::process::Command::new("tar")
+            .arg("-cJf")
+            .arg(pkg_dir.join("metadata.tar.xz"))
+            .arg("-C")
+            .arg(temp.path())
+            .arg("metadata")).await?;
+
+        // Store the image under an image/ prefix, as Portage expects
+        run_tar(tokio::process::Command::new("tar")
+            .arg("-cJf")
+            .arg(pkg_dir.join("image.tar.xz"))
+            .arg("--transform=s,^\\.,image,")
+            .arg("-C")
+            .arg(image)
+            .arg(".")).await?;
+
+        if let Some(signer) = signer {
+            for member in ["metadata.tar.xz", "image.tar.xz"] {
+                signer.sign(&pkg_dir.join(member), &pkg_dir.join(format!("{}.sig", member)))?;
+            }
+        }
+
+        run_tar(tokio::process::Command::new("tar")
+            .arg("-cf")
+            .arg(pkg_path)
+            .arg("-C")
+            .arg(temp.path())
+            .arg(pf)).await
+    }
+
+    /// Add or replace the index entry for a freshly written package
+    async fn update_index(&self, cpv: &str, metadata: &HashMap<String, Vec<u8>>) -> Result<(), InvalidData> {
+        let index_path = self.index_path();
+        let mut index = PackagesIndex::load(&index_path).await?;
+        let entry = self.index_entry(&mut index, cpv, metadata, self.format)?;
+        index.update(cpv, entry);
+        index.save(&index_path).await
+    }
+
+    /// Rebuild the Packages index from the packages actually present in PKGDIR,
+    /// dropping entries whose files are gone. Returns the number of packages indexed.
+    pub async fn regenerate_index(&self) -> Result<usize, InvalidData> {
+        let index_path = self.index_path();
+        let mut index = PackagesIndex::load(&index_path).await?;
+        index.packages.clear();
+
+        let bintree = crate::bintree::BinTree::new("/").with_pkgdir(&self.pkgdir.to_string_lossy());
+        for cpv in bintree.get_all_binpkgs().await? {
+            let info = match bintree.parse_binpkg(&cpv).await {
+                Ok(Some(info)) => info,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("!!! Skipping {} in the Packages index: {}", cpv, e);
+                    continue;
+                }
+            };
+            let metadata: HashMap<String, Vec<u8>> = info.metadata.into_iter()
+                .map(|(key, value)| (key, value.into_bytes()))
+                .collect();
+            let entry = self.index_entry(&mut index, &cpv, &metadata, info.format)?;
+            index.update(&cpv, entry);
+        }
+
+        let count = index.packages.len();
+        index.save(&index_path).await?;
+        Ok(count)
+    }
+
+    /// Index entry for a package file in PKGDIR: selected metadata plus its size and checksums
+    fn index_entry(&self, index: &mut PackagesIndex, cpv: &str, metadata: &HashMap<String, Vec<u8>>, format: BinPkgFormat) -> Result<BTreeMap<String, String>, InvalidData> {
+        let pkg_path = self.pkgdir.join(format!("{}.{}", cpv, format.extension()));
+        let checksums = crate::checksum::hash_file(&pkg_path, &["SHA1", "MD5"])
+            .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", pkg_path.display(), e), None))?;
+
+        let mut entry = BTreeMap::new();
+        for key in INDEX_KEYS {
+            if let Some(value) = metadata.get(*key) {
+                let value = String::from_utf8_lossy(value).trim().to_string();
+                if !value.is_empty() {
+                    entry.insert(key.to_string(), value);
+                }
+            }
+        }
+        if let Some(repo) = metadata.get("repository") {
+            entry.insert("REPO".to_string(), String::from_utf8_lossy(repo).to_string());
+        }
+        if let Some(arch) = metadata.get("ARCH") {
+            index.header.insert("ARCH".to_string(), String::from_utf8_lossy(arch).to_string());
+        }
+        entry.insert("SIZE".to_string(), checksums.size.to_string());
+        entry.extend(checksums.hashes);
+        let mtime = std::fs::metadata(&pkg_path).ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        entry.insert("MTIME".to_string(), mtime.to_string());
+        if format != BinPkgFormat::default() {
+            entry.insert("PATH".to_string(), format!("{}.{}", cpv, format.extension()));
+        }
+        Ok(entry)
+    }
+}
+
+impl PackagesIndex {
+    /// Parse the contents of a Packages file
+    pub fn parse(content: &str) -> Self {
+        let mut index = PackagesIndex::default();
+        let mut blocks = content.split("\n\n").map(parse_block);
+
+        if let Some(header) = blocks.next() {
+            index.header = header;
+        }
+        for block in blocks {
+            if let Some(cpv) = block.get("CPV").cloned() {
+                index.packages.insert(cpv, block);
+            }
+        }
+
+        index
+    }
+
+    /// Load a Packages file, returning an empty index if it does not exist
+    pub async fn load(path: &Path) -> Result<Self, InvalidData> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", path.display(), e), None))?;
+        Ok(Self::parse(&content))
+    }
+
+    /// Insert or replace the entry for a cpv
+    pub fn update(&mut self, cpv: &str, mut entry: BTreeMap<String, String>) {
+        entry.insert("CPV".to_string(), cpv.to_string());
+        self.packages.insert(cpv.to_string(), entry);
+    }
+
+    /// Drop the entry for a cpv
+    pub fn remove(&mut self, cpv: &str) -> bool {
+        self.packages.remove(cpv).is_some()
+    }
+
+    /// Write the index back to disk, refreshing PACKAGES and TIMESTAMP
+    pub async fn save(&mut self, path: &Path) -> Result<(), InvalidData> {
+        self.header.insert("PACKAGES".to_string(), self.packages.len().to_string());
+        self.header.insert("TIMESTAMP".to_string(), chrono::Utc::now().timestamp().to_string());
+        self.header.entry("VERSION".to_string()).or_insert_with(|| "0".to_string());
+
+        fs::write(path, self.to_string())
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))
+    }
+}
+
+impl std::fmt::Display for PackagesIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in &self.header {
+            writeln!(f, "{}: {}", key, value)?;
+        }
+        for entry in self.packages.values() {
+            writeln!(f)?;
+            for (key, value) in entry {
+                writeln!(f, "{}: {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a tar invocation, turning failures into InvalidData
+async fn run_tar(cmd: &mut tokio::process::Command) -> Result<(), InvalidData> {
+    let output = cmd.output()
+        .await
+        .map_err(|e| InvalidData::new(&format!("Failed to run tar: {}", e), None))?;
+    if !output.status.success() {
+        return Err(InvalidData::new(&format!("tar command failed: {}", String::from_utf8_lossy(&output.stderr)), None));
+    }
+    Ok(())
+}
+
+/// Parse one "KEY: value" block of a Packages file
+fn parse_block(block: &str) -> BTreeMap<String, String> {
+    block.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+/// Total size in bytes of the regular files below an image directory
+fn image_size(dir: &Path) -> Result<u64, InvalidData> {
+    let mut total = 0;
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| InvalidData::new(&format!("Failed to read dir {}: {}", dir.display(), e), None))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| InvalidData::new(&format!("Failed to read entry: {}", e), None))?;
+        let metadata = std::fs::symlink_metadata(entry.path())
+            .map_err(|e| InvalidData::new(&format!("Failed to stat {}: {}", entry.path().display(), e), None))?;
+        if metadata.is_dir() {
+            total += image_size(&entry.path())?;
+        } else if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bintree::BinTree;
+    use crate::doebuild::EbuildMetadata;
+    use sha1::Digest;
+    use tempfile::TempDir;
+
+    fn test_ebuild() -> Ebuild {
+        Ebuild {
+            path: PathBuf::from("hello-1.0.ebuild"),
+            category: "app-misc".to_string(),
+            package: "hello".to_string(),
+            version: "1.0".to_string(),
+            metadata: EbuildMetadata {
+                description: Some("Hello world".to_string()),
+                homepage: None,
+                src_uri: vec![],
+                distfile_names: HashMap::new(),
+                license: Some("MIT".to_string()),
+                slot: "0".to_string(),
+                keywords: vec!["amd64".to_string()],
+                iuse: vec!["+nls".to_string(), "debug".to_string()],
+                depend: crate::dep::parse_dependencies("dev-libs/foo:2").unwrap(),
+                rdepend: vec![],
+                pdepend: vec![],
+                any_of: vec![(crate::depgraph::DepType::Runtime, vec![
+                    vec![crate::dep::Atom::new("app-editors/vim").unwrap()],
+                    vec![crate::dep::Atom::new("app-editors/emacs").unwrap(), crate::dep::Atom::new("app-emacs/foo").unwrap()],
+                ])],
+                required_use: None,
+                restrict: vec![],
+                properties: vec![],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_packages_index_roundtrip() {
+        let content = "ARCH: amd64\nPACKAGES: 1\nVERSION: 0\n\nCPV: app-misc/hello-1.0\nSLOT: 0\nUSE: nls\n";
+        let mut index = PackagesIndex::parse(content);
+        assert_eq!(index.header.get("ARCH"), Some(&"amd64".to_string()));
+        assert_eq!(index.packages["app-misc/hello-1.0"].get("USE"), Some(&"nls".to_string()));
+
+        let mut entry = BTreeMap::new();
+        entry.insert("SLOT".to_string(), "1".to_string());
+        index.update("app-misc/other-2.0", entry);
+        assert!(index.remove("app-misc/hello-1.0"));
+
+        let reparsed = PackagesIndex::parse(&index.to_string());
+        assert_eq!(reparsed.packages.len(), 1);
+        assert_eq!(reparsed.packages["app-misc/other-2.0"].get("CPV"), Some(&"app-misc/other-2.0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_build_metadata_use_and_deps() {
+        let mut use_flags = HashMap::new();
+        use_flags.insert("nls".to_string(), true);
+        use_flags.insert("debug".to_string(), false);
+        use_flags.insert("X".to_string(), true);
+        let mut extra = HashMap::new();
+        extra.insert("CFLAGS".to_string(), "-O2 -pipe".to_string());
+
+        let metadata = BinPkgBuilder::build_metadata(&test_ebuild(), &use_flags, &extra);
+        assert_eq!(metadata["USE"], b"nls".to_vec());
+        assert_eq!(metadata["DEPEND"], b"dev-libs/foo:2".to_vec());
+        assert_eq!(metadata["RDEPEND"], b"|| ( app-editors/vim ( app-editors/emacs app-emacs/foo ) )".to_vec());
+        assert_eq!(metadata["CFLAGS"], b"-O2 -pipe".to_vec());
+        assert_eq!(metadata["PF"], b"hello-1.0".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_create_binary_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("image");
+        std::fs::create_dir_all(image.join("usr/bin")).unwrap();
+        std::fs::write(image.join("usr/bin/hello"), "#!/bin/sh\necho hello\n").unwrap();
+        let pkgdir = temp_dir.path().join("packages");
+
+        // Recorded under the repository that holds the ebuild
+        let mut porttree = crate::porttree::PortTree::new("/");
+        porttree.parse_repos_conf("[gentoo]\nlocation = /var/db/repos/gentoo\n\n[overlay]\nlocation = /var/db/repos/overlay\n");
+        let repo = porttree.repository_name_of(Path::new("/var/db/repos/overlay/app-misc/hello/hello-1.0.ebuild")).unwrap();
+        assert_eq!(repo, "overlay");
+
+        let builder = BinPkgBuilder::new(pkgdir.to_str().unwrap());
+        let mut extra = HashMap::new();
+        extra.insert("CONTENTS".to_string(), "obj /usr/bin/hello 0 0\n".to_string());
+        extra.insert("repository".to_string(), repo.to_string());
+        let path = builder.create(&test_ebuild(), &image, &HashMap::new(), &extra).await.unwrap();
+        assert_eq!(path, pkgdir.join("app-misc/hello-1.0.tbz2"));
+
+        let bintree = BinTree::new("/").with_pkgdir(pkgdir.to_str().unwrap());
+        let info = bintree.parse_tbz2("app-misc/hello-1.0").await.unwrap().unwrap();
+        assert_eq!(info.metadata.get("CONTENTS"), Some(&"obj /usr/bin/hello 0 0\n".to_string()));
+        assert_eq!(info.metadata.get("SIZE"), Some(&"21".to_string()));
+
+        let index = PackagesIndex::load(&builder.index_path()).await.unwrap();
+        assert_eq!(index.header.get("PACKAGES"), Some(&"1".to_string()));
+        assert_eq!(index.packages["app-misc/hello-1.0"].get("LICENSE"), Some(&"MIT".to_string()));
+        assert_eq!(index.packages["app-misc/hello-1.0"].get("REPO"), Some(&"overlay".to_string()));
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(index.packages["app-misc/hello-1.0"]["SHA1"], format!("{:x}", sha1::Sha1::digest(&data)));
+
+        // Regeneration drops entries for packages that are gone
+        let mut stale = PackagesIndex::load(&builder.index_path()).await.unwrap();
+        stale.update("app-misc/gone-1.0", BTreeMap::new());
+        stale.save(&builder.index_path()).await.unwrap();
+        assert_eq!(builder.regenerate_index().await.unwrap(), 1);
+        let index = PackagesIndex::load(&builder.index_path()).await.unwrap();
+        assert_eq!(index.packages.keys().collect::<Vec<_>>(), vec!["app-misc/hello-1.0"]);
+        assert_eq!(index.packages["app-misc/hello-1.0"]["SIZE"], data.len().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_create_gpkg_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("image");
+        std::fs::create_dir_all(image.join("usr/bin")).unwrap();
+        std::fs::write(image.join("usr/bin/hello"), "#!/bin/sh\necho hello\n").unwrap();
+        let pkgdir = temp_dir.path().join("packages");
+
+        let builder = BinPkgBuilder::new(pkgdir.to_str().unwrap()).with_format(BinPkgFormat::Gpkg);
+        let path = builder.create(&test_ebuild(), &image, &HashMap::new(), &HashMap::new()).await.unwrap();
+        assert_eq!(path, pkgdir.join("app-misc/hello-1.0.gpkg.tar"));
+
+        let bintree = BinTree::new("/").with_pkgdir(pkgdir.to_str().unwrap());
+        assert_eq!(bintree.get_all_binpkgs().await.unwrap(), vec!["app-misc/hello-1.0".to_string()]);
+        let info = bintree.parse_binpkg("app-misc/hello-1.0").await.unwrap().unwrap();
+        assert_eq!(info.format, BinPkgFormat::Gpkg);
+        assert_eq!(info.metadata.get("LICENSE"), Some(&"MIT".to_string()));
+
+        let dest = temp_dir.path().join("extracted");
+        bintree.extract_gpkg_image("app-misc/hello-1.0", &dest).await.unwrap();
+        assert!(dest.join("usr/bin/hello").exists());
+
+        let index = PackagesIndex::load(&builder.index_path()).await.unwrap();
+        assert_eq!(index.packages["app-misc/hello-1.0"].get("PATH"), Some(&"app-misc/hello-1.0.gpkg.tar".to_string()));
+    }
+
+    /// Writes the file's length as its "signature"
+    #[derive(Debug)]
+    struct LengthSigner;
+
+    impl SignatureSigner for LengthSigner {
+        fn sign(&self, file: &Path, signature: &Path) -> Result<(), InvalidData> {
+            std::fs::write(signature, std::fs::metadata(file).unwrap().len().to_string()).unwrap();
+            Ok(())
+        }
+    }
+
+    struct LengthVerifier;
+
+    impl crate::bintree::SignatureVerifier for LengthVerifier {
+        fn verify(&self, file: &Path, signature: &Path) -> Result<bool, InvalidData> {
+            Ok(std::fs::read_to_string(signature).unwrap() == std::fs::metadata(file).unwrap().len().to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_gpkg() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("image");
+        std::fs::create_dir_all(image.join("usr/bin")).unwrap();
+        std::fs::write(image.join("usr/bin/hello"), "#!/bin/sh\necho hello\n").unwrap();
+        let signed = temp_dir.path().join("signed");
+        let unsigned = temp_dir.path().join("unsigned");
+
+        let err = BinPkgBuilder::new(signed.to_str().unwrap()).with_signer(Box::new(LengthSigner))
+            .create(&test_ebuild(), &image, &HashMap::new(), &HashMap::new()).await.unwrap_err();
+        assert!(err.to_string().contains("BINPKG_FORMAT=gpkg"));
+
+        BinPkgBuilder::new(signed.to_str().unwrap()).with_format(BinPkgFormat::Gpkg).with_signer(Box::new(LengthSigner))
+            .create(&test_ebuild(), &image, &HashMap::new(), &HashMap::new()).await.unwrap();
+        let bintree = BinTree::new("/").with_pkgdir(signed.to_str().unwrap());
+        assert_eq!(bintree.verify_gpkg("app-misc/hello-1.0", &LengthVerifier, true).await.unwrap(), 2);
+
+        BinPkgBuilder::new(unsigned.to_str().unwrap()).with_format(BinPkgFormat::Gpkg)
+            .create(&test_ebuild(), &image, &HashMap::new(), &HashMap::new()).await.unwrap();
+        let bintree = BinTree::new("/").with_pkgdir(unsigned.to_str().unwrap());
+        assert_eq!(bintree.verify_gpkg("app-misc/hello-1.0", &LengthVerifier, false).await.unwrap(), 0);
+        let err = bintree.verify_gpkg("app-misc/hello-1.0", &LengthVerifier, true).await.unwrap_err();
+        assert!(err.to_string().contains("is not signed"));
+    }
+}
@@ -1,6 +1,6 @@
 // bintree.rs -- Binary package database (/usr/portage/packages)
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use std::path::Path;
@@ -13,6 +13,8 @@ pub struct BinTree {
     pub pkgdir: String,
     pub binhost: Vec<String>,
     pub binhost_mirrors: Vec<String>,
+    /// binrepos.conf settings of the binhosts (fetchcommand, signatures)
+    pub binrepos: Vec<BinRepoConfig>,
 }
 
 #[derive(Debug)]
@@ -31,6 +33,303 @@ pub struct BinPkgInfo {
     pub path: String,
     pub tar_size: usize,
     pub metadata: HashMap<String, String>,
+    pub format: BinPkgFormat,
+}
+
+/// On-disk binary package format (BINPKG_FORMAT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BinPkgFormat {
+    #[default]
+    Xpak,
+    Gpkg,
+}
+
+impl BinPkgFormat {
+    /// Parse a BINPKG_FORMAT value
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "xpak" => Some(BinPkgFormat::Xpak),
+            "gpkg" => Some(BinPkgFormat::Gpkg),
+            _ => None,
+        }
+    }
+
+    /// File extension used for packages of this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BinPkgFormat::Xpak => "tbz2",
+            BinPkgFormat::Gpkg => "gpkg.tar",
+        }
+    }
+}
+
+/// Where binary packages may come from (--usepkg, --usepkgonly, --getbinpkg)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BinPkgPolicy {
+    /// Use packages from PKGDIR when they match
+    pub usepkg: bool,
+    /// Never build from source
+    pub usepkgonly: bool,
+    /// Also consider packages listed in the binhost Packages index
+    pub getbinpkg: bool,
+}
+
+impl BinPkgPolicy {
+    pub fn uses_binaries(&self) -> bool {
+        self.usepkg || self.usepkgonly || self.getbinpkg
+    }
+}
+
+/// A package listed in a binhost's Packages index
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RemoteBinPkg {
+    pub cpv: String,
+    pub slot: String,
+    pub keywords: Vec<String>,
+    pub iuse: Vec<String>,
+    pub use_flags: Vec<String>,
+    /// Location relative to the binhost URI
+    pub path: String,
+    pub size: Option<u64>,
+    /// Checksums the index gives, such as SHA1 and MD5
+    pub hashes: BTreeMap<String, String>,
+    pub binhost: String,
+}
+
+impl RemoteBinPkg {
+    pub fn format(&self) -> BinPkgFormat {
+        if self.path.ends_with(".gpkg.tar") { BinPkgFormat::Gpkg } else { BinPkgFormat::Xpak }
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}/{}", self.binhost.trim_end_matches('/'), self.path)
+    }
+}
+
+/// Packages listed in the Packages index of `binhost`
+pub fn parse_packages_index(content: &str, binhost: &str) -> Vec<RemoteBinPkg> {
+    crate::binpkg::PackagesIndex::parse(content).packages.into_iter()
+        .filter(|(cpv, fields)| match fields.get("PATH") {
+            // The cpv names the file in PKGDIR, so it has to be one
+            _ if crate::versions::Cpv::parse(cpv).is_err() => {
+                eprintln!("!!! Ignoring invalid CPV {:?} from {}", cpv, binhost);
+                false
+            }
+            Some(path) if !is_safe_index_path(path) => {
+                eprintln!("!!! Ignoring {} from {}: unsafe PATH {:?}", cpv, binhost, path);
+                false
+            }
+            _ => true,
+        })
+        .map(|(cpv, fields)| {
+            let words = |key: &str| fields.get(key).map(|v| v.split_whitespace().map(|s| s.to_string()).collect()).unwrap_or_default();
+            RemoteBinPkg {
+                slot: fields.get("SLOT").cloned().unwrap_or_else(|| "0".to_string()),
+                keywords: words("KEYWORDS"),
+                iuse: words("IUSE"),
+                use_flags: words("USE"),
+                path: fields.get("PATH").cloned().unwrap_or_else(|| format!("{}.{}", cpv, BinPkgFormat::Xpak.extension())),
+                size: fields.get("SIZE").and_then(|s| s.parse().ok()),
+                hashes: fields.iter()
+                    .filter(|(key, _)| crate::checksum::is_supported(key))
+                    .map(|(key, value)| (key.clone(), value.to_lowercase()))
+                    .collect(),
+                binhost: binhost.to_string(),
+                cpv,
+            }
+        })
+        .collect()
+}
+
+/// Whether a Packages index PATH stays below the binhost URI and is plain text: no
+/// `..`, no leading `/` and nothing a shell or URL would read specially
+pub fn is_safe_index_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && !path.contains("..")
+        && path.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+/@,=".contains(c))
+}
+
+/// Split a FETCHCOMMAND into its words as sh would: quotes and backslashes are honored,
+/// nothing is expanded
+pub fn split_command(command: &str) -> Result<Vec<String>, InvalidData> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => match chars.next() {
+                Some(next) if "\"\\$`".contains(next) => word.push(next),
+                Some(next) => {
+                    word.push('\\');
+                    word.push(next);
+                }
+                None => word.push('\\'),
+            },
+            (Some(_), c) => word.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, '\\') => {
+                word.extend(chars.next());
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(InvalidData::new(&format!("Unterminated quote in fetchcommand: {}", command), None));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Hook for checking detached signatures of GPKG members
+pub trait SignatureVerifier {
+    /// Verify `signature` against `file`, returning false if the signature is bad
+    fn verify(&self, file: &Path, signature: &Path) -> Result<bool, InvalidData>;
+}
+
+/// Verifies signatures with gpg, optionally against a dedicated keyring
+#[derive(Debug, Default)]
+pub struct GpgVerifier {
+    pub keyring: Option<String>,
+}
+
+impl SignatureVerifier for GpgVerifier {
+    fn verify(&self, file: &Path, signature: &Path) -> Result<bool, InvalidData> {
+        let mut cmd = std::process::Command::new("gpg");
+        cmd.arg("--batch");
+        if let Some(keyring) = &self.keyring {
+            cmd.args(["--no-default-keyring", "--keyring", keyring]);
+        }
+        let status = cmd.arg("--verify")
+            .arg(signature)
+            .arg(file)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map_err(|e| InvalidData::new(&format!("Failed to run gpg: {}", e), None))?;
+        Ok(status.success())
+    }
+}
+
+/// Hook for producing detached signatures of GPKG members (FEATURES=binpkg-signing)
+pub trait SignatureSigner: std::fmt::Debug + Send + Sync {
+    /// Write a detached signature of `file` to `signature`
+    fn sign(&self, file: &Path, signature: &Path) -> Result<(), InvalidData>;
+}
+
+/// Signs with gpg using BINPKG_GPG_SIGNING_KEY and BINPKG_GPG_SIGNING_GPG_HOME
+#[derive(Debug, Default)]
+pub struct GpgSigner {
+    pub key: Option<String>,
+    pub homedir: Option<String>,
+}
+
+impl SignatureSigner for GpgSigner {
+    fn sign(&self, file: &Path, signature: &Path) -> Result<(), InvalidData> {
+        let mut cmd = std::process::Command::new("gpg");
+        cmd.args(["--batch", "--yes", "--armor", "--detach-sign"]);
+        if let Some(homedir) = &self.homedir {
+            cmd.args(["--homedir", homedir]);
+        }
+        if let Some(key) = &self.key {
+            cmd.args(["--local-user", key]);
+        }
+        let output = cmd.arg("--output")
+            .arg(signature)
+            .arg(file)
+            .output()
+            .map_err(|e| InvalidData::new(&format!("Failed to run gpg: {}", e), None))?;
+        if !output.status.success() {
+            return Err(InvalidData::new(&format!("Failed to sign {}: {}", file.display(), String::from_utf8_lossy(&output.stderr).trim()), None));
+        }
+        Ok(())
+    }
+}
+
+/// A binary package repository from /etc/portage/binrepos.conf
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinRepoConfig {
+    pub name: String,
+    pub sync_uri: String,
+    pub priority: i32,
+    /// Refuse packages from this repository without valid signatures
+    pub verify_signature: bool,
+    /// gpg keyring holding the keys trusted to sign this repository's packages
+    pub trusted_keyring: Option<String>,
+    /// Download command with ${URI}, ${DISTDIR} and ${FILE} placeholders, instead of curl
+    pub fetchcommand: Option<String>,
+}
+
+impl BinRepoConfig {
+    /// An entry for a bare binhost URI (PORTAGE_BINHOST)
+    pub fn from_uri(name: &str, sync_uri: &str) -> Self {
+        BinRepoConfig {
+            name: name.to_string(),
+            sync_uri: sync_uri.to_string(),
+            priority: 0,
+            verify_signature: false,
+            trusted_keyring: None,
+            fetchcommand: None,
+        }
+    }
+}
+
+/// Parse binrepos.conf; [DEFAULT] values apply to every repository
+pub fn parse_binrepos_conf(content: &str) -> Vec<BinRepoConfig> {
+    let mut sections: Vec<(String, HashMap<String, String>)> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name.trim().to_string(), HashMap::new()));
+        } else if let Some((key, value)) = line.split_once('=')
+            && let Some((_, values)) = sections.last_mut()
+        {
+            values.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+
+    let defaults = sections.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("DEFAULT"))
+        .map(|(_, values)| values.clone())
+        .unwrap_or_default();
+    let mut repos: Vec<BinRepoConfig> = sections.into_iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("DEFAULT"))
+        .filter_map(|(name, values)| {
+            let get = |key: &str| values.get(key).or_else(|| defaults.get(key)).cloned();
+            Some(BinRepoConfig {
+                sync_uri: get("sync-uri")?,
+                priority: get("priority").and_then(|p| p.parse().ok()).unwrap_or(0),
+                verify_signature: get("verify-signature").is_some_and(|v| matches!(v.to_lowercase().as_str(), "true" | "yes" | "1")),
+                trusted_keyring: get("trusted-keyring"),
+                fetchcommand: get("fetchcommand"),
+                name,
+            })
+        })
+        .collect();
+    // Highest priority is tried first
+    repos.sort_by_key(|repo| std::cmp::Reverse(repo.priority));
+    repos
 }
 
 impl BinTree {
@@ -40,6 +339,7 @@ impl BinTree {
             pkgdir: format!("{}/usr/portage/packages", root),
             binhost: vec![],
             binhost_mirrors: vec![],
+            binrepos: vec![],
         }
     }
 
@@ -49,9 +349,66 @@ impl BinTree {
             pkgdir: format!("{}/usr/portage/packages", root),
             binhost,
             binhost_mirrors,
+            binrepos: vec![],
+        }
+    }
+
+    /// Use binrepos.conf repositories, consulted in priority order, as the binhosts
+    pub fn with_binrepos(mut self, binrepos: Vec<BinRepoConfig>) -> Self {
+        self.binhost = binrepos.iter().map(|repo| repo.sync_uri.clone()).collect();
+        self.binrepos = binrepos;
+        self
+    }
+
+    /// The binrepos.conf entry serving a URL
+    pub fn binrepo_for_url(&self, url: &str) -> Option<&BinRepoConfig> {
+        self.binrepos.iter().find(|repo| url.starts_with(repo.sync_uri.trim_end_matches('/')))
+    }
+
+    /// Download `url` to `dest` with the repository's fetchcommand, or curl.
+    /// With `newer_than`, curl only downloads if the remote file is newer.
+    async fn download(&self, url: &str, dest: &Path, newer_than: Option<&Path>) -> Result<(), String> {
+        let output = match self.binrepo_for_url(url).and_then(|repo| repo.fetchcommand.as_ref()) {
+            Some(fetchcommand) => {
+                let dir = dest.parent().unwrap_or(Path::new(".")).to_string_lossy().to_string();
+                let file = dest.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default();
+                // Substituted per word and run without a shell, so nothing in the URL is
+                // ever interpreted
+                let args: Vec<String> = split_command(fetchcommand).map_err(|e| e.value)?.iter()
+                    .map(|word| {
+                        [("URI", url), ("DISTDIR", dir.as_str()), ("FILE", file.as_str())].iter()
+                            .fold(word.clone(), |word, (name, value)| {
+                                word.replace(&format!("${{{}}}", name), value).replace(&format!("${}", name), value)
+                            })
+                    })
+                    .collect();
+                let Some((program, args)) = args.split_first() else {
+                    return Err("Empty fetchcommand".to_string());
+                };
+                tokio::process::Command::new(program).args(args).output().await
+            }
+            None => {
+                let mut cmd = tokio::process::Command::new("curl");
+                cmd.args(["--fail", "--location", "--silent", "--show-error", "--output"]).arg(dest);
+                if let Some(reference) = newer_than.filter(|p| p.exists()) {
+                    cmd.arg("--time-cond").arg(reference);
+                }
+                cmd.arg(url).output().await
+            }
+        };
+        match output {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+            Err(e) => Err(e.to_string()),
         }
     }
 
+    /// Use a PKGDIR other than the default location
+    pub fn with_pkgdir(mut self, pkgdir: &str) -> Self {
+        self.pkgdir = pkgdir.to_string();
+        self
+    }
+
     pub async fn get_all_binpkgs(&self) -> Result<Vec<String>, InvalidData> {
         let path = Path::new(&self.pkgdir);
         if !path.exists() {
@@ -62,20 +419,50 @@ impl BinTree {
         while let Some(entry) = entries.next_entry().await.map_err(|e| InvalidData::new(&format!("Failed to read entry: {}", e), None))? {
             let path = entry.path();
             let metadata = fs::metadata(&path).await.map_err(|e| InvalidData::new(&format!("Failed to read metadata: {}", e), None))?;
-            if metadata.is_file() && path.extension().and_then(|e| e.to_str()) == Some("tbz2") {
-                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                    // Remove .tbz2 extension to get cpv
-                    if let Some(cpv) = name.strip_suffix(".tbz2") {
-                        cpvs.push(cpv.to_string());
+            if metadata.is_file() {
+                if let Some(cpv) = path.file_name().and_then(|n| n.to_str()).and_then(Self::strip_binpkg_extension) {
+                    cpvs.push(cpv.to_string());
+                }
+            } else if metadata.is_dir() {
+                // Packages live in PKGDIR/category/PF.<ext>
+                let category = entry.file_name().to_string_lossy().to_string();
+                let mut pkgs = fs::read_dir(&path).await.map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", path.display(), e), None))?;
+                while let Some(pkg) = pkgs.next_entry().await.map_err(|e| InvalidData::new(&format!("Failed to read entry: {}", e), None))? {
+                    if let Some(pf) = pkg.file_name().to_str().and_then(Self::strip_binpkg_extension) {
+                        cpvs.push(format!("{}/{}", category, pf));
                     }
                 }
             }
         }
+        cpvs.sort();
+        cpvs.dedup();
         Ok(cpvs)
     }
 
+    /// Strip a known binary package extension from a file name
+    fn strip_binpkg_extension(name: &str) -> Option<&str> {
+        [BinPkgFormat::Xpak, BinPkgFormat::Gpkg].iter()
+            .find_map(|format| name.strip_suffix(&format!(".{}", format.extension())))
+    }
+
+    /// Locate the local package file for a cpv in any supported format
+    pub fn find_package(&self, cpv: &str) -> Option<(std::path::PathBuf, BinPkgFormat)> {
+        [BinPkgFormat::Xpak, BinPkgFormat::Gpkg].into_iter()
+            .map(|format| (Path::new(&self.pkgdir).join(format!("{}.{}", cpv, format.extension())), format))
+            .find(|(path, _)| path.exists())
+    }
+
+    /// Parse a local binary package in whichever format it was stored
+    pub async fn parse_binpkg(&self, cpv: &str) -> Result<Option<BinPkgInfo>, InvalidData> {
+        match self.find_package(cpv) {
+            Some((_, BinPkgFormat::Gpkg)) => self.parse_gpkg(cpv).await,
+            Some((_, BinPkgFormat::Xpak)) => self.parse_tbz2(cpv).await,
+            None => Ok(None),
+        }
+    }
+
     pub async fn get_binpkg_info(&self, cpv: &str) -> Result<Option<BinPkg>, InvalidData> {
-        match self.parse_tbz2(cpv).await? {
+        match self.parse_binpkg(cpv).await? {
             Some(info) => Ok(Some(BinPkg {
                 cpv: info.cpv,
                 slot: info.slot,
@@ -87,7 +474,74 @@ impl BinTree {
     }
 
     pub fn is_available(&self, cpv: &str) -> bool {
-        Path::new(&self.pkgdir).join(format!("{}.tbz2", cpv)).exists()
+        self.find_package(cpv).is_some()
+    }
+
+    /// Where the Packages index of a binhost is cached
+    pub fn packages_index_cache(&self, binhost: &str) -> std::path::PathBuf {
+        let host = binhost.split_once("://").map(|(_, rest)| rest).unwrap_or(binhost);
+        let dir: String = host.trim_end_matches('/').chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        Path::new(&self.root).join("var/cache/edb/binhost").join(dir).join("Packages")
+    }
+
+    /// Packages listed by every binhost. Each index is re-downloaded only when
+    /// newer than the cached copy; the cache is used if the binhost is unreachable.
+    pub async fn fetch_packages_index(&self) -> Result<Vec<RemoteBinPkg>, InvalidData> {
+        let mut packages = Vec::new();
+        for binhost in &self.binhost {
+            let cache = self.packages_index_cache(binhost);
+            if let Some(parent) = cache.parent() {
+                fs::create_dir_all(parent).await
+                    .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+            }
+            let url = format!("{}/Packages", binhost.trim_end_matches('/'));
+            let fresh = cache.with_extension("new");
+            match self.download(&url, &fresh, Some(&cache)).await {
+                Ok(()) => {
+                    // Nothing is written when the index hasn't changed
+                    if fs::metadata(&fresh).await.is_ok_and(|m| m.len() > 0) {
+                        fs::rename(&fresh, &cache).await
+                            .map_err(|e| InvalidData::new(&format!("Failed to update {}: {}", cache.display(), e), None))?;
+                    }
+                }
+                Err(e) => eprintln!("!!! Couldn't fetch {}: {}", url, e),
+            }
+            let _ = fs::remove_file(&fresh).await;
+
+            if let Ok(content) = fs::read_to_string(&cache).await {
+                packages.extend(parse_packages_index(&content, binhost));
+            }
+        }
+        Ok(packages)
+    }
+
+    /// Download a package listed in a Packages index into PKGDIR
+    pub async fn fetch_remote_package(&self, pkg: &RemoteBinPkg) -> Result<std::path::PathBuf, InvalidData> {
+        let local_path = Path::new(&self.pkgdir).join(format!("{}.{}", pkg.cpv, pkg.format().extension()));
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await
+                .map_err(|e| InvalidData::new(&format!("Failed to create pkgdir: {}", e), None))?;
+        }
+        if !self.download_binhost_package(&pkg.url(), &local_path).await? {
+            return Err(InvalidData::new(&format!("Couldn't download {}", pkg.url()), None));
+        }
+        let size = fs::metadata(&local_path).await.map(|m| m.len()).unwrap_or(0);
+        if let Some(expected) = pkg.size && expected != size {
+            let _ = fs::remove_file(&local_path).await;
+            return Err(InvalidData::new(&format!("{}: size {} does not match the Packages index ({})", pkg.cpv, size, expected), None));
+        }
+        if !pkg.hashes.is_empty() {
+            let (path, hashes) = (local_path.clone(), pkg.hashes.clone());
+            let verified = tokio::task::spawn_blocking(move || crate::checksum::verify_file(&path, None, &hashes)).await
+                .map_err(|e| InvalidData::new(&format!("Checksum task failed: {}", e), None))?;
+            if let Err(mismatch) = verified {
+                let _ = fs::remove_file(&local_path).await;
+                return Err(InvalidData::new(&format!("{}: checksum does not match the Packages index: {}", pkg.cpv, mismatch), None));
+            }
+        }
+        Ok(local_path)
     }
 
     /// Check if binary package is available from binhost
@@ -164,17 +618,12 @@ impl BinTree {
     async fn download_binhost_package(&self, url: &str, local_path: &Path) -> Result<bool, InvalidData> {
         println!("Fetching {} from {}", local_path.file_name().unwrap().to_string_lossy(), url);
 
-        match tokio::process::Command::new("curl")
-            .args(&["--silent", "--fail", "-o"])
-            .arg(local_path)
-            .arg(url)
-            .output()
-            .await {
-            Ok(output) if output.status.success() => {
+        match self.download(url, local_path, None).await {
+            Ok(()) => {
                 println!("Successfully downloaded {}", local_path.display());
                 Ok(true)
             },
-            _ => Ok(false), // Try next URL
+            Err(_) => Ok(false), // Try next URL
         }
     }
 
@@ -245,6 +694,223 @@ impl BinTree {
             path: pkg_path.to_string_lossy().to_string(),
             tar_size,
             metadata,
+            format: BinPkgFormat::Xpak,
+        }))
+    }
+
+    /// Unpack the outer tar of a .gpkg.tar into `dest`, returning the package directory
+    async fn unpack_gpkg(&self, pkg_path: &Path, dest: &Path) -> Result<std::path::PathBuf, InvalidData> {
+        let output = tokio::process::Command::new("tar")
+            .arg("-xf")
+            .arg(pkg_path)
+            .arg("-C")
+            .arg(dest)
+            .output()
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to unpack {}: {}", pkg_path.display(), e), None))?;
+        if !output.status.success() {
+            return Err(InvalidData::new(&format!("Failed to unpack {}: {}", pkg_path.display(), String::from_utf8_lossy(&output.stderr)), None));
+        }
+
+        // All members live in a single top-level directory next to the gpkg-1 marker
+        let mut entries = fs::read_dir(dest).await.map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", dest.display(), e), None))?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| InvalidData::new(&format!("Failed to read entry: {}", e), None))? {
+            if entry.path().join("gpkg-1").exists() {
+                return Ok(entry.path());
+            }
+        }
+        Err(InvalidData::new(&format!("Invalid gpkg format: no gpkg-1 marker in {}", pkg_path.display()), None))
+    }
+
+    /// Extract a compressed member tarball (e.g. metadata.tar.xz) into `dest`
+    async fn extract_member(member: &Path, dest: &Path) -> Result<(), InvalidData> {
+        let output = tokio::process::Command::new("tar")
+            .arg("-xaf")
+            .arg(member)
+            .arg("-C")
+            .arg(dest)
+            .output()
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to extract {}: {}", member.display(), e), None))?;
+        if !output.status.success() {
+            return Err(InvalidData::new(&format!("Failed to extract {}: {}", member.display(), String::from_utf8_lossy(&output.stderr)), None));
+        }
+        Ok(())
+    }
+
+    /// Find a member of an unpacked gpkg by its base name (any compression)
+    fn find_member(pkg_dir: &Path, name: &str) -> Option<std::path::PathBuf> {
+        std::fs::read_dir(pkg_dir).ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| {
+                p.file_name().and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(&format!("{}.tar", name)) && !n.ends_with(".sig"))
+            })
+    }
+
+    /// Parse a .gpkg.tar binary package and extract metadata
+    pub async fn parse_gpkg(&self, cpv: &str) -> Result<Option<BinPkgInfo>, InvalidData> {
+        let pkg_path = Path::new(&self.pkgdir).join(format!("{}.{}", cpv, BinPkgFormat::Gpkg.extension()));
+        if !pkg_path.exists() {
+            return Ok(None);
+        }
+
+        let temp = tempfile::TempDir::new()
+            .map_err(|e| InvalidData::new(&format!("Failed to create temp dir: {}", e), None))?;
+        let pkg_dir = self.unpack_gpkg(&pkg_path, temp.path()).await?;
+
+        let metadata_member = Self::find_member(&pkg_dir, "metadata")
+            .ok_or_else(|| InvalidData::new("Invalid gpkg format: missing metadata member", None))?;
+        Self::extract_member(&metadata_member, temp.path()).await?;
+
+        let mut metadata = HashMap::new();
+        let metadata_dir = temp.path().join("metadata");
+        if let Ok(entries) = std::fs::read_dir(&metadata_dir) {
+            for entry in entries.flatten() {
+                if let Ok(value) = std::fs::read_to_string(entry.path()) {
+                    metadata.insert(entry.file_name().to_string_lossy().to_string(), value);
+                }
+            }
+        }
+
+        let slot = metadata.get("SLOT").map(|s| s.trim().to_string()).unwrap_or_else(|| "0".to_string());
+        let repo = metadata.get("repository").map(|s| s.trim().to_string()).unwrap_or_else(|| "gentoo".to_string());
+        let tar_size = Self::find_member(&pkg_dir, "image")
+            .and_then(|p| std::fs::metadata(p).ok())
+            .map(|m| m.len() as usize)
+            .unwrap_or(0);
+
+        Ok(Some(BinPkgInfo {
+            cpv: cpv.to_string(),
+            slot,
+            repo,
+            path: pkg_path.to_string_lossy().to_string(),
+            tar_size,
+            metadata,
+            format: BinPkgFormat::Gpkg,
         }))
     }
-}
\ No newline at end of file
+
+    /// Verify the detached signatures shipped inside a gpkg.
+    /// Returns the number of members checked; fails on any bad signature,
+    /// and with `require_signatures` on any member that isn't signed.
+    pub async fn verify_gpkg(&self, cpv: &str, verifier: &dyn SignatureVerifier, require_signatures: bool) -> Result<usize, InvalidData> {
+        let pkg_path = Path::new(&self.pkgdir).join(format!("{}.{}", cpv, BinPkgFormat::Gpkg.extension()));
+        let temp = tempfile::TempDir::new()
+            .map_err(|e| InvalidData::new(&format!("Failed to create temp dir: {}", e), None))?;
+        let pkg_dir = self.unpack_gpkg(&pkg_path, temp.path()).await?;
+
+        let mut checked = 0;
+        for entry in std::fs::read_dir(&pkg_dir).map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", pkg_dir.display(), e), None))?.flatten() {
+            let sig_path = entry.path();
+            let Some(member) = sig_path.to_str().and_then(|p| p.strip_suffix(".sig")) else {
+                continue;
+            };
+            let member_path = Path::new(member);
+            if !member_path.exists() {
+                return Err(InvalidData::new(&format!("Signature {} has no matching member", sig_path.display()), None));
+            }
+            if !verifier.verify(member_path, &sig_path)? {
+                return Err(InvalidData::new(&format!("Bad signature for {} in {}", entry.file_name().to_string_lossy(), cpv), None));
+            }
+            checked += 1;
+        }
+
+        if require_signatures {
+            for entry in std::fs::read_dir(&pkg_dir).map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", pkg_dir.display(), e), None))?.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.contains(".tar") && !name.ends_with(".sig") && !pkg_dir.join(format!("{}.sig", name)).exists() {
+                    return Err(InvalidData::new(&format!("{}: {} is not signed, but signatures are required", cpv, name), None));
+                }
+            }
+        }
+        Ok(checked)
+    }
+
+    /// Extract the image of a gpkg into `dest` so it can be merged
+    pub async fn extract_gpkg_image(&self, cpv: &str, dest: &Path) -> Result<(), InvalidData> {
+        let pkg_path = Path::new(&self.pkgdir).join(format!("{}.{}", cpv, BinPkgFormat::Gpkg.extension()));
+        let temp = tempfile::TempDir::new()
+            .map_err(|e| InvalidData::new(&format!("Failed to create temp dir: {}", e), None))?;
+        let pkg_dir = self.unpack_gpkg(&pkg_path, temp.path()).await?;
+
+        let image_member = Self::find_member(&pkg_dir, "image")
+            .ok_or_else(|| InvalidData::new("Invalid gpkg format: missing image member", None))?;
+        Self::extract_member(&image_member, temp.path()).await?;
+
+        fs::create_dir_all(dest)
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", dest.display(), e), None))?;
+        let status = tokio::process::Command::new("cp")
+            .arg("-a")
+            .arg(format!("{}/.", temp.path().join("image").display()))
+            .arg(dest)
+            .status()
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to copy image: {}", e), None))?;
+        if !status.success() {
+            return Err(InvalidData::new("Failed to copy gpkg image", None));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_packages_index() {
+        let index = "ARCH: amd64\nVERSION: 0\n\nBUILD_ID: 1\nCPV: app-misc/foo-1.0\nIUSE: +doc test\nKEYWORDS: amd64\nPATH: app-misc/foo/foo-1.0-1.gpkg.tar\nSIZE: 1234\nSLOT: 0/1\nUSE: amd64 doc\n\nCPV: app-misc/bar-2.0\n";
+        let packages = parse_packages_index(index, "https://binhost.example/amd64/");
+        assert_eq!(packages.len(), 2);
+        let (bar, foo) = (&packages[0], &packages[1]);
+        assert_eq!(foo.slot, "0/1");
+        assert_eq!(foo.iuse, vec!["+doc", "test"]);
+        assert_eq!(foo.size, Some(1234));
+        assert_eq!(foo.format(), BinPkgFormat::Gpkg);
+        assert_eq!(foo.url(), "https://binhost.example/amd64/app-misc/foo/foo-1.0-1.gpkg.tar");
+        assert_eq!(bar.path, "app-misc/bar-2.0.tbz2");
+        assert_eq!(bar.slot, "0");
+
+        let bintree = BinTree::new("/tmp/root");
+        assert_eq!(bintree.packages_index_cache("https://binhost.example/amd64/"),
+            Path::new("/tmp/root/var/cache/edb/binhost/binhost.example_amd64/Packages"));
+    }
+
+    #[test]
+    fn test_parse_binrepos_conf() {
+        let conf = "[DEFAULT]\nverify-signature = true\n\n[gentoobinhost]\npriority = 1\nsync-uri = https://distfiles.gentoo.org/releases/amd64/binpackages/23.0/x86-64\ntrusted-keyring = /etc/portage/gentoo-release.gpg\n\n[local]\npriority = 9\nsync-uri = http://build.lan:8080\nverify-signature = no\nfetchcommand = wget -O \"${DISTDIR}/${FILE}\" \"${URI}\"\n\n[broken]\npriority = 5\n";
+        let repos = parse_binrepos_conf(conf);
+        assert_eq!(repos.len(), 2);
+        assert_eq!(repos[0].name, "local");
+        assert!(!repos[0].verify_signature);
+        assert_eq!(repos[1].name, "gentoobinhost");
+        assert!(repos[1].verify_signature);
+        assert_eq!(repos[1].trusted_keyring.as_deref(), Some("/etc/portage/gentoo-release.gpg"));
+        assert_eq!(repos[1].fetchcommand, None);
+
+        let bintree = BinTree::new("/").with_binrepos(repos);
+        assert_eq!(bintree.binhost, vec!["http://build.lan:8080", "https://distfiles.gentoo.org/releases/amd64/binpackages/23.0/x86-64"]);
+        assert_eq!(bintree.binrepo_for_url("http://build.lan:8080/Packages").map(|r| r.name.as_str()), Some("local"));
+        assert!(bintree.binrepos[0].fetchcommand.is_some());
+    }
+
+    #[test]
+    fn test_fetchcommand_and_index_paths() {
+        assert_eq!(split_command("wget -O \"${DISTDIR}/${FILE}\" '${URI}' --header=a\\ b").unwrap(),
+            ["wget", "-O", "${DISTDIR}/${FILE}", "${URI}", "--header=a b"]);
+        assert_eq!(split_command("curl \"a\\\"b\" ''").unwrap(), ["curl", "a\"b", ""]);
+        assert!(split_command("wget 'unterminated").is_err());
+
+        assert!(is_safe_index_path("app-misc/foo-1.0-r1.gpkg.tar"));
+        assert!(is_safe_index_path("app-misc/foo/foo-1.0-1.xpak"));
+        for path in ["../../etc/shadow", "/etc/shadow", "app-misc/foo$(id).tbz2", "a;rm -rf /", "a`id`", "a b", ""] {
+            assert!(!is_safe_index_path(path), "{}", path);
+        }
+        let index = "\nCPV: app-misc/evil-1.0\nPATH: app-misc/evil-1.0.tbz2;touch${IFS}/pwned\n\nCPV: app-misc/good-1.0\n\nCPV: ../../etc/passwd-1\n";
+        let packages = parse_packages_index(index, "https://binhost.example");
+        assert_eq!(packages.iter().map(|p| p.cpv.as_str()).collect::<Vec<_>>(), ["app-misc/good-1.0"]);
+    }
+}
@@ -0,0 +1,169 @@
+// cargo.rs -- built-in cargo.eclass: CRATES as distfiles, the vendor directory and cargo
+// config for offline builds, and the cargo build/install phases
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::eclass::ShellSource;
+use crate::exception::InvalidData;
+
+lazy_static! {
+    /// Pre-EAPI 8 `name-version` entries: the version is the last part that starts x.y.z
+    static ref LEGACY_CRATE_RE: Regex = Regex::new(r"^(?P<name>[A-Za-z0-9_-]+)-(?P<version>[0-9]+\.[0-9]+\.[0-9]+.*)$").unwrap();
+}
+
+/// Where crates are downloaded from, as ${CARGO_CRATE_URIS} names them
+pub const CRATES_MIRROR: &str = "https://static.crates.io/crates";
+
+/// A crate listed in CRATES
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crate {
+    pub name: String,
+    pub version: String,
+}
+
+impl Crate {
+    /// `name@version`, or the older `name-version`
+    pub fn parse(entry: &str) -> Option<Self> {
+        if let Some((name, version)) = entry.split_once('@') {
+            return (!name.is_empty() && !version.is_empty())
+                .then(|| Crate { name: name.to_string(), version: version.to_string() });
+        }
+        let caps = LEGACY_CRATE_RE.captures(entry)?;
+        Some(Crate { name: caps["name"].to_string(), version: caps["version"].to_string() })
+    }
+
+    /// The distfile, ${crate}.crate
+    pub fn filename(&self) -> String {
+        format!("{}-{}.crate", self.name, self.version)
+    }
+
+    pub fn uri(&self) -> String {
+        format!("{}/{}/{}", CRATES_MIRROR, self.name, self.filename())
+    }
+}
+
+/// The crates of an ebuild inheriting cargo, none otherwise
+pub fn ebuild_crates(content: &str) -> Vec<Crate> {
+    let source = ShellSource::parse(content);
+    if !source.inherits.iter().any(|eclass| eclass == "cargo") {
+        return Vec::new();
+    }
+    source.value("CRATES").unwrap_or_default()
+        .split_whitespace()
+        .filter_map(Crate::parse)
+        .collect()
+}
+
+/// ${ECARGO_HOME}: CARGO_HOME during the build
+pub fn cargo_home(workdir: &Path) -> PathBuf {
+    workdir.join("cargo_home")
+}
+
+/// ${ECARGO_VENDOR}: the crates, unpacked
+pub fn vendor_dir(workdir: &Path) -> PathBuf {
+    cargo_home(workdir).join("gentoo")
+}
+
+/// Point cargo at the vendor directory instead of crates.io and keep it off the network
+pub fn write_config(workdir: &Path) -> Result<(), InvalidData> {
+    let home = cargo_home(workdir);
+    let config = format!(
+        "[source.gentoo]\ndirectory = \"{}\"\n\n[source.crates-io]\nreplace-with = \"gentoo\"\nlocal-registry = \"/nonexistent\"\n\n[net]\noffline = true\n\n[term]\nverbose = true\ncolor = \"never\"\n",
+        vendor_dir(workdir).display());
+    fs::create_dir_all(&home)
+        .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", home.display(), e), None))?;
+    fs::write(home.join("config.toml"), config)
+        .map_err(|e| InvalidData::new(&format!("Failed to write cargo config: {}", e), None))
+}
+
+/// Give each unpacked crate the empty checksum file cargo wants from a directory source;
+/// the distfiles are verified against the Manifest instead
+pub fn write_checksums(vendor: &Path) -> Result<(), InvalidData> {
+    let entries = fs::read_dir(vendor)
+        .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", vendor.display(), e), None))?;
+    for entry in entries.flatten().filter(|e| e.path().is_dir()) {
+        fs::write(entry.path().join(".cargo-checksum.json"), "{\"package\":\"\",\"files\":{}}")
+            .map_err(|e| InvalidData::new(&format!("Failed to write checksum for {}: {}", entry.path().display(), e), None))?;
+    }
+    Ok(())
+}
+
+/// Source of the built-in cargo eclass; src_unpack is done natively, see BuildEnv
+pub const ECLASS: &str = r#"
+ECARGO_HOME=${WORKDIR}/cargo_home
+ECARGO_VENDOR=${ECARGO_HOME}/gentoo
+
+_cargo_env() {
+	export CARGO_HOME=${ECARGO_HOME}
+	export CARGO_TARGET_DIR=${CARGO_TARGET_DIR:-${WORKDIR}/cargo-target}
+	cd "${S}" || die
+}
+
+_cargo_profile() {
+	has debug ${USE} || echo --release
+}
+
+cargo_src_configure() {
+	:
+}
+
+cargo_src_compile() {
+	_cargo_env
+	cargo build --offline $(_cargo_profile) ${ECARGS} "$@" || die "cargo build failed"
+}
+
+cargo_src_test() {
+	_cargo_env
+	cargo test --offline $(_cargo_profile) ${ECARGS} "$@" || die "cargo test failed"
+}
+
+cargo_src_install() {
+	_cargo_env
+	local debug=
+	has debug ${USE} && debug=--debug
+	cargo install --offline --no-track ${debug} --path "${CARGO_INSTALL_PATH:-.}" --root "${D}/usr" ${ECARGS} "$@" || die "cargo install failed"
+	rm -f "${D}/usr/.crates.toml" "${D}/usr/.crates2.json"
+}
+
+EXPORT_FUNCTIONS src_configure src_compile src_test src_install
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ebuild_crates() {
+        assert_eq!(Crate::parse("serde@1.0.200"), Some(Crate { name: "serde".to_string(), version: "1.0.200".to_string() }));
+        let legacy = Crate::parse("utf8-ranges-1.0.4").unwrap();
+        assert_eq!((legacy.name.as_str(), legacy.version.as_str()), ("utf8-ranges", "1.0.4"));
+        assert_eq!(Crate::parse("wasi-0.11.0+wasi-snapshot-preview1").unwrap().name, "wasi");
+        assert_eq!(Crate::parse("nonsense"), None);
+
+        let content = "EAPI=8\nCRATES=\"\n\tlibc@0.2.155\n\tmemchr@2.7.4\n\"\ninherit cargo\nSRC_URI=\"${CARGO_CRATE_URIS}\"\n";
+        let crates = ebuild_crates(content);
+        assert_eq!(crates.len(), 2);
+        assert_eq!(crates[0].filename(), "libc-0.2.155.crate");
+        assert_eq!(crates[1].uri(), "https://static.crates.io/crates/memchr/memchr-2.7.4.crate");
+        assert!(ebuild_crates("CRATES=\"libc@0.2.155\"\n").is_empty());
+    }
+
+    #[test]
+    fn test_vendor_config() {
+        let temp = TempDir::new().unwrap();
+        let vendor = vendor_dir(temp.path());
+        fs::create_dir_all(vendor.join("libc-0.2.155")).unwrap();
+        write_config(temp.path()).unwrap();
+        write_checksums(&vendor).unwrap();
+
+        let config = fs::read_to_string(temp.path().join("cargo_home/config.toml")).unwrap();
+        assert!(config.contains(&format!("directory = \"{}\"", vendor.display())));
+        assert!(config.contains("replace-with = \"gentoo\"") && config.contains("offline = true"));
+        assert!(vendor.join("libc-0.2.155/.cargo-checksum.json").is_file());
+    }
+}
@@ -0,0 +1,139 @@
+// changelog.rs -- what changed in a package between the installed version and its upgrade,
+// for --changelog: the git history of the repository, else the package's ChangeLog
+
+use std::path::Path;
+use std::process::Command;
+
+/// Commits listed at most for one package, when where the installed version was added is
+/// not known
+const MAX_COMMITS: usize = 20;
+
+/// The changes to `cp` in the repository at `repo` from `installed` up to `target`: the
+/// commits since the installed ebuild was added when the repository is a git checkout,
+/// else the entries of the package's ChangeLog between the two versions
+pub fn changes(repo: &Path, cp: &str, installed: &str, target: &str) -> Vec<String> {
+    if repo.join(".git").exists()
+        && let Some(commits) = git_changes(repo, cp, installed)
+    {
+        return commits;
+    }
+    let pn = cp.rsplit('/').next().unwrap_or(cp);
+    std::fs::read_to_string(repo.join(cp).join("ChangeLog"))
+        .map(|content| changelog_changes(&content, pn, installed, target))
+        .unwrap_or_default()
+}
+
+fn git(repo: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git").arg("-C").arg(repo).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// One line per commit touching `cp` since the commit that added the `installed` ebuild,
+/// newest first
+fn git_changes(repo: &Path, cp: &str, installed: &str) -> Option<Vec<String>> {
+    let pn = cp.rsplit('/').next().unwrap_or(cp);
+    let ebuild = format!("{}/{}-{}.ebuild", cp, pn, installed);
+    let added = git(repo, &["log", "--diff-filter=A", "--format=%H", "-1", "--", &ebuild])?;
+    let format = "--format=%h %ad %an: %s";
+    let log = match added.trim() {
+        "" => git(repo, &["log", format, "--date=short", &format!("-{}", MAX_COMMITS), "--", cp])?,
+        added => git(repo, &["log", format, "--date=short", &format!("{}..HEAD", added), "--", cp])?,
+    };
+    Some(log.lines().map(|line| line.to_string()).collect())
+}
+
+/// The entries of a ChangeLog made while a version newer than `installed` and at most
+/// `target` was the latest: each "*pn-version (date)" release line and what follows it
+/// up to the next one. Entries are newest first, so this stops at the installed version
+pub fn changelog_changes(content: &str, pn: &str, installed: &str, target: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut taking = false;
+    for line in content.lines() {
+        if let Some(release) = line.strip_prefix('*') {
+            let version = release.split_whitespace().next()
+                .and_then(|p| p.strip_prefix(pn))
+                .and_then(|version| version.strip_prefix('-'));
+            let Some(version) = version else {
+                continue;
+            };
+            if crate::versions::vercmp(version, installed).is_some_and(|c| c <= 0) {
+                break;
+            }
+            taking = crate::versions::vercmp(version, target).is_some_and(|c| c <= 0);
+        }
+        if taking {
+            lines.push(line.to_string());
+        }
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CHANGELOG: &str = "\
+# ChangeLog for app-misc/foo
+# Copyright 1999-2024 Gentoo Authors
+
+*foo-1.3 (01 Mar 2024)
+
+  01 Mar 2024; Dev <dev@gentoo.org> +foo-1.3.ebuild:
+  Version bump.
+
+*foo-1.2 (01 Feb 2024)
+
+  15 Feb 2024; Dev <dev@gentoo.org> foo-1.2.ebuild:
+  Fix the build with gcc-14.
+
+  01 Feb 2024; Dev <dev@gentoo.org> +foo-1.2.ebuild:
+  Version bump.
+
+*foo-1.1 (01 Jan 2024)
+
+  01 Jan 2024; Dev <dev@gentoo.org> +foo-1.1.ebuild:
+  Initial import.
+";
+
+    #[test]
+    fn test_changelog_changes() {
+        let changes = changelog_changes(CHANGELOG, "foo", "1.1", "1.2");
+        assert_eq!(changes.first().map(|s| s.as_str()), Some("*foo-1.2 (01 Feb 2024)"));
+        assert_eq!(changes.last().map(|s| s.as_str()), Some("  Version bump."));
+        assert!(changes.iter().any(|line| line.contains("gcc-14")));
+        assert!(!changes.iter().any(|line| line.contains("1.3") || line.contains("Initial import")));
+
+        assert_eq!(changelog_changes(CHANGELOG, "foo", "1.1", "1.3").iter().filter(|line| line.starts_with('*')).count(), 2);
+        assert!(changelog_changes(CHANGELOG, "foo", "1.3", "1.3").is_empty());
+    }
+
+    #[test]
+    fn test_git_changes() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path();
+        let git = |args: &[&str]| assert!(Command::new("git").args(args).current_dir(repo)
+            .env("GIT_AUTHOR_NAME", "dev").env("GIT_AUTHOR_EMAIL", "dev@example.org")
+            .env("GIT_COMMITTER_NAME", "dev").env("GIT_COMMITTER_EMAIL", "dev@example.org")
+            .status().unwrap().success());
+        let commit = |file: &str, message: &str| {
+            std::fs::create_dir_all(repo.join("app-misc/foo")).unwrap();
+            std::fs::write(repo.join(file), message).unwrap();
+            git(&["add", "-A"]);
+            git(&["commit", "--quiet", "-m", message]);
+        };
+        git(&["init", "--quiet"]);
+        commit("app-misc/foo/foo-1.1.ebuild", "app-misc/foo: add 1.1");
+        commit("app-misc/foo/metadata.xml", "app-misc/foo: update maintainers");
+        commit("app-misc/foo/foo-1.2.ebuild", "app-misc/foo: add 1.2");
+        commit("app-misc/bar.txt", "app-misc/bar: unrelated");
+
+        let changes = changes(repo, "app-misc/foo", "1.1", "1.2");
+        assert_eq!(changes.len(), 2);
+        assert!(changes[0].ends_with("dev: app-misc/foo: add 1.2"), "{}", changes[0]);
+        assert!(changes[1].ends_with("dev: app-misc/foo: update maintainers"), "{}", changes[1]);
+    }
+}
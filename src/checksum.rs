@@ -0,0 +1,177 @@
+// checksum.rs -- digests for the hash types of Manifests and Packages indexes, computed
+// over a file in one streaming pass however many are asked for
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::digest::DynDigest;
+
+/// Hash names as Manifests spell them, in the order they sort there
+pub const HASHES: [&str; 9] = ["BLAKE2B", "BLAKE2S", "MD5", "RMD160", "SHA1", "SHA256", "SHA3_256", "SHA3_512", "SHA512"];
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn hasher(algo: &str) -> Option<Box<dyn DynDigest>> {
+    Some(match algo {
+        "BLAKE2B" => Box::new(blake2::Blake2b512::default()),
+        "BLAKE2S" => Box::new(blake2::Blake2s256::default()),
+        "MD5" => Box::new(md5::Md5::default()),
+        "RMD160" => Box::new(ripemd::Ripemd160::default()),
+        "SHA1" => Box::new(sha1::Sha1::default()),
+        "SHA256" => Box::new(sha2::Sha256::default()),
+        "SHA3_256" => Box::new(sha3::Sha3_256::default()),
+        "SHA3_512" => Box::new(sha3::Sha3_512::default()),
+        "SHA512" => Box::new(sha2::Sha512::default()),
+        _ => return None,
+    })
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn is_supported(algo: &str) -> bool {
+    HASHES.contains(&algo)
+}
+
+/// Hex digest of `data`, or None if the algorithm is unsupported
+pub fn hash_bytes(data: &[u8], algo: &str) -> Option<String> {
+    let mut hasher = hasher(algo)?;
+    hasher.update(data);
+    Some(hex(&hasher.finalize()))
+}
+
+/// Size and hex digests of a stream
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checksums {
+    pub size: u64,
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// Digest `reader` once for all of `algos`; unsupported ones are left out
+pub fn hash_reader(mut reader: impl Read, algos: &[&str]) -> std::io::Result<Checksums> {
+    let mut hashers: Vec<(&str, Box<dyn DynDigest>)> = algos.iter()
+        .filter_map(|&algo| hasher(algo).map(|hasher| (algo, hasher)))
+        .collect();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        size += read as u64;
+        for (_, hasher) in &mut hashers {
+            hasher.update(&buffer[..read]);
+        }
+    }
+    let hashes = hashers.into_iter().map(|(algo, hasher)| (algo.to_string(), hex(&hasher.finalize()))).collect();
+    Ok(Checksums { size, hashes })
+}
+
+pub fn hash_file(path: &Path, algos: &[&str]) -> std::io::Result<Checksums> {
+    hash_reader(std::fs::File::open(path)?, algos)
+}
+
+/// Why a file failed verification
+#[derive(Debug)]
+pub enum Mismatch {
+    Unreadable(std::io::Error),
+    Size { expected: u64, actual: u64 },
+    Digest { algo: String, expected: String, actual: String },
+    /// None of the expected hashes is one this module computes
+    NoSupportedHash,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Unreadable(e) => write!(f, "{}", e),
+            Mismatch::Size { expected, actual } => write!(f, "size\n  expected: {}\n  actual:   {}", expected, actual),
+            Mismatch::Digest { algo, expected, actual } => write!(f, "{}\n  expected: {}\n  actual:   {}", algo, expected, actual),
+            Mismatch::NoSupportedHash => write!(f, "no supported hash"),
+        }
+    }
+}
+
+/// Check a file against an expected size and hex digests, hashing it once for all of
+/// them. A wrong size is caught before anything is read
+pub fn verify_file(path: &Path, size: Option<u64>, expected: &BTreeMap<String, String>) -> Result<(), Mismatch> {
+    let actual_size = std::fs::metadata(path).map_err(Mismatch::Unreadable)?.len();
+    if let Some(size) = size && size != actual_size {
+        return Err(Mismatch::Size { expected: size, actual: actual_size });
+    }
+    let algos: Vec<&str> = expected.keys().map(|algo| algo.as_str()).filter(|algo| is_supported(algo)).collect();
+    if algos.is_empty() {
+        return Err(Mismatch::NoSupportedHash);
+    }
+    let actual = hash_file(path, &algos).map_err(Mismatch::Unreadable)?;
+    if let Some(size) = size && size != actual.size {
+        return Err(Mismatch::Size { expected: size, actual: actual.size });
+    }
+    for (algo, digest) in actual.hashes {
+        if !expected[&algo].eq_ignore_ascii_case(&digest) {
+            return Err(Mismatch::Digest { expected: expected[&algo].clone(), algo, actual: digest });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_known_digests() {
+        let digests = [
+            ("MD5", "900150983cd24fb0d6963f7d28e17f72"),
+            ("SHA1", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            ("RMD160", "8eb208f7e05d987a9b044a8e98c6b087f15a0bfc"),
+            ("SHA256", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            ("SHA3_256", "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"),
+            ("BLAKE2S", "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"),
+        ];
+        for (algo, digest) in digests {
+            assert_eq!(hash_bytes(b"abc", algo).as_deref(), Some(digest), "{}", algo);
+        }
+        assert_eq!(hash_bytes(b"abc", "SHA3_512").unwrap().len(), 128);
+        assert_eq!(hash_bytes(b"abc", "WHIRLPOOL"), None);
+
+        // One pass over a file larger than a chunk gives what hashing it whole does
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 17).map(|i| (i % 251) as u8).collect();
+        let checksums = hash_reader(&data[..], &HASHES).unwrap();
+        assert_eq!(checksums.size, data.len() as u64);
+        assert_eq!(checksums.hashes.len(), HASHES.len());
+        for algo in HASHES {
+            assert_eq!(checksums.hashes[algo], hash_bytes(&data, algo).unwrap(), "{}", algo);
+        }
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("foo-1.0.tar.gz");
+        std::fs::write(&path, b"abc").unwrap();
+        let mut expected = BTreeMap::from([
+            ("SHA3_512".to_string(), hash_bytes(b"abc", "SHA3_512").unwrap()),
+            ("MD5".to_string(), "900150983CD24FB0D6963F7D28E17F72".to_string()),
+            ("WHIRLPOOL".to_string(), "0".repeat(128)),
+        ]);
+        assert!(verify_file(&path, Some(3), &expected).is_ok());
+        assert!(matches!(verify_file(&path, Some(4), &expected), Err(Mismatch::Size { expected: 4, actual: 3 })));
+
+        expected.insert("SHA1".to_string(), "0".repeat(40));
+        let Err(Mismatch::Digest { algo, .. }) = verify_file(&path, None, &expected) else {
+            panic!("the SHA1 mismatch is not reported");
+        };
+        assert_eq!(algo, "SHA1");
+        let unsupported = BTreeMap::from([("WHIRLPOOL".to_string(), "0".repeat(128))]);
+        assert!(matches!(verify_file(&path, None, &unsupported), Err(Mismatch::NoSupportedHash)));
+        assert!(matches!(verify_file(&temp.path().join("missing"), None, &expected), Err(Mismatch::Unreadable(_))));
+    }
+}
@@ -5,10 +5,16 @@ use tokio::fs;
 use std::path::{Path, PathBuf};
 use crate::exception::InvalidData;
 use crate::profile::{ProfileManager, ProfileSettings};
+use crate::bintree::{BinPkgFormat, BinRepoConfig};
 
 #[derive(Debug)]
 pub struct Config {
+    /// PORTAGE_CONFIGROOT: where etc/portage is read from
     pub root: String,
+    /// ROOT: where packages are merged
+    pub target_root: String,
+    /// SYSROOT: where build dependencies are looked up by the toolchain
+    pub sysroot: String,
     pub make_conf: HashMap<String, String>,
     pub profile_settings: ProfileSettings,
     pub use_flags: Vec<String>,
@@ -19,16 +25,75 @@ pub struct Config {
     pub package_keywords: HashMap<String, Vec<String>>,
     pub package_mask: HashSet<String>,
     pub package_unmask: HashSet<String>,
+    /// package.env: atom -> files under /etc/portage/env
+    pub package_env: HashMap<String, Vec<String>>,
     pub sets_conf: HashMap<String, Vec<String>>,
     // Binary package repository (binhost) configuration
     pub binhost: Vec<String>, // List of binhost URIs
     pub binhost_mirrors: Vec<String>, // Additional binhost mirrors
+    pub pkgdir: String, // Local binary package directory (PKGDIR)
+    pub binpkg_format: BinPkgFormat, // Format for newly built packages (BINPKG_FORMAT)
+    pub binrepos: Vec<BinRepoConfig>, // Binary repositories from binrepos.conf
+}
+
+/// Global USE flags and package.use layers (profile first, then user)
+#[derive(Debug, Clone, Default)]
+pub struct UseConfig {
+    pub global: HashMap<String, bool>,
+    pub package_use: Vec<HashMap<String, Vec<String>>>,
+}
+
+impl UseConfig {
+    /// Effective USE of a version given its IUSE defaults
+    pub fn flags_for(&self, cpv: &str, iuse_defaults: &HashMap<String, bool>) -> HashMap<String, bool> {
+        let layers: Vec<&HashMap<String, Vec<String>>> = self.package_use.iter().collect();
+        crate::autounmask::effective_use(iuse_defaults, &self.global, &matching_package_use(&layers, cpv))
+    }
+}
+
+/// Flags of the package.use entries whose atoms match `cpv`, later layers last
+fn matching_package_use(layers: &[&HashMap<String, Vec<String>>], cpv: &str) -> Vec<String> {
+    let mut flags = Vec::new();
+    for entries in layers {
+        let mut matching: Vec<(&String, &Vec<String>)> = entries.iter()
+            .filter(|(atom, _)| crate::atom::Atom::new(atom).map(|a| a.matches(cpv)).unwrap_or(false))
+            .collect();
+        matching.sort();
+        for (_, package_flags) in matching {
+            // "VIDEO_CARDS: amdgpu" prefixes the following flags until the next such token
+            let mut prefix = String::new();
+            for flag in package_flags {
+                if let Some(var) = flag.strip_suffix(':') {
+                    prefix = format!("{}_", var.to_lowercase());
+                    continue;
+                }
+                flags.push(match flag.strip_prefix('-') {
+                    Some(flag) => format!("-{}{}", prefix, flag),
+                    None => format!("{}{}", prefix, flag),
+                });
+            }
+        }
+    }
+    flags
+}
+
+/// Split a USE_EXPAND flag into its variable and value (video_cards_amdgpu is
+/// VIDEO_CARDS and amdgpu), preferring the longest matching prefix
+pub fn split_use_expand<'a, 'b>(flag: &'a str, use_expand: &'b [String]) -> Option<(&'b str, &'a str)> {
+    use_expand.iter()
+        .filter_map(|var| {
+            let value = flag.strip_prefix(&var.to_lowercase())?.strip_prefix('_')?;
+            (!value.is_empty()).then_some((var.as_str(), value))
+        })
+        .max_by_key(|(var, _)| var.len())
 }
 
 impl Config {
     pub async fn new(root: &str) -> Result<Self, InvalidData> {
         let mut config = Config {
             root: root.to_string(),
+            target_root: "/".to_string(),
+            sysroot: "/".to_string(),
             make_conf: HashMap::new(),
             profile_settings: ProfileSettings::default(),
             use_flags: vec![],
@@ -38,9 +103,13 @@ impl Config {
             package_keywords: HashMap::new(),
             package_mask: HashSet::new(),
             package_unmask: HashSet::new(),
+            package_env: HashMap::new(),
             sets_conf: HashMap::new(),
             binhost: vec![],
             binhost_mirrors: vec![],
+            pkgdir: Path::new(root).join("usr/portage/packages").to_string_lossy().to_string(),
+            binpkg_format: BinPkgFormat::default(),
+            binrepos: vec![],
         };
 
         // Load profile settings first (lower precedence)
@@ -55,6 +124,14 @@ impl Config {
         // Parse FEATURES from make.conf
         config.parse_features();
 
+        // ROOT and SYSROOT may be set in make.conf, --root and --sysroot override them
+        if let Some(root) = config.make_conf.get("ROOT").filter(|r| !r.is_empty()) {
+            config.target_root = root.clone();
+        }
+        if let Some(sysroot) = config.make_conf.get("SYSROOT").filter(|r| !r.is_empty()) {
+            config.sysroot = sysroot.clone();
+        }
+
         // Parse binhost configuration from make.conf
         config.parse_binhost_config();
 
@@ -63,7 +140,9 @@ impl Config {
         config.load_package_keywords().await?;
         config.load_package_mask().await?;
         config.load_package_unmask().await?;
+        config.load_package_env().await?;
         config.load_sets_conf().await?;
+        config.load_binrepos_conf().await?;
 
         // Parse USE flags from both sources
         config.parse_use_flags();
@@ -92,7 +171,9 @@ impl Config {
 
     async fn load_package_keywords(&mut self) -> Result<(), InvalidData> {
         let package_keywords_path = Path::new(&self.root).join("etc/portage/package.keywords");
-        Self::load_package_config_files(package_keywords_path, &mut self.package_keywords).await
+        Self::load_package_config_files(package_keywords_path, &mut self.package_keywords).await?;
+        let accept_keywords_path = Path::new(&self.root).join("etc/portage/package.accept_keywords");
+        Self::load_package_config_files(accept_keywords_path, &mut self.package_keywords).await
     }
 
     async fn load_package_mask(&mut self) -> Result<(), InvalidData> {
@@ -105,6 +186,11 @@ impl Config {
         Self::load_package_list_files(package_unmask_path, &mut self.package_unmask).await
     }
 
+    async fn load_package_env(&mut self) -> Result<(), InvalidData> {
+        let package_env_path = Path::new(&self.root).join("etc/portage/package.env");
+        Self::load_package_config_files(package_env_path, &mut self.package_env).await
+    }
+
     async fn load_sets_conf(&mut self) -> Result<(), InvalidData> {
         let sets_conf_path = Path::new(&self.root).join("etc/portage/sets.conf");
         if sets_conf_path.exists() {
@@ -116,6 +202,47 @@ impl Config {
         Ok(())
     }
 
+    /// Load /etc/portage/binrepos.conf (a file or a directory of files). PORTAGE_BINHOST
+    /// URIs become priority-0 repositories; `binhost` lists every URI in priority order.
+    async fn load_binrepos_conf(&mut self) -> Result<(), InvalidData> {
+        let path = Path::new(&self.root).join("etc/portage/binrepos.conf");
+        let mut files = Vec::new();
+        if path.is_dir() {
+            let mut entries = fs::read_dir(&path).await
+                .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", path.display(), e), None))?;
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                files.push(entry.path());
+            }
+            files.sort();
+        } else if path.exists() {
+            files.push(path);
+        }
+
+        let mut content = String::new();
+        for file in files {
+            let text = fs::read_to_string(&file).await
+                .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", file.display(), e), None))?;
+            content.push_str(&text);
+            content.push('\n');
+        }
+        let configured = crate::bintree::parse_binrepos_conf(&content);
+        let mut binrepos: Vec<BinRepoConfig> = self.binhost.iter()
+            .filter(|uri| !configured.iter().any(|repo| &repo.sync_uri == *uri))
+            .map(|uri| BinRepoConfig::from_uri("PORTAGE_BINHOST", uri))
+            .collect();
+        binrepos.extend(configured);
+        // Stable, so PORTAGE_BINHOST stays ahead of binrepos.conf entries of equal priority
+        binrepos.sort_by_key(|repo| std::cmp::Reverse(repo.priority));
+        self.binhost = binrepos.iter().map(|repo| repo.sync_uri.clone()).collect();
+        self.binrepos = binrepos;
+        Ok(())
+    }
+
+    /// The binrepos.conf entry a binhost URI belongs to
+    pub fn binrepo_for(&self, uri: &str) -> Option<&BinRepoConfig> {
+        self.binrepos.iter().find(|repo| repo.sync_uri.trim_end_matches('/') == uri.trim_end_matches('/'))
+    }
+
     async fn load_profile_settings(&mut self) -> Result<(), InvalidData> {
         let profile_manager = ProfileManager::new(&self.root);
         let profile = profile_manager.get_current_profile().await
@@ -227,8 +354,9 @@ impl Config {
                 continue;
             }
 
+            // A bare atom is meaningful in package.accept_keywords (accept ~ARCH)
             let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 {
+            if !parts.is_empty() {
                 let package = parts[0].to_string();
                 let flags: Vec<String> = parts[1..].iter().map(|s| s.to_string()).collect();
                 target.insert(package, flags);
@@ -246,10 +374,14 @@ impl Config {
         }
     }
 
-    /// Parse sets.conf content
+    /// Parse sets.conf content: "name atom..." lines before any [section]. Sections
+    /// are portage-style set definitions, handled by sets::parse_sets_conf.
     fn parse_package_list_config(content: &str, target: &mut HashMap<String, Vec<String>>) {
         for line in content.lines() {
             let line = line.trim();
+            if line.starts_with('[') {
+                break;
+            }
             if line.is_empty() || line.starts_with('#') {
                 continue;
             }
@@ -274,11 +406,56 @@ impl Config {
             self.use_flags.extend(use_str.split_whitespace().map(|s| s.to_string()));
         }
 
+        // USE_EXPAND variables become prefixed flags (VIDEO_CARDS="amdgpu" is video_cards_amdgpu),
+        // USE_EXPAND_UNPREFIXED ones (ARCH) plain flags
+        let expanded: Vec<String> = [(self.use_expand(), true), (self.use_expand_unprefixed(), false)]
+            .into_iter()
+            .flat_map(|(vars, prefixed)| vars.into_iter().map(move |var| (var, prefixed)))
+            .flat_map(|(var, prefixed)| {
+                let values: Vec<String> = [self.profile_settings.variables.get(&var), self.make_conf.get(&var)]
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|value| value.split_whitespace().map(|v| v.to_string()).collect::<Vec<_>>())
+                    .collect();
+                let prefix = if prefixed { format!("{}_", var.to_lowercase()) } else { String::new() };
+                values.into_iter().map(move |value| match value.strip_prefix('-') {
+                    Some(value) => format!("-{}{}", prefix, value),
+                    None => format!("{}{}", prefix, value),
+                })
+            })
+            .collect();
+        self.use_flags.extend(expanded);
+
         // Remove duplicates while preserving order
         let mut seen = std::collections::HashSet::new();
         self.use_flags.retain(|flag| seen.insert(flag.clone()));
     }
 
+    /// Names listed in a variable that is incremental across the profile and make.conf
+    fn incremental_names(&self, key: &str) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for value in [self.profile_settings.variables.get(key), self.make_conf.get(key)].into_iter().flatten() {
+            for name in value.split_whitespace() {
+                match name.strip_prefix('-') {
+                    Some(name) => names.retain(|n| n != name),
+                    None if !names.iter().any(|n| n == name) => names.push(name.to_string()),
+                    None => {}
+                }
+            }
+        }
+        names
+    }
+
+    /// USE_EXPAND: variables whose values are USE flags prefixed with the lowercased name
+    pub fn use_expand(&self) -> Vec<String> {
+        self.incremental_names("USE_EXPAND")
+    }
+
+    /// USE_EXPAND_UNPREFIXED: variables whose values are USE flags as they are
+    pub fn use_expand_unprefixed(&self) -> Vec<String> {
+        self.incremental_names("USE_EXPAND_UNPREFIXED")
+    }
+
     fn parse_accept_keywords(&mut self) {
         // Start with ACCEPT_KEYWORDS from profile (make.defaults)
         if let Some(keywords_str) = self.profile_settings.variables.get("ACCEPT_KEYWORDS") {
@@ -299,6 +476,16 @@ impl Config {
         self.make_conf.get(key).or_else(|| self.profile_settings.variables.get(key))
     }
 
+    /// ACCEPT_LICENSE, which is incremental: the profile's value, then make.conf's
+    pub fn accept_license(&self) -> Option<String> {
+        let values: Vec<&str> = [self.profile_settings.variables.get("ACCEPT_LICENSE"), self.make_conf.get("ACCEPT_LICENSE")]
+            .into_iter()
+            .flatten()
+            .map(|value| value.as_str())
+            .collect();
+        (!values.is_empty()).then(|| values.join(" "))
+    }
+
     /// Get USE flags as a HashMap for dependency resolution
     pub fn get_use_flags_map(&self) -> std::collections::HashMap<String, bool> {
         let mut use_map = std::collections::HashMap::new();
@@ -322,6 +509,90 @@ impl Config {
         self.package_use.get(package).or_else(|| self.profile_settings.package_use.get(package))
     }
 
+    /// package.use flags for a specific version, matching atoms such as ">=cat/pkg-1.0"
+    /// (profile entries first, so user entries win)
+    pub fn package_use_for(&self, cpv: &str) -> Vec<String> {
+        matching_package_use(&[&self.profile_settings.package_use, &self.package_use], cpv)
+    }
+
+    /// Snapshot of the USE settings, for code that outlives the Config
+    pub fn use_config(&self) -> UseConfig {
+        UseConfig {
+            global: self.get_use_flags_map(),
+            package_use: vec![self.profile_settings.package_use.clone(), self.package_use.clone()],
+        }
+    }
+
+    /// Variables from the /etc/portage/env files that package.env assigns to a version
+    pub fn package_env_for(&self, cpv: &str) -> HashMap<String, String> {
+        let mut matching: Vec<(&String, &Vec<String>)> = self.package_env.iter()
+            .filter(|(atom, _)| crate::atom::Atom::new(atom).map(|a| a.matches(cpv)).unwrap_or(false))
+            .collect();
+        matching.sort();
+
+        let mut vars = HashMap::new();
+        for name in matching.into_iter().flat_map(|(_, names)| names) {
+            let path = Path::new(&self.root).join("etc/portage/env").join(name);
+            if let Ok(content) = std::fs::read_to_string(&path) {
+                Self::parse_config_file(&content, &mut vars);
+            }
+        }
+        vars
+    }
+
+    /// Variables exported to a version's build: profile and make.conf settings overlaid
+    /// with its package.env files (FEATURES and USE are resolved separately)
+    pub fn build_env_for(&self, cpv: &str) -> HashMap<String, String> {
+        let mut vars = self.profile_settings.variables.clone();
+        vars.extend(self.make_conf.iter().map(|(k, v)| (k.clone(), v.clone())));
+        vars.extend(self.package_env_for(cpv));
+        vars.remove("FEATURES");
+        vars.remove("USE");
+        vars.extend(self.root_vars());
+        vars
+    }
+
+    /// Merge into `root`, building against `sysroot`; "/" for either keeps the default
+    pub fn with_roots(mut self, root: &str, sysroot: &str) -> Self {
+        if root != "/" {
+            self.target_root = root.to_string();
+        }
+        if sysroot != "/" {
+            self.sysroot = sysroot.to_string();
+        }
+        self
+    }
+
+    /// ROOT, SYSROOT and friends as EAPI 7 ebuilds see them: no trailing slash, so "/"
+    /// is empty
+    pub fn root_vars(&self) -> HashMap<String, String> {
+        let unslashed = |path: &str| path.trim_end_matches('/').to_string();
+        let (root, sysroot) = (unslashed(&self.target_root), unslashed(&self.sysroot));
+        HashMap::from([
+            ("ROOT".to_string(), root.clone()),
+            ("EROOT".to_string(), root),
+            ("SYSROOT".to_string(), sysroot.clone()),
+            ("ESYSROOT".to_string(), sysroot),
+            ("BROOT".to_string(), String::new()),
+            ("PORTAGE_CONFIGROOT".to_string(), format!("{}/", unslashed(&self.root))),
+        ])
+    }
+
+    /// FEATURES for a version, with incremental changes from its package.env files
+    pub fn features_for(&self, cpv: &str) -> Vec<String> {
+        let mut features = self.features.clone();
+        if let Some(changes) = self.package_env_for(cpv).get("FEATURES") {
+            for change in changes.split_whitespace() {
+                match change.strip_prefix('-') {
+                    Some(name) => features.retain(|f| f != name),
+                    None if !features.iter().any(|f| f == change) => features.push(change.to_string()),
+                    None => {}
+                }
+            }
+        }
+        features
+    }
+
     /// Check if a package is masked (user config overrides profile)
     pub fn is_package_masked(&self, package: &str) -> bool {
         self.package_mask.contains(package) || self.profile_settings.package_mask.contains(package)
@@ -372,7 +643,7 @@ impl Config {
         // Add default features if none specified
         if self.features.is_empty() {
             // Add some reasonable defaults for Gentoo-like behavior
-            self.features = vec!["sandbox".to_string(), "userpriv".to_string()];
+            self.features = vec!["sandbox".to_string(), "userpriv".to_string(), "preserve-libs".to_string(), "distlocks".to_string()];
         }
     }
 
@@ -387,6 +658,16 @@ impl Config {
         if let Some(mirrors_str) = self.make_conf.get("PORTAGE_BINHOST_MIRRORS") {
             self.binhost_mirrors = mirrors_str.split_whitespace().map(|s| s.to_string()).collect();
         }
+
+        // Parse PKGDIR
+        if let Some(pkgdir) = self.make_conf.get("PKGDIR") {
+            self.pkgdir = pkgdir.clone();
+        }
+
+        // Parse BINPKG_FORMAT, keeping the default for unknown values
+        if let Some(format) = self.get_var("BINPKG_FORMAT").and_then(|f| BinPkgFormat::parse(f)) {
+            self.binpkg_format = format;
+        }
     }
 }
 
@@ -466,6 +747,26 @@ another-set app-misc/foo
         assert_eq!(util_flags, Some(&vec!["-static".to_string()]));
     }
 
+    #[tokio::test]
+    async fn test_roots() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_root = temp_dir.path().to_str().unwrap();
+        fs::create_dir_all(temp_dir.path().join("etc/portage")).unwrap();
+        fs::write(temp_dir.path().join("etc/portage/make.conf"), "SYSROOT=\"/usr/aarch64-unknown-linux-gnu\"\n").unwrap();
+
+        let config = Config::new(config_root).await.unwrap();
+        assert_eq!((config.target_root.as_str(), config.sysroot.as_str()), ("/", "/usr/aarch64-unknown-linux-gnu"));
+        let vars = config.build_env_for("app-misc/foo-1.0");
+        assert_eq!(vars["ROOT"], "");
+        assert_eq!(vars["SYSROOT"], "/usr/aarch64-unknown-linux-gnu");
+        assert_eq!(vars["PORTAGE_CONFIGROOT"], format!("{}/", config_root));
+
+        // --root overrides, and "/" keeps what make.conf said
+        let config = config.with_roots("/mnt/target/", "/");
+        assert_eq!(config.root_vars()["ROOT"], "/mnt/target");
+        assert_eq!(config.root_vars()["ESYSROOT"], "/usr/aarch64-unknown-linux-gnu");
+    }
+
     #[tokio::test]
     async fn test_load_package_mask_file() {
         let temp_dir = TempDir::new().unwrap();
@@ -492,6 +793,7 @@ another-set app-misc/foo
         let package_keywords_dir = temp_dir.path().join("etc/portage/package.keywords");
         fs::create_dir_all(&package_keywords_dir).unwrap();
         fs::write(package_keywords_dir.join("test"), "app-editors/vim ~amd64\nsys-apps/util-linux amd64\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/package.accept_keywords"), "=app-misc/foo-2.0\n").unwrap();
 
         let config = Config::new(root).await.unwrap();
 
@@ -500,6 +802,8 @@ another-set app-misc/foo
 
         let util_keywords = config.get_package_keywords("sys-apps/util-linux");
         assert_eq!(util_keywords, Some(&vec!["amd64".to_string()]));
+
+        assert_eq!(config.get_package_keywords("=app-misc/foo-2.0"), Some(&vec![]));
     }
 
     #[tokio::test]
@@ -521,6 +825,22 @@ another-set app-misc/foo
         assert_eq!(tools_set, Some(&vec!["sys-apps/util-linux".to_string()]));
     }
 
+    #[tokio::test]
+    async fn test_load_binrepos_conf() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        let portage_dir = temp_dir.path().join("etc/portage");
+        fs::create_dir_all(portage_dir.join("binrepos.conf")).unwrap();
+        fs::write(portage_dir.join("make.conf"), "PORTAGE_BINHOST=\"http://old.lan/packages\"\n").unwrap();
+        fs::write(portage_dir.join("binrepos.conf/gentoo.conf"), "[gentoo]\npriority = 1\nsync-uri = https://binhost.example/amd64\n").unwrap();
+        fs::write(portage_dir.join("binrepos.conf/local.conf"), "[local]\npriority = 10\nsync-uri = http://build.lan:8080\nverify-signature = true\n").unwrap();
+
+        let config = Config::new(root).await.unwrap();
+        assert_eq!(config.binhost, vec!["http://build.lan:8080", "https://binhost.example/amd64", "http://old.lan/packages"]);
+        assert_eq!(config.binrepos[2].name, "PORTAGE_BINHOST");
+        assert!(config.binrepo_for("http://build.lan:8080/").unwrap().verify_signature);
+    }
+
     #[tokio::test]
     async fn test_user_config_overrides_profile() {
         let temp_dir = TempDir::new().unwrap();
@@ -537,4 +857,63 @@ another-set app-misc/foo
         let vim_flags = config.get_package_use_flags("app-editors/vim");
         assert_eq!(vim_flags, Some(&vec!["X".to_string(), "gtk".to_string()]));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_use_expand() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+
+        fs::create_dir_all(temp_dir.path().join("etc/portage")).unwrap();
+        fs::write(temp_dir.path().join("etc/portage/make.conf"),
+            "USE=\"X\"\nUSE_EXPAND=\"VIDEO_CARDS PYTHON_TARGETS PYTHON_SINGLE_TARGET\"\nUSE_EXPAND_UNPREFIXED=\"ARCH\"\n\
+             ARCH=\"amd64\"\nVIDEO_CARDS=\"amdgpu -nvidia\"\nPYTHON_SINGLE_TARGET=\"python3_12\"\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/package.use"), "media-libs/mesa VIDEO_CARDS: intel -amdgpu\n").unwrap();
+
+        let config = Config::new(root).await.unwrap();
+        assert_eq!(config.use_expand(), vec!["VIDEO_CARDS", "PYTHON_TARGETS", "PYTHON_SINGLE_TARGET"]);
+        assert_eq!(config.use_flags, vec!["X", "video_cards_amdgpu", "-video_cards_nvidia", "python_single_target_python3_12", "amd64"]);
+        assert_eq!(config.package_use_for("media-libs/mesa-24.0"), vec!["video_cards_intel", "-video_cards_amdgpu"]);
+
+        let use_expand = config.use_expand();
+        assert_eq!(split_use_expand("python_single_target_python3_12", &use_expand), Some(("PYTHON_SINGLE_TARGET", "python3_12")));
+        assert_eq!(split_use_expand("video_cards_amdgpu", &use_expand), Some(("VIDEO_CARDS", "amdgpu")));
+        assert_eq!(split_use_expand("ssl", &use_expand), None);
+    }
+
+    #[tokio::test]
+    async fn test_package_env_features() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+
+        fs::create_dir_all(temp_dir.path().join("etc/portage/env")).unwrap();
+        fs::write(temp_dir.path().join("etc/portage/make.conf"), "FEATURES=\"sandbox test\"\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/env/no-tests.conf"), "FEATURES=\"-test\"\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/env/tests.conf"), "FEATURES=\"test\"\nMAKEOPTS=\"-j1\"\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/package.env"), "dev-lang/rust no-tests.conf\n>=dev-libs/foo-2 tests.conf\n").unwrap();
+
+        let config = Config::new(root).await.unwrap();
+        assert_eq!(config.features_for("dev-lang/rust-1.80.0"), vec!["sandbox"]);
+        assert_eq!(config.features_for("app-misc/hello-1.0"), vec!["sandbox", "test"]);
+        assert_eq!(config.package_env_for("dev-libs/foo-2.1").get("MAKEOPTS").map(|s| s.as_str()), Some("-j1"));
+        assert!(config.package_env_for("dev-libs/foo-1.0").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_build_env_for() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+
+        fs::create_dir_all(temp_dir.path().join("etc/portage/env")).unwrap();
+        fs::write(temp_dir.path().join("etc/portage/make.conf"), "CFLAGS=\"-O2 -pipe\"\nMAKEOPTS=\"-j8\"\nFEATURES=\"sandbox\"\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/env/debug.conf"), "CFLAGS=\"-O0 -g\"\nFEATURES=\"-sandbox\"\n").unwrap();
+        fs::write(temp_dir.path().join("etc/portage/package.env"), "dev-libs/foo debug.conf\n").unwrap();
+
+        let config = Config::new(root).await.unwrap();
+        let env = config.build_env_for("dev-libs/foo-1.0");
+        assert_eq!(env.get("CFLAGS").map(|s| s.as_str()), Some("-O0 -g"));
+        assert_eq!(env.get("MAKEOPTS").map(|s| s.as_str()), Some("-j8"));
+        assert!(!env.contains_key("FEATURES"));
+        assert_eq!(config.build_env_for("app-misc/hello-1.0").get("CFLAGS").map(|s| s.as_str()), Some("-O2 -pipe"));
+        assert!(config.features_for("dev-libs/foo-1.0").is_empty());
+    }
+}
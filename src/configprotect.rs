@@ -0,0 +1,477 @@
+// configprotect.rs -- CONFIG_PROTECT handling and batch merging of ._cfg updates
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::exception::InvalidData;
+
+/// Default protected paths when CONFIG_PROTECT is not set
+pub const DEFAULT_CONFIG_PROTECT: &str = "/etc";
+/// Default exceptions when CONFIG_PROTECT_MASK is not set
+pub const DEFAULT_CONFIG_PROTECT_MASK: &str = "/etc/env.d /etc/gentoo-release";
+
+/// Paths protected from being overwritten during merge
+#[derive(Debug, Clone)]
+pub struct ConfigProtect {
+    pub root: PathBuf,
+    pub protect: Vec<String>,
+    pub mask: Vec<String>,
+}
+
+/// A new config file version waiting to replace its target
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingUpdate {
+    pub target: PathBuf,
+    pub update: PathBuf,
+    pub counter: u32,
+}
+
+/// All pending updates for a single protected file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigUpdate {
+    pub target: PathBuf,
+    pub updates: Vec<PendingUpdate>,
+}
+
+/// One line of a line-based diff
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Outcome of merging trivial updates in one batch
+#[derive(Debug, Default)]
+pub struct AutoMergeReport {
+    pub merged: Vec<PathBuf>,
+    pub remaining: Vec<ConfigUpdate>,
+}
+
+impl ConfigUpdate {
+    /// The update with the highest counter, which is the one that gets merged
+    pub fn newest(&self) -> &PendingUpdate {
+        self.updates.iter().max_by_key(|u| u.counter).expect("ConfigUpdate without updates")
+    }
+
+    /// Installed and proposed contents; a missing target reads as empty
+    fn contents(&self) -> Result<(String, String), InvalidData> {
+        let old = match fs::read(&self.target) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(InvalidData::new(&format!("Failed to read {}: {}", self.target.display(), e), None)),
+        };
+        let update = &self.newest().update;
+        let new = fs::read(update)
+            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+            .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", update.display(), e), None))?;
+        Ok((old, new))
+    }
+
+    pub fn diff(&self) -> Result<Vec<DiffLine>, InvalidData> {
+        let (old, new) = self.contents()?;
+        Ok(diff_lines(&old, &new))
+    }
+
+    /// Unified diff between the installed file and the newest update
+    pub fn unified_diff(&self) -> Result<String, InvalidData> {
+        let diff = self.diff()?;
+        Ok(format_unified(&self.target.to_string_lossy(), &self.newest().update.to_string_lossy(), &diff))
+    }
+
+    /// Whether the update only touches comments or whitespace
+    pub fn is_trivial(&self) -> Result<bool, InvalidData> {
+        let (old, new) = self.contents()?;
+        Ok(self.target.exists() && is_trivial_change(&old, &new))
+    }
+}
+
+impl ConfigProtect {
+    pub fn new(root: &str, protect: &str, mask: &str) -> Self {
+        ConfigProtect {
+            root: PathBuf::from(root),
+            protect: Self::split_paths(protect),
+            mask: Self::split_paths(mask),
+        }
+    }
+
+    /// Read CONFIG_PROTECT and CONFIG_PROTECT_MASK from the environment,
+    /// make.conf or the profile, in that order
+    pub fn from_config(config: &Config) -> Self {
+        let lookup = |key: &str, default: &str| {
+            std::env::var(key).ok()
+                .or_else(|| config.get_var(key).cloned())
+                .unwrap_or_else(|| default.to_string())
+        };
+        Self::new(
+            &config.target_root,
+            &lookup("CONFIG_PROTECT", DEFAULT_CONFIG_PROTECT),
+            &lookup("CONFIG_PROTECT_MASK", DEFAULT_CONFIG_PROTECT_MASK),
+        )
+    }
+
+    fn split_paths(value: &str) -> Vec<String> {
+        value.split_whitespace()
+            .map(|p| {
+                let trimmed = p.trim_end_matches('/');
+                if trimmed.is_empty() { "/".to_string() } else { trimmed.to_string() }
+            })
+            .collect()
+    }
+
+    /// Length of the longest entry in `paths` that contains `path`
+    fn longest_match(paths: &[String], path: &Path) -> Option<usize> {
+        paths.iter()
+            .filter(|p| path.starts_with(p.as_str()))
+            .map(|p| p.len())
+            .max()
+    }
+
+    /// Path as seen from inside ROOT (e.g. "/etc/foo.conf")
+    fn relative_to_root(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(rel) => Path::new("/").join(rel),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /// Whether `path` (absolute, under ROOT) is protected.
+    /// A CONFIG_PROTECT_MASK entry wins when it is at least as specific.
+    pub fn is_protected(&self, path: &Path) -> bool {
+        let path = self.relative_to_root(path);
+        match Self::longest_match(&self.protect, &path) {
+            Some(protect_len) => match Self::longest_match(&self.mask, &path) {
+                Some(mask_len) => mask_len < protect_len,
+                None => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Split a "._cfgNNNN_name" file name into its counter and target name
+    pub fn parse_cfg_name(file_name: &str) -> Option<(u32, &str)> {
+        let rest = file_name.strip_prefix("._cfg")?;
+        let (counter, name) = rest.split_once('_')?;
+        if counter.len() != 4 || name.is_empty() {
+            return None;
+        }
+        counter.parse().ok().map(|c| (c, name))
+    }
+
+    /// Existing ._cfg files for `target`, ordered by counter
+    fn existing_updates(target: &Path) -> Vec<PendingUpdate> {
+        let (Some(dir), Some(name)) = (target.parent(), target.file_name().and_then(|n| n.to_str())) else {
+            return vec![];
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return vec![];
+        };
+        let mut updates: Vec<PendingUpdate> = entries.flatten()
+            .filter_map(|entry| {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                let (counter, target_name) = Self::parse_cfg_name(&file_name)?;
+                (target_name == name).then(|| PendingUpdate {
+                    target: target.to_path_buf(),
+                    update: entry.path(),
+                    counter,
+                })
+            })
+            .collect();
+        updates.sort_by_key(|u| u.counter);
+        updates
+    }
+
+    /// Next free "._cfgNNNN_<name>" path next to `target`
+    pub fn next_cfg_path(target: &Path) -> PathBuf {
+        let counter = Self::existing_updates(target).last().map(|u| u.counter + 1).unwrap_or(0);
+        let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        target.with_file_name(format!("._cfg{:04}_{}", counter, name))
+    }
+
+    /// Where a new version of the protected file `target` should be written,
+    /// or None when the installed file or a pending update already has this content
+    pub fn protected_destination(target: &Path, new_contents: &[u8]) -> Option<PathBuf> {
+        if fs::read(target).map(|c| c == new_contents).unwrap_or(false) {
+            return None;
+        }
+        if Self::existing_updates(target).iter().any(|u| fs::read(&u.update).map(|c| c == new_contents).unwrap_or(false)) {
+            return None;
+        }
+        Some(Self::next_cfg_path(target))
+    }
+
+    /// All pending ._cfg updates below the protected directories, grouped by target
+    pub fn find_pending(&self) -> Vec<PendingUpdate> {
+        let mut pending = Vec::new();
+        for protected in &self.protect {
+            let dir = self.root.join(protected.trim_start_matches('/'));
+            if dir.is_dir() {
+                self.scan_dir(&dir, &mut pending);
+            }
+        }
+        pending.sort_by(|a, b| a.target.cmp(&b.target).then(a.counter.cmp(&b.counter)));
+        pending.dedup();
+        pending
+    }
+
+    fn scan_dir(&self, dir: &Path, pending: &mut Vec<PendingUpdate>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                self.scan_dir(&path, pending);
+                continue;
+            }
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some((counter, name)) = Self::parse_cfg_name(&file_name) {
+                let target = dir.join(name);
+                if self.is_protected(&target) {
+                    pending.push(PendingUpdate { target, update: path, counter });
+                }
+            }
+        }
+    }
+
+    /// Pending updates grouped per target file
+    pub fn pending_updates(&self) -> Vec<ConfigUpdate> {
+        let mut grouped: Vec<ConfigUpdate> = Vec::new();
+        for update in self.find_pending() {
+            match grouped.last_mut() {
+                Some(last) if last.target == update.target => last.updates.push(update),
+                _ => grouped.push(ConfigUpdate { target: update.target.clone(), updates: vec![update] }),
+            }
+        }
+        grouped
+    }
+
+    /// Merge every update that only changes comments or whitespace,
+    /// like etc-update's automatic mode, and return what is left
+    pub fn auto_merge(&self) -> Result<AutoMergeReport, InvalidData> {
+        let mut report = AutoMergeReport::default();
+        for update in self.pending_updates() {
+            if update.is_trivial()? {
+                Self::accept(&update.updates)?;
+                report.merged.push(update.target);
+            } else {
+                report.remaining.push(update);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Replace the target with its newest update and drop the older ones
+    pub fn accept(updates: &[PendingUpdate]) -> Result<(), InvalidData> {
+        let Some(newest) = updates.iter().max_by_key(|u| u.counter) else {
+            return Ok(());
+        };
+        fs::rename(&newest.update, &newest.target)
+            .map_err(|e| InvalidData::new(&format!("Failed to replace {}: {}", newest.target.display(), e), None))?;
+        Self::discard(&updates.iter().filter(|u| u.update != newest.update).cloned().collect::<Vec<_>>())
+    }
+
+    /// Keep the installed file and remove the pending updates
+    pub fn discard(updates: &[PendingUpdate]) -> Result<(), InvalidData> {
+        for update in updates {
+            fs::remove_file(&update.update)
+                .map_err(|e| InvalidData::new(&format!("Failed to remove {}: {}", update.update.display(), e), None))?;
+        }
+        Ok(())
+    }
+}
+
+/// Line-based diff using the longest common subsequence
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let (n, m) = (old.len(), new.len());
+
+    // lcs[i][j] = length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            diff.push(DiffLine::Same(old[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(DiffLine::Removed(old[i].to_string()));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j].to_string()));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|l| DiffLine::Removed(l.to_string())));
+    diff.extend(new[j..].iter().map(|l| DiffLine::Added(l.to_string())));
+    diff
+}
+
+/// Render a diff in unified format with three lines of context
+pub fn format_unified(old_name: &str, new_name: &str, diff: &[DiffLine]) -> String {
+    const CONTEXT: usize = 3;
+    let changes: Vec<usize> = diff.iter().enumerate()
+        .filter(|(_, l)| !matches!(l, DiffLine::Same(_)))
+        .map(|(i, _)| i)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Group changes whose context windows overlap into hunks
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &idx in &changes {
+        let start = idx.saturating_sub(CONTEXT);
+        let end = (idx + CONTEXT + 1).min(diff.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (start, end) in hunks {
+        let old_before = diff[..start].iter().filter(|l| !matches!(l, DiffLine::Added(_))).count();
+        let new_before = diff[..start].iter().filter(|l| !matches!(l, DiffLine::Removed(_))).count();
+        let old_count = diff[start..end].iter().filter(|l| !matches!(l, DiffLine::Added(_))).count();
+        let new_count = diff[start..end].iter().filter(|l| !matches!(l, DiffLine::Removed(_))).count();
+        let old_start = if old_count == 0 { old_before } else { old_before + 1 };
+        let new_start = if new_count == 0 { new_before } else { new_before + 1 };
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start, old_count, new_start, new_count));
+        for line in &diff[start..end] {
+            match line {
+                DiffLine::Same(l) => out.push_str(&format!(" {}\n", l)),
+                DiffLine::Removed(l) => out.push_str(&format!("-{}\n", l)),
+                DiffLine::Added(l) => out.push_str(&format!("+{}\n", l)),
+            }
+        }
+    }
+    out
+}
+
+/// True when both versions are equal once comments, blank lines and
+/// whitespace differences are ignored
+pub fn is_trivial_change(old: &str, new: &str) -> bool {
+    fn significant(text: &str) -> Vec<String> {
+        text.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect()
+    }
+    significant(old) == significant(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_protect_mask() {
+        let cp = ConfigProtect::new("/", "/etc /usr/share/config", "/etc/env.d /etc/ssl/certs/ca.pem");
+        assert!(cp.is_protected(Path::new("/etc/ssh/sshd_config")));
+        assert!(cp.is_protected(Path::new("/usr/share/config/kdeglobals")));
+        assert!(!cp.is_protected(Path::new("/etc/env.d/00basic")));
+        assert!(!cp.is_protected(Path::new("/etc/ssl/certs/ca.pem")));
+        assert!(!cp.is_protected(Path::new("/etcetera/foo")));
+        assert!(!cp.is_protected(Path::new("/usr/bin/ls")));
+
+        let cp = ConfigProtect::new("/mnt/target", "/etc", "/etc/env.d");
+        assert!(cp.is_protected(Path::new("/mnt/target/etc/hosts")));
+        assert!(!cp.is_protected(Path::new("/mnt/target/etc/env.d/50foo")));
+    }
+
+    #[test]
+    fn test_cfg_naming() {
+        assert_eq!(ConfigProtect::parse_cfg_name("._cfg0003_make.conf"), Some((3, "make.conf")));
+        assert_eq!(ConfigProtect::parse_cfg_name("._cfg03_make.conf"), None);
+        assert_eq!(ConfigProtect::parse_cfg_name("make.conf"), None);
+
+        let temp = TempDir::new().unwrap();
+        let target = temp.path().join("hosts");
+        fs::write(&target, "old\n").unwrap();
+        assert_eq!(ConfigProtect::next_cfg_path(&target), temp.path().join("._cfg0000_hosts"));
+
+        let first = ConfigProtect::protected_destination(&target, b"new\n").unwrap();
+        fs::write(&first, "new\n").unwrap();
+        assert_eq!(ConfigProtect::protected_destination(&target, b"old\n"), None);
+        assert_eq!(ConfigProtect::protected_destination(&target, b"new\n"), None);
+        assert_eq!(ConfigProtect::protected_destination(&target, b"newer\n"), Some(temp.path().join("._cfg0001_hosts")));
+    }
+
+    #[test]
+    fn test_find_and_accept_pending() {
+        let temp = TempDir::new().unwrap();
+        let etc = temp.path().join("etc");
+        fs::create_dir_all(etc.join("env.d")).unwrap();
+        fs::write(etc.join("hosts"), "old\n").unwrap();
+        fs::write(etc.join("._cfg0000_hosts"), "v1\n").unwrap();
+        fs::write(etc.join("._cfg0001_hosts"), "v2\n").unwrap();
+        fs::write(etc.join("env.d/._cfg0000_00basic"), "masked\n").unwrap();
+
+        let cp = ConfigProtect::new(temp.path().to_str().unwrap(), "/etc", "/etc/env.d");
+        let pending = cp.find_pending();
+        assert_eq!(pending.len(), 2);
+        assert!(pending.iter().all(|u| u.target == etc.join("hosts")));
+
+        ConfigProtect::accept(&pending).unwrap();
+        assert_eq!(fs::read_to_string(etc.join("hosts")).unwrap(), "v2\n");
+        assert!(cp.find_pending().is_empty());
+    }
+
+    #[test]
+    fn test_diff_and_trivial_changes() {
+        let diff = diff_lines("a\nb\nc\n", "a\nB\nc\nd\n");
+        assert_eq!(diff, vec![
+            DiffLine::Same("a".into()),
+            DiffLine::Removed("b".into()),
+            DiffLine::Added("B".into()),
+            DiffLine::Same("c".into()),
+            DiffLine::Added("d".into()),
+        ]);
+        assert_eq!(
+            format_unified("old", "new", &diff),
+            "--- old\n+++ new\n@@ -1,3 +1,4 @@\n a\n-b\n+B\n c\n+d\n"
+        );
+        assert_eq!(format_unified("old", "new", &diff_lines("x\n", "x\n")), "");
+
+        assert!(is_trivial_change("# old header\nkey = value\n", "# new header\n\nkey   =  value\n"));
+        assert!(!is_trivial_change("key = value\n", "key = other\n"));
+    }
+
+    #[test]
+    fn test_auto_merge() {
+        let temp = TempDir::new().unwrap();
+        let etc = temp.path().join("etc");
+        fs::create_dir_all(&etc).unwrap();
+        fs::write(etc.join("trivial.conf"), "# v1\nfoo=1\n").unwrap();
+        fs::write(etc.join("._cfg0000_trivial.conf"), "# v2\nfoo=1\n").unwrap();
+        fs::write(etc.join("real.conf"), "foo=1\n").unwrap();
+        fs::write(etc.join("._cfg0000_real.conf"), "foo=2\n").unwrap();
+
+        let cp = ConfigProtect::new(temp.path().to_str().unwrap(), "/etc", "");
+        let report = cp.auto_merge().unwrap();
+        assert_eq!(report.merged, vec![etc.join("trivial.conf")]);
+        assert_eq!(report.remaining.len(), 1);
+        assert_eq!(report.remaining[0].target, etc.join("real.conf"));
+        assert_eq!(fs::read_to_string(etc.join("trivial.conf")).unwrap(), "# v2\nfoo=1\n");
+        assert!(!etc.join("._cfg0000_trivial.conf").exists());
+    }
+}
@@ -0,0 +1,173 @@
+// contents.rs -- CONTENTS records of installed files (/var/db/pkg/*/*/CONTENTS)
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::exception::InvalidData;
+use crate::metadata_cache::file_md5;
+
+/// One line of a CONTENTS file
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContentsEntry {
+    Dir { path: String },
+    Obj { path: String, md5: String, mtime: u64 },
+    Sym { path: String, target: String, mtime: u64 },
+    Fif { path: String },
+    Dev { path: String },
+}
+
+impl ContentsEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            ContentsEntry::Dir { path }
+            | ContentsEntry::Obj { path, .. }
+            | ContentsEntry::Sym { path, .. }
+            | ContentsEntry::Fif { path }
+            | ContentsEntry::Dev { path } => path,
+        }
+    }
+
+    /// Parse one line; paths may contain spaces, so fields are taken from the right
+    pub fn parse(line: &str) -> Option<Self> {
+        let (kind, rest) = line.trim_end().split_once(' ')?;
+        match kind {
+            "dir" => Some(ContentsEntry::Dir { path: rest.to_string() }),
+            "fif" => Some(ContentsEntry::Fif { path: rest.to_string() }),
+            "dev" => Some(ContentsEntry::Dev { path: rest.to_string() }),
+            "obj" => {
+                let mut fields = rest.rsplitn(3, ' ');
+                let mtime = fields.next()?.parse().ok()?;
+                let md5 = fields.next()?.to_string();
+                Some(ContentsEntry::Obj { path: fields.next()?.to_string(), md5, mtime })
+            }
+            "sym" => {
+                let (link, mtime) = rest.rsplit_once(' ')?;
+                let (path, target) = link.split_once(" -> ")?;
+                Some(ContentsEntry::Sym { path: path.to_string(), target: target.to_string(), mtime: mtime.parse().ok()? })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            ContentsEntry::Dir { path } => format!("dir {}", path),
+            ContentsEntry::Obj { path, md5, mtime } => format!("obj {} {} {}", path, md5, mtime),
+            ContentsEntry::Sym { path, target, mtime } => format!("sym {} -> {} {}", path, target, mtime),
+            ContentsEntry::Fif { path } => format!("fif {}", path),
+            ContentsEntry::Dev { path } => format!("dev {}", path),
+        }
+    }
+
+    /// Whether the file under `root` no longer matches what was merged
+    pub fn is_modified(&self, root: &Path) -> bool {
+        let on_disk = root.join(self.path().trim_start_matches('/'));
+        match self {
+            ContentsEntry::Obj { md5, .. } => file_md5(&on_disk).as_deref() != Some(md5.as_str()),
+            ContentsEntry::Sym { target, .. } => {
+                fs::read_link(&on_disk).map(|t| t.to_string_lossy() != target.as_str()).unwrap_or(true)
+            }
+            _ => fs::symlink_metadata(&on_disk).is_err(),
+        }
+    }
+}
+
+pub fn parse_contents(content: &str) -> Vec<ContentsEntry> {
+    content.lines().filter_map(ContentsEntry::parse).collect()
+}
+
+pub fn format_contents(entries: &[ContentsEntry]) -> String {
+    entries.iter().map(|e| format!("{}\n", e.to_line())).collect()
+}
+
+/// Record every entry of an image directory. Checksums come from the image;
+/// mtimes from the merged copy under `root` when it exists, as portage records them.
+pub fn generate_contents(image: &Path, root: &Path) -> Result<Vec<ContentsEntry>, InvalidData> {
+    let mut entries = Vec::new();
+    collect(image, image, root, &mut entries)?;
+    entries.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(entries)
+}
+
+fn collect(dir: &Path, image: &Path, root: &Path, entries: &mut Vec<ContentsEntry>) -> Result<(), InvalidData> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let read_dir = fs::read_dir(dir)
+        .map_err(|e| InvalidData::new(&format!("Failed to read dir {}: {}", dir.display(), e), None))?;
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        let relative = path.strip_prefix(image)
+            .map_err(|e| InvalidData::new(&format!("Failed to strip prefix: {}", e), None))?;
+        let record = format!("/{}", relative.to_string_lossy());
+        let metadata = fs::symlink_metadata(&path)
+            .map_err(|e| InvalidData::new(&format!("Failed to get metadata for {}: {}", path.display(), e), None))?;
+        let merged_mtime = fs::symlink_metadata(root.join(relative))
+            .map(|m| m.mtime() as u64)
+            .unwrap_or(metadata.mtime() as u64);
+
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(&path)
+                .map_err(|e| InvalidData::new(&format!("Failed to read link {}: {}", path.display(), e), None))?;
+            entries.push(ContentsEntry::Sym { path: record, target: target.to_string_lossy().into_owned(), mtime: merged_mtime });
+        } else if file_type.is_dir() {
+            entries.push(ContentsEntry::Dir { path: record });
+            collect(&path, image, root, entries)?;
+        } else if file_type.is_file() {
+            let md5 = file_md5(&path)
+                .ok_or_else(|| InvalidData::new(&format!("Failed to read {}", path.display()), None))?;
+            entries.push(ContentsEntry::Obj { path: record, md5, mtime: merged_mtime });
+        } else if std::os::unix::fs::FileTypeExt::is_fifo(&file_type) {
+            entries.push(ContentsEntry::Fif { path: record });
+        } else {
+            entries.push(ContentsEntry::Dev { path: record });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_lines() {
+        let content = "dir /usr/share/my dir\nobj /usr/share/my dir/a file d41d8cd98f00b204e9800998ecf8427e 1700000000\nsym /usr/lib/libfoo.so -> libfoo.so.1 1700000001\nbogus line\n";
+        let entries = parse_contents(content);
+        assert_eq!(entries, vec![
+            ContentsEntry::Dir { path: "/usr/share/my dir".to_string() },
+            ContentsEntry::Obj { path: "/usr/share/my dir/a file".to_string(), md5: "d41d8cd98f00b204e9800998ecf8427e".to_string(), mtime: 1700000000 },
+            ContentsEntry::Sym { path: "/usr/lib/libfoo.so".to_string(), target: "libfoo.so.1".to_string(), mtime: 1700000001 },
+        ]);
+        assert_eq!(format_contents(&entries), content.replace("bogus line\n", ""));
+    }
+
+    #[test]
+    fn test_generate_and_detect_modifications() {
+        let image = TempDir::new().unwrap();
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(image.path().join("usr/lib")).unwrap();
+        fs::write(image.path().join("usr/lib/libfoo.so.1"), "").unwrap();
+        std::os::unix::fs::symlink("libfoo.so.1", image.path().join("usr/lib/libfoo.so")).unwrap();
+
+        let entries = generate_contents(image.path(), root.path()).unwrap();
+        let lines: Vec<String> = entries.iter().map(|e| e.to_line()).collect();
+        assert_eq!(lines[0], "dir /usr");
+        assert_eq!(lines[1], "dir /usr/lib");
+        assert!(lines[2].starts_with("sym /usr/lib/libfoo.so -> libfoo.so.1 "));
+        assert!(lines[3].starts_with("obj /usr/lib/libfoo.so.1 d41d8cd98f00b204e9800998ecf8427e "));
+
+        // Nothing merged yet: every entry looks modified; after merging, none do
+        assert!(entries.iter().all(|e| e.is_modified(root.path())));
+        fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        fs::write(root.path().join("usr/lib/libfoo.so.1"), "").unwrap();
+        std::os::unix::fs::symlink("libfoo.so.1", root.path().join("usr/lib/libfoo.so")).unwrap();
+        assert!(!entries.iter().any(|e| e.is_modified(root.path())));
+
+        fs::write(root.path().join("usr/lib/libfoo.so.1"), "edited").unwrap();
+        assert!(entries[3].is_modified(root.path()));
+    }
+}
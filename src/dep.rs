@@ -6,7 +6,7 @@ use crate::versions::{PkgStr, catpkgsplit};
 use crate::exception::{InvalidAtom, InvalidData};
 
 lazy_static! {
-    static ref ATOM_RE: Regex = Regex::new(r"^(?P<blocker>!!?)?(?P<op>[=~<>]+)?(?P<cpv>[\w+./-]+)(?P<slot>:[\w+./-]+)?(?P<berepo>::[\w-]+)?(?P<use>\[.*\])?$").unwrap();
+    static ref ATOM_RE: Regex = Regex::new(r"^(?P<blocker>!!?)?(?P<op>[=~<>]+)?(?P<cpv>[\w+./-]+)(?P<slot>:[\w+./*=-]+)?(?P<berepo>::[\w-]+)?(?P<use>\[.*\])?$").unwrap();
 }
 
 #[derive(Debug, Clone)]
@@ -15,6 +15,7 @@ pub struct Atom {
     pub op: Option<String>,
     pub slot: Option<String>,
     pub sub_slot: Option<String>,
+    pub slot_op: Option<String>, // "=" or "*" slot operator
     pub repo: Option<String>,
     pub use_deps: Vec<String>,
     pub blocker: Option<String>,
@@ -31,15 +32,24 @@ impl Atom {
         let repo = captures.name("berepo").map(|m| m.as_str().to_string());
         let use_str = captures.name("use").map(|m| m.as_str().to_string());
 
-        let (slot, sub_slot) = if let Some(slot_str) = slot_part {
+        let (slot, sub_slot, slot_op) = if let Some(slot_str) = slot_part {
             let slot_str = &slot_str[1..]; // remove :
-            if let Some(slash_pos) = slot_str.find('/') {
-                (Some(slot_str[..slash_pos].to_string()), Some(slot_str[slash_pos+1..].to_string()))
+            let (slot_str, slot_op) = if slot_str == "*" {
+                ("", Some("*".to_string()))
+            } else if let Some(stripped) = slot_str.strip_suffix('=') {
+                (stripped, Some("=".to_string()))
             } else {
-                (Some(slot_str.to_string()), Some(slot_str.to_string()))
+                (slot_str, None)
+            };
+            if slot_str.is_empty() {
+                (None, None, slot_op)
+            } else if let Some(slash_pos) = slot_str.find('/') {
+                (Some(slot_str[..slash_pos].to_string()), Some(slot_str[slash_pos+1..].to_string()), slot_op)
+            } else {
+                (Some(slot_str.to_string()), Some(slot_str.to_string()), slot_op)
             }
         } else {
-            (None, None)
+            (None, None, None)
         };
 
         let use_deps = if let Some(use_str) = use_str {
@@ -53,6 +63,7 @@ impl Atom {
             op,
             slot,
             sub_slot,
+            slot_op,
             repo,
             use_deps,
             blocker,
@@ -108,6 +119,36 @@ impl Atom {
     }
 }
 
+impl std::fmt::Display for Atom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(blocker) = &self.blocker {
+            write!(f, "{}", blocker)?;
+        }
+        if let Some(op) = &self.op {
+            write!(f, "{}", op)?;
+        }
+        write!(f, "{}", self.cpv)?;
+        if let Some(slot) = &self.slot {
+            write!(f, ":{}", slot)?;
+            if let Some(sub_slot) = self.sub_slot.as_ref().filter(|s| *s != slot) {
+                write!(f, "/{}", sub_slot)?;
+            }
+        } else if self.slot_op.is_some() {
+            write!(f, ":")?;
+        }
+        if let Some(slot_op) = &self.slot_op {
+            write!(f, "{}", slot_op)?;
+        }
+        if let Some(repo) = &self.repo {
+            write!(f, "{}", repo)?;
+        }
+        if !self.use_deps.is_empty() {
+            write!(f, "[{}]", self.use_deps.join(","))?;
+        }
+        Ok(())
+    }
+}
+
 pub fn isvalidatom(atom_str: &str) -> bool {
     ATOM_RE.is_match(atom_str)
 }
@@ -147,6 +188,36 @@ pub fn expand_use_flags(dep_str: &str, use_flags: &std::collections::HashMap<Str
     result.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
+/// Evaluate USE conditionals in a plain token list (RESTRICT, PROPERTIES style),
+/// returning the tokens that apply; "flag? ( .. )" and "!flag? ( .. )" may nest
+pub fn use_reduce(value: &str, use_flags: &std::collections::HashMap<String, bool>) -> Vec<String> {
+    let mut tokens = Vec::new();
+    // Whether each open group is active
+    let mut groups: Vec<bool> = Vec::new();
+    let mut condition: Option<bool> = None;
+    for token in value.split_whitespace() {
+        let active = groups.last().copied().unwrap_or(true);
+        match token {
+            "(" => groups.push(active && condition.take().unwrap_or(true)),
+            ")" => {
+                groups.pop();
+            }
+            "||" | "^^" | "??" => {}
+            _ if token.ends_with('?') => {
+                let flag = &token[..token.len() - 1];
+                let (name, wanted) = match flag.strip_prefix('!') {
+                    Some(name) => (name, false),
+                    None => (flag, true),
+                };
+                condition = Some(use_flags.get(name).copied().unwrap_or(false) == wanted);
+            }
+            _ if active => tokens.push(token.to_string()),
+            _ => {}
+        }
+    }
+    tokens
+}
+
 /// Check if a dependency atom is satisfied given USE flags
 pub fn dep_satisfied_with_use(atom: &crate::atom::Atom, use_flags: &std::collections::HashMap<String, bool>) -> bool {
     // Check USE dependencies
@@ -203,85 +274,149 @@ pub fn parse_dependencies(dep_str: &str) -> Result<Vec<Atom>, InvalidData> {
 }
 
 pub fn parse_dependencies_with_use(dep_str: &str, use_flags: &std::collections::HashMap<String, bool>) -> Result<Vec<Atom>, InvalidData> {
+    parse_dependency_groups(dep_str, use_flags).map(|(atoms, _)| atoms)
+}
+
+/// One || ( ) group: its alternatives in order of preference, each a single atom or
+/// the atoms of an all-of ( ) group
+pub type AnyOf = Vec<Vec<Atom>>;
+
+/// A || ( ) group written back as dependency string syntax
+pub fn format_any_of(group: &AnyOf) -> String {
+    let choices: Vec<String> = group.iter()
+        .map(|choice| match choice.as_slice() {
+            [atom] => atom.to_string(),
+            atoms => format!("( {} )", atoms.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ")),
+        })
+        .collect();
+    format!("|| ( {} )", choices.join(" "))
+}
+
+/// Parse a dependency string into the atoms that are always required and its || ( )
+/// groups, with USE conditionals ("flag? ( .. )", "!flag? ( .. )") evaluated
+pub fn parse_dependency_groups(dep_str: &str, use_flags: &std::collections::HashMap<String, bool>) -> Result<(Vec<Atom>, Vec<AnyOf>), InvalidData> {
+    let mut tokens = dep_str.split_whitespace();
     let mut atoms = Vec::new();
+    let mut any_of = Vec::new();
+    parse_all_of(&mut tokens, use_flags, true, &mut atoms, &mut any_of)?;
+    Ok((atoms, any_of))
+}
 
-    if dep_str.trim().is_empty() {
-        return Ok(atoms);
+/// Whether a "flag?" or "!flag?" conditional holds
+fn condition_holds(token: &str, use_flags: &std::collections::HashMap<String, bool>) -> bool {
+    let flag = &token[..token.len() - 1];
+    match flag.strip_prefix('!') {
+        Some(name) => !use_flags.get(name).copied().unwrap_or(false),
+        None => use_flags.get(flag).copied().unwrap_or(false),
     }
+}
 
-    // Expand USE flag conditionals first
-    let expanded_dep_str = expand_use_flags(dep_str, use_flags);
+fn expect_open<'a>(tokens: &mut impl Iterator<Item = &'a str>, after: &str) -> Result<(), InvalidData> {
+    match tokens.next() {
+        Some("(") => Ok(()),
+        _ => Err(InvalidData::new(&format!("Expected '(' after '{}'", after), None)),
+    }
+}
 
-    // Split by whitespace, but handle parentheses and operators
-    let mut current = String::new();
-    let mut paren_depth = 0;
-    let mut in_use_conditional = false;
+fn parse_atom(token: &str) -> Result<Atom, InvalidData> {
+    Atom::new(token).map_err(|e| InvalidData::new(&format!("Invalid atom '{}': {}", token, e), None))
+}
 
-    for ch in expanded_dep_str.chars() {
-        match ch {
-            '(' => {
-                paren_depth += 1;
-                current.push(ch);
-            }
-            ')' => {
-                paren_depth -= 1;
-                current.push(ch);
-            }
-            '?' => {
-                current.push(ch);
-                in_use_conditional = true;
-            }
-            ' ' if paren_depth == 0 && !in_use_conditional => {
-                // End of atom
-                if !current.trim().is_empty() {
-                    atoms.extend(parse_atom_string(&current)?);
+/// Atoms up to the closing ")" (or the end), all of which are required when `active`
+fn parse_all_of<'a>(
+    tokens: &mut impl Iterator<Item = &'a str>,
+    use_flags: &std::collections::HashMap<String, bool>,
+    active: bool,
+    atoms: &mut Vec<Atom>,
+    any_of: &mut Vec<AnyOf>,
+) -> Result<(), InvalidData> {
+    while let Some(token) = tokens.next() {
+        match token {
+            ")" => return Ok(()),
+            "(" => parse_all_of(tokens, use_flags, active, atoms, any_of)?,
+            "||" => {
+                expect_open(tokens, token)?;
+                let choices = parse_any_of(tokens, use_flags, active)?;
+                if active && !choices.is_empty() {
+                    any_of.push(choices);
                 }
-                current.clear();
-                in_use_conditional = false;
             }
-            _ => {
-                current.push(ch);
+            _ if token.ends_with('?') => {
+                expect_open(tokens, token)?;
+                parse_all_of(tokens, use_flags, active && condition_holds(token, use_flags), atoms, any_of)?;
             }
+            _ if active => atoms.push(parse_atom(token)?),
+            _ => {}
         }
     }
-
-    // Handle remaining content
-    if !current.trim().is_empty() {
-        atoms.extend(parse_atom_string(&current)?);
-    }
-
-    Ok(atoms)
+    Ok(())
 }
 
-/// Parse a single atom string, handling USE conditionals
-fn parse_atom_string(atom_str: &str) -> Result<Vec<Atom>, InvalidData> {
-    let atom_str = atom_str.trim();
-
-    if atom_str.is_empty() {
-        return Ok(vec![]);
-    }
-
-    // Handle USE conditionals: flag? ( dep )
-    if let Some(question_pos) = atom_str.find('?') {
-        let flag_part = &atom_str[..question_pos];
-        let rest = &atom_str[question_pos + 1..].trim();
-
-        if rest.starts_with('(') && rest.ends_with(')') {
-            let deps = &rest[1..rest.len() - 1];
-            let mut atoms = parse_dependencies(deps)?;
-
-            // Add USE conditional to each atom
-            for atom in &mut atoms {
-                atom.use_deps.push(flag_part.to_string());
+/// Alternatives up to the closing ")" of a || group; conditionals and nested || groups
+/// inside it contribute their members as further alternatives
+fn parse_any_of<'a>(
+    tokens: &mut impl Iterator<Item = &'a str>,
+    use_flags: &std::collections::HashMap<String, bool>,
+    active: bool,
+) -> Result<AnyOf, InvalidData> {
+    let mut choices = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            ")" => break,
+            "(" => {
+                let (mut atoms, mut nested) = (Vec::new(), Vec::new());
+                parse_all_of(tokens, use_flags, active, &mut atoms, &mut nested)?;
+                // An any-of group inside an all-of alternative takes its first choice
+                atoms.extend(nested.into_iter().filter_map(|group| group.into_iter().next()).flatten());
+                if active && !atoms.is_empty() {
+                    choices.push(atoms);
+                }
             }
-
-            return Ok(atoms);
+            "||" => {
+                expect_open(tokens, token)?;
+                choices.extend(parse_any_of(tokens, use_flags, active)?);
+            }
+            _ if token.ends_with('?') => {
+                expect_open(tokens, token)?;
+                choices.extend(parse_any_of(tokens, use_flags, active && condition_holds(token, use_flags))?);
+            }
+            _ if active => choices.push(vec![parse_atom(token)?]),
+            _ => {}
         }
     }
+    Ok(choices)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_use_reduce() {
+        let flags = HashMap::from([("test".to_string(), true)]);
+        assert_eq!(use_reduce("!test? ( test ) mirror", &flags), vec!["mirror"]);
+        assert_eq!(use_reduce("!test? ( test ) mirror", &HashMap::new()), vec!["test", "mirror"]);
+        assert_eq!(use_reduce("test? ( a !foo? ( b ) foo? ( c ) ) d", &flags), vec!["a", "b", "d"]);
+
+        let metadata = crate::doebuild::Ebuild::parse_metadata_with_use("RESTRICT=\"bindist !test? ( test )\"\n", &flags).unwrap();
+        assert_eq!(metadata.restrict, vec!["bindist"]);
+    }
 
-    // Parse the atom (blockers are handled in Atom::new)
-    match Atom::new(atom_str) {
-        Ok(atom) => Ok(vec![atom]),
-        Err(e) => Err(InvalidData::new(&format!("Invalid atom '{}': {}", atom_str, e), None)),
+    #[test]
+    fn test_parse_dependency_groups() {
+        let flags = HashMap::from([("ssl".to_string(), true)]);
+        let dep_str = "dev-libs/a || ( app-editors/vim ( app-editors/emacs app-emacs/foo ) ) \
+                       ssl? ( dev-libs/openssl || ( net-misc/curl !gnutls? ( net-libs/mbedtls ) ) ) !ssl? ( dev-libs/nettle )";
+        let (atoms, any_of) = parse_dependency_groups(dep_str, &flags).unwrap();
+        assert_eq!(atoms.iter().map(|a| a.cpv.as_str()).collect::<Vec<_>>(), vec!["dev-libs/a", "dev-libs/openssl"]);
+        let names = |group: &AnyOf| group.iter().map(|choice| choice.iter().map(|a| a.cpv.clone()).collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(any_of.len(), 2);
+        assert_eq!(names(&any_of[0]), vec![vec!["app-editors/vim"], vec!["app-editors/emacs", "app-emacs/foo"]]);
+        assert_eq!(names(&any_of[1]), vec![vec!["net-misc/curl"], vec!["net-libs/mbedtls"]]);
+
+        // Without USE flags only the unconditional parts remain
+        let atoms = parse_dependencies(dep_str).unwrap();
+        assert_eq!(atoms.iter().map(|a| a.cpv.as_str()).collect::<Vec<_>>(), vec!["dev-libs/a", "dev-libs/nettle"]);
+        assert!(parse_dependencies("|| app-editors/vim").is_err());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,148 @@
+// depclean.rs -- installed packages that neither @world nor anything it depends on
+// requires, found by walking the dependencies recorded in the package database
+
+use crate::atom::Atom;
+use crate::vartree::VarTree;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Dependencies needed while a package is installed
+const RUNTIME_DEPS: &[&str] = &["RDEPEND", "PDEPEND"];
+
+/// Dependencies only needed to build a package, kept with --with-bdeps=y
+const BUILD_DEPS: &[&str] = &["DEPEND", "BDEPEND"];
+
+/// An installed package depclean would unmerge, and why nothing needs it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Removal {
+    pub cpv: String,
+    pub reason: String,
+}
+
+/// The installed version best matching `atom`
+fn best_installed(vartree: &VarTree, atom: &str) -> Option<String> {
+    let atom = Atom::new(atom).ok()?;
+    let mut matches: Vec<String> = vartree.installed_versions(&atom.cp()).into_iter()
+        .filter(|cpv| atom.matches(cpv))
+        .filter(|cpv| atom.slot.as_deref().is_none_or(|slot| vartree.slot(cpv).is_some_and(|s| s.split('/').next() == Some(slot))))
+        .collect();
+    matches.sort_by(|a, b| {
+        let (a, b) = (crate::versions::cpv_getversion(a).unwrap_or_default(), crate::versions::cpv_getversion(b).unwrap_or_default());
+        crate::versions::vercmp(&a, &b).unwrap_or(0).cmp(&0)
+    });
+    matches.pop()
+}
+
+/// The installed packages that the `keys` dependencies of `cpv` resolve to, evaluated with
+/// the USE flags it was built with; of a || ( ) group, the first choice that is installed
+fn installed_deps(vartree: &VarTree, cpv: &str, keys: &[&str]) -> Vec<String> {
+    let use_flags: HashMap<String, bool> = vartree.use_flags(cpv).unwrap_or_default().into_iter().map(|flag| (flag, true)).collect();
+    let mut deps = Vec::new();
+    for key in keys {
+        let Some(content) = vartree.entry(cpv, key) else {
+            continue;
+        };
+        let Ok((atoms, any_of)) = crate::dep::parse_dependency_groups(&content, &use_flags) else {
+            continue;
+        };
+        deps.extend(atoms.iter()
+            .filter(|atom| atom.blocker.is_none())
+            .filter_map(|atom| best_installed(vartree, &atom.to_string())));
+        for group in any_of {
+            let choice = group.iter().find_map(|choice| {
+                choice.iter().map(|atom| best_installed(vartree, &atom.to_string())).collect::<Option<Vec<_>>>()
+            });
+            deps.extend(choice.into_iter().flatten());
+        }
+    }
+    deps
+}
+
+/// The installed packages `world` (atoms of @selected, @system and @profile) and their
+/// dependencies do not need, with build-time dependencies counted when `with_bdeps`
+pub fn find_removals(vartree: &VarTree, world: &[String], with_bdeps: bool) -> Vec<Removal> {
+    let keys: Vec<&str> = RUNTIME_DEPS.iter().chain(if with_bdeps { BUILD_DEPS } else { &[] }).copied().collect();
+    let mut required: HashSet<String> = HashSet::new();
+    let mut queue: VecDeque<String> = world.iter()
+        .filter(|atom| !atom.starts_with('@'))
+        .filter_map(|atom| best_installed(vartree, atom))
+        .collect();
+    while let Some(cpv) = queue.pop_front() {
+        if required.insert(cpv.clone()) {
+            queue.extend(installed_deps(vartree, &cpv, &keys));
+        }
+    }
+
+    let mut candidates: Vec<String> = vartree.list_packages().into_iter().filter(|cpv| !required.contains(cpv)).collect();
+    candidates.sort();
+    let dependents = |of: &[String], cpv: &str, keys: &[&str]| -> Vec<String> {
+        of.iter()
+            .filter(|parent| installed_deps(vartree, parent, keys).iter().any(|dep| dep == cpv))
+            .cloned()
+            .collect()
+    };
+    let mut required: Vec<String> = required.into_iter().collect();
+    required.sort();
+    candidates.iter()
+        .map(|cpv| {
+            let build_parents = if with_bdeps { Vec::new() } else { dependents(&required, cpv, BUILD_DEPS) };
+            let reason = if !build_parents.is_empty() {
+                format!("only needed to build {} (--with-bdeps=n)", build_parents.join(", "))
+            } else {
+                let others: Vec<String> = candidates.iter().filter(|other| *other != cpv).cloned().collect();
+                let parents = dependents(&others, cpv, &keys);
+                if parents.is_empty() {
+                    "not in @world and required by no package in it".to_string()
+                } else {
+                    format!("only required by {}, which would be removed as well", parents.join(", "))
+                }
+            };
+            Removal { cpv: cpv.clone(), reason }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn install(root: &std::path::Path, cpv: &str, entries: &[(&str, &str)]) {
+        let dir = root.join("var/db/pkg").join(cpv);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("SLOT"), "0\n").unwrap();
+        for (key, value) in entries {
+            std::fs::write(dir.join(key), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_find_removals() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        install(root, "app-misc/foo-1.0", &[("RDEPEND", "dev-libs/bar ssl? ( dev-libs/openssl )"), ("DEPEND", "dev-util/cmake"), ("USE", "")]);
+        install(root, "dev-libs/bar-2", &[("RDEPEND", "|| ( dev-libs/a dev-libs/b )")]);
+        install(root, "dev-libs/a-1", &[]);
+        install(root, "dev-libs/b-1", &[]);
+        install(root, "dev-libs/openssl-3", &[]);
+        install(root, "dev-util/cmake-3.28", &[("RDEPEND", "dev-util/ninja")]);
+        install(root, "dev-util/ninja-1.12", &[]);
+        install(root, "app-misc/orphan-1", &[("RDEPEND", "dev-libs/leaf")]);
+        install(root, "dev-libs/leaf-1", &[]);
+        let vartree = VarTree::new(root.to_str().unwrap());
+        let world = vec!["app-misc/foo".to_string()];
+
+        let removals = find_removals(&vartree, &world, true);
+        let cpvs: Vec<&str> = removals.iter().map(|r| r.cpv.as_str()).collect();
+        // USE=-ssl leaves openssl out; the first installed || ( ) choice is kept
+        assert_eq!(cpvs, vec!["app-misc/orphan-1", "dev-libs/b-1", "dev-libs/leaf-1", "dev-libs/openssl-3"]);
+        assert_eq!(removals[0].reason, "not in @world and required by no package in it");
+        assert_eq!(removals[2].reason, "only required by app-misc/orphan-1, which would be removed as well");
+
+        // Without build dependencies, cmake and what it pulls in go too
+        let removals = find_removals(&vartree, &world, false);
+        let cmake = removals.iter().find(|r| r.cpv == "dev-util/cmake-3.28").unwrap();
+        assert_eq!(cmake.reason, "only needed to build app-misc/foo-1.0 (--with-bdeps=n)");
+        let ninja = removals.iter().find(|r| r.cpv == "dev-util/ninja-1.12").unwrap();
+        assert_eq!(ninja.reason, "only required by dev-util/cmake-3.28, which would be removed as well");
+    }
+}
@@ -3,8 +3,6 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use crate::atom::{Atom, Operator};
 use crate::exception::InvalidData;
-use crate::versions::vercmp;
-use crate::dep::{expand_use_flags, dep_satisfied_with_use};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum DepType {
@@ -13,6 +11,37 @@ pub enum DepType {
     Post,
 }
 
+impl DepType {
+    /// Name of the edge kind in circular dependency reports
+    pub fn label(&self) -> &'static str {
+        match self {
+            DepType::Runtime => "runtime",
+            DepType::Build => "buildtime",
+            DepType::Post => "runtime_post",
+        }
+    }
+}
+
+/// Slot operator on a dependency atom
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlotOperator {
+    /// `:=` / `:SLOT=` -- rebuild when the slot/subslot of the dependency changes
+    Equal,
+    /// `:*` -- any slot is acceptable
+    Any,
+}
+
+impl SlotOperator {
+    /// Parse the operator part of a slot dependency ("=" or "*")
+    pub fn parse(op: &str) -> Option<Self> {
+        match op {
+            "=" => Some(SlotOperator::Equal),
+            "*" => Some(SlotOperator::Any),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DepNode {
     pub atom: Atom,
@@ -21,30 +50,125 @@ pub struct DepNode {
     pub use_conditional: Option<String>,
     pub slot: Option<String>,
     pub subslot: Option<String>,
+    pub slot_operator: Option<SlotOperator>,
 }
 
 #[derive(Debug)]
 pub struct DepGraph {
-    pub nodes: HashMap<String, DepNode>,
+    pub nodes: HashMap<String, DepNode>, // "cp" or "cp:slot" -> node
     pub edges: HashMap<String, Vec<String>>, // node -> dependencies
+    pub edge_types: HashMap<(String, String), Vec<DepType>>, // (node, dependency) -> kinds
     pub reverse_edges: HashMap<String, Vec<String>>, // node -> dependents
     pub use_flags: HashMap<String, bool>,
+    pub requests: HashMap<String, Vec<(Atom, String)>>, // node -> (atom, pulled in by)
+    /// --exclude: never merged in this run, and not followed for dependencies
+    pub exclude: Vec<Atom>,
+    /// --reinstall-atoms: merged even when already installed
+    pub reinstall: Vec<Atom>,
+    /// || ( ) dependencies, settled while resolving
+    pub any_of: Vec<AnyOfDep>,
+    /// Installed versions (cpv), whose packages are preferred as || ( ) providers
+    pub installed: Vec<String>,
+    /// Visible versions (cpv) of each package, best first, to select from while resolving
+    pub available: HashMap<String, Vec<String>>,
+    /// --backtrack: how many times resolution may start over to get around a conflict
+    pub backtrack: usize,
+    /// Whether a cycle may be broken at a build dependency that is already installed;
+    /// not with --emptytree, where installed packages are rebuilt
+    pub break_installed_build_cycles: bool,
+}
+
+/// Default for --backtrack, as in portage
+pub const DEFAULT_BACKTRACK: usize = 10;
+
+/// A resolved package that a blocker of another one matches
+#[derive(Debug, Clone)]
+pub struct BlockerConflict {
+    pub holder: String,
+    pub blocker: Atom,
+    pub blocked: String,
+}
+
+/// What backtracking has ruled out: || ( ) alternatives as (group, alternative), and versions
+#[derive(Debug, Clone, Default)]
+struct Rejected {
+    choices: HashSet<(usize, usize)>,
+    versions: HashSet<String>,
+}
+
+/// A || ( ) dependency of `parent`: alternatives in order of preference, each a set of
+/// packages that satisfies the group together
+#[derive(Debug, Clone)]
+pub struct AnyOfDep {
+    pub parent: String,
+    pub choices: Vec<Vec<DepNode>>,
+}
+
+/// Packages that depend on each other in a loop: `kinds[i]` are the kinds of the edge from
+/// `members[i]` to the next member, the last one leading back to the first
+#[derive(Debug, Clone, PartialEq)]
+pub struct DependencyCycle {
+    pub members: Vec<String>,
+    pub kinds: Vec<Vec<DepType>>,
+}
+
+/// Several incompatible atoms pulled into the same package slot
+#[derive(Debug, Clone)]
+pub struct SlotConflict {
+    pub cp: String,
+    pub slot: String,
+    pub atoms: Vec<(Atom, String)>,
 }
 
 #[derive(Debug)]
 pub struct ResolutionResult {
     pub resolved: Vec<String>,
+    /// Version selected for resolved keys whose available versions are known
+    pub selected: HashMap<String, String>,
+    /// Keys dropped by --exclude
+    pub excluded: Vec<String>,
+    /// Packages whose blockers match another resolved package
     pub blocked: Vec<String>,
-    pub circular: Vec<String>,
+    /// Blockers nothing but a manual change resolves; `blocked` is an installed cpv when
+    /// a hard blocker matches an installed package
+    pub blocks: Vec<BlockerConflict>,
+    /// Soft blockers of installed packages the plan does not replace, resolved by
+    /// unmerging `blocked` (an installed cpv) once the holder is merged
+    pub uninstalls: Vec<BlockerConflict>,
+    /// Dependency cycles no edge of can be dropped
+    pub circular: Vec<DependencyCycle>,
+    pub slot_conflicts: Vec<SlotConflict>,
+    /// The alternative taken for each || ( ) group, as (parent key, provider keys)
+    pub any_of_choices: Vec<(String, Vec<String>)>,
+    /// Times resolution started over to get around a conflict
+    pub backtracks: usize,
 }
 
+/// Whether any of `atoms` (package names or slot atoms, as given to --exclude and
+/// --reinstall-atoms) names the package `cp` in `slot`
+pub fn atoms_match_package(atoms: &[Atom], cp: &str, slot: Option<&str>) -> bool {
+    atoms.iter().any(|atom| atom.cp() == cp && atom.slot.as_deref().is_none_or(|wanted| Some(wanted) == slot))
+}
+
+/// Parent name used for atoms given on the command line
+pub const REQUESTED: &str = "(requested)";
+
 impl DepGraph {
     pub fn new() -> Self {
         DepGraph {
             nodes: HashMap::new(),
             edges: HashMap::new(),
+            edge_types: HashMap::new(),
             reverse_edges: HashMap::new(),
             use_flags: HashMap::new(),
+            requests: HashMap::new(),
+            exclude: Vec::new(),
+            reinstall: Vec::new(),
+            any_of: Vec::new(),
+            installed: Vec::new(),
+            available: HashMap::new(),
+            backtrack: DEFAULT_BACKTRACK,
+            break_installed_build_cycles: true,
         }
     }
 
@@ -52,18 +176,170 @@ impl DepGraph {
         DepGraph {
             nodes: HashMap::new(),
             edges: HashMap::new(),
+            edge_types: HashMap::new(),
             reverse_edges: HashMap::new(),
             use_flags,
+            requests: HashMap::new(),
+            exclude: Vec::new(),
+            reinstall: Vec::new(),
+            any_of: Vec::new(),
+            installed: Vec::new(),
+            available: HashMap::new(),
+            backtrack: DEFAULT_BACKTRACK,
+            break_installed_build_cycles: true,
+        }
+    }
+
+    pub fn with_exclude(mut self, atoms: Vec<Atom>) -> Self {
+        self.exclude = atoms;
+        self
+    }
+
+    pub fn with_reinstall(mut self, atoms: Vec<Atom>) -> Self {
+        self.reinstall = atoms;
+        self
+    }
+
+    pub fn with_installed(mut self, installed: Vec<String>) -> Self {
+        self.installed = installed;
+        self
+    }
+
+    pub fn with_available(mut self, available: HashMap<String, Vec<String>>) -> Self {
+        self.available = available;
+        self
+    }
+
+    pub fn with_backtrack(mut self, backtrack: usize) -> Self {
+        self.backtrack = backtrack;
+        self
+    }
+
+    pub fn with_break_installed_build_cycles(mut self, enabled: bool) -> Self {
+        self.break_installed_build_cycles = enabled;
+        self
+    }
+
+    /// Slot of the package stored under `key`, if known
+    fn key_slot<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        key.split_once(':').map(|(_, slot)| slot)
+            .or_else(|| self.nodes.get(key).and_then(|node| node.slot.as_deref()))
+    }
+
+    /// Whether --exclude names the package slot stored under `key`
+    pub fn is_excluded(&self, key: &str) -> bool {
+        atoms_match_package(&self.exclude, Self::key_cp(key), self.key_slot(key))
+    }
+
+    /// Whether --reinstall-atoms names the package slot stored under `key`
+    pub fn is_reinstall(&self, key: &str) -> bool {
+        atoms_match_package(&self.reinstall, Self::key_cp(key), self.key_slot(key))
+    }
+
+    /// Graph key for a package slot: "cp:slot" when the slot is known, else "cp"
+    pub fn node_key(cp: &str, slot: Option<&str>) -> String {
+        match slot {
+            Some(slot) => format!("{}:{}", cp, slot),
+            None => cp.to_string(),
+        }
+    }
+
+    /// Category/package part of a graph key
+    pub fn key_cp(key: &str) -> &str {
+        key.split(':').next().unwrap_or(key)
+    }
+
+    /// Slot a dependency asks for; `:*` deps match any slot
+    fn dep_slot(dep: &DepNode) -> Option<&str> {
+        match dep.slot_operator {
+            Some(SlotOperator::Any) => None,
+            _ => dep.slot.as_deref().or(dep.atom.slot.as_deref()),
+        }
+    }
+
+    /// Key a dependency node is stored under
+    fn dep_key(&self, dep: &DepNode) -> String {
+        self.find_key(&dep.atom.cp(), Self::dep_slot(dep))
+    }
+
+    /// Key the package `cp` in `slot` is stored under. Without a slot that is the only slot
+    /// of `cp` in the graph, if there is just one, so a package is a single node whether it
+    /// is pulled in with its slot or without
+    fn find_key(&self, cp: &str, slot: Option<&str>) -> String {
+        if slot.is_some() || self.nodes.contains_key(cp) {
+            return Self::node_key(cp, slot);
+        }
+        let mut slotted = self.nodes.keys().filter(|key| key.contains(':') && Self::key_cp(key) == cp);
+        match (slotted.next(), slotted.next()) {
+            (Some(key), None) => key.clone(),
+            _ => cp.to_string(),
+        }
+    }
+
+    /// The key a node returned as `key` is stored under now: one added without its slot
+    /// becomes its "cp:slot" node once the slot is known
+    pub fn current_key(&self, key: &str) -> String {
+        if self.nodes.contains_key(key) { key.to_string() } else { self.find_key(key, None) }
+    }
+
+    /// find_key for a node about to be added. Once the slot of a package pulled in
+    /// without one is known, its "cp" node becomes the "cp:slot" node
+    fn slot_key(&mut self, cp: &str, slot: Option<&str>) -> String {
+        let key = self.find_key(cp, slot);
+        if let Some(slot) = slot
+            && self.nodes.contains_key(cp)
+            && !self.nodes.contains_key(&key)
+            && !self.nodes.keys().any(|other| other.contains(':') && Self::key_cp(other) == cp)
+        {
+            self.rename_node(cp, &key);
+            if let Some(node) = self.nodes.get_mut(&key) {
+                node.slot = Some(slot.to_string());
+            }
         }
+        key
+    }
+
+    /// Move the node under `from`, with its edges and requests, to `to`
+    fn rename_node(&mut self, from: &str, to: &str) {
+        let rename = |key: &mut String| if key == from { *key = to.to_string() };
+        if let Some(node) = self.nodes.remove(from) {
+            self.nodes.insert(to.to_string(), node);
+        }
+        for map in [&mut self.edges, &mut self.reverse_edges] {
+            if let Some(keys) = map.remove(from) {
+                map.insert(to.to_string(), keys);
+            }
+            map.values_mut().flatten().for_each(rename);
+        }
+        if let Some(requests) = self.requests.remove(from) {
+            self.requests.insert(to.to_string(), requests);
+        }
+        self.requests.values_mut().flatten().for_each(|(_, parent)| rename(parent));
+        self.edge_types = std::mem::take(&mut self.edge_types).into_iter()
+            .map(|((mut node, mut dep), kinds)| {
+                rename(&mut node);
+                rename(&mut dep);
+                ((node, dep), kinds)
+            })
+            .collect();
+        self.any_of.iter_mut().for_each(|group| rename(&mut group.parent));
     }
 
     pub fn add_node_with_blockers(&mut self, cpv: &str, deps: Vec<DepNode>, blockers: Vec<Atom>) -> Result<(), InvalidData> {
-        let node_key = cpv.to_string();
+        let atom = Atom::new(cpv).map_err(|_| InvalidData::new(&format!("Invalid CPV: {}", cpv), None))?;
+        self.add_target(&atom, deps, blockers);
+        Ok(())
+    }
+
+    /// Add a requested atom with its dependencies, returning its graph key
+    pub fn add_target(&mut self, atom: &Atom, deps: Vec<DepNode>, blockers: Vec<Atom>) -> String {
+        let node_key = self.slot_key(&atom.cp(), atom.slot.as_deref());
 
         // Add the main node if not exists
-        if !self.nodes.contains_key(&node_key) {
-            // Create a dummy atom for the package itself
-            let atom = Atom::new(cpv).map_err(|_| InvalidData::new(&format!("Invalid CPV: {}", cpv), None))?;
+        if let Some(node) = self.nodes.get_mut(&node_key) {
+            // Update existing node with additional blockers
+            node.blockers.extend(blockers);
+        } else {
             self.nodes.insert(node_key.clone(), DepNode {
                 atom: atom.clone(),
                 dep_type: DepType::Runtime,
@@ -71,147 +347,509 @@ impl DepGraph {
                 use_conditional: None,
                 slot: atom.slot.clone(),
                 subslot: atom.subslot.clone(),
+                slot_operator: None,
             });
-        } else {
-            // Update existing node with additional blockers
-            if let Some(node) = self.nodes.get_mut(&node_key) {
-                node.blockers.extend(blockers);
-            }
         }
+        self.requests.entry(node_key.clone()).or_default().push((atom.clone(), REQUESTED.to_string()));
+
+        self.add_dependencies(&node_key, deps);
+        node_key
+    }
 
-        // Add dependencies
-        let mut dep_keys = vec![];
+    /// Add dependency edges from an existing node, returning the keys of the dependencies
+    pub fn add_dependencies(&mut self, node_key: &str, deps: Vec<DepNode>) -> Vec<String> {
+        let mut dep_keys = Vec::new();
         for dep in deps {
-            let dep_key = dep.atom.cp();
-            dep_keys.push(dep_key.clone());
+            let dep_key = self.slot_key(&dep.atom.cp(), Self::dep_slot(&dep));
+            self.requests.entry(dep_key.clone()).or_default().push((dep.atom.clone(), node_key.to_string()));
+            let kinds = self.edge_types.entry((node_key.to_string(), dep_key.clone())).or_default();
+            if !kinds.contains(&dep.dep_type) {
+                kinds.push(dep.dep_type.clone());
+            }
 
             if !self.nodes.contains_key(&dep_key) {
                 self.nodes.insert(dep_key.clone(), dep);
             }
 
             // Add edge
-            self.edges.entry(node_key.clone()).or_insert(vec![]).push(dep_key.clone());
-            self.reverse_edges.entry(dep_key).or_insert(vec![]).push(node_key.clone());
+            self.edges.entry(node_key.to_string()).or_default().push(dep_key.clone());
+            self.reverse_edges.entry(dep_key.clone()).or_default().push(node_key.to_string());
+            dep_keys.push(dep_key);
         }
+        dep_keys
+    }
 
-        Ok(())
+    /// Add a || ( ) dependency of an existing node, returning the keys of all its
+    /// alternatives; which one it pulls in is decided by resolve
+    pub fn add_any_of(&mut self, node_key: &str, choices: Vec<Vec<DepNode>>) -> Vec<String> {
+        let mut keys = Vec::new();
+        for dep in choices.iter().flatten() {
+            let key = self.slot_key(&dep.atom.cp(), Self::dep_slot(dep));
+            self.nodes.entry(key.clone()).or_insert_with(|| dep.clone());
+            keys.push(key);
+        }
+        self.any_of.push(AnyOfDep { parent: node_key.to_string(), choices });
+        keys
+    }
+
+    /// Alternative to take for the || ( ) group at `index`, skipping `rejected` ones: one
+    /// already pulled into the graph, else one that is installed, else the first
+    fn choose_any_of(&self, index: usize, rejected: &HashSet<(usize, usize)>, resolved: &[String]) -> Option<usize> {
+        let candidates: Vec<(usize, &Vec<DepNode>)> = self.any_of[index].choices.iter()
+            .enumerate()
+            .filter(|(choice, _)| !rejected.contains(&(index, *choice)))
+            .collect();
+        let in_graph = |dep: &DepNode| {
+            let key = self.dep_key(dep);
+            self.requests.contains_key(&key) || resolved.contains(&key)
+        };
+        let installed = |dep: &DepNode| self.installed.iter().any(|cpv| dep.atom.matches(cpv));
+        candidates.iter().find(|(_, deps)| deps.iter().all(in_graph))
+            .or_else(|| candidates.iter().find(|(_, deps)| deps.iter().all(installed)))
+            .or(candidates.first())
+            .map(|(choice, _)| *choice)
     }
 
+    /// Flag states that atoms pulling in `key` demand through USE dependencies,
+    /// as (flag, enabled, required by). Conditional forms (flag?, flag=) follow the
+    /// global USE flags.
+    pub fn use_requirements(&self, key: &str) -> Vec<(String, bool, String)> {
+        self.use_requirements_with(key, |_| self.use_flags.clone())
+    }
 
+    /// use_requirements, with conditional forms following the flags `parent_use` gives
+    /// each parent: [abi_x86_32(-)?] wants abi_x86_32 on wherever the parent has it
+    pub fn use_requirements_with(&self, key: &str, parent_use: impl Fn(&str) -> HashMap<String, bool>) -> Vec<(String, bool, String)> {
+        let mut requirements = Vec::new();
+        for (atom, parent) in self.requests.get(key).map(|r| r.as_slice()).unwrap_or_default() {
+            for use_dep in &atom.use_deps {
+                let (flag, condition) = match use_dep.strip_suffix('?') {
+                    Some(flag) => (flag, Some('?')),
+                    None => match use_dep.strip_suffix('=') {
+                        Some(flag) => (flag, Some('=')),
+                        None => (use_dep.as_str(), None),
+                    },
+                };
+                let (name, negated) = match flag.strip_prefix('-').or_else(|| flag.strip_prefix('!')) {
+                    Some(name) => (name, true),
+                    None => (flag, false),
+                };
+                let name = name.trim_end_matches("(+)").trim_end_matches("(-)");
+                let parent_has = || parent_use(parent).get(name).copied().unwrap_or(false);
+                let enabled = match condition {
+                    None => !negated,
+                    // flag? (!flag?): on (off) when the parent has it on (off)
+                    Some('?') if parent_has() != negated => !negated,
+                    Some('?') => continue,
+                    // flag= (!flag=): the same (opposite) state as the parent
+                    _ => parent_has() != negated,
+                };
+                requirements.push((name.to_string(), enabled, parent.clone()));
+            }
+        }
+        requirements
+    }
 
     pub fn resolve(&self, targets: &[String]) -> Result<ResolutionResult, InvalidData> {
         self.resolve_advanced(targets)
     }
 
-    /// Advanced dependency resolution with SLOT and version conflict handling
+    /// Advanced dependency resolution with SLOT and version conflict handling. Blocker and
+    /// slot conflicts are backtracked over: the blocked versions of a package, or the
+    /// provider taken for a || ( ) group, are ruled out and resolution starts over, at most
+    /// `backtrack` times.
     pub fn resolve_advanced(&self, targets: &[String]) -> Result<ResolutionResult, InvalidData> {
-        let mut resolved: HashMap<String, String> = HashMap::new(); // slot -> cpv
-        let mut blocked: Vec<String> = Vec::new();
-        let mut to_process: VecDeque<String> = targets.iter().cloned().collect();
+        let mut rejected = Rejected::default();
+        let mut backtracks = 0;
+        loop {
+            let (mut result, chosen) = self.resolve_pass(targets, &rejected);
+            result.backtracks = backtracks;
+            if backtracks >= self.backtrack {
+                return Ok(result);
+            }
+            match self.next_attempt(&result, &chosen, &rejected) {
+                Some(next) => {
+                    rejected = next;
+                    backtracks += 1;
+                }
+                None => return Ok(result),
+            }
+        }
+    }
+
+    /// What to rule out for the next attempt at resolving the conflicts in `result`, if
+    /// anything is left to try
+    fn next_attempt(&self, result: &ResolutionResult, chosen: &[(usize, usize)], rejected: &Rejected) -> Option<Rejected> {
+        // The || ( ) alternative that pulled in `key`, when the group has another one left
+        let alternative = |key: &str| chosen.iter().copied().find(|&(index, choice)| {
+            let group = &self.any_of[index];
+            group.choices[choice].iter().any(|dep| self.dep_key(dep) == key)
+                && (0..group.choices.len()).any(|other| other != choice && !rejected.choices.contains(&(index, other)))
+        });
+        let without_choice = |choice: (usize, usize)| {
+            let mut next = rejected.clone();
+            next.choices.insert(choice);
+            next
+        };
+
+        for block in &result.blocks {
+            // A version of the blocked package the blocker does not match
+            let versions = self.available.get(Self::key_cp(&block.blocked)).map(|v| v.as_slice()).unwrap_or_default();
+            let blocked: Vec<&String> = versions.iter().filter(|cpv| block.blocker.matches(cpv)).collect();
+            let mut next = rejected.clone();
+            next.versions.extend(blocked.into_iter().cloned());
+            if self.select_version(&block.blocked, &next.versions).is_some() {
+                return Some(next);
+            }
+            if let Some(choice) = alternative(&block.blocked).or_else(|| alternative(&block.holder)) {
+                return Some(without_choice(choice));
+            }
+        }
+        for conflict in &result.slot_conflicts {
+            let keys = result.resolved.iter().filter(|key| Self::key_cp(key) == conflict.cp);
+            let parents = conflict.atoms.iter().map(|(_, parent)| parent);
+            if let Some(choice) = keys.chain(parents).find_map(|key| alternative(key)) {
+                return Some(without_choice(choice));
+            }
+        }
+        None
+    }
+
+    /// One resolution with the given alternatives and versions ruled out, also returning
+    /// the (group, alternative) pairs it took for || ( ) groups
+    fn resolve_pass(&self, targets: &[String], rejected: &Rejected) -> (ResolutionResult, Vec<(usize, usize)>) {
+        let mut chosen: Vec<(usize, usize)> = Vec::new();
+        let mut resolved: Vec<String> = Vec::new(); // node keys in discovery order
+        let mut selected = HashMap::new();
+        let mut excluded: Vec<String> = Vec::new();
+        let mut to_process: VecDeque<String> = targets.iter().map(|key| self.current_key(key)).collect();
         let mut visited = HashSet::new();
 
         while let Some(current) = to_process.pop_front() {
-            if visited.contains(&current) {
+            if !visited.insert(current.clone()) {
                 continue;
             }
-            visited.insert(current.clone());
-
-            // Check for blockers and SLOT conflicts
-            if let Some(node) = self.nodes.get(&current) {
-                // Check blockers
-                for blocker in &node.blockers {
-                    for (slot, resolved_cpv) in &resolved {
-                        if blocker.matches(resolved_cpv) {
-                            blocked.push(current.clone());
-                            continue;
-                        }
+
+            // Excluded packages count as satisfied; their dependencies are not pulled in
+            // through them, so the rest of the graph stays consistent
+            if self.is_excluded(&current) {
+                excluded.push(current);
+                continue;
+            }
+
+            if let Some(cpv) = self.select_version(&current, &rejected.versions) {
+                selected.insert(current.clone(), cpv);
+            }
+            resolved.push(current.clone());
+
+            // Add dependencies to process queue; USE dependencies ([flag]) constrain the
+            // dependency's flags and are checked with use_requirements, not here
+            if let Some(deps) = self.edges.get(&current) {
+                for dep in deps {
+                    if self.nodes.contains_key(dep) && !visited.contains(dep) {
+                        to_process.push_back(dep.clone());
                     }
                 }
+            }
 
-                // Check SLOT conflicts
-                if let Some(slot) = &node.slot {
-                    if let Some(existing_cpv) = resolved.get(slot) {
-                        // SLOT conflict - check if they're the same package
-                        if existing_cpv != &current {
-                            // Different packages in same SLOT - this might be allowed
-                            // but we need to check if they're compatible
-                            let existing_cp = existing_cpv.split('-').next().unwrap_or("");
-                            let current_cp = current.split('-').next().unwrap_or("");
-                            if existing_cp != current_cp {
-                                // Different packages in same SLOT - block
-                                blocked.push(current.clone());
-                                continue;
-                            }
-                        }
+            // And the alternative taken for each of its || ( ) groups
+            for index in (0..self.any_of.len()).filter(|&index| self.any_of[index].parent == current) {
+                let Some(choice) = self.choose_any_of(index, &rejected.choices, &resolved) else {
+                    continue;
+                };
+                chosen.push((index, choice));
+                for dep in &self.any_of[index].choices[choice] {
+                    let key = self.dep_key(dep);
+                    if !visited.contains(&key) {
+                        to_process.push_back(key);
                     }
                 }
             }
+        }
 
-            // Add to resolved if not blocked
-            if !blocked.contains(&current) {
-                if let Some(node) = self.nodes.get(&current) {
-                    let slot = node.slot.as_ref().unwrap_or(&"0".to_string()).clone();
-                    resolved.insert(slot, current.clone());
+        // Blockers of every resolved package against every other one
+        let mut blocks = Vec::new();
+        for holder in &resolved {
+            for blocker in self.nodes.get(holder).map(|node| node.blockers.as_slice()).unwrap_or_default() {
+                for key in resolved.iter().filter(|key| *key != holder) {
+                    if self.blocker_matches(blocker, key, &selected) {
+                        blocks.push(BlockerConflict { holder: holder.clone(), blocker: blocker.clone(), blocked: key.clone() });
+                    }
                 }
             }
-
-            // Add dependencies to process queue (filtered by USE flags)
-            if let Some(deps) = self.edges.get(&current) {
-                for dep in deps {
-                    // Check if dependency is satisfied with current USE flags
-                    if let Some(node) = self.nodes.get(dep) {
-                        if dep_satisfied_with_use(&node.atom, &self.use_flags) {
-                            if !visited.contains(dep) {
-                                to_process.push_back(dep.clone());
-                            }
-                        }
+        }
+        // And against installed packages no resolved version replaces
+        let mut uninstalls = Vec::new();
+        for holder in &resolved {
+            for blocker in self.nodes.get(holder).map(|node| node.blockers.as_slice()).unwrap_or_default() {
+                for cpv in self.installed.iter().filter(|cpv| blocker.matches(cpv)) {
+                    if resolved.iter().any(|key| Self::key_cp(key) == blocker.cp()) {
+                        continue;
+                    }
+                    let block = BlockerConflict { holder: holder.clone(), blocker: blocker.clone(), blocked: cpv.clone() };
+                    if blocker.is_hard_blocker() {
+                        blocks.push(block);
+                    } else {
+                        uninstalls.push(block);
                     }
                 }
             }
         }
+        let mut blocked: Vec<String> = Vec::new();
+        for block in &blocks {
+            if !blocked.contains(&block.holder) {
+                blocked.push(block.holder.clone());
+            }
+        }
 
-        // Detect circular dependencies
-        let circular = self.detect_cycles();
+        let slot_conflicts = resolved.iter()
+            .filter_map(|key| self.slot_conflict(key))
+            .collect();
 
-        // Convert resolved map back to vec
-        let resolved_vec = resolved.values().cloned().collect();
+        // Detect circular dependencies that cannot be broken
+        let circular = self.detect_cycles(&resolved);
 
-        Ok(ResolutionResult {
-            resolved: resolved_vec,
+        let any_of_choices = chosen.iter()
+            .map(|&(index, choice)| {
+                let group = &self.any_of[index];
+                (group.parent.clone(), group.choices[choice].iter().map(|dep| self.dep_key(dep)).collect())
+            })
+            .collect();
+
+        (ResolutionResult {
+            resolved,
+            selected,
+            excluded,
             blocked,
+            blocks,
+            uninstalls,
             circular,
+            slot_conflicts,
+            any_of_choices,
+            backtracks: 0,
+        }, chosen)
+    }
+
+    /// Versioned atoms that pull in `key`, all of which its version must match
+    fn version_requirements(&self, key: &str) -> Vec<&Atom> {
+        self.requests.get(key)
+            .into_iter()
+            .flatten()
+            .map(|(atom, _)| atom)
+            .chain(self.nodes.get(key).map(|node| &node.atom))
+            .filter(|atom| atom.op != Operator::None && atom.version.is_some())
+            .collect()
+    }
+
+    /// Best available version for `key` that every atom pulling it in accepts, leaving out
+    /// `rejected` versions; None when no versions are known for the package
+    fn select_version(&self, key: &str, rejected: &HashSet<String>) -> Option<String> {
+        let requirements = self.version_requirements(key);
+        self.available.get(Self::key_cp(key))?
+            .iter()
+            .find(|cpv| !rejected.contains(*cpv) && requirements.iter().all(|atom| atom.matches(cpv)))
+            .cloned()
+    }
+
+    /// Whether a blocker atom matches the package stored under `key`, at the version
+    /// selected for it when there is one
+    fn blocker_matches(&self, blocker: &Atom, key: &str, selected: &HashMap<String, String>) -> bool {
+        let Some(node) = self.nodes.get(key) else {
+            return false;
+        };
+        if blocker.cp() != node.atom.cp() {
+            return false;
+        }
+        if blocker.op == Operator::None {
+            return true;
+        }
+        match (selected.get(key), &node.atom.version) {
+            (Some(cpv), _) => blocker.matches(cpv),
+            (None, Some(version)) => blocker.matches(&format!("{}-{}", node.atom.cp(), version)),
+            (None, None) => false,
+        }
+    }
+
+    /// Check whether the atoms pulled into one slot can be satisfied by a single version
+    fn slot_conflict(&self, key: &str) -> Option<SlotConflict> {
+        let requests = self.requests.get(key)?;
+        let versioned: Vec<&Atom> = requests.iter()
+            .map(|(atom, _)| atom)
+            .filter(|atom| atom.op != Operator::None && atom.version.is_some())
+            .collect();
+        if versioned.len() < 2 {
+            return None;
+        }
+
+        // Any available version, or without those any version named by one of the atoms,
+        // that satisfies all of them resolves the slot
+        let cp = Self::key_cp(key);
+        let named: Vec<String> = versioned.iter()
+            .map(|candidate| format!("{}-{}", cp, candidate.version.as_deref().unwrap_or_default()))
+            .collect();
+        let candidates = self.available.get(cp).filter(|versions| !versions.is_empty()).unwrap_or(&named);
+        let satisfiable = candidates.iter().any(|cpv| versioned.iter().all(|atom| atom.matches(cpv)));
+        if satisfiable {
+            return None;
+        }
+
+        Some(SlotConflict {
+            cp: cp.to_string(),
+            slot: self.nodes.get(key)
+                .and_then(|n| n.slot.clone())
+                .unwrap_or_else(|| "0".to_string()),
+            atoms: requests.iter()
+                .filter(|(atom, _)| atom.op != Operator::None)
+                .cloned()
+                .collect(),
         })
     }
 
-    fn detect_cycles(&self) -> Vec<String> {
+    /// The package that pulled `key` into the graph, or REQUESTED for an argument
+    fn pulled_in_by(&self, key: &str) -> Option<String> {
+        let parents: Vec<&String> = self.requests.get(key).into_iter().flatten().map(|(_, parent)| parent).collect();
+        parents.iter().find(|parent| parent.as_str() == REQUESTED).or(parents.first()).map(|parent| parent.to_string())
+            .or_else(|| {
+                self.any_of.iter()
+                    .find(|group| group.choices.iter().flatten().any(|dep| self.dep_key(dep) == key))
+                    .map(|group| group.parent.clone())
+            })
+    }
+
+    /// "label pulled in by" followed by the chain of packages from `parent` up to an argument
+    fn format_chain(&self, label: &str, parent: Option<String>) -> String {
+        let mut out = format!("  {} pulled in by\n", label);
+        let mut seen = HashSet::new();
+        let mut current = parent;
+        let mut depth = 2;
+        while let Some(key) = current.take() {
+            if key == REQUESTED {
+                out.push_str(&format!("{}(argument)\n", "  ".repeat(depth)));
+                break;
+            }
+            if !seen.insert(key.clone()) {
+                break;
+            }
+            current = self.pulled_in_by(&key);
+            let more = if current.is_some() { " pulled in by" } else { "" };
+            out.push_str(&format!("{}{}{}\n", "  ".repeat(depth), key, more));
+            depth += 1;
+        }
+        out
+    }
+
+    /// Why resolution failed: each slot conflict and blocker with the chains of packages that
+    /// pulled the conflicting requirements in, after how much backtracking
+    pub fn explain_conflicts(&self, result: &ResolutionResult) -> String {
+        let mut out = format!(
+            "!!! Dependency conflicts remain after {} of at most {} backtracking attempts (--backtrack)\n",
+            result.backtracks, self.backtrack,
+        );
+        if !result.slot_conflicts.is_empty() {
+            out.push_str(
+                "\n!!! Multiple package instances within a single package slot have been pulled\n\
+                 !!! into the dependency graph, resulting in a slot conflict:\n",
+            );
+            for conflict in &result.slot_conflicts {
+                out.push_str(&format!("\n{}:{}\n", conflict.cp, conflict.slot));
+                for (atom, parent) in &conflict.atoms {
+                    out.push('\n');
+                    out.push_str(&self.format_chain(&atom.to_string(), Some(parent.clone())));
+                }
+            }
+        }
+        if !result.blocks.is_empty() {
+            out.push_str("\n!!! The following packages cannot be installed at the same time:\n");
+            for block in &result.blocks {
+                let label = |key: &str| result.selected.get(key).cloned().unwrap_or_else(|| key.to_string());
+                let bang = if block.blocker.blocker.as_deref().is_some_and(|b| !b.is_empty()) { "" } else { "!" };
+                out.push_str(&format!("\n{} is blocked by {} ({}{})\n\n", label(&block.blocked), label(&block.holder), bang, block.blocker));
+                if self.nodes.contains_key(&block.blocked) {
+                    out.push_str(&self.format_chain(&label(&block.blocked), self.pulled_in_by(&block.blocked)));
+                } else {
+                    out.push_str(&format!("  {} is installed\n", block.blocked));
+                }
+                out.push('\n');
+                out.push_str(&self.format_chain(&label(&block.holder), self.pulled_in_by(&block.holder)));
+            }
+        }
+        out
+    }
+
+    /// Kinds of the dependency edge from `from` to `to`; edges added without one take the
+    /// kind of the dependency's node
+    pub fn edge_kinds(&self, from: &str, to: &str) -> Vec<DepType> {
+        match self.edge_types.get(&(from.to_string(), to.to_string())) {
+            Some(kinds) => kinds.clone(),
+            None => self.nodes.get(to).map(|node| vec![node.dep_type.clone()]).unwrap_or_default(),
+        }
+    }
+
+    /// Whether merge order may ignore the edge from `from` to `to`: always for PDEPEND, and
+    /// to break a cycle for runtime dependencies, which only have to be in place once both
+    /// are merged, or for build dependencies that are already installed
+    pub fn ignorable_edge(&self, from: &str, to: &str) -> bool {
+        let kinds = self.edge_kinds(from, to);
+        if kinds.iter().all(|kind| *kind == DepType::Post) {
+            return true;
+        }
+        let installed = || self.installed.iter().any(|cpv| crate::versions::cpv_getkey(cpv).as_deref() == Some(Self::key_cp(to)));
+        let breakable = !kinds.contains(&DepType::Build) || (self.break_installed_build_cycles && installed());
+        breakable && (from == to || self.reaches(to, from))
+    }
+
+    /// Whether `to` can be reached from `from` without following PDEPEND edges
+    fn reaches(&self, from: &str, to: &str) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from.to_string()];
+        while let Some(key) = stack.pop() {
+            if key == to {
+                return true;
+            }
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            for dep in self.edges.get(&key).into_iter().flatten() {
+                if !self.edge_kinds(&key, dep).iter().all(|kind| *kind == DepType::Post) {
+                    stack.push(dep.clone());
+                }
+            }
+        }
+        false
+    }
+
+    /// Cycles among the `resolved` packages made only of edges that cannot be ignored
+    fn detect_cycles(&self, resolved: &[String]) -> Vec<DependencyCycle> {
         let mut cycles = Vec::new();
         let mut visited = HashSet::new();
-        let mut rec_stack = HashSet::new();
-
-        for node in self.nodes.keys() {
+        for node in resolved {
             if !visited.contains(node) {
-                self.dfs_cycle(node, &mut visited, &mut rec_stack, &mut cycles);
+                self.dfs_cycle(node, resolved, &mut visited, &mut Vec::new(), &mut cycles);
             }
         }
-
         cycles
     }
 
-    fn dfs_cycle(&self, node: &str, visited: &mut HashSet<String>, rec_stack: &mut HashSet<String>, cycles: &mut Vec<String>) {
+    fn dfs_cycle(&self, node: &str, resolved: &[String], visited: &mut HashSet<String>, path: &mut Vec<String>, cycles: &mut Vec<DependencyCycle>) {
         visited.insert(node.to_string());
-        rec_stack.insert(node.to_string());
+        path.push(node.to_string());
 
-        if let Some(deps) = self.edges.get(node) {
-            for dep in deps {
-                if !visited.contains(dep) {
-                    self.dfs_cycle(dep, visited, rec_stack, cycles);
-                } else if rec_stack.contains(dep) {
-                    cycles.push(format!("Cycle detected involving: {} -> {}", node, dep));
-                }
+        for dep in self.edges.get(node).into_iter().flatten() {
+            if !resolved.contains(dep) || self.ignorable_edge(node, dep) {
+                continue;
+            }
+            if let Some(start) = path.iter().position(|key| key == dep) {
+                let members = path[start..].to_vec();
+                let kinds = (0..members.len())
+                    .map(|i| self.edge_kinds(&members[i], &members[(i + 1) % members.len()]))
+                    .collect();
+                cycles.push(DependencyCycle { members, kinds });
+            } else if !visited.contains(dep) {
+                self.dfs_cycle(dep, resolved, visited, path, cycles);
             }
         }
 
-        rec_stack.remove(node);
+        path.pop();
     }
 
     pub fn get_install_order(&self, targets: &[String]) -> Result<Vec<String>, InvalidData> {
@@ -222,7 +860,7 @@ impl DepGraph {
         }
 
         if !resolution.circular.is_empty() {
-            return Err(InvalidData::new(&format!("Circular dependencies: {:?}", resolution.circular), None));
+            return Err(InvalidData::new(&format_cycles(&resolution.circular), None));
         }
 
         // Simple topological sort (dependencies first)
@@ -236,6 +874,27 @@ impl DepGraph {
         Ok(order)
     }
 
+    /// Nodes below `targets` in --tree order: each node first, then its dependencies one
+    /// level deeper; a node reached twice is only listed the first time
+    pub fn tree_order(&self, targets: &[String]) -> Vec<(String, usize)> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        for target in targets {
+            self.tree_visit(target, 0, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn tree_visit(&self, node: &str, depth: usize, visited: &mut HashSet<String>, order: &mut Vec<(String, usize)>) {
+        if !visited.insert(node.to_string()) {
+            return;
+        }
+        order.push((node.to_string(), depth));
+        for dep in self.edges.get(node).into_iter().flatten() {
+            self.tree_visit(dep, depth + 1, visited, order);
+        }
+    }
+
     fn topological_sort(&self, node: &str, visited: &mut HashSet<String>, order: &mut Vec<String>) {
         if visited.contains(node) {
             return;
@@ -251,4 +910,361 @@ impl DepGraph {
 
         order.push(node.to_string());
     }
-}
\ No newline at end of file
+}
+
+impl std::fmt::Display for SlotConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}:{}", self.cp, self.slot)?;
+        for (atom, parent) in &self.atoms {
+            writeln!(f)?;
+            writeln!(f, "  {} pulled in by", atom)?;
+            writeln!(f, "    {}", parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for DependencyCycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} depends on", self.members[0])?;
+        for (i, kinds) in self.kinds.iter().enumerate() {
+            let next = &self.members[(i + 1) % self.members.len()];
+            let kinds: Vec<&str> = kinds.iter().map(DepType::label).collect();
+            writeln!(f, "{}{} ({})", "  ".repeat(i + 1), next, kinds.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Report cycles that could not be broken, as emerge does
+pub fn format_cycles(cycles: &[DependencyCycle]) -> String {
+    let mut out = String::from("\n * Error: circular dependencies:\n");
+    for cycle in cycles {
+        out.push_str(&format!("\n{}", cycle));
+    }
+    out.push_str("\n * Note that circular dependencies can often be avoided by temporarily\n");
+    out.push_str(" * disabling USE flags that trigger optional dependencies.\n");
+    out
+}
+
+/// Format slot conflicts the way emerge reports them
+pub fn format_slot_conflicts(conflicts: &[SlotConflict]) -> String {
+    let mut out = String::from(
+        "!!! Multiple package instances within a single package slot have been pulled\n\
+         !!! into the dependency graph, resulting in a slot conflict:\n",
+    );
+    for conflict in conflicts {
+        out.push('\n');
+        out.push_str(&conflict.to_string());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(atom: &str, slot: Option<&str>, slot_operator: Option<SlotOperator>) -> DepNode {
+        let atom = Atom::new(atom).unwrap();
+        DepNode {
+            slot: slot.map(|s| s.to_string()).or(atom.slot.clone()),
+            subslot: None,
+            atom,
+            dep_type: DepType::Runtime,
+            blockers: vec![],
+            use_conditional: None,
+            slot_operator,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_package_with_and_without_slot_is_one_node() {
+        // Requested without a slot, then pulled in with it: the "cp" node becomes "cp:slot"
+        let mut graph = DepGraph::new();
+        let target = graph.add_target(&Atom::new("dev-libs/openssl").unwrap(), vec![], vec![]);
+        assert_eq!(target, "dev-libs/openssl");
+        let app = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![dep("dev-libs/openssl:0", None, None)], vec![]);
+        assert_eq!(graph.current_key(&target), "dev-libs/openssl:0");
+        assert!(!graph.nodes.contains_key("dev-libs/openssl"));
+        assert_eq!(graph.requests["dev-libs/openssl:0"].len(), 2);
+        let result = graph.resolve(&[target, app]).unwrap();
+        assert_eq!(result.resolved, vec!["dev-libs/openssl:0", "app-misc/foo"]);
+
+        // Pulled in with the slot first: a later request without one finds the same node
+        let mut graph = DepGraph::new();
+        let app = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![dep("dev-libs/openssl:0", None, None)], vec![]);
+        let target = graph.add_target(&Atom::new("dev-libs/openssl").unwrap(), vec![], vec![]);
+        assert_eq!(target, "dev-libs/openssl:0");
+        assert_eq!(graph.nodes.keys().filter(|key| DepGraph::key_cp(key) == "dev-libs/openssl").count(), 1);
+        assert_eq!(graph.resolve(&[app, target]).unwrap().resolved.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slots_are_separate_nodes() {
+        let mut graph = DepGraph::new();
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![
+            dep("dev-lang/python:3.11", None, None),
+            dep("dev-lang/python:3.12", None, None),
+            dep("dev-libs/openssl", Some("0"), Some(SlotOperator::Equal)),
+            dep("sys-libs/zlib", Some("0"), Some(SlotOperator::Any)),
+        ], vec![]);
+
+        let result = graph.resolve(&[key]).unwrap();
+        assert_eq!(result.resolved.len(), 5);
+        assert!(result.resolved.contains(&"dev-lang/python:3.11".to_string()));
+        assert!(result.resolved.contains(&"dev-lang/python:3.12".to_string()));
+        assert!(result.resolved.contains(&"dev-libs/openssl:0".to_string()));
+        assert!(result.resolved.contains(&"sys-libs/zlib".to_string()));
+        assert!(result.slot_conflicts.is_empty());
+        assert_eq!(DepGraph::key_cp("dev-lang/python:3.11"), "dev-lang/python");
+    }
+
+    #[tokio::test]
+    async fn test_transitive_dependencies_are_resolved() {
+        let mut graph = DepGraph::new();
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![
+            dep("dev-libs/bar", None, None),
+        ], vec![]);
+        let added = graph.add_dependencies("dev-libs/bar", vec![dep("sys-libs/zlib", None, None)]);
+        assert_eq!(added, vec!["sys-libs/zlib".to_string()]);
+
+        let result = graph.resolve(&[key.clone()]).unwrap();
+        assert_eq!(result.resolved.len(), 3);
+        assert!(result.resolved.contains(&"sys-libs/zlib".to_string()));
+
+        assert_eq!(graph.tree_order(&[key]), vec![
+            ("app-misc/foo".to_string(), 0),
+            ("dev-libs/bar".to_string(), 1),
+            ("sys-libs/zlib".to_string(), 2),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_exclude_and_reinstall() {
+        let mut graph = DepGraph::new().with_exclude(vec![Atom::new("dev-libs/bar").unwrap(), Atom::new("dev-lang/python:3.11").unwrap()])
+            .with_reinstall(vec![Atom::new("sys-libs/zlib").unwrap()]);
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![
+            dep("dev-libs/bar", None, None),
+            dep("dev-lang/python:3.11", None, None),
+            dep("dev-lang/python:3.12", None, None),
+        ], vec![]);
+        graph.add_dependencies("dev-libs/bar", vec![dep("dev-libs/only-via-bar", None, None)]);
+        graph.add_dependencies("dev-lang/python:3.12", vec![dep("sys-libs/zlib", None, None)]);
+
+        let result = graph.resolve(&[key]).unwrap();
+        assert_eq!(result.resolved, vec!["app-misc/foo", "dev-lang/python:3.12", "sys-libs/zlib"]);
+        assert_eq!(result.excluded, vec!["dev-libs/bar", "dev-lang/python:3.11"]);
+        assert!(graph.is_reinstall("sys-libs/zlib"));
+        assert!(!graph.is_reinstall("app-misc/foo"));
+    }
+
+    #[tokio::test]
+    async fn test_any_of_providers() {
+        let editors = || vec![vec![dep("app-editors/vim", None, None)], vec![dep("app-editors/emacs", None, None)]];
+
+        // The first alternative, unless another one is installed
+        let mut graph = DepGraph::new();
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![], vec![]);
+        graph.add_any_of(&key, editors());
+        let result = graph.resolve(std::slice::from_ref(&key)).unwrap();
+        assert_eq!(result.resolved, vec!["app-misc/foo", "app-editors/vim"]);
+        assert_eq!(result.any_of_choices, vec![("app-misc/foo".to_string(), vec!["app-editors/vim".to_string()])]);
+
+        let mut graph = DepGraph::new().with_installed(vec!["app-editors/emacs-29.4".to_string()]);
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![], vec![]);
+        graph.add_any_of(&key, editors());
+        assert_eq!(graph.resolve(&[key]).unwrap().resolved, vec!["app-misc/foo", "app-editors/emacs"]);
+
+        // A provider that is blocked is replaced by the next one
+        let mut graph = DepGraph::new();
+        let blocker = graph.add_target(&Atom::new("app-misc/bar").unwrap(), vec![], vec![Atom::new("app-editors/vim").unwrap()]);
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![], vec![]);
+        graph.add_any_of(&key, editors());
+        let result = graph.resolve(&[blocker, key]).unwrap();
+        assert_eq!(result.resolved, vec!["app-misc/bar", "app-misc/foo", "app-editors/emacs"]);
+        assert!(result.blocked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_installed_blockers() {
+        let installed = vec!["app-misc/bar-1.0".to_string(), "sys-apps/baz-1.5".to_string(), "dev-libs/qux-1.0".to_string()];
+        let mut soft = Atom::new("app-misc/bar").unwrap();
+        soft.blocker = Some("!".to_string());
+        let mut hard = Atom::new("<sys-apps/baz-2").unwrap();
+        hard.blocker = Some("!!".to_string());
+        let mut replaced = Atom::new("<dev-libs/qux-2").unwrap();
+        replaced.blocker = Some("!".to_string());
+
+        // A soft blocker unmerges the installed package; one being upgraded is not blocked
+        let mut graph = DepGraph::new().with_installed(installed.clone());
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![dep(">=dev-libs/qux-2", None, None)], vec![soft, replaced]);
+        let result = graph.resolve(&[key]).unwrap();
+        assert!(result.blocks.is_empty());
+        assert_eq!(result.uninstalls.iter().map(|b| (b.holder.as_str(), b.blocked.as_str())).collect::<Vec<_>>(), vec![("app-misc/foo", "app-misc/bar-1.0")]);
+
+        // A hard one has to be resolved by hand
+        let mut graph = DepGraph::new().with_installed(installed).with_backtrack(0);
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![], vec![hard]);
+        let result = graph.resolve(&[key]).unwrap();
+        assert!(result.uninstalls.is_empty());
+        assert_eq!(result.blocks[0].blocked, "sys-apps/baz-1.5");
+        assert!(graph.explain_conflicts(&result).contains("\nsys-apps/baz-1.5 is blocked by app-misc/foo (!!<sys-apps/baz-2)\n\n  sys-apps/baz-1.5 is installed\n"));
+    }
+
+    #[tokio::test]
+    async fn test_cycle_breaking() {
+        let build = |atom: &str| DepNode { dep_type: DepType::Build, ..dep(atom, None, None) };
+        let graph_with = |back: DepNode, installed: Vec<String>| {
+            let mut graph = DepGraph::new().with_installed(installed);
+            let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![build("dev-libs/bar")], vec![]);
+            graph.add_dependencies("dev-libs/bar", vec![back]);
+            (graph, key)
+        };
+
+        // PDEPEND and runtime edges break the cycle
+        let post = DepNode { dep_type: DepType::Post, ..dep("app-misc/foo", None, None) };
+        for back in [post, dep("app-misc/foo", None, None)] {
+            let (graph, key) = graph_with(back, vec![]);
+            assert!(graph.resolve(&[key]).unwrap().circular.is_empty());
+        }
+
+        // Build dependencies both ways only when one of them is installed
+        let (graph, key) = graph_with(build("app-misc/foo"), vec![]);
+        let circular = graph.resolve(&[key]).unwrap().circular;
+        assert_eq!(circular, vec![DependencyCycle {
+            members: vec!["app-misc/foo".to_string(), "dev-libs/bar".to_string()],
+            kinds: vec![vec![DepType::Build], vec![DepType::Build]],
+        }]);
+        assert_eq!(format_cycles(&circular), "\n * Error: circular dependencies:\n\n\
+            app-misc/foo depends on\n  dev-libs/bar (buildtime)\n    app-misc/foo (buildtime)\n\n \
+            * Note that circular dependencies can often be avoided by temporarily\n \
+            * disabling USE flags that trigger optional dependencies.\n");
+
+        let (graph, key) = graph_with(build("app-misc/foo"), vec!["dev-libs/bar-1.0".to_string()]);
+        assert!(graph.resolve(std::slice::from_ref(&key)).unwrap().circular.is_empty());
+        let graph = graph.with_break_installed_build_cycles(false);
+        assert_eq!(graph.resolve(&[key]).unwrap().circular.len(), 1);
+
+        // A package needing itself to build, once it is installed
+        let mut graph = DepGraph::new().with_installed(vec!["dev-lang/rust-1.80".to_string()]);
+        let key = graph.add_target(&Atom::new("dev-lang/rust").unwrap(), vec![build("dev-lang/rust")], vec![]);
+        assert!(graph.resolve(&[key]).unwrap().circular.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backtrack_to_older_version() {
+        let available = HashMap::from([
+            ("dev-libs/foo".to_string(), vec!["dev-libs/foo-3.0".to_string(), "dev-libs/foo-2.1".to_string(), "dev-libs/foo-1.0".to_string()]),
+        ]);
+        let build = |backtrack: usize| {
+            let mut graph = DepGraph::new().with_available(available.clone()).with_backtrack(backtrack);
+            let a = graph.add_target(&Atom::new("app-misc/bar").unwrap(), vec![dep(">=dev-libs/foo-2", None, None)], vec![]);
+            let b = graph.add_target(&Atom::new("app-misc/baz").unwrap(), vec![], vec![Atom::new(">=dev-libs/foo-3").unwrap()]);
+            (graph, vec![a, b])
+        };
+
+        let (graph, targets) = build(DEFAULT_BACKTRACK);
+        let result = graph.resolve(&targets).unwrap();
+        assert!(result.blocks.is_empty());
+        assert_eq!(result.selected["dev-libs/foo"], "dev-libs/foo-2.1");
+        assert_eq!(result.backtracks, 1);
+
+        // Without backtracking the conflict stays, and is explained
+        let (graph, targets) = build(0);
+        let result = graph.resolve(&targets).unwrap();
+        assert_eq!(result.blocked, vec!["app-misc/baz"]);
+        assert_eq!(graph.explain_conflicts(&result), "\
+!!! Dependency conflicts remain after 0 of at most 0 backtracking attempts (--backtrack)
+
+!!! The following packages cannot be installed at the same time:
+
+dev-libs/foo-3.0 is blocked by app-misc/baz (!>=dev-libs/foo-3)
+
+  dev-libs/foo-3.0 pulled in by
+    app-misc/bar pulled in by
+      (argument)
+
+  app-misc/baz pulled in by
+    (argument)
+");
+    }
+
+    #[tokio::test]
+    async fn test_explain_slot_conflict() {
+        let mut graph = DepGraph::new();
+        let a = graph.add_target(&Atom::new("=dev-libs/foo-1.0").unwrap(), vec![], vec![]);
+        let b = graph.add_target(&Atom::new("app-misc/top").unwrap(), vec![dep("app-misc/bar", None, None)], vec![]);
+        graph.add_dependencies("app-misc/bar", vec![dep(">=dev-libs/foo-2.0", None, None)]);
+
+        let result = graph.resolve(&[a, b]).unwrap();
+        let explanation = graph.explain_conflicts(&result);
+        assert!(explanation.contains("\ndev-libs/foo:0\n\n  =dev-libs/foo-1.0 pulled in by\n    (argument)\n"));
+        assert!(explanation.contains("\n  >=dev-libs/foo-2.0 pulled in by\n    app-misc/bar pulled in by\n      app-misc/top pulled in by\n        (argument)\n"));
+    }
+
+    #[tokio::test]
+    async fn test_use_requirements() {
+        let mut graph = DepGraph::new();
+        let mut ssl = dep("dev-libs/bar", None, None);
+        ssl.atom.use_deps = vec!["ssl".to_string(), "-static".to_string(), "doc?".to_string()];
+        let key = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![ssl], vec![]);
+
+        // USE dependencies do not stop the dependency from being pulled in
+        let result = graph.resolve(&[key]).unwrap();
+        assert!(result.resolved.contains(&"dev-libs/bar".to_string()));
+        assert_eq!(graph.use_requirements("dev-libs/bar"), vec![
+            ("ssl".to_string(), true, "app-misc/foo".to_string()),
+            ("static".to_string(), false, "app-misc/foo".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_multilib_use_requirements() {
+        let mut graph = DepGraph::with_use_flags(HashMap::from([("abi_x86_64".to_string(), true)]));
+        let mut multilib = dep("dev-libs/bar", None, None);
+        multilib.atom.use_deps = crate::multilib::MULTILIB_USEDEP.split(',').map(|d| d.to_string()).collect();
+        multilib.atom.use_deps.push("!debug=".to_string());
+        graph.add_target(&Atom::new("app-emulation/wine").unwrap(), vec![multilib], vec![]);
+
+        assert_eq!(graph.use_requirements("dev-libs/bar"), vec![
+            ("abi_x86_64".to_string(), true, "app-emulation/wine".to_string()),
+            ("debug".to_string(), true, "app-emulation/wine".to_string()),
+        ]);
+        // A 32-bit build of the parent wants the 32-bit library
+        let requirements = graph.use_requirements_with("dev-libs/bar", |_| HashMap::from([
+            ("abi_x86_32".to_string(), true),
+            ("abi_x86_64".to_string(), true),
+            ("debug".to_string(), true),
+        ]));
+        assert_eq!(requirements.iter().map(|(flag, enabled, _)| (flag.as_str(), *enabled)).collect::<Vec<_>>(),
+            vec![("abi_x86_32", true), ("abi_x86_64", true), ("debug", false)]);
+    }
+
+    #[tokio::test]
+    async fn test_slot_conflict_between_requested_packages() {
+        let mut graph = DepGraph::new();
+        let a = graph.add_target(&Atom::new("=dev-libs/foo-1.0").unwrap(), vec![], vec![]);
+        let b = graph.add_target(&Atom::new("app-misc/bar").unwrap(), vec![dep(">=dev-libs/foo-2.0", None, None)], vec![]);
+
+        let result = graph.resolve(&[a, b]).unwrap();
+        assert_eq!(result.slot_conflicts.len(), 1);
+        let conflict = &result.slot_conflicts[0];
+        assert_eq!(conflict.cp, "dev-libs/foo");
+        assert_eq!(conflict.atoms.len(), 2);
+
+        let report = format_slot_conflicts(&result.slot_conflicts);
+        assert!(report.contains("slot conflict"));
+        assert!(report.contains("=dev-libs/foo-1.0 pulled in by"));
+        assert!(report.contains("app-misc/bar"));
+    }
+
+    #[tokio::test]
+    async fn test_compatible_versions_do_not_conflict() {
+        let mut graph = DepGraph::new();
+        let a = graph.add_target(&Atom::new("=dev-libs/foo-2.1").unwrap(), vec![], vec![]);
+        let b = graph.add_target(&Atom::new("app-misc/bar").unwrap(), vec![dep(">=dev-libs/foo-2.0", None, None)], vec![]);
+
+        let result = graph.resolve(&[a, b]).unwrap();
+        assert!(result.slot_conflicts.is_empty());
+    }
+}
@@ -7,6 +7,7 @@ use std::process::Command;
 use crate::exception::InvalidData;
 use crate::atom::Atom;
 use crate::ebuild_exec::EbuildExecutor;
+use crate::toolchain::Toolchain;
 use chrono;
 use nix::unistd;
 
@@ -26,6 +27,8 @@ pub struct EbuildMetadata {
     pub description: Option<String>,
     pub homepage: Option<String>,
     pub src_uri: Vec<String>,
+    /// DISTDIR names of the SRC_URI entries renamed with "uri -> name", whatever the USE flags
+    pub distfile_names: std::collections::HashMap<String, String>,
     pub license: Option<String>,
     pub slot: String,
     pub keywords: Vec<String>,
@@ -33,6 +36,13 @@ pub struct EbuildMetadata {
     pub depend: Vec<crate::dep::Atom>,
     pub rdepend: Vec<crate::dep::Atom>,
     pub pdepend: Vec<crate::dep::Atom>,
+    /// || ( ) groups of DEPEND, RDEPEND and PDEPEND, which the lists above leave out
+    pub any_of: Vec<(crate::depgraph::DepType, crate::dep::AnyOf)>,
+    pub required_use: Option<String>,
+    /// RESTRICT tokens with USE conditionals applied
+    pub restrict: Vec<String>,
+    /// PROPERTIES tokens (live, test_network, ...) with USE conditionals applied
+    pub properties: Vec<String>,
 }
 
 /// Build environment for ebuild execution
@@ -48,8 +58,12 @@ pub struct BuildEnv {
     pub executor: Option<EbuildExecutor>,
     // Build environment management
     pub features: Vec<String>,
+    /// GENTOO_MIRRORS, tried before upstream unless RESTRICT=mirror
+    pub mirrors: Vec<String>,
     pub sandbox_enabled: bool,
     pub user_privilege: BuildUser,
+    /// Build log that phase output is teed into, once doebuild has opened it
+    pub log_path: Option<PathBuf>,
 }
 
 /// User privilege settings for builds
@@ -71,6 +85,98 @@ pub enum BuildPhase {
     Test,
     Install,
     Package,
+    /// pkg_pretend: sanity checks before anything is built
+    Pretend,
+    /// pkg_preinst: before the image is merged into ROOT
+    Preinst,
+    /// pkg_postinst: after the image is merged
+    Postinst,
+    /// pkg_prerm: before an installed package is removed
+    Prerm,
+    /// pkg_postrm: after an installed package is removed
+    Postrm,
+}
+
+impl BuildPhase {
+    /// Every phase, in execution order
+    pub const ALL: [BuildPhase; 8] = [
+        BuildPhase::Setup,
+        BuildPhase::Unpack,
+        BuildPhase::Prepare,
+        BuildPhase::Configure,
+        BuildPhase::Compile,
+        BuildPhase::Test,
+        BuildPhase::Install,
+        BuildPhase::Package,
+    ];
+
+    /// pkg_* hooks run around planning, merging and unmerging
+    pub const HOOKS: [BuildPhase; 5] = [
+        BuildPhase::Pretend,
+        BuildPhase::Preinst,
+        BuildPhase::Postinst,
+        BuildPhase::Prerm,
+        BuildPhase::Postrm,
+    ];
+
+    /// Parse a phase name as given to the ebuild command
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().chain(Self::HOOKS).find(|phase| phase.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildPhase::Setup => "setup",
+            BuildPhase::Unpack => "unpack",
+            BuildPhase::Prepare => "prepare",
+            BuildPhase::Configure => "configure",
+            BuildPhase::Compile => "compile",
+            BuildPhase::Test => "test",
+            BuildPhase::Install => "install",
+            BuildPhase::Package => "package",
+            BuildPhase::Pretend => "pretend",
+            BuildPhase::Preinst => "preinst",
+            BuildPhase::Postinst => "postinst",
+            BuildPhase::Prerm => "prerm",
+            BuildPhase::Postrm => "postrm",
+        }
+    }
+
+    /// Marker file left in WORKDIR once the phase has completed; hooks leave none
+    fn stamp(&self) -> Option<&'static str> {
+        match self {
+            BuildPhase::Setup => Some(".setuped"),
+            BuildPhase::Unpack => Some(".unpacked"),
+            BuildPhase::Prepare => Some(".prepared"),
+            BuildPhase::Configure => Some(".configured"),
+            BuildPhase::Compile => Some(".compiled"),
+            BuildPhase::Test => Some(".tested"),
+            BuildPhase::Install => Some(".installed"),
+            BuildPhase::Package => Some(".packaged"),
+            _ => None,
+        }
+    }
+
+    /// Name of the ebuild function implementing a pkg_* hook
+    pub fn function_name(&self) -> Option<&'static str> {
+        match self {
+            BuildPhase::Setup => Some("pkg_setup"),
+            BuildPhase::Pretend => Some("pkg_pretend"),
+            BuildPhase::Preinst => Some("pkg_preinst"),
+            BuildPhase::Postinst => Some("pkg_postinst"),
+            BuildPhase::Prerm => Some("pkg_prerm"),
+            BuildPhase::Postrm => Some("pkg_postrm"),
+            _ => None,
+        }
+    }
+
+    /// Phases that run before this one
+    pub fn prerequisites(&self) -> &'static [BuildPhase] {
+        match Self::ALL.iter().position(|phase| phase.name() == self.name()) {
+            Some(index) => &Self::ALL[..index],
+            None => &[],
+        }
+    }
 }
 
 impl Ebuild {
@@ -88,26 +194,11 @@ impl Ebuild {
         let content = fs::read_to_string(path)
             .map_err(|e| InvalidData::new(&format!("Failed to read ebuild: {}", e), None))?;
 
-        // Extract category/package/version from path
         // Path format: /usr/portage/category/package/package-version.ebuild
-        let path_str = path.to_string_lossy();
-        let parts: Vec<&str> = path_str.split('/').collect();
-
-        if parts.len() < 4 {
-            return Err(InvalidData::new("Invalid ebuild path format", None));
-        }
-
-        let category = parts[parts.len() - 3].to_string();
-        let filename = parts.last().unwrap();
-        let filename_no_ext = filename.trim_end_matches(".ebuild");
-
-        // Split package-version
-        let last_dash = filename_no_ext.rfind('-').ok_or_else(|| {
-            InvalidData::new("Invalid ebuild filename format", None)
-        })?;
-
-        let package = filename_no_ext[..last_dash].to_string();
-        let version = filename_no_ext[last_dash + 1..].to_string();
+        let cpv = crate::versions::Cpv::from_ebuild_path(path)?;
+        let category = cpv.category().to_string();
+        let package = cpv.package().to_string();
+        let version = cpv.pvr();
 
         let metadata = Self::parse_metadata_with_use(&content, use_flags)?;
 
@@ -131,6 +222,7 @@ impl Ebuild {
             description: None,
             homepage: None,
             src_uri: Vec::new(),
+            distfile_names: std::collections::HashMap::new(),
             license: None,
             slot: "0".to_string(),
             keywords: Vec::new(),
@@ -138,43 +230,128 @@ impl Ebuild {
             depend: Vec::new(),
             rdepend: Vec::new(),
             pdepend: Vec::new(),
+            any_of: Vec::new(),
+            required_use: None,
+            restrict: Vec::new(),
+            properties: Vec::new(),
         };
 
         // Simple parsing of bash variable assignments
-        for line in content.lines() {
+        let mut lines = content.lines();
+        while let Some(line) = lines.next() {
             let line = line.trim();
             if line.starts_with("DESCRIPTION=") {
                 metadata.description = Self::extract_quoted_value(line);
             } else if line.starts_with("HOMEPAGE=") {
                 metadata.homepage = Self::extract_quoted_value(line);
-            } else if line.starts_with("SRC_URI=") {
-                metadata.src_uri = Self::extract_array_value(line);
+            } else if let Some(rest) = line.strip_prefix("SRC_URI=") {
+                metadata.src_uri = if rest.starts_with('(') {
+                    Self::extract_array_value(line)
+                } else {
+                    let value = Self::read_multiline_value(rest, &mut lines);
+                    metadata.distfile_names = Self::uri_tokens(value.split_whitespace()).1;
+                    Self::uri_tokens(crate::dep::use_reduce(&value, use_flags).iter().map(|s| s.as_str())).0
+                };
             } else if line.starts_with("LICENSE=") {
                 metadata.license = Self::extract_quoted_value(line);
             } else if line.starts_with("SLOT=") {
                 metadata.slot = Self::extract_quoted_value(line).unwrap_or_else(|| "0".to_string());
             } else if line.starts_with("KEYWORDS=") {
                 metadata.keywords = Self::extract_array_value(line);
-            } else if line.starts_with("IUSE=") {
-                metadata.iuse = Self::extract_array_value(line);
+            } else if let Some(rest) = line.strip_prefix("IUSE=") {
+                // Recorded in the package database, so the usual IUSE="..." form counts too
+                metadata.iuse = if rest.starts_with('(') {
+                    Self::extract_array_value(line)
+                } else {
+                    Self::extract_quoted_value(line).map(|value| value.split_whitespace().map(|flag| flag.to_string()).collect()).unwrap_or_default()
+                };
             } else if line.starts_with("DEPEND=") {
-                if let Some(dep_str) = Self::extract_raw_value(line) {
-                    metadata.depend = crate::dep::parse_dependencies_with_use(&dep_str, &use_flags).unwrap_or_default();
+                if let Some(dep_str) = Self::extract_raw_value(line).map(|v| crate::multilib::expand_usedep(&v)) {
+                    let (atoms, any_of) = crate::dep::parse_dependency_groups(&dep_str, use_flags).unwrap_or_default();
+                    metadata.depend = atoms;
+                    metadata.any_of.extend(any_of.into_iter().map(|group| (crate::depgraph::DepType::Build, group)));
                 }
             } else if line.starts_with("RDEPEND=") {
-                if let Some(dep_str) = Self::extract_raw_value(line) {
-                    metadata.rdepend = crate::dep::parse_dependencies_with_use(&dep_str, &use_flags).unwrap_or_default();
+                if let Some(dep_str) = Self::extract_raw_value(line).map(|v| crate::multilib::expand_usedep(&v)) {
+                    let (atoms, any_of) = crate::dep::parse_dependency_groups(&dep_str, use_flags).unwrap_or_default();
+                    metadata.rdepend = atoms;
+                    metadata.any_of.extend(any_of.into_iter().map(|group| (crate::depgraph::DepType::Runtime, group)));
+                }
+            } else if let Some(rest) = line.strip_prefix("REQUIRED_USE=") {
+                let value = Self::read_multiline_value(rest, &mut lines);
+                metadata.required_use = (!value.is_empty()).then_some(value);
+            } else if line.starts_with("RESTRICT=") {
+                if let Some(value) = Self::extract_quoted_value(line) {
+                    metadata.restrict = crate::dep::use_reduce(&value, use_flags);
+                }
+            } else if line.starts_with("PROPERTIES=") {
+                if let Some(value) = Self::extract_quoted_value(line) {
+                    metadata.properties = crate::dep::use_reduce(&value, use_flags);
                 }
             } else if line.starts_with("PDEPEND=") {
-                if let Some(dep_str) = Self::extract_raw_value(line) {
-                    metadata.pdepend = crate::dep::parse_dependencies_with_use(&dep_str, &use_flags).unwrap_or_default();
+                if let Some(dep_str) = Self::extract_raw_value(line).map(|v| crate::multilib::expand_usedep(&v)) {
+                    let (atoms, any_of) = crate::dep::parse_dependency_groups(&dep_str, use_flags).unwrap_or_default();
+                    metadata.pdepend = atoms;
+                    metadata.any_of.extend(any_of.into_iter().map(|group| (crate::depgraph::DepType::Post, group)));
                 }
             }
         }
+        // cargo.eclass adds a distfile for every crate in CRATES
+        metadata.src_uri.extend(crate::cargo::ebuild_crates(content).iter().map(|c| c.uri()));
 
         Ok(metadata)
     }
 
+    /// A quoted value that may continue over following lines (REQUIRED_USE, SRC_URI)
+    fn read_multiline_value<'a>(rest: &str, lines: &mut impl Iterator<Item = &'a str>) -> String {
+        let mut value = rest.to_string();
+        if value.starts_with('"') && value.matches('"').count() < 2 {
+            for next in lines.by_ref() {
+                value.push(' ');
+                value.push_str(next.trim());
+                if next.contains('"') {
+                    break;
+                }
+            }
+        }
+        value.trim().trim_matches('"').trim_matches('\'').split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// The URLs among SRC_URI tokens, and the names given to those followed by "-> name"
+    fn uri_tokens<'a>(mut tokens: impl Iterator<Item = &'a str>) -> (Vec<String>, std::collections::HashMap<String, String>) {
+        let mut uris: Vec<String> = Vec::new();
+        let mut names = std::collections::HashMap::new();
+        while let Some(token) = tokens.next() {
+            if token == "->" {
+                if let (Some(uri), Some(name)) = (uris.last(), tokens.next()) {
+                    names.insert(uri.clone(), name.to_string());
+                }
+            } else if token.contains("://") {
+                uris.push(token.to_string());
+            }
+        }
+        (uris, names)
+    }
+
+    /// Every SRC_URI location whatever the USE flags, for emerge --fetch-all-uri
+    pub fn all_src_uris(content: &str) -> Vec<String> {
+        let mut uris = Vec::new();
+        let mut lines = content.lines().map(|l| l.trim());
+        while let Some(line) = lines.next() {
+            if let Some(rest) = line.strip_prefix("SRC_URI=") {
+                uris = if rest.starts_with('(') {
+                    Self::extract_array_value(line)
+                } else {
+                    let value = Self::read_multiline_value(rest, &mut lines);
+                    Self::uri_tokens(value.split_whitespace()).0
+                };
+                break;
+            }
+        }
+        uris.extend(crate::cargo::ebuild_crates(content).iter().map(|c| c.uri()));
+        uris
+    }
+
     /// Extract quoted string value from bash variable assignment
     fn extract_quoted_value(line: &str) -> Option<String> {
         let eq_pos = line.find('=')?;
@@ -218,6 +395,14 @@ impl Ebuild {
         }
     }
 
+    /// The name a SRC_URI entry is saved under in DISTDIR: its "-> name", else its last component
+    pub fn distfile_name<'a>(&'a self, uri: &'a str) -> &'a str {
+        match self.metadata.distfile_names.get(uri) {
+            Some(name) => name,
+            None => uri.rsplit('/').next().unwrap_or(uri),
+        }
+    }
+
     /// Get the full package name (category/package-version)
     pub fn cpv(&self) -> String {
         format!("{}/{}-{}", self.category, self.package, self.version)
@@ -230,8 +415,9 @@ impl Ebuild {
 }
 
 impl BuildEnv {
-    /// Create a new build environment for an ebuild
-    pub fn new(ebuild: &Ebuild, portdir: &Path, distdir: &Path, use_flags: HashMap<String, bool>, features: Vec<String>) -> Self {
+    /// Create a new build environment for an ebuild; `env` holds make.conf and package.env
+    /// settings (CFLAGS, MAKEOPTS, ...) and never overrides the build paths
+    pub fn new(ebuild: &Ebuild, portdir: &Path, distdir: &Path, use_flags: HashMap<String, bool>, features: Vec<String>, env: HashMap<String, String>) -> Self {
         // Use a temporary directory for testing
         let temp_dir = std::env::temp_dir();
         let workdir = temp_dir.join("emerge-rs-build").join(&ebuild.cpv());
@@ -239,30 +425,61 @@ impl BuildEnv {
         let builddir = workdir.join("build");
         let destdir = workdir.join("image");
 
-        let mut env_vars = HashMap::new();
+        let mut env_vars = env;
         env_vars.insert("WORKDIR".to_string(), workdir.to_string_lossy().to_string());
         env_vars.insert("S".to_string(), sourcedir.to_string_lossy().to_string());
         env_vars.insert("BUILD_DIR".to_string(), builddir.to_string_lossy().to_string());
         env_vars.insert("D".to_string(), destdir.to_string_lossy().to_string());
+        let eprefix = env_vars.get("EPREFIX").cloned().unwrap_or_default();
+        env_vars.insert("ED".to_string(), format!("{}{}", destdir.to_string_lossy(), eprefix));
         env_vars.insert("PORTDIR".to_string(), portdir.to_string_lossy().to_string());
+        if let Some(package_dir) = ebuild.path.parent() {
+            env_vars.insert("FILESDIR".to_string(), package_dir.join("files").to_string_lossy().to_string());
+        }
         env_vars.insert("DISTDIR".to_string(), distdir.to_string_lossy().to_string());
         env_vars.insert("PV".to_string(), ebuild.version.clone());
         env_vars.insert("PN".to_string(), ebuild.package.clone());
         env_vars.insert("P".to_string(), format!("{}-{}", ebuild.package, ebuild.version));
         env_vars.insert("CATEGORY".to_string(), ebuild.category.clone());
+        env_vars.insert("PROPERTIES".to_string(), ebuild.metadata.properties.join(" "));
+        export_use(&mut env_vars, &use_flags);
+        // Cross builds get the CHOST tools and a pkg-config looking in SYSROOT up front
+        let toolchain = Toolchain::from_env(&env_vars);
+        if let Some(cbuild) = &toolchain.cbuild {
+            env_vars.entry("CBUILD".to_string()).or_insert_with(|| cbuild.clone());
+        }
+        if toolchain.is_cross() {
+            toolchain.export(&mut env_vars);
+        }
+        // Temporary files stay inside WORKDIR, where the sandbox lets them be written
+        let tempdir = workdir.join("temp").to_string_lossy().to_string();
+        env_vars.insert("T".to_string(), tempdir.clone());
+        env_vars.insert("TMPDIR".to_string(), tempdir);
 
         // Determine sandbox and user settings based on features
-        let sandbox_enabled = features.contains(&"sandbox".to_string());
+        let sandbox_enabled = features.iter().any(|f| f == "sandbox" || f == "usersandbox");
         let user_privilege = Self::determine_build_user(&features);
 
         // Set up sandbox environment variables if enabled
         if sandbox_enabled {
             env_vars.insert("SANDBOX_ON".to_string(), "1".to_string());
-            // Add sandbox-specific environment variables
-            env_vars.insert("SANDBOX_WRITE".to_string(), format!("{}:{}", destdir.display(), workdir.display()));
+            // Paths from make.conf/package.env SANDBOX_WRITE stay writable too
+            let mut write = format!("{}:{}", destdir.display(), workdir.display());
+            if let Some(extra) = env_vars.get("SANDBOX_WRITE").filter(|extra| !extra.is_empty()) {
+                write = format!("{}:{}", write, extra);
+            }
+            env_vars.insert("SANDBOX_WRITE".to_string(), write);
             env_vars.insert("SANDBOX_PREDICT".to_string(), "/proc:/dev:/sys".to_string());
         }
 
+        // GENTOO_MIRRORS from make.conf as passed in, else the calling environment
+        let mirrors = env_vars.get("GENTOO_MIRRORS").cloned()
+            .or_else(|| std::env::var("GENTOO_MIRRORS").ok())
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|m| m.trim_end_matches('/').to_string())
+            .collect();
+
         BuildEnv {
             workdir,
             sourcedir,
@@ -274,8 +491,54 @@ impl BuildEnv {
             env_vars,
             executor: None, // Will be set later in doebuild
             features,
+            mirrors,
             sandbox_enabled,
             user_privilege,
+            log_path: None,
+        }
+    }
+
+    /// The ebuild's EAPI, 0 when it sets none
+    fn eapi(&self) -> String {
+        self.executor.as_ref()
+            .and_then(|executor| executor.variables().get("EAPI").cloned())
+            .unwrap_or_else(|| "0".to_string())
+    }
+
+    /// MAKEOPTS for the default src_compile, one job per CPU if unset
+    fn makeopts(&self) -> Vec<String> {
+        match self.env_vars.get("MAKEOPTS") {
+            Some(opts) => opts.split_whitespace().map(|s| s.to_string()).collect(),
+            None => vec![format!("-j{}", crate::util::cpuinfo::get_cpu_count().unwrap_or(1))],
+        }
+    }
+
+    /// NINJAOPTS for the default src_compile, following the MAKEOPTS job count if unset
+    fn ninjaopts(&self) -> Vec<String> {
+        match self.env_vars.get("NINJAOPTS") {
+            Some(opts) => opts.split_whitespace().map(|s| s.to_string()).collect(),
+            None => {
+                let makeopts = self.makeopts().join(" ");
+                vec![format!("-j{}", crate::util::cpuinfo::makeopts_to_job_count(&makeopts).unwrap_or(1))]
+            }
+        }
+    }
+
+    /// Build tool and arguments for the default src_compile/src_install: ninja for a
+    /// meson build directory, make otherwise
+    fn build_command(&self, target: Option<&str>) -> (&'static str, Vec<String>) {
+        if self.sourcedir.join("build/build.ninja").exists() {
+            let mut args = vec!["-C".to_string(), "build".to_string()];
+            match target {
+                Some(target) => args.push(target.to_string()),
+                None => args.extend(self.ninjaopts()),
+            }
+            ("ninja", args)
+        } else {
+            match target {
+                Some(target) => ("make", vec![target.to_string()]),
+                None => ("make", self.makeopts()),
+            }
         }
     }
 
@@ -316,6 +579,28 @@ impl BuildEnv {
         }
     }
 
+    /// Whether `phase` already completed in this WORKDIR
+    pub fn phase_completed(&self, phase: BuildPhase) -> bool {
+        phase.stamp().is_some_and(|stamp| self.workdir.join(stamp).exists())
+    }
+
+    pub fn mark_phase_completed(&self, phase: BuildPhase) -> Result<(), InvalidData> {
+        let Some(stamp) = phase.stamp() else {
+            return Ok(());
+        };
+        fs::write(self.workdir.join(stamp), "")
+            .map_err(|e| InvalidData::new(&format!("Failed to record {} phase: {}", phase.name(), e), None))
+    }
+
+    /// Remove WORKDIR and with it every phase stamp
+    pub fn clean(&self) -> Result<(), InvalidData> {
+        if self.workdir.exists() {
+            fs::remove_dir_all(&self.workdir)
+                .map_err(|e| InvalidData::new(&format!("Failed to remove {}: {}", self.workdir.display(), e), None))?;
+        }
+        Ok(())
+    }
+
     /// Set up the build environment directories
     pub fn setup(&self) -> Result<(), InvalidData> {
         fs::create_dir_all(&self.workdir)
@@ -326,9 +611,11 @@ impl BuildEnv {
             .map_err(|e| InvalidData::new(&format!("Failed to create builddir: {}", e), None))?;
         fs::create_dir_all(&self.destdir)
             .map_err(|e| InvalidData::new(&format!("Failed to create destdir: {}", e), None))?;
+        fs::create_dir_all(self.workdir.join("temp"))
+            .map_err(|e| InvalidData::new(&format!("Failed to create temp dir: {}", e), None))?;
 
         // Set up sandbox if enabled
-        if self.sandbox_enabled {
+        if self.sandbox_enabled || self.features.iter().any(|f| f == "network-sandbox") {
             self.setup_sandbox()?;
         }
 
@@ -340,8 +627,8 @@ impl BuildEnv {
 
     /// Set up sandbox environment
     fn setup_sandbox(&self) -> Result<(), InvalidData> {
-        // Check if sandbox is available
-        if !std::process::Command::new("sandbox").arg("--version").output().is_ok() {
+        // Check if namespaces can be created here
+        if !crate::sandbox::Sandbox::is_supported() {
             if self.features.contains(&"strict".to_string()) {
                 return Err(InvalidData::new("Sandbox requested but not available", None));
             } else {
@@ -350,11 +637,162 @@ impl BuildEnv {
             }
         }
 
-        // Sandbox is already configured via environment variables in new()
-        // The actual sandboxing happens when executing commands
+        // The actual sandboxing happens when executing commands, see sandbox()
         Ok(())
     }
 
+    /// Namespace sandbox for the commands of ebuild function `function` (src_compile, ...),
+    /// from FEATURES and SANDBOX_WRITE; None if disabled or unsupported on this system
+    pub fn sandbox(&self, function: &str) -> Option<crate::sandbox::Sandbox> {
+        let mut write_paths: Vec<PathBuf> = self.env_vars.get("SANDBOX_WRITE")
+            .map(|paths| paths.split(':').filter(|p| !p.is_empty()).map(PathBuf::from).collect())
+            .unwrap_or_else(|| vec![self.workdir.clone(), self.destdir.clone()]);
+        // git-r3 keeps its clones across builds
+        if function == "src_unpack" && self.is_live() {
+            write_paths.push(crate::git_r3::store_dir(&self.env_vars, &self.distdir));
+        }
+        let mut sandbox = crate::sandbox::Sandbox::from_features(&self.features, write_paths)?;
+        if self.phase_networked(function) {
+            sandbox.network = false;
+        }
+        (sandbox.filesystem || sandbox.network)
+            .then_some(sandbox)
+            .filter(|_| crate::sandbox::Sandbox::is_supported())
+    }
+
+    /// PROPERTIES of the ebuild and its eclasses, for the USE flags of the build
+    pub fn properties(&self) -> Vec<String> {
+        let mut properties: Vec<String> = self.env_vars.get("PROPERTIES")
+            .map(|p| p.split_whitespace().map(|p| p.to_string()).collect())
+            .unwrap_or_default();
+        if let Some(value) = self.executor.as_ref().and_then(|executor| executor.variables().get("PROPERTIES").cloned()) {
+            for property in crate::dep::use_reduce(&value, &self.use_flags) {
+                if !properties.contains(&property) {
+                    properties.push(property);
+                }
+            }
+        }
+        properties
+    }
+
+    /// Whether the ebuild builds from a VCS checkout: PROPERTIES=live or a VCS eclass
+    pub fn is_live(&self) -> bool {
+        self.properties().iter().any(|p| p == "live")
+            || self.executor.as_ref().is_some_and(|executor| crate::sets::LIVE_ECLASSES.iter().any(|name| executor.inherits(name)))
+    }
+
+    /// PROPERTIES=interactive: the build talks to the user on the terminal
+    pub fn is_interactive(&self) -> bool {
+        self.properties().iter().any(|p| p == "interactive")
+    }
+
+    /// Phases portage lets through network-sandbox: src_unpack of live ebuilds, and src_test
+    /// of PROPERTIES=test_network ebuilds when ALLOW_TEST permits it
+    fn phase_networked(&self, function: &str) -> bool {
+        let properties = self.properties();
+        match function {
+            "src_unpack" => self.is_live(),
+            "src_test" => properties.iter().any(|p| p == "test_network")
+                && self.env_vars.get("ALLOW_TEST").is_some_and(|allow| allow.split_whitespace().any(|a| a == "network" || a == "all")),
+            _ => false,
+        }
+    }
+
+    /// uid/gid the commands of `function` drop to: src_* phases run as the build user when
+    /// emerge is root, while emerge itself keeps root for merging
+    pub fn build_user(&self, function: &str) -> Option<(u32, u32)> {
+        if !function.starts_with("src_") || !unistd::Uid::effective().is_root() {
+            return None;
+        }
+        match self.user_privilege {
+            BuildUser::Root => None,
+            BuildUser::Portage { uid, gid } | BuildUser::Custom { uid, gid } => Some((uid, gid)),
+        }
+    }
+
+    /// Set up `command` to run `function`'s work in a forked child: sandboxed per FEATURES and
+    /// with privileges dropped per userpriv; the parent only waits for its exit status
+    pub fn confine(&self, function: &str, command: &mut std::process::Command) {
+        use std::os::unix::process::CommandExt;
+
+        let user = self.build_user(function);
+        match (self.sandbox(function), user) {
+            (Some(sandbox), Some((uid, gid))) => sandbox.with_user(uid, gid).apply(command),
+            (Some(sandbox), None) => sandbox.apply(command),
+            (None, Some((uid, gid))) => {
+                command.gid(gid).uid(uid);
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Run `command` in its own process group, copying its stdout and stderr both to the
+    /// terminal and the build log. Interactive builds keep the terminal instead: they stay
+    /// in the foreground process group with stdin, and their output is not logged.
+    pub fn run_logged(&self, command: &mut std::process::Command) -> std::io::Result<std::process::ExitStatus> {
+        use std::io::{Read, Write};
+        use std::process::Stdio;
+
+        if self.is_interactive() {
+            return command.spawn()?.wait();
+        }
+        crate::signals::isolate(command);
+        let Some(log_path) = &self.log_path else {
+            let mut child = command.spawn()?;
+            let _group = crate::signals::ProcessGroup::register(child.id());
+            return child.wait();
+        };
+        let log = std::sync::Arc::new(std::sync::Mutex::new(fs::OpenOptions::new().create(true).append(true).open(log_path)?));
+        let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let _group = crate::signals::ProcessGroup::register(child.id());
+
+        let tee = |mut source: Box<dyn Read + Send>, to_stderr: bool| {
+            let log = log.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                while let Ok(n) = source.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    crate::progress::clear();
+                    let _ = if to_stderr { std::io::stderr().write_all(&buf[..n]) } else { std::io::stdout().write_all(&buf[..n]) };
+                    if let Ok(mut log) = log.lock() {
+                        let _ = log.write_all(&buf[..n]);
+                    }
+                }
+            })
+        };
+        let threads = [
+            child.stdout.take().map(|out| tee(Box::new(out), false)),
+            child.stderr.take().map(|err| tee(Box::new(err), true)),
+        ];
+        let status = child.wait()?;
+        for thread in threads.into_iter().flatten() {
+            let _ = thread.join();
+        }
+        Ok(status)
+    }
+
+    /// Append a finished command's captured output to the build log
+    fn log_output(&self, output: &std::io::Result<std::process::Output>) {
+        use std::io::Write;
+
+        if let (Some(log_path), Ok(output)) = (&self.log_path, output)
+            && let Ok(mut log) = fs::OpenOptions::new().append(true).open(log_path)
+        {
+            let _ = log.write_all(&output.stdout);
+            let _ = log.write_all(&output.stderr);
+        }
+    }
+
+    /// A build command for `function` with the build environment exported, run confined
+    fn command(&self, function: &str, program: &str) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(program);
+        command.envs(&self.env_vars);
+        self.confine(function, command.as_std_mut());
+        command
+    }
+
     /// Set up user privileges for the build
     fn setup_user_privileges(&self) -> Result<(), InvalidData> {
         match &self.user_privilege {
@@ -400,34 +838,65 @@ impl BuildEnv {
 
     /// Execute a build phase
     pub async fn execute_phase(&self, ebuild: &Ebuild, phase: BuildPhase) -> Result<(), InvalidData> {
-        match phase {
-            BuildPhase::Setup => self.phase_setup().await,
+        crate::progress::clear();
+        crate::progress::set_phase(&ebuild.cpv(), phase.name());
+        let result = match phase {
+            BuildPhase::Setup => self.phase_setup(ebuild).await,
             BuildPhase::Unpack => self.phase_unpack(ebuild).await,
             BuildPhase::Prepare => self.phase_prepare(ebuild).await,
             BuildPhase::Configure => self.phase_configure(ebuild).await,
             BuildPhase::Compile => self.phase_compile(ebuild).await,
             BuildPhase::Test => self.phase_test(ebuild).await,
-            BuildPhase::Install => self.phase_install(ebuild).await,
+            BuildPhase::Install => {
+                self.phase_install(ebuild).await?;
+                self.strip_image(ebuild).await?;
+                self.compress_docs(ebuild)?;
+                self.install_qa()
+            }
             BuildPhase::Package => self.phase_package(ebuild).await,
+            BuildPhase::Pretend | BuildPhase::Preinst | BuildPhase::Postinst
+            | BuildPhase::Prerm | BuildPhase::Postrm => self.run_pkg_function(ebuild, phase),
+        };
+
+        // Make downloads during the build stand out as the cause of a failure
+        let function = format!("src_{}", phase.name());
+        if result.is_err()
+            && matches!(phase, BuildPhase::Unpack | BuildPhase::Prepare | BuildPhase::Configure
+                | BuildPhase::Compile | BuildPhase::Test | BuildPhase::Install)
+            && self.sandbox(&function).is_some_and(|sandbox| sandbox.network)
+        {
+            eprintln!(" * {} ran with FEATURES=network-sandbox and had no network access", function);
         }
+        result
     }
 
-    async fn phase_setup(&self) -> Result<(), InvalidData> {
+    /// Run the ebuild's pkg_* function for `phase`; packages without one have nothing to do
+    fn run_pkg_function(&self, ebuild: &Ebuild, phase: BuildPhase) -> Result<(), InvalidData> {
+        let Some(name) = phase.function_name() else {
+            return Ok(());
+        };
+        if let Some(executor) = &self.executor && executor.has_function(name) {
+            println!(">>> Running {} for {}", name, ebuild.cpv());
+            return executor.execute_function(name, self);
+        }
+        Ok(())
+    }
+
+    async fn phase_setup(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
         // Create basic directory structure
         println!("Setting up build environment...");
 
-        // Switch to build user if configured
-        self.switch_to_build_user()?;
-
         // Sandbox setup is already done in BuildEnv::setup()
         // but we can do additional phase-specific setup here if needed
 
-        Ok(())
+        // The phase saves the environment for the package database, even without pkg_setup
+        if let Some(executor) = &self.executor && !executor.has_function("pkg_setup") {
+            return executor.save_environment(self);
+        }
+        self.run_pkg_function(ebuild, BuildPhase::Setup)
     }
 
     async fn phase_unpack(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
-        use tokio::process::Command;
-
         println!("Unpacking sources for {}...", ebuild.cpv());
 
         // Check if there's a custom src_unpack function
@@ -450,64 +919,25 @@ impl BuildEnv {
         }
 
         // Default src_unpack implementation
+        let mut crates = Vec::new();
         for uri in &ebuild.metadata.src_uri {
-            println!("Downloading: {}", uri);
-
-            // Extract filename from URI
-            let filename = uri.split('/').last().unwrap_or("unknown.tar.gz");
+            let filename = ebuild.distfile_name(uri);
+            let file_path = self.distdir.join(filename);
+            self.fetch_distfile(ebuild, uri, &file_path).await?;
 
-            // Download the file
-            let output = Command::new("wget")
-                .arg("-O")
-                .arg(self.distdir.join(filename))
-                .arg(uri)
-                .output()
-                .await;
-
-            match output {
-                Ok(result) if result.status.success() => {
-                    println!("Downloaded: {}", filename);
-                }
-                Ok(result) => {
-                    eprintln!("Failed to download {}: {}", uri, String::from_utf8_lossy(&result.stderr));
-                    return Err(InvalidData::new(&format!("Download failed for {}", uri), None));
-                }
-                Err(e) => {
-                    eprintln!("Failed to run wget: {}", e);
-                    return Err(InvalidData::new(&format!("Download command failed: {}", e), None));
-                }
-            }
+            // Never unpack a distfile that doesn't match the Manifest
+            self.verify_distfile(ebuild, &file_path)?;
 
             // Extract the file
-            let file_path = self.distdir.join(filename);
-            if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
-                let output = Command::new("tar")
-                    .arg("-xzf")
+            if filename.ends_with(".crate") {
+                crates.push(file_path);
+            } else if let Some(flag) = tar_flag(filename) {
+                let output = crate::signals::output(self.command("src_unpack", "tar")
+                    .arg(flag)
                     .arg(&file_path)
                     .arg("-C")
-                    .arg(&self.sourcedir)
-                    .output().await;
-
-                match output {
-                    Ok(result) if result.status.success() => {
-                        println!("Extracted: {}", filename);
-                    }
-                    Ok(result) => {
-                        eprintln!("Failed to extract {}: {}", filename, String::from_utf8_lossy(&result.stderr));
-                        return Err(InvalidData::new(&format!("Extraction failed for {}", filename), None));
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to run tar: {}", e);
-                        return Err(InvalidData::new(&format!("Extraction command failed: {}", e), None));
-                    }
-                }
-            } else if filename.ends_with(".tar.bz2") || filename.ends_with(".tbz2") {
-                let output = Command::new("tar")
-                    .arg("-xjf")
-                    .arg(&file_path)
-                    .arg("-C")
-                    .arg(&self.sourcedir)
-                    .output().await;
+                    .arg(&self.sourcedir)).await;
+                self.log_output(&output);
 
                 match output {
                     Ok(result) if result.status.success() => {
@@ -531,7 +961,117 @@ impl BuildEnv {
                 println!("Copied: {}", filename);
             }
         }
+        if !crates.is_empty() {
+            self.vendor_crates(&crates).await?;
+        }
+
+        // go-module.eclass: EGO_SUM modules, served to go from a proxy directory
+        let content = fs::read_to_string(&ebuild.path).unwrap_or_default();
+        let modules = crate::go_module::ebuild_modules(&content);
+        if !modules.is_empty() {
+            for module in &modules {
+                let file_path = self.distdir.join(module.distfile());
+                self.fetch_distfile(ebuild, &module.uri(), &file_path).await?;
+                self.verify_distfile(ebuild, &file_path)?;
+            }
+            crate::go_module::populate_proxy(&modules, &self.distdir, &self.workdir)?;
+            println!("Linked {} Go module files", modules.len());
+        }
+
+        Ok(())
+    }
+
+    /// cargo_src_unpack: unpack the crates into ${ECARGO_VENDOR} and configure cargo to
+    /// build from there, offline
+    async fn vendor_crates(&self, crates: &[PathBuf]) -> Result<(), InvalidData> {
+        let vendor = crate::cargo::vendor_dir(&self.workdir);
+        tokio::fs::create_dir_all(&vendor).await
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", vendor.display(), e), None))?;
+        for file_path in crates {
+            let output = crate::signals::output(self.command("src_unpack", "tar")
+                .arg("-xzf")
+                .arg(file_path)
+                .arg("-C")
+                .arg(&vendor)).await;
+            self.log_output(&output);
+            match output {
+                Ok(result) if result.status.success() => {}
+                Ok(result) => {
+                    eprintln!("Failed to unpack {}: {}", file_path.display(), String::from_utf8_lossy(&result.stderr));
+                    return Err(InvalidData::new(&format!("Unpacking failed for {}", file_path.display()), None));
+                }
+                Err(e) => return Err(InvalidData::new(&format!("Unpacking command failed: {}", e), None)),
+            }
+        }
+        crate::cargo::write_checksums(&vendor)?;
+        crate::cargo::write_config(&self.workdir)?;
+        println!("Vendored {} crates", crates.len());
+        Ok(())
+    }
+
+    /// Download a distfile unless it is already in DISTDIR, honoring RESTRICT=fetch
+    async fn fetch_distfile(&self, ebuild: &Ebuild, uri: &str, file_path: &Path) -> Result<(), InvalidData> {
+        let filename = file_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        if ebuild.metadata.restrict.iter().any(|r| r == "fetch") {
+            if file_path.exists() {
+                return Ok(());
+            }
+            self.run_nofetch(ebuild, &filename)?;
+            return Err(InvalidData::new(&format!(
+                "{}: {} is fetch-restricted (RESTRICT=fetch); place it in {} manually",
+                ebuild.cpv(), filename, self.distdir.display()), None));
+        }
 
+        // Existing files are checked against the Manifest and fetched again if they don't match
+        let mut request = fetch_request(ebuild, uri);
+        request.filename = filename.clone();
+        if !self.features.iter().any(|f| f == "assume-digests") {
+            // A distfile the Manifest doesn't list could never be verified, so it isn't fetched
+            let package_dir = ebuild.path.parent().unwrap_or(Path::new("."));
+            let entry = crate::manifest::load_dist_entries(package_dir)?.remove(&filename).ok_or_else(|| {
+                InvalidData::new(&format!("{}: digest verification failed: no DIST entry in {}",
+                    filename, package_dir.join("Manifest").display()), None)
+            })?;
+            request.manifest = Some(entry);
+        }
+        // mirror:// names come from the ebuild's repository, else from PORTDIR
+        let mut repos: Vec<PathBuf> = ebuild.path.ancestors().nth(3).map(Path::to_path_buf).into_iter().collect();
+        repos.push(self.portdir.clone());
+        let manager = crate::fetch::FetchManager::new(&self.distdir, self.mirrors.clone())
+            .with_thirdpartymirrors(&repos)
+            .with_distlocks(self.features.iter().any(|f| f == "distlocks"));
+        manager.fetch(&request).await?;
+        Ok(())
+    }
+
+    /// pkg_nofetch, or the default instructions for fetch-restricted files
+    fn run_nofetch(&self, ebuild: &Ebuild, filename: &str) -> Result<(), InvalidData> {
+        if let Some(executor) = &self.executor && executor.has_function("pkg_nofetch") {
+            return executor.execute_function("pkg_nofetch", self);
+        }
+        println!(" * The following file cannot be fetched for {}:", ebuild.cpv());
+        println!(" *   {}", filename);
+        if let Some(homepage) = &ebuild.metadata.homepage {
+            println!(" * Download it from {}", homepage);
+        }
+        println!(" * and place it in {}", self.distdir.display());
+        Ok(())
+    }
+
+    /// Verify a distfile against the package Manifest unless FEATURES=assume-digests
+    fn verify_distfile(&self, ebuild: &Ebuild, file_path: &Path) -> Result<(), InvalidData> {
+        if self.features.iter().any(|f| f == "assume-digests") {
+            return Ok(());
+        }
+        let filename = file_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let package_dir = ebuild.path.parent().unwrap_or(Path::new("."));
+        let entries = crate::manifest::load_dist_entries(package_dir)?;
+        let entry = entries.get(&filename).ok_or_else(|| {
+            InvalidData::new(&format!("{}: digest verification failed: no DIST entry in {}",
+                filename, package_dir.join("Manifest").display()), None)
+        })?;
+        crate::manifest::verify_distfile(file_path, entry)?;
+        println!("Verified: {}", filename);
         Ok(())
     }
 
@@ -542,18 +1082,28 @@ impl BuildEnv {
         if let Some(executor) = &self.executor {
             if executor.has_function("src_prepare") {
                 println!("Executing custom src_prepare function");
-                return executor.execute_function("src_prepare", self);
+                executor.execute_function("src_prepare", self)?;
+            } else if let Some(patches) = executor.variables().get("PATCHES") {
+                // The default src_prepare applies PATCHES
+                crate::eapply::eapply(&crate::eapply::patches_var(patches, &self.env_vars, &self.sourcedir), &self.sourcedir)?;
+            }
+            // autotools ebuilds get current config.sub/config.guess, as econf would
+            if executor.inherits("autotools") && let Some(system) = crate::autotools::system_gnuconfig_dir() {
+                for path in crate::autotools::update_gnuconfig(&self.sourcedir, &system)? {
+                    println!(" * Updating {}", path.display());
+                }
             }
         }
 
-        // Default src_prepare implementation
-        // In real implementation, this would apply patches, etc.
+        // User patches, unless the ebuild's eapply_user applied them already
+        if !Path::new(&self.env_vars["T"]).join(crate::user_patches::APPLIED_MARKER).exists() {
+            let slot = self.executor.as_ref().and_then(|executor| executor.variables().get("SLOT").cloned());
+            crate::user_patches::apply_all(&crate::user_patches::for_build(&self.env_vars, slot.as_deref()), &self.sourcedir)?;
+        }
         Ok(())
     }
 
     async fn phase_configure(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
-        use tokio::process::Command;
-
         println!("Configuring {}...", ebuild.cpv());
 
         // Check if there's a custom src_configure function
@@ -573,10 +1123,16 @@ impl BuildEnv {
         let configure_path = sourcedir.join("configure");
         if configure_path.exists() {
             println!("Running ./configure...");
-            let output = Command::new("./configure")
-                .current_dir(sourcedir)
-                .output()
-                .await;
+            let help = crate::signals::output(self.command("src_configure", "./configure")
+                .arg("--help")
+                .current_dir(sourcedir)).await
+                .map(|result| String::from_utf8_lossy(&result.stdout).into_owned())
+                .unwrap_or_default();
+            let args = crate::econf::Econf::new(&self.env_vars, &self.eapi()).args_for(&help, &self.env_vars);
+            let output = crate::signals::output(self.command("src_configure", "./configure")
+                .args(&args)
+                .current_dir(sourcedir)).await;
+            self.log_output(&output);
 
             match output {
                 Ok(result) if result.status.success() => {
@@ -585,6 +1141,9 @@ impl BuildEnv {
                 }
                 Ok(result) => {
                     eprintln!("Configuration failed: {}", String::from_utf8_lossy(&result.stderr));
+                    if let Some(tail) = crate::econf::config_log_tail(sourcedir, 25) {
+                        eprintln!("!!! Last lines of {}:\n{}", sourcedir.join("config.log").display(), tail);
+                    }
                     return Err(InvalidData::new("Configuration failed", None));
                 }
                 Err(e) => {
@@ -598,11 +1157,11 @@ impl BuildEnv {
         let cmake_path = sourcedir.join("CMakeLists.txt");
         if cmake_path.exists() {
             println!("Running cmake...");
-            let output = Command::new("cmake")
+            let output = crate::signals::output(self.command("src_configure", "cmake")
+                .args(Toolchain::from_env(&self.env_vars).cmake_args(&self.env_vars))
                 .arg(".")
-                .current_dir(sourcedir)
-                .output()
-                .await;
+                .current_dir(sourcedir)).await;
+            self.log_output(&output);
 
             match output {
                 Ok(result) if result.status.success() => {
@@ -624,12 +1183,11 @@ impl BuildEnv {
         let meson_path = sourcedir.join("meson.build");
         if meson_path.exists() {
             println!("Running meson setup...");
-            let output = Command::new("meson")
+            let output = crate::signals::output(self.command("src_configure", "meson")
                 .arg("setup")
                 .arg("build")
-                .current_dir(sourcedir)
-                .output()
-                .await;
+                .current_dir(sourcedir)).await;
+            self.log_output(&output);
 
             match output {
                 Ok(result) if result.status.success() => {
@@ -653,8 +1211,6 @@ impl BuildEnv {
     }
 
     async fn phase_compile(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
-        use tokio::process::Command;
-
         println!("Compiling {}...", ebuild.cpv());
 
         // Check if there's a custom src_compile function
@@ -685,13 +1241,13 @@ int main() {
             }
 
             // Compile hello.c
-            let output = Command::new("gcc")
+            let cc = Toolchain::from_env(&self.env_vars).tool(&self.env_vars, "CC", "gcc");
+            let output = crate::signals::output(self.command("src_compile", &cc)
                 .arg("hello.c")
                 .arg("-o")
                 .arg("hello")
-                .current_dir(&self.sourcedir)
-                .output()
-                .await;
+                .current_dir(&self.sourcedir)).await;
+            self.log_output(&output);
 
             match output {
                 Ok(result) if result.status.success() => {
@@ -709,13 +1265,12 @@ int main() {
             }
         } else {
             // Default src_compile implementation
-            // Run make in the source directory
-            let output = Command::new("make")
-                .arg("-j")
-                .arg("4")  // Use 4 parallel jobs
-                .current_dir(&self.sourcedir)
-                .output()
-                .await;
+            // Run make (or ninja) in the source directory
+            let (tool, args) = self.build_command(None);
+            let output = crate::signals::output(self.command("src_compile", tool)
+                .args(args)
+                .current_dir(&self.sourcedir)).await;
+            self.log_output(&output);
 
             match output {
                 Ok(result) if result.status.success() => {
@@ -727,7 +1282,7 @@ int main() {
                     Err(InvalidData::new("Compilation failed", None))
                 }
                 Err(e) => {
-                    eprintln!("Failed to run make: {}", e);
+                    eprintln!("Failed to run {}: {}", tool, e);
                     Err(InvalidData::new(&format!("Make command failed: {}", e), None))
                 }
             }
@@ -735,6 +1290,15 @@ int main() {
     }
 
     async fn phase_test(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
+        if !self.features.iter().any(|f| f == "test") {
+            println!(">>> Skipping src_test for {} (FEATURES=test is not enabled)", ebuild.cpv());
+            return Ok(());
+        }
+        if ebuild.metadata.restrict.iter().any(|r| r == "test") {
+            println!(">>> Skipping src_test for {} (RESTRICT=test)", ebuild.cpv());
+            return Ok(());
+        }
+
         println!("Testing {}...", ebuild.cpv());
 
         // Check if there's a custom src_test function
@@ -751,8 +1315,6 @@ int main() {
     }
 
     async fn phase_install(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
-        use tokio::process::Command;
-
         println!("Installing {}...", ebuild.cpv());
 
         // Check if there's a custom src_install function
@@ -785,13 +1347,13 @@ int main() {
             Ok(())
         } else {
             // Default src_install implementation
-            // Run make install with DESTDIR
-            let output = Command::new("make")
-                .arg("install")
+            // Run make (or ninja) install with DESTDIR
+            let (tool, args) = self.build_command(Some("install"));
+            let output = crate::signals::output(self.command("src_install", tool)
+                .args(args)
                 .env("DESTDIR", &self.destdir)
-                .current_dir(&self.sourcedir)
-                .output()
-                .await;
+                .current_dir(&self.sourcedir)).await;
+            self.log_output(&output);
 
             match output {
                 Ok(result) if result.status.success() => {
@@ -803,217 +1365,532 @@ int main() {
                     Err(InvalidData::new("Installation failed", None))
                 }
                 Err(e) => {
-                    eprintln!("Failed to run make install: {}", e);
+                    eprintln!("Failed to run {} install: {}", tool, e);
                     Err(InvalidData::new(&format!("Make install command failed: {}", e), None))
                 }
             }
         }
     }
 
-    async fn phase_package(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
-        println!("Packaging {}...", ebuild.cpv());
-
-        // Create binary package (.tbz2)
-        self.create_binary_package(ebuild, "gentoo").await // TODO: get actual repository
+    /// Check the image for insecure or broken files; FEATURES=stricter turns notices into failures
+    fn install_qa(&self) -> Result<(), InvalidData> {
+        let root = self.env_vars.get("ROOT").map(|r| r.as_str()).filter(|r| !r.is_empty()).unwrap_or("/");
+        let issues = crate::install_qa::scan_image(&self.destdir, &self.workdir, Path::new(root));
+        let stricter = self.features.iter().any(|f| f == "stricter");
+        crate::install_qa::report(&issues, stricter, Some(&self.workdir.join("temp")))
     }
 
-    /// Create a binary package (.tbz2 file)
-    async fn create_binary_package(&self, ebuild: &Ebuild, repository: &str) -> Result<(), InvalidData> {
-        use tokio::process::Command;
-
-        let cpv = ebuild.cpv();
-        let pkgdir = format!("/usr/portage/packages");
-
-        // Ensure packages directory exists
-        tokio::fs::create_dir_all(&pkgdir)
-            .await
-            .map_err(|e| InvalidData::new(&format!("Failed to create packages directory: {}", e), None))?;
-
-        let tbz2_path = format!("{}/{}.tbz2", pkgdir, cpv);
-
-        // Create tar.bz2 archive of the installed files
-        let tar_cmd = Command::new("tar")
-            .args(&["-cjf", &tbz2_path, "-C", &self.destdir.to_string_lossy(), "."])
-            .status()
-            .await
-            .map_err(|e| InvalidData::new(&format!("Failed to create tar archive: {}", e), None))?;
+    /// Strip installed executables and shared libraries unless FEATURES=nostrip; under
+    /// RESTRICT=strip only what the ebuild named with dostrip is stripped
+    async fn strip_image(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
+        if self.features.iter().any(|f| f == "nostrip") {
+            return Ok(());
+        }
+        let restricted = ebuild.metadata.restrict.iter().any(|r| r == "strip");
+        let tempdir = self.workdir.join("temp");
+        let settings = crate::estrip::StripSettings::from_env(&self.env_vars, &self.features, &tempdir, restricted, &ebuild.cpv());
+        if settings.include.as_ref().is_some_and(|include| include.is_empty()) {
+            println!(">>> Not stripping {} (RESTRICT=strip)", ebuild.cpv());
+            return Ok(());
+        }
 
-        if !tar_cmd.success() {
-            return Err(InvalidData::new("tar command failed", None));
+        println!(">>> Stripping with {}", settings.strip);
+        for path in crate::estrip::strip_image(&self.destdir, &self.workdir, &tempdir, &settings)? {
+            println!("strip: {}", path);
         }
+        Ok(())
+    }
 
-        // Create XPAK metadata
-        let mut xpak_data = std::collections::HashMap::new();
+    /// Compress man pages, info pages and docs with PORTAGE_COMPRESS
+    fn compress_docs(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
+        let pf = format!("{}-{}", ebuild.package, ebuild.version);
+        let Some(settings) = crate::ecompress::CompressSettings::from_env(&self.env_vars, &self.workdir.join("temp"), &pf)? else {
+            return Ok(());
+        };
+        let compressed = crate::ecompress::compress_image(&self.destdir, &settings)?;
+        if !compressed.is_empty() {
+            println!(">>> Compressed {} files with {}", compressed.len(), settings.program);
+        }
+        Ok(())
+    }
 
-        // Add basic metadata
-        xpak_data.insert("SLOT".to_string(), ebuild.metadata.slot.as_bytes().to_vec());
-        xpak_data.insert("repository".to_string(), repository.as_bytes().to_vec());
+    async fn phase_package(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
+        println!("Packaging {}...", ebuild.cpv());
 
-        if let Some(description) = &ebuild.metadata.description {
-            xpak_data.insert("DESCRIPTION".to_string(), description.as_bytes().to_vec());
-        }
+        // Create binary package (.tbz2 or .gpkg.tar)
+        self.create_binary_package(ebuild).await
+    }
 
-        if let Some(license) = &ebuild.metadata.license {
-            xpak_data.insert("LICENSE".to_string(), license.as_bytes().to_vec());
-        }
+    /// Create a binary package in PKGDIR using BINPKG_FORMAT
+    async fn create_binary_package(&self, ebuild: &Ebuild) -> Result<(), InvalidData> {
+        let pkgdir = self.env_vars.get("PKGDIR").map(|s| s.as_str()).unwrap_or("/usr/portage/packages");
 
-        // Add USE flags (simplified)
-        let use_flags: Vec<String> = self.use_flags.iter()
-            .filter(|&(_, &enabled)| enabled)
-            .map(|(flag, _)| flag.clone())
+        // Record the build settings alongside the package
+        let mut extra: HashMap<String, String> = crate::binpkg::BUILD_VARS.iter()
+            .filter_map(|key| self.env_vars.get(*key).map(|value| (key.to_string(), value.clone())))
             .collect();
-        if !use_flags.is_empty() {
-            xpak_data.insert("USE".to_string(), use_flags.join(" ").as_bytes().to_vec());
+        extra.insert("FEATURES".to_string(), self.features.join(" "));
+        let config_root = self.env_vars.get("PORTAGE_CONFIGROOT").map(|s| s.as_str()).unwrap_or("/");
+        let mut porttree = crate::porttree::PortTree::new(config_root);
+        porttree.scan_repositories();
+        if let Some(repo) = porttree.repository_name_of(&ebuild.path) {
+            extra.insert("repository".to_string(), repo.to_string());
         }
 
-        // Add keywords
-        if !ebuild.metadata.keywords.is_empty() {
-            xpak_data.insert("KEYWORDS".to_string(), ebuild.metadata.keywords.join(" ").as_bytes().to_vec());
+        let format = self.env_vars.get("BINPKG_FORMAT")
+            .and_then(|f| crate::bintree::BinPkgFormat::parse(f))
+            .unwrap_or_default();
+
+        let mut builder = crate::binpkg::BinPkgBuilder::new(pkgdir).with_format(format);
+        if self.features.iter().any(|f| f == "binpkg-signing") {
+            builder = builder.with_signer(Box::new(crate::bintree::GpgSigner {
+                key: self.env_vars.get("BINPKG_GPG_SIGNING_KEY").cloned(),
+                homedir: self.env_vars.get("BINPKG_GPG_SIGNING_GPG_HOME").cloned(),
+            }));
         }
+        builder.create(ebuild, &self.destdir, &self.use_flags, &extra).await?;
+        Ok(())
+    }
+}
 
-        // Create XPAK data
-        let xpak_bytes = crate::xpak::xpak_mem(&xpak_data);
+/// Set up build logging for a package
+fn setup_build_logging(ebuild: &Ebuild, build_env: &BuildEnv) -> Result<(PathBuf, std::fs::File), InvalidData> {
+    use std::fs;
 
-        // Append XPAK data to the .tbz2 file
-        use std::fs::OpenOptions;
-        use std::io::Write;
+    let log_path = build_log_path(ebuild, build_env, chrono::Local::now());
+    if let Some(log_dir) = log_path.parent() {
+        fs::create_dir_all(log_dir)
+            .map_err(|e| InvalidData::new(&format!("Failed to create log directory: {}", e), None))?;
+    }
 
-        let mut file = OpenOptions::new()
-            .append(true)
-            .open(&tbz2_path)
-            .map_err(|e| InvalidData::new(&format!("Failed to open tbz2 file for appending: {}", e), None))?;
+    let log_file = fs::File::create(&log_path)
+        .map_err(|e| InvalidData::new(&format!("Failed to create log file {}: {}", log_path.display(), e), None))?;
 
-        file.write_all(&xpak_bytes)
-            .map_err(|e| InvalidData::new(&format!("Failed to append XPAK data: {}", e), None))?;
+    println!("Build log: {}", log_path.display());
+    Ok((log_path, log_file))
+}
 
-        println!("Created binary package: {}", tbz2_path);
-        Ok(())
+/// PORTAGE_LOGDIR/category:pf:timestamp.log as portage names it, else T/build.log
+pub fn build_log_path(ebuild: &Ebuild, build_env: &BuildEnv, time: chrono::DateTime<chrono::Local>) -> PathBuf {
+    match build_env.env_vars.get("PORTAGE_LOGDIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Path::new(dir).join(format!(
+            "{}:{}-{}:{}.log",
+            ebuild.category,
+            ebuild.package,
+            ebuild.version,
+            time.format("%Y%m%d-%H%M%S"),
+        )),
+        None => build_env.workdir.join("temp/build.log"),
     }
+}
 
-    /// Switch to portage user if running as root
-    fn switch_to_build_user(&self) -> Result<(), InvalidData> {
-        match &self.user_privilege {
-            BuildUser::Root => {
-                // Already running as root, nothing to do
-                Ok(())
-            }
-            BuildUser::Portage { uid, gid } => {
-                // Check if we're running as root
-                if !unistd::Uid::effective().is_root() {
-                    return Ok(());
-                }
+/// gzip a finished build log (FEATURES=compress-build-logs), returning the new path
+pub fn compress_build_log(log_path: &Path) -> Result<PathBuf, InvalidData> {
+    use std::io::Write;
+
+    let data = fs::read(log_path)
+        .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", log_path.display(), e), None))?;
+    let compressed_path = PathBuf::from(format!("{}.gz", log_path.display()));
+    let file = fs::File::create(&compressed_path)
+        .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", compressed_path.display(), e), None))?;
+    let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    encoder.write_all(&data)
+        .and_then(|_| encoder.finish().map(|_| ()))
+        .map_err(|e| InvalidData::new(&format!("Failed to compress {}: {}", log_path.display(), e), None))?;
+    fs::remove_file(log_path)
+        .map_err(|e| InvalidData::new(&format!("Failed to remove {}: {}", log_path.display(), e), None))?;
+    Ok(compressed_path)
+}
 
-                println!("Switching to portage user for build (uid: {}, gid: {})...", uid, gid);
+/// tar's decompression flag for a source archive
+fn tar_flag(filename: &str) -> Option<&'static str> {
+    if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+        Some("-xzf")
+    } else if filename.ends_with(".tar.bz2") || filename.ends_with(".tbz2") {
+        Some("-xjf")
+    } else if filename.ends_with(".tar.xz") || filename.ends_with(".txz") {
+        Some("-xJf")
+    } else {
+        None
+    }
+}
 
-                // Switch to portage user
-                if let Err(e) = unistd::setgid(unistd::Gid::from_raw(*gid)) {
-                    eprintln!("Warning: Failed to setgid to portage group: {}, continuing as root", e);
-                    return Ok(());
-                }
-                if let Err(e) = unistd::setuid(unistd::Uid::from_raw(*uid)) {
-                    eprintln!("Warning: Failed to setuid to portage user: {}, continuing as root", e);
-                    return Ok(());
-                }
-                println!("Switched to portage user");
-                Ok(())
-            }
-            BuildUser::Custom { uid, gid } => {
-                // Check if we're running as root
-                if !unistd::Uid::effective().is_root() {
-                    return Ok(());
-                }
+/// What to fetch for a SRC_URI entry; RESTRICT=mirror (or fetch) keeps it off GENTOO_MIRRORS,
+/// RESTRICT=primaryuri tries upstream first
+pub fn fetch_request(ebuild: &Ebuild, uri: &str) -> crate::fetch::FetchRequest {
+    crate::fetch::FetchRequest {
+        filename: ebuild.distfile_name(uri).to_string(),
+        uris: vec![uri.to_string()],
+        use_mirrors: !ebuild.metadata.restrict.iter().any(|r| r == "mirror" || r == "fetch"),
+        primaryuri: ebuild.metadata.restrict.iter().any(|r| r == "primaryuri"),
+        manifest: None,
+    }
+}
 
-                println!("Switching to custom user for build (uid: {}, gid: {})...", uid, gid);
+/// Default MAKEOPTS and NINJAOPTS for one of `parallel_builds` concurrent builds: the CPUs are
+/// split between them, and a --load-average limit is added to options that lack one
+pub fn apply_job_control(env: &mut HashMap<String, String>, parallel_builds: usize, load_average: Option<f64>) {
+    use crate::util::cpuinfo::{get_cpu_count, has_load_limit, makeopts_to_job_count};
 
-                // Switch to custom user
-                if let Err(e) = unistd::setgid(unistd::Gid::from_raw(*gid)) {
-                    eprintln!("Warning: Failed to setgid to custom group: {}, continuing as root", e);
-                    return Ok(());
-                }
-                if let Err(e) = unistd::setuid(unistd::Uid::from_raw(*uid)) {
-                    eprintln!("Warning: Failed to setuid to custom user: {}, continuing as root", e);
-                    return Ok(());
-                }
-                println!("Switched to custom user");
-                Ok(())
-            }
-        }
+    let makeopts = env.entry("MAKEOPTS".to_string()).or_insert_with(|| {
+        format!("-j{}", (get_cpu_count().unwrap_or(1) / parallel_builds.max(1)).max(1))
+    });
+    if let Some(limit) = load_average && !has_load_limit(makeopts) {
+        makeopts.push_str(&format!(" -l{}", limit));
     }
 
+    let jobs = makeopts_to_job_count(makeopts).unwrap_or(1);
+    let ninjaopts = env.entry("NINJAOPTS".to_string()).or_insert_with(|| format!("-j{}", jobs));
+    if let Some(limit) = load_average && !has_load_limit(ninjaopts) {
+        ninjaopts.push_str(&format!(" -l{}", limit));
+    }
+}
 
+/// Export the enabled flags as USE, and again per USE_EXPAND variable without their
+/// prefix (video_cards_amdgpu sets VIDEO_CARDS="amdgpu")
+pub fn export_use(env: &mut HashMap<String, String>, use_flags: &HashMap<String, bool>) {
+    let mut enabled: Vec<&str> = use_flags.iter().filter(|(_, on)| **on).map(|(flag, _)| flag.as_str()).collect();
+    enabled.sort();
+    env.insert("USE".to_string(), enabled.join(" "));
+
+    let use_expand: Vec<String> = env.get("USE_EXPAND").map(|v| v.split_whitespace().map(|s| s.to_string()).collect()).unwrap_or_default();
+    for var in &use_expand {
+        let values: Vec<&str> = enabled.iter()
+            .filter_map(|flag| crate::config::split_use_expand(flag, &use_expand).filter(|(v, _)| v == var).map(|(_, value)| value))
+            .collect();
+        env.insert(var.clone(), values.join(" "));
+    }
 }
 
-/// Set up build logging for a package
-fn setup_build_logging(ebuild: &Ebuild) -> Result<Option<std::fs::File>, InvalidData> {
-    use std::fs;
+/// DISTDIR from the environment, or the local test directory
+pub fn default_distdir() -> PathBuf {
+    std::env::var_os("DISTDIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./test-distfiles"))
+}
 
-    // Create log directory if it doesn't exist
-    let log_dir = Path::new("./var/log/portage");
-    fs::create_dir_all(log_dir)
-        .map_err(|e| InvalidData::new(&format!("Failed to create log directory: {}", e), None))?;
+/// Run a single pkg_* hook (pretend, prerm, postrm) outside of a build, with `portdir`
+/// as PORTDIR
+pub async fn run_pkg_phase(ebuild_path: &Path, portdir: &Path, phase: BuildPhase, use_flags: HashMap<String, bool>, features: Vec<String>, env: HashMap<String, String>) -> Result<(), InvalidData> {
+    let ebuild = Ebuild::from_path_with_use(ebuild_path, &use_flags)?;
+    let executor = EbuildExecutor::from_ebuild(ebuild_path)?;
+    if !phase.function_name().is_some_and(|name| executor.has_function(name)) {
+        return Ok(());
+    }
 
-    // Create log file
-    let log_path = log_dir.join(format!("{}.log", ebuild.cpv().replace('/', "_")));
-    let log_file = fs::File::create(&log_path)
-        .map_err(|e| InvalidData::new(&format!("Failed to create log file {}: {}", log_path.display(), e), None))?;
+    let distdir = default_distdir();
+    let mut build_env = BuildEnv::new(&ebuild, portdir, &distdir, use_flags, features, env);
+    build_env.executor = Some(executor);
+    let created = !build_env.workdir.exists();
+    fs::create_dir_all(&build_env.workdir)
+        .map_err(|e| InvalidData::new(&format!("Failed to create workdir: {}", e), None))?;
+    let result = build_env.execute_phase(&ebuild, phase).await;
+    if created {
+        let _ = build_env.clean();
+    }
+    result
+}
 
-    println!("Build log: {}", log_path.display());
-    Ok(Some(log_file))
+/// Run a pkg_* hook (prerm, postrm) of an installed package in the environment saved when
+/// it was built, as portage does, so that it sees the eclasses and variables of that build
+pub async fn run_saved_pkg_phase(environment: &Path, ebuild_path: &Path, portdir: &Path, phase: BuildPhase, features: Vec<String>, env: HashMap<String, String>) -> Result<(), InvalidData> {
+    let Some(name) = phase.function_name() else {
+        return Ok(());
+    };
+    let saved = fs::read_to_string(environment)
+        .map_err(|e| InvalidData::new(&format!("Failed to read saved environment: {}", e), None))?;
+    if !crate::ebuild_exec::saved_function_defined(&saved, name) {
+        return Ok(());
+    }
+
+    let ebuild = Ebuild::from_path(ebuild_path)?;
+    let distdir = default_distdir();
+    let build_env = BuildEnv::new(&ebuild, portdir, &distdir, HashMap::new(), features, env);
+    let created = !build_env.workdir.exists();
+    fs::create_dir_all(&build_env.workdir)
+        .map_err(|e| InvalidData::new(&format!("Failed to create workdir: {}", e), None))?;
+    println!(">>> Running {} for {}", name, ebuild.cpv());
+    let result = crate::ebuild_exec::execute_saved_function(environment, name, &build_env);
+    if created {
+        let _ = build_env.clean();
+    }
+    result
 }
 
 /// Main doebuild function to build a package from ebuild
-pub async fn doebuild(ebuild_path: &Path, phases: &[BuildPhase], use_flags: HashMap<String, bool>, features: Vec<String>) -> Result<BuildEnv, InvalidData> {
+pub async fn doebuild(ebuild_path: &Path, portdir: &Path, phases: &[BuildPhase], mut use_flags: HashMap<String, bool>, features: Vec<String>, env: HashMap<String, String>) -> Result<BuildEnv, InvalidData> {
+    // USE=test follows FEATURES=test, as in portage
+    use_flags.insert("test".to_string(), features.iter().any(|f| f == "test"));
     let ebuild = Ebuild::from_path_with_use(ebuild_path, &use_flags)?;
 
     println!("Building {} from {}", ebuild.cpv(), ebuild_path.display());
     println!("Ebuild metadata: {:?}", ebuild.metadata);
 
-    // Set up build logging
-    let mut log_file = setup_build_logging(&ebuild)?;
-
-    // Use test directories for now
-    let portdir = Path::new("./test-portage");
-    let distdir = Path::new("./test-distfiles");
+    let distdir = default_distdir();
 
-    let mut build_env = BuildEnv::new(&ebuild, portdir, distdir, use_flags, features);
+    let mut build_env = BuildEnv::new(&ebuild, portdir, &distdir, use_flags, features, env);
     println!("Build environment workdir: {}", build_env.workdir.display());
     println!("Build environment sourcedir: {}", build_env.sourcedir.display());
 
     // Create ebuild executor
     build_env.executor = Some(EbuildExecutor::from_ebuild(&ebuild.path)?);
+    if build_env.is_interactive() && !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        return Err(InvalidData::new(&format!("{} is interactive and needs a controlling terminal", ebuild.cpv()), None));
+    }
 
     build_env.setup()?;
+    // An interrupted build leaves nothing worth resuming
+    let _workdir = crate::signals::RemoveOnInterrupt::register(&build_env.workdir);
+
+    // Set up build logging; phase output is teed into the log as well
+    let (log_path, mut log_file) = setup_build_logging(&ebuild, &build_env)?;
+    build_env.log_path = Some(log_path.clone());
 
     // Log build start
-    if let Some(ref mut log_file) = log_file {
+    {
         use std::io::Write;
         let _ = writeln!(log_file, ">>> Build started for {} at {}", ebuild.cpv(), chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"));
     }
 
     for &phase in phases {
+        if crate::signals::interrupted() {
+            return Err(InvalidData::new(&format!("Build of {} interrupted", ebuild.cpv()), None));
+        }
         println!("Executing phase: {:?}", phase);
+        let started = std::time::Instant::now();
 
         // Log phase start
-        if let Some(ref mut log_file) = log_file {
+        {
             use std::io::Write;
             let _ = writeln!(log_file, ">>> Executing phase: {:?} at {}", phase, chrono::Utc::now().format("%H:%M:%S"));
         }
 
-        build_env.execute_phase(&ebuild, phase).await?;
+        if let Err(e) = build_env.execute_phase(&ebuild, phase).await {
+            eprintln!("!!! ERROR: {} failed ({} phase):", ebuild.cpv(), phase.name());
+            eprintln!("!!!   {}", e);
+            eprintln!("!!! The complete build log is located at '{}'.", log_path.display());
+            return Err(e);
+        }
+        build_env.mark_phase_completed(phase)?;
 
         // Log phase completion
-        if let Some(ref mut log_file) = log_file {
+        let took = crate::progress::format_duration(started.elapsed());
+        println!(">>> Phase {} of {} took {}", phase.name(), ebuild.cpv(), took);
+        {
             use std::io::Write;
-            let _ = writeln!(log_file, ">>> Phase {:?} completed successfully", phase);
+            let _ = writeln!(log_file, ">>> Phase {:?} completed successfully in {}", phase, took);
         }
     }
 
     // Log build completion
-    if let Some(ref mut log_file) = log_file {
+    {
         use std::io::Write;
         let _ = writeln!(log_file, ">>> Build completed successfully for {} at {}", ebuild.cpv(), chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"));
     }
+    drop(log_file);
+
+    if build_env.features.iter().any(|f| f == "compress-build-logs") {
+        match compress_build_log(&log_path) {
+            Ok(compressed) => build_env.log_path = Some(compressed),
+            Err(e) => eprintln!("Warning: {}", e),
+        }
+    }
 
     println!("Build completed successfully for {}", ebuild.cpv());
     Ok(build_env)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ebuild_with_restrict(restrict: &str) -> Ebuild {
+        let content = format!("EAPI=8\nSRC_URI=(\"https://example.org/foo-1.0.tar.gz\")\nRESTRICT=\"{}\"\n", restrict);
+        Ebuild {
+            path: PathBuf::from("foo-1.0.ebuild"),
+            category: "app-misc".to_string(),
+            package: "foo".to_string(),
+            version: "1.0".to_string(),
+            metadata: Ebuild::parse_metadata(&content).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_fetch_request_honors_restrict_mirror() {
+        let ebuild = ebuild_with_restrict("");
+        let uri = &ebuild.metadata.src_uri[0];
+        let request = fetch_request(&ebuild, uri);
+        assert_eq!(request.filename, "foo-1.0.tar.gz");
+        assert!(request.use_mirrors);
+
+        let restricted = ebuild_with_restrict("mirror strip");
+        assert_eq!(restricted.metadata.restrict, vec!["mirror", "strip"]);
+        assert!(!fetch_request(&restricted, uri).use_mirrors);
+        assert!(!request.primaryuri);
+        assert!(fetch_request(&ebuild_with_restrict("primaryuri"), uri).primaryuri);
+    }
+
+    #[test]
+    fn test_conditional_src_uri() {
+        let content = "EAPI=8\nSRC_URI=\"https://example.org/foo-1.0.tar.gz\n\tdoc? ( https://example.org/foo-doc-1.0.tar.gz )\n\t!doc? ( https://example.org/foo-man-1.0.tar.gz -> foo-man.tar.gz )\"\n";
+        let metadata = Ebuild::parse_metadata(content).unwrap();
+        assert_eq!(metadata.src_uri, vec!["https://example.org/foo-1.0.tar.gz", "https://example.org/foo-man-1.0.tar.gz"]);
+        let doc = HashMap::from([("doc".to_string(), true)]);
+        let metadata = Ebuild::parse_metadata_with_use(content, &doc).unwrap();
+        assert_eq!(metadata.src_uri, vec!["https://example.org/foo-1.0.tar.gz", "https://example.org/foo-doc-1.0.tar.gz"]);
+        assert_eq!(Ebuild::all_src_uris(content).len(), 3);
+
+        // Renames hold whatever the USE flags, so --fetch-all-uri saves under them too
+        let ebuild = Ebuild {
+            path: PathBuf::from("foo-1.0.ebuild"),
+            category: "app-misc".to_string(),
+            package: "foo".to_string(),
+            version: "1.0".to_string(),
+            metadata,
+        };
+        assert_eq!(ebuild.distfile_name("https://example.org/foo-man-1.0.tar.gz"), "foo-man.tar.gz");
+        assert_eq!(fetch_request(&ebuild, "https://example.org/foo-man-1.0.tar.gz").filename, "foo-man.tar.gz");
+        assert_eq!(ebuild.distfile_name("https://example.org/foo-doc-1.0.tar.gz"), "foo-doc-1.0.tar.gz");
+    }
+
+    #[test]
+    fn test_build_env_overrides() {
+        let ebuild = ebuild_with_restrict("");
+        let env = HashMap::from([
+            ("MAKEOPTS".to_string(), "-j1 -l2".to_string()),
+            ("WORKDIR".to_string(), "/elsewhere".to_string()),
+        ]);
+        let build_env = BuildEnv::new(&ebuild, Path::new("."), Path::new("."), HashMap::new(), vec![], env);
+        assert_eq!(build_env.makeopts(), vec!["-j1", "-l2"]);
+        assert_eq!(build_env.env_vars["WORKDIR"], build_env.workdir.to_string_lossy());
+        assert!(!build_env.sandbox_enabled);
+
+        let build_env = BuildEnv::new(&ebuild, Path::new("."), Path::new("."), HashMap::new(), vec!["sandbox".to_string()], HashMap::new());
+        assert_eq!(build_env.env_vars["SANDBOX_ON"], "1");
+
+        let env = HashMap::from([
+            ("USE_EXPAND".to_string(), "VIDEO_CARDS PYTHON_TARGETS".to_string()),
+            ("VIDEO_CARDS".to_string(), "amdgpu nvidia".to_string()),
+        ]);
+        let use_flags = HashMap::from([
+            ("ssl".to_string(), true),
+            ("video_cards_amdgpu".to_string(), true),
+            ("video_cards_nvidia".to_string(), false),
+            ("doc".to_string(), false),
+        ]);
+        let build_env = BuildEnv::new(&ebuild, Path::new("."), Path::new("."), use_flags, vec![], env);
+        assert_eq!(build_env.env_vars["USE"], "ssl video_cards_amdgpu");
+        assert_eq!(build_env.env_vars["VIDEO_CARDS"], "amdgpu");
+        assert_eq!(build_env.env_vars["PYTHON_TARGETS"], "");
+
+        let env = HashMap::from([
+            ("CHOST".to_string(), "aarch64-unknown-linux-gnu".to_string()),
+            ("CBUILD".to_string(), "x86_64-pc-linux-gnu".to_string()),
+        ]);
+        let build_env = BuildEnv::new(&ebuild, Path::new("."), Path::new("."), HashMap::new(), vec![], env);
+        assert_eq!(build_env.env_vars["CC"], "aarch64-unknown-linux-gnu-gcc");
+        let env = HashMap::from([("CHOST".to_string(), "x86_64-pc-linux-gnu".to_string())]);
+        let build_env = BuildEnv::new(&ebuild, Path::new("."), Path::new("."), HashMap::new(), vec![], env);
+        assert_eq!(build_env.env_vars["CBUILD"], "x86_64-pc-linux-gnu");
+        assert!(!build_env.env_vars.contains_key("CC"));
+    }
+
+    #[test]
+    fn test_network_sandbox_phases() {
+        let mut build_env = BuildEnv::new(&ebuild_with_restrict(""), Path::new("."), Path::new("."), HashMap::new(), vec!["network-sandbox".to_string()], HashMap::new());
+        assert!(!build_env.phase_networked("src_unpack"));
+        assert!(!build_env.sandbox_enabled);
+
+        build_env.env_vars.insert("PROPERTIES".to_string(), "live test_network".to_string());
+        assert!(build_env.phase_networked("src_unpack"));
+        assert!(!build_env.phase_networked("src_compile"));
+        assert!(!build_env.phase_networked("src_test"));
+        build_env.env_vars.insert("ALLOW_TEST".to_string(), "network".to_string());
+        assert!(build_env.phase_networked("src_test"));
+
+        // A network-only sandbox leaves the filesystem alone
+        let sandbox = crate::sandbox::Sandbox::from_features(&build_env.features, vec![]).unwrap();
+        assert!(sandbox.network && !sandbox.filesystem);
+    }
+
+    #[test]
+    fn test_eclass_properties() {
+        // git-r3 adds PROPERTIES=live; the ebuild's own USE-conditional ones are reduced
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("foo-9999.ebuild");
+        fs::write(&path, "EAPI=8\ninherit git-r3\nPROPERTIES=\"debug? ( interactive )\"\n").unwrap();
+        let use_flags = HashMap::from([("debug".to_string(), true)]);
+        let mut build_env = BuildEnv::new(&ebuild_with_restrict(""), Path::new("."), Path::new("."), use_flags, vec![], HashMap::new());
+        assert!(!build_env.is_live() && !build_env.is_interactive());
+        build_env.executor = Some(EbuildExecutor::from_ebuild(&path).unwrap());
+        assert!(build_env.is_live() && build_env.is_interactive());
+        build_env.use_flags.insert("debug".to_string(), false);
+        assert_eq!(build_env.properties(), vec!["live"]);
+    }
+
+    #[test]
+    fn test_build_user_only_for_src_phases() {
+        let mut build_env = BuildEnv::new(&ebuild_with_restrict(""), Path::new("."), Path::new("."), HashMap::new(), vec![], HashMap::new());
+        build_env.user_privilege = BuildUser::Custom { uid: 65534, gid: 65534 };
+        assert_eq!(build_env.build_user("pkg_setup"), None);
+
+        if !unistd::Uid::effective().is_root() {
+            assert_eq!(build_env.build_user("src_compile"), None);
+            return;
+        }
+        assert_eq!(build_env.build_user("src_compile"), Some((65534, 65534)));
+        let mut command = std::process::Command::new("id");
+        command.arg("-u");
+        build_env.confine("src_compile", &mut command);
+        assert_eq!(String::from_utf8_lossy(&command.output().unwrap().stdout).trim(), "65534");
+    }
+
+    #[test]
+    fn test_build_log() {
+        use chrono::TimeZone;
+        use std::io::Read;
+
+        let logdir = tempfile::TempDir::new().unwrap();
+        let ebuild = ebuild_with_restrict("");
+        let mut build_env = BuildEnv::new(&ebuild, Path::new("."), Path::new("."), HashMap::new(), vec![], HashMap::new());
+        let time = chrono::Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+        assert_eq!(build_log_path(&ebuild, &build_env, time), build_env.workdir.join("temp/build.log"));
+
+        build_env.env_vars.insert("PORTAGE_LOGDIR".to_string(), logdir.path().to_string_lossy().to_string());
+        let log_path = build_log_path(&ebuild, &build_env, time);
+        assert_eq!(log_path, logdir.path().join("app-misc:foo-1.0:20240501-123000.log"));
+
+        fs::write(&log_path, ">>> Build started\n").unwrap();
+        build_env.log_path = Some(log_path.clone());
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("echo compiling; echo oops >&2");
+        assert!(build_env.run_logged(&mut command).unwrap().success());
+        let logged = fs::read_to_string(&log_path).unwrap();
+        assert!(logged.starts_with(">>> Build started\n") && logged.contains("compiling\n") && logged.contains("oops\n"));
+
+        let compressed = compress_build_log(&log_path).unwrap();
+        assert_eq!(compressed, logdir.path().join("app-misc:foo-1.0:20240501-123000.log.gz"));
+        assert!(!log_path.exists());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(&compressed).unwrap()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, logged);
+    }
+
+    #[test]
+    fn test_apply_job_control() {
+        let cpus = crate::util::cpuinfo::get_cpu_count().unwrap_or(1);
+        let mut env = HashMap::new();
+        apply_job_control(&mut env, 2, Some(4.0));
+        assert_eq!(env["MAKEOPTS"], format!("-j{} -l4", (cpus / 2).max(1)));
+        assert_eq!(env["NINJAOPTS"], format!("-j{} -l4", (cpus / 2).max(1)));
+
+        // User settings are kept; only a missing load limit is added
+        let mut env = HashMap::from([("MAKEOPTS".to_string(), "-j3".to_string())]);
+        apply_job_control(&mut env, 8, Some(2.5));
+        assert_eq!(env["MAKEOPTS"], "-j3 -l2.5");
+        assert_eq!(env["NINJAOPTS"], "-j3 -l2.5");
+
+        let mut env = HashMap::from([("MAKEOPTS".to_string(), "-j2 -l1".to_string())]);
+        apply_job_control(&mut env, 1, Some(6.0));
+        assert_eq!(env["MAKEOPTS"], "-j2 -l1");
+    }
+}
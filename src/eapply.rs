@@ -0,0 +1,230 @@
+// eapply.rs -- applying patches natively in src_prepare: files or directories of them,
+// plain unified diffs or git-style ones, at the strip level that fits
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::exception::InvalidData;
+
+/// Strip levels probed in order: -p1 as eapply uses, then -p0 and -p2
+const LEVELS: [u32; 3] = [1, 0, 2];
+
+/// The kind of diff a patch file holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Unified,
+    /// diff --git output, which patch handles: renames, copies and modes
+    Git,
+    /// git diffs with binary files, which only git apply handles
+    GitBinary,
+}
+
+impl PatchFormat {
+    pub fn detect(content: &str) -> Self {
+        let mut git = false;
+        for line in content.lines() {
+            if line == "GIT binary patch" || (line.starts_with("Binary files ") && line.ends_with(" differ")) {
+                return PatchFormat::GitBinary;
+            }
+            git |= line.starts_with("diff --git ");
+        }
+        if git { PatchFormat::Git } else { PatchFormat::Unified }
+    }
+}
+
+/// The patch files named by `paths`: a directory stands for its *.diff and *.patch files
+/// in name order
+pub fn expand(paths: &[PathBuf]) -> Result<Vec<PathBuf>, InvalidData> {
+    let mut patches = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+                .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", path.display(), e), None))?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|file| file.is_file() && file.extension().is_some_and(|ext| ext == "patch" || ext == "diff"))
+                .collect();
+            if files.is_empty() {
+                return Err(InvalidData::new(&format!("No patches in {}", path.display()), None));
+            }
+            files.sort();
+            patches.extend(files);
+        } else if path.is_file() {
+            patches.push(path.clone());
+        } else {
+            return Err(InvalidData::new(&format!("Patch {} does not exist", path.display()), None));
+        }
+    }
+    Ok(patches)
+}
+
+fn command(format: PatchFormat, patch: &Path, dir: &Path, level: u32, dry_run: bool) -> Command {
+    let mut command;
+    if format == PatchFormat::GitBinary {
+        command = Command::new("git");
+        command.args(["apply", &format!("-p{}", level)]);
+        if dry_run {
+            command.arg("--check");
+        }
+        command.arg(patch);
+        // Relative to `dir`, not to a repository it may be inside of
+        command.env("GIT_CEILING_DIRECTORIES", dir);
+    } else {
+        command = Command::new("patch");
+        command.args(["-N", "-f", &format!("-p{}", level)]);
+        if dry_run {
+            command.arg("--dry-run");
+        }
+        command.arg("-i").arg(patch);
+    }
+    command.current_dir(dir);
+    command
+}
+
+fn run(mut command: Command) -> (bool, String) {
+    match command.output() {
+        Ok(output) => (output.status.success(),
+            format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr))),
+        Err(e) => (false, e.to_string()),
+    }
+}
+
+/// The first strip level at which `patch` applies cleanly in `dir`
+pub fn strip_level(patch: &Path, dir: &Path) -> Option<u32> {
+    let format = PatchFormat::detect(&String::from_utf8_lossy(&std::fs::read(patch).ok()?));
+    LEVELS.into_iter().find(|&level| run(command(format, patch, dir, level, true)).0)
+}
+
+/// Apply `patch` in `dir` at the strip level that fits, returning it. When none does, the
+/// error carries what patch said at -p1: the hunks that failed
+pub fn apply(patch: &Path, dir: &Path) -> Result<u32, InvalidData> {
+    let name = patch.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let content = std::fs::read(patch)
+        .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", patch.display(), e), None))?;
+    let format = PatchFormat::detect(&String::from_utf8_lossy(&content));
+    let Some(level) = LEVELS.into_iter().find(|&level| run(command(format, patch, dir, level, true)).0) else {
+        let (_, output) = run(command(format, patch, dir, LEVELS[0], true));
+        return Err(InvalidData::new(&format!("{} does not apply to {}:\n{}", name, dir.display(), output.trim_end()), None));
+    };
+    let (applied, output) = run(command(format, patch, dir, level, false));
+    if !applied {
+        return Err(InvalidData::new(&format!("Failed to apply {}:\n{}", name, output.trim_end()), None));
+    }
+    Ok(level)
+}
+
+/// Apply the patches and directories of them in order in `dir`, stopping at the first
+/// that fails
+pub fn eapply(paths: &[PathBuf], dir: &Path) -> Result<(), InvalidData> {
+    for patch in expand(paths)? {
+        println!(" * Applying {} ...", patch.file_name().unwrap_or_default().to_string_lossy());
+        apply(&patch, dir)?;
+    }
+    Ok(())
+}
+
+/// The words of a PATCHES assignment, array or string, with ${VAR} and $VAR taken from
+/// `env`, relative paths resolved against `dir`
+pub fn patches_var(value: &str, env: &HashMap<String, String>, dir: &Path) -> Vec<PathBuf> {
+    let value = value.trim();
+    let value = value.strip_prefix('(').and_then(|v| v.strip_suffix(')')).unwrap_or(value);
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' if quote.is_none() => {
+                quote = Some(c);
+                in_word = true;
+            }
+            c if Some(c) == quote => quote = None,
+            '$' if quote != Some('\'') => {
+                let braced = chars.next_if_eq(&'{').is_some();
+                let mut name = String::new();
+                while let Some(&next) = chars.peek() {
+                    if braced && next == '}' {
+                        chars.next();
+                        break;
+                    }
+                    if !(braced || next.is_ascii_alphanumeric() || next == '_') {
+                        break;
+                    }
+                    name.push(next);
+                    chars.next();
+                }
+                word.push_str(env.get(&name).map(|value| value.as_str()).unwrap_or_default());
+                in_word = true;
+            }
+            c if c.is_whitespace() && quote.is_none() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words.into_iter().filter(|word| !word.is_empty()).map(|word| dir.join(word)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(PatchFormat::detect("--- a/x\n+++ b/x\n@@ -1 +1 @@\n-a\n+b\n"), PatchFormat::Unified);
+        assert_eq!(PatchFormat::detect("diff --git a/x b/y\nsimilarity index 100%\nrename from x\nrename to y\n"), PatchFormat::Git);
+        assert_eq!(PatchFormat::detect("diff --git a/logo.png b/logo.png\nindex 1..2 100644\nGIT binary patch\nliteral 4\n"), PatchFormat::GitBinary);
+    }
+
+    #[test]
+    fn test_apply_patches() {
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("hello.txt"), "hello\n").unwrap();
+        let patches = temp.path().join("files");
+        std::fs::create_dir_all(&patches).unwrap();
+        std::fs::write(patches.join("01-p1.patch"), "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-hello\n+hello, world\n").unwrap();
+        std::fs::write(patches.join("02-p0.diff"), "--- hello.txt\n+++ hello.txt\n@@ -1 +1 @@\n-hello, world\n+goodbye\n").unwrap();
+        std::fs::write(patches.join("03-p2.patch"), "--- x/a/hello.txt\n+++ x/b/hello.txt\n@@ -1 +1 @@\n-goodbye\n+farewell\n").unwrap();
+        std::fs::write(patches.join("notes.txt"), "not a patch").unwrap();
+
+        assert_eq!(strip_level(&patches.join("01-p1.patch"), &source), Some(1));
+        assert_eq!(strip_level(&patches.join("03-p2.patch"), &source), None);
+        eapply(std::slice::from_ref(&patches), &source).unwrap();
+        assert_eq!(std::fs::read_to_string(source.join("hello.txt")).unwrap(), "farewell\n");
+
+        // The failing hunks are reported, and nothing after the failing patch is applied
+        std::fs::write(temp.path().join("later.patch"), "--- a/new.txt\n+++ b/new.txt\n@@ -0,0 +1 @@\n+new\n").unwrap();
+        let error = eapply(&[patches.join("01-p1.patch"), temp.path().join("later.patch")], &source).unwrap_err();
+        assert!(error.value.contains("01-p1.patch does not apply"), "{}", error);
+        assert!(error.value.contains("Hunk #1 FAILED"), "{}", error);
+        assert!(!source.join("new.txt").exists());
+        assert!(eapply(&[temp.path().join("missing.patch")], &source).is_err());
+    }
+
+    #[test]
+    fn test_patches_var() {
+        let env = HashMap::from([("FILESDIR".to_string(), "/repo/app-misc/foo/files".to_string()), ("P".to_string(), "foo-1.0".to_string())]);
+        let dir = Path::new("/work/foo-1.0");
+        assert_eq!(patches_var("(\n\t\"${FILESDIR}\"/${P}-fix.patch\n\t'${FILESDIR}/literal'\n\tlocal.patch\n)", &env, dir), [
+            PathBuf::from("/repo/app-misc/foo/files/foo-1.0-fix.patch"),
+            PathBuf::from("/work/foo-1.0/${FILESDIR}/literal"),
+            PathBuf::from("/work/foo-1.0/local.patch"),
+        ]);
+        assert_eq!(patches_var("$FILESDIR/a.patch", &env, dir), [PathBuf::from("/repo/app-misc/foo/files/a.patch")]);
+        assert!(patches_var("()", &env, dir).is_empty());
+    }
+}
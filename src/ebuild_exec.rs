@@ -1,19 +1,84 @@
 // ebuild_exec.rs - Ebuild function execution engine
 
 use std::collections::HashMap;
-use std::process::{Command, Stdio};
+use std::process::Command;
 use std::fs;
 use std::path::{Path, PathBuf};
 use regex::Regex;
 use lazy_static::lazy_static;
 use crate::exception::InvalidData;
 use crate::doebuild::BuildEnv;
+use crate::econf::Econf;
+use crate::eclass::{repo_eclass_dir, Inheritance, ShellSource, INCREMENTAL_VARS};
 
 lazy_static! {
-    static ref FUNCTION_RE: Regex = Regex::new(r"^(?P<name>src_(?:unpack|prepare|configure|compile|test|install))\s*\(\)\s*\{(?P<body>.*?)\}$").unwrap();
+    static ref FUNCTION_RE: Regex = Regex::new(r"^(?P<name>(?:src_(?:unpack|prepare|configure|compile|test|install)|pkg_(?:pretend|setup|preinst|postinst|prerm|postrm)))\s*\(\)\s*\{(?P<body>.*?)\}$").unwrap();
     static ref HELPER_RE: Regex = Regex::new(r"\b(?P<helper>do(?:bin|ins|man|doc|lib|etc|initd|confd))\s+(?P<args>.*?)(?=\s|$|;)").unwrap();
 }
 
+/// Appended to every phase script: saves the variables and functions the phase leaves to
+/// $T/environment, without the ones bash sets itself, for the package database
+const SAVE_ENVIRONMENT: &str = r#"
+if [[ -n ${T} && -d ${T} ]]; then
+    for __var in $(compgen -v); do
+        case ${__var} in
+            BASH*|COMP_*|DIRSTACK|EPOCHREALTIME|EPOCHSECONDS|EUID|FUNCNAME|GROUPS|HISTCMD|LINENO|OLDPWD|OPTIND|PIPESTATUS|PPID|PWD|RANDOM|SECONDS|SHELLOPTS|SHLVL|SRANDOM|UID|_|__var) continue ;;
+        esac
+        declare -p "${__var}" 2>/dev/null || :
+    done > "${T}/environment"
+    unset __var
+    declare -f >> "${T}/environment"
+fi
+"#;
+
+/// Whether the saved environment `environment` defines the function `name`
+pub fn saved_function_defined(environment: &str, name: &str) -> bool {
+    let header = format!("{} ()", name);
+    environment.lines().any(|line| line.trim_end() == header)
+}
+
+/// Variables a saved environment does not override: where the package is being unmerged
+/// from and where temporary files go now
+const CURRENT_VARS: &[&str] = &["ROOT", "EROOT", "SYSROOT", "ESYSROOT", "BROOT", "T", "TMPDIR", "HOME"];
+
+/// Run the function `name` of an installed package in the environment saved at
+/// `environment` when it was built, over the settings of `build_env`
+pub fn execute_saved_function(environment: &Path, name: &str, build_env: &BuildEnv) -> Result<(), InvalidData> {
+    let mut script = String::from("#!/bin/bash\n");
+    for (key, value) in &build_env.env_vars {
+        script.push_str(&format!("export {}=\"{}\"\n", key, value));
+    }
+    script.push_str(&format!("source '{}'\nset -e\n\n", environment.display().to_string().replace('\'', "'\\''")));
+    for key in CURRENT_VARS {
+        if let Some(value) = build_env.env_vars.get(*key) {
+            script.push_str(&format!("export {}=\"{}\"\n", key, value));
+        }
+    }
+    let phase = name.strip_prefix("pkg_").unwrap_or(name);
+    script.push_str(&format!("export EBUILD_PHASE=\"{}\"\n{}\n", phase, name));
+    run_script(name, &script, build_env)
+}
+
+fn run_script(name: &str, script: &str, build_env: &BuildEnv) -> Result<(), InvalidData> {
+    // src_* phases are sandboxed and unprivileged per FEATURES, pkg_* ones act on ROOT
+    let mut command = Command::new("bash");
+    if name.starts_with("src_") {
+        build_env.confine(name, &mut command);
+    }
+    command
+        .arg("-c")
+        .arg(script)
+        .current_dir(&build_env.workdir);
+    let status = build_env.run_logged(&mut command)
+        .map_err(|e| InvalidData::new(&format!("Failed to execute {}: {}", name, e), None))?;
+
+    if !status.success() {
+        return Err(InvalidData::new(&format!("Function {} failed", name), None));
+    }
+
+    Ok(())
+}
+
 /// Represents a parsed ebuild function
 #[derive(Debug, Clone)]
 pub struct EbuildFunction {
@@ -23,108 +88,79 @@ pub struct EbuildFunction {
 
 /// Ebuild execution engine
 pub struct EbuildExecutor {
-    functions: HashMap<String, EbuildFunction>,
+    /// What the ebuild itself defines
+    source: ShellSource,
+    /// The eclasses it inherits
+    inheritance: Inheritance,
 }
 
 impl EbuildExecutor {
-    /// Create a new executor by parsing an ebuild file
+    /// Create a new executor by parsing an ebuild file and the eclasses it inherits from
+    /// its repository
     pub fn from_ebuild(ebuild_path: &Path) -> Result<Self, InvalidData> {
+        let eclass_dirs: Vec<PathBuf> = repo_eclass_dir(ebuild_path).into_iter().collect();
+        Self::from_ebuild_with_eclass_dirs(ebuild_path, &eclass_dirs)
+    }
+
+    /// Like `from_ebuild`, looking for eclasses in `eclass_dirs` (the repository's own and
+    /// those of its masters)
+    pub fn from_ebuild_with_eclass_dirs(ebuild_path: &Path, eclass_dirs: &[PathBuf]) -> Result<Self, InvalidData> {
         let content = fs::read_to_string(ebuild_path)
             .map_err(|e| InvalidData::new(&format!("Failed to read ebuild: {}", e), None))?;
 
-        let functions = Self::parse_functions(&content)?;
-        Ok(EbuildExecutor { functions })
-    }
-
-    /// Parse functions from ebuild content
-    fn parse_functions(content: &str) -> Result<HashMap<String, EbuildFunction>, InvalidData> {
-        let mut functions = HashMap::new();
-
-        // Simple function parsing - look for src_* functions
-        let lines: Vec<&str> = content.lines().collect();
-        let mut i = 0;
-
-        while i < lines.len() {
-            let line = lines[i].trim();
-
-            // Look for function start
-            if line.starts_with("src_") && line.contains("() {") {
-                let func_name = line.split("()").next().unwrap().trim();
-
-                // Find the matching closing brace
-                let mut brace_count = 0;
-                let mut func_body = String::new();
-                let mut found_start = false;
-
-                for j in i..lines.len() {
-                    let current_line = lines[j];
-
-                    for ch in current_line.chars() {
-                        if ch == '{' {
-                            brace_count += 1;
-                            found_start = true;
-                        } else if ch == '}' {
-                            brace_count -= 1;
-                        }
-                    }
-
-                    if found_start {
-                        func_body.push_str(current_line);
-                        func_body.push('\n');
-                    }
-
-                    if found_start && brace_count == 0 {
-                        // Remove the function declaration line and closing brace
-                        let body_lines: Vec<&str> = func_body.lines().collect();
-                        let body_content = body_lines[1..body_lines.len()-1].join("\n");
-
-                        functions.insert(func_name.to_string(), EbuildFunction {
-                            name: func_name.to_string(),
-                            body: body_content,
-                        });
-                        i = j;
-                        break;
-                    }
-                }
-            }
-            i += 1;
-        }
+        let source = ShellSource::parse(&content);
+        let inheritance = Inheritance::resolve(&source.inherits, eclass_dirs)?;
+        Ok(EbuildExecutor { source, inheritance })
+    }
 
-        Ok(functions)
+    /// The function that runs for phase `name`: the ebuild's own, else one an eclass exports
+    fn phase_function(&self, name: &str) -> Option<String> {
+        if self.source.functions.contains_key(name) {
+            return Some(name.to_string());
+        }
+        self.inheritance.exported_phase(name)
     }
 
     /// Check if a specific function exists
     pub fn has_function(&self, name: &str) -> bool {
-        self.functions.contains_key(name)
+        self.phase_function(name).is_some()
+    }
+
+    /// Whether the ebuild inherits `eclass`, directly or not
+    pub fn inherits(&self, eclass: &str) -> bool {
+        self.inheritance.inherits(eclass)
+    }
+
+    /// Every eclass the ebuild inherits, in sourcing order (INHERITED)
+    pub fn inherited(&self) -> Vec<&str> {
+        self.inheritance.eclasses.iter().map(|eclass| eclass.name.as_str()).collect()
+    }
+
+    /// Global variables of the ebuild merged with those of its eclasses
+    pub fn variables(&self) -> HashMap<String, String> {
+        self.inheritance.variables(&self.source)
     }
 
     /// Execute a specific ebuild function
     pub fn execute_function(&self, name: &str, build_env: &BuildEnv) -> Result<(), InvalidData> {
-        let function = self.functions.get(name)
+        let function = self.phase_function(name)
             .ok_or_else(|| InvalidData::new(&format!("Function {} not found", name), None))?;
 
         // Create a bash script with the function
-        let script = self.create_bash_script(&function.body, build_env)?;
-
-        // Execute the script
-        let output = Command::new("bash")
-            .arg("-c")
-            .arg(&script)
-            .current_dir(&build_env.workdir)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()
-            .map_err(|e| InvalidData::new(&format!("Failed to execute {}: {}", name, e), None))?;
-
-        if !output.status.success() {
-            return Err(InvalidData::new(&format!("Function {} failed", name), None));
-        }
+        let script = self.create_bash_script(name, &function, build_env)?;
+        run_script(name, &script, build_env)
+    }
 
-        Ok(())
+    /// Save the environment of the setup phase to $T/environment for an ebuild without
+    /// pkg_setup, whose script would otherwise save it
+    pub fn save_environment(&self, build_env: &BuildEnv) -> Result<(), InvalidData> {
+        let script = self.create_bash_script("pkg_setup", ":", build_env)?;
+        run_script("pkg_setup", &script, build_env)
     }
 
-    /// Create a bash script with proper environment setup
-    fn create_bash_script(&self, body: &str, build_env: &BuildEnv) -> Result<String, InvalidData> {
+    /// Create a bash script with proper environment setup that runs `function` for phase
+    /// `name`
+    fn create_bash_script(&self, name: &str, function: &str, build_env: &BuildEnv) -> Result<String, InvalidData> {
         let mut script = String::new();
 
         // Set up environment variables
@@ -135,15 +171,69 @@ impl EbuildExecutor {
         for (key, value) in &build_env.env_vars {
             script.push_str(&format!("export {}=\"{}\"\n", key, value));
         }
+        // Implementations the built-in python eclasses build for
+        if crate::python::ECLASSES.iter().any(|name| self.inheritance.inherits(name)) {
+            let single = self.inheritance.inherits("python-single-r1") || self.variables().contains_key("DISTUTILS_SINGLE_IMPL");
+            for (key, value) in crate::python::env_vars(&build_env.use_flags, single) {
+                script.push_str(&format!("export {}=\"{}\"\n", key, value));
+            }
+        }
+        // ABIs the built-in multilib eclasses build for
+        if crate::multilib::ECLASSES.iter().any(|name| self.inheritance.inherits(name)) {
+            for (key, value) in crate::multilib::env_vars(&build_env.use_flags, &build_env.env_vars) {
+                script.push_str(&format!("export {}=\"{}\"\n", key, value));
+            }
+        }
+        // Patches from /etc/portage/patches for eapply_user
+        if name == "src_prepare" {
+            let slot = self.variables().get("SLOT").cloned();
+            script.push_str(&crate::user_patches::bash_array(&crate::user_patches::for_build(&build_env.env_vars, slot.as_deref())));
+        }
+        // Phase name the elog helpers file their messages under
+        let phase = name.strip_prefix("src_").or_else(|| name.strip_prefix("pkg_")).unwrap_or(name);
+        script.push_str(&format!("export EBUILD_PHASE=\"{}\"\n", phase));
 
         // Add helper functions
         script.push_str("\n# Ebuild helper functions\n");
         script.push_str(&self.generate_helper_functions());
+        let eapi = self.variables().get("EAPI").cloned().unwrap_or_else(|| "0".to_string());
+        script.push_str(&Econf::new(&build_env.env_vars, &eapi).bash_function());
+
+        // Globals of the eclasses, then the ebuild's; a failing $(...) in one is not fatal
+        script.push_str("\n# Global scope\nset +e\n");
+        for eclass in &self.inheritance.eclasses {
+            script.push_str(&format!("ECLASS={}\n", eclass.name));
+            for statement in &eclass.source.globals {
+                script.push_str(statement);
+                script.push('\n');
+            }
+        }
+        script.push_str("unset ECLASS\n");
+        for statement in &self.source.globals {
+            script.push_str(statement);
+            script.push('\n');
+        }
+        let variables = self.variables();
+        for name in INCREMENTAL_VARS {
+            if let Some(value) = variables.get(*name) {
+                script.push_str(&format!("{}='{}'\n", name, value.replace('\'', "'\\''")));
+            }
+        }
+        script.push_str("set -e\n");
+
+        // Eclass functions, overridden by the ebuild's own
+        script.push_str("\n# Functions\n");
+        let functions = self.inheritance.eclasses.iter()
+            .flat_map(|eclass| eclass.source.functions.values())
+            .chain(self.source.functions.values());
+        for function in functions {
+            script.push_str(&format!("{}() {{\n{}\n}}\n", function.name, function.body));
+        }
 
-        // Add the function body
-        script.push_str("\n# Function body\n");
-        script.push_str(body);
-        script.push_str("\n");
+        // Run the phase
+        script.push_str("\n# Phase\n");
+        script.push_str(&format!("{}\n", function));
+        script.push_str(SAVE_ENVIRONMENT);
 
         Ok(script)
     }
@@ -152,67 +242,128 @@ impl EbuildExecutor {
     fn generate_helper_functions(&self) -> String {
         let mut helpers = String::new();
 
-        // dobin - install binary
-        helpers.push_str("dobin() {\n");
-        helpers.push_str("    for file in \"$@\"; do\n");
-        helpers.push_str("        if [ -f \"$file\" ]; then\n");
-        helpers.push_str("            install -D -m0755 \"$file\" \"$D/usr/bin/$(basename \"$file\")\"\n");
-        helpers.push_str("        else\n");
-        helpers.push_str("            echo \"dobin: $file not found\" >&2\n");
-        helpers.push_str("            return 1\n");
-        helpers.push_str("        fi\n");
+        // The install helpers: do*/new*, insinto and friends, dosym, keepdir, einstalldocs
+        helpers.push_str(&crate::install_helpers::bash_functions());
+
+        // dostrip - record paths to strip under RESTRICT=strip, or with -x to leave unstripped
+        helpers.push_str("dostrip() {\n");
+        helpers.push_str("    local list=\"${T}/.dostrip_include\"\n");
+        helpers.push_str("    if [ \"$1\" = \"-x\" ]; then\n");
+        helpers.push_str("        list=\"${T}/.dostrip_exclude\"\n");
+        helpers.push_str("        shift\n");
+        helpers.push_str("    fi\n");
+        helpers.push_str("    for path in \"$@\"; do\n");
+        helpers.push_str("        echo \"/${path#/}\" >> \"$list\"\n");
         helpers.push_str("    done\n");
         helpers.push_str("}\n\n");
 
-        // doins - install file
-        helpers.push_str("doins() {\n");
-        helpers.push_str("    for file in \"$@\"; do\n");
-        helpers.push_str("        if [ -f \"$file\" ]; then\n");
-        helpers.push_str("            install -D -m0644 \"$file\" \"$D/usr/share/$(basename \"$file\")\"\n");
-        helpers.push_str("        else\n");
-        helpers.push_str("            echo \"doins: $file not found\" >&2\n");
-        helpers.push_str("            return 1\n");
-        helpers.push_str("        fi\n");
+        // docompress - add paths to compress after src_install, or with -x to leave uncompressed
+        helpers.push_str("docompress() {\n");
+        helpers.push_str("    local list=\"${T}/.docompress_include\"\n");
+        helpers.push_str("    if [ \"$1\" = \"-x\" ]; then\n");
+        helpers.push_str("        list=\"${T}/.docompress_exclude\"\n");
+        helpers.push_str("        shift\n");
+        helpers.push_str("    fi\n");
+        helpers.push_str("    for path in \"$@\"; do\n");
+        helpers.push_str("        echo \"/${path#/}\" >> \"$list\"\n");
         helpers.push_str("    done\n");
         helpers.push_str("}\n\n");
 
-        // doman - install man page
-        helpers.push_str("doman() {\n");
-        helpers.push_str("    for file in \"$@\"; do\n");
-        helpers.push_str("        if [ -f \"$file\" ]; then\n");
-        helpers.push_str("            # Extract section from filename (e.g., man1, man8)\n");
-        helpers.push_str("            section=\"${file##*.}\"\n");
-        helpers.push_str("            install -D -m0644 \"$file\" \"$D/usr/share/man/man$section/$(basename \"$file\")\"\n");
-        helpers.push_str("        else\n");
-        helpers.push_str("            echo \"doman: $file not found\" >&2\n");
-        helpers.push_str("            return 1\n");
-        helpers.push_str("        fi\n");
-        helpers.push_str("    done\n");
+        // die/has - abort the phase, test list membership
+        helpers.push_str("die() {\n");
+        helpers.push_str("    echo \"ERROR: ${CATEGORY}/${PF:-${P}} failed: $*\" >&2\n");
+        helpers.push_str("    exit 1\n");
         helpers.push_str("}\n\n");
-
-        // dodoc - install documentation
-        helpers.push_str("dodoc() {\n");
-        helpers.push_str("    for file in \"$@\"; do\n");
-        helpers.push_str("        if [ -f \"$file\" ]; then\n");
-        helpers.push_str("            install -D -m0644 \"$file\" \"$D/usr/share/doc/${PF}/$(basename \"$file\")\"\n");
-        helpers.push_str("        else\n");
-        helpers.push_str("            echo \"dodoc: $file not found\" >&2\n");
-        helpers.push_str("            return 1\n");
-        helpers.push_str("        fi\n");
+        helpers.push_str("has() {\n");
+        helpers.push_str("    local needle=$1 x\n");
+        helpers.push_str("    shift\n");
+        helpers.push_str("    for x in \"$@\"; do\n");
+        helpers.push_str("        [ \"$x\" = \"$needle\" ] && return 0\n");
         helpers.push_str("    done\n");
+        helpers.push_str("    return 1\n");
         helpers.push_str("}\n\n");
 
-        // default - run default implementation
+        // eapply, eapply_user and default_src_prepare
+        helpers.push_str(crate::user_patches::BASH_FUNCTIONS);
+        helpers.push('\n');
+
+        // default - run the default implementation, where there is one
         helpers.push_str("default() {\n");
-        helpers.push_str("    # Default implementation - currently a no-op\n");
-        helpers.push_str("    true\n");
+        helpers.push_str("    if [ \"${EBUILD_PHASE}\" = \"prepare\" ]; then\n");
+        helpers.push_str("        default_src_prepare\n");
+        helpers.push_str("    fi\n");
         helpers.push_str("}\n\n");
 
+        // einfo/elog/ewarn/eerror/eqawarn - print and record in ${T}/logging for elog
+        helpers.push_str("__elog_message() {\n");
+        helpers.push_str("    local class=$1\n");
+        helpers.push_str("    shift\n");
+        helpers.push_str("    mkdir -p \"${T}/logging\" 2>/dev/null || return 0\n");
+        helpers.push_str("    printf '%s\\n' \"$*\" | while IFS= read -r line; do\n");
+        helpers.push_str("        echo \"${class} ${line}\" >> \"${T}/logging/${EBUILD_PHASE:-other}\"\n");
+        helpers.push_str("    done\n");
+        helpers.push_str("}\n\n");
+        for (helper, class) in [("einfo", "INFO"), ("elog", "LOG"), ("ewarn", "WARN"), ("eerror", "ERROR"), ("eqawarn", "QA")] {
+            helpers.push_str(&format!("{}() {{\n", helper));
+            helpers.push_str("    echo \" * $*\" >&2\n");
+            helpers.push_str(&format!("    __elog_message {} \"$@\"\n", class));
+            helpers.push_str("}\n\n");
+        }
+
         // emake - run make with proper flags
         helpers.push_str("emake() {\n");
-        helpers.push_str("    make \"$@\"\n");
+        helpers.push_str("    make ${MAKEOPTS} \"$@\"\n");
         helpers.push_str("}\n\n");
 
         helpers
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inherited_phases() {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir_all(temp.path().join("eclass")).unwrap();
+        fs::write(temp.path().join("eclass/cmake.eclass"),
+            "IUSE=\"ninja\"\ncmake_src_configure() {\n\tcmake .\n}\ncmake_src_install() { :; }\nEXPORT_FUNCTIONS src_configure src_install\n").unwrap();
+        let ebuild = temp.path().join("app-misc/foo/foo-1.0.ebuild");
+        fs::create_dir_all(ebuild.parent().unwrap()).unwrap();
+        fs::write(&ebuild, "EAPI=8\ninherit cmake\nIUSE=\"ssl\"\nsrc_install() {\n\tcmake_src_install\n}\n").unwrap();
+
+        let executor = EbuildExecutor::from_ebuild(&ebuild).unwrap();
+        assert_eq!(executor.phase_function("src_configure").as_deref(), Some("cmake_src_configure"));
+        assert_eq!(executor.phase_function("src_install").as_deref(), Some("src_install"));
+        assert!(!executor.has_function("src_compile"));
+        assert_eq!(executor.variables()["IUSE"], "ninja ssl");
+
+        fs::write(&ebuild, "EAPI=8\ninherit missing\n").unwrap();
+        assert!(EbuildExecutor::from_ebuild(&ebuild).is_err());
+    }
+
+    #[test]
+    fn test_saved_environment() {
+        let temp = TempDir::new().unwrap();
+        let script = format!("T='{}'\nMY_PN=\"foo \\\"quoted\\\"\"\npkg_prerm() {{\n\techo \"removing ${{MY_PN}}\"\n}}\n{}", temp.path().display(), SAVE_ENVIRONMENT);
+        assert!(Command::new("bash").arg("-c").arg(&script).status().unwrap().success());
+        let environment = fs::read_to_string(temp.path().join("environment")).unwrap();
+        assert!(saved_function_defined(&environment, "pkg_prerm"));
+        assert!(!saved_function_defined(&environment, "pkg_postrm"));
+        assert!(!environment.contains("PPID=") && !environment.contains("__var"));
+
+        // Compressed into the package database and read back
+        let dir = temp.path().join("var/db/pkg/app-misc/foo-1.0");
+        fs::create_dir_all(&dir).unwrap();
+        crate::vartree::compress_environment(&temp.path().join("environment"), &dir.join("environment.bz2")).unwrap();
+        let vartree = crate::vartree::VarTree::new(temp.path().to_str().unwrap());
+        let saved = vartree.environment("app-misc/foo-1.0").unwrap();
+        assert_eq!(saved, environment);
+        assert_eq!(crate::vartree::parse_environment(&saved)["MY_PN"], "foo \"quoted\"");
+
+        let output = Command::new("bash").arg("-c").arg(format!("source '{}'; pkg_prerm", temp.path().join("environment").display())).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "removing foo \"quoted\"\n");
+    }
+}
@@ -0,0 +1,338 @@
+// eclass.rs -- eclass inheritance for the phase executor: parse ebuild and eclass sources,
+// resolve `inherit` from the repository's eclass directories and merge what they define
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::ebuild_exec::EbuildFunction;
+use crate::exception::InvalidData;
+
+lazy_static! {
+    static ref FUNCTION_RE: Regex = Regex::new(r"^(?:function\s+)?(?P<name>[A-Za-z_][A-Za-z0-9_+-]*)\s*\(\)\s*\{").unwrap();
+    static ref ASSIGNMENT_RE: Regex = Regex::new(r"^(?:export\s+)?(?P<name>[A-Za-z_][A-Za-z0-9_]*)(?P<append>\+?)=(?P<value>.*)$").unwrap();
+}
+
+/// Variables whose eclass values are combined with the ebuild's rather than replaced
+pub const INCREMENTAL_VARS: &[&str] = &["IUSE", "REQUIRED_USE", "DEPEND", "RDEPEND", "PDEPEND", "BDEPEND", "IDEPEND", "PROPERTIES", "RESTRICT"];
+
+/// A global variable assignment, `NAME=value` or `NAME+=value`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub append: bool,
+    /// The value with its quotes removed
+    pub value: String,
+}
+
+/// What an ebuild or eclass defines at global scope
+#[derive(Debug, Clone, Default)]
+pub struct ShellSource {
+    /// Shell functions by name
+    pub functions: HashMap<String, EbuildFunction>,
+    /// Global statements as written: assignments and `: ${VAR:=default}` defaults
+    pub globals: Vec<String>,
+    pub assignments: Vec<Assignment>,
+    /// Eclasses named by `inherit`, in order
+    pub inherits: Vec<String>,
+    /// Phases named by EXPORT_FUNCTIONS
+    pub exports: Vec<String>,
+}
+
+impl ShellSource {
+    /// Scan bash source line by line; conditionals are looked through, so the guarded body
+    /// of an eclass counts as global scope
+    pub fn parse(content: &str) -> Self {
+        let mut source = ShellSource::default();
+        let lines: Vec<&str> = content.lines().collect();
+        let mut i = 0;
+        while i < lines.len() {
+            let line = lines[i].trim();
+            if line.is_empty() || line.starts_with('#') {
+                i += 1;
+                continue;
+            }
+
+            if let Some(caps) = FUNCTION_RE.captures(line) {
+                let name = caps["name"].to_string();
+                let (body, end) = function_body(&lines, i);
+                source.functions.insert(name.clone(), EbuildFunction { name, body });
+                i = end + 1;
+                continue;
+            }
+
+            let words: Vec<&str> = line.split(';').next().unwrap_or_default().split_whitespace().collect();
+            match words.first() {
+                Some(&"inherit") => source.inherits.extend(words[1..].iter().map(|w| w.to_string())),
+                Some(&"EXPORT_FUNCTIONS") => source.exports.extend(words[1..].iter().map(|w| w.to_string())),
+                Some(&":") if line.contains("${") => source.globals.push(line.to_string()),
+                _ => {
+                    if let Some(caps) = ASSIGNMENT_RE.captures(line) {
+                        // Quoted values and arrays may run over several lines
+                        let mut statement = line.to_string();
+                        while !statement_complete(&statement) && i + 1 < lines.len() {
+                            i += 1;
+                            statement.push('\n');
+                            statement.push_str(lines[i]);
+                        }
+                        let value = statement[statement.find('=').unwrap_or(0) + 1..].to_string();
+                        source.assignments.push(Assignment {
+                            name: caps["name"].to_string(),
+                            append: !caps["append"].is_empty(),
+                            value: unquote(&value),
+                        });
+                        source.globals.push(statement);
+                    }
+                }
+            }
+            i += 1;
+        }
+        source
+    }
+
+    /// Final value of `name` after this source's own assignments
+    pub fn value(&self, name: &str) -> Option<String> {
+        let mut value: Option<String> = None;
+        for assignment in self.assignments.iter().filter(|a| a.name == name) {
+            value = Some(match value {
+                Some(previous) if assignment.append => format!("{}{}", previous, assignment.value),
+                _ => assignment.value.clone(),
+            });
+        }
+        value
+    }
+}
+
+/// The body of the function starting at `lines[start]` and the line its closing brace is on
+fn function_body(lines: &[&str], start: usize) -> (String, usize) {
+    let mut depth = 0;
+    let mut body = Vec::new();
+    for (offset, line) in lines[start..].iter().enumerate() {
+        for ch in line.chars() {
+            match ch {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        body.push(*line);
+        if depth <= 0 {
+            let end = start + offset;
+            if body.len() == 1 {
+                // One-liner: foo() { ...; }
+                let inner = line.find('{').map(|open| &line[open + 1..line.rfind('}').unwrap_or(line.len())]).unwrap_or_default();
+                return (inner.trim().to_string(), end);
+            }
+            return (body[1..body.len() - 1].join("\n"), end);
+        }
+    }
+    (body.get(1..).map(|rest| rest.join("\n")).unwrap_or_default(), lines.len() - 1)
+}
+
+/// Whether the quotes and parentheses of an assignment are all closed
+fn statement_complete(statement: &str) -> bool {
+    let (mut double, mut single, mut escaped, mut parens) = (false, false, false, 0);
+    for ch in statement.chars() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if !single => escaped = true,
+            '"' if !single => double = !double,
+            '\'' if !double => single = !single,
+            '(' if !double && !single => parens += 1,
+            ')' if !double && !single => parens -= 1,
+            _ => {}
+        }
+    }
+    !double && !single && parens <= 0
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return value[1..value.len() - 1].to_string();
+        }
+    }
+    value.to_string()
+}
+
+/// A parsed eclass
+#[derive(Debug, Clone)]
+pub struct Eclass {
+    pub name: String,
+    /// None for one built into the executor
+    pub path: Option<PathBuf>,
+    pub source: ShellSource,
+}
+
+/// The eclasses an ebuild inherits, directly or through other eclasses, in the order they
+/// are sourced
+#[derive(Debug, Clone, Default)]
+pub struct Inheritance {
+    pub eclasses: Vec<Eclass>,
+}
+
+impl Inheritance {
+    /// Resolve `inherits` from `eclass_dirs`, searched in order; an eclass is sourced after
+    /// the ones it inherits and only once
+    pub fn resolve(inherits: &[String], eclass_dirs: &[PathBuf]) -> Result<Self, InvalidData> {
+        let mut inheritance = Inheritance::default();
+        for name in inherits {
+            inheritance.load(name, eclass_dirs, &mut Vec::new())?;
+        }
+        Ok(inheritance)
+    }
+
+    fn load(&mut self, name: &str, eclass_dirs: &[PathBuf], stack: &mut Vec<String>) -> Result<(), InvalidData> {
+        if self.eclasses.iter().any(|e| e.name == name) || stack.iter().any(|e| e == name) {
+            return Ok(());
+        }
+        let (path, source) = match builtin_source(name) {
+            Some(content) => (None, ShellSource::parse(content)),
+            None => {
+                let path = find_eclass(eclass_dirs, name)
+                    .ok_or_else(|| InvalidData::new(&format!("{}.eclass could not be found by inherit", name), None))?;
+                let content = fs::read_to_string(&path)
+                    .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", path.display(), e), None))?;
+                (Some(path), ShellSource::parse(&content))
+            }
+        };
+        stack.push(name.to_string());
+        for inherited in &source.inherits {
+            self.load(inherited, eclass_dirs, stack)?;
+        }
+        stack.pop();
+        self.eclasses.push(Eclass { name: name.to_string(), path, source });
+        Ok(())
+    }
+
+    /// Whether `name` is inherited, directly or not
+    pub fn inherits(&self, name: &str) -> bool {
+        self.eclasses.iter().any(|eclass| eclass.name == name)
+    }
+
+    /// The function a phase runs when the ebuild does not define it: the last eclass to
+    /// EXPORT_FUNCTIONS the phase wins, as in ebuild.sh
+    pub fn exported_phase(&self, phase: &str) -> Option<String> {
+        self.eclasses.iter().rev()
+            .find(|eclass| eclass.source.exports.iter().any(|p| p == phase))
+            .map(|eclass| format!("{}_{}", eclass.name, phase))
+    }
+
+    /// Global variables after the eclasses and then `ebuild` are sourced, with the
+    /// incremental ones accumulated across all of them
+    pub fn variables(&self, ebuild: &ShellSource) -> HashMap<String, String> {
+        let sources: Vec<&ShellSource> = self.eclasses.iter().map(|e| &e.source).chain([ebuild]).collect();
+        let mut variables = HashMap::new();
+        for source in &sources {
+            for assignment in &source.assignments {
+                let value = source.value(&assignment.name).unwrap_or_default();
+                variables.insert(assignment.name.clone(), value);
+            }
+        }
+        for name in INCREMENTAL_VARS {
+            let values: Vec<String> = sources.iter().filter_map(|source| source.value(name)).collect();
+            if !values.is_empty() {
+                variables.insert(name.to_string(), values.join(" ").split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+        }
+        variables
+    }
+}
+
+/// Eclasses the executor implements itself, used instead of the repository's
+fn builtin_source(name: &str) -> Option<&'static str> {
+    match name {
+        "autotools" => Some(crate::autotools::AUTOTOOLS_ECLASS),
+        "libtool" => Some(crate::autotools::LIBTOOL_ECLASS),
+        "cargo" => Some(crate::cargo::ECLASS),
+        "go-module" => Some(crate::go_module::ECLASS),
+        "git-r3" => Some(crate::git_r3::ECLASS),
+        _ => crate::python::eclass_source(name).or_else(|| crate::multilib::eclass_source(name)),
+    }
+}
+
+/// `<name>.eclass` from the first directory that has it
+pub fn find_eclass(eclass_dirs: &[PathBuf], name: &str) -> Option<PathBuf> {
+    eclass_dirs.iter()
+        .map(|dir| dir.join(format!("{}.eclass", name)))
+        .find(|path| path.is_file())
+}
+
+/// The eclass directory of the repository an ebuild belongs to
+pub fn repo_eclass_dir(ebuild_path: &Path) -> Option<PathBuf> {
+    Some(ebuild_path.parent()?.parent()?.parent()?.join("eclass"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_source() {
+        let source = ShellSource::parse(r#"
+# Copyright
+if [[ -z ${_FOO_ECLASS} ]]; then
+_FOO_ECLASS=1
+inherit bar baz
+: ${FOO_TYPE:=release}
+IUSE="doc"
+DEPEND="dev-libs/a
+	dev-libs/b"
+IUSE+=" test"
+foo_src_configure() {
+	local x=1
+	if true; then
+		echo "${x}"
+	fi
+}
+foo_helper() { echo hi; }
+EXPORT_FUNCTIONS src_configure
+fi
+"#);
+        assert_eq!(source.inherits, vec!["bar", "baz"]);
+        assert_eq!(source.exports, vec!["src_configure"]);
+        assert_eq!(source.functions["foo_src_configure"].body, "\tlocal x=1\n\tif true; then\n\t\techo \"${x}\"\n\tfi");
+        assert_eq!(source.functions["foo_helper"].body, "echo hi;");
+        assert_eq!(source.value("IUSE").unwrap(), "doc test");
+        assert_eq!(source.value("DEPEND").unwrap(), "dev-libs/a\n\tdev-libs/b");
+        assert_eq!(source.globals.len(), 5);
+        assert_eq!(source.globals[1], ": ${FOO_TYPE:=release}");
+    }
+
+    #[test]
+    fn test_resolve_inheritance() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("eclass");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.eclass"), "IUSE=\"doc\"\nbase_src_install() { :; }\nEXPORT_FUNCTIONS src_install src_configure\n").unwrap();
+        fs::write(dir.join("cmake.eclass"), "inherit base\nIUSE=\"ninja\"\nBDEPEND=\"dev-build/cmake\"\ncmake_src_configure() { :; }\nEXPORT_FUNCTIONS src_configure\n").unwrap();
+
+        let inheritance = Inheritance::resolve(&["cmake".to_string(), "base".to_string()], &[dir.clone()]).unwrap();
+        let names: Vec<&str> = inheritance.eclasses.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["base", "cmake"]);
+        assert_eq!(inheritance.exported_phase("src_configure").as_deref(), Some("cmake_src_configure"));
+        assert_eq!(inheritance.exported_phase("src_install").as_deref(), Some("base_src_install"));
+        assert_eq!(inheritance.exported_phase("src_compile"), None);
+
+        let ebuild = ShellSource::parse("EAPI=8\ninherit cmake\nIUSE=\"+ssl\"\nSLOT=\"0\"\n");
+        let variables = inheritance.variables(&ebuild);
+        assert_eq!(variables["IUSE"], "doc ninja +ssl");
+        assert_eq!(variables["BDEPEND"], "dev-build/cmake");
+        assert_eq!(variables["SLOT"], "0");
+
+        assert!(Inheritance::resolve(&["missing".to_string()], &[dir.clone()]).is_err());
+
+        // The executor's own python eclasses win over the repository's
+        fs::write(dir.join("distutils-r1.eclass"), "distutils-r1_src_compile() { false; }\n").unwrap();
+        let inheritance = Inheritance::resolve(&["distutils-r1".to_string()], &[dir]).unwrap();
+        assert!(inheritance.inherits("python-utils-r1"));
+        assert!(inheritance.eclasses.iter().all(|e| e.path.is_none()));
+        assert_eq!(inheritance.exported_phase("src_compile").as_deref(), Some("distutils-r1_src_compile"));
+        assert!(inheritance.eclasses[1].source.functions["distutils-r1_src_compile"].body.contains("_distutils_foreach compile"));
+    }
+}
@@ -0,0 +1,284 @@
+// ecompress.rs -- Compress man pages, info pages and docs in the image (PORTAGE_COMPRESS)
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use crate::exception::InvalidData;
+
+/// Suffixes of files that are left alone because they are already compressed
+const COMPRESSED_SUFFIXES: &[&str] = &[
+    ".Z", ".gz", ".bz2", ".lzma", ".lz", ".lzo", ".lz4", ".xz", ".zst",
+    ".tgz", ".tbz2", ".txz", ".7z", ".zip", ".jpg", ".jpeg", ".png", ".gif",
+];
+
+/// Suffix a PORTAGE_COMPRESS program gives its output
+pub fn compress_suffix(program: &str) -> Option<&'static str> {
+    match Path::new(program).file_name()?.to_str()? {
+        "gzip" | "pigz" => Some(".gz"),
+        "bzip2" | "pbzip2" | "lbzip2" => Some(".bz2"),
+        "xz" | "pxz" => Some(".xz"),
+        "lzma" => Some(".lzma"),
+        "zstd" | "pzstd" => Some(".zst"),
+        "lz4" => Some(".lz4"),
+        "lzip" | "plzip" => Some(".lz"),
+        "lzop" => Some(".lzo"),
+        _ => None,
+    }
+}
+
+/// What gets compressed and how
+#[derive(Debug, Clone)]
+pub struct CompressSettings {
+    pub program: String,
+    pub flags: Vec<String>,
+    pub suffix: String,
+    /// Installed paths compressed (the EAPI defaults plus docompress)
+    pub include: Vec<String>,
+    /// Installed paths left alone (docompress -x)
+    pub exclude: Vec<String>,
+    /// Files smaller than this aren't worth compressing (PORTAGE_DOCOMPRESS_SIZE_LIMIT)
+    pub size_limit: u64,
+}
+
+impl CompressSettings {
+    /// Settings from the build environment, None with an empty PORTAGE_COMPRESS; `tempdir`
+    /// holds the docompress lists
+    pub fn from_env(env: &HashMap<String, String>, tempdir: &Path, pf: &str) -> Result<Option<Self>, InvalidData> {
+        let program = env.get("PORTAGE_COMPRESS").map(|p| p.trim()).unwrap_or("bzip2");
+        if program.is_empty() {
+            return Ok(None);
+        }
+        let suffix = compress_suffix(program)
+            .ok_or_else(|| InvalidData::new(&format!("Unknown PORTAGE_COMPRESS program: {}", program), None))?;
+        let read_list = |name: &str| -> Vec<String> {
+            fs::read_to_string(tempdir.join(name))
+                .map(|content| content.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect())
+                .unwrap_or_default()
+        };
+
+        let mut include: Vec<String> = ["/usr/share/doc", "/usr/share/info", "/usr/share/man"].iter().map(|p| p.to_string()).collect();
+        include.extend(read_list(".docompress_include"));
+        let mut exclude = vec![format!("/usr/share/doc/{}/html", pf)];
+        exclude.extend(read_list(".docompress_exclude"));
+        Ok(Some(CompressSettings {
+            program: program.to_string(),
+            flags: env.get("PORTAGE_COMPRESS_FLAGS").map(|f| f.as_str()).unwrap_or("-9").split_whitespace().map(|f| f.to_string()).collect(),
+            suffix: suffix.to_string(),
+            include,
+            exclude,
+            size_limit: env.get("PORTAGE_DOCOMPRESS_SIZE_LIMIT").and_then(|l| l.parse().ok()).unwrap_or(128),
+        }))
+    }
+
+    fn excluded(&self, installed: &str) -> bool {
+        self.exclude.iter().any(|x| installed == x || installed.starts_with(&format!("{}/", x.trim_end_matches('/'))))
+    }
+
+    /// Whether the installed regular file should be compressed
+    fn wanted(&self, installed: &str, size: u64) -> bool {
+        !self.excluded(installed)
+            && size >= self.size_limit
+            && !COMPRESSED_SUFFIXES.iter().any(|s| installed.ends_with(s))
+            // The info directory index is regenerated on ROOT, never installed compressed
+            && !installed.starts_with("/usr/share/info/dir")
+    }
+}
+
+fn installed_path(destdir: &Path, path: &Path) -> String {
+    format!("/{}", path.strip_prefix(destdir).unwrap_or(path).display())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}", path.display(), suffix))
+}
+
+/// Files and symlinks under the included paths, skipping excluded trees
+fn collect(destdir: &Path, settings: &CompressSettings, files: &mut Vec<PathBuf>, links: &mut Vec<PathBuf>) {
+    fn walk(path: &Path, destdir: &Path, settings: &CompressSettings, files: &mut Vec<PathBuf>, links: &mut Vec<PathBuf>) {
+        if settings.excluded(&installed_path(destdir, path)) || files.contains(&path.to_path_buf()) || links.contains(&path.to_path_buf()) {
+            return;
+        }
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return;
+        };
+        if metadata.file_type().is_symlink() {
+            links.push(path.to_path_buf());
+        } else if metadata.is_file() {
+            files.push(path.to_path_buf());
+        } else if metadata.is_dir() && let Ok(entries) = fs::read_dir(path) {
+            let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+            entries.sort();
+            for entry in entries {
+                walk(&entry, destdir, settings, files, links);
+            }
+        }
+    }
+    for include in &settings.include {
+        walk(&destdir.join(include.trim_start_matches('/')), destdir, settings, files, links);
+    }
+}
+
+/// Compress `path` to `path` + suffix, keeping its mode and mtime, and remove the original
+fn compress_file(path: &Path, settings: &CompressSettings) -> Result<PathBuf, InvalidData> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| InvalidData::new(&format!("Failed to stat {}: {}", path.display(), e), None))?;
+    let target = with_suffix(path, &settings.suffix);
+    let input = fs::File::open(path)
+        .map_err(|e| InvalidData::new(&format!("Failed to open {}: {}", path.display(), e), None))?;
+    let output = fs::File::create(&target)
+        .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", target.display(), e), None))?;
+
+    let status = Command::new(&settings.program)
+        .args(&settings.flags)
+        .arg("-c")
+        .stdin(Stdio::from(input))
+        .stdout(Stdio::from(output))
+        .status()
+        .map_err(|e| InvalidData::new(&format!("Failed to run {}: {}", settings.program, e), None))?;
+    if !status.success() {
+        let _ = fs::remove_file(&target);
+        return Err(InvalidData::new(&format!("{} failed on {}", settings.program, path.display()), None));
+    }
+
+    fs::set_permissions(&target, metadata.permissions())
+        .and_then(|_| fs::File::options().write(true).open(&target)?.set_modified(metadata.modified()?))
+        .and_then(|_| fs::remove_file(path))
+        .map_err(|e| InvalidData::new(&format!("Failed to replace {}: {}", path.display(), e), None))?;
+    Ok(target)
+}
+
+/// Compress the docs in `destdir`, returning the installed paths that were compressed.
+/// Hardlinked files are compressed once and relinked; symlinks to compressed files are
+/// renamed and retargeted to match
+pub fn compress_image(destdir: &Path, settings: &CompressSettings) -> Result<Vec<String>, InvalidData> {
+    let (mut files, mut links) = (Vec::new(), Vec::new());
+    collect(destdir, settings, &mut files, &mut links);
+
+    // Group hardlinks so each inode is compressed once
+    let mut groups: Vec<((u64, u64), Vec<PathBuf>)> = Vec::new();
+    for file in files {
+        let Ok(metadata) = fs::metadata(&file) else {
+            continue;
+        };
+        if !settings.wanted(&installed_path(destdir, &file), metadata.len()) {
+            continue;
+        }
+        let inode = (metadata.dev(), metadata.ino());
+        match groups.iter_mut().find(|(key, _)| *key == inode) {
+            Some((_, paths)) => paths.push(file),
+            None => groups.push((inode, vec![file])),
+        }
+    }
+
+    let mut compressed = Vec::new();
+    for (_, paths) in groups {
+        let first = compress_file(&paths[0], settings)?;
+        compressed.push(installed_path(destdir, &paths[0]));
+        for other in &paths[1..] {
+            fs::remove_file(other)
+                .and_then(|_| fs::hard_link(&first, with_suffix(other, &settings.suffix)))
+                .map_err(|e| InvalidData::new(&format!("Failed to relink {}: {}", other.display(), e), None))?;
+            compressed.push(installed_path(destdir, other));
+        }
+    }
+
+    // A symlink may point at another symlink that is only renamed in this loop, so repeat
+    // until nothing changes
+    loop {
+        let mut changed = false;
+        for link in links.iter_mut() {
+            let Ok(target) = fs::read_link(&*link) else {
+                continue;
+            };
+            let resolved = if target.is_absolute() {
+                destdir.join(target.strip_prefix("/").unwrap_or(&target))
+            } else {
+                link.parent().unwrap_or(destdir).join(&target)
+            };
+            if fs::symlink_metadata(&resolved).is_ok() || fs::symlink_metadata(with_suffix(&resolved, &settings.suffix)).is_err() {
+                continue;
+            }
+            let new_link = with_suffix(link, &settings.suffix);
+            fs::remove_file(&*link)
+                .and_then(|_| std::os::unix::fs::symlink(with_suffix(&target, &settings.suffix), &new_link))
+                .map_err(|e| InvalidData::new(&format!("Failed to update symlink {}: {}", link.display(), e), None))?;
+            *link = new_link;
+            changed = true;
+        }
+        if !changed {
+            break;
+        }
+    }
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_from_env() {
+        let temp = TempDir::new().unwrap();
+        fs::write(temp.path().join(".docompress_include"), "/usr/share/foo\n").unwrap();
+        fs::write(temp.path().join(".docompress_exclude"), "/usr/share/doc/foo-1.0/examples\n").unwrap();
+
+        let settings = CompressSettings::from_env(&HashMap::new(), temp.path(), "foo-1.0").unwrap().unwrap();
+        assert_eq!((settings.program.as_str(), settings.suffix.as_str()), ("bzip2", ".bz2"));
+        assert_eq!(settings.flags, vec!["-9"]);
+        assert_eq!(settings.include.last().unwrap(), "/usr/share/foo");
+        assert_eq!(settings.exclude, vec!["/usr/share/doc/foo-1.0/html", "/usr/share/doc/foo-1.0/examples"]);
+        assert!(settings.wanted("/usr/share/doc/foo-1.0/README", 4096));
+        assert!(!settings.wanted("/usr/share/doc/foo-1.0/README", 10));
+        assert!(!settings.wanted("/usr/share/doc/foo-1.0/html/index.html", 4096));
+        assert!(!settings.wanted("/usr/share/doc/foo-1.0/ChangeLog.xz", 4096));
+
+        let env = HashMap::from([("PORTAGE_COMPRESS".to_string(), String::new())]);
+        assert!(CompressSettings::from_env(&env, temp.path(), "foo-1.0").unwrap().is_none());
+        let env = HashMap::from([("PORTAGE_COMPRESS".to_string(), "compress-o-matic".to_string())]);
+        assert!(CompressSettings::from_env(&env, temp.path(), "foo-1.0").is_err());
+    }
+
+    #[test]
+    fn test_compress_image() {
+        let temp = TempDir::new().unwrap();
+        let image = temp.path().join("image");
+        let (man, doc) = (image.join("usr/share/man/man1"), image.join("usr/share/doc/foo-1.0"));
+        fs::create_dir_all(&man).unwrap();
+        fs::create_dir_all(doc.join("html")).unwrap();
+        fs::create_dir_all(image.join("usr/bin")).unwrap();
+        let page = ".TH FOO 1\n".repeat(40);
+        fs::write(man.join("foo.1"), &page).unwrap();
+        fs::hard_link(man.join("foo.1"), man.join("foo-tool.1")).unwrap();
+        std::os::unix::fs::symlink("foo.1", man.join("bar.1")).unwrap();
+        std::os::unix::fs::symlink("bar.1", man.join("baz.1")).unwrap();
+        fs::write(doc.join("README"), &page).unwrap();
+        fs::write(doc.join("TINY"), "x\n").unwrap();
+        fs::write(doc.join("NEWS.gz"), &page).unwrap();
+        fs::write(doc.join("html/index.html"), &page).unwrap();
+        fs::write(image.join("usr/bin/foo"), &page).unwrap();
+
+        let env = HashMap::from([("PORTAGE_COMPRESS".to_string(), "gzip".to_string())]);
+        let settings = CompressSettings::from_env(&env, temp.path(), "foo-1.0").unwrap().unwrap();
+        let mut compressed = compress_image(&image, &settings).unwrap();
+        compressed.sort();
+        assert_eq!(compressed, vec!["/usr/share/doc/foo-1.0/README", "/usr/share/man/man1/foo-tool.1", "/usr/share/man/man1/foo.1"]);
+
+        assert!(!man.join("foo.1").exists());
+        let (first, second) = (fs::metadata(man.join("foo.1.gz")).unwrap(), fs::metadata(man.join("foo-tool.1.gz")).unwrap());
+        assert_eq!(first.ino(), second.ino());
+        assert_eq!(fs::read_link(man.join("bar.1.gz")).unwrap(), Path::new("foo.1.gz"));
+        assert_eq!(fs::read_link(man.join("baz.1.gz")).unwrap(), Path::new("bar.1.gz"));
+        assert!(fs::symlink_metadata(man.join("bar.1")).is_err());
+
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(fs::File::open(doc.join("README.gz")).unwrap()), &mut decoded).unwrap();
+        assert_eq!(decoded, page);
+        for untouched in ["TINY", "NEWS.gz", "html/index.html"] {
+            assert!(doc.join(untouched).is_file());
+        }
+        assert!(image.join("usr/bin/foo").is_file());
+    }
+}
@@ -0,0 +1,199 @@
+// econf.rs -- econf: the ./configure arguments PMS gives each EAPI, for the default
+// src_configure and the econf helper of ebuild phases
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Arguments for a ./configure run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Econf {
+    /// Passed to every configure script
+    pub args: Vec<String>,
+    /// Passed only when `./configure --help` mentions the option they go with
+    pub if_supported: Vec<(String, String)>,
+}
+
+impl Econf {
+    /// The arguments for `eapi`, with paths from the build environment: EPREFIX, CHOST,
+    /// CBUILD, CTARGET, the ABI's LIBDIR_* from the profile, PF and ESYSROOT
+    pub fn new(env: &HashMap<String, String>, eapi: &str) -> Self {
+        let get = |var: &str| env.get(var).filter(|v| !v.is_empty()).cloned();
+        let eapi: u32 = eapi.trim().parse().unwrap_or(8);
+        let eprefix = get("EPREFIX").unwrap_or_default();
+
+        let mut args = vec![format!("--prefix={}/usr", eprefix)];
+        if let Some(cbuild) = get("CBUILD") {
+            args.push(format!("--build={}", cbuild));
+        }
+        if let Some(chost) = get("CHOST") {
+            args.push(format!("--host={}", chost));
+        }
+        if let Some(ctarget) = get("CTARGET") {
+            args.push(format!("--target={}", ctarget));
+        }
+        args.extend([
+            format!("--mandir={}/usr/share/man", eprefix),
+            format!("--infodir={}/usr/share/info", eprefix),
+            format!("--datadir={}/usr/share", eprefix),
+            format!("--sysconfdir={}/etc", eprefix),
+            format!("--localstatedir={}/var/lib", eprefix),
+            format!("--libdir={}/usr/{}", eprefix, libdir(env)),
+        ]);
+
+        let mut if_supported = Vec::new();
+        let mut supported = |option: &str, arg: String| if_supported.push((option.to_string(), arg));
+        if eapi >= 4 {
+            supported("--disable-dependency-tracking", "--disable-dependency-tracking".to_string());
+        }
+        if eapi >= 5 {
+            supported("--disable-silent-rules", "--disable-silent-rules".to_string());
+        }
+        if eapi >= 6 {
+            let pf = get("PF").or_else(|| get("P")).unwrap_or_default();
+            supported("--docdir", format!("--docdir={}/usr/share/doc/{}", eprefix, pf));
+            supported("--htmldir", format!("--htmldir={}/usr/share/doc/{}/html", eprefix, pf));
+        }
+        if eapi >= 7 {
+            supported("--with-sysroot", format!("--with-sysroot={}", get("ESYSROOT").unwrap_or_else(|| "/".to_string())));
+        }
+        if eapi >= 8 {
+            supported("--datarootdir", format!("--datarootdir={}/usr/share", eprefix));
+            supported("--enable-static", "--disable-static".to_string());
+        }
+        Econf { args, if_supported }
+    }
+
+    /// Every argument for a configure script with `help` as its --help output, then
+    /// EXTRA_ECONF
+    pub fn args_for(&self, help: &str, env: &HashMap<String, String>) -> Vec<String> {
+        let mut args = self.args.clone();
+        args.extend(self.if_supported.iter()
+            .filter(|(option, _)| supports(help, option))
+            .map(|(_, arg)| arg.clone()));
+        if let Some(extra) = env.get("EXTRA_ECONF") {
+            args.extend(extra.split_whitespace().map(|a| a.to_string()));
+        }
+        args
+    }
+
+    /// The econf helper for ebuild phases: the same arguments, then EXTRA_ECONF and the
+    /// ebuild's; dies with the end of config.log when configure fails. --host and --libdir
+    /// follow CHOST and ABI at run time, which multilib builds change per ABI
+    pub fn bash_function(&self) -> String {
+        let quote = |arg: &str| format!("'{}'", arg.replace('\'', "'\\''"));
+        let mut function = String::from("get_libdir() {\n");
+        function.push_str("    local var=LIBDIR_${ABI:-${DEFAULT_ABI}}\n");
+        function.push_str("    echo \"${!var:-lib}\"\n");
+        function.push_str("}\n\n");
+        function.push_str("econf() {\n");
+        function.push_str("    local ECONF_SOURCE=${ECONF_SOURCE:-.}\n");
+        function.push_str("    [ -x \"${ECONF_SOURCE}/configure\" ] || die \"econf: ${ECONF_SOURCE}/configure is missing\"\n");
+        function.push_str("    local help\n");
+        function.push_str("    help=$(\"${ECONF_SOURCE}/configure\" --help 2>/dev/null)\n");
+        let mut args: Vec<String> = Vec::new();
+        for arg in self.args.iter().filter(|a| !a.starts_with("--host=")) {
+            if arg.starts_with("--libdir=") {
+                args.push("\"--libdir=${EPREFIX}/usr/$(get_libdir)\"".to_string());
+            } else {
+                args.push(quote(arg));
+            }
+            // --host comes after --prefix and --build
+            if arg.starts_with("--prefix=") && !self.args.iter().any(|a| a.starts_with("--build=")) || arg.starts_with("--build=") {
+                args.push("${CHOST:+\"--host=${CHOST}\"}".to_string());
+            }
+        }
+        function.push_str(&format!("    local args=( {} )\n", args.join(" ")));
+        for (option, arg) in &self.if_supported {
+            // As supports(): the option followed by '=', '[' or whitespace
+            function.push_str(&format!(
+                "    grep -Eq -- {}'(=|\\[|[[:space:]]|$)' <<< \"${{help}}\" && args+=( {} )\n", quote(option), quote(arg)));
+        }
+        function.push_str("    echo \"${ECONF_SOURCE}/configure ${args[*]} ${EXTRA_ECONF} $*\"\n");
+        function.push_str("    if ! \"${ECONF_SOURCE}/configure\" \"${args[@]}\" ${EXTRA_ECONF} \"$@\"; then\n");
+        function.push_str("        [ -f config.log ] && tail -n 25 config.log >&2\n");
+        function.push_str("        die \"econf failed\"\n");
+        function.push_str("    fi\n");
+        function.push_str("}\n\n");
+        function
+    }
+}
+
+/// Whether configure --help output lists `option`
+fn supports(help: &str, option: &str) -> bool {
+    help.match_indices(option).any(|(at, _)| {
+        help[at + option.len()..].chars().next().is_none_or(|c| c == '=' || c == '[' || c.is_whitespace())
+    })
+}
+
+/// get_libdir: LIBDIR_${ABI} from the profile, lib without one
+pub fn libdir(env: &HashMap<String, String>) -> String {
+    let abi = env.get("ABI").or_else(|| env.get("DEFAULT_ABI")).filter(|a| !a.is_empty());
+    abi.and_then(|abi| env.get(&format!("LIBDIR_{}", abi)))
+        .filter(|dir| !dir.is_empty())
+        .cloned()
+        .unwrap_or_else(|| "lib".to_string())
+}
+
+/// The last `lines` lines of config.log in `dir`, to show why configure failed
+pub fn config_log_tail(dir: &Path, lines: usize) -> Option<String> {
+    let log = std::fs::read_to_string(dir.join("config.log")).ok()?;
+    let all: Vec<&str> = log.lines().collect();
+    Some(all[all.len().saturating_sub(lines)..].join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> HashMap<String, String> {
+        HashMap::from([
+            ("CHOST".to_string(), "x86_64-pc-linux-gnu".to_string()),
+            ("CBUILD".to_string(), "x86_64-pc-linux-gnu".to_string()),
+            ("DEFAULT_ABI".to_string(), "amd64".to_string()),
+            ("LIBDIR_amd64".to_string(), "lib64".to_string()),
+            ("PF".to_string(), "foo-1.0-r1".to_string()),
+            ("EXTRA_ECONF".to_string(), "--enable-foo".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_econf_args() {
+        let help = "  --disable-dependency-tracking  speeds up one-time build\n  --docdir=DIR  documentation\n  --enable-static[=PKGS]\n  --with-sysroot=DIR\n";
+        let args = Econf::new(&env(), "8").args_for(help, &env());
+        assert_eq!(&args[..3], ["--prefix=/usr", "--build=x86_64-pc-linux-gnu", "--host=x86_64-pc-linux-gnu"]);
+        assert!(args.contains(&"--libdir=/usr/lib64".to_string()));
+        assert!(args.contains(&"--docdir=/usr/share/doc/foo-1.0-r1".to_string()));
+        assert!(args.contains(&"--disable-static".to_string()) && args.contains(&"--with-sysroot=/".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("--htmldir") || a == "--disable-silent-rules"));
+        assert_eq!(args.last().unwrap(), "--enable-foo");
+
+        // Older EAPIs get fewer defaults
+        let args = Econf::new(&env(), "6").args_for(help, &HashMap::new());
+        assert!(args.contains(&"--docdir=/usr/share/doc/foo-1.0-r1".to_string()));
+        assert!(!args.iter().any(|a| a == "--disable-static" || a.starts_with("--with-sysroot")));
+        assert_eq!(libdir(&HashMap::new()), "lib");
+    }
+
+    #[test]
+    fn test_econf_helper() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let configure = temp.path().join("configure");
+        std::fs::write(&configure, "#!/bin/sh\nif [ \"$1\" = --help ]; then echo '  --enable-static[=PKGS]'; exit 0; fi\necho \"$@\" > args\necho 'checking for cc... no' > config.log\ncase \"$*\" in *--fail*) exit 1;; esac\n").unwrap();
+        std::fs::set_permissions(&configure, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let function = Econf::new(&HashMap::new(), "8").bash_function();
+        let run = |extra: &str| std::process::Command::new("bash")
+            .args(["-c", &format!("die() {{ echo \"$*\" >&2; exit 1; }}\n{}econf {}", function, extra)])
+            .current_dir(temp.path())
+            .output()
+            .unwrap();
+        assert!(run("--enable-bar").status.success());
+        let args = std::fs::read_to_string(temp.path().join("args")).unwrap();
+        assert!(args.starts_with("--prefix=/usr --mandir=/usr/share/man"));
+        assert!(args.trim_end().ends_with("--libdir=/usr/lib --disable-static --enable-bar"));
+
+        let failed = run("--fail");
+        assert!(!failed.status.success());
+        assert!(String::from_utf8_lossy(&failed.stderr).contains("checking for cc... no"));
+    }
+}
@@ -0,0 +1,335 @@
+// elf.rs -- Minimal ELF dynamic section reader (SONAME, NEEDED, RPATH) and build-id lookup
+
+use std::path::Path;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_NOTE: u32 = 4;
+const NT_GNU_BUILD_ID: u64 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_TEXTREL: u64 = 22;
+const DT_RUNPATH: u64 = 29;
+const DT_FLAGS: u64 = 30;
+const DF_TEXTREL: u64 = 0x4;
+
+/// Dynamic linking information of an ELF object
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ElfInfo {
+    /// Architecture as named in NEEDED.ELF.2 (X86_64, AARCH64, ...)
+    pub arch: String,
+    /// Portage multilib category (x86_64, x86_32, arm_64, ...), empty if unknown
+    pub multilib_category: String,
+    pub soname: Option<String>,
+    pub rpath: Vec<String>,
+    pub needed: Vec<String>,
+    /// Has text relocations (DT_TEXTREL or DF_TEXTREL)
+    pub textrel: bool,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.data.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<2>(offset)?;
+        Some(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) } as u64)
+    }
+
+    fn u32(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<4>(offset)?;
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) } as u64)
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<8>(offset)?;
+        Some(if self.big_endian { u64::from_be_bytes(b) } else { u64::from_le_bytes(b) })
+    }
+
+    /// Address-sized word
+    fn word(&self, offset: usize) -> Option<u64> {
+        if self.is_64 { self.u64(offset) } else { self.u32(offset) }
+    }
+
+    fn c_str(&self, offset: usize) -> Option<String> {
+        let rest = self.data.get(offset..)?;
+        let end = rest.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+fn arch_name(machine: u64) -> String {
+    match machine {
+        3 => "X86".to_string(),
+        8 => "MIPS".to_string(),
+        20 => "PPC".to_string(),
+        21 => "PPC64".to_string(),
+        40 => "ARM".to_string(),
+        62 => "X86_64".to_string(),
+        183 => "AARCH64".to_string(),
+        243 => "RISCV".to_string(),
+        other => format!("MACHINE_{}", other),
+    }
+}
+
+/// ABI category as computed by portage's compute_multilib_category
+fn multilib_category(machine: u64, is_64: bool, flags: u64) -> String {
+    let bits = if is_64 { "64" } else { "32" };
+    match machine {
+        3 => "x86_32".to_string(),
+        62 if is_64 => "x86_64".to_string(),
+        62 => "x86_x32".to_string(),
+        40 | 183 => format!("arm_{}", bits),
+        20 | 21 => format!("ppc_{}", bits),
+        8 if is_64 => "mips_n64".to_string(),
+        // EF_MIPS_ABI2 marks n32
+        8 if flags & 0x20 != 0 => "mips_n32".to_string(),
+        8 => "mips_o32".to_string(),
+        243 => {
+            // EF_RISCV_FLOAT_ABI: 0 soft, 2 single, 4 double
+            let float = match flags & 0x6 { 2 => "f", 4 => "d", _ => "" };
+            format!("riscv_{}{}", if is_64 { "lp64" } else { "ilp32" }, float)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Parse ELF bytes; None for anything that isn't a dynamically linked ELF object
+pub fn parse_elf(data: &[u8]) -> Option<ElfInfo> {
+    if data.len() < 52 || &data[..4] != b"\x7fELF" {
+        return None;
+    }
+    let reader = Reader { data, is_64: data[4] == 2, big_endian: data[5] == 2 };
+
+    let (phoff, phentsize, phnum) = if reader.is_64 {
+        (reader.u64(32)?, reader.u16(54)?, reader.u16(56)?)
+    } else {
+        (reader.u32(28)?, reader.u16(42)?, reader.u16(44)?)
+    };
+
+    // (type, offset, vaddr, filesz) of each program header
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let base = (phoff + i * phentsize) as usize;
+        let segment = if reader.is_64 {
+            (reader.u32(base)?, reader.u64(base + 8)?, reader.u64(base + 16)?, reader.u64(base + 32)?)
+        } else {
+            (reader.u32(base)?, reader.u32(base + 4)?, reader.u32(base + 8)?, reader.u32(base + 16)?)
+        };
+        segments.push((segment.0 as u32, segment.1, segment.2, segment.3));
+    }
+    let &(_, dyn_offset, _, dyn_size) = segments.iter().find(|s| s.0 == PT_DYNAMIC)?;
+
+    let entry_size = if reader.is_64 { 16 } else { 8 };
+    let mut strtab_addr = None;
+    let mut soname = None;
+    let mut rpaths = Vec::new();
+    let mut needed = Vec::new();
+    let mut textrel = false;
+    for i in 0..dyn_size / entry_size {
+        let base = (dyn_offset + i * entry_size) as usize;
+        let tag = reader.word(base)?;
+        let value = reader.word(base + entry_size as usize / 2)?;
+        match tag {
+            DT_NULL => break,
+            DT_STRTAB => strtab_addr = Some(value),
+            DT_NEEDED => needed.push(value),
+            DT_SONAME => soname = Some(value),
+            DT_RPATH | DT_RUNPATH => rpaths.push(value),
+            DT_TEXTREL => textrel = true,
+            DT_FLAGS if value & DF_TEXTREL != 0 => textrel = true,
+            _ => {}
+        }
+    }
+
+    // DT_STRTAB is a virtual address; map it back to a file offset through PT_LOAD
+    let strtab_addr = strtab_addr?;
+    let strtab = segments.iter()
+        .filter(|s| s.0 == PT_LOAD)
+        .find(|s| strtab_addr >= s.2 && strtab_addr < s.2 + s.3)
+        .map(|s| strtab_addr - s.2 + s.1)?;
+    let string = |offset: u64| reader.c_str((strtab + offset) as usize);
+
+    let machine = reader.u16(18)?;
+    let flags = reader.u32(if reader.is_64 { 48 } else { 36 })?;
+    Some(ElfInfo {
+        arch: arch_name(machine),
+        multilib_category: multilib_category(machine, reader.is_64, flags),
+        soname: soname.and_then(string),
+        rpath: rpaths.into_iter()
+            .filter_map(string)
+            .flat_map(|r| r.split(':').map(|s| s.to_string()).collect::<Vec<_>>())
+            .filter(|r| !r.is_empty())
+            .collect(),
+        needed: needed.into_iter().filter_map(string).collect(),
+        textrel,
+    })
+}
+
+/// The GNU build-id note as lowercase hex, from any ELF object with a PT_NOTE segment
+pub fn build_id(data: &[u8]) -> Option<String> {
+    if data.len() < 52 || &data[..4] != b"\x7fELF" {
+        return None;
+    }
+    let reader = Reader { data, is_64: data[4] == 2, big_endian: data[5] == 2 };
+    let (phoff, phentsize, phnum) = if reader.is_64 {
+        (reader.u64(32)?, reader.u16(54)?, reader.u16(56)?)
+    } else {
+        (reader.u32(28)?, reader.u16(42)?, reader.u16(44)?)
+    };
+
+    for i in 0..phnum {
+        let base = (phoff + i * phentsize) as usize;
+        if reader.u32(base)? != PT_NOTE as u64 {
+            continue;
+        }
+        let (offset, size, align) = if reader.is_64 {
+            (reader.u64(base + 8)?, reader.u64(base + 32)?, reader.u64(base + 48)?)
+        } else {
+            (reader.u32(base + 4)?, reader.u32(base + 16)?, reader.u32(base + 28)?)
+        };
+        let align = if align == 8 { 8 } else { 4 };
+        let pad = |n: u64| n.div_ceil(align) * align;
+
+        // Each note: namesz, descsz, type, then the padded name and descriptor
+        let mut note = offset;
+        while note + 12 <= offset + size {
+            let (namesz, descsz, note_type) = (reader.u32(note as usize)?, reader.u32(note as usize + 4)?, reader.u32(note as usize + 8)?);
+            let name = note + 12;
+            let desc = name + pad(namesz);
+            if note_type == NT_GNU_BUILD_ID && data.get(name as usize..(name + namesz) as usize) == Some(b"GNU\0") {
+                let id = data.get(desc as usize..(desc + descsz) as usize)?;
+                return Some(id.iter().map(|b| format!("{:02x}", b)).collect());
+            }
+            note = desc + pad(descsz);
+        }
+    }
+    None
+}
+
+/// Read an ELF file; None if it can't be read or isn't dynamically linked ELF
+pub fn read_elf(path: &Path) -> Option<ElfInfo> {
+    let data = std::fs::read(path).ok()?;
+    parse_elf(&data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A little-endian ELF64 with one PT_LOAD covering the file and a PT_DYNAMIC
+    pub(crate) fn build_elf64(soname: Option<&str>, needed: &[&str]) -> Vec<u8> {
+        build_elf64_with(soname, needed, None, false)
+    }
+
+    /// As build_elf64, with an optional DT_RUNPATH and DT_TEXTREL
+    pub(crate) fn build_elf64_with(soname: Option<&str>, needed: &[&str], runpath: Option<&str>, textrel: bool) -> Vec<u8> {
+        let mut strtab = vec![0u8];
+        let mut add = |s: &str| {
+            let offset = strtab.len() as u64;
+            strtab.extend_from_slice(s.as_bytes());
+            strtab.push(0);
+            offset
+        };
+        let mut dynamic: Vec<(u64, u64)> = needed.iter().map(|n| (DT_NEEDED, add(n))).collect();
+        if let Some(soname) = soname {
+            dynamic.push((DT_SONAME, add(soname)));
+        }
+        if let Some(runpath) = runpath {
+            dynamic.push((DT_RUNPATH, add(runpath)));
+        }
+        if textrel {
+            dynamic.push((DT_TEXTREL, 0));
+        }
+
+        let phoff = 64u64;
+        let strtab_off = phoff + 2 * 56;
+        let dyn_off = strtab_off + strtab.len() as u64;
+        dynamic.push((DT_STRTAB, 0x400000 + strtab_off));
+        dynamic.push((DT_NULL, 0));
+        let total = dyn_off + dynamic.len() as u64 * 16;
+
+        let mut data = vec![0u8; 64];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        // ET_DYN
+        data[16..18].copy_from_slice(&3u16.to_le_bytes());
+        data[18..20].copy_from_slice(&62u16.to_le_bytes());
+        data[32..40].copy_from_slice(&phoff.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&2u16.to_le_bytes());
+        for (p_type, offset, vaddr, size) in [(PT_LOAD, 0, 0x400000, total), (PT_DYNAMIC, dyn_off, 0x400000 + dyn_off, total - dyn_off)] {
+            let mut header = vec![0u8; 56];
+            header[..4].copy_from_slice(&p_type.to_le_bytes());
+            header[8..16].copy_from_slice(&offset.to_le_bytes());
+            header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            header[32..40].copy_from_slice(&size.to_le_bytes());
+            data.extend(header);
+        }
+        data.extend(&strtab);
+        for (tag, value) in dynamic {
+            data.extend(tag.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_elf() {
+        let info = parse_elf(&build_elf64(Some("libfoo.so.1"), &["libc.so.6", "libbar.so.2"])).unwrap();
+        assert_eq!(info.arch, "X86_64");
+        assert_eq!(info.multilib_category, "x86_64");
+        assert_eq!(info.soname.as_deref(), Some("libfoo.so.1"));
+        assert_eq!(info.needed, vec!["libc.so.6", "libbar.so.2"]);
+
+        assert_eq!(parse_elf(&build_elf64(None, &[])).unwrap().soname, None);
+        assert!(!info.textrel);
+
+        let info = parse_elf(&build_elf64_with(None, &[], Some("/lib:$ORIGIN"), true)).unwrap();
+        assert_eq!(info.rpath, vec!["/lib", "$ORIGIN"]);
+        assert!(info.textrel);
+        assert!(parse_elf(b"#!/bin/sh\necho not an elf file at all, just a script....\n").is_none());
+    }
+
+    #[test]
+    fn test_build_id() {
+        // An ELF64 header with a single PT_NOTE holding one unrelated note and the build-id
+        let mut notes = Vec::new();
+        for (name, note_type, desc) in [(&b"GNU\0"[..], 1u32, &[0u8, 0, 0, 0, 3, 2, 0, 0][..]), (b"GNU\0", 3, &[0xde, 0xad, 0xbe, 0xef, 0x01])] {
+            notes.extend((name.len() as u32).to_le_bytes());
+            notes.extend((desc.len() as u32).to_le_bytes());
+            notes.extend(note_type.to_le_bytes());
+            notes.extend(name);
+            notes.extend(desc);
+            notes.resize(notes.len().div_ceil(4) * 4, 0);
+        }
+        let mut data = vec![0u8; 64];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+        let mut header = vec![0u8; 56];
+        header[..4].copy_from_slice(&PT_NOTE.to_le_bytes());
+        header[8..16].copy_from_slice(&120u64.to_le_bytes());
+        header[32..40].copy_from_slice(&(notes.len() as u64).to_le_bytes());
+        header[48..56].copy_from_slice(&4u64.to_le_bytes());
+        data.extend(header);
+        data.extend(notes);
+
+        assert_eq!(build_id(&data).as_deref(), Some("deadbeef01"));
+        assert_eq!(build_id(&build_elf64(None, &[])), None);
+    }
+}
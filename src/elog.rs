@@ -0,0 +1,272 @@
+// elog.rs -- Collect einfo/ewarn/eerror/elog/eqawarn messages and dispatch them (PORTAGE_ELOG_*)
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::config::Config;
+use crate::exception::InvalidData;
+
+/// Phases in the order their messages are reported
+const PHASE_ORDER: &[&str] = &[
+    "pretend", "setup", "unpack", "prepare", "configure", "compile", "test", "install",
+    "preinst", "postinst", "prerm", "postrm", "other",
+];
+
+/// Message class, as written by the helpers into T/logging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElogClass {
+    Info,
+    Log,
+    Warn,
+    Error,
+    Qa,
+}
+
+impl ElogClass {
+    /// Parse a class from its log prefix (INFO) or PORTAGE_ELOG_CLASSES name (info)
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "info" => Some(ElogClass::Info),
+            "log" => Some(ElogClass::Log),
+            "warn" => Some(ElogClass::Warn),
+            "error" => Some(ElogClass::Error),
+            "qa" => Some(ElogClass::Qa),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ElogClass::Info => "INFO",
+            ElogClass::Log => "LOG",
+            ElogClass::Warn => "WARN",
+            ElogClass::Error => "ERROR",
+            ElogClass::Qa => "QA",
+        }
+    }
+}
+
+/// One line logged by an ebuild helper
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElogMessage {
+    pub phase: String,
+    pub class: ElogClass,
+    pub text: String,
+}
+
+/// Read the messages the helpers left in T/logging, in phase order
+pub fn collect_messages(tempdir: &Path) -> Vec<ElogMessage> {
+    let logging = tempdir.join("logging");
+    let mut phases: Vec<String> = fs::read_dir(&logging)
+        .map(|entries| entries.flatten().map(|e| e.file_name().to_string_lossy().to_string()).collect())
+        .unwrap_or_default();
+    phases.sort_by_key(|phase| PHASE_ORDER.iter().position(|p| p == phase).unwrap_or(PHASE_ORDER.len()));
+
+    let mut messages = Vec::new();
+    for phase in phases {
+        let Ok(content) = fs::read_to_string(logging.join(&phase)) else {
+            continue;
+        };
+        for line in content.lines() {
+            let (class, text) = line.split_once(' ').unwrap_or((line, ""));
+            if let Some(class) = ElogClass::parse(class) {
+                messages.push(ElogMessage { phase: phase.clone(), class, text: text.to_string() });
+            }
+        }
+    }
+    messages
+}
+
+/// Messages grouped as portage logs them: a "CLASS: phase" header per run of lines
+pub fn format_messages(messages: &[ElogMessage]) -> String {
+    let mut out = String::new();
+    let mut current: Option<(&str, ElogClass)> = None;
+    for message in messages {
+        if current != Some((message.phase.as_str(), message.class)) {
+            if current.is_some() {
+                out.push('\n');
+            }
+            out.push_str(&format!("{}: {}\n", message.class.name(), message.phase));
+            current = Some((message.phase.as_str(), message.class));
+        }
+        out.push_str(&message.text);
+        out.push('\n');
+    }
+    out
+}
+
+/// What to collect and where to send it
+#[derive(Debug, Clone)]
+pub struct ElogSettings {
+    /// PORTAGE_ELOG_CLASSES
+    pub classes: Vec<ElogClass>,
+    /// PORTAGE_ELOG_SYSTEM modules, each with an optional class override (save:warn,error)
+    pub systems: Vec<(String, Option<Vec<ElogClass>>)>,
+    /// Directory for the save and save_summary modules
+    pub logdir: PathBuf,
+    pub mail_uri: String,
+    pub mail_from: String,
+    pub mail_subject: String,
+}
+
+fn parse_classes(list: &str) -> Vec<ElogClass> {
+    list.split([' ', ',']).filter_map(ElogClass::parse).collect()
+}
+
+fn parse_systems(list: &str) -> Vec<(String, Option<Vec<ElogClass>>)> {
+    list.split_whitespace()
+        .map(|system| match system.split_once(':') {
+            Some((name, classes)) => (name.to_string(), Some(parse_classes(classes))),
+            None => (system.to_string(), None),
+        })
+        .collect()
+}
+
+impl ElogSettings {
+    pub fn from_config(config: &Config) -> Self {
+        let var = |key: &str, default: &str| config.get_var(key).cloned().unwrap_or_else(|| default.to_string());
+        let logdir = config.get_var("PORTAGE_LOGDIR")
+            .or_else(|| config.get_var("PORT_LOGDIR"))
+            .map(|dir| PathBuf::from(dir).join("elog"))
+            .unwrap_or_else(|| Path::new(&config.root).join("var/log/portage/elog"));
+        ElogSettings {
+            classes: parse_classes(&var("PORTAGE_ELOG_CLASSES", "log warn error")),
+            systems: parse_systems(&var("PORTAGE_ELOG_SYSTEM", "save_summary:log,warn,error,qa echo")),
+            logdir,
+            mail_uri: var("PORTAGE_ELOG_MAILURI", "root@localhost"),
+            mail_from: var("PORTAGE_ELOG_MAILFROM", "portage@localhost"),
+            mail_subject: var("PORTAGE_ELOG_MAILSUBJECT", "[portage] ebuild log for ${PACKAGE} on ${HOST}"),
+        }
+    }
+
+    /// Run the configured modules on a package's messages; returns what the echo
+    /// module should print once the merge is over
+    pub fn process(&self, cpv: &str, messages: &[ElogMessage]) -> Result<Vec<ElogMessage>, InvalidData> {
+        let mut echo = Vec::new();
+        for (system, classes) in &self.systems {
+            let classes = classes.as_ref().unwrap_or(&self.classes);
+            let selected: Vec<ElogMessage> = messages.iter().filter(|m| classes.contains(&m.class)).cloned().collect();
+            if selected.is_empty() {
+                continue;
+            }
+            match system.as_str() {
+                "save" => self.save(cpv, &selected)?,
+                "save_summary" => self.save_summary(cpv, &selected)?,
+                "echo" => echo = selected,
+                "mail" => self.mail(cpv, &selected)?,
+                other => eprintln!("!!! Unknown PORTAGE_ELOG_SYSTEM module: {}", other),
+            }
+        }
+        Ok(echo)
+    }
+
+    /// One file per merge: <category>:<pf>:<date>.log
+    fn save(&self, cpv: &str, messages: &[ElogMessage]) -> Result<(), InvalidData> {
+        let (category, pf) = cpv.split_once('/').unwrap_or(("", cpv));
+        fs::create_dir_all(&self.logdir)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", self.logdir.display(), e), None))?;
+        let path = self.logdir.join(format!("{}:{}:{}.log", category, pf, chrono::Local::now().format("%Y%m%d-%H%M%S")));
+        fs::write(&path, format_messages(messages))
+            .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))
+    }
+
+    /// Every merge appended to summary.log
+    fn save_summary(&self, cpv: &str, messages: &[ElogMessage]) -> Result<(), InvalidData> {
+        fs::create_dir_all(&self.logdir)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", self.logdir.display(), e), None))?;
+        let path = self.logdir.join("summary.log");
+        let entry = format!(
+            ">>> Messages generated by process {} on {} for package {}:\n\n{}\n",
+            std::process::id(),
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S %Z"),
+            cpv,
+            format_messages(messages),
+        );
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(entry.as_bytes()))
+            .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))
+    }
+
+    /// Hand the messages to the local sendmail for PORTAGE_ELOG_MAILURI's recipient
+    fn mail(&self, cpv: &str, messages: &[ElogMessage]) -> Result<(), InvalidData> {
+        let recipient = self.mail_uri.split_whitespace().next().unwrap_or("root@localhost");
+        let host = fs::read_to_string("/proc/sys/kernel/hostname").map(|h| h.trim().to_string()).unwrap_or_default();
+        let subject = self.mail_subject.replace("${PACKAGE}", cpv).replace("${HOST}", &host);
+        let mail = format!("From: {}\nTo: {}\nSubject: {}\n\n{}", self.mail_from, recipient, subject, format_messages(messages));
+
+        let mut child = std::process::Command::new("sendmail")
+            .arg("-t")
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| InvalidData::new(&format!("Failed to run sendmail: {}", e), None))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(mail.as_bytes());
+        }
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            _ => Err(InvalidData::new(&format!("Failed to mail elog messages for {}", cpv), None)),
+        }
+    }
+}
+
+/// The end-of-merge report of what the echo module kept
+pub fn format_summary(packages: &[(String, Vec<ElogMessage>)]) -> String {
+    let mut out = String::new();
+    for (cpv, messages) in packages.iter().filter(|(_, messages)| !messages.is_empty()) {
+        out.push_str(&format!("\n * Messages for package {}:\n\n", cpv));
+        for message in messages {
+            out.push_str(&format!(" * {}\n", message.text));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn settings(logdir: &Path, classes: &str, systems: &str) -> ElogSettings {
+        ElogSettings {
+            classes: parse_classes(classes),
+            systems: parse_systems(systems),
+            logdir: logdir.to_path_buf(),
+            mail_uri: String::new(),
+            mail_from: String::new(),
+            mail_subject: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_collect_and_process() {
+        let temp = TempDir::new().unwrap();
+        let logging = temp.path().join("T/logging");
+        fs::create_dir_all(&logging).unwrap();
+        fs::write(logging.join("postinst"), "LOG Run foo-setup once\nWARN Config format changed\n").unwrap();
+        fs::write(logging.join("setup"), "INFO Checking kernel\nQA Deprecated eclass\n").unwrap();
+
+        let messages = collect_messages(&temp.path().join("T"));
+        assert_eq!(messages.iter().map(|m| m.phase.as_str()).collect::<Vec<_>>(), vec!["setup", "setup", "postinst", "postinst"]);
+        assert_eq!(messages[3], ElogMessage { phase: "postinst".to_string(), class: ElogClass::Warn, text: "Config format changed".to_string() });
+
+        let logdir = temp.path().join("elog");
+        let echo = settings(&logdir, "log warn error", "save save_summary:warn,qa echo")
+            .process("app-misc/foo-1.0", &messages)
+            .unwrap();
+        assert_eq!(echo.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), vec!["Run foo-setup once", "Config format changed"]);
+
+        let saved: Vec<PathBuf> = fs::read_dir(&logdir).unwrap().flatten().map(|e| e.path()).collect();
+        let log = saved.iter().find(|p| p.file_name().unwrap().to_string_lossy().starts_with("app-misc:foo-1.0:")).unwrap();
+        assert_eq!(fs::read_to_string(log).unwrap(), "LOG: postinst\nRun foo-setup once\n\nWARN: postinst\nConfig format changed\n");
+
+        let summary = fs::read_to_string(logdir.join("summary.log")).unwrap();
+        assert!(summary.contains("for package app-misc/foo-1.0:\n\nQA: setup\nDeprecated eclass\n\nWARN: postinst\n"));
+        assert!(!summary.contains("Checking kernel"));
+
+        assert_eq!(format_summary(&[("app-misc/foo-1.0".to_string(), echo)]),
+            "\n * Messages for package app-misc/foo-1.0:\n\n * Run foo-setup once\n * Config format changed\n");
+    }
+}
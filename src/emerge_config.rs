@@ -1 +1,131 @@
-// emerge_config.rs - Emerge configuration handling
\ No newline at end of file
+// emerge_config.rs - Emerge configuration handling
+
+/// Command line options shared by the install and upgrade actions
+#[derive(Debug, Clone)]
+pub struct EmergeOptions {
+    pub pretend: bool,
+    pub ask: bool,
+    /// Continue the merge list saved by an interrupted or failed run
+    pub resume: bool,
+    /// With --resume, drop the first package of the list
+    pub skipfirst: bool,
+    /// Show the merge list as a dependency tree
+    pub tree: bool,
+    /// List every USE flag in the merge list, not only changed ones
+    pub verbose: bool,
+    /// Show what changed in each upgraded package below the merge list (--changelog)
+    pub changelog: bool,
+    pub jobs: usize,
+    /// Do not start new jobs while the load average is at or above this
+    pub load_average: Option<f64>,
+    /// ROOT: where packages are merged (--root)
+    pub root: String,
+    /// SYSROOT: where build dependencies are found (--sysroot)
+    pub sysroot: String,
+    /// PORTAGE_CONFIGROOT: where etc/portage is read from (--config-root)
+    pub config_root: String,
+    pub with_bdeps: bool,
+    /// Pull in test-only dependencies even without FEATURES=test
+    pub with_test_deps: bool,
+    pub oneshot: bool,
+    /// Continue with unaffected packages after a build failure
+    pub keep_going: bool,
+    pub deep: bool,
+    pub newuse: bool,
+    /// Treat nothing as installed: rebuild the whole dependency tree of the targets
+    pub emptytree: bool,
+    /// Merge the targets without their dependencies (--nodeps)
+    pub nodeps: bool,
+    /// Merge only the dependencies of the targets (--onlydeps)
+    pub onlydeps: bool,
+    /// --exclude: packages never merged in this run
+    pub exclude: Vec<String>,
+    /// --reinstall-atoms: packages merged even when already installed
+    pub reinstall_atoms: Vec<String>,
+    /// --backtrack: how often the resolver may start over to get around a conflict
+    pub backtrack: usize,
+    /// Write autounmask changes to /etc/portage/package.* instead of only showing them
+    pub autounmask_write: bool,
+    /// Only download the distfiles of the merge list
+    pub fetchonly: bool,
+    /// With --fetchonly, fetch SRC_URI entries for every USE combination
+    pub fetch_all_uri: bool,
+    /// Parallel distfile downloads
+    pub fetch_jobs: usize,
+    /// Ask servers for the sizes of distfiles the Manifest doesn't list (--remote-sizes)
+    pub remote_sizes: bool,
+    /// Binary package sources: --usepkg, --usepkgonly, --getbinpkg
+    pub binpkg_policy: crate::bintree::BinPkgPolicy,
+    /// Merge without the emerge instance and package database locks
+    pub nolock: bool,
+    /// With --pretend, print the merge plan as JSON (--json)
+    pub json: bool,
+}
+
+impl Default for EmergeOptions {
+    fn default() -> Self {
+        EmergeOptions {
+            pretend: false,
+            ask: false,
+            resume: false,
+            skipfirst: false,
+            tree: false,
+            verbose: false,
+            changelog: false,
+            jobs: 1,
+            load_average: None,
+            root: "/".to_string(),
+            sysroot: "/".to_string(),
+            config_root: "/".to_string(),
+            with_bdeps: false,
+            with_test_deps: false,
+            oneshot: false,
+            keep_going: false,
+            deep: false,
+            newuse: false,
+            emptytree: false,
+            nodeps: false,
+            onlydeps: false,
+            exclude: Vec::new(),
+            reinstall_atoms: Vec::new(),
+            backtrack: crate::depgraph::DEFAULT_BACKTRACK,
+            autounmask_write: false,
+            fetchonly: false,
+            fetch_all_uri: false,
+            fetch_jobs: 3,
+            remote_sizes: false,
+            binpkg_policy: crate::bintree::BinPkgPolicy::default(),
+            nolock: false,
+            json: false,
+        }
+    }
+}
+
+/// Command line options for --search and --searchdesc
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    /// Also match package descriptions (--searchdesc)
+    pub search_description: bool,
+    pub fuzzy: bool,
+    /// Treat patterns containing regex characters as regular expressions
+    pub regex_auto: bool,
+    /// ROOT: whose installed versions are shown
+    pub root: String,
+    /// PORTAGE_CONFIGROOT: whose repositories are searched, with the index sync keeps there
+    pub config_root: String,
+    /// Print the results as JSON (--json)
+    pub json: bool,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            search_description: false,
+            fuzzy: true,
+            regex_auto: true,
+            root: "/".to_string(),
+            config_root: "/".to_string(),
+            json: false,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// emergelog.rs -- /var/log/emerge.log in portage's format, for genlop, qlop and the other
+// tools that read merge and sync history from it
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The log, once a command that changes the system has enabled it
+static LOG: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The directory emerge.log is kept in: EMERGE_LOG_DIR, else /var/log
+pub fn log_dir() -> PathBuf {
+    std::env::var("EMERGE_LOG_DIR").ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/var/log"))
+}
+
+/// Log to emerge.log in `dir` from now on; until then nothing is logged, as for --pretend
+pub fn enable(dir: &Path) {
+    *LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(dir.join("emerge.log"));
+}
+
+/// A log line: the Unix time, then the message
+fn line(timestamp: i64, message: &str) -> String {
+    format!("{}: {}\n", timestamp, message)
+}
+
+/// Append `message` to the log at `path`
+pub fn append(path: &Path, message: &str) -> std::io::Result<()> {
+    let mut log = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    log.write_all(line(chrono::Utc::now().timestamp(), message).as_bytes())
+}
+
+/// Log `message` if logging is enabled; like portage, a log that cannot be written is
+/// not worth failing over
+pub fn emergelog(message: &str) {
+    let path = LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    if let Some(path) = path {
+        let _ = append(&path, message);
+    }
+}
+
+fn started_message(now: chrono::DateTime<chrono::Local>) -> String {
+    format!("Started emerge on: {}", now.format("%b %d, %Y %H:%M:%S"))
+}
+
+/// Log the start of an emerge run with the command line `args`
+pub fn start(args: &[String]) {
+    emergelog(&started_message(chrono::Local::now()));
+    emergelog(&format!(" *** emerge {}", args.join(" ")));
+}
+
+/// Log the end of an emerge run that exits with `status`
+pub fn finish(status: i32) {
+    if status == 0 {
+        emergelog(" *** exiting successfully.");
+    } else {
+        emergelog(&format!(" *** exiting unsuccessfully with status '{}'.", status));
+    }
+    emergelog(" *** terminating.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(line(1700000000, " >>> emerge (1 of 2) app-misc/foo-1.0 to /"), "1700000000:  >>> emerge (1 of 2) app-misc/foo-1.0 to /\n");
+        let now = chrono::Local.with_ymd_and_hms(2026, 3, 7, 9, 5, 1).unwrap();
+        assert_eq!(started_message(now), "Started emerge on: Mar 07, 2026 09:05:01");
+
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("emerge.log");
+        append(&path, " >>> emerge (1 of 1) app-misc/foo-1.0 to /").unwrap();
+        append(&path, " ::: completed emerge (1 of 1) app-misc/foo-1.0 to /").unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let (timestamp, message) = lines[1].split_once(": ").unwrap();
+        assert!(timestamp.parse::<i64>().is_ok());
+        assert_eq!(message, " ::: completed emerge (1 of 1) app-misc/foo-1.0 to /");
+    }
+}
@@ -0,0 +1,380 @@
+// estrip.rs -- Strip ELF files in the image and split off their debug info (FEATURES=splitdebug)
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::exception::InvalidData;
+use crate::toolchain::Toolchain;
+
+/// PORTAGE_STRIP_FLAGS default
+const DEFAULT_STRIP_FLAGS: &str = "--strip-unneeded -R .comment -R .GCC.command.line -R .note.gnu.gold-version";
+
+/// How the image gets stripped
+#[derive(Debug, Clone)]
+pub struct StripSettings {
+    /// strip program, ${STRIP} or ${CHOST}-strip as toolchain-funcs picks it
+    pub strip: String,
+    pub objcopy: String,
+    pub strip_flags: Vec<String>,
+    /// Keep the debug info in /usr/lib/debug (FEATURES=splitdebug)
+    pub splitdebug: bool,
+    /// Also install the sources the debug info refers to (FEATURES=installsources)
+    pub installsources: bool,
+    /// Only strip these paths (dostrip under RESTRICT=strip); None strips everything
+    pub include: Option<Vec<String>>,
+    /// dostrip -x paths and STRIP_MASK patterns left alone
+    pub exclude: Vec<String>,
+    /// Where installsources puts the sources, /usr/src/debug/${CATEGORY}/${PF}
+    pub source_dir: String,
+}
+
+impl StripSettings {
+    /// Settings from the build environment; `tempdir` holds the dostrip lists
+    pub fn from_env(env: &HashMap<String, String>, features: &[String], tempdir: &Path, restricted: bool, cpv: &str) -> Self {
+        let has = |feature: &str| features.iter().any(|f| f == feature);
+        let read_list = |name: &str| -> Vec<String> {
+            fs::read_to_string(tempdir.join(name))
+                .map(|content| content.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect())
+                .unwrap_or_default()
+        };
+
+        let mut exclude = read_list(".dostrip_exclude");
+        if let Some(mask) = env.get("STRIP_MASK") {
+            exclude.extend(mask.split_whitespace().map(|m| m.to_string()));
+        }
+        let toolchain = Toolchain::from_env(env);
+        StripSettings {
+            strip: toolchain.tool(env, "STRIP", "strip"),
+            objcopy: toolchain.tool(env, "OBJCOPY", "objcopy"),
+            strip_flags: env.get("PORTAGE_STRIP_FLAGS")
+                .map(|f| f.as_str())
+                .unwrap_or(DEFAULT_STRIP_FLAGS)
+                .split_whitespace()
+                .map(|f| f.to_string())
+                .collect(),
+            splitdebug: has("splitdebug"),
+            installsources: has("installsources"),
+            include: restricted.then(|| read_list(".dostrip_include")),
+            exclude,
+            source_dir: format!("/usr/src/debug/{}", cpv),
+        }
+    }
+
+    /// Whether the installed `path` should be stripped
+    pub fn selected(&self, path: &str) -> bool {
+        let matches = |pattern: &String| glob_match(pattern, path) || glob_match(&format!("{}/*", pattern.trim_end_matches('/')), path);
+        if let Some(include) = &self.include
+            && !include.iter().any(matches)
+        {
+            return false;
+        }
+        !self.exclude.iter().any(matches)
+    }
+}
+
+/// Shell-style match of `*` and `?`, where `*` also crosses `/` as in [[ == ]]
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// ELF executables and shared objects (ET_EXEC/ET_DYN)
+pub fn is_strippable(path: &Path) -> bool {
+    use std::io::Read;
+    let mut header = [0u8; 18];
+    let Ok(mut file) = fs::File::open(path) else {
+        return false;
+    };
+    if file.read_exact(&mut header).is_err() || &header[..4] != b"\x7fELF" {
+        return false;
+    }
+    let e_type = if header[5] == 2 { u16::from_be_bytes([header[16], header[17]]) } else { u16::from_le_bytes([header[16], header[17]]) };
+    e_type == 2 || e_type == 3
+}
+
+fn run(program: &str, args: &[&std::ffi::OsStr]) -> Result<(), String> {
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Err(e) => Err(format!("{}: {}", program, e)),
+    }
+}
+
+fn regular_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+    entries.sort();
+    for path in entries {
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            regular_files(&path, files);
+        } else if metadata.is_file() {
+            files.push(path);
+        }
+    }
+}
+
+/// Strip the ELF files in `destdir`, returning their installed paths. With splitdebug the
+/// debug info goes to /usr/lib/debug/<path>.debug, linked from .build-id/xx/yyyy.debug
+pub fn strip_image(destdir: &Path, workdir: &Path, tempdir: &Path, settings: &StripSettings) -> Result<Vec<String>, InvalidData> {
+    if let Err(e) = run(&settings.strip, &["--version".as_ref()]) {
+        eprintln!("Warning: {} is not available: {}", settings.strip, e);
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    regular_files(destdir, &mut files);
+    let debug_root = destdir.join("usr/lib/debug");
+    let sources_list = tempdir.join("debug.sources");
+    let debugedit = settings.installsources && settings.splitdebug && run("debugedit", &["--help".as_ref()]).is_ok();
+    if settings.installsources && settings.splitdebug && !debugedit {
+        eprintln!(" * installsources: debugedit not found, not installing sources");
+    }
+
+    let mut seen = HashSet::new();
+    let mut stripped = Vec::new();
+    for file in files {
+        if file.starts_with(&debug_root) || !is_strippable(&file) {
+            continue;
+        }
+        let installed = format!("/{}", file.strip_prefix(destdir).unwrap_or(&file).display());
+        if !settings.selected(&installed) {
+            continue;
+        }
+        // Hardlinks are stripped once
+        let Ok(metadata) = fs::metadata(&file) else {
+            continue;
+        };
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+
+        if settings.splitdebug {
+            if debugedit {
+                let result = run("debugedit", &[
+                    "-b".as_ref(), workdir.as_os_str(),
+                    "-d".as_ref(), settings.source_dir.as_ref(),
+                    "-l".as_ref(), sources_list.as_os_str(),
+                    file.as_os_str(),
+                ]);
+                if let Err(e) = result {
+                    eprintln!(" * debugedit failed on {}: {}", installed, e);
+                }
+            }
+            split_debug(&file, &installed, destdir, settings)?;
+        }
+
+        let mut args: Vec<&std::ffi::OsStr> = settings.strip_flags.iter().map(|f| f.as_ref()).collect();
+        args.push(file.as_os_str());
+        if let Err(e) = run(&settings.strip, &args) {
+            eprintln!("Warning: failed to strip {}: {}", installed, e);
+            continue;
+        }
+        if settings.splitdebug {
+            let debug_file = debug_root.join(format!("{}.debug", installed.trim_start_matches('/')));
+            let link = format!("--add-gnu-debuglink={}", debug_file.display());
+            if let Err(e) = run(&settings.objcopy, &[link.as_ref(), file.as_os_str()]) {
+                eprintln!("Warning: failed to add debuglink to {}: {}", installed, e);
+            }
+        }
+        stripped.push(installed);
+    }
+
+    if debugedit {
+        install_sources(&sources_list, workdir, &destdir.join(settings.source_dir.trim_start_matches('/')))?;
+    }
+    Ok(stripped)
+}
+
+/// Keep the debug info of `file` in /usr/lib/debug and link it by build-id
+fn split_debug(file: &Path, installed: &str, destdir: &Path, settings: &StripSettings) -> Result<(), InvalidData> {
+    let debug_installed = PathBuf::from(format!("/usr/lib/debug{}.debug", installed));
+    let debug_file = destdir.join(debug_installed.strip_prefix("/").unwrap_or(&debug_installed));
+    if let Some(parent) = debug_file.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+    }
+    if let Err(e) = run(&settings.objcopy, &["--only-keep-debug".as_ref(), file.as_os_str(), debug_file.as_os_str()]) {
+        eprintln!("Warning: failed to split debug info from {}: {}", installed, e);
+        return Ok(());
+    }
+
+    let Some(build_id) = fs::read(file).ok().and_then(|data| crate::elf::build_id(&data)) else {
+        return Ok(());
+    };
+    if build_id.len() < 3 {
+        return Ok(());
+    }
+    let link_dir = PathBuf::from(format!("/usr/lib/debug/.build-id/{}", &build_id[..2]));
+    let image_link_dir = destdir.join(link_dir.strip_prefix("/").unwrap_or(&link_dir));
+    fs::create_dir_all(&image_link_dir)
+        .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", image_link_dir.display(), e), None))?;
+    for (name, target) in [(format!("{}.debug", &build_id[2..]), debug_installed.as_path()), (build_id[2..].to_string(), Path::new(installed))] {
+        let link = image_link_dir.join(name);
+        // Another file with the same build-id already owns the link
+        if fs::symlink_metadata(&link).is_ok() {
+            continue;
+        }
+        let relative = pathdiff::diff_paths(target, &link_dir).unwrap_or_else(|| target.to_path_buf());
+        std::os::unix::fs::symlink(&relative, &link)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", link.display(), e), None))?;
+    }
+    Ok(())
+}
+
+/// Copy the sources debugedit listed (NUL separated, relative to `workdir`) into `target`
+fn install_sources(sources_list: &Path, workdir: &Path, target: &Path) -> Result<(), InvalidData> {
+    let Ok(list) = fs::read(sources_list) else {
+        return Ok(());
+    };
+    let sources: HashSet<String> = list.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .collect();
+    for source in sources {
+        let from = workdir.join(&source);
+        if !from.is_file() || source.split('/').any(|part| part == "..") {
+            continue;
+        }
+        let to = target.join(&source);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+        }
+        fs::copy(&from, &to)
+            .map_err(|e| InvalidData::new(&format!("Failed to install source {}: {}", source, e), None))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn settings(include: Option<&[&str]>, exclude: &[&str]) -> StripSettings {
+        let list = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        StripSettings {
+            strip: "strip".to_string(),
+            objcopy: "objcopy".to_string(),
+            strip_flags: vec!["--strip-unneeded".to_string()],
+            splitdebug: false,
+            installsources: false,
+            include: include.map(list),
+            exclude: list(exclude),
+            source_dir: "/usr/src/debug/app-misc/foo-1.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_is_strippable() {
+        let dir = TempDir::new().unwrap();
+        let lib = dir.path().join("libfoo.so");
+        fs::write(&lib, crate::elf::tests::build_elf64(Some("libfoo.so"), &[])).unwrap();
+        assert!(is_strippable(&lib));
+
+        let script = dir.path().join("foo-config");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        assert!(!is_strippable(&script));
+    }
+
+    #[test]
+    fn test_selected() {
+        let s = settings(None, &["/usr/lib/firmware", "*/libkeep.so*"]);
+        assert!(s.selected("/usr/bin/foo"));
+        assert!(!s.selected("/usr/lib/firmware/blob.bin"));
+        assert!(!s.selected("/usr/lib64/libkeep.so.1"));
+        assert!(s.selected("/usr/lib/firmware2/blob.bin"));
+
+        // RESTRICT=strip with dostrip /usr/bin/foo
+        let s = settings(Some(&["/usr/bin/foo"]), &[]);
+        assert!(s.selected("/usr/bin/foo"));
+        assert!(!s.selected("/usr/bin/bar"));
+    }
+
+    #[test]
+    fn test_from_env() {
+        let temp = TempDir::new().unwrap();
+        fs::write(temp.path().join(".dostrip_exclude"), "/opt/foo\n").unwrap();
+        fs::write(temp.path().join(".dostrip_include"), "/usr/bin/foo\n").unwrap();
+        let bin = temp.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        fs::write(bin.join("x86_64-pc-linux-gnu-strip"), "").unwrap();
+
+        let mut env = HashMap::from([
+            ("CHOST".to_string(), "x86_64-pc-linux-gnu".to_string()),
+            ("PATH".to_string(), bin.display().to_string()),
+            ("STRIP_MASK".to_string(), "*.ko".to_string()),
+        ]);
+        let features = vec!["splitdebug".to_string()];
+        let s = StripSettings::from_env(&env, &features, temp.path(), false, "app-misc/foo-1.0");
+        assert_eq!(s.strip, "x86_64-pc-linux-gnu-strip");
+        assert_eq!(s.objcopy, "objcopy");
+        assert_eq!(s.exclude, vec!["/opt/foo", "*.ko"]);
+        assert_eq!(s.include, None);
+        assert!(s.splitdebug && !s.installsources);
+        assert_eq!(s.source_dir, "/usr/src/debug/app-misc/foo-1.0");
+
+        env.insert("STRIP".to_string(), "llvm-strip".to_string());
+        let s = StripSettings::from_env(&env, &features, temp.path(), true, "app-misc/foo-1.0");
+        assert_eq!(s.strip, "llvm-strip");
+        assert_eq!(s.include, Some(vec!["/usr/bin/foo".to_string()]));
+    }
+
+    #[test]
+    fn test_strip_image_splitdebug() {
+        // Needs binutils and a compiler to produce a binary with debug info and a build-id
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("hello.c");
+        fs::write(&source, "int main(void) { return 0; }\n").unwrap();
+        let image = temp.path().join("image");
+        fs::create_dir_all(image.join("usr/bin")).unwrap();
+        let binary = image.join("usr/bin/hello");
+        let compiled = Command::new("cc")
+            .args(["-g", "-Wl,--build-id=sha1", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status()
+            .is_ok_and(|s| s.success());
+        if !compiled || run("objcopy", &["--version".as_ref()]).is_err() {
+            return;
+        }
+        let build_id = crate::elf::build_id(&fs::read(&binary).unwrap()).unwrap();
+        let unstripped = fs::metadata(&binary).unwrap().len();
+
+        let mut s = settings(None, &[]);
+        s.splitdebug = true;
+        let stripped = strip_image(&image, temp.path(), temp.path(), &s).unwrap();
+        assert_eq!(stripped, vec!["/usr/bin/hello"]);
+
+        let debug_file = image.join("usr/lib/debug/usr/bin/hello.debug");
+        assert!(debug_file.is_file());
+        assert!(fs::metadata(&binary).unwrap().len() < unstripped);
+        let link_dir = image.join("usr/lib/debug/.build-id").join(&build_id[..2]);
+        assert_eq!(fs::read_link(link_dir.join(format!("{}.debug", &build_id[2..]))).unwrap(), Path::new("../../usr/bin/hello.debug"));
+        assert_eq!(fs::read_link(link_dir.join(&build_id[2..])).unwrap(), Path::new("../../../../bin/hello"));
+        assert!(link_dir.join(format!("{}.debug", &build_id[2..])).is_file());
+    }
+}
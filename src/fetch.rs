@@ -0,0 +1,541 @@
+// fetch.rs -- Distfile fetching: GENTOO_MIRRORS rotation and layouts, mirror:// through
+// thirdpartymirrors, resume, locking and parallel downloads
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::exception::InvalidData;
+use crate::locks::LockFile;
+use crate::manifest::ManifestEntry;
+
+/// Mirrors that failed this many times are no longer tried
+pub const MAX_MIRROR_FAILURES: usize = 3;
+
+/// curl: "HTTP server doesn't seem to support byte ranges"
+const CURL_RANGE_ERROR: i32 = 33;
+
+/// Seconds a HEAD request for the size of a distfile may take
+const HEAD_TIMEOUT_SECS: u32 = 5;
+
+/// Sizes servers gave for URLs this run, None where they gave none
+static REMOTE_SIZES: Mutex<BTreeMap<String, Option<u64>>> = Mutex::new(BTreeMap::new());
+
+/// One distfile to fetch
+#[derive(Debug, Clone)]
+pub struct FetchRequest {
+    pub filename: String,
+    /// Upstream SRC_URI locations
+    pub uris: Vec<String>,
+    /// False with RESTRICT=mirror
+    pub use_mirrors: bool,
+    /// RESTRICT=primaryuri: upstream before GENTOO_MIRRORS
+    pub primaryuri: bool,
+    /// DIST entry to verify against, if the Manifest has one
+    pub manifest: Option<ManifestEntry>,
+}
+
+/// How a mirror arranges its distfiles, from the layout.conf in its distfiles directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MirrorLayout {
+    Flat,
+    /// Directories named after the leading bits of a hash of the filename, one level per cutoff
+    FilenameHash { algorithm: String, cutoffs: Vec<usize> },
+}
+
+impl MirrorLayout {
+    /// The first structure of a layout.conf that is understood, flat without one
+    pub fn parse(content: &str) -> Self {
+        let mut structures: Vec<(usize, &str)> = Vec::new();
+        let mut in_structure = false;
+        for line in content.lines().map(|line| line.trim()) {
+            if line.starts_with('[') {
+                in_structure = line == "[structure]";
+            } else if in_structure
+                && let Some((index, value)) = line.split_once('=')
+                && let Ok(index) = index.trim().parse()
+            {
+                structures.push((index, value.trim()));
+            }
+        }
+        structures.sort();
+        for (_, structure) in structures {
+            let words: Vec<&str> = structure.split_whitespace().collect();
+            match words.as_slice() {
+                ["flat"] => return MirrorLayout::Flat,
+                ["filename-hash", algorithm, cutoffs] if crate::checksum::hash_bytes(b"", algorithm).is_some() => {
+                    let cutoffs: Option<Vec<usize>> = cutoffs.split(':').map(|c| c.parse().ok().filter(|c| c % 4 == 0 && *c > 0)).collect();
+                    if let Some(cutoffs) = cutoffs {
+                        return MirrorLayout::FilenameHash { algorithm: algorithm.to_string(), cutoffs };
+                    }
+                }
+                _ => {}
+            }
+        }
+        MirrorLayout::Flat
+    }
+
+    /// Where `filename` is under the mirror's distfiles directory
+    pub fn path(&self, filename: &str) -> String {
+        match self {
+            MirrorLayout::Flat => filename.to_string(),
+            MirrorLayout::FilenameHash { algorithm, cutoffs } => {
+                let hash = crate::checksum::hash_bytes(filename.as_bytes(), algorithm).unwrap_or_default();
+                let mut rest = hash.as_str();
+                let mut path = String::new();
+                for cutoff in cutoffs {
+                    let (dir, remaining) = rest.split_at((cutoff / 4).min(rest.len()));
+                    path.push_str(dir);
+                    path.push('/');
+                    rest = remaining;
+                }
+                path.push_str(filename);
+                path
+            }
+        }
+    }
+}
+
+/// profiles/thirdpartymirrors of a repository: the base URLs of each mirror:// name
+pub fn load_thirdpartymirrors(repo: &Path) -> HashMap<String, Vec<String>> {
+    let content = std::fs::read_to_string(repo.join("profiles/thirdpartymirrors")).unwrap_or_default();
+    content.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let name = words.next()?;
+            Some((name.to_string(), words.map(|url| url.trim_end_matches('/').to_string()).collect()))
+        })
+        .collect()
+}
+
+pub struct FetchManager {
+    pub distdir: PathBuf,
+    pub mirrors: Vec<String>,
+    /// mirror:// names and their base URLs
+    pub thirdpartymirrors: HashMap<String, Vec<String>>,
+    pub jobs: usize,
+    /// FEATURES=distlocks: hold a lock on each distfile while fetching it
+    pub distlocks: bool,
+    failures: Mutex<HashMap<String, usize>>,
+    rotation: AtomicUsize,
+    /// Layouts of the GENTOO_MIRRORS, once known
+    layouts: Mutex<HashMap<String, MirrorLayout>>,
+}
+
+impl FetchManager {
+    pub fn new(distdir: &Path, mirrors: Vec<String>) -> Self {
+        FetchManager {
+            distdir: distdir.to_path_buf(),
+            mirrors: mirrors.into_iter().map(|m| m.trim_end_matches('/').to_string()).collect(),
+            jobs: 1,
+            distlocks: false,
+            thirdpartymirrors: HashMap::new(),
+            failures: Mutex::new(HashMap::new()),
+            rotation: AtomicUsize::new(0),
+            layouts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve mirror:// with the thirdpartymirrors of these repositories; a name in
+    /// several gets the URLs of all of them
+    pub fn with_thirdpartymirrors(mut self, repos: &[PathBuf]) -> Self {
+        for repo in repos {
+            for (name, urls) in load_thirdpartymirrors(repo) {
+                let known = self.thirdpartymirrors.entry(name).or_default();
+                for url in urls {
+                    if !known.contains(&url) {
+                        known.push(url);
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    pub fn with_mirror_layout(self, mirror: &str, layout: MirrorLayout) -> Self {
+        self.layouts.lock().unwrap().insert(mirror.trim_end_matches('/').to_string(), layout);
+        self
+    }
+
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    pub fn with_distlocks(mut self, distlocks: bool) -> Self {
+        self.distlocks = distlocks;
+        self
+    }
+
+    /// Failures recorded against a mirror so far
+    pub fn mirror_failures(&self, mirror: &str) -> usize {
+        self.failures.lock().unwrap().get(mirror).copied().unwrap_or(0)
+    }
+
+    fn record_failure(&self, url: &str) {
+        if let Some(mirror) = self.mirrors.iter().find(|m| url.starts_with(m.as_str())) {
+            *self.failures.lock().unwrap().entry(mirror.clone()).or_default() += 1;
+        }
+    }
+
+    /// `{mirror}/distfiles/{path}`, following the mirror's layout when it is known
+    fn mirror_url(&self, mirror: &str, path: &str) -> String {
+        let layout = self.layouts.lock().unwrap().get(mirror).cloned().unwrap_or(MirrorLayout::Flat);
+        format!("{}/distfiles/{}", mirror, layout.path(path))
+    }
+
+    /// The URLs an upstream URI stands for: mirror://name/path at each of the name's
+    /// thirdpartymirrors, mirror://gentoo/ at GENTOO_MIRRORS; none for an unknown name
+    pub fn expand_uri(&self, uri: &str) -> Vec<String> {
+        let Some((name, path)) = uri.strip_prefix("mirror://").and_then(|rest| rest.split_once('/')) else {
+            return vec![uri.to_string()];
+        };
+        if name == "gentoo" {
+            return self.mirrors.iter().map(|mirror| self.mirror_url(mirror, path)).collect();
+        }
+        self.thirdpartymirrors.get(name)
+            .map(|urls| urls.iter().map(|url| format!("{}/{}", url, path)).collect())
+            .unwrap_or_default()
+    }
+
+    /// URLs to try in order: healthy mirrors, rotated so parallel fetches spread
+    /// across them and least-failed first, then the upstream URIs with mirror://
+    /// resolved. RESTRICT=primaryuri puts upstream first
+    pub fn candidate_urls(&self, request: &FetchRequest) -> Vec<String> {
+        let mut mirror_urls = Vec::new();
+        if request.use_mirrors && !self.mirrors.is_empty() {
+            let start = self.rotation.fetch_add(1, Ordering::Relaxed) % self.mirrors.len();
+            let mut mirrors: Vec<&String> = self.mirrors.iter().cycle().skip(start).take(self.mirrors.len()).collect();
+            mirrors.sort_by_key(|m| self.mirror_failures(m));
+            mirror_urls.extend(mirrors.into_iter()
+                .filter(|m| self.mirror_failures(m) < MAX_MIRROR_FAILURES)
+                .map(|m| self.mirror_url(m, &request.filename)));
+        }
+        let upstream_urls: Vec<String> = request.uris.iter().flat_map(|uri| self.expand_uri(uri)).collect();
+        let (first, second) = if request.primaryuri { (upstream_urls, mirror_urls) } else { (mirror_urls, upstream_urls) };
+        let mut urls: Vec<String> = Vec::new();
+        for url in first.into_iter().chain(second) {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+
+    /// Learn the layout of each GENTOO_MIRRORS entry from its layout.conf, once; a mirror
+    /// without one is flat
+    async fn load_layouts(&self) {
+        for mirror in &self.mirrors {
+            if self.layouts.lock().unwrap().contains_key(mirror) {
+                continue;
+            }
+            let url = format!("{}/distfiles/layout.conf", mirror);
+            let layout = match crate::signals::output(tokio::process::Command::new("curl")
+                .args(["--fail", "--location", "--silent", "--max-time", "30"]).arg(&url)).await
+            {
+                Ok(output) if output.status.success() => MirrorLayout::parse(&String::from_utf8_lossy(&output.stdout)),
+                _ => MirrorLayout::Flat,
+            };
+            self.layouts.lock().unwrap().insert(mirror.clone(), layout);
+        }
+    }
+
+    /// Fetch one distfile into DISTDIR, resuming a partial download if there is one
+    pub async fn fetch(&self, request: &FetchRequest) -> Result<PathBuf, InvalidData> {
+        std::fs::create_dir_all(&self.distdir)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", self.distdir.display(), e), None))?;
+        let _lock = if self.distlocks { Some(DistfileLock::acquire(&self.distdir, &request.filename).await?) } else { None };
+
+        let path = self.distdir.join(&request.filename);
+        if path.exists() {
+            match &request.manifest {
+                Some(entry) => match crate::manifest::verify_distfile(&path, entry) {
+                    Ok(()) => return Ok(path),
+                    Err(e) => {
+                        eprintln!("!!! {}; fetching again", e);
+                        // Keep a short file around so the download resumes from it
+                        let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                        let partial = self.distdir.join(format!("{}.__download__", request.filename));
+                        if size < entry.size {
+                            let _ = std::fs::rename(&path, &partial);
+                        } else {
+                            let _ = std::fs::remove_file(&path);
+                        }
+                    }
+                },
+                None => return Ok(path),
+            }
+        }
+
+        if request.use_mirrors || request.uris.iter().any(|uri| uri.starts_with("mirror://gentoo/")) {
+            self.load_layouts().await;
+        }
+        let partial = self.distdir.join(format!("{}.__download__", request.filename));
+        for url in self.candidate_urls(request) {
+            println!(">>> Downloading '{}'", url);
+            if let Err(e) = download(&url, &partial).await {
+                eprintln!("!!! Couldn't download '{}': {}", url, e);
+                self.record_failure(&url);
+                continue;
+            }
+            std::fs::rename(&partial, &path)
+                .map_err(|e| InvalidData::new(&format!("Failed to move {} into place: {}", request.filename, e), None))?;
+            if let Some(entry) = &request.manifest
+                && let Err(e) = crate::manifest::verify_distfile(&path, entry)
+            {
+                eprintln!("!!! {}", e);
+                let _ = std::fs::remove_file(&path);
+                self.record_failure(&url);
+                continue;
+            }
+            return Ok(path);
+        }
+        Err(InvalidData::new(&format!("Couldn't download '{}'. Aborting.", request.filename), None))
+    }
+
+    /// Fetch many distfiles, at most `jobs` at a time
+    pub async fn fetch_all(self: &Arc<Self>, requests: Vec<FetchRequest>) -> Vec<(String, Result<PathBuf, InvalidData>)> {
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(self.jobs));
+        let mut tasks = tokio::task::JoinSet::new();
+        for (index, request) in requests.into_iter().enumerate() {
+            let manager = self.clone();
+            let semaphore = semaphore.clone();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = manager.fetch(&request).await;
+                (index, request.filename, result)
+            });
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(e) => results.push((usize::MAX, String::new(), Err(InvalidData::new(&format!("Fetch task failed: {}", e), None)))),
+            }
+        }
+        results.sort_by_key(|(index, _, _)| *index);
+        results.into_iter().map(|(_, filename, result)| (filename, result)).collect()
+    }
+}
+
+/// The Content-Length of HEAD response headers; after redirects, that of the last response
+pub fn content_length(headers: &str) -> Option<u64> {
+    headers.lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .filter_map(|(_, value)| value.trim().parse().ok())
+        .next_back()
+}
+
+/// The size of the file at `url` as a HEAD request reports it, asked once per run
+pub async fn remote_size(url: &str) -> Option<u64> {
+    if let Some(size) = REMOTE_SIZES.lock().unwrap().get(url) {
+        return *size;
+    }
+    let output = crate::signals::output(tokio::process::Command::new("curl")
+        .args(["--head", "--fail", "--location", "--silent", "--max-time", &HEAD_TIMEOUT_SECS.to_string()])
+        .arg(url))
+        .await;
+    let size = match output {
+        Ok(output) if output.status.success() => content_length(&String::from_utf8_lossy(&output.stdout)),
+        _ => None,
+    };
+    REMOTE_SIZES.lock().unwrap().insert(url.to_string(), size);
+    size
+}
+
+/// The sizes of distfiles from HEAD requests, at most `jobs` at a time: for each list of
+/// URLs of one file, the size the first to answer with one gives
+pub async fn remote_sizes(files: Vec<Vec<String>>, jobs: usize) -> Vec<Option<u64>> {
+    let mut sizes = vec![None; files.len()];
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(jobs.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, urls) in files.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            for url in &urls {
+                if let Some(size) = remote_size(url).await {
+                    return (index, Some(size));
+                }
+            }
+            (index, None)
+        });
+    }
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok((index, size)) = joined {
+            sizes[index] = size;
+        }
+    }
+    sizes
+}
+
+/// Exclusive lock on DISTDIR/.locks/<file>.portage_lockfile, released on drop
+pub struct DistfileLock {
+    _lock: LockFile,
+    _cleanup: crate::signals::RemoveOnInterrupt,
+}
+
+impl DistfileLock {
+    pub async fn acquire(distdir: &Path, filename: &str) -> Result<Self, InvalidData> {
+        let lock_path = distdir.join(".locks").join(format!("{}.portage_lockfile", filename));
+        // Another emerge may be fetching the same file
+        let lock = LockFile::acquire(&lock_path, &format!(">>> Waiting for lock on {}", filename)).await?;
+        Ok(DistfileLock { _cleanup: crate::signals::RemoveOnInterrupt::register(&lock_path), _lock: lock })
+    }
+}
+
+/// Download with curl, continuing from the end of `dest` if it already has data
+pub async fn download(url: &str, dest: &Path) -> Result<(), String> {
+    for attempt in 0..2 {
+        let output = crate::signals::output(tokio::process::Command::new("curl")
+            .args(["--fail", "--location", "--silent", "--show-error", "--continue-at", "-", "--output"])
+            .arg(dest)
+            .arg(url))
+            .await
+            .map_err(|e| format!("failed to run curl: {}", e))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        // Servers without range support: start over once
+        if attempt == 0 && output.status.code() == Some(CURL_RANGE_ERROR) {
+            let _ = std::fs::remove_file(dest);
+            continue;
+        }
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Err("download restarted without success".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn request(use_mirrors: bool) -> FetchRequest {
+        FetchRequest {
+            filename: "foo-1.0.tar.gz".to_string(),
+            uris: vec!["https://example.org/foo-1.0.tar.gz".to_string()],
+            use_mirrors,
+            primaryuri: false,
+            manifest: None,
+        }
+    }
+
+    #[test]
+    fn test_mirror_rotation_and_failures() {
+        let distdir = TempDir::new().unwrap();
+        let manager = FetchManager::new(distdir.path(), vec!["https://a.example/gentoo/".to_string(), "https://b.example/gentoo".to_string()]);
+
+        assert_eq!(manager.candidate_urls(&request(true)), vec![
+            "https://a.example/gentoo/distfiles/foo-1.0.tar.gz",
+            "https://b.example/gentoo/distfiles/foo-1.0.tar.gz",
+            "https://example.org/foo-1.0.tar.gz",
+        ]);
+        // The next fetch starts at the other mirror
+        assert_eq!(manager.candidate_urls(&request(true))[0], "https://b.example/gentoo/distfiles/foo-1.0.tar.gz");
+        assert_eq!(manager.candidate_urls(&request(false)), vec!["https://example.org/foo-1.0.tar.gz"]);
+
+        manager.record_failure("https://b.example/gentoo/distfiles/foo-1.0.tar.gz");
+        assert_eq!(manager.mirror_failures("https://b.example/gentoo"), 1);
+        assert_eq!(manager.candidate_urls(&request(true))[0], "https://a.example/gentoo/distfiles/foo-1.0.tar.gz");
+
+        for _ in 0..MAX_MIRROR_FAILURES {
+            manager.record_failure("https://a.example/gentoo/distfiles/bar.tar.gz");
+        }
+        assert_eq!(manager.candidate_urls(&request(true)), vec![
+            "https://b.example/gentoo/distfiles/foo-1.0.tar.gz",
+            "https://example.org/foo-1.0.tar.gz",
+        ]);
+    }
+
+    #[test]
+    fn test_thirdpartymirrors_and_layouts() {
+        let repo = TempDir::new().unwrap();
+        std::fs::create_dir_all(repo.path().join("profiles")).unwrap();
+        std::fs::write(repo.path().join("profiles/thirdpartymirrors"),
+            "# mirror://name base URLs\ngnu https://ftp.gnu.org/gnu https://mirrors.example/gnu/\nsourceforge https://downloads.sourceforge.net\n").unwrap();
+        let layout = MirrorLayout::parse("[structure]\n1=flat\n0=filename-hash BLAKE2B 8\n");
+        let hash = crate::checksum::hash_bytes(b"foo-1.0.tar.gz", "BLAKE2B").unwrap();
+        assert_eq!(layout.path("foo-1.0.tar.gz"), format!("{}/foo-1.0.tar.gz", &hash[..2]));
+        assert_eq!(MirrorLayout::parse("[structure]\n0=filename-hash FOO 8\n1=flat\n"), MirrorLayout::Flat);
+
+        let distdir = TempDir::new().unwrap();
+        let manager = FetchManager::new(distdir.path(), vec!["https://a.example/gentoo".to_string()])
+            .with_thirdpartymirrors(&[repo.path().to_path_buf()])
+            .with_mirror_layout("https://a.example/gentoo/", layout);
+        let mut request = request(true);
+        request.uris = vec!["mirror://gnu/foo/foo-1.0.tar.gz".to_string(), "mirror://unknown/foo-1.0.tar.gz".to_string()];
+        let mirror_url = format!("https://a.example/gentoo/distfiles/{}/foo-1.0.tar.gz", &hash[..2]);
+        assert_eq!(manager.candidate_urls(&request), vec![
+            mirror_url.clone(),
+            "https://ftp.gnu.org/gnu/foo/foo-1.0.tar.gz".to_string(),
+            "https://mirrors.example/gnu/foo/foo-1.0.tar.gz".to_string(),
+        ]);
+        assert_eq!(manager.expand_uri("mirror://gentoo/foo-1.0.tar.gz"), vec![mirror_url.clone()]);
+
+        // RESTRICT=primaryuri: upstream first; RESTRICT=mirror still resolves mirror://
+        request.primaryuri = true;
+        assert_eq!(manager.candidate_urls(&request).last(), Some(&mirror_url));
+        request.use_mirrors = false;
+        assert_eq!(manager.candidate_urls(&request).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_remote_sizes() {
+        let headers = "HTTP/1.1 302 Found\r\nLocation: https://b.example/foo.tar.gz\r\ncontent-length: 0\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 12345\r\n\r\n";
+        assert_eq!(content_length(headers), Some(12345));
+        assert_eq!(content_length("HTTP/1.1 200 OK\r\n\r\n"), None);
+
+        // Unreachable servers give no size, and are asked once
+        let distdir = TempDir::new().unwrap();
+        let url = format!("file://{}/missing.tar.gz", distdir.path().display());
+        std::fs::write(distdir.path().join("present.tar.gz"), "12345678").unwrap();
+        let present = format!("file://{}/present.tar.gz", distdir.path().display());
+        let sizes = remote_sizes(vec![vec![url.clone()], vec![url.clone(), present]], 2).await;
+        assert_eq!(sizes, vec![None, Some(8)]);
+        assert_eq!(REMOTE_SIZES.lock().unwrap().get(&url), Some(&None));
+    }
+
+    #[tokio::test]
+    async fn test_existing_distfiles_are_not_fetched() {
+        let distdir = TempDir::new().unwrap();
+        std::fs::write(distdir.path().join("foo-1.0.tar.gz"), "data").unwrap();
+        let manager = Arc::new(FetchManager::new(distdir.path(), vec![]).with_jobs(2));
+        let mut missing = request(false);
+        missing.filename = "missing.tar.gz".to_string();
+        missing.uris = vec![];
+
+        let results = manager.fetch_all(vec![request(false), missing]).await;
+        assert_eq!(results[0].0, "foo-1.0.tar.gz");
+        assert_eq!(results[0].1.as_ref().unwrap(), &distdir.path().join("foo-1.0.tar.gz"));
+        assert!(results[1].1.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_distlocks_and_corrupt_distfiles() {
+        let distdir = TempDir::new().unwrap();
+        let lock = DistfileLock::acquire(distdir.path(), "foo-1.0.tar.gz").await.unwrap();
+        let lock_path = distdir.path().join(".locks/foo-1.0.tar.gz.portage_lockfile");
+        let other = std::fs::File::options().write(true).open(&lock_path).unwrap();
+        assert!(matches!(other.try_lock(), Err(std::fs::TryLockError::WouldBlock)));
+        drop(lock);
+        other.try_lock().unwrap();
+        drop(other);
+
+        // A truncated distfile is not reused: it becomes the partial download
+        std::fs::write(distdir.path().join("foo-1.0.tar.gz"), "da").unwrap();
+        let manifest = "DIST foo-1.0.tar.gz 4 SHA512 0\n";
+        std::fs::write(distdir.path().join("Manifest"), manifest).unwrap();
+        let mut corrupt = request(false);
+        corrupt.uris = vec![];
+        corrupt.manifest = crate::manifest::load_dist_entries(distdir.path()).unwrap().remove("foo-1.0.tar.gz");
+        let manager = FetchManager::new(distdir.path(), vec![]).with_distlocks(true);
+        assert!(manager.fetch(&corrupt).await.is_err());
+        assert!(!distdir.path().join("foo-1.0.tar.gz").exists());
+        assert!(distdir.path().join("foo-1.0.tar.gz.__download__").exists());
+    }
+}
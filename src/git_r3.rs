@@ -0,0 +1,209 @@
+// git_r3.rs -- built-in git-r3.eclass for live ebuilds: EGIT_REPO_URI cloned into a
+// persistent EGIT3_STORE_DIR and checked out into WORKDIR, and the upstream check behind
+// @live-rebuild
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What a build records about its checkout, as files in ${T}/git-r3 and the vdb
+pub const RECORD_KEYS: &[&str] = &["EGIT_REPO_URI", "EGIT_BRANCH", "EGIT_VERSION"];
+
+/// The git-r3 record of a build, relative to ${T}
+pub const RECORD_DIR: &str = "git-r3";
+
+/// ${EGIT3_STORE_DIR}: from the environment, else ${DISTDIR}/git3-src
+pub fn store_dir(env: &HashMap<String, String>, distdir: &Path) -> PathBuf {
+    env.get("EGIT3_STORE_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| distdir.join("git3-src"))
+}
+
+/// The EGIT_REPO_URI, EGIT_BRANCH and EGIT_VERSION recorded in `dir`
+pub fn read_record(dir: &Path) -> HashMap<String, String> {
+    RECORD_KEYS.iter()
+        .filter_map(|key| {
+            let value = std::fs::read_to_string(dir.join(key)).ok()?;
+            Some((key.to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// The commit `branch` (the default branch without one) points to in the repository at
+/// `uri`; None when it cannot be reached
+pub fn remote_head(uri: &str, branch: Option<&str>) -> Option<String> {
+    let reference = branch.filter(|b| !b.is_empty()).map_or_else(|| "HEAD".to_string(), |b| format!("refs/heads/{}", b));
+    let output = Command::new("git")
+        .args(["ls-remote", "--", uri, &reference])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines()
+        .find_map(|line| line.split_once('\t').filter(|(_, name)| *name == reference).map(|(commit, _)| commit.to_string()))
+}
+
+/// Whether the upstream of a checkout recorded as `record` has moved on; true when the
+/// record is incomplete or upstream cannot be reached, so the package is rebuilt
+pub fn upstream_changed(record: &HashMap<String, String>) -> bool {
+    let (Some(uri), Some(version)) = (record.get("EGIT_REPO_URI"), record.get("EGIT_VERSION")) else {
+        return true;
+    };
+    match remote_head(uri, record.get("EGIT_BRANCH").map(|b| b.as_str())) {
+        Some(head) => head != *version,
+        None => {
+            eprintln!("Warning: could not check {} for changes", uri);
+            true
+        }
+    }
+}
+
+/// Source of the built-in git-r3 eclass
+pub const ECLASS: &str = r#"
+PROPERTIES+=" live"
+EGIT3_STORE_DIR=${EGIT3_STORE_DIR:-${DISTDIR}/git3-src}
+
+_git-r3_repo_dir() {
+	local name=${1#*://}
+	name=${name%/}
+	name=${name%.git}
+	echo "${EGIT3_STORE_DIR}/${name//\//_}"
+}
+
+# The local ref a fetch leaves the commit to build in
+_git-r3_ref() {
+	echo "refs/git-r3/${CATEGORY}/${PN}"
+}
+
+git-r3_fetch() {
+	local repo_uri=${1:-${EGIT_REPO_URI}}
+	local uri dir fetched= commit
+	[[ -n ${repo_uri} ]] || die "EGIT_REPO_URI is not set"
+	mkdir -p "${EGIT3_STORE_DIR}" || die "Unable to create ${EGIT3_STORE_DIR}"
+	for uri in ${repo_uri}; do
+		dir=$(_git-r3_repo_dir "${uri}")
+		[[ -d ${dir} ]] || git init --quiet --bare "${dir}" || die "Unable to create ${dir}"
+		einfo "Fetching ${uri}"
+		if git --git-dir="${dir}" fetch --quiet --prune --force "${uri}" \
+			"+HEAD:refs/git-r3/HEAD" "+refs/heads/*:refs/heads/*" "+refs/tags/*:refs/tags/*"; then
+			fetched=${uri}
+			break
+		fi
+		ewarn "Unable to fetch from ${uri}"
+	done
+	[[ -n ${fetched} ]] || die "Unable to fetch from any of EGIT_REPO_URI"
+
+	if [[ -n ${EGIT_COMMIT} ]]; then
+		commit=$(git --git-dir="${dir}" rev-parse --verify --quiet "${EGIT_COMMIT}^{commit}")
+	elif [[ -n ${EGIT_BRANCH} ]]; then
+		commit=$(git --git-dir="${dir}" rev-parse --verify --quiet "refs/heads/${EGIT_BRANCH}^{commit}")
+	else
+		commit=$(git --git-dir="${dir}" rev-parse --verify --quiet "refs/git-r3/HEAD^{commit}")
+	fi
+	[[ -n ${commit} ]] || die "${EGIT_COMMIT:-${EGIT_BRANCH:-HEAD}} not found in ${fetched}"
+	git --git-dir="${dir}" update-ref "$(_git-r3_ref)" "${commit}" || die
+
+	# What was built, for @live-rebuild to compare upstream with
+	mkdir -p "${T}/git-r3" || die
+	echo "${fetched}" > "${T}/git-r3/EGIT_REPO_URI"
+	echo "${EGIT_BRANCH}" > "${T}/git-r3/EGIT_BRANCH"
+	echo "${commit}" > "${T}/git-r3/EGIT_VERSION"
+}
+
+git-r3_checkout() {
+	local repo_uri=${1:-${EGIT_REPO_URI}} out_dir=${2:-${EGIT_CHECKOUT_DIR:-${WORKDIR}/${P}}}
+	local uri dir commit=
+	for uri in ${repo_uri}; do
+		dir=$(_git-r3_repo_dir "${uri}")
+		commit=$(git --git-dir="${dir}" rev-parse --verify --quiet "$(_git-r3_ref)") && break
+	done
+	[[ -n ${commit} ]] || die "git-r3_checkout: ${repo_uri} has not been fetched"
+
+	einfo "Checking out ${commit} to ${out_dir}"
+	rm -rf "${out_dir}"
+	git clone --quiet --shared --no-checkout "${dir}" "${out_dir}" || die "Unable to clone ${dir}"
+	git -C "${out_dir}" checkout --quiet --detach "${commit}" || die "Unable to check out ${commit}"
+	if [[ -f ${out_dir}/.gitmodules ]] && [[ -z ${EGIT_SUBMODULES+set} || ${EGIT_SUBMODULES[*]} == '*' ]]; then
+		git -C "${out_dir}" submodule --quiet update --init --recursive || die "Unable to check out submodules"
+	fi
+	export EGIT_VERSION=${commit}
+}
+
+git-r3_src_fetch() {
+	git-r3_fetch
+}
+
+git-r3_src_unpack() {
+	git-r3_fetch
+	git-r3_checkout
+}
+
+EXPORT_FUNCTIONS src_unpack
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "dev").env("GIT_AUTHOR_EMAIL", "dev@example.org")
+            .env("GIT_COMMITTER_NAME", "dev").env("GIT_COMMITTER_EMAIL", "dev@example.org")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    #[test]
+    fn test_fetch_checkout_and_upstream_check() {
+        let temp = TempDir::new().unwrap();
+        let upstream = temp.path().join("upstream");
+        std::fs::create_dir_all(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet", "-b", "main"]);
+        std::fs::write(upstream.join("README"), "one\n").unwrap();
+        git(&upstream, &["add", "README"]);
+        git(&upstream, &["commit", "--quiet", "-m", "one"]);
+        let first = git(&upstream, &["rev-parse", "HEAD"]);
+
+        let uri = format!("file://{}", upstream.display());
+        let script = format!(
+            "die() {{ echo \"$*\" >&2; exit 1; }}\neinfo() {{ :; }}\newarn() {{ :; }}\n{}\ngit-r3_src_unpack\necho \"${{EGIT_VERSION}}\"\n", ECLASS);
+        let run = || Command::new("bash")
+            .args(["-c", &script])
+            .env("EGIT_REPO_URI", &uri)
+            .env("DISTDIR", temp.path().join("distfiles"))
+            .env("WORKDIR", temp.path().join("work"))
+            .env("T", temp.path().join("work/temp"))
+            .env("P", "foo-9999")
+            .env("PN", "foo")
+            .env("CATEGORY", "app-misc")
+            .output()
+            .unwrap();
+        let output = run();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), first);
+        assert_eq!(std::fs::read_to_string(temp.path().join("work/foo-9999/README")).unwrap(), "one\n");
+        let store = store_dir(&HashMap::new(), &temp.path().join("distfiles"));
+        assert!(std::fs::read_dir(&store).unwrap().count() == 1);
+
+        let record = read_record(&temp.path().join("work/temp").join(RECORD_DIR));
+        assert_eq!(record["EGIT_REPO_URI"], uri);
+        assert_eq!(record["EGIT_VERSION"], first);
+        assert!(!upstream_changed(&record));
+
+        // A new upstream commit is noticed, and a second fetch updates the store
+        std::fs::write(upstream.join("README"), "two\n").unwrap();
+        git(&upstream, &["commit", "--quiet", "-am", "two"]);
+        assert!(upstream_changed(&record));
+        let output = run();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), git(&upstream, &["rev-parse", "HEAD"]));
+        assert_eq!(std::fs::read_to_string(temp.path().join("work/foo-9999/README")).unwrap(), "two\n");
+    }
+}
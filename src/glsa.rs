@@ -0,0 +1,417 @@
+// glsa.rs -- Gentoo Linux Security Advisories from a repository's metadata/glsa: parsing,
+// matching the affected version ranges against installed packages, and the upgrades
+// that fix them (glsa-check)
+
+use crate::exception::InvalidData;
+use crate::vartree::VarTree;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// GLSA ids applied by hand (glsa-check --inject) or fixed, one per line
+pub const CHECKFILE: &str = "var/lib/portage/glsa_injected";
+
+/// A comparison from a <vulnerable> or <unaffected> element; the r* forms compare only
+/// the revision of the same version
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeOp {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+    RLt,
+    RLe,
+    RGe,
+    RGt,
+}
+
+impl RangeOp {
+    fn parse(range: &str) -> Option<Self> {
+        Some(match range {
+            "lt" => RangeOp::Lt,
+            "le" => RangeOp::Le,
+            "eq" => RangeOp::Eq,
+            "ge" => RangeOp::Ge,
+            "gt" => RangeOp::Gt,
+            "rlt" => RangeOp::RLt,
+            "rle" => RangeOp::RLe,
+            "rge" => RangeOp::RGe,
+            "rgt" => RangeOp::RGt,
+            _ => return None,
+        })
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            RangeOp::Lt | RangeOp::RLt => "<",
+            RangeOp::Le | RangeOp::RLe => "<=",
+            RangeOp::Eq => "=",
+            RangeOp::Ge | RangeOp::RGe => ">=",
+            RangeOp::Gt | RangeOp::RGt => ">",
+        }
+    }
+}
+
+/// A version range of an affected package
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionRange {
+    pub op: RangeOp,
+    pub version: String,
+    /// Only versions in this SLOT are in the range; "*" or none for any
+    pub slot: Option<String>,
+}
+
+/// "1.2.3-r4" as ("1.2.3", 4)
+fn split_revision(version: &str) -> (&str, u64) {
+    match version.rsplit_once("-r") {
+        Some((base, revision)) if revision.parse::<u64>().is_ok() => (base, revision.parse().unwrap_or(0)),
+        _ => (version, 0),
+    }
+}
+
+impl VersionRange {
+    /// Whether an installed or available `version` in `slot` is in the range
+    pub fn matches(&self, version: &str, slot: Option<&str>) -> bool {
+        if let (Some(wanted), Some(slot)) = (self.slot.as_deref().filter(|s| *s != "*"), slot)
+            && slot.split('/').next() != Some(wanted)
+        {
+            return false;
+        }
+        let ordering = match self.op {
+            RangeOp::RLt | RangeOp::RLe | RangeOp::RGe | RangeOp::RGt => {
+                let ((base, revision), (range_base, range_revision)) = (split_revision(version), split_revision(&self.version));
+                if crate::versions::vercmp(base, range_base) != Some(0) {
+                    return false;
+                }
+                revision.cmp(&range_revision)
+            }
+            RangeOp::Eq if self.version.ends_with('*') => return version.starts_with(self.version.trim_end_matches('*')),
+            _ => match crate::versions::vercmp(version, &self.version) {
+                Some(cmp) => cmp.cmp(&0),
+                None => return false,
+            },
+        };
+        match self.op {
+            RangeOp::Lt | RangeOp::RLt => ordering.is_lt(),
+            RangeOp::Le | RangeOp::RLe => ordering.is_le(),
+            RangeOp::Eq => ordering.is_eq(),
+            RangeOp::Ge | RangeOp::RGe => ordering.is_ge(),
+            RangeOp::Gt | RangeOp::RGt => ordering.is_gt(),
+        }
+    }
+
+    /// The range as an atom of `cp`, e.g. ">=app-misc/foo-1.2.3"
+    pub fn atom(&self, cp: &str) -> String {
+        let slot = self.slot.as_deref().filter(|s| *s != "*").map(|s| format!(":{}", s)).unwrap_or_default();
+        format!("{}{}-{}{}", self.op.symbol(), cp, self.version, slot)
+    }
+}
+
+/// A package of the <affected> section
+#[derive(Debug, Clone, PartialEq)]
+pub struct AffectedPackage {
+    pub name: String,
+    /// Keywords the advisory applies to; "*" for all
+    pub arch: Vec<String>,
+    pub vulnerable: Vec<VersionRange>,
+    pub unaffected: Vec<VersionRange>,
+}
+
+impl AffectedPackage {
+    /// Whether `version` in `slot` is in a vulnerable range and in no unaffected one
+    pub fn is_vulnerable(&self, version: &str, slot: Option<&str>) -> bool {
+        self.vulnerable.iter().any(|range| range.matches(version, slot))
+            && !self.unaffected.iter().any(|range| range.matches(version, slot))
+    }
+
+    fn applies_to(&self, arch: &str) -> bool {
+        self.arch.iter().any(|a| a == "*" || a == arch)
+    }
+}
+
+/// One advisory
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Glsa {
+    pub id: String,
+    pub title: String,
+    pub synopsis: String,
+    pub announced: String,
+    pub revised: String,
+    pub bugs: Vec<String>,
+    pub access: String,
+    pub affected: Vec<AffectedPackage>,
+    pub resolution: String,
+    pub references: Vec<String>,
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element.try_get_attribute(name).ok().flatten().and_then(|attr| attr.unescape_value().ok()).map(|value| value.into_owned())
+}
+
+/// Collapse the whitespace of element text
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl Glsa {
+    pub fn parse(xml: &str) -> Result<Self, InvalidData> {
+        let mut reader = quick_xml::Reader::from_str(xml);
+        let mut glsa = Glsa::default();
+        let mut text = String::new();
+        let mut range: Option<(RangeOp, Option<String>)> = None;
+        let mut in_resolution = false;
+        loop {
+            let event = reader.read_event()
+                .map_err(|e| InvalidData::new(&format!("Invalid GLSA XML at {}: {}", reader.buffer_position(), e), None))?;
+            match event {
+                Event::Start(element) | Event::Empty(element) => {
+                    text.clear();
+                    match element.name().as_ref() {
+                        b"glsa" => glsa.id = attribute(&element, "id").unwrap_or_default(),
+                        b"package" => glsa.affected.push(AffectedPackage {
+                            name: attribute(&element, "name").unwrap_or_default(),
+                            arch: attribute(&element, "arch").unwrap_or_else(|| "*".to_string()).split_whitespace().map(|a| a.to_string()).collect(),
+                            vulnerable: Vec::new(),
+                            unaffected: Vec::new(),
+                        }),
+                        b"vulnerable" | b"unaffected" => {
+                            let op = attribute(&element, "range").and_then(|r| RangeOp::parse(&r))
+                                .ok_or_else(|| InvalidData::new(&format!("GLSA {} has a version range without a valid range attribute", glsa.id), None))?;
+                            range = Some((op, attribute(&element, "slot")));
+                        }
+                        b"resolution" => in_resolution = true,
+                        b"uri" => glsa.references.extend(attribute(&element, "link")),
+                        _ => {}
+                    }
+                }
+                Event::Text(content) => {
+                    let content = content.unescape().map_err(|e| InvalidData::new(&format!("Invalid GLSA XML: {}", e), None))?;
+                    text.push_str(&content);
+                    if in_resolution {
+                        glsa.resolution.push_str(&content);
+                        glsa.resolution.push('\n');
+                    }
+                }
+                Event::End(element) => {
+                    let value = normalize(&text);
+                    match element.name().as_ref() {
+                        b"title" => glsa.title = value,
+                        b"synopsis" => glsa.synopsis = value,
+                        b"announced" => glsa.announced = value,
+                        b"revised" => glsa.revised = value,
+                        b"bug" => glsa.bugs.push(value),
+                        b"access" => glsa.access = value,
+                        b"resolution" => in_resolution = false,
+                        name @ (b"vulnerable" | b"unaffected") => {
+                            let (Some((op, slot)), Some(package)) = (range.take(), glsa.affected.last_mut()) else {
+                                continue;
+                            };
+                            let ranges = if name == b"vulnerable" { &mut package.vulnerable } else { &mut package.unaffected };
+                            ranges.push(VersionRange { op, version: value, slot });
+                        }
+                        _ => {}
+                    }
+                    text.clear();
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        if glsa.id.is_empty() {
+            return Err(InvalidData::new("Not a GLSA: no <glsa id=...> element", None));
+        }
+        glsa.resolution = glsa.resolution.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join("\n");
+        Ok(glsa)
+    }
+
+    /// The installed packages this advisory affects on `arch`
+    pub fn affected_installed(&self, vartree: &VarTree, arch: &str) -> Vec<String> {
+        let mut affected = Vec::new();
+        for package in self.affected.iter().filter(|package| package.applies_to(arch)) {
+            for cpv in vartree.installed_versions(&package.name) {
+                let Some(version) = crate::versions::cpv_getversion(&cpv) else {
+                    continue;
+                };
+                if package.is_vulnerable(&version, vartree.slot(&cpv).as_deref()) && !affected.contains(&cpv) {
+                    affected.push(cpv);
+                }
+            }
+        }
+        affected
+    }
+
+    /// The lowest of the `available` (cpv, SLOT) versions of an affected package that is
+    /// newer than the vulnerable `installed` one and no longer vulnerable
+    pub fn minimal_upgrade(&self, installed: &str, available: &[(String, Option<String>)]) -> Option<String> {
+        let cp = crate::versions::cpv_getkey(installed)?;
+        let installed_version = crate::versions::cpv_getversion(installed)?;
+        let package = self.affected.iter().find(|package| package.name == cp)?;
+        let mut candidates: Vec<(String, String)> = available.iter()
+            .filter_map(|(cpv, slot)| {
+                let version = crate::versions::cpv_getversion(cpv)?;
+                let fixed = !package.is_vulnerable(&version, slot.as_deref())
+                    && package.unaffected.iter().any(|range| range.matches(&version, slot.as_deref()))
+                    && crate::versions::vercmp(&version, &installed_version).is_some_and(|cmp| cmp > 0);
+                fixed.then(|| (cpv.clone(), version))
+            })
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| crate::versions::vercmp(a, b).unwrap_or(0).cmp(&0));
+        candidates.into_iter().next().map(|(cpv, _)| cpv)
+    }
+}
+
+/// Every advisory in the metadata/glsa directory of the repository at `location`, by id
+pub fn load_advisories(location: &Path) -> Vec<Glsa> {
+    let Ok(entries) = std::fs::read_dir(location.join("metadata/glsa")) else {
+        return Vec::new();
+    };
+    let mut advisories: Vec<Glsa> = entries.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("glsa-") && n.ends_with(".xml")))
+        .filter_map(|path| {
+            let content = std::fs::read_to_string(&path).ok()?;
+            match Glsa::parse(&content) {
+                Ok(glsa) => Some(glsa),
+                Err(e) => {
+                    eprintln!("!!! Failed to parse {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+    advisories.sort_by(|a, b| a.id.cmp(&b.id));
+    advisories
+}
+
+/// The GLSA ids recorded as applied under `root`
+pub fn applied(root: &str) -> HashSet<String> {
+    std::fs::read_to_string(Path::new(root).join(CHECKFILE))
+        .map(|content| content.lines().map(|line| line.trim().to_string()).filter(|id| !id.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Record `id` as applied under `root`
+pub fn mark_applied(root: &str, id: &str) -> Result<(), InvalidData> {
+    if applied(root).contains(id) {
+        return Ok(());
+    }
+    let path = Path::new(root).join(CHECKFILE);
+    let result = (|| {
+        use std::io::Write;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", id)
+    })();
+    result.map_err(|e| InvalidData::new(&format!("Failed to update {}: {}", path.display(), e), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const GLSA: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE glsa SYSTEM "http://www.gentoo.org/dtd/glsa.dtd">
+<glsa id="202401-01">
+  <title>Foo: Multiple vulnerabilities</title>
+  <synopsis>Multiple vulnerabilities have been discovered in Foo,
+    the worst of which could lead to remote code execution.</synopsis>
+  <product type="ebuild">foo</product>
+  <announced>2024-01-05</announced>
+  <revised count="1">2024-01-05</revised>
+  <bug>912345</bug>
+  <bug>912346</bug>
+  <access>remote</access>
+  <affected>
+    <package name="app-misc/foo" auto="yes" arch="*">
+      <unaffected range="ge">1.2.3</unaffected>
+      <unaffected range="rge" slot="1">1.1.5-r2</unaffected>
+      <vulnerable range="lt">1.2.3</vulnerable>
+    </package>
+    <package name="dev-libs/bar" auto="yes" arch="arm64">
+      <vulnerable range="le">2</vulnerable>
+    </package>
+  </affected>
+  <resolution>
+    <p>All Foo users should upgrade to the latest version:</p>
+    <code>
+      # emerge --ask --oneshot --verbose "&gt;=app-misc/foo-1.2.3"
+    </code>
+  </resolution>
+  <references>
+    <uri link="https://nvd.nist.gov/vuln/detail/CVE-2023-1234">CVE-2023-1234</uri>
+  </references>
+</glsa>
+"#;
+
+    #[test]
+    fn test_parse() {
+        let glsa = Glsa::parse(GLSA).unwrap();
+        assert_eq!(glsa.id, "202401-01");
+        assert_eq!(glsa.title, "Foo: Multiple vulnerabilities");
+        assert_eq!(glsa.synopsis, "Multiple vulnerabilities have been discovered in Foo, the worst of which could lead to remote code execution.");
+        assert_eq!(glsa.bugs, vec!["912345", "912346"]);
+        assert_eq!(glsa.references, vec!["https://nvd.nist.gov/vuln/detail/CVE-2023-1234"]);
+        assert_eq!(glsa.resolution, "All Foo users should upgrade to the latest version:\n# emerge --ask --oneshot --verbose \">=app-misc/foo-1.2.3\"");
+        assert_eq!(glsa.affected.len(), 2);
+        let foo = &glsa.affected[0];
+        assert_eq!(foo.unaffected[1], VersionRange { op: RangeOp::RGe, version: "1.1.5-r2".to_string(), slot: Some("1".to_string()) });
+        assert_eq!(foo.unaffected[1].atom("app-misc/foo"), ">=app-misc/foo-1.1.5-r2:1");
+        assert_eq!(glsa.affected[1].arch, vec!["arm64"]);
+        assert!(Glsa::parse("<news/>").is_err());
+    }
+
+    #[test]
+    fn test_ranges() {
+        let glsa = Glsa::parse(GLSA).unwrap();
+        let foo = &glsa.affected[0];
+        assert!(foo.is_vulnerable("1.2.2", Some("0")));
+        assert!(!foo.is_vulnerable("1.2.3", Some("0")));
+        // Revision fixes of the older slot
+        assert!(foo.is_vulnerable("1.1.5-r1", Some("1")));
+        assert!(!foo.is_vulnerable("1.1.5-r2", Some("1")));
+        assert!(foo.is_vulnerable("1.1.5-r2", Some("0")));
+        assert!(foo.is_vulnerable("1.1.4", Some("1")));
+
+        let wildcard = VersionRange { op: RangeOp::Eq, version: "2.4*".to_string(), slot: None };
+        assert!(wildcard.matches("2.4.1", None));
+        assert!(!wildcard.matches("2.5", None));
+
+        let available = vec![
+            ("app-misc/foo-1.2.2".to_string(), Some("0".to_string())),
+            ("app-misc/foo-1.2.5".to_string(), Some("0".to_string())),
+            ("app-misc/foo-1.2.3".to_string(), Some("0".to_string())),
+        ];
+        assert_eq!(glsa.minimal_upgrade("app-misc/foo-1.2.1", &available).as_deref(), Some("app-misc/foo-1.2.3"));
+        assert_eq!(glsa.minimal_upgrade("app-misc/foo-1.2.1", &available[..1]), None);
+    }
+
+    #[test]
+    fn test_affected_installed() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().to_str().unwrap();
+        for (cpv, slot) in [("app-misc/foo-1.2.1", "0"), ("app-misc/foo-1.1.5-r2", "1"), ("dev-libs/bar-1", "0")] {
+            let dir = temp.path().join("var/db/pkg").join(cpv);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("SLOT"), format!("{}\n", slot)).unwrap();
+        }
+        let glsa = Glsa::parse(GLSA).unwrap();
+        let vartree = VarTree::new(root);
+        assert_eq!(glsa.affected_installed(&vartree, "amd64"), vec!["app-misc/foo-1.2.1"]);
+        assert_eq!(glsa.affected_installed(&vartree, "arm64"), vec!["app-misc/foo-1.2.1", "dev-libs/bar-1"]);
+
+        let repo = temp.path().join("repo");
+        std::fs::create_dir_all(repo.join("metadata/glsa")).unwrap();
+        std::fs::write(repo.join("metadata/glsa/glsa-202401-01.xml"), GLSA).unwrap();
+        std::fs::write(repo.join("metadata/glsa/index.xml"), "<index/>").unwrap();
+        assert_eq!(load_advisories(&repo).len(), 1);
+
+        assert!(applied(root).is_empty());
+        mark_applied(root, "202401-01").unwrap();
+        mark_applied(root, "202401-01").unwrap();
+        assert_eq!(applied(root), HashSet::from(["202401-01".to_string()]));
+    }
+}
@@ -0,0 +1,181 @@
+// go_module.rs -- built-in go-module.eclass: EGO_SUM modules as distfiles served to the go
+// command from a local proxy, GOMODCACHE inside WORKDIR and go build phases
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::eclass::ShellSource;
+use crate::exception::InvalidData;
+
+/// Where EGO_SUM modules are downloaded from
+pub const GOPROXY: &str = "https://proxy.golang.org";
+
+/// One EGO_SUM entry: a module's source zip, or only its go.mod
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoModule {
+    pub path: String,
+    pub version: String,
+    pub go_mod_only: bool,
+}
+
+impl GoModule {
+    /// "module version" or "module version/go.mod"
+    pub fn parse(entry: &str) -> Option<Self> {
+        let (path, version) = entry.trim().split_once(char::is_whitespace)?;
+        let version = version.trim();
+        let (version, go_mod_only) = match version.strip_suffix("/go.mod") {
+            Some(version) => (version, true),
+            None => (version, false),
+        };
+        (!path.is_empty() && !version.is_empty())
+            .then(|| GoModule { path: path.to_string(), version: version.to_string(), go_mod_only })
+    }
+
+    fn extension(&self) -> &'static str {
+        if self.go_mod_only { "mod" } else { "zip" }
+    }
+
+    /// The file as the module proxy protocol names it: <escaped path>/@v/<version>.<ext>
+    pub fn proxy_path(&self) -> String {
+        format!("{}/@v/{}.{}", escape(&self.path), escape(&self.version), self.extension())
+    }
+
+    /// The distfile, the proxy path with its slashes encoded as go-module.eclass does
+    pub fn distfile(&self) -> String {
+        self.proxy_path().replace('/', "%2F")
+    }
+
+    pub fn uri(&self) -> String {
+        format!("{}/{}", GOPROXY, self.proxy_path())
+    }
+}
+
+/// Module path escaping: capitals become '!' and the lower case letter
+pub fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for ch in path.chars() {
+        if ch.is_ascii_uppercase() {
+            escaped.push('!');
+            escaped.push(ch.to_ascii_lowercase());
+        } else {
+            escaped.push(ch);
+        }
+    }
+    escaped
+}
+
+/// The EGO_SUM modules of an ebuild inheriting go-module, none otherwise
+pub fn ebuild_modules(content: &str) -> Vec<GoModule> {
+    let source = ShellSource::parse(content);
+    if !source.inherits.iter().any(|eclass| eclass == "go-module") {
+        return Vec::new();
+    }
+    let value = source.value("EGO_SUM").unwrap_or_default();
+    // An array of quoted "module version" strings
+    value.split('"').skip(1).step_by(2).filter_map(GoModule::parse).collect()
+}
+
+/// ${GOMODCACHE}
+pub fn mod_cache(workdir: &Path) -> PathBuf {
+    workdir.join("go-mod")
+}
+
+/// The file:// GOPROXY the EGO_SUM distfiles are served from
+pub fn proxy_dir(workdir: &Path) -> PathBuf {
+    workdir.join("go-proxy")
+}
+
+/// Link the downloaded `modules` from `distdir` into the proxy directory
+pub fn populate_proxy(modules: &[GoModule], distdir: &Path, workdir: &Path) -> Result<(), InvalidData> {
+    let proxy = proxy_dir(workdir);
+    for module in modules {
+        let target = proxy.join(module.proxy_path());
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+        }
+        let _ = fs::remove_file(&target);
+        std::os::unix::fs::symlink(distdir.join(module.distfile()), &target)
+            .map_err(|e| InvalidData::new(&format!("Failed to link {}: {}", target.display(), e), None))?;
+    }
+    Ok(())
+}
+
+/// Source of the built-in go-module eclass; EGO_SUM is fetched natively, see BuildEnv
+pub const ECLASS: &str = r#"
+export GO111MODULE=on
+export GOCACHE=${T}/go-build
+export GOMODCACHE=${WORKDIR}/go-mod
+export GOFLAGS="-buildvcs=false -modcacherw -v -x"
+export GOTOOLCHAIN=local
+
+_go_env() {
+	cd "${S}" || die
+	if [[ -d ${WORKDIR}/go-proxy ]]; then
+		export GOPROXY=file://${WORKDIR}/go-proxy GOSUMDB=off
+	else
+		export GOPROXY=off
+	fi
+	[[ -d vendor ]] && export GOFLAGS="${GOFLAGS} -mod=vendor"
+	export CGO_CFLAGS=${CGO_CFLAGS:-${CFLAGS}}
+	export CGO_CXXFLAGS=${CGO_CXXFLAGS:-${CXXFLAGS}}
+	export CGO_LDFLAGS=${CGO_LDFLAGS:-${LDFLAGS}}
+	true
+}
+
+ego() {
+	echo "go $*"
+	go "$@" || die "go $* failed"
+}
+
+go-module_src_compile() {
+	_go_env
+	mkdir -p "${WORKDIR}/go-bin" || die
+	ego build -trimpath ${EGO_BUILD_FLAGS} -o "${WORKDIR}/go-bin/" ${EGO_BUILD_TARGETS:-./...}
+}
+
+go-module_src_test() {
+	_go_env
+	ego test -trimpath ${EGO_TEST_FLAGS} ${EGO_BUILD_TARGETS:-./...}
+}
+
+go-module_src_install() {
+	local bin
+	for bin in "${WORKDIR}"/go-bin/*; do
+		[[ -f ${bin} ]] && dobin "${bin}"
+	done
+	true
+}
+
+EXPORT_FUNCTIONS src_compile src_test src_install
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ebuild_modules() {
+        let content = "EAPI=8\ninherit go-module\nEGO_SUM=(\n\t\"github.com/BurntSushi/toml v1.3.2\"\n\t\"github.com/BurntSushi/toml v1.3.2/go.mod\"\n)\ngo-module_set_globals\n";
+        let modules = ebuild_modules(content);
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].proxy_path(), "github.com/!burnt!sushi/toml/@v/v1.3.2.zip");
+        assert_eq!(modules[1].distfile(), "github.com%2F!burnt!sushi%2Ftoml%2F@v%2Fv1.3.2.mod");
+        assert_eq!(modules[1].uri(), "https://proxy.golang.org/github.com/!burnt!sushi/toml/@v/v1.3.2.mod");
+        assert!(ebuild_modules("EGO_SUM=( \"a v1\" )\n").is_empty());
+    }
+
+    #[test]
+    fn test_populate_proxy() {
+        let temp = TempDir::new().unwrap();
+        let distdir = temp.path().join("distfiles");
+        fs::create_dir_all(&distdir).unwrap();
+        let module = GoModule::parse("golang.org/x/sys v0.1.0").unwrap();
+        fs::write(distdir.join(module.distfile()), "zip").unwrap();
+
+        populate_proxy(std::slice::from_ref(&module), &distdir, temp.path()).unwrap();
+        let linked = proxy_dir(temp.path()).join("golang.org/x/sys/@v/v0.1.0.zip");
+        assert_eq!(fs::read_to_string(linked).unwrap(), "zip");
+    }
+}
@@ -0,0 +1,289 @@
+// install_helpers.rs -- the PMS install helpers for src_install: the do*/new* family,
+// their into/opts state, dosym, keepdir, fowners/fperms and einstalldocs
+
+/// Where the helpers install to and with which modes, until into/insinto/exeinto/docinto
+/// or the *opts functions change it
+const STATE: &str = r#"DESTTREE=/usr
+INSDESTTREE=
+_E_EXEDESTTREE_=
+_E_DOCDESTTREE_=
+INSOPTIONS=-m0644
+EXEOPTIONS=-m0755
+LIBOPTIONS=-m0644
+DIROPTIONS=-m0755
+"#;
+
+const HELPERS: &str = r#"into() { DESTTREE=${1%/}; }
+insinto() { INSDESTTREE=${1%/}; }
+exeinto() { _E_EXEDESTTREE_=${1%/}; }
+docinto() { _E_DOCDESTTREE_=${1#/}; _E_DOCDESTTREE_=${_E_DOCDESTTREE_%/}; }
+insopts() { INSOPTIONS="$*"; }
+exeopts() { EXEOPTIONS="$*"; }
+libopts() { LIBOPTIONS="$*"; }
+diropts() { DIROPTIONS="$*"; }
+
+# Copy the directory $2 with its contents into $3 of the image
+__install_tree() {
+    local helper=$1 dir=${2%/} dest=$3 options=$4 path relative base
+    base=${dir##*/}
+    while IFS= read -r -d '' path; do
+        relative=${base}${path#"${dir}"}
+        if [[ -L ${path} ]]; then
+            ln -snf "$(readlink "${path}")" "${ED%/}${dest}/${relative}" || die "${helper}: failed to install ${path}"
+        elif [[ -d ${path} ]]; then
+            install -d ${DIROPTIONS} "${ED%/}${dest}/${relative}" || die "${helper}: failed to install ${path}"
+        else
+            install ${options} "${path}" "${ED%/}${dest}/${relative}" || die "${helper}: failed to install ${path}"
+        fi
+    done < <(find "${dir}" -print0)
+}
+
+# Install files, and directories with -r, into $2 of the image with install options $3
+__doins() {
+    local helper=$1 dest=$2 options=$3 recursive= file
+    shift 3
+    if [[ $1 == -r ]]; then
+        recursive=1
+        shift
+    fi
+    [[ $# -gt 0 ]] || die "${helper}: at least one argument needed"
+    install -d "${ED%/}${dest}" || die "${helper}: failed to create ${dest}"
+    for file in "$@"; do
+        if [[ -d ${file} && ! -L ${file} ]]; then
+            [[ -n ${recursive} ]] || die "${helper}: ${file} is a directory"
+            __install_tree "${helper}" "${file}" "${dest}" "${options}"
+        elif [[ -L ${file} && ${helper} == dolib* ]]; then
+            ln -snf "$(readlink "${file}")" "${ED%/}${dest}/${file##*/}" || die "${helper}: failed to install ${file}"
+        elif [[ -e ${file} ]]; then
+            install ${options} "${file}" "${ED%/}${dest}/" || die "${helper}: failed to install ${file}"
+        else
+            die "${helper}: ${file} does not exist"
+        fi
+    done
+}
+
+doins() { __doins doins "${INSDESTTREE}" "${INSOPTIONS}" "$@"; }
+doexe() { __doins doexe "${_E_EXEDESTTREE_}" "${EXEOPTIONS}" "$@"; }
+dodoc() { __doins dodoc "/usr/share/doc/${PF:-${P}}${_E_DOCDESTTREE_:+/${_E_DOCDESTTREE_}}" -m0644 "$@"; }
+dobin() { __doins dobin "${DESTTREE}/bin" -m0755 "$@"; }
+dosbin() { __doins dosbin "${DESTTREE}/sbin" -m0755 "$@"; }
+doinfo() { __doins doinfo /usr/share/info -m0644 "$@"; }
+doinitd() { __doins doinitd /etc/init.d -m0755 "$@"; }
+doconfd() { __doins doconfd /etc/conf.d -m0644 "$@"; }
+doenvd() { __doins doenvd /etc/env.d -m0644 "$@"; }
+dolib() { __doins dolib "${DESTTREE}/$(get_libdir)" "${LIBOPTIONS}" "$@"; }
+dolib.so() { __doins dolib.so "${DESTTREE}/$(get_libdir)" -m0755 "$@"; }
+dolib.a() { __doins dolib.a "${DESTTREE}/$(get_libdir)" -m0644 "$@"; }
+
+# Man pages go to the section their suffix names; foo.de.1, or -i18n=de, to the German ones
+doman() {
+    local i18n= file name section lang re='\.([a-z][a-z](_[A-Z][A-Z])?)\.[^.]+$'
+    if [[ $1 == -i18n=* ]]; then
+        i18n=${1#-i18n=}
+        shift
+    fi
+    [[ $# -gt 0 ]] || die "doman: at least one argument needed"
+    for file in "$@"; do
+        [[ -e ${file} ]] || die "doman: ${file} does not exist"
+        name=${file##*/}
+        section=${name##*.}
+        lang=${i18n}
+        if [[ ${name} =~ ${re} ]]; then
+            [[ -n ${lang} ]] || lang=${BASH_REMATCH[1]}
+            name=${name%."${BASH_REMATCH[1]}".${section}}.${section}
+        fi
+        [[ ${section} =~ ^[0-9nl] ]] || die "doman: ${file} has no man section suffix"
+        install -d "${ED%/}/usr/share/man${lang:+/${lang}}/man${section:0:1}" || die "doman: failed to install ${file}"
+        install -m0644 "${file}" "${ED%/}/usr/share/man${lang:+/${lang}}/man${section:0:1}/${name}" || die "doman: failed to install ${file}"
+    done
+}
+
+# Install $1 under the name $2 with the do* helper of the same name
+__newfile() {
+    local helper=$1
+    shift
+    [[ $# -eq 2 ]] || die "new${helper#do}: two arguments needed"
+    local tmp="${T}/new/${2##*/}"
+    mkdir -p "${T}/new" || die "new${helper#do}: failed to create ${T}/new"
+    if [[ $1 == - ]]; then
+        cat > "${tmp}" || die "new${helper#do}: failed to read stdin"
+    else
+        cp -P "$1" "${tmp}" || die "new${helper#do}: failed to copy $1"
+    fi
+    "${helper}" "${tmp}"
+    rm -f "${tmp}"
+}
+
+newins() { __newfile doins "$@"; }
+newexe() { __newfile doexe "$@"; }
+newdoc() { __newfile dodoc "$@"; }
+newbin() { __newfile dobin "$@"; }
+newsbin() { __newfile dosbin "$@"; }
+newman() { __newfile doman "$@"; }
+newinitd() { __newfile doinitd "$@"; }
+newconfd() { __newfile doconfd "$@"; }
+newenvd() { __newfile doenvd "$@"; }
+newlib.so() { __newfile dolib.so "$@"; }
+newlib.a() { __newfile dolib.a "$@"; }
+
+dodir() {
+    local dir
+    for dir in "$@"; do
+        install -d ${DIROPTIONS} "${ED%/}/${dir#/}" || die "dodir: failed to create ${dir}"
+    done
+}
+
+# Directories that stay when empty, held by a .keep file
+keepdir() {
+    local dir
+    dodir "$@"
+    for dir in "$@"; do
+        touch "${ED%/}/${dir#/}/.keep_${CATEGORY}_${PN}-${SLOT%/*}" || die "keepdir: failed to create ${dir}"
+    done
+}
+
+# dosym [-r] target link; -r makes an absolute target relative to the link
+dosym() {
+    local relative= target link
+    if [[ $1 == -r ]]; then
+        relative=1
+        shift
+    fi
+    [[ $# -eq 2 ]] || die "dosym: two arguments needed"
+    target=$1
+    link=/${2#/}
+    if [[ -n ${relative} ]]; then
+        [[ ${target} == /* ]] || die "dosym -r: ${target} is not an absolute path"
+        target=$(realpath -s -m --relative-to="${link%/*}/" "${target}") || die "dosym -r: failed to resolve ${target}"
+    fi
+    mkdir -p "${ED%/}${link%/*}" || die "dosym: failed to create ${link%/*}"
+    ln -snf "${target}" "${ED%/}${link}" || die "dosym: failed to create ${link}"
+}
+
+dohard() {
+    [[ $# -eq 2 ]] || die "dohard: two arguments needed"
+    local link=/${2#/}
+    mkdir -p "${ED%/}${link%/*}" || die "dohard: failed to create ${link%/*}"
+    ln -f "${ED%/}/${1#/}" "${ED%/}${link}" || die "dohard: failed to create ${link}"
+}
+
+# chown/chmod with the paths taken inside the image
+__image_paths() {
+    local arg
+    for arg in "$@"; do
+        if [[ ${arg} == /* ]]; then
+            printf '%s\0' "${ED%/}${arg}"
+        else
+            printf '%s\0' "${arg}"
+        fi
+    done
+}
+fowners() {
+    local args
+    mapfile -d '' args < <(__image_paths "$@")
+    chown "${args[@]}" || die "fowners: failed to change owners of $*"
+}
+fperms() {
+    local args
+    mapfile -d '' args < <(__image_paths "$@")
+    chmod "${args[@]}" || die "fperms: failed to change modes of $*"
+}
+
+# DOCS, else the usual documentation files, and HTML_DOCS under html/
+einstalldocs() {
+    local _E_DOCDESTTREE_= doc
+    if [[ $(declare -p DOCS 2>/dev/null) == "declare -a"* ]]; then
+        if [[ ${#DOCS[@]} -gt 0 ]]; then
+            dodoc -r "${DOCS[@]}"
+        fi
+    elif [[ -n ${DOCS+set} ]]; then
+        if [[ -n ${DOCS} ]]; then
+            dodoc -r ${DOCS}
+        fi
+    else
+        for doc in README* ChangeLog AUTHORS NEWS TODO CHANGES THANKS BUGS FAQ CREDITS CHANGELOG; do
+            if [[ -f ${doc} && -s ${doc} ]]; then
+                dodoc "${doc}"
+            fi
+        done
+    fi
+    _E_DOCDESTTREE_=html
+    if [[ $(declare -p HTML_DOCS 2>/dev/null) == "declare -a"* ]]; then
+        if [[ ${#HTML_DOCS[@]} -gt 0 ]]; then
+            dodoc -r "${HTML_DOCS[@]}"
+        fi
+    elif [[ -n ${HTML_DOCS} ]]; then
+        dodoc -r ${HTML_DOCS}
+    fi
+    return 0
+}
+"#;
+
+/// The install helpers and their initial state, as bash
+pub fn bash_functions() -> String {
+    format!("{}\n{}\n", STATE, HELPERS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    fn run(dir: &Path, script: &str) -> std::process::Output {
+        let full = format!(
+            "set -e\nexport D='{0}/image' ED='{0}/image' T='{0}/temp' P=foo-1.0 PF=foo-1.0-r1 PN=foo CATEGORY=app-misc SLOT=0/1 ABI=amd64 LIBDIR_amd64=lib64\n\
+             die() {{ echo \"die: $*\" >&2; exit 1; }}\nget_libdir() {{ local var=LIBDIR_${{ABI}}; echo \"${{!var:-lib}}\"; }}\n{1}\ncd '{0}/src'\n{2}",
+            dir.display(), bash_functions(), script);
+        Command::new("bash").arg("-c").arg(full).output().unwrap()
+    }
+
+    #[test]
+    fn test_install_helpers() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let src = dir.join("src");
+        for (path, content) in [
+            ("foo", "#!/bin/sh\n"), ("foo.conf", "x=1\n"), ("foo.1", ".TH FOO 1\n"), ("foo.de.8", ".TH FOO 8\n"),
+            ("libfoo.so.1", "elf"), ("README", "read me\n"), ("AUTHORS", ""), ("data/sub/a.txt", "a\n"), ("foo.info", "info\n"),
+        ] {
+            std::fs::create_dir_all(src.join(path).parent().unwrap()).unwrap();
+            std::fs::write(src.join(path), content).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("temp")).unwrap();
+        std::os::unix::fs::symlink("libfoo.so.1", src.join("libfoo.so")).unwrap();
+
+        let output = run(dir, "dobin foo\ninto /\ndosbin foo\ninsinto /etc/foo\ndoins foo.conf\nnewins foo.conf bar.conf\ninsinto /usr/share/foo\ndoins -r data\n\
+            exeinto /usr/libexec/foo\nexeopts -m0750\ndoexe foo\ninto /usr\ndolib.so libfoo.so.1 libfoo.so\ndoman foo.1 foo.de.8\n\
+            doinfo foo.info\nkeepdir /var/lib/foo\ndosym -r /usr/bin/foo /usr/libexec/foo/bar\n\
+            dosym foo /usr/bin/foo-link\nfperms 0600 /etc/foo/foo.conf\neinstalldocs\ndocinto examples\ndodoc foo.conf\n");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let image = dir.join("image");
+        let mode = |path: &str| std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(image.join(path)).unwrap().permissions()) & 0o7777;
+        assert_eq!(mode("usr/bin/foo"), 0o755);
+        assert_eq!(mode("sbin/foo"), 0o755);
+        assert_eq!(mode("etc/foo/foo.conf"), 0o600);
+        assert_eq!(mode("etc/foo/bar.conf"), 0o644);
+        assert!(image.join("usr/share/foo/data/sub/a.txt").is_file());
+        assert_eq!(mode("usr/libexec/foo/foo"), 0o750);
+        assert_eq!(mode("usr/lib64/libfoo.so.1"), 0o755);
+        assert_eq!(std::fs::read_link(image.join("usr/lib64/libfoo.so")).unwrap(), Path::new("libfoo.so.1"));
+        assert!(image.join("usr/share/man/man1/foo.1").is_file());
+        assert!(image.join("usr/share/man/de/man8/foo.8").is_file());
+        assert!(image.join("usr/share/info/foo.info").is_file());
+        assert!(image.join("var/lib/foo/.keep_app-misc_foo-0").is_file());
+        assert_eq!(std::fs::read_link(image.join("usr/libexec/foo/bar")).unwrap(), Path::new("../../bin/foo"));
+        assert_eq!(std::fs::read_link(image.join("usr/bin/foo-link")).unwrap(), Path::new("foo"));
+        // The default documentation, without empty files
+        assert!(image.join("usr/share/doc/foo-1.0-r1/README").is_file());
+        assert!(!image.join("usr/share/doc/foo-1.0-r1/AUTHORS").exists());
+        assert!(image.join("usr/share/doc/foo-1.0-r1/examples/foo.conf").is_file());
+
+        let output = run(dir, "doins missing\n");
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("die: doins: missing does not exist"));
+        assert!(!run(dir, "doins data\n").status.success());
+    }
+}
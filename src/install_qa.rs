@@ -0,0 +1,232 @@
+// install_qa.rs -- QA checks run on the image directory after src_install
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use crate::exception::InvalidData;
+
+/// A kind of problem the install QA checks look for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QaCheck {
+    SetXid,
+    WorldWritable,
+    BrokenSymlink,
+    MissingSoname,
+    InsecureRunpath,
+    TextRelocation,
+}
+
+impl QaCheck {
+    pub fn description(&self) -> &'static str {
+        match self {
+            QaCheck::SetXid => "setXid files found",
+            QaCheck::WorldWritable => "world writable file(s)",
+            QaCheck::BrokenSymlink => "broken symlink(s)",
+            QaCheck::MissingSoname => "shared library without SONAME",
+            QaCheck::InsecureRunpath => "insecure RUNPATH pointing into the build directory",
+            QaCheck::TextRelocation => "files with text relocations",
+        }
+    }
+
+    /// Always a failure; the other notices only fail with FEATURES=stricter
+    fn always_fatal(&self) -> bool {
+        matches!(self, QaCheck::InsecureRunpath)
+    }
+
+    /// Informational only, even with FEATURES=stricter
+    fn informational(&self) -> bool {
+        matches!(self, QaCheck::SetXid)
+    }
+}
+
+/// One finding, with the path as it will be installed
+#[derive(Debug, Clone, PartialEq)]
+pub struct QaIssue {
+    pub check: QaCheck,
+    pub path: String,
+    pub detail: Option<String>,
+}
+
+/// Run every check on `destdir`; `workdir` is the build directory RUNPATHs must not
+/// point into and `root` is where absolute symlink targets may already exist
+pub fn scan_image(destdir: &Path, workdir: &Path, root: &Path) -> Vec<QaIssue> {
+    let mut files = Vec::new();
+    walk(destdir, &mut files);
+
+    let mut issues = Vec::new();
+    for file in files {
+        let Ok(metadata) = fs::symlink_metadata(&file) else {
+            continue;
+        };
+        let installed = format!("/{}", file.strip_prefix(destdir).unwrap_or(&file).display());
+        let issue = |check: QaCheck, detail: Option<String>| QaIssue { check, path: installed.clone(), detail };
+
+        if metadata.file_type().is_symlink() {
+            if let Ok(target) = fs::read_link(&file)
+                && !symlink_resolves(&file, &target, destdir, root)
+            {
+                issues.push(issue(QaCheck::BrokenSymlink, Some(format!("-> {}", target.display()))));
+            }
+            continue;
+        }
+
+        let mode = metadata.permissions().mode();
+        if metadata.is_file() && mode & 0o6000 != 0 {
+            issues.push(issue(QaCheck::SetXid, Some(format!("mode {:o}", mode & 0o7777))));
+        }
+        // World writable directories are fine when sticky, like /tmp
+        if mode & 0o002 != 0 && !(metadata.is_dir() && mode & 0o1000 != 0) {
+            issues.push(issue(QaCheck::WorldWritable, None));
+        }
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let Some(elf) = crate::elf::read_elf(&file) else {
+            continue;
+        };
+        let name = file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        if elf.soname.is_none() && name.starts_with("lib") && (name.ends_with(".so") || name.contains(".so.")) {
+            issues.push(issue(QaCheck::MissingSoname, None));
+        }
+        let workdir = workdir.to_string_lossy();
+        for rpath in &elf.rpath {
+            let relative = !rpath.starts_with('/') && !rpath.starts_with("$ORIGIN") && !rpath.starts_with("${ORIGIN}");
+            if relative || rpath.starts_with(workdir.as_ref()) {
+                issues.push(issue(QaCheck::InsecureRunpath, Some(rpath.clone())));
+            }
+        }
+        if elf.textrel {
+            issues.push(issue(QaCheck::TextRelocation, None));
+        }
+    }
+    issues
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+    entries.sort();
+    for path in entries {
+        let is_dir = fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir());
+        files.push(path.clone());
+        if is_dir {
+            walk(&path, files);
+        }
+    }
+}
+
+/// Whether a link in the image points at something in the image or already on ROOT
+fn symlink_resolves(link: &Path, target: &Path, destdir: &Path, root: &Path) -> bool {
+    if target.is_absolute() {
+        let relative = target.strip_prefix("/").unwrap_or(target);
+        return destdir.join(relative).exists() || root.join(relative).exists();
+    }
+    let parent = link.parent().unwrap_or(destdir);
+    if parent.join(target).exists() {
+        return true;
+    }
+    // A relative link may also reach files only present on ROOT
+    let installed_parent = parent.strip_prefix(destdir).unwrap_or(parent);
+    root.join(installed_parent).join(target).exists()
+}
+
+/// Print the findings as QA notices, also recording them for elog in `tempdir`; errors when
+/// a finding is fatal (always, or with FEATURES=stricter)
+pub fn report(issues: &[QaIssue], stricter: bool, tempdir: Option<&Path>) -> Result<(), InvalidData> {
+    let mut lines = Vec::new();
+    let mut fatal = Vec::new();
+    for check in [QaCheck::InsecureRunpath, QaCheck::TextRelocation, QaCheck::MissingSoname,
+                  QaCheck::WorldWritable, QaCheck::BrokenSymlink, QaCheck::SetXid] {
+        let found: Vec<&QaIssue> = issues.iter().filter(|issue| issue.check == check).collect();
+        if found.is_empty() {
+            continue;
+        }
+        lines.push(format!("QA Notice: {}:", check.description()));
+        for issue in found {
+            match &issue.detail {
+                Some(detail) => lines.push(format!("  {} ({})", issue.path, detail)),
+                None => lines.push(format!("  {}", issue.path)),
+            }
+        }
+        if check.always_fatal() || (stricter && !check.informational()) {
+            fatal.push(check.description());
+        }
+    }
+
+    for line in &lines {
+        eprintln!(" * {}", line);
+    }
+    if let Some(tempdir) = tempdir
+        && !lines.is_empty()
+    {
+        use std::io::Write;
+        let logging = tempdir.join("logging");
+        let _ = fs::create_dir_all(&logging);
+        if let Ok(mut log) = fs::OpenOptions::new().create(true).append(true).open(logging.join("install")) {
+            for line in &lines {
+                let _ = writeln!(log, "QA {}", line);
+            }
+        }
+    }
+
+    if fatal.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidData::new(&format!("Install QA failed: {}", fatal.join(", ")), None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tests::build_elf64_with;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_scan_image() {
+        let temp = TempDir::new().unwrap();
+        let (image, root) = (temp.path().join("image"), temp.path().join("root"));
+        let workdir = temp.path().join("work");
+        fs::create_dir_all(image.join("usr/lib")).unwrap();
+        fs::create_dir_all(image.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/os-release"), "").unwrap();
+
+        fs::write(image.join("usr/lib/libgood.so.1"), build_elf64_with(Some("libgood.so.1"), &[], Some("$ORIGIN"), false)).unwrap();
+        fs::write(image.join("usr/lib/libnosoname.so"), build_elf64_with(None, &[], None, true)).unwrap();
+        let runpath = format!("{}/build/lib", workdir.display());
+        fs::write(image.join("usr/bin/tool"), build_elf64_with(None, &[], Some(&runpath), false)).unwrap();
+        fs::set_permissions(image.join("usr/bin/tool"), fs::Permissions::from_mode(0o4757)).unwrap();
+        std::os::unix::fs::symlink("libgood.so.1", image.join("usr/lib/libgood.so")).unwrap();
+        std::os::unix::fs::symlink("/etc/os-release", image.join("usr/lib/os-release")).unwrap();
+        std::os::unix::fs::symlink("libmissing.so.2", image.join("usr/lib/libmissing.so")).unwrap();
+
+        let mut found: Vec<(QaCheck, String)> = scan_image(&image, &workdir, &root).into_iter().map(|i| (i.check, i.path)).collect();
+        found.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(found, vec![
+            (QaCheck::SetXid, "/usr/bin/tool".to_string()),
+            (QaCheck::WorldWritable, "/usr/bin/tool".to_string()),
+            (QaCheck::InsecureRunpath, "/usr/bin/tool".to_string()),
+            (QaCheck::BrokenSymlink, "/usr/lib/libmissing.so".to_string()),
+            (QaCheck::MissingSoname, "/usr/lib/libnosoname.so".to_string()),
+            (QaCheck::TextRelocation, "/usr/lib/libnosoname.so".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_report_severity() {
+        let issue = |check| QaIssue { check, path: "/usr/lib/libfoo.so".to_string(), detail: None };
+        let temp = TempDir::new().unwrap();
+
+        assert!(report(&[issue(QaCheck::TextRelocation), issue(QaCheck::SetXid)], false, Some(temp.path())).is_ok());
+        assert!(report(&[issue(QaCheck::SetXid)], true, None).is_ok());
+        assert!(report(&[issue(QaCheck::TextRelocation)], true, None).is_err());
+        assert!(report(&[issue(QaCheck::InsecureRunpath)], false, None).is_err());
+
+        let logged = fs::read_to_string(temp.path().join("logging/install")).unwrap();
+        assert_eq!(logged, "QA QA Notice: files with text relocations:\nQA   /usr/lib/libfoo.so\nQA QA Notice: setXid files found:\nQA   /usr/lib/libfoo.so\n");
+    }
+}
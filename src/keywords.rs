@@ -0,0 +1,223 @@
+// keywords.rs -- KEYWORDS visibility (ARCH, ACCEPT_KEYWORDS, package.accept_keywords)
+
+use std::collections::HashMap;
+
+use crate::atom::Atom;
+use crate::config::Config;
+
+/// Decides which ebuild versions are visible for the configured architecture
+#[derive(Debug, Clone, Default)]
+pub struct KeywordFilter {
+    pub arch: Option<String>,
+    pub accept_keywords: Vec<String>,
+    pub package_keywords: Vec<(Atom, Vec<String>)>,
+}
+
+/// A version hidden because none of its KEYWORDS are accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeywordMasked {
+    pub cpv: String,
+    pub keywords: Vec<String>,
+    /// Keyword to add to package.accept_keywords to make the version visible
+    pub suggested: String,
+}
+
+impl KeywordMasked {
+    /// Reason in emerge's "masked by" wording
+    pub fn reason(&self) -> String {
+        if self.suggested == "**" {
+            "missing keyword".to_string()
+        } else {
+            format!("{} keyword", self.suggested)
+        }
+    }
+
+    /// Line to add to package.accept_keywords
+    pub fn accept_keywords_entry(&self) -> String {
+        format!("={} {}", self.cpv, self.suggested)
+    }
+}
+
+impl KeywordFilter {
+    pub fn new(arch: Option<&str>, accept_keywords: Vec<String>) -> Self {
+        KeywordFilter {
+            arch: arch.map(|a| a.to_string()),
+            accept_keywords,
+            package_keywords: vec![],
+        }
+    }
+
+    /// Add per-package entries (package.accept_keywords format); a bare atom accepts ~ARCH
+    pub fn with_package_keywords(mut self, entries: &HashMap<String, Vec<String>>) -> Self {
+        let mut parsed: Vec<(Atom, Vec<String>)> = entries.iter()
+            .filter_map(|(atom, keywords)| Atom::new(atom).ok().map(|a| (a, keywords.clone())))
+            .collect();
+        parsed.sort_by_key(|(atom, _)| atom.to_string());
+        self.package_keywords.extend(parsed);
+        self
+    }
+
+    /// ARCH, ACCEPT_KEYWORDS and per-package keywords from the profile and /etc/portage
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.get_var("ARCH").map(|a| a.as_str()), config.accept_keywords.clone())
+            .with_package_keywords(&config.profile_settings.package_keywords)
+            .with_package_keywords(&config.package_keywords)
+    }
+
+    /// Without ARCH or ACCEPT_KEYWORDS there is nothing to filter against
+    pub fn is_enabled(&self) -> bool {
+        self.arch.is_some() || !self.accept_keywords.is_empty()
+    }
+
+    /// The architecture keyword, falling back to the first stable ACCEPT_KEYWORDS entry
+    fn arch(&self) -> Option<String> {
+        self.arch.clone().or_else(|| {
+            self.accept_keywords.iter()
+                .map(|k| k.trim_start_matches('~'))
+                .find(|k| !k.is_empty() && !k.starts_with('-') && *k != "*" && *k != "**")
+                .map(|k| k.to_string())
+        })
+    }
+
+    /// Accepted keywords for `cpv` after applying per-package entries.
+    /// "-kw" removes a keyword and "-*" clears everything before it.
+    pub fn accepted_for(&self, cpv: &str) -> Vec<String> {
+        let mut incoming: Vec<String> = if self.accept_keywords.is_empty() {
+            self.arch.iter().cloned().collect()
+        } else {
+            self.accept_keywords.clone()
+        };
+        for (atom, keywords) in &self.package_keywords {
+            if !atom.matches(cpv) {
+                continue;
+            }
+            if keywords.is_empty() {
+                incoming.extend(self.arch().map(|a| format!("~{}", a)));
+            } else {
+                incoming.extend(keywords.iter().cloned());
+            }
+        }
+
+        let mut accepted: Vec<String> = Vec::new();
+        for keyword in incoming {
+            if keyword == "-*" {
+                accepted.clear();
+            } else if let Some(removed) = keyword.strip_prefix('-') {
+                accepted.retain(|k| k != removed);
+            } else if !accepted.contains(&keyword) {
+                accepted.push(keyword);
+            }
+        }
+        accepted
+    }
+
+    /// Whether an ebuild with `keywords` is visible
+    pub fn is_accepted(&self, cpv: &str, keywords: &[String]) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let accepted = self.accepted_for(cpv);
+        if accepted.iter().any(|k| k == "**") {
+            return true;
+        }
+        keywords.iter().filter(|kw| !kw.starts_with('-')).any(|kw| {
+            if accepted.contains(kw) {
+                return true;
+            }
+            match kw.strip_prefix('~') {
+                Some(_) => accepted.iter().any(|k| k == "~*"),
+                // Accepting ~arch also accepts stable arch
+                None => accepted.iter().any(|k| k == "*" || *k == format!("~{}", kw)),
+            }
+        })
+    }
+
+    /// Explain why `cpv` is hidden, or None when it is visible
+    pub fn check(&self, cpv: &str, keywords: &[String]) -> Option<KeywordMasked> {
+        if self.is_accepted(cpv, keywords) {
+            return None;
+        }
+        let suggested = match self.arch() {
+            Some(arch) if keywords.contains(&format!("~{}", arch)) => format!("~{}", arch),
+            _ => "**".to_string(),
+        };
+        Some(KeywordMasked {
+            cpv: cpv.to_string(),
+            keywords: keywords.to_vec(),
+            suggested,
+        })
+    }
+}
+
+/// Extract KEYWORDS from ebuild source, accepting quoted strings and bash arrays
+pub fn parse_ebuild_keywords(content: &str) -> Vec<String> {
+    for line in content.lines() {
+        let Some(value) = line.trim().strip_prefix("KEYWORDS=") else {
+            continue;
+        };
+        let value = value.trim()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .trim_matches(|c| c == '"' || c == '\'');
+        return value.split_whitespace()
+            .map(|k| k.trim_matches(|c| c == '"' || c == '\'').to_string())
+            .filter(|k| !k.is_empty())
+            .collect();
+    }
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kw(list: &str) -> Vec<String> {
+        list.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_stable_and_testing_keywords() {
+        let filter = KeywordFilter::new(Some("amd64"), kw("amd64"));
+        assert!(filter.is_accepted("app-misc/foo-1.0", &kw("amd64 ~x86")));
+        assert!(!filter.is_accepted("app-misc/foo-1.1", &kw("~amd64 x86")));
+        assert!(!filter.is_accepted("app-misc/foo-9999", &[]));
+
+        let testing = KeywordFilter::new(Some("amd64"), kw("amd64 ~amd64"));
+        assert!(testing.is_accepted("app-misc/foo-1.1", &kw("~amd64")));
+
+        let disabled = KeywordFilter::default();
+        assert!(disabled.is_accepted("app-misc/foo-9999", &[]));
+    }
+
+    #[test]
+    fn test_package_accept_keywords() {
+        let mut entries = HashMap::new();
+        entries.insert("app-misc/foo".to_string(), vec![]);
+        entries.insert("=app-misc/bar-2.0".to_string(), kw("**"));
+        let filter = KeywordFilter::new(Some("amd64"), kw("amd64")).with_package_keywords(&entries);
+
+        assert!(filter.is_accepted("app-misc/foo-1.1", &kw("~amd64")));
+        assert!(filter.is_accepted("app-misc/bar-2.0", &[]));
+        assert!(!filter.is_accepted("app-misc/bar-2.1", &kw("~amd64")));
+    }
+
+    #[test]
+    fn test_masked_suggestion() {
+        let filter = KeywordFilter::new(Some("amd64"), kw("amd64"));
+        let masked = filter.check("app-misc/foo-1.1", &kw("~amd64 ~x86")).unwrap();
+        assert_eq!(masked.reason(), "~amd64 keyword");
+        assert_eq!(masked.accept_keywords_entry(), "=app-misc/foo-1.1 ~amd64");
+
+        let masked = filter.check("app-misc/foo-9999", &[]).unwrap();
+        assert_eq!(masked.reason(), "missing keyword");
+        assert_eq!(masked.accept_keywords_entry(), "=app-misc/foo-9999 **");
+        assert!(filter.check("app-misc/foo-1.0", &kw("amd64")).is_none());
+    }
+
+    #[test]
+    fn test_parse_ebuild_keywords() {
+        assert_eq!(parse_ebuild_keywords("EAPI=8\nKEYWORDS=\"~amd64 x86\"\n"), kw("~amd64 x86"));
+        assert_eq!(parse_ebuild_keywords("KEYWORDS=( \"amd64\" )"), kw("amd64"));
+        assert!(parse_ebuild_keywords("EAPI=8\n").is_empty());
+    }
+}
@@ -1,25 +1,75 @@
+//! emerge-rs: a Gentoo package manager. The `api` module is the stable library interface:
+//! `sync` and `resolve` return structured reports and an `Error` enum and print nothing.
+//! The other modules are the building blocks behind it and the emerge command line, and
+//! may change between releases.
+
  pub mod actions;
+ pub mod api;
  pub mod atom;
+ pub mod autotools;
+ pub mod autounmask;
+ pub mod binhost;
  pub mod bintree;
+ pub mod binpkg;
+ pub mod cargo;
+ pub mod changelog;
+ pub mod checksum;
  pub mod config;
+ pub mod configprotect;
+ pub mod contents;
  pub mod dep;
  pub mod dep_check;
+ pub mod depclean;
  pub mod depgraph;
  pub mod doebuild;
+ pub mod elf;
+ pub mod elog;
+ pub mod emergelog;
+ pub mod eapply;
  pub mod ebuild_exec;
+ pub mod eclass;
+ pub mod ecompress;
+ pub mod econf;
  pub mod emerge_config;
+ pub mod estrip;
  pub mod exception;
+ pub mod fetch;
+ pub mod git_r3;
+ pub mod glsa;
+ pub mod go_module;
+ pub mod install_helpers;
+ pub mod install_qa;
+ pub mod keywords;
  pub mod license;
+ pub mod linkage;
+ pub mod locks;
+ pub mod manifest;
  pub mod mask;
  pub mod merge;
+ pub mod metadata_cache;
+ pub mod multilib;
  pub mod news;
+ pub mod output;
   pub mod porttree;
+ pub mod preserved_libs;
+ pub mod python;
   pub mod profile;
+ pub mod progress;
+ pub mod required_use;
+ pub mod sandbox;
+  pub mod scheduler;
+ pub mod search_index;
   pub mod sets;
+ pub mod signals;
  pub mod sync;
+ pub mod toolchain;
+ pub mod user_patches;
  pub mod util;
  pub mod vartree;
+ pub mod vdb;
  pub mod versions;
  pub mod world;
  pub mod xml;
  pub mod xpak;
+
+pub use api::{Error, MergePlan, Resolution, ResolutionReport, SyncOptions, SyncReport};
@@ -4,11 +4,21 @@ use std::io;
 use std::path::{Path, PathBuf};
 use crate::exception::InvalidData;
 
+/// Stand-in for @FREE when no repository provides profiles/license_groups
+const FREE_LICENSES: &[&str] = &[
+    "GPL-2", "GPL-3", "LGPL-2.1", "LGPL-3", "BSD", "MIT", "Apache-2.0",
+    "ISC", "CC0-1.0", "ZLIB", "Boost-1.0", "PostgreSQL", "OpenSSL",
+];
+
 /// License manager for handling license acceptance
 pub struct LicenseManager {
     root: String,
     accepted_licenses_file: PathBuf,
     package_license_dir: PathBuf,
+    /// ACCEPT_LICENSE tokens, evaluated left to right
+    accept_license: Vec<String>,
+    /// Repositories whose profiles/license_groups define @GROUP names
+    repositories: Vec<PathBuf>,
 }
 
 impl LicenseManager {
@@ -19,7 +29,87 @@ impl LicenseManager {
             root: root.to_string(),
             accepted_licenses_file: root_path.join("var/lib/portage/license-accepted"),
             package_license_dir: root_path.join("etc/portage/package.license"),
+            accept_license: vec!["-*".to_string(), "@FREE".to_string()],
+            repositories: vec![root_path.join("var/db/repos/gentoo")],
+        }
+    }
+
+    /// Use this ACCEPT_LICENSE instead of the default "-* @FREE"
+    pub fn with_accept_license(mut self, accept_license: &str) -> Self {
+        self.accept_license = accept_license.split_whitespace().map(|token| token.to_string()).collect();
+        self
+    }
+
+    /// Read license_groups from these repositories instead of the default gentoo location
+    pub fn with_repositories(mut self, repositories: Vec<PathBuf>) -> Self {
+        self.repositories = repositories;
+        self
+    }
+
+    /// Groups from profiles/license_groups ("GROUP license @OTHER ..."), later
+    /// repositories extending earlier definitions
+    pub fn license_groups(&self) -> HashMap<String, Vec<String>> {
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        for repo in &self.repositories {
+            let content = fs::read_to_string(repo.join("profiles/license_groups")).unwrap_or_default();
+            for line in content.lines() {
+                let mut parts = line.split('#').next().unwrap_or("").split_whitespace();
+                if let Some(group) = parts.next() {
+                    groups.entry(group.to_string()).or_default().extend(parts.map(|l| l.to_string()));
+                }
+            }
+        }
+        groups.entry("FREE".to_string())
+            .or_insert_with(|| FREE_LICENSES.iter().map(|l| l.to_string()).collect());
+        groups
+    }
+
+    /// Licenses in a group, with nested @groups expanded
+    pub fn expand_group(groups: &HashMap<String, Vec<String>>, group: &str) -> HashSet<String> {
+        let mut licenses = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![group.to_string()];
+        while let Some(group) = pending.pop() {
+            if !seen.insert(group.clone()) {
+                continue;
+            }
+            for entry in groups.get(&group).into_iter().flatten() {
+                match entry.strip_prefix('@') {
+                    Some(nested) => pending.push(nested.to_string()),
+                    None => {
+                        licenses.insert(entry.clone());
+                    }
+                }
+            }
         }
+        licenses
+    }
+
+    /// Evaluate ACCEPT_LICENSE-style tokens (*, -*, @GROUP, -@GROUP, LICENSE,
+    /// -LICENSE) for one license; the last token that names it wins
+    pub fn tokens_accept(tokens: &[String], groups: &HashMap<String, Vec<String>>, license: &str) -> bool {
+        let mut accepted = false;
+        for token in tokens {
+            let (negated, name) = match token.strip_prefix('-') {
+                Some(name) => (true, name),
+                None => (false, token.as_str()),
+            };
+            let names_license = match name.strip_prefix('@') {
+                Some(group) => Self::expand_group(groups, group).contains(license),
+                None => name == "*" || name == license,
+            };
+            if names_license {
+                accepted = !negated;
+            }
+        }
+        accepted
+    }
+
+    /// ACCEPT_LICENSE followed by the package.license tokens of entries matching `cpv`
+    fn tokens_for(&self, cpv: &str) -> Result<Vec<String>, InvalidData> {
+        let mut tokens = self.accept_license.clone();
+        tokens.extend(self.package_license_tokens(cpv)?);
+        Ok(tokens)
     }
 
     /// Parse a license string into individual license groups
@@ -78,20 +168,16 @@ impl LicenseManager {
             return Ok(true);
         }
 
-        // Get accepted licenses
-        let accepted_licenses = self.get_accepted_licenses()?;
-
         // Check if any group is fully accepted
-        for group in &license_groups {
-            if group.iter().all(|license| accepted_licenses.contains(license)) {
-                return Ok(true);
-            }
-        }
-
-        Ok(false)
+        let accepted_licenses = self.get_accepted_licenses()?;
+        let groups = self.license_groups();
+        let accepted = |license: &String| {
+            accepted_licenses.contains(license) || Self::tokens_accept(&self.accept_license, &groups, license)
+        };
+        Ok(license_groups.iter().any(|group| group.iter().all(accepted)))
     }
 
-    /// Get all accepted licenses from various sources
+    /// Licenses accepted interactively, from /var/lib/portage/license-accepted
     pub fn get_accepted_licenses(&self) -> Result<HashSet<String>, InvalidData> {
         let mut accepted = HashSet::new();
 
@@ -108,21 +194,53 @@ impl LicenseManager {
             }
         }
 
-        // TODO: Add licenses from /etc/portage/package.license files
-        // This would require parsing package.license files which can have complex syntax
+        Ok(accepted)
+    }
 
-        // Add common accepted licenses (Gentoo defaults)
-        // In a real implementation, this would come from make.conf ACCEPT_LICENSE
-        let default_accepted = [
-            "GPL-2", "GPL-3", "LGPL-2.1", "LGPL-3", "BSD", "MIT", "Apache-2.0",
-            "ISC", "CC0-1.0", "ZLIB", "Boost-1.0", "PostgreSQL", "OpenSSL",
-        ];
+    /// Tokens that /etc/portage/package.license entries matching `cpv` add, in file order
+    pub fn package_license_tokens(&self, cpv: &str) -> Result<Vec<String>, InvalidData> {
+        let mut files = Vec::new();
+        if self.package_license_dir.is_dir() {
+            let entries = fs::read_dir(&self.package_license_dir)
+                .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", self.package_license_dir.display(), e), None))?;
+            files.extend(entries.flatten().map(|e| e.path()).filter(|p| p.is_file()));
+            files.sort();
+        } else if self.package_license_dir.is_file() {
+            files.push(self.package_license_dir.clone());
+        }
 
-        for license in &default_accepted {
-            accepted.insert(license.to_string());
+        let mut tokens = Vec::new();
+        for file in files {
+            let content = fs::read_to_string(&file)
+                .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", file.display(), e), None))?;
+            for line in content.lines() {
+                let mut parts = line.split('#').next().unwrap_or("").split_whitespace();
+                let Some(atom) = parts.next() else {
+                    continue;
+                };
+                if crate::atom::Atom::new(atom).map(|a| a.matches(cpv)).unwrap_or(false) {
+                    tokens.extend(parts.map(|l| l.to_string()));
+                }
+            }
         }
+        Ok(tokens)
+    }
 
-        Ok(accepted)
+    /// Smallest set of licenses that must be accepted for `cpv` to be installable,
+    /// empty when its LICENSE is already satisfied
+    pub fn missing_licenses(&self, cpv: &str, license_str: &str) -> Result<Vec<String>, InvalidData> {
+        let interactive = self.get_accepted_licenses()?;
+        let tokens = self.tokens_for(cpv)?;
+        let groups = self.license_groups();
+
+        let missing = Self::parse_license_string(license_str)?
+            .into_iter()
+            .map(|group| group.into_iter()
+                .filter(|l| !interactive.contains(l) && !Self::tokens_accept(&tokens, &groups, l))
+                .collect::<Vec<_>>())
+            .min_by_key(|missing| missing.len())
+            .unwrap_or_default();
+        Ok(missing)
     }
 
     /// Accept a license by adding it to the accepted licenses file
@@ -168,16 +286,8 @@ impl LicenseManager {
         for cpv in packages {
             if let Some(metadata) = porttree.get_metadata(cpv).await {
                 if let Some(license_str) = metadata.get("LICENSE") {
-                    if !self.is_license_accepted(license_str)? {
-                        // Parse license groups and collect unaccepted ones
-                        let groups = Self::parse_license_string(license_str)?;
-                        for group in groups {
-                            for license in group {
-                                if !self.get_accepted_licenses()?.contains(&license) {
-                                    unaccepted_licenses.push((cpv.clone(), license));
-                                }
-                            }
-                        }
+                    for license in self.missing_licenses(cpv, license_str)? {
+                        unaccepted_licenses.push((cpv.clone(), license));
                     }
                 }
             }
@@ -198,6 +308,7 @@ impl LicenseManager {
 
         for (cpv, licenses) in &package_licenses {
             println!("{}: {}", cpv, licenses.join(", "));
+            println!("  package.license: >={} {}", cpv, licenses.join(" "));
         }
 
         println!();
@@ -298,4 +409,52 @@ mod tests {
         // Now UNKNOWN1 || UNKNOWN2 should be accepted
         assert!(manager.is_license_accepted("UNKNOWN1 || UNKNOWN2").unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_missing_licenses_with_package_license() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let manager = LicenseManager::new(temp_dir.path().to_str().unwrap());
+
+        assert!(manager.missing_licenses("app-misc/foo-1.0", "GPL-2").unwrap().is_empty());
+        assert_eq!(manager.missing_licenses("app-misc/foo-1.0", "EULA").unwrap(), vec!["EULA".to_string()]);
+        assert!(manager.missing_licenses("app-misc/foo-1.0", "EULA || ( MIT )").unwrap().is_empty());
+
+        let license_dir = temp_dir.path().join("etc/portage/package.license");
+        fs::create_dir_all(&license_dir).unwrap();
+        fs::write(license_dir.join("zz-autounmask"), ">=app-misc/foo-1.0 EULA\n").unwrap();
+        assert!(manager.missing_licenses("app-misc/foo-1.0", "EULA").unwrap().is_empty());
+        assert!(!manager.missing_licenses("app-misc/bar-1.0", "EULA").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_license_groups() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let repo = temp_dir.path().join("var/db/repos/gentoo");
+        fs::create_dir_all(repo.join("profiles")).unwrap();
+        fs::write(repo.join("profiles/license_groups"), concat!(
+            "# Licenses approved by the FSF\n",
+            "FSF-APPROVED GPL-2 GPL-3 LGPL-2.1\n",
+            "OSI-APPROVED MIT BSD\n",
+            "FREE @FSF-APPROVED @OSI-APPROVED\n",
+            "EULA Broadcom NVIDIA-r2 # proprietary\n",
+            "BINARY-REDISTRIBUTABLE @FREE NVIDIA-r2 Broadcom\n",
+        )).unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+
+        let groups = LicenseManager::new(root).license_groups();
+        let free = LicenseManager::expand_group(&groups, "FREE");
+        assert_eq!(free.len(), 5);
+        assert!(free.contains("MIT") && free.contains("LGPL-2.1"));
+
+        let manager = LicenseManager::new(root).with_accept_license("-* @BINARY-REDISTRIBUTABLE -@EULA Broadcom");
+        assert!(manager.missing_licenses("app-misc/foo-1.0", "GPL-2").unwrap().is_empty());
+        assert!(manager.missing_licenses("net-wireless/b43-1", "Broadcom").unwrap().is_empty());
+        assert_eq!(manager.missing_licenses("x11-drivers/nvidia-550", "NVIDIA-r2").unwrap(), vec!["NVIDIA-r2"]);
+        assert_eq!(manager.missing_licenses("app-misc/foo-1.0", "( GPL-2 NVIDIA-r2 ) || UNKNOWN").unwrap(), vec!["NVIDIA-r2"]);
+
+        fs::create_dir_all(temp_dir.path().join("etc/portage")).unwrap();
+        fs::write(temp_dir.path().join("etc/portage/package.license"), "x11-drivers/nvidia @EULA\napp-misc/foo -@FREE\n").unwrap();
+        assert!(manager.missing_licenses("x11-drivers/nvidia-550", "NVIDIA-r2").unwrap().is_empty());
+        assert_eq!(manager.missing_licenses("app-misc/foo-1.0", "GPL-2").unwrap(), vec!["GPL-2"]);
+    }
+}
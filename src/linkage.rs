@@ -0,0 +1,223 @@
+// linkage.rs -- broken library linkage, as revdep-rebuild finds it: installed ELF objects
+// whose NEEDED libraries the dynamic linker would not find, and the packages owning them
+
+use crate::elf::ElfInfo;
+use crate::vartree::VarTree;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Directories holding the executables and libraries that are checked
+pub const SEARCH_DIRS: &[&str] = &[
+    "bin", "sbin", "usr/bin", "usr/sbin", "usr/libexec",
+    "lib", "lib32", "lib64", "usr/lib", "usr/lib32", "usr/lib64",
+];
+
+/// Trees under the search directories without anything that links
+const SKIP_DIRS: &[&str] = &["lib/modules", "lib/firmware", "usr/lib/modules", "usr/lib/firmware", "usr/lib/debug"];
+
+/// Where the dynamic linker looks after RPATH/RUNPATH and ld.so.conf
+const TRUSTED_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+
+/// An installed object with libraries missing
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokenObject {
+    pub path: String,
+    /// NEEDED entries that resolve to no library of the object's ABI
+    pub missing: Vec<String>,
+    /// Packages whose CONTENTS list the object
+    pub owners: Vec<String>,
+}
+
+/// The ABI a library has to match, as in PROVIDES/REQUIRES
+fn category(info: &ElfInfo) -> &str {
+    if info.multilib_category.is_empty() { &info.arch } else { &info.multilib_category }
+}
+
+/// The library directories of ld.so.conf under `root`, following its include lines
+pub fn ld_so_conf_dirs(root: &Path) -> Vec<String> {
+    let mut dirs = Vec::new();
+    read_ld_so_conf(root, &root.join("etc/ld.so.conf"), &mut dirs, 0);
+    dirs
+}
+
+fn read_ld_so_conf(root: &Path, path: &Path, dirs: &mut Vec<String>, depth: usize) {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return;
+    };
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some(pattern) = line.strip_prefix("include ") {
+            if depth < 8 {
+                for file in glob_files(root, pattern.trim()) {
+                    read_ld_so_conf(root, &file, dirs, depth + 1);
+                }
+            }
+        } else if line.starts_with('/') && !dirs.iter().any(|dir| dir == line) {
+            dirs.push(line.to_string());
+        }
+    }
+}
+
+/// Files matching an ld.so.conf include pattern, relative to /etc unless absolute; only
+/// the file name may hold a '*'
+fn glob_files(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let pattern = match pattern.strip_prefix('/') {
+        Some(absolute) => absolute.to_string(),
+        None => format!("etc/{}", pattern),
+    };
+    let (dir, name) = pattern.rsplit_once('/').unwrap_or(("", &pattern));
+    let dir = root.join(dir);
+    let Some((prefix, suffix)) = name.split_once('*') else {
+        return vec![dir.join(name)];
+    };
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .map(|entries| {
+            entries.flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|file| file.len() >= prefix.len() + suffix.len() && file.starts_with(prefix) && file.ends_with(suffix))
+                .map(|file| dir.join(file))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Whether `path` starts with the ELF magic, checked before reading the whole file
+fn is_elf(path: &Path) -> bool {
+    use std::io::Read;
+    let mut magic = [0u8; 4];
+    std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == b"\x7fELF"
+}
+
+/// The dynamically linked objects under `dir`, as paths below `root`; symlinks are left
+/// out so that each object is seen once
+fn walk(root: &Path, dir: &str, objects: &mut Vec<(String, ElfInfo)>) {
+    if SKIP_DIRS.contains(&dir) {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(root.join(dir)) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+        if file_type.is_dir() {
+            walk(root, &path, objects);
+        } else if file_type.is_file() && is_elf(&entry.path())
+            && let Some(info) = crate::elf::read_elf(&entry.path())
+        {
+            objects.push((format!("/{}", path), info));
+        }
+    }
+}
+
+/// Finds NEEDED libraries the way the dynamic linker does, remembering the ABI of every
+/// file looked at
+struct Resolver<'a> {
+    root: &'a Path,
+    library_dirs: Vec<String>,
+    seen: HashMap<PathBuf, Option<String>>,
+}
+
+impl Resolver<'_> {
+    fn library_category(&mut self, path: PathBuf) -> Option<String> {
+        self.seen.entry(path)
+            .or_insert_with_key(|path| crate::elf::read_elf(path).map(|info| category(&info).to_string()))
+            .clone()
+    }
+
+    /// Whether `needed` of the object at `path` resolves through its RPATH/RUNPATH, then
+    /// ld.so.conf and the trusted directories
+    fn resolves(&mut self, path: &str, info: &ElfInfo, needed: &str) -> bool {
+        if needed.contains('/') {
+            return self.library_category(self.root.join(needed.trim_start_matches('/'))).is_some();
+        }
+        let origin = path.rsplit_once('/').map_or("/", |(dir, _)| dir);
+        let rpath = info.rpath.iter().map(|dir| dir.replace("${ORIGIN}", origin).replace("$ORIGIN", origin));
+        let dirs: Vec<String> = rpath.chain(self.library_dirs.iter().cloned()).collect();
+        let wanted = category(info).to_string();
+        dirs.iter().any(|dir| {
+            let candidate = self.root.join(dir.trim_start_matches('/')).join(needed);
+            self.library_category(candidate).as_deref() == Some(wanted.as_str())
+        })
+    }
+}
+
+/// The objects installed under `vartree`'s root with missing libraries, with their owners
+pub fn find_broken(vartree: &VarTree) -> Vec<BrokenObject> {
+    let root = Path::new(&vartree.root);
+    let mut objects = Vec::new();
+    for dir in SEARCH_DIRS {
+        walk(root, dir, &mut objects);
+    }
+    let mut library_dirs = ld_so_conf_dirs(root);
+    library_dirs.extend(TRUSTED_DIRS.iter().map(|dir| dir.to_string()));
+    let mut resolver = Resolver { root, library_dirs, seen: HashMap::new() };
+
+    let owners = vartree.file_owners();
+    let mut broken: Vec<BrokenObject> = objects.iter()
+        .filter_map(|(path, info)| {
+            let missing: Vec<String> = info.needed.iter().filter(|needed| !resolver.resolves(path, info, needed)).cloned().collect();
+            (!missing.is_empty()).then(|| BrokenObject {
+                path: path.clone(),
+                missing,
+                owners: owners.get(path).cloned().unwrap_or_default(),
+            })
+        })
+        .collect();
+    broken.sort_by(|a, b| a.path.cmp(&b.path));
+    broken
+}
+
+/// The packages owning broken objects, each once, to be rebuilt
+pub fn broken_packages(broken: &[BrokenObject]) -> Vec<String> {
+    let mut packages: Vec<String> = broken.iter().flat_map(|object| object.owners.iter().cloned()).collect();
+    packages.sort();
+    packages.dedup();
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tests::{build_elf64, build_elf64_with};
+    use tempfile::TempDir;
+
+    fn write(root: &Path, path: &str, data: &[u8]) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_find_broken() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        write(root, "usr/lib64/libgood.so.1", &build_elf64(Some("libgood.so.1"), &[]));
+        write(root, "usr/bin/ok", &build_elf64(None, &["libgood.so.1"]));
+        write(root, "usr/bin/broken", &build_elf64(None, &["libgood.so.1", "libgone.so.2"]));
+        write(root, "usr/lib64/private/libpriv.so", &build_elf64(Some("libpriv.so"), &[]));
+        write(root, "usr/bin/rpathed", &build_elf64_with(None, &["libpriv.so"], Some("$ORIGIN/../lib64/private"), false));
+        write(root, "opt/foo/lib/libopt.so.3", &build_elf64(Some("libopt.so.3"), &[]));
+        write(root, "usr/bin/optuser", &build_elf64(None, &["libopt.so.3"]));
+        write(root, "usr/bin/script", b"#!/bin/sh\n");
+        write(root, "etc/ld.so.conf", b"# comment\ninclude ld.so.conf.d/*.conf\n");
+        write(root, "etc/ld.so.conf.d/05foo.conf", b"/opt/foo/lib\n");
+        write(root, "etc/ld.so.conf.d/README", b"/nowhere\n");
+        write(root, "var/db/pkg/app-misc/broken-1/CONTENTS", b"obj /usr/bin/broken 0123456789abcdef0123456789abcdef 1700000000\n");
+        write(root, "var/db/pkg/app-misc/broken-1/SLOT", b"0\n");
+
+        assert_eq!(ld_so_conf_dirs(root), vec!["/opt/foo/lib"]);
+        let vartree = VarTree::new(root.to_str().unwrap());
+        let broken = find_broken(&vartree);
+        assert_eq!(broken, vec![BrokenObject {
+            path: "/usr/bin/broken".to_string(),
+            missing: vec!["libgone.so.2".to_string()],
+            owners: vec!["app-misc/broken-1".to_string()],
+        }]);
+        assert_eq!(broken_packages(&broken), vec!["app-misc/broken-1"]);
+    }
+}
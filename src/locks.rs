@@ -0,0 +1,118 @@
+// locks.rs -- flock based lock files shared with Portage: the emerge instance lock, the
+// package database lock and per-entry locks
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::exception::InvalidData;
+
+/// Held by the emerge instance that is merging or unmerging into ROOT
+pub const EMERGE_LOCK: &str = "run/lock/emerge.lock";
+
+/// An exclusive flock on a lock file, released on drop
+#[derive(Debug)]
+pub struct LockFile {
+    _file: File,
+    pub path: PathBuf,
+}
+
+impl LockFile {
+    /// Lock `path`, creating it as needed; when another process holds it, print `waiting`
+    /// and block until it is released
+    pub async fn acquire(path: &Path, waiting: &str) -> Result<Self, InvalidData> {
+        let file = open(path)?;
+        match file.try_lock() {
+            Ok(()) => return Ok(LockFile { _file: file, path: path.to_path_buf() }),
+            Err(std::fs::TryLockError::WouldBlock) => println!("{}", waiting),
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(InvalidData::new(&format!("Failed to lock {}: {}", path.display(), e), None));
+            }
+        }
+        // Block off the async workers until the other holder is done
+        let file = tokio::task::spawn_blocking(move || file.lock().map(|_| file))
+            .await
+            .map_err(|e| InvalidData::new(&format!("Lock task failed: {}", e), None))?
+            .map_err(|e| InvalidData::new(&format!("Failed to lock {}: {}", path.display(), e), None))?;
+        Ok(LockFile { _file: file, path: path.to_path_buf() })
+    }
+
+    /// Lock `path` only if nobody else holds it
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>, InvalidData> {
+        let file = open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(LockFile { _file: file, path: path.to_path_buf() })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(e)) => Err(InvalidData::new(&format!("Failed to lock {}: {}", path.display(), e), None)),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File, InvalidData> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+    }
+    File::options().create(true).truncate(false).write(true).open(path)
+        .map_err(|e| InvalidData::new(&format!("Failed to open {}: {}", path.display(), e), None))
+}
+
+/// Portage's lock file for a directory: `.<name>.portage_lockfile` next to it
+pub fn dir_lock_path(dir: &Path) -> PathBuf {
+    let name = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    dir.with_file_name(format!(".{}.portage_lockfile", name))
+}
+
+/// The emerge instance lock of `root`, waiting for any other instance merging there
+pub async fn lock_emerge(root: &str) -> Result<LockFile, InvalidData> {
+    LockFile::acquire(&Path::new(root).join(EMERGE_LOCK),
+        ">>> Another instance of emerge-rs is running; waiting for it to finish (--nolock skips this)").await
+}
+
+/// Locks held while an entry of the package database of `root` is written or removed
+#[derive(Debug)]
+pub struct VdbLock {
+    _vdb: LockFile,
+    _entry: LockFile,
+}
+
+/// Lock /var/db/pkg as a whole, as Portage's vardbapi does, then the entry of `cpv`
+pub async fn lock_vdb(root: &str, cpv: &str) -> Result<VdbLock, InvalidData> {
+    let vdb = Path::new(root).join("var/db/pkg");
+    let vdb_lock = LockFile::acquire(&dir_lock_path(&vdb), ">>> Waiting for the package database lock").await?;
+    let entry_lock = LockFile::acquire(&dir_lock_path(&vdb.join(cpv)), &format!(">>> Waiting for the lock on {}", cpv)).await?;
+    Ok(VdbLock { _vdb: vdb_lock, _entry: entry_lock })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dir_lock_path() {
+        assert_eq!(dir_lock_path(Path::new("/var/db/pkg")), PathBuf::from("/var/db/.pkg.portage_lockfile"));
+        assert_eq!(dir_lock_path(Path::new("/var/db/pkg/app-misc/foo-1.0")), PathBuf::from("/var/db/pkg/app-misc/.foo-1.0.portage_lockfile"));
+    }
+
+    #[tokio::test]
+    async fn test_locks_exclude_each_other() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().to_string_lossy().to_string();
+
+        let held = lock_emerge(&root).await.unwrap();
+        assert!(held.path.ends_with(EMERGE_LOCK));
+        assert!(LockFile::try_acquire(&held.path).unwrap().is_none());
+
+        // Waiting ends once the holder lets go
+        let path = held.path.clone();
+        let waiter = tokio::spawn(async move { LockFile::acquire(&path, "waiting").await.map(|_| ()) });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        drop(held);
+        waiter.await.unwrap().unwrap();
+
+        let _vdb = lock_vdb(&root, "app-misc/foo-1.0").await.unwrap();
+        assert!(temp.path().join("var/db/.pkg.portage_lockfile").exists());
+        assert!(LockFile::try_acquire(&temp.path().join("var/db/pkg/app-misc/.foo-1.0.portage_lockfile")).unwrap().is_none());
+    }
+}
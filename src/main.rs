@@ -2,6 +2,9 @@ use clap::{Arg, ArgMatches, Command};
 use std::process;
 
 use emerge_rs::actions;
+use emerge_rs::emerge_config::{EmergeOptions, SearchOptions};
+use emerge_rs::emergelog;
+use emerge_rs::signals;
 
 #[tokio::main]
 async fn main() {
@@ -10,7 +13,12 @@ async fn main() {
     let app = create_app();
     let matches = app.get_matches();
 
+    signals::install();
     let result = run_emerge(matches).await;
+    emergelog::finish(if signals::interrupted() { signals::EXIT_INTERRUPTED } else { result });
+    if signals::interrupted() {
+        signals::exit_interrupted();
+    }
     process::exit(result);
 }
 
@@ -40,6 +48,38 @@ fn create_app() -> Command {
                 .help("Verbose output")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("tree")
+                .long("tree")
+                .short('t')
+                .help("Show the merge list as a tree of dependencies")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("changelog")
+                .long("changelog")
+                .help("With --pretend or --ask, show the changes of each upgrade since the installed version")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .help("Enable or disable colored output")
+                .value_parser(["y", "n"]),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print the --pretend merge plan, --search, --info and --sync results as JSON")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("output_format")
+                .long("output-format")
+                .help("Output format; json is the same as --json")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
         .arg(
             Arg::new("quiet")
                 .long("quiet")
@@ -47,6 +87,43 @@ fn create_app() -> Command {
                 .help("Quiet output")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("oneshot")
+                .long("oneshot")
+                .short('1')
+                .help("Do not add the packages to the world file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("keep_going")
+                .long("keep-going")
+                .help("Continue as much as possible after a package fails to build")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("root")
+                .long("root")
+                .value_name("DIR")
+                .help("Merge packages into DIR instead of / (also ROOT)"),
+        )
+        .arg(
+            Arg::new("sysroot")
+                .long("sysroot")
+                .value_name("DIR")
+                .help("Build against the libraries and headers in DIR (also SYSROOT)"),
+        )
+        .arg(
+            Arg::new("config_root")
+                .long("config-root")
+                .value_name("DIR")
+                .help("Read the configuration from DIR/etc/portage (also PORTAGE_CONFIGROOT)"),
+        )
+        .arg(
+            Arg::new("nolock")
+                .long("nolock")
+                .help("Do not lock out other emerge instances or lock the package database")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("update")
                 .long("update")
@@ -61,6 +138,13 @@ fn create_app() -> Command {
                 .help("Consider the entire dependency tree")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("emptytree")
+                .long("emptytree")
+                .short('e')
+                .help("Reinstall the target and its entire dependency tree")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("newuse")
                 .long("newuse")
@@ -75,6 +159,13 @@ fn create_app() -> Command {
                 .help("Resume interrupted operations")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("skipfirst")
+                .long("skipfirst")
+                .help("With --resume, skip the first package of the resume list")
+                .requires("resume")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("jobs")
                 .long("jobs")
@@ -83,17 +174,224 @@ fn create_app() -> Command {
                 .value_parser(clap::value_parser!(usize))
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("fetchonly")
+                .long("fetchonly")
+                .short('f')
+                .help("Only download the source files of the packages to merge")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fetch_all_uri")
+                .long("fetch-all-uri")
+                .short('F')
+                .help("Like --fetchonly, but fetch every SRC_URI regardless of USE flags")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("usepkg")
+                .long("usepkg")
+                .short('k')
+                .help("Use binary packages from PKGDIR when they match")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("usepkgonly")
+                .long("usepkgonly")
+                .short('K')
+                .help("Only merge binary packages, never build from source")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("getbinpkg")
+                .long("getbinpkg")
+                .short('g')
+                .help("Download binary packages from PORTAGE_BINHOST (implies --usepkg)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fetch_jobs")
+                .long("fetch-jobs")
+                .help("Number of parallel distfile downloads")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("remote_sizes")
+                .long("remote-sizes")
+                .help("Count distfiles the Manifest doesn't list in the download size, asking their servers")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("load_average")
+                .long("load-average")
+                .short('l')
+                .help("Do not start new jobs while the load average is at or above this value")
+                .value_parser(clap::value_parser!(f64)),
+        )
         .arg(
             Arg::new("with_bdeps")
                 .long("with-bdeps")
-                .help("Include build dependencies")
+                .help("Include build dependencies (default n, y for --depclean)")
+                .value_parser(["y", "n"]),
+        )
+        .arg(
+            Arg::new("with_test_deps")
+                .long("with-test-deps")
+                .help("Include test dependencies of the packages being built")
                 .value_parser(["y", "n"])
                 .default_value("n"),
         )
         .arg(
             Arg::new("sync")
                 .long("sync")
-                .help("Sync package repositories")
+                .help("Sync package repositories, or only the ones named")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .help("With --sync, sync repositories even within their sync-interval")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sync_submodule")
+                .long("sync-submodule")
+                .help("With --sync, only update these submodules of git repositories")
+                .value_parser(["glsa", "news", "profiles"])
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("search")
+                .long("search")
+                .short('s')
+                .help("Search package names (%name for an exact match, cat/pkg to restrict the category)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("searchdesc")
+                .long("searchdesc")
+                .short('S')
+                .help("Search package names and descriptions")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fuzzy_search")
+                .long("fuzzy-search")
+                .help("Also match names similar to the search pattern")
+                .value_parser(["y", "n"])
+                .default_value("y"),
+        )
+        .arg(
+            Arg::new("regex_search_auto")
+                .long("regex-search-auto")
+                .help("Treat search patterns containing regex characters as regular expressions")
+                .value_parser(["y", "n"])
+                .default_value("y"),
+        )
+        .arg(
+            Arg::new("owns")
+                .long("owns")
+                .help("Show which installed packages own the given files (globs allowed)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("serve_binhost")
+                .long("serve-binhost")
+                .value_name("ADDR")
+                .help("Regenerate PKGDIR/Packages and serve PKGDIR over HTTP (e.g. 0.0.0.0:8080)"),
+        )
+        .arg(
+            Arg::new("info")
+                .long("info")
+                .help("Show a report of the system configuration, and details of any given packages")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("list_sets")
+                .long("list-sets")
+                .help("List the available package sets")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("unmerge")
+                .long("unmerge")
+                .short('C')
+                .help("Remove the given packages or sets, dropping sets from world_sets")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("depclean")
+                .long("depclean")
+                .short('c')
+                .help("Remove installed packages that @world does not depend on")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("security")
+                .long("security")
+                .help("Install the upgrades fixing the security advisories that affect the system")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("regen")
+                .long("regen")
+                .help("Regenerate the metadata cache of all repositories")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("nodeps")
+                .long("nodeps")
+                .short('O')
+                .help("Merge the packages without resolving their dependencies")
+                .conflicts_with("onlydeps")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("onlydeps")
+                .long("onlydeps")
+                .short('o')
+                .help("Only merge the dependencies of the packages, not the packages themselves")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .value_name("ATOMS")
+                .help("Never merge packages matching these names or slot atoms")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("reinstall_atoms")
+                .long("reinstall-atoms")
+                .value_name("ATOMS")
+                .help("Reinstall packages matching these names or slot atoms even when installed")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("backtrack")
+                .long("backtrack")
+                .value_name("COUNT")
+                .help("How many times the resolver may start over to get around a conflict")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("autounmask_write")
+                .long("autounmask-write")
+                .help("Write required keyword, USE and license changes to /etc/portage")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dispatch_conf")
+                .long("dispatch-conf")
+                .help("Interactively review pending configuration file updates")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("config_update")
+                .long("config-update")
+                .help("Automatically merge trivial configuration file updates")
                 .action(clap::ArgAction::SetTrue),
         )
         .arg(
@@ -102,20 +400,170 @@ fn create_app() -> Command {
                 .action(clap::ArgAction::Set)
                 .num_args(0..),
         )
+        .subcommand(
+            Command::new("glsa")
+                .about("Check the system against the Gentoo Linux Security Advisories")
+                .subcommand_required(true)
+                .subcommands(["list", "test", "fix"].map(|command| {
+                    let about = match command {
+                        "list" => "List advisories, marked [A] affected, [N] not affected or [U] applied",
+                        "test" => "Print the advisories affecting the system",
+                        _ => "Install the upgrades that fix the advisories",
+                    };
+                    Command::new(command).about(about).arg(
+                        Arg::new("glsa_ids")
+                            .help("GLSA ids, or \"all\" or \"affected\"")
+                            .num_args(0..),
+                    )
+                })),
+        )
+        .subcommand(
+            Command::new("news")
+                .about("Read the news repositories publish, as eselect news does")
+                .subcommand_required(true)
+                .subcommands(["list", "read", "unread", "purge", "count"].map(|command| {
+                    let about = match command {
+                        "list" => "List the news items relevant to the system, marked N if unread",
+                        "read" => "Show news items and mark them read",
+                        "unread" => "Mark news items unread",
+                        "purge" => "Stop listing the news items that have been read",
+                        _ => "Print the number of unread news items",
+                    };
+                    Command::new(command).about(about).arg(
+                        Arg::new("news_items")
+                            .help("News item numbers or names, or \"new\" or \"all\"")
+                            .num_args(0..),
+                    )
+                })),
+        )
+}
+
+/// Values of a repeatable option taking space separated atoms
+fn atom_list(matches: &ArgMatches, id: &str) -> Vec<String> {
+    matches.get_many::<String>(id)
+        .unwrap_or_default()
+        .flat_map(|value| value.split_whitespace().map(|atom| atom.to_string()))
+        .collect()
+}
+
+/// A root directory from its option, else the environment variable Portage reads, else /
+fn root_option(matches: &ArgMatches, id: &str, var: &str) -> String {
+    matches.get_one::<String>(id).cloned()
+        .or_else(|| std::env::var(var).ok().filter(|value| !value.is_empty()))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+/// Whether the action merges, unmerges or syncs rather than only reporting
+fn changes_system(matches: &ArgMatches) -> bool {
+    if let Some(glsa) = matches.subcommand_matches("glsa") {
+        return glsa.subcommand_name() == Some("fix");
+    }
+    if matches.subcommand_matches("news").is_some() {
+        return false;
+    }
+    let reports = ["regen", "dispatch_conf", "config_update", "list_sets", "info", "owns", "search", "searchdesc"]
+        .iter()
+        .any(|flag| matches.get_flag(flag));
+    matches.get_flag("sync") || !reports && matches.get_one::<String>("serve_binhost").is_none()
 }
 
 async fn run_emerge(matches: ArgMatches) -> i32 {
-    let ask = matches.get_flag("ask");
-    let pretend = matches.get_flag("pretend");
     let update = matches.get_flag("update");
-    let deep = matches.get_flag("deep");
-    let newuse = matches.get_flag("newuse");
-    let resume = matches.get_flag("resume");
-    let jobs = matches.get_one::<usize>("jobs").copied().unwrap_or(1);
-    let with_bdeps = matches.get_one::<String>("with_bdeps").map(|s| s == "y").unwrap_or(false);
+    emerge_rs::output::set_color(emerge_rs::output::default_color(matches.get_one::<String>("color").map(|c| c.as_str())));
+    let options = EmergeOptions {
+        pretend: matches.get_flag("pretend"),
+        ask: matches.get_flag("ask"),
+        resume: matches.get_flag("resume"),
+        skipfirst: matches.get_flag("skipfirst"),
+        tree: matches.get_flag("tree"),
+        verbose: matches.get_flag("verbose"),
+        changelog: matches.get_flag("changelog"),
+        jobs: matches.get_one::<usize>("jobs").copied().unwrap_or(1),
+        load_average: matches.get_one::<f64>("load_average").copied(),
+        root: root_option(&matches, "root", "ROOT"),
+        sysroot: root_option(&matches, "sysroot", "SYSROOT"),
+        config_root: root_option(&matches, "config_root", "PORTAGE_CONFIGROOT"),
+        with_bdeps: matches.get_one::<String>("with_bdeps").map(|s| s == "y").unwrap_or(false),
+        with_test_deps: matches.get_one::<String>("with_test_deps").map(|s| s == "y").unwrap_or(false),
+        oneshot: matches.get_flag("oneshot"),
+        keep_going: matches.get_flag("keep_going"),
+        deep: matches.get_flag("deep"),
+        newuse: matches.get_flag("newuse"),
+        emptytree: matches.get_flag("emptytree"),
+        nodeps: matches.get_flag("nodeps"),
+        onlydeps: matches.get_flag("onlydeps"),
+        exclude: atom_list(&matches, "exclude"),
+        reinstall_atoms: atom_list(&matches, "reinstall_atoms"),
+        backtrack: matches.get_one::<usize>("backtrack").copied().unwrap_or(emerge_rs::depgraph::DEFAULT_BACKTRACK),
+        autounmask_write: matches.get_flag("autounmask_write"),
+        fetchonly: matches.get_flag("fetchonly") || matches.get_flag("fetch_all_uri"),
+        fetch_all_uri: matches.get_flag("fetch_all_uri"),
+        fetch_jobs: matches.get_one::<usize>("fetch_jobs").copied().unwrap_or(3),
+        remote_sizes: matches.get_flag("remote_sizes"),
+        binpkg_policy: emerge_rs::bintree::BinPkgPolicy {
+            usepkg: matches.get_flag("usepkg") || matches.get_flag("getbinpkg"),
+            usepkgonly: matches.get_flag("usepkgonly"),
+            getbinpkg: matches.get_flag("getbinpkg"),
+        },
+        nolock: matches.get_flag("nolock"),
+        json: matches.get_flag("json") || matches.get_one::<String>("output_format").is_some_and(|f| f == "json"),
+    };
+
+    // Like portage, only runs that change the system are logged
+    if !options.pretend && !options.fetchonly && changes_system(&matches) {
+        emergelog::enable(&emergelog::log_dir());
+        emergelog::start(&std::env::args().skip(1).collect::<Vec<_>>());
+
+        // Entries a merge that died left half-written
+        match emerge_rs::vdb::recover(&options.root) {
+            Ok(removed) => {
+                for entry in removed {
+                    eprintln!(" * Removed incomplete package database entry {}", entry);
+                    emergelog::emergelog(&format!(" *** removed incomplete vdb entry {}", entry));
+                }
+            }
+            Err(e) => eprintln!("!!! {}", e),
+        }
+    }
+
+    if let Some(("glsa", glsa)) = matches.subcommand()
+        && let Some((command, args)) = glsa.subcommand()
+    {
+        let ids: Vec<String> = args.get_many::<String>("glsa_ids").unwrap_or_default().cloned().collect();
+        return actions::action_glsa(command, &ids, &options).await;
+    }
+
+    if let Some(("news", news)) = matches.subcommand()
+        && let Some((command, args)) = news.subcommand()
+    {
+        let items: Vec<String> = args.get_many::<String>("news_items").unwrap_or_default().cloned().collect();
+        return actions::action_news(command, &items, &options);
+    }
+
+    if matches.get_flag("security") {
+        return actions::action_glsa("fix", &["affected".to_string()], &options).await;
+    }
 
     if matches.get_flag("sync") {
-        return actions::action_sync().await;
+        let repos: Vec<String> = matches.get_many::<String>("packages").unwrap_or_default().cloned().collect();
+        let submodules: Vec<String> = matches.get_many::<String>("sync_submodule").unwrap_or_default().cloned().collect();
+        return actions::action_sync(&options.config_root, &repos, options.jobs, matches.get_flag("force"), &submodules, options.json).await;
+    }
+
+    if matches.get_flag("regen") {
+        return actions::action_regen(options.jobs).await;
+    }
+
+    if let Some(addr) = matches.get_one::<String>("serve_binhost") {
+        return actions::action_serve_binhost(addr, &options.root).await;
+    }
+
+    if matches.get_flag("dispatch_conf") {
+        return actions::action_dispatch_conf(&options.root).await;
+    }
+
+    if matches.get_flag("config_update") {
+        return actions::action_config_update(&options.root, options.pretend).await;
     }
 
     // Get packages
@@ -125,15 +573,54 @@ async fn run_emerge(matches: ArgMatches) -> i32 {
         .cloned()
         .collect();
 
+    if matches.get_flag("list_sets") {
+        return actions::action_set(Some("list"), None).await;
+    }
+
+    if matches.get_flag("info") {
+        return actions::action_info(&options.root, &options.config_root, &packages, options.json).await;
+    }
+
+    // The saved merge list stands in for targets
+    if options.resume {
+        return actions::action_install_with_options(&packages, &options).await;
+    }
+
+    // Targets only narrow down what depclean removes
+    if matches.get_flag("depclean") {
+        let with_bdeps = matches.get_one::<String>("with_bdeps").is_none_or(|s| s == "y");
+        return actions::action_depclean(&packages, &options, with_bdeps).await;
+    }
+
     if packages.is_empty() {
         eprintln!("emerge: no targets specified (use --help for usage)");
         return 1;
     }
 
+    if matches.get_flag("unmerge") {
+        return actions::action_remove(&packages, &options).await;
+    }
+
+    if matches.get_flag("owns") {
+        return actions::action_owns(&packages, &options.root).await;
+    }
+
+    if matches.get_flag("search") || matches.get_flag("searchdesc") {
+        let search_options = SearchOptions {
+            search_description: matches.get_flag("searchdesc"),
+            fuzzy: matches.get_one::<String>("fuzzy_search").is_some_and(|s| s == "y"),
+            regex_auto: matches.get_one::<String>("regex_search_auto").is_some_and(|s| s == "y"),
+            root: options.root.clone(),
+            config_root: options.config_root.clone(),
+            json: options.json,
+        };
+        return actions::action_search(&packages, &search_options).await;
+    }
+
     // Determine action based on flags
     if update {
-        return actions::action_upgrade(&packages, pretend, ask, deep, newuse, with_bdeps).await;
+        return actions::action_upgrade(&packages, &options).await;
     } else {
-        return actions::action_install_with_root(&packages, pretend, ask, resume, jobs, "/", with_bdeps).await;
+        return actions::action_install_with_options(&packages, &options).await;
     }
 }
@@ -0,0 +1,424 @@
+// manifest.rs -- Manifest and MetaManifest (GLEP 74) tree verification
+
+use crate::checksum::{self, Mismatch};
+use crate::exception::InvalidData;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Gentoo release keys shipped by sec-keys/openpgp-keys-gentoo-release
+pub const DEFAULT_KEY_PATH: &str = "/usr/share/openpgp-keys/gentoo-release.asc";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    Manifest,
+    Data,
+    Ebuild,
+    Aux,
+    Misc,
+    Dist,
+    Ignore,
+    Timestamp,
+}
+
+impl EntryKind {
+    pub fn parse(tag: &str) -> Option<Self> {
+        match tag {
+            "MANIFEST" => Some(EntryKind::Manifest),
+            "DATA" => Some(EntryKind::Data),
+            "EBUILD" => Some(EntryKind::Ebuild),
+            "AUX" => Some(EntryKind::Aux),
+            "MISC" => Some(EntryKind::Misc),
+            "DIST" => Some(EntryKind::Dist),
+            "IGNORE" => Some(EntryKind::Ignore),
+            "TIMESTAMP" => Some(EntryKind::Timestamp),
+            _ => None,
+        }
+    }
+}
+
+/// One line of a Manifest file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub path: String,
+    pub size: u64,
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// Summary of a successful tree verification
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    pub manifests: usize,
+    pub files: usize,
+    pub timestamp: Option<String>,
+}
+
+/// Parse Manifest lines, skipping ones that are malformed or of unknown type
+pub fn parse_manifest(content: &str) -> Vec<ManifestEntry> {
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(kind) = fields.first().and_then(|tag| EntryKind::parse(tag)) else {
+            continue;
+        };
+        let Some(path) = fields.get(1) else {
+            continue;
+        };
+
+        let mut entry = ManifestEntry {
+            kind,
+            path: path.to_string(),
+            size: 0,
+            hashes: BTreeMap::new(),
+        };
+        if matches!(entry.kind, EntryKind::Ignore | EntryKind::Timestamp) {
+            entries.push(entry);
+            continue;
+        }
+        let Some(size) = fields.get(2).and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        entry.size = size;
+        for pair in fields[3..].chunks(2) {
+            if let [algo, value] = pair {
+                entry.hashes.insert(algo.to_string(), value.to_lowercase());
+            }
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Body of an OpenPGP clearsigned message, or None if the text is not signed
+pub fn strip_signature(content: &str) -> Option<String> {
+    let mut lines = content.lines();
+    if lines.next()? != "-----BEGIN PGP SIGNED MESSAGE-----" {
+        return None;
+    }
+    // Armor headers end at the first blank line
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut body = String::new();
+    for line in lines {
+        if line == "-----BEGIN PGP SIGNATURE-----" {
+            return Some(body);
+        }
+        body.push_str(line.strip_prefix("- ").unwrap_or(line));
+        body.push('\n');
+    }
+    None
+}
+
+/// DIST entries of a package Manifest, keyed by distfile name
+pub fn load_dist_entries(package_dir: &Path) -> Result<BTreeMap<String, ManifestEntry>, InvalidData> {
+    let path = package_dir.join("Manifest");
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| InvalidData::new(&format!("Cannot read {}: {}", path.display(), e), None))?;
+    Ok(parse_manifest(&strip_signature(&content).unwrap_or(content))
+        .into_iter()
+        .filter(|entry| entry.kind == EntryKind::Dist)
+        .map(|entry| (entry.path.clone(), entry))
+        .collect())
+}
+
+/// Check a downloaded distfile against its DIST entry
+pub fn verify_distfile(path: &Path, entry: &ManifestEntry) -> Result<(), InvalidData> {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    match checksum::verify_file(path, Some(entry.size), &entry.hashes) {
+        Ok(()) => Ok(()),
+        Err(Mismatch::Unreadable(e)) => Err(InvalidData::new(&format!("Cannot read {}: {}", path.display(), e), None)),
+        Err(mismatch) => Err(InvalidData::new(&format!("{}: digest verification failed: {}", name, mismatch), None)),
+    }
+}
+
+/// Add or refresh DIST entries (BLAKE2B and SHA512) for the given distfiles in a package Manifest.
+/// Other entries are kept; returns the number of DIST entries written.
+pub fn update_dist_entries(package_dir: &Path, distfiles: &[PathBuf]) -> Result<usize, InvalidData> {
+    let path = package_dir.join("Manifest");
+    let existing = std::fs::read_to_string(&path).unwrap_or_default();
+    let mut dist: BTreeMap<String, String> = BTreeMap::new();
+    let mut other = Vec::new();
+    for line in existing.lines() {
+        match parse_manifest(line).into_iter().next() {
+            Some(entry) if entry.kind == EntryKind::Dist => {
+                dist.insert(entry.path, line.to_string());
+            }
+            _ if !line.trim().is_empty() => other.push(line.to_string()),
+            _ => {}
+        }
+    }
+
+    for distfile in distfiles {
+        let checksums = checksum::hash_file(distfile, &["BLAKE2B", "SHA512"])
+            .map_err(|e| InvalidData::new(&format!("Cannot read {}: {}", distfile.display(), e), None))?;
+        let name = distfile.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let line = format!("DIST {} {} BLAKE2B {} SHA512 {}", name, checksums.size, checksums.hashes["BLAKE2B"], checksums.hashes["SHA512"]);
+        dist.insert(name, line);
+    }
+
+    let mut content: String = dist.values().map(|line| format!("{}\n", line)).collect();
+    for line in other {
+        content.push_str(&line);
+        content.push('\n');
+    }
+    std::fs::write(&path, content)
+        .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))?;
+    Ok(dist.len())
+}
+
+pub struct ManifestVerifier {
+    root: PathBuf,
+    key_path: Option<PathBuf>,
+}
+
+impl ManifestVerifier {
+    pub fn new(root: &Path) -> Self {
+        ManifestVerifier {
+            root: root.to_path_buf(),
+            key_path: None,
+        }
+    }
+
+    /// Verify the top-level signature against this key file instead of the user's keyring
+    pub fn with_key(mut self, key_path: &Path) -> Self {
+        self.key_path = Some(key_path.to_path_buf());
+        self
+    }
+
+    /// Check the top-level signature, then every file of the tree
+    pub async fn verify(&self) -> Result<VerifyReport, InvalidData> {
+        self.verify_signature(&self.root.join("Manifest")).await?;
+        self.verify_tree()
+    }
+
+    /// Verify a clearsigned Manifest with gpg
+    pub async fn verify_signature(&self, manifest: &Path) -> Result<(), InvalidData> {
+        let content = std::fs::read_to_string(manifest)
+            .map_err(|e| InvalidData::new(&format!("Cannot read {}: {}", manifest.display(), e), None))?;
+        if strip_signature(&content).is_none() {
+            return Err(InvalidData::new(&format!("{} is not signed", manifest.display()), None));
+        }
+
+        // Import the release keys into a throwaway home so only they are trusted
+        let home = tempfile::TempDir::new()
+            .map_err(|e| InvalidData::new(&format!("Failed to create gpg home: {}", e), None))?;
+        let mut gpg_args = vec!["--batch".to_string()];
+        if let Some(key_path) = &self.key_path {
+            gpg_args.extend(["--homedir".to_string(), home.path().display().to_string()]);
+            let import = Command::new("gpg")
+                .args(&gpg_args)
+                .arg("--import")
+                .arg(key_path)
+                .output()
+                .await
+                .map_err(|e| InvalidData::new(&format!("Failed to run gpg: {}", e), None))?;
+            if !import.status.success() {
+                return Err(InvalidData::new(&format!("Failed to import keys from {}", key_path.display()), None));
+            }
+        }
+
+        let output = Command::new("gpg")
+            .args(&gpg_args)
+            .args(["--status-fd", "1", "--verify"])
+            .arg(manifest)
+            .output()
+            .await
+            .map_err(|e| InvalidData::new(&format!("Failed to run gpg: {}", e), None))?;
+        let status = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || !status.lines().any(|l| l.starts_with("[GNUPG:] GOODSIG")) {
+            return Err(InvalidData::new(&format!("Bad OpenPGP signature on {}", manifest.display()), None));
+        }
+        Ok(())
+    }
+
+    /// Check every Manifest entry and make sure no file is left uncovered
+    pub fn verify_tree(&self) -> Result<VerifyReport, InvalidData> {
+        let mut report = VerifyReport::default();
+        let mut problems = Vec::new();
+        let mut covered: HashSet<PathBuf> = HashSet::new();
+        let mut ignored: Vec<PathBuf> = Vec::new();
+
+        let top = PathBuf::from("Manifest");
+        let mut pending = vec![(top.clone(), self.read_manifest(&top)?)];
+        while let Some((manifest, content)) = pending.pop() {
+            report.manifests += 1;
+            let dir = manifest.parent().map(Path::to_path_buf).unwrap_or_default();
+            for entry in parse_manifest(&content) {
+                let rel = match entry.kind {
+                    EntryKind::Aux => dir.join("files").join(&entry.path),
+                    _ => dir.join(&entry.path),
+                };
+                match entry.kind {
+                    EntryKind::Ignore => ignored.push(rel),
+                    EntryKind::Timestamp => report.timestamp = Some(entry.path.clone()),
+                    EntryKind::Dist => {}
+                    kind => {
+                        if let Err(problem) = self.check_file(&rel, &entry) {
+                            problems.push(problem);
+                            continue;
+                        }
+                        covered.insert(rel.clone());
+                        if kind == EntryKind::Manifest {
+                            pending.push((rel.clone(), self.read_manifest(&rel)?));
+                        } else {
+                            report.files += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let entries = std::fs::read_dir(self.root.join(&dir))
+                .map_err(|e| InvalidData::new(&format!("Cannot read {}: {}", dir.display(), e), None))?;
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let rel = dir.join(&name);
+                // Dot files are outside the scope of Manifests
+                if name.starts_with('.') || rel == top || ignored.iter().any(|i| rel.starts_with(i)) {
+                    continue;
+                }
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    dirs.push(rel);
+                } else if !covered.contains(&rel) {
+                    problems.push(format!("{}: not listed in any Manifest", rel.display()));
+                }
+            }
+        }
+
+        if !problems.is_empty() {
+            problems.sort();
+            let shown: Vec<&str> = problems.iter().take(10).map(|p| p.as_str()).collect();
+            return Err(InvalidData::new(
+                &format!("Manifest verification failed ({} problems): {}", problems.len(), shown.join("; ")),
+                None,
+            ));
+        }
+        Ok(report)
+    }
+
+    /// Read a Manifest, decompressing and unwrapping the signature as needed
+    fn read_manifest(&self, rel: &Path) -> Result<String, InvalidData> {
+        let path = self.root.join(rel);
+        let data = std::fs::read(&path)
+            .map_err(|e| InvalidData::new(&format!("Cannot read {}: {}", path.display(), e), None))?;
+        let content = if rel.extension().is_some_and(|ext| ext == "gz") {
+            let mut content = String::new();
+            flate2::read::GzDecoder::new(&data[..]).read_to_string(&mut content)
+                .map_err(|e| InvalidData::new(&format!("Cannot decompress {}: {}", path.display(), e), None))?;
+            content
+        } else {
+            String::from_utf8_lossy(&data).into_owned()
+        };
+        Ok(strip_signature(&content).unwrap_or(content))
+    }
+
+    fn check_file(&self, rel: &Path, entry: &ManifestEntry) -> Result<(), String> {
+        match checksum::verify_file(&self.root.join(rel), Some(entry.size), &entry.hashes) {
+            Ok(()) => Ok(()),
+            Err(Mismatch::Unreadable(_)) => Err(format!("{}: missing", rel.display())),
+            Err(Mismatch::Size { expected, actual }) => Err(format!("{}: size {} does not match {}", rel.display(), actual, expected)),
+            Err(Mismatch::Digest { algo, .. }) => Err(format!("{}: {} mismatch", rel.display(), algo)),
+            Err(Mismatch::NoSupportedHash) => Err(format!("{}: no supported hash", rel.display())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn manifest_line(kind: &str, path: &str, data: &[u8]) -> String {
+        format!("{} {} {} BLAKE2B {} SHA512 {}\n", kind, path, data.len(),
+            checksum::hash_bytes(data, "BLAKE2B").unwrap(), checksum::hash_bytes(data, "SHA512").unwrap())
+    }
+
+    #[test]
+    fn test_verify_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("app-misc/foo/files")).unwrap();
+        std::fs::create_dir_all(root.join("distfiles")).unwrap();
+        std::fs::write(root.join("app-misc/foo/foo-1.0.ebuild"), "EAPI=8\n").unwrap();
+        std::fs::write(root.join("app-misc/foo/files/fix.patch"), "--- a\n").unwrap();
+        std::fs::write(root.join("distfiles/foo.tar.gz"), "ignored").unwrap();
+        std::fs::write(root.join(".sync_metadata"), "{}").unwrap();
+
+        let category = manifest_line("EBUILD", "foo/foo-1.0.ebuild", b"EAPI=8\n")
+            + &manifest_line("AUX", "fix.patch", b"--- a\n").replace("AUX fix.patch", "DATA foo/files/fix.patch");
+        std::fs::write(root.join("app-misc/Manifest"), &category).unwrap();
+        let top = format!("IGNORE distfiles\nTIMESTAMP 2024-01-01T00:00:00Z\n{}",
+            manifest_line("MANIFEST", "app-misc/Manifest", category.as_bytes()));
+        std::fs::write(root.join("Manifest"), top).unwrap();
+
+        let report = ManifestVerifier::new(root).verify_tree().unwrap();
+        assert_eq!(report.manifests, 2);
+        assert_eq!(report.files, 2);
+        assert_eq!(report.timestamp.as_deref(), Some("2024-01-01T00:00:00Z"));
+
+        std::fs::write(root.join("app-misc/foo/foo-1.0.ebuild"), "EAPI=7\n").unwrap();
+        let err = ManifestVerifier::new(root).verify_tree().unwrap_err();
+        assert!(err.to_string().contains("BLAKE2B mismatch"));
+
+        std::fs::write(root.join("app-misc/foo/foo-1.0.ebuild"), "EAPI=8\n").unwrap();
+        std::fs::write(root.join("app-misc/foo/stray"), "x").unwrap();
+        let err = ManifestVerifier::new(root).verify_tree().unwrap_err();
+        assert!(err.to_string().contains("stray: not listed"));
+    }
+
+    #[test]
+    fn test_verify_distfile() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        std::fs::write(root.join("Manifest"), manifest_line("DIST", "foo-1.0.tar.gz", b"tarball")
+            + &manifest_line("EBUILD", "foo-1.0.ebuild", b"EAPI=8\n")).unwrap();
+        let entries = load_dist_entries(root).unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["foo-1.0.tar.gz"]);
+
+        let distfile = root.join("foo-1.0.tar.gz");
+        std::fs::write(&distfile, "tarball").unwrap();
+        verify_distfile(&distfile, &entries["foo-1.0.tar.gz"]).unwrap();
+
+        std::fs::write(&distfile, "tarbal!").unwrap();
+        let err = verify_distfile(&distfile, &entries["foo-1.0.tar.gz"]).unwrap_err().to_string();
+        assert!(err.contains("digest verification failed: BLAKE2B"));
+        assert!(err.contains(&format!("expected: {}", checksum::hash_bytes(b"tarball", "BLAKE2B").unwrap())));
+        assert!(err.contains(&format!("actual:   {}", checksum::hash_bytes(b"tarbal!", "BLAKE2B").unwrap())));
+
+        std::fs::write(&distfile, "short").unwrap();
+        let err = verify_distfile(&distfile, &entries["foo-1.0.tar.gz"]).unwrap_err().to_string();
+        assert!(err.contains("digest verification failed: size"));
+    }
+
+    #[test]
+    fn test_update_dist_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let ebuild_line = manifest_line("EBUILD", "foo-1.0.ebuild", b"EAPI=8\n");
+        std::fs::write(root.join("Manifest"), manifest_line("DIST", "foo-1.0.tar.gz", b"old") + &ebuild_line).unwrap();
+        std::fs::write(root.join("foo-1.0.tar.gz"), "tarball").unwrap();
+        std::fs::write(root.join("bar-2.0.tar.gz"), "other").unwrap();
+
+        let written = update_dist_entries(root, &[root.join("foo-1.0.tar.gz"), root.join("bar-2.0.tar.gz")]).unwrap();
+        assert_eq!(written, 2);
+        let content = std::fs::read_to_string(root.join("Manifest")).unwrap();
+        assert_eq!(content, manifest_line("DIST", "bar-2.0.tar.gz", b"other")
+            + &manifest_line("DIST", "foo-1.0.tar.gz", b"tarball") + &ebuild_line);
+        verify_distfile(&root.join("foo-1.0.tar.gz"), &load_dist_entries(root).unwrap()["foo-1.0.tar.gz"]).unwrap();
+    }
+
+    #[test]
+    fn test_strip_signature() {
+        let signed = "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA512\n\nTIMESTAMP x\n- -dashed\n-----BEGIN PGP SIGNATURE-----\nabc\n-----END PGP SIGNATURE-----\n";
+        assert_eq!(strip_signature(signed).unwrap(), "TIMESTAMP x\n-dashed\n");
+        assert!(strip_signature("TIMESTAMP x\n").is_none());
+    }
+}
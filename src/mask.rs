@@ -3,6 +3,7 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use crate::exception::InvalidData;
 use crate::atom::Atom;
+use crate::keywords::KeywordFilter;
 use crate::profile::{ProfileManager, Profile};
 
 /// Package masking types
@@ -18,7 +19,10 @@ pub enum MaskType {
 pub struct MaskRule {
     pub mask_type: MaskType,
     pub atom: Atom,
+    /// The reason given above the entry, or its trailing comment
     pub comment: Option<String>,
+    /// File the rule was read from
+    pub source: PathBuf,
 }
 
 /// Package masking manager for handling package.mask, package.unmask, etc.
@@ -27,6 +31,9 @@ pub struct MaskManager {
     config_dir: PathBuf,
     profile_manager: ProfileManager,
     accept_keywords: Vec<String>,
+    keyword_filter: Option<KeywordFilter>,
+    /// Repositories whose profiles/package.mask apply, masters first
+    repositories: Vec<PathBuf>,
 }
 
 impl MaskManager {
@@ -38,26 +45,46 @@ impl MaskManager {
             config_dir: root_path.join("etc/portage"),
             profile_manager: ProfileManager::new(root),
             accept_keywords,
+            keyword_filter: None,
+            repositories: vec![root_path.join("var/db/repos/gentoo")],
         }
     }
 
+    /// Decide keyword visibility with a KeywordFilter instead of the plain ACCEPT_KEYWORDS list
+    pub fn with_keyword_filter(mut self, keyword_filter: KeywordFilter) -> Self {
+        self.keyword_filter = Some(keyword_filter);
+        self
+    }
+
+    /// Read repository masks from these repositories instead of the default gentoo location
+    pub fn with_repositories(mut self, repositories: Vec<PathBuf>) -> Self {
+        self.repositories = repositories;
+        self
+    }
+
     /// Check if a package atom is masked
     /// Returns Some(reason) if masked, None if not masked
     pub async fn is_masked(&self, atom: &Atom) -> Result<Option<String>, InvalidData> {
-        // Check package.mask files
-        let masked_by_mask = self.check_mask_files(atom, MaskType::Mask).await?;
-        if let Some(reason) = masked_by_mask {
-            // Check if it's unmasked by package.unmask
-            let unmasked = self.check_mask_files(atom, MaskType::Unmask).await?;
-            if unmasked.is_none() {
+        // Masks from repositories, profiles and package.mask; package.unmask lifts any of them
+        let masks = self.collect_rules(MaskType::Mask).await?;
+        if let Some(mask) = masks.iter().rev().find(|rule| rule_matches(&rule.atom, atom)) {
+            let unmasks = self.collect_rules(MaskType::Unmask).await?;
+            if !unmasks.iter().any(|rule| rule_matches(&rule.atom, atom)) {
+                let mut reason = format!("masked by {} in {}", mask.atom, mask.source.display());
+                if let Some(comment) = &mask.comment {
+                    reason.push_str(":\n");
+                    reason.push_str(comment);
+                }
                 return Ok(Some(reason));
             }
         }
 
-        // Check keyword restrictions from package.keywords
-        let keyword_masked = self.check_keyword_restrictions(atom).await?;
-        if let Some(reason) = keyword_masked {
-            return Ok(Some(reason));
+        // Check keyword restrictions from package.keywords (already part of the filter when set)
+        if self.keyword_filter.is_none() {
+            let keyword_masked = self.check_keyword_restrictions(atom).await?;
+            if let Some(reason) = keyword_masked {
+                return Ok(Some(reason));
+            }
         }
 
         // Check ebuild KEYWORDS if version is specified
@@ -80,6 +107,11 @@ impl MaskManager {
             // Parse the ebuild to get KEYWORDS
             match self.parse_ebuild_keywords(&path) {
                 Ok(keywords) => {
+                    if let Some(filter) = &self.keyword_filter {
+                        let cpv = format!("{}-{}", atom.cp(), version);
+                        return Ok(filter.check(&cpv, &keywords).map(|masked| format!("masked by: {}", masked.reason())));
+                    }
+
                     // Check if any of the ebuild's keywords are accepted
                     let accepted_keywords: std::collections::HashSet<_> = self.accept_keywords.iter().cloned().collect();
 
@@ -141,14 +173,11 @@ impl MaskManager {
                     if let Ok(entry) = entry {
                         if let Some(filename) = entry.file_name().to_str() {
                             if filename.ends_with(".ebuild") {
-                                // Extract version from filename
                                 // Format: package-version.ebuild
                                 let name_without_ext = filename.trim_end_matches(".ebuild");
-                                if let Some(dash_pos) = name_without_ext.rfind('-') {
-                                    let file_version = &name_without_ext[dash_pos + 1..];
-                                    if file_version == version {
-                                        return Ok(Some(entry.path()));
-                                    }
+                                let cpv = crate::versions::Cpv::parse(&format!("{}/{}", atom.category, name_without_ext));
+                                if cpv.is_ok_and(|cpv| cpv.package().as_str() == atom.package && cpv.pvr() == version) {
+                                    return Ok(Some(entry.path()));
                                 }
                             }
                         }
@@ -202,101 +231,52 @@ impl MaskManager {
         Ok(vec![])
     }
 
-    /// Check mask files of a specific type for a given atom
-    async fn check_mask_files(&self, atom: &Atom, mask_type: MaskType) -> Result<Option<String>, InvalidData> {
-        let mut mask_files = Vec::new();
-
-        // Get current profile and its inheritance chain
-        if let Ok(current_profile) = self.profile_manager.get_current_profile().await {
-            // Add profile mask files from inheritance chain (parent first, then child)
-            let mut profiles = current_profile.parent_profiles.clone();
-            profiles.push(current_profile);
-
-            for profile in profiles {
-                let profile_mask_file = match mask_type {
-                    MaskType::Mask => profile.path.join("package.mask"),
-                    MaskType::Unmask => profile.path.join("package.unmask"),
-                    MaskType::Keywords => profile.path.join("package.keywords"),
-                };
-                mask_files.push(profile_mask_file);
-            }
+    /// The profile stack, parents first
+    async fn profiles(&self) -> Vec<Profile> {
+        match self.profile_manager.get_current_profile().await {
+            Ok(current) => current.stack().into_iter().cloned().collect(),
+            Err(_) => Vec::new(),
         }
+    }
 
-        // Add user config mask files (highest precedence)
-        let config_mask_file = match mask_type {
-            MaskType::Mask => self.config_dir.join("package.mask"),
-            MaskType::Unmask => self.config_dir.join("package.unmask"),
-            MaskType::Keywords => self.config_dir.join("package.keywords"),
+    /// Files of one kind in precedence order: repository profiles/package.mask (masks
+    /// only), the profile stack, then /etc/portage
+    async fn rule_sources(&self, mask_type: &MaskType) -> Vec<PathBuf> {
+        let name = match mask_type {
+            MaskType::Mask => "package.mask",
+            MaskType::Unmask => "package.unmask",
+            MaskType::Keywords => "package.keywords",
         };
-        mask_files.push(config_mask_file);
-
-        // Check each mask file in order (profile inheritance, then user config)
-        for mask_file in mask_files {
-            if mask_file.exists() {
-                let content = fs::read_to_string(&mask_file)
-                    .map_err(|e| InvalidData::new(&format!("Failed to read mask file {}: {}", mask_file.display(), e), None))?;
-
-                let reason = self.check_mask_file_content(&content, atom, &mask_type)?;
-                if reason.is_some() {
-                    return Ok(reason);
-                }
-            }
+        let mut sources = Vec::new();
+        if *mask_type == MaskType::Mask {
+            sources.extend(self.repositories.iter().map(|repo| repo.join("profiles").join(name)));
         }
-
-        Ok(None)
+        sources.extend(self.profiles().await.iter().map(|profile| profile.path.join(name)));
+        sources.push(self.config_dir.join(name));
+        sources
     }
 
-    /// Check if content from a mask file matches the atom
-    fn check_mask_file_content(&self, content: &str, atom: &Atom, mask_type: &MaskType) -> Result<Option<String>, InvalidData> {
-        for line in content.lines() {
-            let line = line.trim();
-
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-
-            // Parse the line
-            let (atom_str, comment) = if let Some(comment_pos) = line.find('#') {
-                let atom_part = line[..comment_pos].trim();
-                let comment_part = line[comment_pos + 1..].trim().to_string();
-                (atom_part, Some(comment_part))
-            } else {
-                (line, None)
-            };
-
-            if atom_str.is_empty() {
-                continue;
-            }
-
-            // Try to parse as atom
-            match Atom::new(atom_str) {
-                Ok(mask_atom) => {
-                    // For masking, we compare category/package
-                    if mask_atom.category == atom.category && mask_atom.package == atom.package {
-                        let reason = match mask_type {
-                            MaskType::Mask => format!("masked by {}", atom_str),
-                            MaskType::Unmask => format!("unmasked by {}", atom_str),
-                            MaskType::Keywords => format!("keyword restricted by {}", atom_str),
-                        };
-
-                        let full_reason = if let Some(comment) = comment {
-                            format!("{}: {}", reason, comment)
-                        } else {
-                            reason
-                        };
-
-                        return Ok(Some(full_reason));
+    /// Rules of one kind in precedence order. A "-atom" line drops an entry added
+    /// by an earlier file, as profiles use to lift a parent's mask.
+    async fn collect_rules(&self, mask_type: MaskType) -> Result<Vec<MaskRule>, InvalidData> {
+        let mut rules: Vec<MaskRule> = Vec::new();
+        for source in self.rule_sources(&mask_type).await {
+            for file in rule_files(&source) {
+                let content = fs::read_to_string(&file)
+                    .map_err(|e| InvalidData::new(&format!("Failed to read mask file {}: {}", file.display(), e), None))?;
+                for (entry, comment) in parse_entries(&content) {
+                    if let Some(lifted) = entry.strip_prefix('-') {
+                        rules.retain(|rule| rule.atom.to_string() != lifted);
+                        continue;
+                    }
+                    match Atom::new(&entry) {
+                        Ok(atom) => rules.push(MaskRule { mask_type: mask_type.clone(), atom, comment, source: file.clone() }),
+                        Err(_) => eprintln!("Warning: Invalid atom syntax in {}: {}", file.display(), entry),
                     }
-                }
-                Err(_) => {
-                    // Invalid atom syntax, skip
-                    continue;
                 }
             }
         }
-
-        Ok(None)
+        Ok(rules)
     }
 
     /// Check keyword restrictions for a package
@@ -362,92 +342,75 @@ impl MaskManager {
     /// Get all masking rules from all mask files
     pub async fn get_all_mask_rules(&self) -> Result<Vec<MaskRule>, InvalidData> {
         let mut rules = Vec::new();
-
-        let mask_types = vec![MaskType::Mask, MaskType::Unmask, MaskType::Keywords];
-
-        for mask_type in mask_types {
-            let mut mask_files = Vec::new();
-
-            // Add profile mask files from inheritance chain
-            if let Ok(current_profile) = self.profile_manager.get_current_profile().await {
-                let mut profiles = current_profile.parent_profiles.clone();
-                profiles.push(current_profile);
-
-                for profile in profiles {
-                    let profile_mask_file = match mask_type {
-                        MaskType::Mask => profile.path.join("package.mask"),
-                        MaskType::Unmask => profile.path.join("package.unmask"),
-                        MaskType::Keywords => profile.path.join("package.keywords"),
-                    };
-                    mask_files.push(profile_mask_file);
-                }
-            }
-
-            // Add user config mask files (highest precedence)
-            let config_mask_file = match mask_type {
-                MaskType::Mask => self.config_dir.join("package.mask"),
-                MaskType::Unmask => self.config_dir.join("package.unmask"),
-                MaskType::Keywords => self.config_dir.join("package.keywords"),
-            };
-            mask_files.push(config_mask_file);
-
-            for mask_file in mask_files {
-                if mask_file.exists() {
-                    let content = fs::read_to_string(&mask_file)
-                        .map_err(|e| InvalidData::new(&format!("Failed to read mask file {}: {}", mask_file.display(), e), None))?;
-
-                    let file_rules = self.parse_mask_file(&content, mask_type.clone())?;
-                    rules.extend(file_rules);
-                }
-            }
+        for mask_type in [MaskType::Mask, MaskType::Unmask, MaskType::Keywords] {
+            rules.extend(self.collect_rules(mask_type).await?);
         }
-
         Ok(rules)
     }
+}
 
-    /// Parse a mask file content into MaskRule objects
-    fn parse_mask_file(&self, content: &str, mask_type: MaskType) -> Result<Vec<MaskRule>, InvalidData> {
-        let mut rules = Vec::new();
-
-        for line in content.lines() {
-            let line = line.trim();
-
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-
-            // Parse the line
-            let (atom_str, comment) = if let Some(comment_pos) = line.find('#') {
-                let atom_part = line[..comment_pos].trim();
-                let comment_part = line[comment_pos + 1..].trim().to_string();
-                (atom_part, Some(comment_part))
-            } else {
-                (line, None)
-            };
+/// Whether a mask entry covers the queried atom: versioned queries are matched by
+/// version range, unversioned ones only by unversioned entries
+fn rule_matches(rule: &Atom, atom: &Atom) -> bool {
+    if rule.slot.is_some() && atom.slot.is_some() && rule.slot != atom.slot {
+        return false;
+    }
+    match &atom.version {
+        Some(version) => rule.matches(&format!("{}-{}", atom.cp(), version)),
+        None => rule.cp() == atom.cp() && rule.op == crate::atom::Operator::None,
+    }
+}
 
-            if atom_str.is_empty() {
-                continue;
-            }
+/// A mask file, or the files of a mask directory in name order
+fn rule_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    let Ok(entries) = fs::read_dir(path) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries.flatten()
+        .map(|entry| entry.path())
+        .filter(|file| file.is_file())
+        .filter(|file| file.file_name().is_some_and(|n| !n.to_string_lossy().starts_with('.') && !n.to_string_lossy().ends_with('~')))
+        .collect();
+    files.sort();
+    files
+}
 
-            // Try to parse as atom
-            match Atom::new(atom_str) {
-                Ok(atom) => {
-                    rules.push(MaskRule {
-                        mask_type: mask_type.clone(),
-                        atom,
-                        comment,
-                    });
-                }
-                Err(_) => {
-                    // Invalid atom syntax, skip with warning
-                    eprintln!("Warning: Invalid atom syntax in mask file: {}", atom_str);
-                }
+/// Entries of a package.mask-style file with their reason: the comment block right
+/// above the entry's group (kept with its '#'), else a trailing comment
+fn parse_entries(content: &str) -> Vec<(String, Option<String>)> {
+    let mut entries = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    let mut in_group = false;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            block.clear();
+            in_group = false;
+            continue;
+        }
+        if line.starts_with('#') {
+            if in_group {
+                block.clear();
+                in_group = false;
             }
+            block.push(line);
+            continue;
         }
-
-        Ok(rules)
+        in_group = true;
+        let (entry, trailing) = match line.split_once('#') {
+            Some((entry, comment)) => (entry.trim(), Some(comment.trim())),
+            None => (line, None),
+        };
+        if entry.is_empty() {
+            continue;
+        }
+        let comment = if block.is_empty() { trailing.map(|c| c.to_string()) } else { Some(block.join("\n")) };
+        entries.push((entry.to_string(), comment));
     }
+    entries
 }
 
 #[cfg(test)]
@@ -504,6 +467,48 @@ mod tests {
         assert!(result.is_none()); // Should not be masked due to unmask
     }
 
+    #[tokio::test]
+    async fn test_mask_precedence_and_reasons() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let repo = root.join("var/db/repos/gentoo");
+        fs::create_dir_all(repo.join("profiles/base")).unwrap();
+        fs::write(repo.join("profiles/package.mask"), concat!(
+            "# Jane Dev <jane@gentoo.org> (2024-01-01)\n",
+            "# Breaks the ABI, wait for the rebuilds.\n",
+            ">=dev-libs/foo-2\n",
+            "dev-libs/foo-bin\n",
+            "\n",
+            "# Removal on 2024-02-01\n",
+            "app-misc/old\n",
+            "app-misc/profile-lifted\n",
+        )).unwrap();
+        fs::write(repo.join("profiles/base/package.mask"), "-app-misc/profile-lifted\n").unwrap();
+        fs::write(repo.join("profiles/base/package.unmask"), "=dev-libs/foo-2.1\n").unwrap();
+        fs::create_dir_all(root.join("etc/portage/package.mask")).unwrap();
+        fs::write(root.join("etc/portage/package.mask/local"), "<app-misc/hello-3 # too old\n").unwrap();
+        fs::write(root.join("etc/portage/package.unmask"), "app-misc/old\n").unwrap();
+        std::os::unix::fs::symlink(repo.join("profiles/base"), root.join("etc/portage/make.profile")).unwrap();
+
+        let manager = MaskManager::new(root.to_str().unwrap(), vec!["amd64".to_string()]);
+        let masked = |atom: &str| {
+            let atom = Atom::new(atom).unwrap();
+            let manager = &manager;
+            async move { manager.is_masked(&atom).await.unwrap() }
+        };
+
+        let reason = masked("=dev-libs/foo-2.0").await.unwrap();
+        assert!(reason.starts_with("masked by >=dev-libs/foo-2 in "));
+        assert!(reason.ends_with("profiles/package.mask:\n# Jane Dev <jane@gentoo.org> (2024-01-01)\n# Breaks the ABI, wait for the rebuilds."));
+        assert!(masked("=dev-libs/foo-1.9").await.is_none());
+        assert!(masked("=dev-libs/foo-2.1").await.is_none());
+        assert!(masked("=dev-libs/foo-bin-1").await.unwrap().ends_with("# Breaks the ABI, wait for the rebuilds."));
+        assert!(masked("=app-misc/profile-lifted-1").await.is_none());
+        assert!(masked("=app-misc/old-1").await.is_none());
+        assert!(masked("=app-misc/hello-2").await.unwrap().ends_with(":\ntoo old"));
+        assert!(masked("=app-misc/hello-3").await.is_none());
+    }
+
     #[tokio::test]
     async fn test_keyword_restrictions() {
         let temp_dir = tempfile::TempDir::new().unwrap();
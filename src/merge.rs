@@ -3,14 +3,16 @@
 use tokio::fs;
 use std::path::Path;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Semaphore;
 use crate::exception::InvalidData;
 use crate::vartree::VarTree;
-use crate::versions::PkgStr;
+use crate::versions::Cpv;
 use crate::doebuild::{doebuild, BuildPhase};
-use crate::bintree::BinTree;
+use crate::emergelog::emergelog;
+use crate::bintree::{BinPkgFormat, BinPkgPolicy, BinTree, GpgVerifier, RemoteBinPkg};
 use crate::porttree::PortTree;
+use crate::scheduler::{JobState, Scheduler};
+use crate::keywords::{parse_ebuild_keywords, KeywordFilter, KeywordMasked};
+use crate::configprotect::{ConfigProtect, DEFAULT_CONFIG_PROTECT, DEFAULT_CONFIG_PROTECT_MASK};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
@@ -19,14 +21,105 @@ pub struct MergeResult {
     pub failed: Vec<String>,
 }
 
+/// One package of a saved merge list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeEntry {
+    /// "ebuild" or "binary"
+    pub pkg_type: String,
+    pub cpv: String,
+    /// USE state the package was to be merged with
+    pub use_flags: Vec<(String, bool)>,
+    /// Packages of the list (cpv) merged before this one
+    pub deps: Vec<String>,
+    /// Installed packages it soft blocks, unmerged right after it
+    pub uninstall: Vec<String>,
+    /// PROPERTIES=interactive, so it is merged alone
+    #[serde(default)]
+    pub interactive: bool,
+}
+
+impl ResumeEntry {
+    pub fn ebuild(cpv: &str) -> Self {
+        ResumeEntry { pkg_type: "ebuild".to_string(), cpv: cpv.to_string(), use_flags: Vec::new(), deps: Vec::new(), uninstall: Vec::new(), interactive: false }
+    }
+}
+
+/// The merge in progress, kept under "resume" in the mtimedb for --resume
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ResumeState {
     pub operation_id: String,
-    pub packages: Vec<String>,
+    /// The whole merge list, in order
+    pub mergelist: Vec<ResumeEntry>,
     pub completed: Vec<String>,
     pub failed: Vec<String>,
     pub in_progress: Option<String>,
     pub start_time: chrono::DateTime<chrono::Utc>,
+    /// Targets to record in the world file once everything is merged
+    pub favorites: Vec<String>,
+}
+
+impl ResumeState {
+    pub fn new(mergelist: Vec<ResumeEntry>, favorites: Vec<String>) -> Self {
+        let start_time = chrono::Utc::now();
+        ResumeState {
+            operation_id: format!("install-{}", start_time.timestamp()),
+            mergelist,
+            completed: Vec::new(),
+            failed: Vec::new(),
+            in_progress: None,
+            start_time,
+            favorites,
+        }
+    }
+
+    /// Entries not merged yet, in order
+    pub fn remaining(&self) -> Vec<&ResumeEntry> {
+        self.mergelist.iter().filter(|entry| !self.completed.contains(&entry.cpv)).collect()
+    }
+
+    /// --skipfirst: drop the first entry not merged yet, usually the one that failed
+    pub fn skip_first(&mut self) -> Option<String> {
+        let first = self.remaining().first()?.cpv.clone();
+        self.drop_entry(&first);
+        Some(first)
+    }
+
+    /// Remove an entry, and the entries waiting for it that are not merged yet; returns
+    /// the dependents dropped along with it
+    pub fn drop_entry(&mut self, cpv: &str) -> Vec<String> {
+        let mut dropped = vec![cpv.to_string()];
+        let mut i = 0;
+        while i < dropped.len() {
+            let gone = dropped[i].clone();
+            for entry in &self.mergelist {
+                if entry.deps.contains(&gone) && !self.completed.contains(&entry.cpv) && !dropped.contains(&entry.cpv) {
+                    dropped.push(entry.cpv.clone());
+                }
+            }
+            i += 1;
+        }
+        self.mergelist.retain(|entry| !dropped.contains(&entry.cpv));
+        self.failed.retain(|failed| !dropped.contains(failed));
+        dropped.split_off(1)
+    }
+
+    /// Scheduler for the entries not merged yet
+    pub fn scheduler(&self, max_jobs: usize) -> Scheduler {
+        let mut scheduler = Scheduler::new(max_jobs);
+        let remaining = self.remaining();
+        for entry in &remaining {
+            scheduler.add_job(&entry.cpv);
+            if entry.interactive {
+                scheduler.set_interactive(&entry.cpv);
+            }
+        }
+        for entry in &remaining {
+            for dep in &entry.deps {
+                scheduler.add_dependency(&entry.cpv, dep);
+            }
+        }
+        scheduler
+    }
 }
 
 pub struct Merger {
@@ -34,6 +127,70 @@ pub struct Merger {
     pub vartree: VarTree,
     pub binhost: Vec<String>,
     pub binhost_mirrors: Vec<String>,
+    /// Binary repositories, highest priority first
+    pub binrepos: Vec<crate::bintree::BinRepoConfig>,
+    pub pkgdir: String,
+    pub config_protect: ConfigProtect,
+    pub keyword_filter: KeywordFilter,
+    pub binpkg_policy: BinPkgPolicy,
+    /// USE a binary package must have been built with to be used
+    pub use_config: crate::config::UseConfig,
+    /// Packages built at the same time (--jobs), sharing the CPUs between them
+    pub parallel_builds: usize,
+    /// --load-average limit passed on to make and ninja
+    pub load_average: Option<f64>,
+    /// Installed packages to unmerge once the package soft blocking them is merged
+    pub blocker_uninstalls: HashMap<String, Vec<String>>,
+    /// --nolock: take neither the emerge instance lock nor package database locks
+    pub nolock: bool,
+    /// PORTAGE_CONFIGROOT the configuration is read from
+    pub config_root: String,
+    /// SYSROOT packages are built against
+    pub sysroot: String,
+    /// Binhost Packages indexes, fetched on first use
+    remote_index: tokio::sync::OnceCell<Vec<RemoteBinPkg>>,
+    /// elog messages the echo module reports once the merge is over
+    elog_echo: std::sync::Mutex<Vec<(String, Vec<crate::elog::ElogMessage>)>>,
+}
+
+/// A binary package that may be merged instead of building from source
+#[derive(Debug, Clone)]
+pub struct BinaryCandidate {
+    pub cpv: String,
+    /// Set when the package still has to be downloaded from a binhost
+    pub remote: Option<RemoteBinPkg>,
+}
+
+/// vercmp on the version parts of two cpvs of the same package
+fn compare_cpv_versions(a: &str, b: &str) -> i32 {
+    match (crate::versions::cpv_getversion(a), crate::versions::cpv_getversion(b)) {
+        (Some(va), Some(vb)) => crate::versions::vercmp(&va, &vb).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// PORTDIR when no repository is configured
+pub const DEFAULT_PORTDIR: &str = "/var/db/repos/gentoo";
+
+/// How an installed package's pkg_prerm/pkg_postrm run
+struct UnmergeHook<'a> {
+    ebuild: &'a Path,
+    /// The environment saved when it was built
+    environment: Option<&'a Path>,
+    portdir: &'a Path,
+    /// USE it was built with, over its IUSE
+    use_flags: HashMap<String, bool>,
+    features: Vec<String>,
+    env: HashMap<String, String>,
+}
+
+/// Run pkg_prerm/pkg_postrm of a package being unmerged: in the environment saved when it
+/// was built if there is one, else from its saved ebuild
+async fn run_unmerge_phase(hook: &UnmergeHook<'_>, phase: BuildPhase) -> Result<(), InvalidData> {
+    match hook.environment {
+        Some(environment) => crate::doebuild::run_saved_pkg_phase(environment, hook.ebuild, hook.portdir, phase, hook.features.clone(), hook.env.clone()).await,
+        None => crate::doebuild::run_pkg_phase(hook.ebuild, hook.portdir, phase, hook.use_flags.clone(), hook.features.clone(), hook.env.clone()).await,
+    }
 }
 
 impl Merger {
@@ -43,6 +200,20 @@ impl Merger {
             vartree: VarTree::new(root),
             binhost: vec![],
             binhost_mirrors: vec![],
+            binrepos: vec![],
+            pkgdir: BinTree::new(root).pkgdir,
+            config_protect: ConfigProtect::new(root, DEFAULT_CONFIG_PROTECT, DEFAULT_CONFIG_PROTECT_MASK),
+            keyword_filter: KeywordFilter::default(),
+            binpkg_policy: BinPkgPolicy::default(),
+            use_config: crate::config::UseConfig::default(),
+            parallel_builds: 1,
+            load_average: None,
+            blocker_uninstalls: HashMap::new(),
+            nolock: false,
+            config_root: "/".to_string(),
+            sysroot: "/".to_string(),
+            remote_index: tokio::sync::OnceCell::new(),
+            elog_echo: std::sync::Mutex::new(Vec::new()),
         }
     }
 
@@ -52,132 +223,338 @@ impl Merger {
             vartree: VarTree::new(root),
             binhost,
             binhost_mirrors,
+            binrepos: vec![],
+            pkgdir: BinTree::new(root).pkgdir,
+            config_protect: ConfigProtect::new(root, DEFAULT_CONFIG_PROTECT, DEFAULT_CONFIG_PROTECT_MASK),
+            keyword_filter: KeywordFilter::default(),
+            binpkg_policy: BinPkgPolicy::default(),
+            use_config: crate::config::UseConfig::default(),
+            parallel_builds: 1,
+            load_average: None,
+            blocker_uninstalls: HashMap::new(),
+            nolock: false,
+            config_root: "/".to_string(),
+            sysroot: "/".to_string(),
+            remote_index: tokio::sync::OnceCell::new(),
+            elog_echo: std::sync::Mutex::new(Vec::new()),
         }
     }
 
-    /// Find the best available version for a package, considering PortTree
-    pub async fn find_best_version_with_porttree(&self, cp: &str, porttree: Option<&PortTree>) -> Result<Option<String>, InvalidData> {
-        // First check binary packages
-        if !self.binhost.is_empty() {
-            // TODO: Check binhost for available versions
+    pub fn with_nolock(mut self, nolock: bool) -> Self {
+        self.nolock = nolock;
+        self
+    }
+
+    /// Read the configuration from `config_root` and build against `sysroot`
+    pub fn with_config_roots(mut self, config_root: &str, sysroot: &str) -> Self {
+        self.config_root = config_root.to_string();
+        self.sysroot = sysroot.to_string();
+        self
+    }
+
+    /// The configuration for building and merging into ROOT
+    async fn load_config(&self) -> Result<crate::config::Config, InvalidData> {
+        Ok(crate::config::Config::new(&self.config_root).await?.with_roots(&self.root, &self.sysroot))
+    }
+
+    /// The emerge instance lock, unless --nolock
+    async fn lock_emerge(&self) -> Result<Option<crate::locks::LockFile>, InvalidData> {
+        if self.nolock {
+            return Ok(None);
         }
+        crate::locks::lock_emerge(&self.root).await.map(Some)
+    }
+
+    /// The main repository of PORTAGE_CONFIGROOT, PORTDIR for hooks run outside a build
+    fn portdir(&self) -> std::path::PathBuf {
+        let mut porttree = PortTree::new(&self.config_root);
+        porttree.scan_repositories();
+        porttree.repository_locations().into_iter().next().unwrap_or_else(|| std::path::PathBuf::from(DEFAULT_PORTDIR))
+    }
+
+    /// Lock the package database for writing the entry of `cpv`, unless --nolock
+    async fn lock_vdb(&self, cpv: &str) -> Result<Option<crate::locks::VdbLock>, InvalidData> {
+        if self.nolock {
+            return Ok(None);
+        }
+        crate::locks::lock_vdb(&self.root, cpv).await.map(Some)
+    }
+
+    /// Use PKGDIR for locating and creating binary packages
+    pub fn with_pkgdir(mut self, pkgdir: &str) -> Self {
+        self.pkgdir = pkgdir.to_string();
+        self
+    }
+
+    /// Use the given CONFIG_PROTECT settings when merging files
+    pub fn with_config_protect(mut self, config_protect: ConfigProtect) -> Self {
+        self.config_protect = config_protect;
+        self
+    }
+
+    /// Only select ebuild versions whose KEYWORDS this filter accepts
+    pub fn with_keyword_filter(mut self, keyword_filter: KeywordFilter) -> Self {
+        self.keyword_filter = keyword_filter;
+        self
+    }
+
+    /// Consult these binary repositories, in order, instead of the bare binhost list
+    pub fn with_binrepos(mut self, binrepos: Vec<crate::bintree::BinRepoConfig>) -> Self {
+        self.binhost = binrepos.iter().map(|repo| repo.sync_uri.clone()).collect();
+        self.binrepos = binrepos;
+        self
+    }
+
+    /// Which binary packages --usepkg, --usepkgonly and --getbinpkg allow
+    pub fn with_binpkg_policy(mut self, binpkg_policy: BinPkgPolicy) -> Self {
+        self.binpkg_policy = binpkg_policy;
+        self
+    }
+
+    /// Size MAKEOPTS/NINJAOPTS defaults for `parallel_builds` concurrent builds under a load limit
+    pub fn with_job_control(mut self, parallel_builds: usize, load_average: Option<f64>) -> Self {
+        self.parallel_builds = parallel_builds.max(1);
+        self.load_average = load_average;
+        self
+    }
 
-        // Check PortTree for ebuild versions
-        if let Some(porttree) = porttree {
-            if let Some(best_version) = self.find_best_ebuild_version(cp, porttree).await? {
-                return Ok(Some(best_version));
+    /// Unmerge the installed packages listed for a cpv right after merging it
+    pub fn with_blocker_uninstalls(mut self, blocker_uninstalls: HashMap<String, Vec<String>>) -> Self {
+        self.blocker_uninstalls = blocker_uninstalls;
+        self
+    }
+
+    /// USE settings binary packages are checked against
+    pub fn with_use_config(mut self, use_config: crate::config::UseConfig) -> Self {
+        self.use_config = use_config;
+        self
+    }
+
+    /// Dispatch the build's einfo/ewarn/... messages to the PORTAGE_ELOG_SYSTEM modules
+    fn process_elog(&self, cpv: &str, build_env: &crate::doebuild::BuildEnv, config: &crate::config::Config) {
+        let messages = crate::elog::collect_messages(&build_env.workdir.join("temp"));
+        if messages.is_empty() {
+            return;
+        }
+        match crate::elog::ElogSettings::from_config(config).process(cpv, &messages) {
+            Ok(echo) if !echo.is_empty() => {
+                if let Ok(mut pending) = self.elog_echo.lock() {
+                    pending.push((cpv.to_string(), echo));
+                }
             }
+            Ok(_) => {}
+            Err(e) => eprintln!("Warning: elog: {}", e),
         }
+    }
 
-        Ok(None)
+    /// Messages of the merged packages for the echo elog module
+    pub fn elog_summary(&self) -> String {
+        self.elog_echo.lock().map(|pending| crate::elog::format_summary(&pending)).unwrap_or_default()
     }
 
-    /// Find the best ebuild version from PortTree
-    async fn find_best_ebuild_version(&self, cp: &str, porttree: &PortTree) -> Result<Option<String>, InvalidData> {
-        let mut best_version: Option<String> = None;
-        let mut best_cmp = i32::MIN;
+    /// Binary package tree for the configured PKGDIR and binhosts
+    fn bintree(&self) -> BinTree {
+        let bintree = BinTree::with_binhost(&self.root, self.binhost.clone(), self.binhost_mirrors.clone()).with_pkgdir(&self.pkgdir);
+        if self.binrepos.is_empty() { bintree } else { bintree.with_binrepos(self.binrepos.clone()) }
+    }
 
-        // Split cp into category and package
-        let parts: Vec<&str> = cp.split('/').collect();
-        if parts.len() != 2 {
-            return Ok(None);
+    /// Find the best available version for a package, considering PortTree
+    pub async fn find_best_version_with_porttree(&self, cp: &str, porttree: Option<&PortTree>) -> Result<Option<String>, InvalidData> {
+        let mut best = None;
+        if !self.binpkg_policy.usepkgonly
+            && let Some(porttree) = porttree
+        {
+            best = self.find_best_ebuild_version(cp, porttree).await?;
         }
-        let category = parts[0];
-        let package = parts[1];
 
-        // Check each repository
-        for repo in porttree.repositories.values() {
-            let category_path = Path::new(&repo.location).join(category);
-            if !category_path.exists() {
-                continue;
+        // Binary packages compete on version; an equal version is the same cpv
+        if self.binpkg_policy.uses_binaries() {
+            for candidate in self.binary_candidates(cp).await? {
+                if best.as_ref().is_none_or(|current| compare_cpv_versions(&candidate.cpv, current) > 0) {
+                    best = Some(candidate.cpv);
+                }
             }
+        }
+        Ok(best)
+    }
+
+    /// Every version of `cp` find_best_version_with_porttree could pick, best first
+    pub async fn visible_versions(&self, cp: &str, porttree: &PortTree) -> Result<Vec<String>, InvalidData> {
+        let mut versions = Vec::new();
+        if !self.binpkg_policy.usepkgonly {
+            versions.extend(self.ebuild_candidates(cp, porttree).await
+                .into_iter()
+                .filter(|(cpv, keywords)| self.keyword_filter.is_accepted(cpv, keywords))
+                .map(|(cpv, _)| cpv));
+        }
+        if self.binpkg_policy.uses_binaries() {
+            versions.extend(self.binary_candidates(cp).await?.into_iter().map(|candidate| candidate.cpv));
+        }
+        versions.sort_by(|a, b| compare_cpv_versions(b, a).cmp(&0));
+        versions.dedup();
+        Ok(versions)
+    }
 
-            let package_path = category_path.join(package);
-            if !package_path.exists() {
+    /// Binary packages of `cp` that are keyword-visible and were built with the
+    /// USE flags this configuration asks for: PKGDIR first, then binhosts (--getbinpkg)
+    pub async fn binary_candidates(&self, cp: &str) -> Result<Vec<BinaryCandidate>, InvalidData> {
+        let bintree = self.bintree();
+        let mut candidates = Vec::new();
+        for cpv in bintree.get_all_binpkgs().await? {
+            if crate::versions::cpv_getkey(&cpv).as_deref() != Some(cp) {
                 continue;
             }
+            let Some(info) = bintree.parse_binpkg(&cpv).await? else {
+                continue;
+            };
+            let words = |key: &str| info.metadata.get(key).map(|v| v.split_whitespace().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap_or_default();
+            if self.binary_usable(&cpv, &words("KEYWORDS"), &words("IUSE"), &words("USE")) {
+                candidates.push(BinaryCandidate { cpv, remote: None });
+            }
+        }
 
-            // Scan for ebuild files
-            if let Ok(mut entries) = fs::read_dir(&package_path).await {
-                while let Some(entry) = entries.next_entry().await.transpose() {
-                    let entry = match entry {
-                        Ok(e) => e,
-                        Err(_) => continue, // Skip entries we can't read
-                    };
-                    let path = entry.path();
-                    if let Some(ext) = path.extension() {
-                        if ext == "ebuild" {
-                            if let Some(filename) = path.file_stem() {
-                                let filename_str = filename.to_string_lossy();
-
-                                // Extract version from filename (package-version format)
-                                if let Some(last_dash) = filename_str.rfind('-') {
-                                    let version = &filename_str[last_dash + 1..];
-
-                                    // Compare versions
-                                    if let Some(cmp) = crate::versions::vercmp(version, best_version.as_deref().unwrap_or("")) {
-                                        if cmp > best_cmp {
-                                            best_version = Some(version.to_string());
-                                            best_cmp = cmp;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+        if self.binpkg_policy.getbinpkg && !self.binhost.is_empty() {
+            let index = self.remote_index.get_or_try_init(|| bintree.fetch_packages_index()).await?;
+            for pkg in index {
+                if crate::versions::cpv_getkey(&pkg.cpv).as_deref() != Some(cp)
+                    || candidates.iter().any(|c| c.cpv == pkg.cpv)
+                    || !self.binary_usable(&pkg.cpv, &pkg.keywords, &pkg.iuse, &pkg.use_flags)
+                {
+                    continue;
                 }
+                candidates.push(BinaryCandidate { cpv: pkg.cpv.clone(), remote: Some(pkg.clone()) });
             }
         }
+        Ok(candidates)
+    }
 
-        Ok(best_version)
+    /// Whether a binary package's KEYWORDS are accepted and its USE matches the configuration
+    fn binary_usable(&self, cpv: &str, keywords: &[String], iuse: &[String], use_flags: &[String]) -> bool {
+        if !keywords.is_empty() && !self.keyword_filter.is_accepted(cpv, keywords) {
+            return false;
+        }
+        let mut iuse_defaults = HashMap::new();
+        for flag in iuse {
+            match flag.strip_prefix('+') {
+                Some(name) => iuse_defaults.insert(name.to_string(), true),
+                None => iuse_defaults.insert(flag.trim_start_matches('-').to_string(), false),
+            };
+        }
+        let wanted = self.use_config.flags_for(cpv, &iuse_defaults);
+        iuse_defaults.keys().all(|flag| wanted.get(flag).copied().unwrap_or(false) == use_flags.contains(flag))
     }
 
-    /// Get the path to the resume state file
-    fn resume_state_path(&self) -> std::path::PathBuf {
-        Path::new(&self.root).join("var/cache/edb/emerge.state")
+    /// Find the best keyword-visible ebuild version from PortTree
+    async fn find_best_ebuild_version(&self, cp: &str, porttree: &PortTree) -> Result<Option<String>, InvalidData> {
+        let mut best: Option<String> = None;
+        for (cpv, keywords) in self.ebuild_candidates(cp, porttree).await {
+            if !self.keyword_filter.is_accepted(&cpv, &keywords) {
+                continue;
+            }
+            let is_better = match &best {
+                Some(current) => compare_cpv_versions(&cpv, current) > 0,
+                None => true,
+            };
+            if is_better {
+                best = Some(cpv);
+            }
+        }
+        Ok(best)
     }
 
-    /// Save resume state
-    async fn save_resume_state(&self, state: &ResumeState) -> Result<(), InvalidData> {
-        let state_path = self.resume_state_path();
-        tokio::fs::create_dir_all(state_path.parent().unwrap())
-            .await
-            .map_err(|e| InvalidData::new(&format!("Failed to create state directory: {}", e), None))?;
+    /// Versions of `cp` hidden by KEYWORDS, newest first
+    pub async fn keyword_masked_versions(&self, cp: &str, porttree: &PortTree) -> Vec<KeywordMasked> {
+        let mut masked: Vec<KeywordMasked> = self.ebuild_candidates(cp, porttree).await
+            .into_iter()
+            .filter_map(|(cpv, keywords)| self.keyword_filter.check(&cpv, &keywords))
+            .collect();
+        masked.sort_by(|a, b| compare_cpv_versions(&b.cpv, &a.cpv).cmp(&0));
+        masked
+    }
 
-        let json = serde_json::to_string_pretty(state)
-            .map_err(|e| InvalidData::new(&format!("Failed to serialize state: {}", e), None))?;
+    /// All ebuilds for `cp` across repositories as (cpv, KEYWORDS)
+    async fn ebuild_candidates(&self, cp: &str, porttree: &PortTree) -> Vec<(String, Vec<String>)> {
+        let mut candidates = Vec::new();
+        let Some((category, package)) = cp.split_once('/') else {
+            return candidates;
+        };
 
-        tokio::fs::write(&state_path, json)
-            .await
-            .map_err(|e| InvalidData::new(&format!("Failed to write state file: {}", e), None))?;
+        for repo in porttree.repositories.values() {
+            let package_path = Path::new(&repo.location).join(category).join(package);
+            let Ok(mut entries) = fs::read_dir(&package_path).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if path.extension().is_none_or(|ext| ext != "ebuild") {
+                    continue;
+                }
+                let Some(stem) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+                    continue;
+                };
+                let cpv = format!("{}/{}", category, stem);
+                // Skip files whose name does not carry this package's version
+                if crate::versions::cpv_getkey(&cpv).as_deref() != Some(cp) {
+                    continue;
+                }
+                let keywords = fs::read_to_string(&path).await
+                    .map(|content| parse_ebuild_keywords(&content))
+                    .unwrap_or_default();
+                candidates.push((cpv, keywords));
+            }
+        }
+        candidates
+    }
 
-        Ok(())
+    /// The mtimedb, a JSON object whose "resume" key holds the merge in progress
+    fn mtimedb_path(&self) -> std::path::PathBuf {
+        Path::new(&self.root).join("var/cache/edb/mtimedb")
     }
 
-    /// Load resume state
-    async fn load_resume_state(&self) -> Result<Option<ResumeState>, InvalidData> {
-        let state_path = self.resume_state_path();
-        if !state_path.exists() {
-            return Ok(None);
+    fn read_mtimedb(&self) -> Result<serde_json::Map<String, serde_json::Value>, InvalidData> {
+        let path = self.mtimedb_path();
+        if !path.exists() {
+            return Ok(serde_json::Map::new());
         }
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", path.display(), e), None))?;
+        serde_json::from_str(&content)
+            .map_err(|e| InvalidData::new(&format!("Failed to parse {}: {}", path.display(), e), None))
+    }
 
-        let content = tokio::fs::read_to_string(&state_path)
-            .await
-            .map_err(|e| InvalidData::new(&format!("Failed to read state file: {}", e), None))?;
+    fn write_mtimedb(&self, mtimedb: &serde_json::Map<String, serde_json::Value>) -> Result<(), InvalidData> {
+        let path = self.mtimedb_path();
+        std::fs::create_dir_all(path.parent().unwrap())
+            .map_err(|e| InvalidData::new(&format!("Failed to create state directory: {}", e), None))?;
+        let json = serde_json::to_string_pretty(mtimedb)
+            .map_err(|e| InvalidData::new(&format!("Failed to serialize state: {}", e), None))?;
+        std::fs::write(&path, json)
+            .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))
+    }
 
-        let state: ResumeState = serde_json::from_str(&content)
-            .map_err(|e| InvalidData::new(&format!("Failed to parse state file: {}", e), None))?;
+    /// Save the merge in progress, keeping the rest of the mtimedb
+    pub fn save_resume_state(&self, state: &ResumeState) -> Result<(), InvalidData> {
+        let mut mtimedb = self.read_mtimedb()?;
+        let value = serde_json::to_value(state)
+            .map_err(|e| InvalidData::new(&format!("Failed to serialize state: {}", e), None))?;
+        mtimedb.insert("resume".to_string(), value);
+        self.write_mtimedb(&mtimedb)
+    }
 
-        Ok(Some(state))
+    /// The merge left unfinished by an earlier run, if any
+    pub fn load_resume_state(&self) -> Result<Option<ResumeState>, InvalidData> {
+        match self.read_mtimedb()?.remove("resume") {
+            Some(value) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(|e| InvalidData::new(&format!("Failed to parse resume list: {}", e), None)),
+            None => Ok(None),
+        }
     }
 
-    /// Clear resume state
-    async fn clear_resume_state(&self) -> Result<(), InvalidData> {
-        let state_path = self.resume_state_path();
-        if state_path.exists() {
-            tokio::fs::remove_file(&state_path)
-                .await
-                .map_err(|e| InvalidData::new(&format!("Failed to remove state file: {}", e), None))?;
+    pub fn clear_resume_state(&self) -> Result<(), InvalidData> {
+        let mut mtimedb = self.read_mtimedb()?;
+        if mtimedb.remove("resume").is_some() {
+            self.write_mtimedb(&mtimedb)?;
         }
         Ok(())
     }
@@ -191,131 +568,60 @@ impl Merger {
     }
 
     pub async fn install_packages_parallel(&self, packages: &[String], pretend: bool, resume: bool, max_jobs: usize) -> Result<MergeResult, InvalidData> {
-        let operation_id = format!("install-{}", chrono::Utc::now().timestamp());
-
-        let (packages_to_process, mut installed, mut failed) = if resume {
-            match self.load_resume_state().await? {
-                Some(state) => {
-                    println!("Resuming previous operation: {}", state.operation_id);
-                    let remaining: Vec<String> = state.packages.into_iter()
-                        .filter(|pkg| !state.completed.contains(pkg) && !state.failed.contains(pkg))
-                        .collect();
-                    (remaining, state.completed, state.failed)
+        let saved = if resume { self.load_resume_state()? } else { None };
+        let state = saved.unwrap_or_else(|| ResumeState::new(packages.iter().map(|cpv| ResumeEntry::ebuild(cpv)).collect(), Vec::new()));
+        let scheduler = state.scheduler(max_jobs);
+        self.install_scheduled(scheduler, pretend, state).await
+    }
+
+    /// Merge the scheduler's packages in dependency order, recording progress in `state`
+    /// for --resume
+    pub async fn install_scheduled(&self, scheduler: Scheduler, pretend: bool, state: ResumeState) -> Result<MergeResult, InvalidData> {
+        let _lock = if pretend { None } else { self.lock_emerge().await? };
+        let mut scheduler = scheduler.with_build_times(crate::progress::BuildTimes::load(&crate::progress::build_times_path(&self.root)));
+        let state = std::sync::Mutex::new(state);
+        let result = scheduler.run(
+            |cpv: String| async move {
+                let (position, total) = crate::progress::position(&cpv).unwrap_or((1, 1));
+                emergelog(&format!(" >>> emerge ({} of {}) {} to {}", position, total, cpv, self.root));
+                self.install_package(&cpv, pretend).await?;
+                for blocked in self.blocker_uninstalls.get(&cpv).into_iter().flatten() {
+                    println!(">>> Unmerging {}, soft blocked by {}", blocked, cpv);
+                    self.remove_package(blocked, pretend).await?;
                 }
-                None => {
-                    println!("No previous operation to resume");
-                    (packages.to_vec(), Vec::new(), Vec::new())
+                emergelog(&format!(" ::: completed emerge ({} of {}) {} to {}", position, total, cpv, self.root));
+                Ok(())
+            },
+            |scheduler| {
+                if pretend {
+                    return;
                 }
-            }
-        } else {
-            // Clear any existing state
-            self.clear_resume_state().await?;
-            (packages.to_vec(), Vec::new(), Vec::new())
-        };
-
-        // For parallel execution, we'll use a simpler approach for now
-        // In a full implementation, we'd analyze dependencies to determine
-        // which packages can be built in parallel
-        if max_jobs == 1 {
-            // Sequential execution (existing logic)
-            let mut in_progress = None;
-
-            for pkg in &packages_to_process {
-                in_progress = Some(pkg.clone());
-
-                // Save state before attempting installation
-                let state = ResumeState {
-                    operation_id: operation_id.clone(),
-                    packages: packages.to_vec(),
-                    completed: installed.clone(),
-                    failed: failed.clone(),
-                    in_progress: in_progress.clone(),
-                    start_time: chrono::Utc::now(),
+                let Ok(mut state) = state.lock() else {
+                    return;
                 };
-                self.save_resume_state(&state).await?;
-
-                match self.install_package(pkg, pretend).await {
-                    Ok(_) => {
-                        installed.push(pkg.clone());
-                        println!("Successfully installed: {}", pkg);
+                for job in scheduler.jobs() {
+                    if job.state == JobState::Done && !state.completed.contains(&job.cpv) {
+                        state.completed.push(job.cpv.clone());
                     }
-                    Err(e) => {
-                        eprintln!("Failed to install {}: {}", pkg, e);
-                        failed.push(pkg.clone());
-                    }
-                }
-            }
-        } else {
-            // Parallel execution
-            println!("Building with up to {} parallel jobs", max_jobs);
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                self.install_packages_parallel_async(
-                    &packages_to_process,
-                    pretend,
-                    max_jobs,
-                    &operation_id,
-                    &mut installed,
-                    &mut failed,
-                ).await
-            })?;
-        }
-
-        // Clear state on completion
-        self.clear_resume_state().await?;
-
-        Ok(MergeResult { installed, failed })
-    }
-
-    async fn install_packages_parallel_async(
-        &self,
-        packages: &[String],
-        pretend: bool,
-        max_jobs: usize,
-        operation_id: &str,
-        installed: &mut Vec<String>,
-        failed: &mut Vec<String>,
-    ) -> Result<(), InvalidData> {
-        let semaphore = Arc::new(Semaphore::new(max_jobs));
-        let mut tasks = Vec::new();
-
-        for pkg in packages {
-            let pkg = pkg.clone();
-            let semaphore = semaphore.clone();
-            let operation_id = operation_id.to_string();
-
-            let task = tokio::spawn(async move {
-                let _permit = semaphore.acquire().await.unwrap();
-                // In a real implementation, we'd create a new Merger instance
-                // or make the methods async. For now, we'll simulate.
-                println!("Building {} (parallel job)", pkg);
-                // Simulate some work
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                Ok::<String, InvalidData>(pkg)
-            });
-
-            tasks.push(task);
-        }
-
-        // Wait for all tasks to complete
-        for task in tasks {
-            match task.await {
-                Ok(Ok(pkg)) => {
-                    installed.push(pkg.clone());
-                    println!("Successfully installed: {}", pkg);
                 }
-                Ok(Err(e)) => {
-                    eprintln!("Failed to install: {}", e);
-                    failed.push("unknown".to_string()); // In real impl, we'd track this properly
+                state.failed = scheduler.jobs().iter().filter(|j| j.state == JobState::Failed).map(|j| j.cpv.clone()).collect();
+                state.in_progress = scheduler.running().into_iter().next();
+                if let Err(e) = self.save_resume_state(&state) {
+                    eprintln!("Warning: {}", e);
                 }
-                Err(e) => {
-                    eprintln!("Task panicked: {}", e);
-                    failed.push("unknown".to_string());
-                }
-            }
+            },
+        ).await;
+
+        if !result.pending.is_empty() {
+            eprintln!("Not merged because of earlier failures: {}", result.pending.join(", "));
         }
 
-        Ok(())
+        // Keep the state around so a failed merge can be resumed
+        if result.failed.is_empty() && !pretend {
+            self.clear_resume_state()?;
+        }
+
+        Ok(MergeResult { installed: result.merged, failed: result.failed })
     }
 
     async fn install_package(&self, cpv: &str, pretend: bool) -> Result<(), InvalidData> {
@@ -327,14 +633,30 @@ impl Merger {
         println!("Installing: {}", cpv);
 
         // Parse package info
-        let pkg = PkgStr::new(cpv)?;
+        let pkg = Cpv::parse(cpv)?;
         println!("Parsed package: {:?}", pkg);
 
-        // Check if binary package is available first
-        let bintree = BinTree::with_binhost("/", self.binhost.clone(), self.binhost_mirrors.clone());
-        if bintree.is_available(cpv) || bintree.is_available_from_binhost(cpv).await {
-            println!("Binary package available, installing from binary");
-            return self.install_binary_package(cpv, pretend).await;
+        // Prefer a matching binary package when --usepkg/--getbinpkg allow it
+        if self.binpkg_policy.uses_binaries() {
+            let cp = crate::versions::cpv_getkey(cpv).unwrap_or_default();
+            if let Some(candidate) = self.binary_candidates(&cp).await?.into_iter().find(|c| c.cpv == cpv) {
+                println!("Binary package available, installing from binary");
+                if let Some(remote) = &candidate.remote {
+                    self.bintree().fetch_remote_package(remote).await?;
+                }
+
+                // Signatures are required by FEATURES=binpkg-request-signature or the binrepo
+                let config = self.load_config().await?;
+                let binrepo = candidate.remote.as_ref()
+                    .and_then(|remote| self.binrepos.iter().find(|repo| repo.sync_uri == remote.binhost).or_else(|| config.binrepo_for(&remote.binhost)));
+                let require_signatures = config.features.iter().any(|f| f == "binpkg-request-signature")
+                    || binrepo.is_some_and(|repo| repo.verify_signature);
+                let verifier = GpgVerifier { keyring: binrepo.and_then(|repo| repo.trusted_keyring.clone()) };
+                return self.install_binary_package(cpv, pretend, &verifier, require_signatures).await;
+            }
+            if self.binpkg_policy.usepkgonly {
+                return Err(InvalidData::new(&format!("No usable binary package for {} (--usepkgonly)", cpv), None));
+            }
         }
 
         // Fall back to building from source
@@ -359,58 +681,151 @@ impl Merger {
             BuildPhase::Install,
         ];
 
-        // USE flags from config
-        let config = crate::config::Config::new("/").await?;
+        // USE flags from config, FEATURES and build variables adjusted by package.env
+        let config = self.load_config().await?;
         let use_flags = config.get_use_flags_map();
+        let features = config.features_for(cpv);
+
+        let mut env = config.build_env_for(cpv);
+        crate::doebuild::apply_job_control(&mut env, self.parallel_builds, self.load_average);
+
+        // Execute build; source builds are timed for the ETAs of later ones
+        let started = std::time::Instant::now();
+        let build_env = doebuild(&ebuild_path, &self.portdir(), &phases, use_flags.clone(), features.clone(), env).await?;
+
+        self.check_collisions(&build_env.destdir, cpv, &features)?;
+
+        let ebuild = crate::doebuild::Ebuild::from_path_with_use(&ebuild_path, &use_flags)?;
+        build_env.execute_phase(&ebuild, BuildPhase::Preinst).await?;
 
-        // Execute build
-        let build_env = doebuild(&ebuild_path, &phases, use_flags, config.features.clone()).await?;
+        let vdb_lock = self.lock_vdb(cpv).await?;
+        // The package database entry is written aside and replaces the installed one only
+        // once every file is merged
+        let entry = crate::vdb::PendingEntry::begin(Path::new(&self.vartree.dbpath), cpv)?;
+        self.update_package_db(entry.path(), &pkg, &ebuild_path, Some(&build_env)).await?;
 
         // Copy installed files from build destdir to root filesystem
         self.copy_files_to_root(&build_env.destdir, &self.root).await?;
+        entry.commit()?;
+        self.vartree.invalidate(cpv);
+        drop(vdb_lock);
 
-        // Create package directory (use temp dir for testing)
-        let temp_dir = std::env::temp_dir();
-        let pkg_dir = temp_dir.join("emerge-rs-db").join(cpv);
-        if let Err(e) = fs::create_dir_all(&pkg_dir).await {
-            return Err(InvalidData::new(&format!("Failed to create package directory: {}", e), None));
-        }
+        build_env.execute_phase(&ebuild, BuildPhase::Postinst).await?;
+        self.process_elog(cpv, &build_env, &config);
+
+        self.prune_preserved_libs()?;
 
-        // Update package database
-        self.update_package_db(&pkg_dir, &pkg, &ebuild_path, Some(&build_env)).await?;
+        // FEATURES=buildpkg: save the image as a binary package in PKGDIR; a live build
+        // is not reproducible from its version, so it gets none
+        if features.iter().any(|f| f == "buildpkg") && build_env.is_live() {
+            println!("Not creating a binary package for live package {}", cpv);
+        } else if features.iter().any(|f| f == "buildpkg") {
+            self.build_binary_package(&ebuild_path, &build_env, &use_flags, &config).await?;
+        }
 
         // Clean up build environment
         if let Err(e) = tokio::fs::remove_dir_all(&build_env.workdir).await {
             eprintln!("Warning: Failed to clean up build directory: {}", e);
         }
 
+        let build_times = crate::progress::build_times_path(&self.root);
+        if let Err(e) = crate::progress::BuildTimes::record(&build_times, cpv, started.elapsed()) {
+            eprintln!("Warning: Failed to record build time in {}: {}", build_times.display(), e);
+        }
+
         println!("Successfully installed: {}", cpv);
         Ok(())
     }
 
-    fn find_ebuild(&self, pkg: &PkgStr) -> Result<std::path::PathBuf, InvalidData> {
-        // Try test portage directory first, then system portage
-        let test_portdir = Path::new("./test-portage");
-        let ebuild_path = test_portdir
-            .join(&pkg.cpv_split[0])  // category
-            .join(&pkg.cpv_split[1])  // package
-            .join(format!("{}-{}.ebuild", pkg.cpv_split[1], pkg.version));
+    /// Files of an image already owned by other installed packages, with their owners.
+    /// Other versions of the same package are being replaced and don't count.
+    pub fn find_collisions(&self, image: &Path, cpv: &str) -> Result<Vec<(String, Vec<String>)>, InvalidData> {
+        let cp = crate::versions::cpv_getkey(cpv).unwrap_or_default();
+        let owners = self.vartree.file_owners();
+        let mut collisions = Vec::new();
+        for entry in crate::contents::generate_contents(image, Path::new(&self.root))? {
+            if matches!(entry, crate::contents::ContentsEntry::Dir { .. }) {
+                continue;
+            }
+            let Some(owned_by) = owners.get(entry.path()) else {
+                continue;
+            };
+            let others: Vec<String> = owned_by.iter()
+                .filter(|owner| crate::versions::cpv_getkey(owner).unwrap_or_default() != cp)
+                .cloned()
+                .collect();
+            if !others.is_empty() {
+                collisions.push((entry.path().to_string(), others));
+            }
+        }
+        Ok(collisions)
+    }
+
+    /// Abort on collisions with FEATURES=collision-protect, warn with protect-owned
+    fn check_collisions(&self, image: &Path, cpv: &str, features: &[String]) -> Result<(), InvalidData> {
+        let protect = features.iter().any(|f| f == "collision-protect");
+        if !protect && !features.iter().any(|f| f == "protect-owned") {
+            return Ok(());
+        }
+        let collisions = self.find_collisions(image, cpv)?;
+        if collisions.is_empty() {
+            return Ok(());
+        }
+
+        eprintln!(" * This package will overwrite one or more files that may belong to other packages:");
+        for (path, owners) in &collisions {
+            eprintln!(" * \t{} (owned by {})", path, owners.join(", "));
+        }
+        if protect {
+            return Err(InvalidData::new(&format!(
+                "{}: {} file collisions with installed packages (FEATURES=collision-protect)", cpv, collisions.len()), None));
+        }
+        eprintln!(" * Continuing anyway (FEATURES=protect-owned warns only)");
+        Ok(())
+    }
+
+    /// Create a binary package from a finished build, recording the build settings
+    async fn build_binary_package(&self, ebuild_path: &Path, build_env: &crate::doebuild::BuildEnv, use_flags: &HashMap<String, bool>, config: &crate::config::Config) -> Result<(), InvalidData> {
+        let ebuild = crate::doebuild::Ebuild::from_path_with_use(ebuild_path, use_flags)?;
+
+        let mut extra: HashMap<String, String> = crate::binpkg::BUILD_VARS.iter()
+            .filter_map(|key| config.get_var(key).map(|value| (key.to_string(), value.clone())))
+            .collect();
+        extra.insert("FEATURES".to_string(), config.features.join(" "));
+        extra.insert("CONTENTS".to_string(), self.generate_contents_file_from_build(&build_env.destdir)?);
+        let mut porttree = PortTree::new(&self.config_root);
+        porttree.scan_repositories();
+        if let Some(repo) = porttree.repository_name_of(ebuild_path) {
+            extra.insert("repository".to_string(), repo.to_string());
+        }
 
-        if ebuild_path.exists() {
-            return Ok(ebuild_path);
+        let mut builder = crate::binpkg::BinPkgBuilder::new(&config.pkgdir).with_format(config.binpkg_format);
+        if config.features.iter().any(|f| f == "binpkg-signing") {
+            builder = builder.with_signer(Box::new(crate::bintree::GpgSigner {
+                key: config.get_var("BINPKG_GPG_SIGNING_KEY").cloned(),
+                homedir: config.get_var("BINPKG_GPG_SIGNING_GPG_HOME").cloned(),
+            }));
         }
+        builder.create(&ebuild, &build_env.destdir, use_flags, &extra).await?;
+        Ok(())
+    }
 
-        // Fall back to system portage
-        let portdir = Path::new("/usr/portage");
-        let system_ebuild_path = portdir
-            .join(&pkg.cpv_split[0])  // category
-            .join(&pkg.cpv_split[1])  // package
-            .join(format!("{}-{}.ebuild", pkg.cpv_split[1], pkg.version));
+    /// The ebuild of `pkg` in the repositories of PORTAGE_CONFIGROOT, else where it would be
+    /// in the main one
+    fn find_ebuild(&self, pkg: &Cpv) -> Result<std::path::PathBuf, InvalidData> {
+        let mut porttree = PortTree::new(&self.config_root);
+        porttree.scan_repositories();
+        if let Some(path) = porttree.get_ebuild_path(&pkg.to_string()) {
+            return Ok(std::path::PathBuf::from(path));
+        }
 
-        Ok(system_ebuild_path)
+        Ok(self.portdir()
+            .join(pkg.category().as_str())
+            .join(pkg.package().as_str())
+            .join(format!("{}.ebuild", pkg.pf())))
     }
 
-    async fn install_binary_package(&self, cpv: &str, pretend: bool) -> Result<(), InvalidData> {
+    async fn install_binary_package(&self, cpv: &str, pretend: bool, verifier: &GpgVerifier, require_signatures: bool) -> Result<(), InvalidData> {
         if pretend {
             println!("Would install binary package: {}", cpv);
             return Ok(());
@@ -419,14 +834,20 @@ impl Merger {
         println!("Installing binary package: {}", cpv);
 
         // Parse package info
-        let pkg = PkgStr::new(cpv)?;
+        let pkg = Cpv::parse(cpv)?;
         println!("Parsed package: {:?}", pkg);
 
         // Check if binary package exists, fetch from binhost if needed
-        let bintree = BinTree::with_binhost("/", self.binhost.clone(), self.binhost_mirrors.clone());
+        let bintree = self.bintree();
         if !bintree.is_available(cpv) && bintree.is_available_from_binhost(cpv).await {
             bintree.fetch_from_binhost(cpv).await?;
         }
+        if let Some((_, BinPkgFormat::Gpkg)) = bintree.find_package(cpv) {
+            return self.install_gpkg_package(&bintree, cpv, verifier, require_signatures).await;
+        }
+        if require_signatures {
+            return Err(InvalidData::new(&format!("{} is not signed: signatures are required, but only gpkg packages can carry them", cpv), None));
+        }
         let binpkg_info = bintree.parse_tbz2(cpv).await?;
 
         match binpkg_info {
@@ -468,9 +889,12 @@ impl Merger {
                     return Err(InvalidData::new("dd command failed", None));
                 }
 
-                // Extract the tar.bz2
+                // Extract the tar.bz2 into the image directory
+                let image_dir = extract_dir.join("image");
+                fs::create_dir_all(&image_dir).await
+                    .map_err(|e| InvalidData::new(&format!("Failed to create image dir: {}", e), None))?;
                 let tar_output = tokio::process::Command::new("tar")
-                    .args(&["-xjf", &tar_path.to_string_lossy(), "-C", &extract_dir.to_string_lossy()])
+                    .args(["-xjf", &tar_path.to_string_lossy(), "-C", &image_dir.to_string_lossy()])
                     .output()
                     .await
                     .map_err(|e| InvalidData::new(&format!("Failed to extract tar.bz2: {}", e), None))?;
@@ -479,30 +903,20 @@ impl Merger {
                     return Err(InvalidData::new("tar extraction failed", None));
                 }
 
-                // Find the image directory (usually contains the files to install)
-                let image_dir = extract_dir.join("image");
-                if !image_dir.exists() {
-                    return Err(InvalidData::new("No image directory found in binary package", None));
-                }
+                // Older packages nest their files under an image/ directory
+                let image_dir = if image_dir.join("image").is_dir() {
+                    image_dir.join("image")
+                } else {
+                    image_dir
+                };
 
                 // Copy files to root
+                let _vdb_lock = self.lock_vdb(cpv).await?;
+                let entry = crate::vdb::PendingEntry::begin(Path::new(&self.vartree.dbpath), cpv)?;
+                self.register_binary_package(entry.path(), &info).await?;
                 self.copy_files_to_root(&image_dir, &self.root).await?;
-
-                // Create package database entry
-                let pkg_dir = std::env::temp_dir().join("emerge-rs-db").join(cpv);
-                fs::create_dir_all(&pkg_dir).await
-                    .map_err(|e| InvalidData::new(&format!("Failed to create package directory: {}", e), None))?;
-
-                // Write basic package info
-                let contents = format!("SLOT={}\nREPO={}\n", info.slot, info.repo);
-                fs::write(pkg_dir.join("environment.bz2"), &[]).await
-                    .map_err(|e| InvalidData::new(&format!("Failed to write environment: {}", e), None))?;
-
-                // Write metadata files
-                for (key, value) in &info.metadata {
-                    fs::write(pkg_dir.join(key), value).await
-                        .map_err(|e| InvalidData::new(&format!("Failed to write metadata {}: {}", key, e), None))?;
-                }
+                entry.commit()?;
+                self.vartree.invalidate(cpv);
 
                 println!("Successfully installed binary package: {}", cpv);
                 Ok(())
@@ -511,16 +925,64 @@ impl Merger {
         }
     }
 
+    /// Install a .gpkg.tar package, checking any member signatures first
+    async fn install_gpkg_package(&self, bintree: &BinTree, cpv: &str, verifier: &GpgVerifier, require_signatures: bool) -> Result<(), InvalidData> {
+        let info = bintree.parse_gpkg(cpv).await?
+            .ok_or_else(|| InvalidData::new(&format!("Binary package not found: {}", cpv), None))?;
+        println!("Found binary package: {} (image: {} bytes)", info.path, info.tar_size);
+
+        let verified = bintree.verify_gpkg(cpv, verifier, require_signatures).await?;
+        if verified > 0 {
+            println!("Verified {} signed member(s) of {}", verified, cpv);
+        }
+
+        let image_dir = std::env::temp_dir().join("emerge-rs-extract").join(cpv).join("image");
+        if image_dir.exists() {
+            fs::remove_dir_all(&image_dir).await
+                .map_err(|e| InvalidData::new(&format!("Failed to clean extract dir: {}", e), None))?;
+        }
+        bintree.extract_gpkg_image(cpv, &image_dir).await?;
+
+        let _vdb_lock = self.lock_vdb(cpv).await?;
+        let entry = crate::vdb::PendingEntry::begin(Path::new(&self.vartree.dbpath), cpv)?;
+        self.register_binary_package(entry.path(), &info).await?;
+        self.copy_files_to_root(&image_dir, &self.root).await?;
+        entry.commit()?;
+        self.vartree.invalidate(cpv);
+
+        println!("Successfully installed binary package: {}", cpv);
+        Ok(())
+    }
+
+    /// Write the package database entry for a binary package into `pkg_dir`
+    async fn register_binary_package(&self, pkg_dir: &Path, info: &crate::bintree::BinPkgInfo) -> Result<(), InvalidData> {
+        // Write metadata files
+        for (key, value) in &info.metadata {
+            fs::write(pkg_dir.join(key), value).await
+                .map_err(|e| InvalidData::new(&format!("Failed to write metadata {}: {}", key, e), None))?;
+        }
+        Ok(())
+    }
+
     async fn copy_files_to_root(&self, source: &Path, root: &str) -> Result<(), InvalidData> {
         use std::pin::Pin;
         use std::future::Future;
 
-        fn copy_recursive<'a>(src: &'a Path, dst: &'a Path) -> Pin<Box<dyn Future<Output = Result<(), InvalidData>> + 'a + Send>> {
+        fn copy_recursive<'a>(src: &'a Path, dst: &'a Path, protect: &'a ConfigProtect) -> Pin<Box<dyn Future<Output = Result<(), InvalidData>> + 'a + Send>> {
             Box::pin(async move {
-                let src_metadata = fs::metadata(src).await
+                let src_metadata = fs::symlink_metadata(src).await
                     .map_err(|e| InvalidData::new(&format!("Failed to read metadata: {}", e), None))?;
-                
-                if src_metadata.is_dir() {
+
+                if src_metadata.file_type().is_symlink() {
+                    // Symlinks are merged as symlinks, replacing whatever was there
+                    let target = fs::read_link(src).await
+                        .map_err(|e| InvalidData::new(&format!("Failed to read link {}: {}", src.display(), e), None))?;
+                    if fs::symlink_metadata(dst).await.is_ok_and(|m| !m.is_dir()) {
+                        let _ = fs::remove_file(dst).await;
+                    }
+                    fs::symlink(&target, dst).await
+                        .map_err(|e| InvalidData::new(&format!("Failed to create symlink {}: {}", dst.display(), e), None))?;
+                } else if src_metadata.is_dir() {
                     if !dst.exists() {
                         fs::create_dir_all(dst).await
                             .map_err(|e| InvalidData::new(&format!("Failed to create dir {}: {}", dst.display(), e), None))?;
@@ -532,16 +994,19 @@ impl Merger {
                         .map_err(|e| InvalidData::new(&format!("Failed to read entry: {}", e), None))? {
                         let src_path = entry.path();
                         let dst_path = dst.join(entry.file_name());
-                        copy_recursive(&src_path, &dst_path).await?;
+                        copy_recursive(&src_path, &dst_path, protect).await?;
                     }
                 } else {
                     // Check if this is a config file that needs protection
-                    if Merger::is_config_file(&dst) && dst.exists() {
-                        // Config file protection: save new version as .new
-                        let new_path = format!("{}.new", dst.display());
-                        println!("Config file {} exists, saving new version as {}", dst.display(), new_path);
-                        fs::copy(src, &new_path).await
-                            .map_err(|e| InvalidData::new(&format!("Failed to copy config {} to {}: {}", src.display(), new_path, e), None))?;
+                    if dst.exists() && protect.is_protected(dst) {
+                        // Config file protection: save new version as ._cfgNNNN_<name>
+                        let contents = fs::read(src).await
+                            .map_err(|e| InvalidData::new(&format!("Failed to read {}: {}", src.display(), e), None))?;
+                        if let Some(new_path) = ConfigProtect::protected_destination(dst, &contents) {
+                            println!("Config file {} is protected, saving new version as {}", dst.display(), new_path.display());
+                            fs::write(&new_path, &contents).await
+                                .map_err(|e| InvalidData::new(&format!("Failed to write config {}: {}", new_path.display(), e), None))?;
+                        }
                     } else {
                         fs::copy(src, dst).await
                             .map_err(|e| InvalidData::new(&format!("Failed to copy {} to {}: {}", src.display(), dst.display(), e), None))?;
@@ -552,7 +1017,7 @@ impl Merger {
         }
 
         let root_path = Path::new(root);
-        copy_recursive(source, root_path).await
+        copy_recursive(source, root_path, &self.config_protect).await
     }
 
     /// Find the best available version for a given category/package
@@ -562,23 +1027,24 @@ impl Merger {
 
 
 
-    async fn update_package_db(&self, pkg_dir: &Path, pkg: &PkgStr, ebuild_path: &Path, build_env: Option<&crate::doebuild::BuildEnv>) -> Result<(), InvalidData> {
+    async fn update_package_db(&self, pkg_dir: &Path, pkg: &Cpv, ebuild_path: &Path, build_env: Option<&crate::doebuild::BuildEnv>) -> Result<(), InvalidData> {
         use crate::doebuild::Ebuild;
 
         // Parse ebuild to get metadata
-        let ebuild = Ebuild::from_path_with_use(ebuild_path, &std::collections::HashMap::new())?;
+        let use_flags = build_env.map(|b| b.use_flags.clone()).unwrap_or_default();
+        let ebuild = Ebuild::from_path_with_use(ebuild_path, &use_flags)?;
 
         // Create package database files
         if let Err(e) = fs::write(pkg_dir.join("SLOT"), format!("{}\n", ebuild.metadata.slot)).await {
             return Err(InvalidData::new(&format!("Failed to write SLOT: {}", e), None));
         }
-        if let Err(e) = fs::write(pkg_dir.join("CATEGORY"), format!("{}\n", pkg.cpv_split[0])).await {
+        if let Err(e) = fs::write(pkg_dir.join("CATEGORY"), format!("{}\n", pkg.category())).await {
             return Err(InvalidData::new(&format!("Failed to write CATEGORY: {}", e), None));
         }
-        if let Err(e) = fs::write(pkg_dir.join("PF"), format!("{}\n", pkg.cpv_split[1])).await {
+        if let Err(e) = fs::write(pkg_dir.join("PF"), format!("{}\n", pkg.pf())).await {
             return Err(InvalidData::new(&format!("Failed to write PF: {}", e), None));
         }
-        if let Err(e) = fs::write(pkg_dir.join("PVR"), format!("{}\n", pkg.version)).await {
+        if let Err(e) = fs::write(pkg_dir.join("PVR"), format!("{}\n", pkg.pvr())).await {
             return Err(InvalidData::new(&format!("Failed to write PVR: {}", e), None));
         }
 
@@ -594,15 +1060,64 @@ impl Merger {
             }
         }
 
+        // IUSE, and the USE the package was built with, over it
+        let iuse: Vec<&str> = ebuild.metadata.iuse.iter().map(|flag| flag.trim_start_matches(['+', '-'])).collect();
+        let mut enabled: Vec<&str> = iuse.iter().copied().filter(|flag| use_flags.get(*flag) == Some(&true)).collect();
+        enabled.sort();
+        for (key, value) in [("IUSE", ebuild.metadata.iuse.join(" ")), ("USE", enabled.join(" "))] {
+            if let Err(e) = fs::write(pkg_dir.join(key), format!("{}\n", value)).await {
+                return Err(InvalidData::new(&format!("Failed to write {}: {}", key, e), None));
+            }
+        }
+
         if let Some(license) = &ebuild.metadata.license {
             if let Err(e) = fs::write(pkg_dir.join("LICENSE"), format!("{}\n", license)).await {
                 return Err(InvalidData::new(&format!("Failed to write LICENSE: {}", e), None));
             }
         }
 
+        // The eclasses, and what a live build checked out, for @live-rebuild
+        if let Some(build_env) = build_env {
+            let mut entries: Vec<(String, String)> = build_env.executor.iter()
+                .map(|executor| ("INHERITED".to_string(), executor.inherited().join(" ")))
+                .filter(|(_, inherited)| !inherited.is_empty())
+                .collect();
+            if let Some(tempdir) = build_env.env_vars.get("T") {
+                entries.extend(crate::git_r3::read_record(&Path::new(tempdir).join(crate::git_r3::RECORD_DIR)));
+            }
+            for (key, value) in entries {
+                if let Err(e) = fs::write(pkg_dir.join(&key), format!("{}\n", value)).await {
+                    return Err(InvalidData::new(&format!("Failed to write {}: {}", key, e), None));
+                }
+            }
+        }
+
+        // Dependencies, with := bound to the slot/subslot built against
+        for (key, atoms) in [("DEPEND", &ebuild.metadata.depend), ("RDEPEND", &ebuild.metadata.rdepend)] {
+            if atoms.is_empty() {
+                continue;
+            }
+            if let Err(e) = fs::write(pkg_dir.join(key), format!("{}\n", self.bind_slot_operators(atoms))).await {
+                return Err(InvalidData::new(&format!("Failed to write {}: {}", key, e), None));
+            }
+        }
+
+        // The environment the build left, which pkg_prerm/pkg_postrm run in at unmerge time
+        if let Some(tempdir) = build_env.and_then(|build_env| build_env.env_vars.get("T")) {
+            let environment = Path::new(tempdir).join("environment");
+            if environment.is_file() {
+                crate::vartree::compress_environment(&environment, &pkg_dir.join("environment.bz2"))?;
+            }
+        }
+
+        // Keep the ebuild so pkg_prerm/pkg_postrm can run at unmerge time
+        if let Err(e) = fs::copy(ebuild_path, pkg_dir.join(format!("{}.ebuild", pkg.pf()))).await {
+            return Err(InvalidData::new(&format!("Failed to save ebuild: {}", e), None));
+        }
+
         // Create CONTENTS file
         let contents = if let Some(build_env) = build_env {
-            self.generate_contents_file_from_build(pkg, &build_env.destdir)?
+            self.generate_contents_file_from_build(&build_env.destdir)?
         } else {
             self.generate_contents_file(pkg)?
         };
@@ -610,33 +1125,46 @@ impl Merger {
             return Err(InvalidData::new(&format!("Failed to write CONTENTS: {}", e), None));
         }
 
-        Ok(())
-    }
-
-    async fn simulate_install(&self, pkg_dir: &Path, pkg: &PkgStr) -> Result<(), InvalidData> {
-        // Create basic package database files
-        if let Err(e) = fs::write(pkg_dir.join("SLOT"), "0\n").await {
-            return Err(InvalidData::new(&format!("Failed to write SLOT: {}", e), None));
-        }
-        if let Err(e) = fs::write(pkg_dir.join("CATEGORY"), format!("{}\n", pkg.cpv_split[0])).await {
-            return Err(InvalidData::new(&format!("Failed to write CATEGORY: {}", e), None));
-        }
-        if let Err(e) = fs::write(pkg_dir.join("PF"), format!("{}\n", pkg.cpv_split[1])).await {
-            return Err(InvalidData::new(&format!("Failed to write PF: {}", e), None));
-        }
-        if let Err(e) = fs::write(pkg_dir.join("PVR"), format!("{}\n", pkg.version)).await {
-            return Err(InvalidData::new(&format!("Failed to write PVR: {}", e), None));
-        }
-
-        // Create CONTENTS file (placeholder)
-        if let Err(e) = fs::write(pkg_dir.join("CONTENTS"), "# Placeholder contents\n").await {
-            return Err(InvalidData::new(&format!("Failed to write CONTENTS: {}", e), None));
+        // Linkage data for preserve-libs and soname dependencies
+        if let Some(build_env) = build_env {
+            use crate::preserved_libs::{format_provides, format_requires, generate_needed, NEEDED_FILE, PROVIDES_FILE, REQUIRES_FILE};
+            let entries = generate_needed(&build_env.destdir)?;
+            let needed: String = entries.iter().map(|entry| format!("{}\n", entry.to_line())).collect();
+            for (name, content) in [(NEEDED_FILE, needed), (PROVIDES_FILE, format_provides(&entries)), (REQUIRES_FILE, format_requires(&entries))] {
+                if content.is_empty() {
+                    continue;
+                }
+                if let Err(e) = fs::write(pkg_dir.join(name), content).await {
+                    return Err(InvalidData::new(&format!("Failed to write {}: {}", name, e), None));
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Render dependencies, recording the installed slot/subslot on `:=` atoms
+    fn bind_slot_operators(&self, atoms: &[crate::dep::Atom]) -> String {
+        atoms.iter()
+            .map(|atom| {
+                if atom.slot_op.as_deref() != Some("=") {
+                    return atom.to_string();
+                }
+                let Some(slot) = self.vartree.best_installed_slot(&atom.cp(), atom.slot.as_deref()) else {
+                    return atom.to_string();
+                };
+                let (slot, subslot) = slot.split_once('/').unwrap_or((&slot, &slot));
+                let mut bound = atom.clone();
+                bound.slot = Some(slot.to_string());
+                bound.sub_slot = Some(subslot.to_string());
+                bound.to_string()
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     pub async fn remove_packages(&self, packages: &[String], pretend: bool) -> Result<MergeResult, InvalidData> {
+        let _lock = if pretend { None } else { self.lock_emerge().await? };
         let mut removed = Vec::new();
         let mut failed = Vec::new();
 
@@ -644,6 +1172,7 @@ impl Merger {
             match self.remove_package(pkg, pretend).await {
                 Ok(_) => removed.push(pkg.clone()),
                 Err(e) => {
+                    emergelog(&format!(" !!! unmerge FAILURE: {}", pkg));
                     eprintln!("Failed to remove {}: {}", pkg, e);
                     failed.push(pkg.clone());
                 }
@@ -663,6 +1192,7 @@ impl Merger {
         }
 
         println!("Removing: {}", cpv);
+        emergelog(&format!(" === Unmerging... ({})", cpv));
 
         // Check if package is installed
         if !self.vartree.is_installed(cpv) {
@@ -673,20 +1203,92 @@ impl Merger {
         let pkg_info = self.vartree.get_pkg_info(cpv).await?
             .ok_or_else(|| InvalidData::new(&format!("Package {} not found in database", cpv), None))?;
 
+        // The ebuild saved in the package database provides pkg_prerm/pkg_postrm
+        let parsed = Cpv::parse(cpv)?;
+        let pf = parsed.pf();
+        let vdb_ebuild = Path::new(&self.root).join("var/db/pkg").join(cpv).join(format!("{}.ebuild", pf));
+        let (features, env) = match self.load_config().await {
+            Ok(config) => (config.features_for(cpv), config.build_env_for(cpv)),
+            Err(_) => (Vec::new(), HashMap::new()),
+        };
+        // Copied aside first, since removal deletes the package database entry
+        let saved_dir = tempfile::TempDir::new()
+            .map_err(|e| InvalidData::new(&format!("Failed to create temporary directory: {}", e), None))?;
+        // Laid out as in a repository, category/package/pf.ebuild, which is where ebuilds are read from
+        let saved_ebuild = saved_dir.path().join(parsed.category().as_str()).join(parsed.package().as_str()).join(format!("{}.ebuild", pf));
+        let has_ebuild = vdb_ebuild.exists()
+            && std::fs::create_dir_all(saved_ebuild.parent().unwrap()).is_ok()
+            && std::fs::copy(&vdb_ebuild, &saved_ebuild).is_ok();
+        let saved_environment = saved_dir.path().join("environment");
+        let environment = self.vartree.environment(cpv)
+            .filter(|environment| std::fs::write(&saved_environment, environment).is_ok())
+            .map(|_| saved_environment.as_path());
+        let enabled = self.vartree.use_flags(cpv).unwrap_or_default();
+        let use_flags = self.vartree.iuse(cpv).unwrap_or_default().into_iter()
+            .map(|flag| (flag.clone(), enabled.contains(&flag)))
+            .chain(enabled.iter().map(|flag| (flag.clone(), true)))
+            .collect();
+        let portdir = self.portdir();
+        let hook = UnmergeHook { ebuild: &saved_ebuild, environment, portdir: &portdir, use_flags, features: features.clone(), env };
+        if has_ebuild {
+            run_unmerge_phase(&hook, BuildPhase::Prerm).await?;
+        }
+
+        let vdb_lock = self.lock_vdb(cpv).await?;
+        if features.iter().any(|f| f == "preserve-libs") {
+            self.preserve_libs(cpv)?;
+        }
+
         // Placeholder: In real implementation, this would:
         // 1. Check reverse dependencies
-        // 2. Run pre-remove hooks
-        // 3. Remove files from filesystem
-        // 4. Update package database
-        // 5. Run post-remove hooks
+        // 2. Remove files from filesystem
+        // 3. Update package database
 
         // Simulate removal
         self.simulate_remove(cpv).await?;
+        self.vartree.invalidate(cpv);
+        drop(vdb_lock);
+
+        if has_ebuild {
+            run_unmerge_phase(&hook, BuildPhase::Postrm).await?;
+        }
 
+        emergelog(&format!(" >>> unmerge success: {}", cpv));
         println!("Successfully removed: {}", cpv);
         Ok(())
     }
 
+    /// Register the libraries of a package being unmerged that others still link against
+    fn preserve_libs(&self, cpv: &str) -> Result<(), InvalidData> {
+        let paths = crate::preserved_libs::find_libs_to_preserve(&self.vartree, cpv);
+        if paths.is_empty() {
+            return Ok(());
+        }
+        println!(">>> Preserving {} librar{} still needed by other packages:", paths.len(), if paths.len() == 1 { "y" } else { "ies" });
+        for path in &paths {
+            println!("    {}", path);
+        }
+        let mut registry = crate::preserved_libs::PreservedLibsRegistry::load(&self.root)?;
+        registry.register(cpv, paths);
+        registry.save()?;
+        println!(" * Use emerge @preserved-rebuild to rebuild packages using these libraries");
+        Ok(())
+    }
+
+    /// Release preserved libraries that are no longer needed after a merge
+    fn prune_preserved_libs(&self) -> Result<(), InvalidData> {
+        let mut registry = crate::preserved_libs::PreservedLibsRegistry::load(&self.root)?;
+        if !registry.has_entries() {
+            return Ok(());
+        }
+        let pruned = registry.prune(&self.vartree);
+        if !pruned.is_empty() {
+            println!(">>> Removing {} preserved librar{} no longer needed", pruned.len(), if pruned.len() == 1 { "y" } else { "ies" });
+            registry.save()?;
+        }
+        Ok(())
+    }
+
     async fn simulate_remove(&self, cpv: &str) -> Result<(), InvalidData> {
         // Remove package directory from /var/db/pkg
         let pkg_dir = Path::new(&self.root).join("var/db/pkg").join(cpv);
@@ -743,93 +1345,32 @@ impl Merger {
             None => return Ok(false),
         };
 
-        // Placeholder: verify files exist, checksums match, etc.
-        // For now, just check if package directory exists
-        let pkg_dir = Path::new(&self.root).join("var/db/pkg").join(cpv);
-        Ok(pkg_dir.exists())
-    }
-
-    /// Generate a CONTENTS file based on actual installed files
-    fn generate_contents_file_from_build(&self, pkg: &PkgStr, destdir: &Path) -> Result<String, InvalidData> {
-        use std::fs;
-        use std::collections::HashMap;
-
-        let mut contents = String::new();
-        let mut file_info = HashMap::new();
-
-        // Walk the destdir and collect all files
-        fn collect_files(dir: &Path, base: &Path, file_info: &mut HashMap<String, (String, u64)>) -> Result<(), InvalidData> {
-            if !dir.exists() {
-                return Ok(());
-            }
-
-            for entry in fs::read_dir(dir)
-                .map_err(|e| InvalidData::new(&format!("Failed to read dir {}: {}", dir.display(), e), None))? {
-                let entry = entry
-                    .map_err(|e| InvalidData::new(&format!("Failed to read entry: {}", e), None))?;
-                let path = entry.path();
-                let relative_path = path.strip_prefix(base)
-                    .map_err(|e| InvalidData::new(&format!("Failed to strip prefix: {}", e), None))?;
-
-                if path.is_dir() {
-                    // Record directory
-                    let path_str = relative_path.to_string_lossy();
-                    if !path_str.is_empty() {
-                        file_info.insert(path_str.to_string(), ("dir".to_string(), 0));
-                    }
-                    collect_files(&path, base, file_info)?;
-                } else {
-                    // Record file with size
-                    let metadata = fs::metadata(&path)
-                        .map_err(|e| InvalidData::new(&format!("Failed to get metadata for {}: {}", path.display(), e), None))?;
-                    let size = metadata.len();
-                    let path_str = relative_path.to_string_lossy().to_string();
-
-                    // Generate a simple MD5-like hash for the file (placeholder)
-                    let hash = format!("{:x}", size.wrapping_mul(0x123456789ABCDEF)); // Simple hash for now
-                    file_info.insert(path_str, ("obj".to_string(), size));
-                }
-            }
-            Ok(())
-        }
-
-        collect_files(destdir, destdir, &mut file_info)?;
-
-        // Generate CONTENTS format
-        let mut dirs: Vec<String> = file_info.iter()
-            .filter(|(_, (typ, _))| typ == "dir")
-            .map(|(path, _)| path.clone())
-            .collect();
-        dirs.sort();
-
-        let mut objs: Vec<(String, u64)> = file_info.iter()
-            .filter(|(_, (typ, _))| typ == "obj")
-            .map(|(path, (_, size))| (path.clone(), *size))
+        // Every recorded file must still be present and unmodified
+        let entries = crate::contents::parse_contents(&pkg_info.contents.join("\n"));
+        let root = Path::new(&self.root);
+        let modified: Vec<&str> = entries.iter()
+            .filter(|e| e.is_modified(root))
+            .map(|e| e.path())
             .collect();
-        objs.sort_by(|a, b| a.0.cmp(&b.0));
-
-        // Add directories first
-        for dir in dirs {
-            contents.push_str(&format!("dir {}\n", dir));
-        }
-
-        // Add objects
-        for (path, size) in objs {
-            // Generate a placeholder hash - in real implementation, this would be MD5
-            let hash = format!("{:032x}", size.wrapping_mul(0x123456789ABCDEF));
-            contents.push_str(&format!("obj {} {} {}\n", path, hash, size));
+        for path in &modified {
+            eprintln!("--- modified or missing: {}", path);
         }
+        Ok(modified.is_empty())
+    }
 
-        Ok(contents)
+    /// Generate a CONTENTS file based on actual installed files
+    fn generate_contents_file_from_build(&self, destdir: &Path) -> Result<String, InvalidData> {
+        let entries = crate::contents::generate_contents(destdir, Path::new(&self.root))?;
+        Ok(crate::contents::format_contents(&entries))
     }
 
     /// Generate a basic CONTENTS file for a package (fallback)
-    fn generate_contents_file(&self, pkg: &PkgStr) -> Result<String, InvalidData> {
+    fn generate_contents_file(&self, pkg: &Cpv) -> Result<String, InvalidData> {
         let mut contents = String::new();
 
         // Parse category/package
-        let category = pkg.cpv_split[0].clone();
-        let package = pkg.cpv_split[1].clone();
+        let category = pkg.category().to_string();
+        let package = pkg.package().to_string();
 
         // Generate typical file structure based on category
         match category.as_str() {
@@ -880,19 +1421,150 @@ impl Merger {
 
         // Add documentation if it exists
         contents.push_str("dir /usr/share/doc\n");
-        contents.push_str(&format!("dir /usr/share/doc/{}-{}\n", package, pkg.version));
+        contents.push_str(&format!("dir /usr/share/doc/{}\n", pkg.pf()));
 
         Ok(contents)
     }
+}
 
-    /// Check if a file path is a config file that should be protected
-    fn is_config_file(path: &Path) -> bool {
-        // Config files are typically in /etc directory
-        if let Some(parent) = path.parent() {
-            if parent.starts_with("/etc") {
-                return true;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_merge_unmerge_runs_hooks() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("root");
+        let repo = temp.path().join("repo");
+        let package_dir = repo.join("app-misc/roundtrip-hooks");
+        fs::create_dir_all(&package_dir).unwrap();
+        let log = temp.path().join("hooks.log");
+        let ebuild_path = package_dir.join("roundtrip-hooks-1.0-r1.ebuild");
+        fs::write(&ebuild_path, format!("EAPI=8\nSLOT=\"0\"\nIUSE=\"+ssl doc\"\npkg_prerm() {{ echo \"prerm ${{USE}}\" >> '{0}'; }}\npkg_postrm() {{ echo postrm >> '{0}'; }}\n", log.display())).unwrap();
+
+        // Merge: the entry is written aside, the image merged, then the entry put in place
+        let merger = Merger::new(root.to_str().unwrap()).with_nolock(true);
+        let cpv = "app-misc/roundtrip-hooks-1.0-r1";
+        let ebuild = crate::doebuild::Ebuild::from_path(&ebuild_path).unwrap();
+        let use_flags = HashMap::from([("ssl".to_string(), true), ("doc".to_string(), false), ("X".to_string(), true)]);
+        let build_env = crate::doebuild::BuildEnv::new(&ebuild, &repo, temp.path(), use_flags, vec![], HashMap::new());
+        fs::create_dir_all(build_env.destdir.join("usr/share/roundtrip")).unwrap();
+        fs::write(build_env.destdir.join("usr/share/roundtrip/data"), "data").unwrap();
+        let entry = crate::vdb::PendingEntry::begin(Path::new(&merger.vartree.dbpath), cpv).unwrap();
+        merger.update_package_db(entry.path(), &Cpv::parse(cpv).unwrap(), &ebuild_path, Some(&build_env)).await.unwrap();
+        merger.copy_files_to_root(&build_env.destdir, &merger.root).await.unwrap();
+        entry.commit().unwrap();
+        let _ = build_env.clean();
+
+        let vdb = root.join("var/db/pkg").join(cpv);
+        assert!(vdb.join("roundtrip-hooks-1.0-r1.ebuild").is_file());
+        assert_eq!(fs::read_to_string(vdb.join("USE")).unwrap(), "ssl\n");
+        assert_eq!(fs::read_to_string(vdb.join("IUSE")).unwrap(), "+ssl doc\n");
+        assert!(root.join("usr/share/roundtrip/data").is_file());
+
+        // Unmerge runs pkg_prerm with the USE the package was built with, then pkg_postrm
+        let result = merger.remove_packages(&[cpv.to_string()], false).await.unwrap();
+        assert!(result.failed.is_empty(), "{:?}", result.failed);
+        assert_eq!(fs::read_to_string(&log).unwrap(), "prerm ssl\npostrm\n");
+        assert!(!vdb.exists());
+    }
+
+    #[test]
+    fn test_resume_state() {
+        let root = TempDir::new().unwrap();
+        let merger = Merger::new(root.path().to_str().unwrap());
+        let entry = |cpv: &str, deps: &[&str]| ResumeEntry {
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            use_flags: vec![("ssl".to_string(), true)],
+            ..ResumeEntry::ebuild(cpv)
+        };
+        let mut state = ResumeState::new(vec![
+            entry("sys-libs/zlib-1.3", &[]),
+            entry("dev-libs/bar-1.0", &[]),
+            entry("app-misc/foo-1.0", &["dev-libs/bar-1.0"]),
+            entry("app-misc/qux-1.0", &["sys-libs/zlib-1.3"]),
+        ], vec!["app-misc/foo".to_string()]);
+        state.completed.push("sys-libs/zlib-1.3".to_string());
+        state.failed.push("dev-libs/bar-1.0".to_string());
+
+        // Other mtimedb keys survive saving and clearing the resume list
+        fs::create_dir_all(root.path().join("var/cache/edb")).unwrap();
+        fs::write(root.path().join("var/cache/edb/mtimedb"), r#"{"updates": {"/var/db/repos/gentoo/profiles/updates/1Q-2024": 1700000000}}"#).unwrap();
+        merger.save_resume_state(&state).unwrap();
+        let mut loaded = merger.load_resume_state().unwrap().unwrap();
+        assert_eq!(loaded.mergelist, state.mergelist);
+        assert_eq!(loaded.favorites, vec!["app-misc/foo"]);
+
+        // --skipfirst drops the failed package and what waits for it
+        assert_eq!(loaded.remaining().iter().map(|e| e.cpv.as_str()).collect::<Vec<_>>(), vec!["dev-libs/bar-1.0", "app-misc/foo-1.0", "app-misc/qux-1.0"]);
+        assert_eq!(loaded.skip_first().as_deref(), Some("dev-libs/bar-1.0"));
+        assert!(loaded.failed.is_empty());
+        assert_eq!(loaded.scheduler(1).jobs().iter().map(|j| j.cpv.as_str()).collect::<Vec<_>>(), vec!["app-misc/qux-1.0"]);
+
+        merger.clear_resume_state().unwrap();
+        assert!(merger.load_resume_state().unwrap().is_none());
+        assert!(fs::read_to_string(root.path().join("var/cache/edb/mtimedb")).unwrap().contains("1Q-2024"));
+    }
+
+    #[test]
+    fn test_find_collisions() {
+        let root = TempDir::new().unwrap();
+        let vdb = root.path().join("var/db/pkg");
+        for (cpv, contents) in [
+            ("sys-libs/foo-1.0", "dir /usr\nobj /usr/lib/libfoo.so d41d8cd98f00b204e9800998ecf8427e 1\n"),
+            ("app-misc/bar-2.0", "obj /usr/bin/bar d41d8cd98f00b204e9800998ecf8427e 1\n"),
+        ] {
+            fs::create_dir_all(vdb.join(cpv)).unwrap();
+            fs::write(vdb.join(cpv).join("CONTENTS"), contents).unwrap();
         }
-        false
+
+        let image = TempDir::new().unwrap();
+        fs::create_dir_all(image.path().join("usr/lib")).unwrap();
+        fs::create_dir_all(image.path().join("usr/bin")).unwrap();
+        fs::write(image.path().join("usr/lib/libfoo.so"), "").unwrap();
+        fs::write(image.path().join("usr/bin/bar"), "").unwrap();
+
+        let merger = Merger::new(root.path().to_str().unwrap());
+        let collisions = merger.find_collisions(image.path(), "app-misc/bar-2.1").unwrap();
+        assert_eq!(collisions, vec![("/usr/lib/libfoo.so".to_string(), vec!["sys-libs/foo-1.0".to_string()])]);
+
+        let features = vec!["collision-protect".to_string()];
+        assert!(merger.check_collisions(image.path(), "app-misc/bar-2.1", &features).is_err());
+        assert!(merger.check_collisions(image.path(), "app-misc/bar-2.1", &["protect-owned".to_string()]).is_ok());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_binary_candidates_respect_use() {
+        let root = TempDir::new().unwrap();
+        let image = root.path().join("image");
+        fs::create_dir_all(image.join("usr/bin")).unwrap();
+        fs::write(image.join("usr/bin/hello"), "").unwrap();
+        let pkgdir = root.path().join("packages");
+        let mut metadata = crate::doebuild::Ebuild::parse_metadata("EAPI=8\nSLOT=\"0\"\n").unwrap();
+        metadata.iuse = vec!["+nls".to_string(), "debug".to_string()];
+        let ebuild = crate::doebuild::Ebuild {
+            path: root.path().join("hello-1.0.ebuild"),
+            category: "app-misc".to_string(),
+            package: "hello".to_string(),
+            version: "1.0".to_string(),
+            metadata,
+        };
+        let built_with = HashMap::from([("nls".to_string(), true)]);
+        crate::binpkg::BinPkgBuilder::new(pkgdir.to_str().unwrap())
+            .create(&ebuild, &image, &built_with, &HashMap::new()).await.unwrap();
+
+        let merger = Merger::new(root.path().to_str().unwrap()).with_pkgdir(pkgdir.to_str().unwrap());
+        // Binaries are ignored without --usepkg
+        assert_eq!(merger.find_best_version("app-misc/hello").await.unwrap(), None);
+
+        let policy = BinPkgPolicy { usepkgonly: true, ..Default::default() };
+        let merger = merger.with_binpkg_policy(policy);
+        assert_eq!(merger.find_best_version("app-misc/hello").await.unwrap().as_deref(), Some("app-misc/hello-1.0"));
+
+        let use_config = crate::config::UseConfig { global: HashMap::from([("debug".to_string(), true)]), ..Default::default() };
+        let merger = merger.with_use_config(use_config);
+        assert!(merger.binary_candidates("app-misc/hello").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,470 @@
+// metadata_cache.rs -- metadata/md5-cache reading, validation and regeneration
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+
+use crate::exception::InvalidData;
+
+/// Cache directory inside a repository
+pub const MD5_CACHE_DIR: &str = "metadata/md5-cache";
+
+/// Metadata keys stored in md5-cache entries
+pub const AUXDB_KEYS: &[&str] = &[
+    "BDEPEND", "DEFINED_PHASES", "DEPEND", "DESCRIPTION", "EAPI", "HOMEPAGE", "IDEPEND",
+    "INHERIT", "IUSE", "KEYWORDS", "LICENSE", "PDEPEND", "PROPERTIES", "RDEPEND",
+    "REQUIRED_USE", "RESTRICT", "SLOT", "SRC_URI",
+];
+
+/// Variables whose eclass values are combined with the ebuild's
+const INCREMENTAL_KEYS: &[&str] = &["IUSE", "REQUIRED_USE", "DEPEND", "RDEPEND", "PDEPEND", "BDEPEND", "IDEPEND"];
+
+/// Phase functions and their DEFINED_PHASES names
+const PHASES: &[(&str, &str)] = &[
+    ("pkg_config", "config"), ("src_compile", "compile"), ("src_configure", "configure"),
+    ("pkg_info", "info"), ("src_install", "install"), ("pkg_nofetch", "nofetch"),
+    ("pkg_postinst", "postinst"), ("pkg_postrm", "postrm"), ("pkg_preinst", "preinst"),
+    ("src_prepare", "prepare"), ("pkg_prerm", "prerm"), ("pkg_pretend", "pretend"),
+    ("pkg_setup", "setup"), ("src_test", "test"), ("src_unpack", "unpack"),
+];
+
+/// One md5-cache entry
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheEntry {
+    pub metadata: BTreeMap<String, String>,
+    /// Every inherited eclass with the MD5 of its source
+    pub eclasses: Vec<(String, String)>,
+    /// MD5 of the ebuild the entry was generated from
+    pub ebuild_md5: String,
+}
+
+impl CacheEntry {
+    pub fn parse(content: &str) -> Self {
+        let mut entry = CacheEntry::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                "_md5_" => entry.ebuild_md5 = value.to_string(),
+                "_eclasses_" => {
+                    let fields: Vec<&str> = value.split('\t').collect();
+                    entry.eclasses = fields.chunks(2)
+                        .filter(|pair| pair.len() == 2)
+                        .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                        .collect();
+                }
+                _ => {
+                    entry.metadata.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+        entry
+    }
+
+    /// Serialize in egencache's format: sorted keys, empty values omitted
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in &self.metadata {
+            if !value.is_empty() {
+                out.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+        if !self.eclasses.is_empty() {
+            let fields: Vec<String> = self.eclasses.iter().map(|(name, md5)| format!("{}\t{}", name, md5)).collect();
+            out.push_str(&format!("_eclasses_={}\n", fields.join("\t")));
+        }
+        out.push_str(&format!("_md5_={}\n", self.ebuild_md5));
+        out
+    }
+}
+
+/// The md5-cache of one repository
+#[derive(Debug, Clone)]
+pub struct Md5Cache {
+    pub location: PathBuf,
+    /// Eclass directories searched in order, the repository's own first
+    pub eclass_dirs: Vec<PathBuf>,
+    /// Namespaces the depend phase runs in when sourcing ebuilds
+    pub sandbox: Option<crate::sandbox::Sandbox>,
+    /// uid/gid the depend phase drops to when we are root
+    pub user: Option<(u32, u32)>,
+}
+
+impl Md5Cache {
+    pub fn new(location: &Path) -> Self {
+        Md5Cache {
+            location: location.to_path_buf(),
+            eclass_dirs: vec![location.join("eclass")],
+            sandbox: None,
+            user: None,
+        }
+    }
+
+    /// Source ebuilds the way portage runs the depend phase: with nothing writable and no
+    /// network where namespaces work, and as the portage user when we are root
+    pub fn confined(mut self) -> Self {
+        self.sandbox = crate::sandbox::Sandbox::is_supported().then(|| crate::sandbox::Sandbox {
+            filesystem: true,
+            network: true,
+            write_paths: Vec::new(),
+            user: None,
+        });
+        if nix::unistd::Uid::effective().is_root()
+            && let Ok(Some(portage)) = nix::unistd::User::from_name("portage")
+        {
+            self.user = Some((portage.uid.as_raw(), portage.gid.as_raw()));
+        }
+        self
+    }
+
+    /// Add the eclass directories of master repositories
+    pub fn with_master_eclass_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.eclass_dirs.extend(dirs);
+        self
+    }
+
+    pub fn entry_path(&self, cpv: &str) -> Option<PathBuf> {
+        let (category, pf) = cpv.split_once('/')?;
+        Some(self.location.join(MD5_CACHE_DIR).join(category).join(pf))
+    }
+
+    pub fn ebuild_path(&self, cpv: &str) -> Option<PathBuf> {
+        let (category, pf) = cpv.split_once('/')?;
+        let parts = crate::versions::catpkgsplit(cpv)?;
+        Some(self.location.join(category).join(&parts[1]).join(format!("{}.ebuild", pf)))
+    }
+
+    fn find_eclass(&self, name: &str) -> Option<PathBuf> {
+        self.eclass_dirs.iter()
+            .map(|dir| dir.join(format!("{}.eclass", name)))
+            .find(|path| path.is_file())
+    }
+
+    /// The cached entry, whether or not it is still valid
+    pub fn read(&self, cpv: &str) -> Option<CacheEntry> {
+        let content = fs::read_to_string(self.entry_path(cpv)?).ok()?;
+        Some(CacheEntry::parse(&content))
+    }
+
+    /// Whether the entry matches the current ebuild and eclass sources
+    pub fn is_valid(&self, cpv: &str, entry: &CacheEntry) -> bool {
+        let Some(ebuild_md5) = self.ebuild_path(cpv).and_then(|path| file_md5(&path)) else {
+            return false;
+        };
+        ebuild_md5 == entry.ebuild_md5 && entry.eclasses.iter().all(|(name, md5)| {
+            self.find_eclass(name).and_then(|path| file_md5(&path)).as_deref() == Some(md5.as_str())
+        })
+    }
+
+    /// Metadata from a valid cache entry
+    pub fn get(&self, cpv: &str) -> Option<BTreeMap<String, String>> {
+        let entry = self.read(cpv)?;
+        self.is_valid(cpv, &entry).then_some(entry.metadata)
+    }
+
+    pub fn write(&self, cpv: &str, entry: &CacheEntry) -> Result<(), InvalidData> {
+        let path = self.entry_path(cpv)
+            .ok_or_else(|| InvalidData::new(&format!("Invalid cpv '{}'", cpv), None))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+        }
+        fs::write(&path, entry.serialize())
+            .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", path.display(), e), None))
+    }
+
+    /// Source the ebuild in bash (depend phase) and collect its metadata
+    pub fn generate(&self, cpv: &str) -> Result<CacheEntry, InvalidData> {
+        let ebuild_path = self.ebuild_path(cpv)
+            .filter(|path| path.is_file())
+            .ok_or_else(|| InvalidData::new(&format!("No ebuild for {}", cpv), None))?;
+        let ebuild_md5 = file_md5(&ebuild_path)
+            .ok_or_else(|| InvalidData::new(&format!("Failed to read {}", ebuild_path.display()), None))?;
+        let parts = crate::versions::catpkgsplit(cpv)
+            .ok_or_else(|| InvalidData::new(&format!("Invalid cpv '{}'", cpv), None))?;
+        let (category, pf) = cpv.split_once('/').unwrap_or_default();
+        let pv = crate::versions::cpv_getversion(cpv).unwrap_or_default();
+        let pv = pv.split("-r").next().unwrap_or_default().to_string();
+
+        let eclass_dirs: Vec<String> = self.eclass_dirs.iter().map(|d| d.display().to_string()).collect();
+        let mut command = Command::new("bash");
+        match (&self.sandbox, self.user) {
+            (Some(sandbox), Some((uid, gid))) => sandbox.clone().with_user(uid, gid).apply(&mut command),
+            (Some(sandbox), None) => sandbox.apply(&mut command),
+            (None, Some((uid, gid))) => {
+                use std::os::unix::process::CommandExt;
+                command.gid(gid).uid(uid);
+            }
+            (None, None) => {}
+        }
+        let mut child = command
+            .env_clear()
+            .env("PATH", "/usr/bin:/bin")
+            .env("EBUILD_PHASE", "depend")
+            .env("CATEGORY", category)
+            .env("PF", pf)
+            .env("PN", &parts[1])
+            .env("PV", &pv)
+            .env("PR", &parts[3])
+            .env("PVR", if parts[3] == "r0" { pv.clone() } else { format!("{}-{}", pv, parts[3]) })
+            .env("P", format!("{}-{}", parts[1], pv))
+            .env("EBUILD", &ebuild_path)
+            .env("ECLASSDIRS", eclass_dirs.join(":"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| InvalidData::new(&format!("Failed to run bash: {}", e), None))?;
+        child.stdin.take().unwrap().write_all(depend_script().as_bytes())
+            .map_err(|e| InvalidData::new(&format!("Failed to run bash: {}", e), None))?;
+        let output = child.wait_with_output()
+            .map_err(|e| InvalidData::new(&format!("Failed to run bash: {}", e), None))?;
+        if !output.status.success() {
+            return Err(InvalidData::new(&format!(
+                "Failed to source {}: {}", ebuild_path.display(), String::from_utf8_lossy(&output.stderr).trim()), None));
+        }
+
+        let mut entry = CacheEntry { ebuild_md5, ..Default::default() };
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if key == "_eclasses_" {
+                for name in value.split_whitespace() {
+                    if entry.eclasses.iter().any(|(n, _)| n == name) {
+                        continue;
+                    }
+                    let md5 = self.find_eclass(name).and_then(|path| file_md5(&path))
+                        .ok_or_else(|| InvalidData::new(&format!("Failed to read {}.eclass", name), None))?;
+                    entry.eclasses.push((name.to_string(), md5));
+                }
+            } else if !value.trim().is_empty() {
+                entry.metadata.insert(key.to_string(), value.split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+        }
+        entry.eclasses.sort();
+        Ok(entry)
+    }
+
+    /// Every ebuild in the repository as a cpv
+    pub fn list_ebuilds(&self) -> Vec<String> {
+        let mut cpvs = Vec::new();
+        for category in list_dirs(&self.location) {
+            if matches!(category.as_str(), "metadata" | "profiles" | "eclass" | "licenses" | "scripts") {
+                continue;
+            }
+            for package in list_dirs(&self.location.join(&category)) {
+                let Ok(entries) = fs::read_dir(self.location.join(&category).join(&package)) else {
+                    continue;
+                };
+                let mut pfs: Vec<String> = entries.flatten()
+                    .filter_map(|e| e.file_name().to_str().and_then(|f| f.strip_suffix(".ebuild")).map(|s| s.to_string()))
+                    .filter(|pf| pf.starts_with(&format!("{}-", package)))
+                    .collect();
+                pfs.sort();
+                cpvs.extend(pfs.into_iter().map(|pf| format!("{}/{}", category, pf)));
+            }
+        }
+        cpvs
+    }
+
+    /// Remove cache entries whose ebuild no longer exists; returns how many
+    pub fn prune(&self) -> usize {
+        let cache_dir = self.location.join(MD5_CACHE_DIR);
+        let mut removed = 0;
+        for category in list_dirs(&cache_dir) {
+            let Ok(entries) = fs::read_dir(cache_dir.join(&category)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let Some(pf) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                    continue;
+                };
+                let cpv = format!("{}/{}", category, pf);
+                if !self.ebuild_path(&cpv).is_some_and(|path| path.is_file()) && fs::remove_file(entry.path()).is_ok() {
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// A valid entry, regenerating and writing it when missing or stale
+    pub fn regen(&self, cpv: &str) -> Result<CacheEntry, InvalidData> {
+        if let Some(entry) = self.read(cpv) && self.is_valid(cpv, &entry) {
+            return Ok(entry);
+        }
+        let entry = self.generate(cpv)?;
+        self.write(cpv, &entry)?;
+        Ok(entry)
+    }
+}
+
+/// Sorted subdirectory names, skipping hidden ones
+fn list_dirs(dir: &Path) -> Vec<String> {
+    let mut dirs: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries.flatten()
+                .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+                .filter(|name| !name.starts_with('.'))
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
+}
+
+pub fn file_md5(path: &Path) -> Option<String> {
+    crate::checksum::hash_file(path, &["MD5"]).ok()?.hashes.remove("MD5")
+}
+
+/// Bash driver for the depend phase: inherit() sources eclasses and keeps their
+/// incremental variables apart from the ebuild's, as ebuild.sh does
+fn depend_script() -> String {
+    let incremental = INCREMENTAL_KEYS.join(" ");
+    let mut script = format!(r#"
+__INCREMENTAL="{incremental}"
+__INHERITED=""
+__DIRECT=""
+__DEPTH=0
+IFS=: read -r -a __ECLASSDIRS <<< "$ECLASSDIRS"
+die() {{ echo "die: $*" >&2; exit 1; }}
+einfo() {{ :; }}; ewarn() {{ :; }}; eerror() {{ :; }}; elog() {{ :; }}; eqawarn() {{ :; }}
+has() {{ local n=$1; shift; local x; for x; do [[ $x == "$n" ]] && return 0; done; return 1; }}
+EXPORT_FUNCTIONS() {{
+    local f
+    for f; do eval "$f() {{ ${{ECLASS}}_$f \"\$@\"; }}"; done
+}}
+inherit() {{
+    local __e __f __d __v __s __n ECLASS
+    for __e; do
+        __f=""
+        for __d in "${{__ECLASSDIRS[@]}}"; do
+            if [[ -f $__d/$__e.eclass ]]; then __f=$__d/$__e.eclass; break; fi
+        done
+        [[ -n $__f ]] || die "$__e.eclass could not be found by inherit"
+        [[ $__DEPTH -eq 0 ]] && __DIRECT+=" $__e"
+        for __v in $__INCREMENTAL; do
+            local "__saved_$__v=${{!__v}}"
+            unset "$__v"
+        done
+        ECLASS=$__e
+        __DEPTH=$((__DEPTH + 1))
+        source "$__f" || die "failed to source $__f"
+        __DEPTH=$((__DEPTH - 1))
+        for __v in $__INCREMENTAL; do
+            __n="__E_$__v"
+            __s="__saved_$__v"
+            printf -v "$__n" '%s %s' "${{!__n}}" "${{!__v}}"
+            printf -v "$__v" '%s' "${{!__s}}"
+        done
+        __INHERITED+=" $__e"
+    done
+}}
+source "$EBUILD" || die "failed to source $EBUILD"
+for __v in $__INCREMENTAL; do
+    __n="__E_$__v"
+    printf -v "$__v" '%s %s' "${{!__n}}" "${{!__v}}"
+done
+INHERIT=$__DIRECT
+__PHASES=""
+"#);
+    for (function, name) in PHASES {
+        script.push_str(&format!("declare -F {} >/dev/null && __PHASES+=\" {}\"\n", function, name));
+    }
+    script.push_str("DEFINED_PHASES=${__PHASES:- -}\n");
+    for key in AUXDB_KEYS {
+        script.push_str(&format!("printf '%s=%s\\n' {} \"$(echo ${})\"\n", key, key));
+    }
+    script.push_str("printf '_eclasses_=%s\\n' \"$__INHERITED\"\n");
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn repo(temp: &TempDir) -> Md5Cache {
+        let location = temp.path();
+        fs::create_dir_all(location.join("eclass")).unwrap();
+        fs::write(location.join("eclass/base.eclass"),
+            "IUSE=\"doc\"\nDEPEND=\"dev-libs/base\"\nbase_src_compile() { :; }\nEXPORT_FUNCTIONS src_compile\n").unwrap();
+        fs::write(location.join("eclass/util.eclass"), "inherit base\nutil_func() { :; }\n").unwrap();
+        fs::create_dir_all(location.join("app-misc/foo")).unwrap();
+        fs::write(location.join("app-misc/foo/foo-1.0-r1.ebuild"),
+            "EAPI=8\ninherit util\nDESCRIPTION=\"Foo for ${PN}\"\nSLOT=\"0\"\nIUSE=\"+ssl\"\nDEPEND=\"dev-libs/openssl\"\nKEYWORDS=\"~amd64\"\nsrc_install() { :; }\n").unwrap();
+        Md5Cache::new(location)
+    }
+
+    #[test]
+    fn test_parse_and_serialize() {
+        let content = "DEFINED_PHASES=install\nEAPI=8\nSLOT=0\n_eclasses_=base\tabc\tutil\tdef\n_md5_=123\n";
+        let entry = CacheEntry::parse(content);
+        assert_eq!(entry.metadata.get("SLOT").map(|s| s.as_str()), Some("0"));
+        assert_eq!(entry.eclasses, vec![("base".to_string(), "abc".to_string()), ("util".to_string(), "def".to_string())]);
+        assert_eq!(entry.ebuild_md5, "123");
+        assert_eq!(entry.serialize(), content);
+    }
+
+    #[test]
+    fn test_generate_and_validate() {
+        let temp = TempDir::new().unwrap();
+        let cache = repo(&temp);
+        let cpv = "app-misc/foo-1.0-r1";
+        assert!(cache.get(cpv).is_none());
+
+        let entry = cache.regen(cpv).unwrap();
+        let value = |key: &str| entry.metadata.get(key).cloned().unwrap_or_default();
+        assert_eq!(value("DESCRIPTION"), "Foo for foo");
+        assert_eq!(value("IUSE"), "doc +ssl");
+        assert_eq!(value("DEPEND"), "dev-libs/base dev-libs/openssl");
+        assert_eq!(value("INHERIT"), "util");
+        assert_eq!(value("DEFINED_PHASES"), "compile install");
+        assert_eq!(entry.eclasses.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(), vec!["base", "util"]);
+        assert_eq!(cache.get(cpv).unwrap(), entry.metadata);
+
+        // Changing an inherited eclass invalidates the entry
+        fs::write(temp.path().join("eclass/base.eclass"), "IUSE=\"doc test\"\n").unwrap();
+        assert!(cache.get(cpv).is_none());
+        assert_eq!(cache.regen(cpv).unwrap().metadata.get("IUSE").unwrap(), "doc test +ssl");
+    }
+
+    #[test]
+    fn test_generate_confined() {
+        if !crate::sandbox::Sandbox::is_supported() {
+            eprintln!("namespaces unavailable, skipping");
+            return;
+        }
+        let temp = TempDir::new().unwrap();
+        let cache = repo(&temp).confined();
+        assert!(cache.sandbox.is_some());
+        // Global scope of an ebuild writing into the repository
+        let ebuild = temp.path().join("app-misc/foo/foo-1.0-r1.ebuild");
+        let content = fs::read_to_string(&ebuild).unwrap();
+        fs::write(&ebuild, format!("{}touch \"${{EBUILD}}.written\" 2>/dev/null || :\n", content)).unwrap();
+
+        let entry = cache.generate("app-misc/foo-1.0-r1").unwrap();
+        assert_eq!(entry.metadata.get("SLOT").map(|s| s.as_str()), Some("0"));
+        assert!(!temp.path().join("app-misc/foo/foo-1.0-r1.ebuild.written").exists());
+    }
+
+    #[test]
+    fn test_list_and_prune() {
+        let temp = TempDir::new().unwrap();
+        let cache = repo(&temp);
+        fs::write(temp.path().join("app-misc/foo/foo-2.0.ebuild"), "EAPI=8\nSLOT=\"0\"\n").unwrap();
+        assert_eq!(cache.list_ebuilds(), vec!["app-misc/foo-1.0-r1", "app-misc/foo-2.0"]);
+
+        cache.regen("app-misc/foo-2.0").unwrap();
+        cache.write("app-misc/gone-1.0", &CacheEntry::default()).unwrap();
+        assert_eq!(cache.prune(), 1);
+        assert!(cache.read("app-misc/foo-2.0").is_some());
+        assert!(cache.read("app-misc/gone-1.0").is_none());
+    }
+}
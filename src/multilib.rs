@@ -0,0 +1,254 @@
+// multilib.rs -- multilib builds: the ABIs ABI_X86 enables, their toolchain settings and
+// the built-in multilib-build and multilib-minimal eclasses looping over them
+
+use std::collections::HashMap;
+
+/// Eclasses that build once per enabled ABI
+pub const ECLASSES: &[&str] = &["multilib-build", "multilib-minimal"];
+
+/// ${MULTILIB_USEDEP}: a dependency is wanted for every ABI its dependent is built for
+pub const MULTILIB_USEDEP: &str = "abi_x86_32(-)?,abi_x86_64(-)?,abi_x86_x32(-)?";
+
+/// An x86 ABI, with the settings an amd64 multilib profile gives it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Abi {
+    /// ABI as the profile names it: amd64, x86, x32
+    pub name: &'static str,
+    /// The ABI_X86 flag enabling it
+    pub flag: &'static str,
+    pub chost: &'static str,
+    pub libdir: &'static str,
+    pub cflags: &'static str,
+}
+
+pub const ABIS: &[Abi] = &[
+    Abi { name: "amd64", flag: "abi_x86_64", chost: "x86_64-pc-linux-gnu", libdir: "lib64", cflags: "-m64" },
+    Abi { name: "x86", flag: "abi_x86_32", chost: "i686-pc-linux-gnu", libdir: "lib", cflags: "-m32" },
+    Abi { name: "x32", flag: "abi_x86_x32", chost: "x86_64-pc-linux-gnux32", libdir: "libx32", cflags: "-mx32" },
+];
+
+impl Abi {
+    pub fn from_name(name: &str) -> Option<&'static Abi> {
+        ABIS.iter().find(|abi| abi.name == name)
+    }
+
+    pub fn from_flag(flag: &str) -> Option<&'static Abi> {
+        ABIS.iter().find(|abi| abi.flag == flag)
+    }
+}
+
+/// The ABIs ABI_X86 enables, `default_abi` last as multilib_get_enabled_abis orders them;
+/// only the default one when none is
+pub fn enabled_abis(use_flags: &HashMap<String, bool>, default_abi: &str) -> Vec<&'static Abi> {
+    let mut abis: Vec<&'static Abi> = ABIS.iter()
+        .filter(|abi| use_flags.get(abi.flag).copied().unwrap_or(false))
+        .collect();
+    abis.sort_by_key(|abi| abi.name == default_abi);
+    if abis.is_empty() {
+        abis.extend(Abi::from_name(default_abi));
+    }
+    abis
+}
+
+/// Environment the built-in eclasses read: MULTILIB_ABIS, DEFAULT_ABI, and the CHOST_*,
+/// LIBDIR_* and CFLAGS_* of each ABI the profile in `env` does not set itself
+pub fn env_vars(use_flags: &HashMap<String, bool>, env: &HashMap<String, String>) -> HashMap<String, String> {
+    let default_abi = env.get("DEFAULT_ABI").filter(|abi| !abi.is_empty()).map_or("amd64", |abi| abi.as_str());
+    let abis = enabled_abis(use_flags, default_abi);
+    let mut vars = HashMap::from([
+        ("MULTILIB_ABIS".to_string(), abis.iter().map(|abi| abi.name).collect::<Vec<_>>().join(" ")),
+        ("DEFAULT_ABI".to_string(), default_abi.to_string()),
+    ]);
+    for abi in abis {
+        // The native ABI builds with the CHOST that was configured
+        let chost = match env.get("CHOST") {
+            Some(chost) if abi.name == default_abi => chost.as_str(),
+            _ => abi.chost,
+        };
+        for (var, value) in [("CHOST", chost), ("LIBDIR", abi.libdir), ("CFLAGS", abi.cflags)] {
+            let name = format!("{}_{}", var, abi.name);
+            if !env.contains_key(&name) {
+                vars.insert(name, value.to_string());
+            }
+        }
+    }
+    vars
+}
+
+/// Replace ${MULTILIB_USEDEP} in a dependency string
+pub fn expand_usedep(deps: &str) -> String {
+    deps.replace("${MULTILIB_USEDEP}", MULTILIB_USEDEP).replace("$MULTILIB_USEDEP", MULTILIB_USEDEP)
+}
+
+/// Source of a built-in eclass
+pub fn eclass_source(name: &str) -> Option<&'static str> {
+    match name {
+        "multilib-build" => Some(MULTILIB_BUILD),
+        "multilib-minimal" => Some(MULTILIB_MINIMAL),
+        _ => None,
+    }
+}
+
+const MULTILIB_BUILD: &str = r#"
+MULTILIB_USEDEP="abi_x86_32(-)?,abi_x86_64(-)?,abi_x86_x32(-)?"
+IUSE="abi_x86_32 abi_x86_64 abi_x86_x32"
+
+multilib_get_enabled_abis() {
+	echo ${MULTILIB_ABIS:-${DEFAULT_ABI:-default}}
+}
+
+multilib_is_native_abi() {
+	[[ ${ABI:-${DEFAULT_ABI}} == "${DEFAULT_ABI}" ]]
+}
+
+_multilib_set_abi() {
+	local abi=$1 var
+	export ABI=${abi}
+	var=CHOST_${abi}
+	export CHOST=${!var:-${CHOST}}
+	var=CFLAGS_${abi}
+	export CFLAGS="${CFLAGS} ${!var}" CXXFLAGS="${CXXFLAGS} ${!var}" LDFLAGS="${LDFLAGS} ${!var}"
+	# Other ABIs link against their own libraries
+	multilib_is_native_abi || export PKG_CONFIG_LIBDIR=${ESYSROOT:-${EPREFIX}}/usr/$(get_libdir)/pkgconfig
+	export BUILD_DIR=${WORKDIR}/${P}-${abi}
+}
+
+multilib_foreach_abi() {
+	local abi
+	for abi in $(multilib_get_enabled_abis); do
+		(
+			_multilib_set_abi "${abi}"
+			"$@"
+		) || die "$* failed for ABI ${abi}"
+	done
+}
+
+multilib_for_best_abi() {
+	(
+		_multilib_set_abi "${DEFAULT_ABI}"
+		"$@"
+	) || die "$* failed for ABI ${DEFAULT_ABI}"
+}
+"#;
+
+const MULTILIB_MINIMAL: &str = r#"
+inherit multilib-build
+
+_multilib_minimal_phase() {
+	local phase=$1
+	mkdir -p "${BUILD_DIR}" && cd "${BUILD_DIR}" || die
+	if declare -f "multilib_${phase}" > /dev/null; then
+		"multilib_${phase}"
+	else
+		"_multilib_minimal_default_${phase}"
+	fi
+}
+
+_multilib_minimal_default_src_configure() {
+	[[ -x ${ECONF_SOURCE:-${S}}/configure ]] || return 0
+	ECONF_SOURCE=${ECONF_SOURCE:-${S}} econf
+}
+
+_multilib_minimal_default_src_compile() {
+	[[ -f Makefile || -f GNUmakefile || -f makefile ]] || return 0
+	emake || die "emake failed for ABI ${ABI}"
+}
+
+_multilib_minimal_default_src_test() {
+	emake -n check > /dev/null 2>&1 || return 0
+	emake -j1 check || die "Tests failed for ABI ${ABI}"
+}
+
+_multilib_minimal_default_src_install() {
+	[[ -f Makefile || -f GNUmakefile || -f makefile ]] || return 0
+	emake DESTDIR="${D}" install || die "emake install failed for ABI ${ABI}"
+}
+
+multilib-minimal_src_configure() {
+	multilib_foreach_abi _multilib_minimal_phase src_configure
+}
+
+multilib-minimal_src_compile() {
+	multilib_foreach_abi _multilib_minimal_phase src_compile
+}
+
+multilib-minimal_src_test() {
+	multilib_foreach_abi _multilib_minimal_phase src_test
+}
+
+multilib-minimal_src_install() {
+	multilib_foreach_abi _multilib_minimal_phase src_install
+	if declare -f multilib_src_install_all > /dev/null; then
+		cd "${S}" || die
+		multilib_src_install_all
+	fi
+}
+
+EXPORT_FUNCTIONS src_configure src_compile src_test src_install
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_enabled_abis() {
+        let use_flags = HashMap::from([
+            ("abi_x86_64".to_string(), true),
+            ("abi_x86_32".to_string(), true),
+            ("abi_x86_x32".to_string(), false),
+        ]);
+        let names = |abis: Vec<&Abi>| abis.iter().map(|abi| abi.name).collect::<Vec<_>>();
+        assert_eq!(names(enabled_abis(&use_flags, "amd64")), vec!["x86", "amd64"]);
+        assert_eq!(names(enabled_abis(&HashMap::new(), "amd64")), vec!["amd64"]);
+        assert_eq!(Abi::from_flag("abi_x86_32").unwrap().libdir, "lib");
+
+        let env = HashMap::from([
+            ("CHOST".to_string(), "x86_64-gentoo-linux-musl".to_string()),
+            ("LIBDIR_x86".to_string(), "lib32".to_string()),
+        ]);
+        let vars = env_vars(&use_flags, &env);
+        assert_eq!(vars["MULTILIB_ABIS"], "x86 amd64");
+        assert_eq!(vars["CHOST_amd64"], "x86_64-gentoo-linux-musl");
+        assert_eq!(vars["CHOST_x86"], "i686-pc-linux-gnu");
+        assert_eq!(vars["CFLAGS_x86"], "-m32");
+        assert!(!vars.contains_key("LIBDIR_x86"));
+
+        assert_eq!(expand_usedep("dev-libs/foo[${MULTILIB_USEDEP}]"), format!("dev-libs/foo[{}]", MULTILIB_USEDEP));
+        assert_eq!(crate::eclass::ShellSource::parse(MULTILIB_BUILD).value("MULTILIB_USEDEP").as_deref(), Some(MULTILIB_USEDEP));
+    }
+
+    #[test]
+    fn test_foreach_abi() {
+        // Each ABI configures in its own BUILD_DIR with its CHOST, CFLAGS and libdir
+        let temp = tempfile::TempDir::new().unwrap();
+        let source = temp.path().join("foo-1.0");
+        std::fs::create_dir_all(&source).unwrap();
+        let configure = source.join("configure");
+        std::fs::write(&configure, "#!/bin/sh\n[ \"$1\" = --help ] && exit 0\necho \"$* $CFLAGS\" > args\n").unwrap();
+        std::fs::set_permissions(&configure, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let econf = crate::econf::Econf::new(&HashMap::new(), "8").bash_function();
+        let script = format!(
+            "die() {{ echo \"$*\" >&2; exit 1; }}\ninherit() {{ :; }}\nemake() {{ :; }}\n{}{}{}\nmultilib-minimal_src_configure\n",
+            econf, MULTILIB_BUILD, MULTILIB_MINIMAL);
+        let use_flags = HashMap::from([("abi_x86_64".to_string(), true), ("abi_x86_32".to_string(), true)]);
+        let output = Command::new("bash")
+            .args(["-c", &script])
+            .envs(env_vars(&use_flags, &HashMap::new()))
+            .env("WORKDIR", temp.path())
+            .env("S", &source)
+            .env("P", "foo-1.0")
+            .env("CFLAGS", "-O2")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let args = |abi: &str| std::fs::read_to_string(temp.path().join(format!("foo-1.0-{}/args", abi))).unwrap();
+        let x86 = args("x86");
+        assert!(x86.contains("--host=i686-pc-linux-gnu") && x86.contains("--libdir=/usr/lib "));
+        assert!(x86.trim_end().ends_with("-O2 -m32"));
+        let amd64 = args("amd64");
+        assert!(amd64.contains("--host=x86_64-pc-linux-gnu") && amd64.contains("--libdir=/usr/lib64 "));
+    }
+}
@@ -1,26 +1,133 @@
-use std::collections::HashSet;
+// news.rs -- GLEP 42 news: the items repositories ship in metadata/news, the ones relevant
+// to this system, and their per-repository read state as eselect news keeps it
+
+use std::collections::BTreeSet;
 use std::fs;
 use std::path::{Path, PathBuf};
+use crate::atom::Atom;
 use crate::exception::InvalidData;
+use crate::vartree::VarTree;
 
 /// Represents a Gentoo news item
 #[derive(Debug, Clone)]
 pub struct NewsItem {
-    pub name: String,           // e.g., "20231001-1"
+    pub name: String,           // e.g., "2023-10-01-foo"
+    pub repo: String,           // Repository shipping the item
     pub title: String,
     pub author: String,
     pub posted: String,         // Date posted
     pub revised: Option<String>, // Date revised
-    pub display_if_uninstalled: bool,
-    pub display_if_installed: bool,
+    /// Display-If-Installed atoms; the item is relevant if any is installed
+    pub display_if_installed: Vec<String>,
+    /// Display-If-Keyword keywords; the item is relevant if any is ARCH
+    pub display_if_keyword: Vec<String>,
+    /// Display-If-Profile paths, relative to the repository's profiles directory; a
+    /// trailing "/*" also matches the profiles below
+    pub display_if_profile: Vec<String>,
     pub content: String,
 }
 
+impl NewsItem {
+    /// Parse the news item `name` of `repo` from its file content
+    pub fn parse(name: &str, repo: &str, content: &str) -> Self {
+        let mut item = NewsItem {
+            name: name.to_string(),
+            repo: repo.to_string(),
+            title: String::new(),
+            author: String::new(),
+            posted: String::new(),
+            revised: None,
+            display_if_installed: Vec::new(),
+            display_if_keyword: Vec::new(),
+            display_if_profile: Vec::new(),
+            content: String::new(),
+        };
+        let mut lines = content.lines();
+        for line in lines.by_ref() {
+            if line.trim().is_empty() {
+                // The headers end at the first empty line
+                break;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match key {
+                "Title" => item.title = value,
+                "Author" => item.author = value,
+                "Posted" => item.posted = value,
+                "Revised" => item.revised = Some(value),
+                "Display-If-Installed" => item.display_if_installed.push(value),
+                "Display-If-Keyword" => item.display_if_keyword.push(value),
+                "Display-If-Profile" => item.display_if_profile.push(value),
+                _ => {}
+            }
+        }
+        item.content = lines.collect::<Vec<&str>>().join("\n");
+        item
+    }
+
+    /// Whether the item is relevant to a system with `vartree` installed, `arch` and the
+    /// profile at `profile`: each kind of Display-If header present has to have one
+    /// header that holds
+    pub fn is_relevant(&self, vartree: &VarTree, arch: &str, profile: Option<&str>) -> bool {
+        let installed = self.display_if_installed.is_empty()
+            || self.display_if_installed.iter().any(|atom| is_installed(vartree, atom));
+        let keyword = self.display_if_keyword.is_empty() || self.display_if_keyword.iter().any(|keyword| keyword == arch);
+        let profile = self.display_if_profile.is_empty()
+            || profile.is_some_and(|profile| self.display_if_profile.iter().any(|pattern| profile_matches(pattern, profile)));
+        installed && keyword && profile
+    }
+}
+
+/// Whether any installed package matches `atom`
+fn is_installed(vartree: &VarTree, atom: &str) -> bool {
+    let Ok(atom) = Atom::new(atom) else {
+        return false;
+    };
+    vartree.installed_versions(&atom.cp()).iter()
+        .filter(|cpv| atom.matches(cpv))
+        .any(|cpv| atom.slot.as_deref().is_none_or(|slot| vartree.slot(cpv).is_some_and(|s| s.split('/').next() == Some(slot))))
+}
+
+fn profile_matches(pattern: &str, profile: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(parent) => profile.strip_prefix(parent).is_some_and(|rest| rest.starts_with('/')),
+        None => pattern == profile,
+    }
+}
+
+/// What items are judged relevant against: the installed packages, ARCH and the profile
+/// make.profile points to
+pub struct NewsSystem {
+    vartree: VarTree,
+    arch: String,
+    profile: Option<PathBuf>,
+}
+
+impl NewsSystem {
+    pub fn new(root: &str, config_root: &str, arch: &str) -> Self {
+        Self {
+            vartree: VarTree::new(root),
+            arch: arch.to_string(),
+            profile: fs::canonicalize(Path::new(config_root).join("etc/portage/make.profile")).ok(),
+        }
+    }
+
+    /// The profile as a path in the profiles directory of the repository at `location`
+    fn profile_in(&self, location: &Path) -> Option<String> {
+        let profiles = fs::canonicalize(location.join("profiles")).ok()?;
+        let relative = self.profile.as_ref()?.strip_prefix(profiles).ok()?;
+        Some(relative.to_string_lossy().into_owned())
+    }
+}
+
 /// News system manager for handling Gentoo news
 pub struct NewsManager {
     root: String,
     news_dir: PathBuf,
-    status_file: PathBuf,
+    /// Repositories and their locations, by name
+    repos: Vec<(String, PathBuf)>,
 }
 
 impl NewsManager {
@@ -30,170 +137,181 @@ impl NewsManager {
         Self {
             root: root.to_string(),
             news_dir: root_path.join("var/lib/gentoo/news"),
-            status_file: root_path.join("var/lib/gentoo/news/news-gentoo.eselect"),
+            repos: Vec::new(),
         }
     }
 
-    /// Get all available news items
-    pub fn get_news_items(&self) -> Result<Vec<NewsItem>, InvalidData> {
-        if !self.news_dir.exists() {
-            return Ok(Vec::new());
-        }
+    /// Read news from the repository `name` at `location`
+    pub fn with_repository(mut self, name: &str, location: &Path) -> Self {
+        self.repos.push((name.to_string(), location.to_path_buf()));
+        self.repos.sort();
+        self
+    }
 
-        let mut items = Vec::new();
+    /// Read news from every repository of `porttree`
+    pub fn with_repositories(self, porttree: &crate::porttree::PortTree) -> Self {
+        porttree.repositories.values().fold(self, |manager, repo| manager.with_repository(&repo.name, Path::new(&repo.location)))
+    }
 
-        for entry in fs::read_dir(&self.news_dir)
-            .map_err(|e| InvalidData::new(&format!("Failed to read news directory: {}", e), None))? {
+    /// The names of the repositories news is read from
+    pub fn repositories(&self) -> Vec<&str> {
+        self.repos.iter().map(|(name, _)| name.as_str()).collect()
+    }
 
-            let entry = entry
-                .map_err(|e| InvalidData::new(&format!("Failed to read directory entry: {}", e), None))?;
-            let path = entry.path();
+    /// Get all the news items the repositories ship, in English
+    pub fn get_news_items(&self) -> Result<Vec<NewsItem>, InvalidData> {
+        let mut items = Vec::new();
+        for (repo, location) in &self.repos {
+            items.extend(self.repo_news_items(repo, location)?);
+        }
 
-            if path.is_file() {
-                if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                    // Skip the status file
-                    if filename == "news-gentoo.eselect" {
-                        continue;
-                    }
+        // Sort by name (which starts with the date)
+        items.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.repo.cmp(&b.repo)));
 
-                    if let Ok(item) = self.parse_news_item(&path) {
-                        items.push(item);
-                    }
-                }
+        Ok(items)
+    }
+
+    fn repo_news_items(&self, repo: &str, location: &Path) -> Result<Vec<NewsItem>, InvalidData> {
+        let Ok(entries) = fs::read_dir(location.join("metadata/news")) else {
+            return Ok(Vec::new());
+        };
+        let mut items = Vec::new();
+        for entry in entries.flatten() {
+            let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            let path = entry.path().join(format!("{}.en.txt", name));
+            if name.starts_with('.') || !path.is_file() {
+                continue;
             }
+            items.push(self.parse_news_item(&path, repo)?);
         }
-
-        // Sort by name (which includes date)
-        items.sort_by(|a, b| a.name.cmp(&b.name));
-
         Ok(items)
     }
 
     /// Parse a news item from file
-    fn parse_news_item(&self, path: &Path) -> Result<NewsItem, InvalidData> {
+    fn parse_news_item(&self, path: &Path, repo: &str) -> Result<NewsItem, InvalidData> {
         let content = fs::read_to_string(path)
             .map_err(|e| InvalidData::new(&format!("Failed to read news item {}: {}", path.display(), e), None))?;
-
-        let filename = path.file_name()
+        let name = path.parent()
+            .and_then(|dir| dir.file_name())
             .and_then(|n| n.to_str())
-            .ok_or_else(|| InvalidData::new("Invalid news item filename", None))?;
-
-        let mut title = String::new();
-        let mut author = String::new();
-        let mut posted = String::new();
-        let mut revised = None;
-        let mut display_if_uninstalled = false;
-        let mut display_if_installed = false;
-        let mut body_start = 0;
-
-        for (i, line) in content.lines().enumerate() {
-            if line.starts_with("Title: ") {
-                title = line[7..].trim().to_string();
-            } else if line.starts_with("Author: ") {
-                author = line[8..].trim().to_string();
-            } else if line.starts_with("Posted: ") {
-                posted = line[8..].trim().to_string();
-            } else if line.starts_with("Revised: ") {
-                revised = Some(line[9..].trim().to_string());
-            } else if line.starts_with("Display-If-Uninstalled: ") {
-                display_if_uninstalled = line[24..].trim().to_lowercase() == "yes";
-            } else if line.starts_with("Display-If-Installed: ") {
-                display_if_installed = line[22..].trim().to_lowercase() == "yes";
-            } else if line.trim().is_empty() && !title.is_empty() {
-                // Empty line after headers, body starts next
-                body_start = i + 1;
-                break;
-            }
-        }
-
-        let news_content = content.lines().skip(body_start).collect::<Vec<&str>>().join("\n");
+            .ok_or_else(|| InvalidData::new("Invalid news item directory", None))?;
+        Ok(NewsItem::parse(name, repo, &content))
+    }
 
-        Ok(NewsItem {
-            name: filename.to_string(),
-            title,
-            author,
-            posted,
-            revised,
-            display_if_uninstalled,
-            display_if_installed,
-            content: news_content,
-        })
+    /// The state file of `repo` of `kind`: "unread", "read" or "skip", the ones items
+    /// have been judged relevant already
+    fn state_file(&self, repo: &str, kind: &str) -> PathBuf {
+        self.news_dir.join(format!("news-{}.{}", repo, kind))
     }
 
-    /// Get unread news items
-    pub fn get_unread_news(&self) -> Result<Vec<NewsItem>, InvalidData> {
-        let all_items = self.get_news_items()?;
-        let read_items = self.get_read_news_names()?;
+    fn read_state(&self, repo: &str, kind: &str) -> Result<BTreeSet<String>, InvalidData> {
+        let path = self.state_file(repo, kind);
+        if !path.exists() {
+            return Ok(BTreeSet::new());
+        }
+        let content = fs::read_to_string(&path)
+            .map_err(|e| InvalidData::new(&format!("Failed to read news status file {}: {}", path.display(), e), None))?;
+        Ok(content.lines().map(|line| line.trim()).filter(|line| !line.is_empty()).map(|line| line.to_string()).collect())
+    }
 
-        Ok(all_items.into_iter()
-            .filter(|item| !read_items.contains(&item.name))
-            .collect())
+    fn write_state(&self, repo: &str, kind: &str, names: &BTreeSet<String>) -> Result<(), InvalidData> {
+        fs::create_dir_all(&self.news_dir)
+            .map_err(|e| InvalidData::new(&format!("Failed to create news directory: {}", e), None))?;
+        let content: String = names.iter().map(|name| format!("{}\n", name)).collect();
+        let path = self.state_file(repo, kind);
+        fs::write(&path, content)
+            .map_err(|e| InvalidData::new(&format!("Failed to write news status file {}: {}", path.display(), e), None))
     }
 
-    /// Get names of read news items from status file
-    fn get_read_news_names(&self) -> Result<HashSet<String>, InvalidData> {
-        if !self.status_file.exists() {
-            return Ok(HashSet::new());
+    /// Get the relevant news items that have not been read
+    pub fn get_unread_news(&self) -> Result<Vec<NewsItem>, InvalidData> {
+        let mut unread = Vec::new();
+        for (repo, _) in &self.repos {
+            let names = self.read_state(repo, "unread")?;
+            unread.extend(self.get_news_items()?.into_iter().filter(|item| item.repo == *repo && names.contains(&item.name)));
         }
+        unread.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.repo.cmp(&b.repo)));
+        Ok(unread)
+    }
 
-        let content = fs::read_to_string(&self.status_file)
-            .map_err(|e| InvalidData::new(&format!("Failed to read news status file: {}", e), None))?;
-
-        let mut read_names = HashSet::new();
+    /// Get the relevant news items, read or not, as eselect news lists them
+    pub fn get_relevant_news(&self) -> Result<Vec<NewsItem>, InvalidData> {
+        let mut relevant = Vec::new();
+        for (repo, _) in &self.repos {
+            let mut names = self.read_state(repo, "unread")?;
+            names.extend(self.read_state(repo, "read")?);
+            relevant.extend(self.get_news_items()?.into_iter().filter(|item| item.repo == *repo && names.contains(&item.name)));
+        }
+        relevant.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.repo.cmp(&b.repo)));
+        Ok(relevant)
+    }
 
-        for line in content.lines() {
-            let line = line.trim();
-            if !line.is_empty() && !line.starts_with('#') {
-                read_names.insert(line.to_string());
+    /// The number of unread items of each repository that has any
+    pub fn unread_counts(&self) -> Result<Vec<(String, usize)>, InvalidData> {
+        let mut counts = Vec::new();
+        for (repo, _) in &self.repos {
+            let count = self.read_state(repo, "unread")?.len();
+            if count > 0 {
+                counts.push((repo.clone(), count));
             }
         }
-
-        Ok(read_names)
+        Ok(counts)
     }
 
-    /// Check if a news item has been read
-    pub fn is_read(&self, news_name: &str) -> Result<bool, InvalidData> {
-        let read_names = self.get_read_news_names()?;
-        Ok(read_names.contains(news_name))
+    /// Check if the news item `news_name` of `repo` has been read
+    pub fn is_read(&self, repo: &str, news_name: &str) -> Result<bool, InvalidData> {
+        Ok(self.read_state(repo, "read")?.contains(news_name))
     }
 
-    /// Mark a news item as read
-    pub fn mark_as_read(&self, news_name: &str) -> Result<(), InvalidData> {
-        let mut read_names = self.get_read_news_names()?;
-        read_names.insert(news_name.to_string());
-
-        self.write_status_file(&read_names)
+    /// Mark the news item `news_name` of `repo` as read
+    pub fn mark_as_read(&self, repo: &str, news_name: &str) -> Result<(), InvalidData> {
+        self.move_item(repo, news_name, "unread", "read")
     }
 
-    /// Mark a news item as unread
-    pub fn mark_as_unread(&self, news_name: &str) -> Result<(), InvalidData> {
-        let mut read_names = self.get_read_news_names()?;
-        read_names.remove(news_name);
-
-        self.write_status_file(&read_names)
+    /// Mark the news item `news_name` of `repo` as unread
+    pub fn mark_as_unread(&self, repo: &str, news_name: &str) -> Result<(), InvalidData> {
+        self.move_item(repo, news_name, "read", "unread")
     }
 
-    /// Write the status file with read news names
-    fn write_status_file(&self, read_names: &HashSet<String>) -> Result<(), InvalidData> {
-        // Ensure the directory exists
-        if let Some(parent) = self.status_file.parent() {
-            fs::create_dir_all(parent)
-                .map_err(|e| InvalidData::new(&format!("Failed to create news directory: {}", e), None))?;
-        }
+    /// Forget the read items of `repo`, so that they are no longer listed
+    pub fn purge(&self, repo: &str) -> Result<(), InvalidData> {
+        self.write_state(repo, "read", &BTreeSet::new())
+    }
 
-        let mut content = String::from("# News items that have been read\n");
-        let mut sorted_names: Vec<&String> = read_names.iter().collect();
-        sorted_names.sort();
+    fn move_item(&self, repo: &str, news_name: &str, from: &str, to: &str) -> Result<(), InvalidData> {
+        let mut source = self.read_state(repo, from)?;
+        let mut target = self.read_state(repo, to)?;
+        source.remove(news_name);
+        target.insert(news_name.to_string());
+        self.write_state(repo, from, &source)?;
+        self.write_state(repo, to, &target)
+    }
 
-        for name in sorted_names {
-            content.push_str(name);
-            content.push('\n');
+    /// Judge the items not seen before against `system`, adding the relevant ones to the
+    /// unread items of their repository; items are judged once, as portage does, so that
+    /// ones read or irrelevant do not come back. Returns how many were added
+    pub fn update(&self, system: &NewsSystem) -> Result<usize, InvalidData> {
+        let mut added = 0;
+        for (repo, location) in &self.repos {
+            let mut skip = self.read_state(repo, "skip")?;
+            let mut unread = self.read_state(repo, "unread")?;
+            let profile = system.profile_in(location);
+            let new_items: Vec<NewsItem> = self.repo_news_items(repo, location)?.into_iter().filter(|item| !skip.contains(&item.name)).collect();
+            if new_items.is_empty() {
+                continue;
+            }
+            for item in new_items {
+                if item.is_relevant(&system.vartree, &system.arch, profile.as_deref()) && unread.insert(item.name.clone()) {
+                    added += 1;
+                }
+                skip.insert(item.name);
+            }
+            self.write_state(repo, "unread", &unread)?;
+            self.write_state(repo, "skip", &skip)?;
         }
-
-        fs::write(&self.status_file, content)
-            .map_err(|e| InvalidData::new(&format!("Failed to write news status file: {}", e), None))?;
-
-        Ok(())
+        Ok(added)
     }
 }
 
@@ -202,6 +320,12 @@ mod tests {
     use super::*;
     use std::fs;
 
+    fn write_item(repo: &Path, name: &str, content: &str) {
+        let dir = repo.join("metadata/news").join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{}.en.txt", name)), content).unwrap();
+    }
+
     #[tokio::test]
     async fn test_news_manager_creation() {
         let manager = NewsManager::new("/");
@@ -210,30 +334,29 @@ mod tests {
 
     #[tokio::test]
     async fn test_parse_news_item() {
-        let temp_dir = tempfile::TempDir::new().unwrap();
-        let news_file = temp_dir.path().join("20231001-1");
-
         let content = r#"Title: Test News Item
 Author: Test Author
 Posted: 2023-10-01
-Display-If-Uninstalled: yes
-Display-If-Installed: no
+News-Item-Format: 2.0
+Display-If-Installed: dev-lang/python:2.7
+Display-If-Installed: <dev-lang/perl-5.36
+Display-If-Keyword: amd64
+Display-If-Profile: default/linux/amd64/17.1/*
 
 This is the content of the news item.
 It can span multiple lines."#;
 
-        fs::write(&news_file, content).unwrap();
-
-        let manager = NewsManager::new("/");
-        let item = manager.parse_news_item(&news_file).unwrap();
+        let item = NewsItem::parse("2023-10-01-test", "gentoo", content);
 
-        assert_eq!(item.name, "20231001-1");
+        assert_eq!(item.name, "2023-10-01-test");
+        assert_eq!(item.repo, "gentoo");
         assert_eq!(item.title, "Test News Item");
         assert_eq!(item.author, "Test Author");
         assert_eq!(item.posted, "2023-10-01");
-        assert_eq!(item.display_if_uninstalled, true);
-        assert_eq!(item.display_if_installed, false);
-        assert!(item.content.contains("This is the content"));
+        assert_eq!(item.display_if_installed, vec!["dev-lang/python:2.7", "<dev-lang/perl-5.36"]);
+        assert_eq!(item.display_if_keyword, vec!["amd64"]);
+        assert_eq!(item.display_if_profile, vec!["default/linux/amd64/17.1/*"]);
+        assert!(item.content.starts_with("This is the content"));
     }
 
     #[tokio::test]
@@ -244,117 +367,111 @@ It can span multiple lines."#;
         let manager = NewsManager::new(temp_path);
 
         // Initially no read items
-        let read_names = manager.get_read_news_names().unwrap();
-        assert!(read_names.is_empty());
+        assert!(!manager.is_read("gentoo", "20231001-1").unwrap());
 
         // Mark an item as read
-        manager.mark_as_read("20231001-1").unwrap();
-
-        let read_names = manager.get_read_news_names().unwrap();
-        assert!(read_names.contains("20231001-1"));
+        manager.mark_as_read("gentoo", "20231001-1").unwrap();
+        assert!(manager.is_read("gentoo", "20231001-1").unwrap());
+        assert!(!manager.is_read("guru", "20231001-1").unwrap());
+        let read = fs::read_to_string(temp_dir.path().join("var/lib/gentoo/news/news-gentoo.read")).unwrap();
+        assert_eq!(read, "20231001-1\n");
 
         // Mark as unread
-        manager.mark_as_unread("20231001-1").unwrap();
-
-        let read_names = manager.get_read_news_names().unwrap();
-        assert!(!read_names.contains("20231001-1"));
+        manager.mark_as_unread("gentoo", "20231001-1").unwrap();
+        assert!(!manager.is_read("gentoo", "20231001-1").unwrap());
+        let unread = fs::read_to_string(temp_dir.path().join("var/lib/gentoo/news/news-gentoo.unread")).unwrap();
+        assert_eq!(unread, "20231001-1\n");
     }
 
-    #[tokio::test]
-    async fn test_get_unread_news() {
+    #[test]
+    fn test_relevance() {
         let temp_dir = tempfile::TempDir::new().unwrap();
-        let temp_path = temp_dir.path().to_str().unwrap();
-
-        let manager = NewsManager::new(temp_path);
-
-        // Create news directory and files
-        let news_dir = temp_dir.path().join("var/lib/gentoo/news");
-        fs::create_dir_all(&news_dir).unwrap();
-
-        // Create two news items
-        let news1_path = news_dir.join("20231001-1");
-        let news2_path = news_dir.join("20231002-1");
-
-        let content1 = r#"Title: First News
-Author: Author1
-Posted: 2023-10-01
-
-First news content."#;
-
-        let content2 = r#"Title: Second News
-Author: Author2
-Posted: 2023-10-02
-
-Second news content."#;
-
-        fs::write(&news1_path, content1).unwrap();
-        fs::write(&news2_path, content2).unwrap();
-
-        // Initially both should be unread
-        let unread = manager.get_unread_news().unwrap();
-        assert_eq!(unread.len(), 2);
-        assert!(unread.iter().any(|n| n.name == "20231001-1"));
-        assert!(unread.iter().any(|n| n.name == "20231002-1"));
-
-        // Mark first as read
-        manager.mark_as_read("20231001-1").unwrap();
+        let pkg = temp_dir.path().join("var/db/pkg/dev-lang/python-2.7.18");
+        fs::create_dir_all(&pkg).unwrap();
+        fs::write(pkg.join("SLOT"), "2.7\n").unwrap();
+        let vartree = VarTree::new(temp_dir.path().to_str().unwrap());
+
+        let item = |headers: &str| NewsItem::parse("item", "gentoo", &format!("Title: T\n{}\n\nBody.", headers));
+        assert!(item("").is_relevant(&vartree, "amd64", None));
+        assert!(item("Display-If-Installed: dev-lang/python:2.7").is_relevant(&vartree, "amd64", None));
+        assert!(!item("Display-If-Installed: dev-lang/python:3.12").is_relevant(&vartree, "amd64", None));
+        // Headers of one kind are alternatives
+        assert!(item("Display-If-Installed: dev-lang/perl\nDisplay-If-Installed: <dev-lang/python-3").is_relevant(&vartree, "amd64", None));
+        assert!(!item("Display-If-Keyword: arm64").is_relevant(&vartree, "amd64", None));
+        // Headers of different kinds all have to hold
+        assert!(!item("Display-If-Installed: dev-lang/python\nDisplay-If-Keyword: arm64").is_relevant(&vartree, "amd64", None));
+
+        let profile = item("Display-If-Profile: default/linux/amd64/17.1/*");
+        assert!(profile.is_relevant(&vartree, "amd64", Some("default/linux/amd64/17.1/desktop")));
+        assert!(!profile.is_relevant(&vartree, "amd64", Some("default/linux/amd64/23.0")));
+        assert!(!profile.is_relevant(&vartree, "amd64", None));
+        assert!(item("Display-If-Profile: default/linux/amd64/23.0").is_relevant(&vartree, "amd64", Some("default/linux/amd64/23.0")));
+    }
 
-        let unread = manager.get_unread_news().unwrap();
-        assert_eq!(unread.len(), 1);
-        assert_eq!(unread[0].name, "20231002-1");
+    #[test]
+    fn test_update() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path().join("root");
+        let repo = temp_dir.path().join("repo");
+        write_item(&repo, "2024-01-01-foo", "Title: Foo\nPosted: 2024-01-01\n\nBody.");
+        write_item(&repo, "2024-02-01-arm", "Title: Arm\nPosted: 2024-02-01\nDisplay-If-Keyword: arm64\n\nBody.");
+        write_item(&repo, "2024-03-01-profile", "Title: Profile\nPosted: 2024-03-01\nDisplay-If-Profile: default/linux/amd64/23.0/*\n\nBody.");
+        fs::write(repo.join("metadata/news/2024-01-01-foo/2024-01-01-foo.de.txt"), "Title: Foo\n").unwrap();
+        fs::create_dir_all(repo.join("profiles/default/linux/amd64/23.0/desktop")).unwrap();
+        fs::create_dir_all(root.join("etc/portage")).unwrap();
+        std::os::unix::fs::symlink(repo.join("profiles/default/linux/amd64/23.0/desktop"), root.join("etc/portage/make.profile")).unwrap();
+
+        let root = root.to_str().unwrap();
+        let manager = NewsManager::new(root).with_repository("gentoo", &repo);
+        let system = NewsSystem::new(root, root, "amd64");
+        assert_eq!(manager.get_news_items().unwrap().len(), 3);
+        assert_eq!(manager.update(&system).unwrap(), 2);
+        assert_eq!(manager.update(&system).unwrap(), 0);
+        let unread: Vec<String> = manager.get_unread_news().unwrap().into_iter().map(|item| item.name).collect();
+        assert_eq!(unread, vec!["2024-01-01-foo", "2024-03-01-profile"]);
+        assert_eq!(manager.unread_counts().unwrap(), vec![("gentoo".to_string(), 2)]);
+
+        // A read item stays read, and is still listed until purged
+        manager.mark_as_read("gentoo", "2024-01-01-foo").unwrap();
+        assert_eq!(manager.update(&system).unwrap(), 0);
+        assert_eq!(manager.get_unread_news().unwrap().len(), 1);
+        assert_eq!(manager.get_relevant_news().unwrap().len(), 2);
+        manager.purge("gentoo").unwrap();
+        assert_eq!(manager.get_relevant_news().unwrap().len(), 1);
     }
 
     #[tokio::test]
     async fn test_parse_news_item_with_revised() {
         let temp_dir = tempfile::TempDir::new().unwrap();
-        let news_file = temp_dir.path().join("20231001-1");
-
-        let content = r#"Title: Test News
-Author: Test Author
-Posted: 2023-10-01
-Revised: 2023-10-02
-Display-If-Uninstalled: no
-Display-If-Installed: yes
-
-This is revised content."#;
-
-        fs::write(&news_file, content).unwrap();
+        write_item(temp_dir.path(), "20231001-1", "Title: Test News\nAuthor: Test Author\nPosted: 2023-10-01\nRevised: 2023-10-02\n\nThis is revised content.");
 
         let manager = NewsManager::new("/");
-        let item = manager.parse_news_item(&news_file).unwrap();
+        let item = manager.parse_news_item(&temp_dir.path().join("metadata/news/20231001-1/20231001-1.en.txt"), "gentoo").unwrap();
 
+        assert_eq!(item.name, "20231001-1");
         assert_eq!(item.title, "Test News");
         assert_eq!(item.author, "Test Author");
         assert_eq!(item.posted, "2023-10-01");
         assert_eq!(item.revised, Some("2023-10-02".to_string()));
-        assert_eq!(item.display_if_uninstalled, false);
-        assert_eq!(item.display_if_installed, true);
+        assert_eq!(item.content, "This is revised content.");
     }
 
     #[tokio::test]
     async fn test_news_sorting() {
         let temp_dir = tempfile::TempDir::new().unwrap();
         let temp_path = temp_dir.path().to_str().unwrap();
+        let gentoo = temp_dir.path().join("gentoo");
+        let guru = temp_dir.path().join("guru");
 
-        let manager = NewsManager::new(temp_path);
-
-        // Create news directory and files in reverse order
-        let news_dir = temp_dir.path().join("var/lib/gentoo/news");
-        fs::create_dir_all(&news_dir).unwrap();
-
-        let news1_path = news_dir.join("20231002-1");
-        let news2_path = news_dir.join("20231001-1");
-
-        let content1 = "Title: Later News\nAuthor: Author\nPosted: 2023-10-02\n\nContent1.";
-        let content2 = "Title: Earlier News\nAuthor: Author\nPosted: 2023-10-01\n\nContent2.";
-
-        fs::write(&news1_path, content1).unwrap();
-        fs::write(&news2_path, content2).unwrap();
+        write_item(&gentoo, "20231002-1", "Title: Later News\nAuthor: Author\nPosted: 2023-10-02\n\nContent1.");
+        write_item(&guru, "20231001-1", "Title: Earlier News\nAuthor: Author\nPosted: 2023-10-01\n\nContent2.");
 
+        let manager = NewsManager::new(temp_path).with_repository("guru", &guru).with_repository("gentoo", &gentoo);
         let items = manager.get_news_items().unwrap();
         assert_eq!(items.len(), 2);
-        // Should be sorted by name (date)
+        // Should be sorted by name (date), across repositories
         assert_eq!(items[0].name, "20231001-1");
+        assert_eq!(items[0].repo, "guru");
         assert_eq!(items[1].name, "20231002-1");
     }
 
@@ -363,7 +480,7 @@ This is revised content."#;
         let temp_dir = tempfile::TempDir::new().unwrap();
         let temp_path = temp_dir.path().to_str().unwrap();
 
-        let manager = NewsManager::new(temp_path);
+        let manager = NewsManager::new(temp_path).with_repository("gentoo", &temp_dir.path().join("repo"));
 
         // No news directory exists
         let items = manager.get_news_items().unwrap();
@@ -378,21 +495,14 @@ This is revised content."#;
         let temp_dir = tempfile::TempDir::new().unwrap();
         let temp_path = temp_dir.path().to_str().unwrap();
 
-        let manager = NewsManager::new(temp_path);
-
-        // Create news directory
-        let news_dir = temp_dir.path().join("var/lib/gentoo/news");
-        fs::create_dir_all(&news_dir).unwrap();
-
         // Create malformed news file (missing title)
-        let news_file = news_dir.join("20231001-1");
-        let content = "Author: Author\nPosted: 2023-10-01\n\nContent.";
-        fs::write(&news_file, content).unwrap();
+        write_item(temp_dir.path(), "20231001-1", "Author: Author\nPosted: 2023-10-01\n\nContent.");
 
         // Should still parse (with empty title)
+        let manager = NewsManager::new(temp_path).with_repository("gentoo", temp_dir.path());
         let items = manager.get_news_items().unwrap();
         assert_eq!(items.len(), 1);
         assert_eq!(items[0].title, "");
         assert_eq!(items[0].author, "Author");
     }
-}
\ No newline at end of file
+}
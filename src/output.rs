@@ -0,0 +1,483 @@
+// output.rs -- Colored terminal output, the merge list display, --ask prompts and --json
+
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Turn colored output on or off for the whole process
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Whether to use color: --color=y/n wins, otherwise only on a terminal without NOCOLOR
+pub fn default_color(choice: Option<&str>) -> bool {
+    match choice {
+        Some("y") => true,
+        Some("n") => false,
+        _ => {
+            let nocolor = std::env::var("NOCOLOR").is_ok_and(|v| matches!(v.to_lowercase().as_str(), "yes" | "true"));
+            !nocolor && std::io::IsTerminal::is_terminal(&std::io::stdout())
+        }
+    }
+}
+
+/// Wrap `text` in the escape sequence of a portage color name when color is on
+pub fn colorize(color: &str, text: &str) -> String {
+    let code = match color {
+        "bold" => "01",
+        "red" => "31;01",
+        "green" => "32;01",
+        "darkgreen" => "32",
+        "yellow" => "33;01",
+        "blue" => "34;01",
+        "turquoise" => "36;01",
+        _ => return text.to_string(),
+    };
+    if color_enabled() {
+        format!("\x1b[{}m{}\x1b[39;49;00m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Where a package in the merge list comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PkgType {
+    Ebuild,
+    Binary,
+}
+
+/// USE of the installed version a merge replaces, from the vdb
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BuiltUse {
+    pub iuse: Vec<String>,
+    pub enabled: Vec<String>,
+}
+
+/// One line of the merge list
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeEntry {
+    pub pkg_type: PkgType,
+    pub cpv: String,
+    /// Version installed in the same slot, replaced by this merge
+    pub installed: Option<String>,
+    /// Other slots of the package are installed, but not this one
+    pub new_slot: bool,
+    pub fetch_restrict: bool,
+    /// Requested on the command line, shown highlighted
+    pub target: bool,
+    pub use_flags: Vec<(String, bool)>,
+    /// USE of the replaced version, to mark what changes
+    pub built_use: Option<BuiltUse>,
+    /// USE_EXPAND variables, whose flags are listed under their own name (VIDEO_CARDS="amdgpu")
+    pub use_expand: Vec<String>,
+    /// Nesting under the targets with --tree
+    pub depth: usize,
+    /// Bytes of distfiles still to download, from the Manifest
+    pub download_size: Option<u64>,
+}
+
+impl MergeEntry {
+    pub fn new(pkg_type: PkgType, cpv: &str) -> Self {
+        MergeEntry {
+            pkg_type,
+            cpv: cpv.to_string(),
+            installed: None,
+            new_slot: false,
+            fetch_restrict: false,
+            target: false,
+            use_flags: Vec::new(),
+            built_use: None,
+            use_expand: Vec::new(),
+            depth: 0,
+            download_size: None,
+        }
+    }
+
+    /// vercmp of the new version against the installed one
+    fn compare_installed(&self) -> Option<i32> {
+        let installed = self.installed.as_deref()?;
+        let version = crate::versions::cpv_getversion(&self.cpv)?;
+        crate::versions::vercmp(&version, installed)
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.compare_installed().is_some_and(|c| c > 0)
+    }
+
+    fn is_downgrade(&self) -> bool {
+        self.compare_installed().is_some_and(|c| c < 0)
+    }
+
+    fn is_reinstall(&self) -> bool {
+        self.installed.is_some() && self.compare_installed().is_none_or(|c| c == 0)
+    }
+
+    /// The seven status columns: Interactive, New, Slot/Replace, Fetch, Update, Downgrade, mask
+    pub fn status(&self) -> String {
+        let column = |set: bool, color: &str, flag: &str| if set { colorize(color, flag) } else { " ".to_string() };
+        let mut status = String::from(" ");
+        status.push_str(&column(self.installed.is_none(), "green", "N"));
+        status.push_str(&if self.is_reinstall() {
+            colorize("yellow", "R")
+        } else {
+            column(self.installed.is_none() && self.new_slot, "green", "S")
+        });
+        status.push_str(&column(self.fetch_restrict, "red", "F"));
+        status.push_str(&column(self.is_upgrade() || self.is_downgrade(), "turquoise", "U"));
+        status.push_str(&column(self.is_downgrade(), "blue", "D"));
+        status.push(' ');
+        status
+    }
+
+    /// USE="..." with enabled flags first. Against the replaced version, flags that changed
+    /// state get a *, flags new to IUSE a %, and flags dropped from IUSE show as (-flag%).
+    /// Unless `verbose`, only those marked flags are listed. USE_EXPAND flags follow in
+    /// their own groups, such as VIDEO_CARDS="amdgpu -nvidia".
+    fn format_use(&self, verbose: bool) -> Option<String> {
+        let mut flags: Vec<&(String, bool)> = self.use_flags.iter().collect();
+        flags.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut groups: Vec<(&str, Vec<String>)> = vec![("USE", Vec::new())];
+        let mut push = |flag: &str, text: String| {
+            let var = crate::config::split_use_expand(flag, &self.use_expand).map_or("USE", |(var, _)| var);
+            match groups.iter_mut().find(|(name, _)| *name == var) {
+                Some((_, formatted)) => formatted.push(text),
+                None => groups.push((var, vec![text])),
+            }
+        };
+        for (flag, enabled) in flags {
+            let shown = crate::config::split_use_expand(flag, &self.use_expand).map_or(flag.as_str(), |(_, value)| value);
+            let name = format!("{}{}", if *enabled { "" } else { "-" }, shown);
+            let (text, color) = match &self.built_use {
+                Some(built) if !built.iuse.contains(flag) => (format!("{}%", name), "yellow"),
+                Some(built) if *enabled != built.enabled.contains(flag) => (format!("{}*", name), "green"),
+                _ if !verbose => continue,
+                _ => (name, if *enabled { "red" } else { "blue" }),
+            };
+            push(flag, colorize(color, &text));
+        }
+        if let Some(built) = &self.built_use {
+            let mut removed: Vec<&String> = built.iuse.iter().filter(|flag| !self.use_flags.iter().any(|(f, _)| f == *flag)).collect();
+            removed.sort();
+            for flag in removed {
+                let shown = crate::config::split_use_expand(flag, &self.use_expand).map_or(flag.as_str(), |(_, value)| value);
+                let was_enabled = if built.enabled.contains(flag) { "*" } else { "" };
+                push(flag, colorize("yellow", &format!("(-{}%{})", shown, was_enabled)));
+            }
+        }
+        groups[1..].sort_by_key(|(var, _)| *var);
+        let formatted: Vec<String> = groups.into_iter()
+            .filter(|(_, formatted)| !formatted.is_empty())
+            .map(|(var, formatted)| format!("{}=\"{}\"", var, formatted.join(" ")))
+            .collect();
+        (!formatted.is_empty()).then(|| formatted.join(" "))
+    }
+
+    /// What merging it does to the installed package: new, new-slot, upgrade, downgrade
+    /// or reinstall
+    pub fn action(&self) -> &'static str {
+        if self.is_upgrade() {
+            "upgrade"
+        } else if self.is_downgrade() {
+            "downgrade"
+        } else if self.is_reinstall() {
+            "reinstall"
+        } else if self.new_slot {
+            "new-slot"
+        } else {
+            "new"
+        }
+    }
+
+    /// The entry for --json: its versions, each USE flag with whether it changed against
+    /// the replaced version, flags dropped from IUSE and the download size
+    pub fn to_json(&self) -> serde_json::Value {
+        let built = self.built_use.as_ref();
+        let use_flags: Vec<serde_json::Value> = self.use_flags.iter().map(|(flag, enabled)| serde_json::json!({
+            "name": flag,
+            "enabled": enabled,
+            "changed": built.is_some_and(|b| b.iuse.contains(flag) && *enabled != b.enabled.contains(flag)),
+            "new": built.is_some_and(|b| !b.iuse.contains(flag)),
+        })).collect();
+        let mut removed: Vec<&String> = built.map(|b| b.iuse.iter().filter(|flag| !self.use_flags.iter().any(|(f, _)| f == *flag)).collect())
+            .unwrap_or_default();
+        removed.sort();
+        serde_json::json!({
+            "cpv": self.cpv,
+            "version": crate::versions::cpv_getversion(&self.cpv),
+            "type": match self.pkg_type {
+                PkgType::Ebuild => "ebuild",
+                PkgType::Binary => "binary",
+            },
+            "action": self.action(),
+            "installed": self.installed,
+            "target": self.target,
+            "fetch_restrict": self.fetch_restrict,
+            "use": use_flags,
+            "removed_use": removed,
+            "download_size": self.download_size,
+            "depth": self.depth,
+        })
+    }
+
+    pub fn format(&self, verbose: bool) -> String {
+        let pkg_type = match self.pkg_type {
+            PkgType::Ebuild => "ebuild".to_string(),
+            PkgType::Binary => colorize("turquoise", "binary"),
+        };
+        let name = colorize(if self.target { "green" } else { "darkgreen" }, &self.cpv);
+        let mut line = format!("[{} {}] {}{}", pkg_type, self.status(), "  ".repeat(self.depth), name);
+        if let Some(installed) = &self.installed
+            && !self.is_reinstall()
+        {
+            line.push_str(&format!(" {}", colorize("blue", &format!("[{}]", installed))));
+        }
+        if let Some(use_flags) = self.format_use(verbose) {
+            line.push(' ');
+            line.push_str(&use_flags);
+        }
+        if verbose && let Some(size) = self.download_size {
+            line.push(' ');
+            line.push_str(&format_size(size));
+        }
+        line
+    }
+}
+
+/// A [blocks] line: an installed package blocked by one being merged. Soft blockers (b)
+/// are resolved by unmerging it, hard ones (B) are not
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockerEntry {
+    /// The blocker atom, without its ! or !!
+    pub atom: String,
+    /// cpv of the package being merged that holds the blocker
+    pub holder: String,
+    pub hard: bool,
+}
+
+impl BlockerEntry {
+    pub fn format(&self) -> String {
+        let (status, kind) = if self.hard { (colorize("red", "B"), "hard") } else { (colorize("yellow", "b"), "soft") };
+        format!("[{} {}      ] {} (\"{}\" is {} blocking {})", colorize("red", "blocks"), status, self.atom, self.atom, kind, self.holder)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "atom": self.atom, "holder": self.holder, "hard": self.hard })
+    }
+}
+
+fn plural(count: usize, singular: &str, plural: &str) -> String {
+    format!("{} {}", count, if count == 1 { singular } else { plural })
+}
+
+/// The "Total: ..." line under the merge list
+pub fn format_totals(entries: &[MergeEntry]) -> String {
+    let count = |f: fn(&MergeEntry) -> bool| entries.iter().filter(|e| f(e)).count();
+    let mut parts = Vec::new();
+    for (n, singular, many) in [
+        (count(MergeEntry::is_upgrade), "upgrade", "upgrades"),
+        (count(MergeEntry::is_downgrade), "downgrade", "downgrades"),
+        (count(|e| e.installed.is_none() && !e.new_slot), "new", "new"),
+        (count(|e| e.installed.is_none() && e.new_slot), "in new slot", "in new slots"),
+        (count(MergeEntry::is_reinstall), "reinstall", "reinstalls"),
+        (count(|e| e.pkg_type == PkgType::Binary), "binary", "binaries"),
+    ] {
+        if n > 0 {
+            parts.push(plural(n, singular, many));
+        }
+    }
+    let mut totals = format!("Total: {} ({})", plural(entries.len(), "package", "packages"), parts.join(", "));
+    if entries.iter().any(|e| e.download_size.is_some()) {
+        totals.push_str(&format!(", Size of downloads: {}", format_size(entries.iter().filter_map(|e| e.download_size).sum())));
+    }
+    totals
+}
+
+/// A download size as emerge shows it, in KiB rounded up with thousands separators
+pub fn format_size(bytes: u64) -> String {
+    let kib = bytes.div_ceil(1024).to_string();
+    let mut grouped = String::new();
+    for (i, digit) in kib.chars().enumerate() {
+        if i > 0 && (kib.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    format!("{} KiB", grouped)
+}
+
+/// Print `value` as JSON on stdout, for --json
+pub fn print_json(value: &impl serde::Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("!!! Failed to serialize JSON output: {}", e),
+    }
+}
+
+/// The merge list as emerge --pretend/--ask shows it, each package's blockers just before it
+pub fn format_merge_list(entries: &[MergeEntry], blockers: &[BlockerEntry], tree: bool, verbose: bool) -> String {
+    let mut out = format!(
+        "\nThese are the packages that would be merged, in {}order:\n\n",
+        if tree { "reverse " } else { "" },
+    );
+    for entry in entries {
+        for blocker in blockers.iter().filter(|blocker| blocker.holder == entry.cpv) {
+            out.push_str(&blocker.format());
+            out.push('\n');
+        }
+        let mut entry = entry.clone();
+        if !tree {
+            entry.depth = 0;
+        }
+        out.push_str(&entry.format(verbose));
+        out.push('\n');
+    }
+    out.push_str(&format!("\n{}\n", format_totals(entries)));
+    if !blockers.is_empty() {
+        let hard = blockers.iter().filter(|blocker| blocker.hard).count();
+        let unsatisfied = if hard > 0 { format!(" ({} unsatisfied)", hard) } else { String::new() };
+        out.push_str(&format!("Conflict: {}{}\n", plural(blockers.len(), "block", "blocks"), unsatisfied));
+    }
+    out
+}
+
+/// Ask `prompt` until the answer is a prefix of Yes or No; an empty answer means Yes.
+/// None when input ends
+pub fn userquery(prompt: &str, input: &mut impl BufRead) -> Option<bool> {
+    loop {
+        print!("{} [{}/{}] ", colorize("bold", prompt), colorize("green", "Yes"), colorize("red", "No"));
+        let _ = std::io::stdout().flush();
+        let mut response = String::new();
+        if input.read_line(&mut response).ok()? == 0 {
+            println!("Interrupted.");
+            return None;
+        }
+        let response = response.trim().to_lowercase();
+        if "yes".starts_with(&response) {
+            return Some(true);
+        }
+        if "no".starts_with(&response) {
+            return Some(false);
+        }
+        println!("Sorry, response '{}' not understood.", response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(cpv: &str, installed: Option<&str>) -> MergeEntry {
+        let mut entry = MergeEntry::new(PkgType::Ebuild, cpv);
+        entry.installed = installed.map(|v| v.to_string());
+        entry
+    }
+
+    #[test]
+    fn test_merge_entry_format() {
+        let mut new = entry("app-misc/foo-1.0", None);
+        new.use_flags = vec![("ssl".to_string(), false), ("zlib".to_string(), true), ("acl".to_string(), true)];
+        assert_eq!(new.format(true), "[ebuild  N     ] app-misc/foo-1.0 USE=\"acl zlib -ssl\"");
+        assert_eq!(new.format(false), "[ebuild  N     ] app-misc/foo-1.0");
+
+        let mut slot = entry("dev-lang/python-3.13", None);
+        slot.new_slot = true;
+        slot.depth = 1;
+        assert_eq!(slot.format(true), "[ebuild  NS    ]   dev-lang/python-3.13");
+
+        assert_eq!(entry("sys-libs/zlib-1.3", Some("1.2.13")).format(true), "[ebuild     U  ] sys-libs/zlib-1.3 [1.2.13]");
+        assert_eq!(entry("sys-libs/zlib-1.2", Some("1.3")).format(true), "[ebuild     UD ] sys-libs/zlib-1.2 [1.3]");
+        assert_eq!(entry("sys-libs/zlib-1.3", Some("1.3")).format(true), "[ebuild   R    ] sys-libs/zlib-1.3");
+
+        let mut binary = entry("app-misc/bar-2.0", None);
+        binary.pkg_type = PkgType::Binary;
+        binary.fetch_restrict = true;
+        assert_eq!(binary.format(true), "[binary  N F   ] app-misc/bar-2.0");
+
+        let entries = vec![new.clone(), slot, binary, entry("sys-libs/zlib-1.3", Some("1.2.13")), entry("sys-libs/zlib-1.3", Some("1.3"))];
+        assert_eq!(format_totals(&entries), "Total: 5 packages (1 upgrade, 2 new, 1 in new slot, 1 reinstall, 1 binary)");
+        let list = format_merge_list(&entries[..2], &[], false, true);
+        assert!(list.starts_with("\nThese are the packages that would be merged, in order:\n\n[ebuild  N     ] app-misc/foo-1.0"));
+        assert!(list.contains("\n[ebuild  NS    ] dev-lang/python-3.13\n"));
+        assert!(format_merge_list(&entries[..2], &[], true, true).contains("in reverse order"));
+
+        let mut sized = entries[..2].to_vec();
+        sized[0].download_size = Some(1_500_000);
+        sized[1].download_size = Some(0);
+        assert_eq!(format_totals(&sized), "Total: 2 packages (1 new, 1 in new slot), Size of downloads: 1,465 KiB");
+        assert_eq!(format_size(0), "0 KiB");
+        assert!(sized[0].format(true).ends_with(" 1,465 KiB"));
+        assert!(!sized[0].format(false).contains("KiB"));
+    }
+
+    #[test]
+    fn test_blocker_lines() {
+        let soft = BlockerEntry { atom: "app-misc/bar".to_string(), holder: "app-misc/foo-1.0".to_string(), hard: false };
+        assert_eq!(soft.format(), "[blocks b      ] app-misc/bar (\"app-misc/bar\" is soft blocking app-misc/foo-1.0)");
+        let hard = BlockerEntry { atom: "<sys-apps/baz-2".to_string(), hard: true, ..soft.clone() };
+        assert_eq!(hard.format(), "[blocks B      ] <sys-apps/baz-2 (\"<sys-apps/baz-2\" is hard blocking app-misc/foo-1.0)");
+
+        let list = format_merge_list(&[entry("app-misc/foo-1.0", None)], &[soft], false, false);
+        assert!(list.contains("\n[blocks b      ] app-misc/bar (\"app-misc/bar\" is soft blocking app-misc/foo-1.0)\n[ebuild  N     ] app-misc/foo-1.0\n"));
+        assert!(list.ends_with("Total: 1 package (1 new)\nConflict: 1 block\n"));
+    }
+
+    #[test]
+    fn test_use_changes() {
+        let mut upgrade = entry("app-misc/foo-1.1", Some("1.0"));
+        upgrade.use_flags = vec![
+            ("acl".to_string(), true),
+            ("ssl".to_string(), true),
+            ("doc".to_string(), false),
+            ("zstd".to_string(), false),
+        ];
+        upgrade.built_use = Some(BuiltUse {
+            iuse: ["acl", "ssl", "doc", "gtk", "static"].iter().map(|f| f.to_string()).collect(),
+            enabled: ["acl", "gtk"].iter().map(|f| f.to_string()).collect(),
+        });
+        assert_eq!(upgrade.format(true), "[ebuild     U  ] app-misc/foo-1.1 [1.0] USE=\"acl ssl* -doc -zstd% (-gtk%*) (-static%)\"");
+        assert_eq!(upgrade.format(false), "[ebuild     U  ] app-misc/foo-1.1 [1.0] USE=\"ssl* -zstd% (-gtk%*) (-static%)\"");
+
+        let json = upgrade.to_json();
+        assert_eq!(json["action"], "upgrade");
+        assert_eq!(json["installed"], "1.0");
+        assert_eq!(json["use"][1], serde_json::json!({ "name": "ssl", "enabled": true, "changed": true, "new": false }));
+        assert_eq!(json["use"][3]["new"], true);
+        assert_eq!(json["removed_use"], serde_json::json!(["gtk", "static"]));
+
+        // Nothing changed: only --verbose lists the flags
+        upgrade.use_flags = vec![("acl".to_string(), true)];
+        upgrade.built_use = Some(BuiltUse { iuse: vec!["acl".to_string()], enabled: vec!["acl".to_string()] });
+        assert_eq!(upgrade.format(false), "[ebuild     U  ] app-misc/foo-1.1 [1.0]");
+    }
+
+    #[test]
+    fn test_use_expand_groups() {
+        let mut mesa = entry("media-libs/mesa-24.0", None);
+        mesa.use_expand = vec!["VIDEO_CARDS".to_string(), "LLVM_SLOT".to_string()];
+        mesa.use_flags = vec![
+            ("video_cards_nvidia".to_string(), false),
+            ("llvm_slot_18".to_string(), true),
+            ("video_cards_amdgpu".to_string(), true),
+            ("vulkan".to_string(), true),
+        ];
+        assert_eq!(mesa.format(true), "[ebuild  N     ] media-libs/mesa-24.0 USE=\"vulkan\" LLVM_SLOT=\"18\" VIDEO_CARDS=\"amdgpu -nvidia\"");
+
+        mesa.use_flags.retain(|(flag, _)| flag.starts_with("video_cards"));
+        assert_eq!(mesa.format(true), "[ebuild  N     ] media-libs/mesa-24.0 VIDEO_CARDS=\"amdgpu -nvidia\"");
+    }
+
+    #[test]
+    fn test_userquery() {
+        assert_eq!(userquery("Merge?", &mut "\n".as_bytes()), Some(true));
+        assert_eq!(userquery("Merge?", &mut "maybe\nN\n".as_bytes()), Some(false));
+        assert_eq!(userquery("Merge?", &mut "YES\n".as_bytes()), Some(true));
+        assert_eq!(userquery("Merge?", &mut "".as_bytes()), None);
+    }
+}
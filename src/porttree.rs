@@ -7,6 +7,8 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs as tokio_fs;
 use tokio::process::Command;
 
+use crate::metadata_cache::Md5Cache;
+
 #[derive(Debug)]
 pub struct PortTree {
     pub root: String,
@@ -31,6 +33,14 @@ pub struct Repository {
     pub auto_sync: bool,           // whether to sync automatically
     pub sync_depth: Option<i32>,   // git sync depth
     pub sync_hooks_only_on_change: bool, // optimization flag
+    pub sync_verify_metamanifest: bool, // verify Manifests after rsync
+    pub sync_openpgp_key_path: Option<String>, // keys for Manifest signatures
+    pub sync_interval: Option<u64>, // minimum seconds between syncs
+    pub sync_allow_hardlinks: bool, // rsync into a hardlinked copy until it is verified
+    pub sync_openpgp_key_refresh: bool, // refresh the signing keys before verifying
+    pub sync_openpgp_keyserver: Option<String>, // keyserver to refresh from instead of WKD
+    pub sync_submodules: Vec<String>, // submodule paths to sync, every one when empty
+    pub sync_squashfs_mount: bool, // mount squashfs snapshots instead of extracting them
     pub sync_metadata: SyncMetadata,
     pub eclass_cache: HashMap<String, String>,
     pub metadata_cache: HashMap<String, HashMap<String, String>>,
@@ -52,10 +62,11 @@ impl PortTree {
         ];
 
         for conf_path in &repos_conf_paths {
-            let path = Path::new(conf_path);
-            
+            // Under PORTAGE_CONFIGROOT
+            let path = Path::new(&self.root).join(conf_path.trim_start_matches('/'));
+
             if path.is_dir() {
-                if let Ok(entries) = fs::read_dir(path) {
+                if let Ok(entries) = fs::read_dir(&path) {
                     for entry in entries.flatten() {
                         let entry_path = entry.path();
                         if entry_path.is_file() && entry_path.extension().and_then(|s| s.to_str()) == Some("conf") {
@@ -66,7 +77,7 @@ impl PortTree {
                     }
                 }
             } else if path.is_file() {
-                if let Ok(content) = fs::read_to_string(conf_path) {
+                if let Ok(content) = fs::read_to_string(&path) {
                     self.parse_repos_conf(&content);
                 }
             }
@@ -82,6 +93,14 @@ impl PortTree {
                 auto_sync: true,
                 sync_depth: None,
                 sync_hooks_only_on_change: false,
+                sync_verify_metamanifest: false,
+                sync_openpgp_key_path: None,
+                sync_interval: None,
+                sync_allow_hardlinks: true,
+                sync_openpgp_key_refresh: true,
+                sync_openpgp_keyserver: None,
+                sync_submodules: Vec::new(),
+                sync_squashfs_mount: false,
                 sync_metadata: SyncMetadata {
                     last_sync: None,
                     last_attempt: None,
@@ -130,6 +149,14 @@ impl PortTree {
                     auto_sync: true,
                     sync_depth: None,
                     sync_hooks_only_on_change: false,
+                    sync_verify_metamanifest: false,
+                    sync_openpgp_key_path: None,
+                    sync_interval: None,
+                    sync_allow_hardlinks: true,
+                    sync_openpgp_key_refresh: true,
+                    sync_openpgp_keyserver: None,
+                    sync_submodules: Vec::new(),
+                    sync_squashfs_mount: false,
                     sync_metadata: SyncMetadata {
                         last_sync: None,
                         last_attempt: None,
@@ -166,6 +193,21 @@ impl PortTree {
                         "sync-hooks-only-on-change" => {
                             repo.sync_hooks_only_on_change = value.to_lowercase() == "true" || value == "yes";
                         }
+                        "sync-rsync-verify-metamanifest" => {
+                            repo.sync_verify_metamanifest = value.to_lowercase() == "true" || value == "yes";
+                        }
+                        "sync-openpgp-key-path" => repo.sync_openpgp_key_path = Some(value.to_string()),
+                        "sync-interval" => repo.sync_interval = parse_interval(value),
+                        "sync-allow-hardlinks" => {
+                            repo.sync_allow_hardlinks = value.to_lowercase() == "true" || value == "yes";
+                        }
+                        "sync-openpgp-key-refresh" => {
+                            repo.sync_openpgp_key_refresh = value.to_lowercase() == "true" || value == "yes";
+                        }
+                        "sync-openpgp-keyserver" => repo.sync_openpgp_keyserver = Some(value.to_string()),
+                        "sync-squashfs-mount" => {
+                            repo.sync_squashfs_mount = value.to_lowercase() == "true" || value == "yes";
+                        }
                         _ => {} // Ignore unknown keys
                     }
                 }
@@ -207,28 +249,13 @@ impl PortTree {
     }
 
     pub fn get_ebuild_path(&self, cpv: &str) -> Option<String> {
-        // Parse CPV to extract category/package/version
-        let parts: Vec<&str> = cpv.split('/').collect();
-        if parts.len() != 2 {
-            return None;
-        }
-
-        let category = parts[0];
-        let pkg_version = parts[1];
+        let cpv = crate::versions::Cpv::parse(cpv).ok()?;
 
-        // Find the last dash to separate package name from version
-        if let Some(last_dash) = pkg_version.rfind('-') {
-            let package = &pkg_version[..last_dash];
-            let version = &pkg_version[last_dash + 1..];
-
-            // Check each repository
-            for repo in self.repositories.values() {
-                let ebuild_path = format!("{}/{}/{}/{}-{}.ebuild",
-                    repo.location, category, package, package, version);
-
-                if std::path::Path::new(&ebuild_path).exists() {
-                    return Some(ebuild_path);
-                }
+        // Check each repository
+        for repo in self.repositories.values() {
+            let ebuild_path = format!("{}/{}/{}/{}.ebuild", repo.location, cpv.category(), cpv.package(), cpv.pf());
+            if std::path::Path::new(&ebuild_path).exists() {
+                return Some(ebuild_path);
             }
         }
 
@@ -243,6 +270,26 @@ impl PortTree {
             }
         }
 
+        // Next, the repository's md5-cache, regenerated only when its _md5_ or an eclass
+        // checksum no longer matches
+        if let Some(cache) = self.md5_cache_for(cpv) {
+            let key = cpv.to_string();
+            let metadata = tokio::task::spawn_blocking(move || {
+                if let Some(metadata) = cache.get(&key) {
+                    return Some(metadata);
+                }
+                let entry = cache.generate(&key).ok()?;
+                // Written back so it is valid next time; a read-only repository regenerates
+                let _ = cache.write(&key, &entry);
+                Some(entry.metadata)
+            }).await.ok().flatten();
+            if let Some(metadata) = metadata {
+                let meta: HashMap<String, String> = metadata.into_iter().collect();
+                self.cache_metadata(cpv, meta.clone());
+                return Some(meta);
+            }
+        }
+
         // Not in cache, try to load from ebuild
         if let Some(ebuild_path) = self.get_ebuild_path(cpv) {
             if let Ok(content) = tokio::fs::read_to_string(&ebuild_path).await {
@@ -269,6 +316,57 @@ impl PortTree {
         None
     }
 
+    /// Repository locations with the main repository first, then the rest by name
+    pub fn repository_locations(&self) -> Vec<std::path::PathBuf> {
+        let mut repos: Vec<&Repository> = self.repositories.values().collect();
+        repos.sort_by_key(|repo| (self.main_repo.as_deref() != Some(repo.name.as_str()), repo.name.clone()));
+        repos.into_iter().map(|repo| std::path::PathBuf::from(&repo.location)).collect()
+    }
+
+    /// Name of the repository whose location holds `path`, such as an ebuild
+    pub fn repository_name_of(&self, path: &Path) -> Option<&str> {
+        self.repositories.values()
+            .filter(|repo| path.starts_with(&repo.location))
+            .max_by_key(|repo| repo.location.len())
+            .map(|repo| repo.name.as_str())
+    }
+
+    /// Whether any repository has the package `cp`
+    pub fn has_package(&self, cp: &str) -> bool {
+        self.repositories.values().any(|repo| Path::new(&repo.location).join(cp).is_dir())
+    }
+
+    /// Names of a repository's masters from metadata/layout.conf
+    pub fn repo_masters(&self, repo_name: &str) -> Vec<String> {
+        let Some(repo) = self.repositories.get(repo_name) else {
+            return vec![];
+        };
+        let layout = fs::read_to_string(Path::new(&repo.location).join("metadata/layout.conf")).unwrap_or_default();
+        layout.lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(key, _)| key.trim() == "masters")
+            .map(|(_, value)| value.split_whitespace().map(|s| s.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The md5-cache of a repository, with its masters' eclass directories
+    pub fn md5_cache(&self, repo_name: &str) -> Option<Md5Cache> {
+        let repo = self.repositories.get(repo_name)?;
+        let master_dirs = self.repo_masters(repo_name).iter()
+            .filter_map(|master| self.repositories.get(master))
+            .map(|master| Path::new(&master.location).join("eclass"))
+            .collect();
+        Some(Md5Cache::new(Path::new(&repo.location)).with_master_eclass_dirs(master_dirs).confined())
+    }
+
+    /// The md5-cache of the repository providing `cpv`
+    fn md5_cache_for(&self, cpv: &str) -> Option<Md5Cache> {
+        let ebuild_path = self.get_ebuild_path(cpv)?;
+        let repo = self.repositories.values()
+            .find(|repo| Path::new(&ebuild_path).starts_with(&repo.location))?;
+        self.md5_cache(&repo.name)
+    }
+
     /// Cache metadata for a package
     pub fn cache_metadata(&mut self, cpv: &str, metadata: HashMap<String, String>) {
         // Find the repository that contains this package
@@ -328,16 +426,12 @@ impl PortTree {
                                                                 if let Ok(ebuild_entry) = ebuild_entry {
                                                                     if let Some(file_name) = ebuild_entry.path().file_name().and_then(|n| n.to_str()) {
                                                                         if file_name.ends_with(".ebuild") {
-                                                                            // Extract version from filename (package-version.ebuild)
-                                                                            if let Some(version_start) = file_name.find('-') {
-                                                                                if let Some(version_end) = file_name.rfind('.') {
-                                                                                    let version = &file_name[version_start + 1..version_end];
-                                                                                    let cpv = format!("{}/{}-{}", category_name, pkg_name, version);
-
-                                                                                    // Cache metadata for this CPV
-                                                                                     if let Some(_) = self.get_metadata(&cpv).await {
-                                                                                        // get_metadata will cache it automatically
-                                                                                    }
+                                                                            // The file name is already package-version.ebuild
+                                                                            let path = Path::new(category_name).join(pkg_name).join(file_name);
+                                                                            if let Ok(cpv) = crate::versions::Cpv::from_ebuild_path(&path) {
+                                                                                // Cache metadata for this CPV
+                                                                                if self.get_metadata(&cpv.to_string()).await.is_some() {
+                                                                                    // get_metadata will cache it automatically
                                                                                 }
                                                                             }
                                                                         }
@@ -361,12 +455,13 @@ impl PortTree {
         Ok(())
     }
 
-    /// Validate repository integrity
-    pub async fn validate_repository_integrity(&self, repo_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    /// Validate repository integrity, returning what the checks found worth telling
+    pub async fn validate_repository_integrity(&self, repo_name: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let repo = self.repositories.get(repo_name)
             .ok_or_else(|| format!("Repository {} not found", repo_name))?;
 
         let repo_path = Path::new(&repo.location);
+        let mut notes = Vec::new();
 
         // Check if repository exists
         if !repo_path.exists() {
@@ -393,6 +488,18 @@ impl PortTree {
             }
         }
 
+        // Full Manifest verification for rsync trees, as gemato would do; with
+        // sync-allow-hardlinks the backend verified the tree before swapping it in
+        let is_rsync = repo.sync_type.as_deref().unwrap_or("rsync") == "rsync";
+        if repo.sync_verify_metamanifest && is_rsync && !repo.sync_allow_hardlinks {
+            let key_path = repo.sync_openpgp_key_path.as_deref().unwrap_or(crate::manifest::DEFAULT_KEY_PATH);
+            let report = crate::manifest::ManifestVerifier::new(repo_path)
+                .with_key(Path::new(key_path))
+                .verify()
+                .await?;
+            notes.push(format!("verified {} files in {} Manifests", report.files, report.manifests));
+        }
+
         let is_main_repo = self.main_repo.as_ref().map(|m| m == repo_name).unwrap_or(false);
         
         if is_main_repo {
@@ -406,12 +513,11 @@ impl PortTree {
             }
             
             if !missing_dirs.is_empty() {
-                eprintln!("Warning: Main repository {} missing core directories: {}", 
-                    repo_name, missing_dirs.join(", "));
+                notes.push(format!("missing core directories: {}", missing_dirs.join(", ")));
             }
         }
 
-        Ok(())
+        Ok(notes)
     }
 
     /// Load sync metadata from disk
@@ -463,29 +569,45 @@ impl PortTree {
 
     /// Check if a repository needs syncing (based on auto-sync and time since last sync)
     pub fn needs_sync(&self, repo_name: &str) -> bool {
-        if let Some(repo) = self.repositories.get(repo_name) {
-            if !repo.auto_sync {
-                return false;
-            }
+        self.repositories.get(repo_name).is_some_and(|repo| repo.auto_sync && self.sync_due(repo_name))
+    }
 
-            // If never synced, needs sync
-            if repo.sync_metadata.last_sync.is_none() {
-                return true;
-            }
+    /// Whether a repository's sync-interval has passed since it last synced
+    pub fn sync_due(&self, repo_name: &str) -> bool {
+        let Some(repo) = self.repositories.get(repo_name) else {
+            return false;
+        };
+        let (Some(interval), Some(last_sync)) = (repo.sync_interval, repo.sync_metadata.last_sync) else {
+            return true;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        now.saturating_sub(last_sync) >= interval
+    }
+}
 
-            // Check if it's been more than 24 hours since last sync
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+/// Parse a repos.conf interval: seconds, or a number with an s, m, h or d suffix
+pub fn parse_interval(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 's') => (&value[..i], 1),
+        (i, 'm') => (&value[..i], 60),
+        (i, 'h') => (&value[..i], 3600),
+        (i, 'd') => (&value[..i], 86400),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok().map(|n| n * unit)
+}
 
-            if let Some(last_sync) = repo.sync_metadata.last_sync {
-                now.saturating_sub(last_sync) > 86400 // 24 hours in seconds
-            } else {
-                true
-            }
-        } else {
-            false
-        }
+/// An interval in the largest unit parse_interval reads it back from exactly
+pub fn format_interval(seconds: u64) -> String {
+    match seconds {
+        0 => "0s".to_string(),
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
     }
 }
\ No newline at end of file
@@ -0,0 +1,371 @@
+// preserved_libs.rs -- Keep shared libraries still linked by other packages (FEATURES=preserve-libs)
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::contents::ContentsEntry;
+use crate::exception::InvalidData;
+use crate::vartree::VarTree;
+
+pub const NEEDED_FILE: &str = "NEEDED.ELF.2";
+pub const PROVIDES_FILE: &str = "PROVIDES";
+pub const REQUIRES_FILE: &str = "REQUIRES";
+pub const REGISTRY_PATH: &str = "var/lib/portage/preserved_libs_registry.json";
+
+/// One line of NEEDED.ELF.2: arch;path;soname;rpath;needed;multilib category
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeededEntry {
+    pub arch: String,
+    pub path: String,
+    pub soname: String,
+    pub rpath: Vec<String>,
+    pub needed: Vec<String>,
+    pub multilib_category: String,
+}
+
+impl NeededEntry {
+    pub fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.trim_end().split(';').collect();
+        if fields.len() < 5 {
+            return None;
+        }
+        let list = |field: &str, sep: char| field.split(sep).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect();
+        Some(NeededEntry {
+            arch: fields[0].to_string(),
+            path: fields[1].to_string(),
+            soname: fields[2].to_string(),
+            rpath: list(fields[3], ':'),
+            needed: list(fields[4], ','),
+            multilib_category: fields.get(5).unwrap_or(&"").to_string(),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let line = format!("{};{};{};{};{}", self.arch, self.path, self.soname, self.rpath.join(":"), self.needed.join(","));
+        if self.multilib_category.is_empty() { line } else { format!("{};{}", line, self.multilib_category) }
+    }
+
+    /// Category used in PROVIDES/REQUIRES, falling back to the arch for unknown ABIs
+    fn category(&self) -> &str {
+        if self.multilib_category.is_empty() { &self.arch } else { &self.multilib_category }
+    }
+}
+
+/// PROVIDES contents: "category: soname ..." for every library of the package
+pub fn format_provides(entries: &[NeededEntry]) -> String {
+    let mut by_category: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for entry in entries.iter().filter(|e| !e.soname.is_empty()) {
+        by_category.entry(entry.category()).or_default().insert(&entry.soname);
+    }
+    format_soname_deps(&by_category)
+}
+
+/// REQUIRES contents: sonames linked against that the package doesn't provide itself
+pub fn format_requires(entries: &[NeededEntry]) -> String {
+    let provided: HashSet<(&str, &str)> = entries.iter()
+        .filter(|e| !e.soname.is_empty())
+        .map(|e| (e.category(), e.soname.as_str()))
+        .collect();
+    let mut by_category: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for entry in entries {
+        for soname in &entry.needed {
+            if !provided.contains(&(entry.category(), soname.as_str())) {
+                by_category.entry(entry.category()).or_default().insert(soname);
+            }
+        }
+    }
+    format_soname_deps(&by_category)
+}
+
+fn format_soname_deps(by_category: &BTreeMap<&str, BTreeSet<&str>>) -> String {
+    if by_category.is_empty() {
+        return String::new();
+    }
+    let groups: Vec<String> = by_category.iter()
+        .map(|(category, sonames)| format!("{}: {}", category, sonames.iter().copied().collect::<Vec<_>>().join(" ")))
+        .collect();
+    format!("{}\n", groups.join(" "))
+}
+
+/// Scan an image for ELF objects, recording paths as they will be merged
+pub fn generate_needed(image: &Path) -> Result<Vec<NeededEntry>, InvalidData> {
+    let mut entries = Vec::new();
+    for entry in crate::contents::generate_contents(image, image)? {
+        let ContentsEntry::Obj { path, .. } = entry else {
+            continue;
+        };
+        if let Some(info) = crate::elf::read_elf(&image.join(path.trim_start_matches('/'))) {
+            entries.push(NeededEntry {
+                arch: info.arch,
+                path,
+                soname: info.soname.unwrap_or_default(),
+                rpath: info.rpath,
+                needed: info.needed,
+                multilib_category: info.multilib_category,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// NEEDED.ELF.2 of an installed package
+pub fn read_needed(vartree: &VarTree, cpv: &str) -> Vec<NeededEntry> {
+    fs::read_to_string(Path::new(&vartree.dbpath).join(cpv).join(NEEDED_FILE))
+        .map(|content| content.lines().filter_map(NeededEntry::parse).collect())
+        .unwrap_or_default()
+}
+
+/// Libraries of `cpv` that other installed packages still link against and that
+/// nothing else provides, along with the symlinks pointing at them
+pub fn find_libs_to_preserve(vartree: &VarTree, cpv: &str) -> Vec<String> {
+    let mut provided = HashSet::new();
+    let mut wanted = HashSet::new();
+    for other in vartree.list_packages().iter().filter(|other| other.as_str() != cpv) {
+        for entry in read_needed(vartree, other) {
+            for soname in &entry.needed {
+                wanted.insert((entry.arch.clone(), soname.clone()));
+            }
+            if !entry.soname.is_empty() {
+                provided.insert((entry.arch, entry.soname));
+            }
+        }
+    }
+
+    let mut preserve: Vec<String> = read_needed(vartree, cpv).into_iter()
+        .filter(|entry| !entry.soname.is_empty())
+        .filter(|entry| {
+            let key = (entry.arch.clone(), entry.soname.clone());
+            wanted.contains(&key) && !provided.contains(&key)
+        })
+        .map(|entry| entry.path)
+        .collect();
+
+    // libfoo.so.1 -> libfoo.so.1.2.3 style links are needed to find the library by soname
+    let file_names: HashSet<String> = preserve.iter().filter_map(|p| p.rsplit('/').next()).map(|s| s.to_string()).collect();
+    for entry in vartree.contents(cpv) {
+        if let ContentsEntry::Sym { path, target, .. } = entry
+            && file_names.contains(target.rsplit('/').next().unwrap_or(&target))
+        {
+            preserve.push(path);
+        }
+    }
+    preserve.sort();
+    preserve
+}
+
+/// Libraries kept on disk after their package was unmerged, by former owner
+#[derive(Debug, Default)]
+pub struct PreservedLibsRegistry {
+    path: PathBuf,
+    pub entries: BTreeMap<String, Vec<String>>,
+}
+
+impl PreservedLibsRegistry {
+    pub fn load(root: &str) -> Result<Self, InvalidData> {
+        let path = Path::new(root).join(REGISTRY_PATH);
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| InvalidData::new(&format!("Failed to parse {}: {}", path.display(), e), None))?,
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(PreservedLibsRegistry { path, entries })
+    }
+
+    pub fn save(&self) -> Result<(), InvalidData> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+        }
+        let content = serde_json::to_string_pretty(&self.entries)
+            .map_err(|e| InvalidData::new(&format!("Failed to serialize preserved libs registry: {}", e), None))?;
+        fs::write(&self.path, content + "\n")
+            .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", self.path.display(), e), None))
+    }
+
+    pub fn register(&mut self, cpv: &str, paths: Vec<String>) {
+        if !paths.is_empty() {
+            self.entries.insert(cpv.to_string(), paths);
+        }
+    }
+
+    pub fn has_entries(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    /// (arch, soname) of every preserved library still on disk
+    fn preserved_sonames(&self, root: &str) -> HashSet<(String, String)> {
+        self.entries.values()
+            .flatten()
+            .filter_map(|path| crate::elf::read_elf(&Path::new(root).join(path.trim_start_matches('/'))))
+            .filter_map(|info| Some((info.arch, info.soname?)))
+            .collect()
+    }
+
+    /// Installed packages linking against a preserved library (@preserved-rebuild)
+    pub fn consumers(&self, vartree: &VarTree) -> Vec<String> {
+        let sonames = self.preserved_sonames(&vartree.root);
+        vartree.list_packages().into_iter()
+            .filter(|cpv| {
+                read_needed(vartree, cpv).iter().any(|entry| {
+                    entry.needed.iter().any(|soname| sonames.contains(&(entry.arch.clone(), soname.clone())))
+                })
+            })
+            .collect()
+    }
+
+    /// Drop libraries nobody links against any more, or that an installed package
+    /// owns again, deleting the unowned ones. Returns the paths removed from the registry.
+    pub fn prune(&mut self, vartree: &VarTree) -> Vec<String> {
+        let owned = vartree.file_owners();
+        let mut wanted = HashSet::new();
+        for cpv in vartree.list_packages() {
+            for entry in read_needed(vartree, &cpv) {
+                for soname in entry.needed {
+                    wanted.insert((entry.arch.clone(), soname));
+                }
+            }
+        }
+
+        let root = Path::new(&vartree.root);
+        let mut pruned = Vec::new();
+        for paths in self.entries.values_mut() {
+            // Libraries first, so their symlinks can follow
+            let mut kept_names = HashSet::new();
+            let mut drop = HashSet::new();
+            for path in paths.iter() {
+                let on_disk = root.join(path.trim_start_matches('/'));
+                if on_disk.is_symlink() {
+                    continue;
+                }
+                let still_wanted = crate::elf::read_elf(&on_disk)
+                    .and_then(|info| Some((info.arch, info.soname?)))
+                    .is_some_and(|key| wanted.contains(&key));
+                if still_wanted && !owned.contains_key(path) {
+                    kept_names.insert(path.rsplit('/').next().unwrap_or(path).to_string());
+                } else {
+                    drop.insert(path.clone());
+                }
+            }
+            for path in paths.iter() {
+                let on_disk = root.join(path.trim_start_matches('/'));
+                if let Ok(target) = fs::read_link(&on_disk) {
+                    let target = target.to_string_lossy().into_owned();
+                    if !kept_names.contains(target.rsplit('/').next().unwrap_or(&target)) {
+                        drop.insert(path.clone());
+                    }
+                }
+            }
+
+            paths.retain(|path| {
+                if !drop.contains(path) {
+                    return true;
+                }
+                if !owned.contains_key(path) {
+                    let _ = fs::remove_file(root.join(path.trim_start_matches('/')));
+                }
+                pruned.push(path.clone());
+                false
+            });
+        }
+        self.entries.retain(|_, paths| !paths.is_empty());
+        pruned.sort();
+        pruned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tests::build_elf64;
+    use tempfile::TempDir;
+
+    fn install(root: &Path, cpv: &str, contents: &str, needed: &[NeededEntry]) {
+        let dir = root.join("var/db/pkg").join(cpv);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("CONTENTS"), contents).unwrap();
+        let lines: String = needed.iter().map(|e| e.to_line() + "\n").collect();
+        fs::write(dir.join(NEEDED_FILE), lines).unwrap();
+    }
+
+    fn needed(path: &str, soname: &str, libs: &[&str]) -> NeededEntry {
+        NeededEntry {
+            arch: "X86_64".to_string(),
+            path: path.to_string(),
+            soname: soname.to_string(),
+            rpath: vec![],
+            needed: libs.iter().map(|s| s.to_string()).collect(),
+            multilib_category: "x86_64".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_needed_line_round_trip() {
+        let line = "X86_64;/usr/bin/foo;;/usr/lib/foo:/opt/lib;libc.so.6,libbar.so.2;x86_64";
+        let entry = NeededEntry::parse(line).unwrap();
+        assert_eq!(entry.multilib_category, "x86_64");
+        assert_eq!(entry.rpath, vec!["/usr/lib/foo", "/opt/lib"]);
+        assert_eq!(entry.needed, vec!["libc.so.6", "libbar.so.2"]);
+        assert_eq!(entry.to_line(), line);
+        assert!(NeededEntry::parse("X86_64;/usr/bin/foo").is_none());
+        assert_eq!(NeededEntry::parse("X86_64;/usr/bin/foo;;;libc.so.6").unwrap().multilib_category, "");
+    }
+
+    #[test]
+    fn test_generate_needed_and_soname_deps() {
+        let image = TempDir::new().unwrap();
+        fs::create_dir_all(image.path().join("usr/lib64")).unwrap();
+        fs::create_dir_all(image.path().join("usr/bin")).unwrap();
+        fs::write(image.path().join("usr/lib64/libfoo.so.1"), build_elf64(Some("libfoo.so.1"), &["libc.so.6"])).unwrap();
+        fs::write(image.path().join("usr/bin/foo"), build_elf64(None, &["libfoo.so.1", "libz.so.1"])).unwrap();
+        fs::write(image.path().join("usr/bin/foo-config"), "#!/bin/sh\n").unwrap();
+
+        let entries = generate_needed(image.path()).unwrap();
+        let lines: Vec<String> = entries.iter().map(|e| e.to_line()).collect();
+        assert_eq!(lines, vec![
+            "X86_64;/usr/bin/foo;;;libfoo.so.1,libz.so.1;x86_64",
+            "X86_64;/usr/lib64/libfoo.so.1;libfoo.so.1;;libc.so.6;x86_64",
+        ]);
+        assert_eq!(format_provides(&entries), "x86_64: libfoo.so.1\n");
+        assert_eq!(format_requires(&entries), "x86_64: libc.so.6 libz.so.1\n");
+        assert_eq!(format_provides(&[]), "");
+    }
+
+    #[test]
+    fn test_preserve_and_prune() {
+        let root = TempDir::new().unwrap();
+        let lib = root.path().join("usr/lib64");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(lib.join("libfoo.so.1.0"), build_elf64(Some("libfoo.so.1"), &["libc.so.6"])).unwrap();
+        std::os::unix::fs::symlink("libfoo.so.1.0", lib.join("libfoo.so.1")).unwrap();
+
+        install(root.path(), "dev-libs/foo-1.0",
+            "obj /usr/lib64/libfoo.so.1.0 d41d8cd98f00b204e9800998ecf8427e 1\nsym /usr/lib64/libfoo.so.1 -> libfoo.so.1.0 1\n",
+            &[needed("/usr/lib64/libfoo.so.1.0", "libfoo.so.1", &["libc.so.6"])]);
+        install(root.path(), "app-misc/bar-1.0", "obj /usr/bin/bar d41d8cd98f00b204e9800998ecf8427e 1\n",
+            &[needed("/usr/bin/bar", "", &["libfoo.so.1", "libc.so.6"])]);
+
+        let vartree = VarTree::new(root.path().to_str().unwrap());
+        let preserve = find_libs_to_preserve(&vartree, "dev-libs/foo-1.0");
+        assert_eq!(preserve, vec!["/usr/lib64/libfoo.so.1", "/usr/lib64/libfoo.so.1.0"]);
+
+        // Unmerge foo, keeping the library for bar
+        fs::remove_dir_all(root.path().join("var/db/pkg/dev-libs/foo-1.0")).unwrap();
+        vartree.invalidate("dev-libs/foo-1.0");
+        let mut registry = PreservedLibsRegistry::load(&vartree.root).unwrap();
+        registry.register("dev-libs/foo-1.0", preserve);
+        registry.save().unwrap();
+
+        let mut registry = PreservedLibsRegistry::load(&vartree.root).unwrap();
+        assert_eq!(registry.consumers(&vartree), vec!["app-misc/bar-1.0"]);
+        assert!(registry.prune(&vartree).is_empty());
+
+        // bar rebuilt against the new soname: the old library can go
+        install(root.path(), "app-misc/bar-1.0", "obj /usr/bin/bar d41d8cd98f00b204e9800998ecf8427e 1\n",
+            &[needed("/usr/bin/bar", "", &["libfoo.so.2", "libc.so.6"])]);
+        assert_eq!(registry.prune(&vartree).len(), 2);
+        assert!(!registry.has_entries());
+        assert!(!lib.join("libfoo.so.1.0").exists());
+        assert!(lib.join("libfoo.so.1").symlink_metadata().is_err());
+    }
+}
@@ -12,6 +12,18 @@ pub struct Profile {
     pub parent_profiles: Vec<Profile>,
 }
 
+impl Profile {
+    /// This profile and all its ancestors, parents first (the order settings stack in)
+    pub fn stack(&self) -> Vec<&Profile> {
+        let mut stack = Vec::new();
+        for parent in &self.parent_profiles {
+            stack.extend(parent.stack());
+        }
+        stack.push(self);
+        stack
+    }
+}
+
 /// Profile settings loaded from various profile files
 #[derive(Debug, Clone, Default)]
 pub struct ProfileSettings {
@@ -31,6 +43,8 @@ pub struct ProfileSettings {
     pub use_mask: HashSet<String>,
     /// USE flag forces from use.force
     pub use_force: HashSet<String>,
+    /// Default providers of old-style virtuals from the virtuals file, preferred first
+    pub virtuals: HashMap<String, Vec<String>>,
 }
 
 /// Gentoo profile manager
@@ -206,6 +220,11 @@ impl ProfileManager {
             settings.use_force.extend(use_force);
         }
 
+        // Load virtuals ("virtual/editor app-editors/nano")
+        if let Ok(virtuals) = self.parse_package_flags_file(profile_path, "virtuals").await {
+            settings.virtuals.extend(virtuals);
+        }
+
         Ok(settings)
     }
 
@@ -428,6 +447,14 @@ impl ProfileManager {
         // Merge USE masks/forces
         target.use_mask.extend(source.use_mask.clone());
         target.use_force.extend(source.use_force.clone());
+
+        // Providers from the more specific profile are preferred over inherited ones
+        for (virtual_cp, providers) in &source.virtuals {
+            let inherited = target.virtuals.remove(virtual_cp).unwrap_or_default();
+            let mut merged = providers.clone();
+            merged.extend(inherited.into_iter().filter(|p| !providers.contains(p)));
+            target.virtuals.insert(virtual_cp.clone(), merged);
+        }
     }
 
     /// List all available profiles
@@ -644,6 +671,25 @@ ACCEPT_LICENSE="-* @FREE"
         assert!(settings.system_packages.contains("app-admin/sudo"));
     }
 
+    #[tokio::test]
+    async fn test_virtuals() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let (parent, child) = (temp_dir.path().join("base"), temp_dir.path().join("desktop"));
+        fs::create_dir_all(&parent).unwrap();
+        fs::create_dir_all(&child).unwrap();
+        fs::write(parent.join("virtuals"), "# default providers\nvirtual/editor app-editors/nano\nvirtual/mta mail-mta/nullmailer\n").unwrap();
+        fs::write(child.join("virtuals"), "virtual/editor app-editors/vim app-editors/nano\n").unwrap();
+
+        let manager = ProfileManager::new("/");
+        let mut settings = ProfileSettings::default();
+        for dir in [&parent, &child] {
+            let layer = manager.load_single_profile_settings(dir).await.unwrap();
+            manager.merge_settings(&mut settings, &layer);
+        }
+        assert_eq!(settings.virtuals["virtual/editor"], vec!["app-editors/vim", "app-editors/nano"]);
+        assert_eq!(settings.virtuals["virtual/mta"], vec!["mail-mta/nullmailer"]);
+    }
+
     #[tokio::test]
     async fn test_merge_settings() {
         let mut target = ProfileSettings {
@@ -0,0 +1,251 @@
+// progress.rs -- terminal progress for merges: a status line with the merge list position
+// and the phase and elapsed time of each running build, and the build-times log its ETAs
+// are estimated from
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Durations of finished source builds, one "<timestamp> <cpv> <seconds>" line each
+pub const BUILD_TIMES: &str = "var/log/emerge-rs/build-times";
+
+/// How many of the latest builds of a package its estimate averages
+const ESTIMATE_BUILDS: usize = 3;
+
+static BUILDS: Mutex<Vec<Build>> = Mutex::new(Vec::new());
+/// Whether the status line is on the terminal and has to be cleared before other output
+static DRAWN: AtomicBool = AtomicBool::new(false);
+
+/// A build in progress
+struct Build {
+    cpv: String,
+    position: usize,
+    total: usize,
+    phase: Option<String>,
+    started: Instant,
+    estimate: Option<Duration>,
+}
+
+fn builds() -> std::sync::MutexGuard<'static, Vec<Build>> {
+    BUILDS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The build-times log under `root`
+pub fn build_times_path(root: &str) -> PathBuf {
+    Path::new(root).join(BUILD_TIMES)
+}
+
+/// Recorded build durations, by package
+#[derive(Debug, Default)]
+pub struct BuildTimes {
+    durations: HashMap<String, Vec<Duration>>,
+}
+
+impl BuildTimes {
+    pub fn parse(content: &str) -> Self {
+        let mut times = BuildTimes::default();
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(_), Some(cpv), Some(seconds)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let (Some(cp), Ok(seconds)) = (crate::versions::cpv_getkey(cpv), seconds.parse::<u64>()) else {
+                continue;
+            };
+            times.durations.entry(cp).or_default().push(Duration::from_secs(seconds));
+        }
+        times
+    }
+
+    /// The log at `path`; empty when there is none yet
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path).map(|content| Self::parse(&content)).unwrap_or_default()
+    }
+
+    /// How long `cpv` can be expected to build: the average of the latest builds of any
+    /// version of the package
+    pub fn estimate(&self, cpv: &str) -> Option<Duration> {
+        let durations = self.durations.get(&crate::versions::cpv_getkey(cpv)?)?;
+        let latest = &durations[durations.len().saturating_sub(ESTIMATE_BUILDS)..];
+        Some(latest.iter().sum::<Duration>() / latest.len() as u32)
+    }
+
+    /// Append a build of `cpv` that took `duration` to the log at `path`
+    pub fn record(path: &Path, cpv: &str, duration: Duration) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut log = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(log, "{} {} {}", chrono::Utc::now().timestamp(), cpv, duration.as_secs())
+    }
+}
+
+/// A duration for humans: "45s", "3m12s", "1h05m"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// A bar `width` characters wide, `done` of `total` filled: "[=====>    ]"
+pub fn bar(done: usize, total: usize, width: usize) -> String {
+    let filled = (done * width).checked_div(total).map_or(width, |filled| filled.min(width));
+    let mut bar = "=".repeat(filled);
+    if filled < width {
+        bar.push('>');
+        bar.push_str(&" ".repeat(width - filled - 1));
+    }
+    format!("[{}]", bar)
+}
+
+/// A build of `cpv`, `position` of `total` in the merge list, has started
+pub fn begin(cpv: &str, position: usize, total: usize, estimate: Option<Duration>) {
+    builds().push(Build { cpv: cpv.to_string(), position, total, phase: None, started: Instant::now(), estimate });
+}
+
+/// The build of `cpv` has entered `phase`
+pub fn set_phase(cpv: &str, phase: &str) {
+    if let Some(build) = builds().iter_mut().find(|b| b.cpv == cpv) {
+        build.phase = Some(phase.to_string());
+    }
+}
+
+/// Where the build of `cpv` is in the merge list: (position, total)
+pub fn position(cpv: &str) -> Option<(usize, usize)> {
+    builds().iter().find(|b| b.cpv == cpv).map(|b| (b.position, b.total))
+}
+
+/// The build of `cpv` is over; how long it ran
+pub fn end(cpv: &str) -> Option<Duration> {
+    let mut builds = builds();
+    let index = builds.iter().position(|b| b.cpv == cpv)?;
+    Some(builds.remove(index).started.elapsed())
+}
+
+/// The status line for the running builds with `done` of the merge list finished, e.g.
+/// "[===>      ] 3 of 10  app-misc/foo-1.0 compile 3m12s (~1m05s left)"
+pub fn status(done: usize) -> Option<String> {
+    format_status(&builds(), done)
+}
+
+fn format_status(builds: &[Build], done: usize) -> Option<String> {
+    let total = builds.first()?.total;
+    let running: Vec<String> = builds.iter()
+        .map(|build| {
+            let elapsed = build.started.elapsed();
+            let mut entry = format!("({}) {}", build.position, build.cpv);
+            if let Some(phase) = &build.phase {
+                entry.push_str(&format!(" {}", phase));
+            }
+            entry.push_str(&format!(" {}", format_duration(elapsed)));
+            if let Some(left) = build.estimate.and_then(|estimate| estimate.checked_sub(elapsed)) {
+                entry.push_str(&format!(" (~{} left)", format_duration(left)));
+            }
+            entry
+        })
+        .collect();
+    Some(format!("{} {} of {}  {}", bar(done, total, 20), done, total, running.join(", ")))
+}
+
+/// Whether the status line is shown: only on a terminal that can redraw a line
+pub fn enabled() -> bool {
+    std::io::IsTerminal::is_terminal(&std::io::stderr()) && std::env::var("TERM").is_ok_and(|term| term != "dumb")
+}
+
+fn terminal_width() -> usize {
+    // SAFETY: TIOCGWINSZ only fills in the winsize it is given
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+/// Redraw the status line in place on stderr; it is cut to the terminal width so that it
+/// never wraps
+pub fn draw(done: usize) {
+    if !enabled() {
+        return;
+    }
+    let Some(line) = status(done) else {
+        return;
+    };
+    let line: String = line.chars().take(terminal_width().saturating_sub(1)).collect();
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(stderr, "\r\x1b[K{}\r", line);
+    let _ = stderr.flush();
+    DRAWN.store(true, Ordering::SeqCst);
+}
+
+/// Erase the status line, if drawn, so that other output starts on a clean line
+pub fn clear() {
+    if DRAWN.swap(false, Ordering::SeqCst) {
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K");
+        let _ = stderr.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_build_times() {
+        let temp = TempDir::new().unwrap();
+        let path = build_times_path(temp.path().to_str().unwrap());
+        assert!(BuildTimes::load(&path).estimate("app-misc/foo-1.0").is_none());
+
+        for seconds in [600, 100, 200, 300] {
+            BuildTimes::record(&path, "app-misc/foo-1.0", Duration::from_secs(seconds)).unwrap();
+        }
+        BuildTimes::record(&path, "dev-libs/bar-2", Duration::from_secs(42)).unwrap();
+        let times = BuildTimes::load(&path);
+        // The latest three builds, of any version
+        assert_eq!(times.estimate("app-misc/foo-1.1"), Some(Duration::from_secs(200)));
+        assert_eq!(times.estimate("dev-libs/bar-2"), Some(Duration::from_secs(42)));
+        assert_eq!(times.estimate("dev-libs/baz-1"), None);
+        assert!(BuildTimes::parse("garbage\n1700000000 app-misc/foo-1.0 soon\n").durations.is_empty());
+    }
+
+    #[test]
+    fn test_status_line() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(192)), "3m12s");
+        assert_eq!(format_duration(Duration::from_secs(3900)), "1h05m");
+        assert_eq!(bar(0, 4, 8), "[>       ]");
+        assert_eq!(bar(2, 4, 8), "[====>   ]");
+        assert_eq!(bar(4, 4, 8), "[========]");
+
+        let build = |cpv: &str, position, phase: Option<&str>, estimate| Build {
+            cpv: cpv.to_string(),
+            position,
+            total: 10,
+            phase: phase.map(|p| p.to_string()),
+            started: Instant::now(),
+            estimate,
+        };
+        let running = [
+            build("app-misc/foo-1.0", 3, Some("compile"), Some(Duration::from_secs(3600))),
+            build("dev-libs/bar-2", 4, None, None),
+        ];
+        let line = format_status(&running, 2).unwrap();
+        assert!(line.starts_with("[====>               ] 2 of 10  (3) app-misc/foo-1.0 compile 0s (~"), "{}", line);
+        assert!(line.ends_with(" left), (4) dev-libs/bar-2 0s"), "{}", line);
+        assert!(format_status(&[], 0).is_none());
+
+        begin("app-misc/progress-test-1.0", 1, 1, None);
+        assert_eq!(position("app-misc/progress-test-1.0"), Some((1, 1)));
+        set_phase("app-misc/progress-test-1.0", "compile");
+        assert!(end("app-misc/progress-test-1.0").is_some());
+        assert!(end("app-misc/progress-test-1.0").is_none());
+    }
+}
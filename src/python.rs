@@ -0,0 +1,309 @@
+// python.rs -- built-in python-r1, python-single-r1 and distutils-r1 for the phase executor:
+// PYTHON_TARGETS selection and per-implementation build, install and byte-compiling
+
+use std::collections::HashMap;
+
+/// Eclasses the executor provides itself instead of sourcing the repository's
+pub const ECLASSES: &[&str] = &["python-utils-r1", "python-r1", "python-single-r1", "distutils-r1"];
+
+/// A Python implementation as PYTHON_TARGETS names it, e.g. python3_12 or pypy3_11
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PythonImpl(String);
+
+impl PythonImpl {
+    pub fn new(name: &str) -> Option<Self> {
+        (name.starts_with("python") || name.starts_with("pypy")).then(|| PythonImpl(name.to_string()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// The interpreter, EPYTHON: python3_12 runs as python3.12
+    pub fn epython(&self) -> String {
+        self.0.replacen('_', ".", 1)
+    }
+
+    /// Where the implementation's packages are installed
+    pub fn sitedir(&self) -> String {
+        format!("/usr/lib/{}/site-packages", self.epython())
+    }
+}
+
+/// Implementations enabled by USE: python_single_target_* for single-impl packages,
+/// python_targets_* otherwise
+pub fn selected_impls(use_flags: &HashMap<String, bool>, single: bool) -> Vec<PythonImpl> {
+    let prefix = if single { "python_single_target_" } else { "python_targets_" };
+    let mut impls: Vec<PythonImpl> = use_flags.iter()
+        .filter(|&(_, &enabled)| enabled)
+        .filter_map(|(flag, _)| flag.strip_prefix(prefix).and_then(PythonImpl::new))
+        .collect();
+    impls.sort();
+    impls
+}
+
+/// Environment the built-in eclasses read: PYTHON_IMPLS, the enabled implementations
+/// python_foreach_impl and python_setup pick from (narrowed to PYTHON_COMPAT in bash)
+pub fn env_vars(use_flags: &HashMap<String, bool>, single: bool) -> HashMap<String, String> {
+    let impls: Vec<String> = selected_impls(use_flags, single).iter().map(|i| i.name().to_string()).collect();
+    HashMap::from([("PYTHON_IMPLS".to_string(), impls.join(" "))])
+}
+
+/// Source of a built-in eclass
+pub fn eclass_source(name: &str) -> Option<&'static str> {
+    match name {
+        "python-utils-r1" => Some(PYTHON_UTILS_R1),
+        "python-r1" => Some(PYTHON_R1),
+        "python-single-r1" => Some(PYTHON_SINGLE_R1),
+        "distutils-r1" => Some(DISTUTILS_R1),
+        _ => None,
+    }
+}
+
+const PYTHON_UTILS_R1: &str = r#"
+_python_impls() {
+	local impl
+	for impl in ${PYTHON_IMPLS}; do
+		if [[ ${#PYTHON_COMPAT[@]} -eq 0 ]] || has "${impl}" "${PYTHON_COMPAT[@]}"; then
+			echo "${impl}"
+		fi
+	done
+}
+
+_python_set_impl() {
+	EPYTHON=${1/_/.}
+	PYTHON=/usr/bin/${EPYTHON}
+	command -v "${EPYTHON}" >/dev/null && PYTHON=$(command -v "${EPYTHON}")
+	export EPYTHON PYTHON
+}
+
+python_get_sitedir() {
+	echo "/usr/lib/${EPYTHON}/site-packages"
+}
+
+python_optimize() {
+	local dirs=( "$@" )
+	[[ ${#dirs[@]} -eq 0 ]] && dirs=( "${D}$(python_get_sitedir)" )
+	local d
+	for d in "${dirs[@]}"; do
+		[[ -d ${d} ]] || continue
+		"${PYTHON}" -m compileall -q -f -o 0 -o 1 -o 2 -s "${D}" -p / "${d}" || die "Byte-compiling ${d} failed"
+	done
+}
+
+python_setup() {
+	local impl best
+	for impl in $(_python_impls); do
+		best=${impl}
+	done
+	[[ -n ${best} ]] || die "No Python implementation enabled in PYTHON_TARGETS or PYTHON_SINGLE_TARGET"
+	_python_set_impl "${best}"
+}
+
+python_foreach_impl() {
+	local impl found
+	for impl in $(_python_impls); do
+		found=1
+		(
+			_python_set_impl "${impl}"
+			BUILD_DIR=${WORKDIR}/${P}-${impl}
+			export BUILD_DIR
+			mkdir -p "${BUILD_DIR}"
+			"$@"
+		) || die "$* failed for ${impl}"
+	done
+	[[ -n ${found} ]] || die "No Python implementation enabled in PYTHON_TARGETS"
+}
+"#;
+
+const PYTHON_R1: &str = r#"
+inherit python-utils-r1
+"#;
+
+const PYTHON_SINGLE_R1: &str = r#"
+inherit python-utils-r1
+
+python-single-r1_pkg_setup() {
+	python_setup
+}
+
+EXPORT_FUNCTIONS pkg_setup
+"#;
+
+const DISTUTILS_R1: &str = r#"
+inherit python-utils-r1
+
+_distutils_pep517() {
+	[[ -n ${DISTUTILS_USE_PEP517} && ${DISTUTILS_USE_PEP517} != no ]] && return 0
+	[[ -f ${S}/pyproject.toml && ! -f ${S}/setup.py ]]
+}
+
+_distutils_sub() {
+	cd "${S}" || die
+	if declare -F "python_$1" >/dev/null; then
+		"python_$1"
+	else
+		"distutils-r1_python_$1"
+	fi
+}
+
+_distutils_all() {
+	cd "${S}" || die
+	if declare -F "python_$1_all" >/dev/null; then
+		"python_$1_all"
+	else
+		"distutils-r1_python_$1_all"
+	fi
+}
+
+_distutils_foreach() {
+	if [[ -n ${DISTUTILS_SINGLE_IMPL} ]]; then
+		python_setup
+		BUILD_DIR=${WORKDIR}/${P}-${EPYTHON/./_}
+		mkdir -p "${BUILD_DIR}"
+		_distutils_sub "$1"
+	else
+		python_foreach_impl _distutils_sub "$1"
+	fi
+}
+
+distutils-r1_python_prepare_all() {
+	local patch
+	for patch in "${PATCHES[@]}"; do
+		patch -p1 < "${patch}" || die "Applying ${patch} failed"
+	done
+}
+
+distutils-r1_python_configure() {
+	:
+}
+
+distutils-r1_python_configure_all() {
+	:
+}
+
+distutils-r1_python_compile() {
+	if _distutils_pep517; then
+		local wheeldir=${BUILD_DIR}/wheel
+		mkdir -p "${wheeldir}"
+		if "${PYTHON}" -c 'import gpep517' 2>/dev/null; then
+			"${PYTHON}" -m gpep517 build-wheel --prefix=/usr --output-fd 3 --wheel-dir "${wheeldir}" 3>/dev/null || die "Building the wheel failed"
+		else
+			"${PYTHON}" -m pip wheel --no-deps --no-build-isolation --wheel-dir "${wheeldir}" . || die "Building the wheel failed"
+		fi
+	else
+		"${PYTHON}" setup.py build --build-base "${BUILD_DIR}/build" || die "setup.py build failed"
+	fi
+}
+
+distutils-r1_python_compile_all() {
+	:
+}
+
+distutils-r1_python_test() {
+	if "${PYTHON}" -c 'import pytest' 2>/dev/null; then
+		"${PYTHON}" -m pytest -vv || die "Tests fail with ${EPYTHON}"
+	else
+		"${PYTHON}" -m unittest discover -v || die "Tests fail with ${EPYTHON}"
+	fi
+}
+
+distutils-r1_python_test_all() {
+	:
+}
+
+distutils-r1_python_install() {
+	if _distutils_pep517; then
+		local wheel
+		for wheel in "${BUILD_DIR}"/wheel/*.whl; do
+			if "${PYTHON}" -c 'import gpep517' 2>/dev/null; then
+				"${PYTHON}" -m gpep517 install-wheel --destdir="${D}" --interpreter="${PYTHON}" --prefix=/usr "${wheel}" || die "Installing ${wheel} failed"
+			else
+				"${PYTHON}" -m installer --destdir="${D}" --prefix=/usr --no-compile-bytecode "${wheel}" || die "Installing ${wheel} failed"
+			fi
+		done
+	else
+		"${PYTHON}" setup.py build --build-base "${BUILD_DIR}/build" install --root="${D}" --prefix=/usr --skip-build || die "setup.py install failed"
+	fi
+	python_optimize
+}
+
+distutils-r1_python_install_all() {
+	local doc
+	for doc in README* CHANGELOG* ChangeLog* NEWS* AUTHORS*; do
+		[[ -f ${doc} ]] && dodoc "${doc}"
+	done
+	true
+}
+
+distutils-r1_src_prepare() {
+	_distutils_all prepare
+}
+
+distutils-r1_src_configure() {
+	_distutils_foreach configure
+	_distutils_all configure
+}
+
+distutils-r1_src_compile() {
+	_distutils_foreach compile
+	_distutils_all compile
+}
+
+distutils-r1_src_test() {
+	_distutils_foreach test
+	_distutils_all test
+}
+
+distutils-r1_src_install() {
+	_distutils_foreach install
+	_distutils_all install
+}
+
+EXPORT_FUNCTIONS src_prepare src_configure src_compile src_test src_install
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_selected_impls() {
+        let use_flags = HashMap::from([
+            ("python_targets_python3_13".to_string(), true),
+            ("python_targets_python3_12".to_string(), true),
+            ("python_targets_pypy3_11".to_string(), false),
+            ("python_single_target_python3_12".to_string(), true),
+            ("ssl".to_string(), true),
+        ]);
+        let impls = selected_impls(&use_flags, false);
+        assert_eq!(impls.iter().map(|i| i.name()).collect::<Vec<_>>(), vec!["python3_12", "python3_13"]);
+        assert_eq!(impls[0].epython(), "python3.12");
+        assert_eq!(impls[0].sitedir(), "/usr/lib/python3.12/site-packages");
+        assert_eq!(PythonImpl::new("pypy3_11").unwrap().epython(), "pypy3.11");
+        assert_eq!(env_vars(&use_flags, true)["PYTHON_IMPLS"], "python3_12");
+    }
+
+    #[test]
+    fn test_foreach_impl() {
+        // PYTHON_COMPAT narrows what is enabled; each run gets its own EPYTHON and BUILD_DIR
+        let temp = tempfile::TempDir::new().unwrap();
+        let script = format!(
+            "die() {{ echo \"$*\" >&2; exit 1; }}\nhas() {{ local n=$1; shift; local x; for x; do [[ $x == \"$n\" ]] && return 0; done; return 1; }}\ninherit() {{ :; }}\n{}\n\
+             PYTHON_COMPAT=( python3_{{11..12}} )\nshow() {{ echo \"${{EPYTHON}} $(python_get_sitedir) ${{BUILD_DIR##*/}}\"; }}\npython_foreach_impl show\npython_setup\necho \"setup ${{EPYTHON}}\"\n",
+            PYTHON_UTILS_R1);
+        let output = Command::new("bash")
+            .args(["-c", &script])
+            .env("PYTHON_IMPLS", "python3_11 python3_12 python3_13")
+            .env("WORKDIR", temp.path())
+            .env("P", "foo-1.0")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout),
+            "python3.11 /usr/lib/python3.11/site-packages foo-1.0-python3_11\n\
+             python3.12 /usr/lib/python3.12/site-packages foo-1.0-python3_12\n\
+             setup python3.12\n");
+    }
+}
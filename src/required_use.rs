@@ -0,0 +1,279 @@
+// required_use.rs -- REQUIRED_USE parsing and evaluation
+
+use std::collections::HashMap;
+
+use crate::exception::InvalidData;
+
+/// A node of a REQUIRED_USE expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequiredUse {
+    /// "flag" or "!flag"
+    Flag { name: String, enabled: bool },
+    /// "( ... )", and the top level
+    AllOf(Vec<RequiredUse>),
+    /// "|| ( ... )"
+    AnyOf(Vec<RequiredUse>),
+    /// "^^ ( ... )"
+    ExactlyOne(Vec<RequiredUse>),
+    /// "?? ( ... )"
+    AtMostOne(Vec<RequiredUse>),
+    /// "flag? ( ... )" or "!flag? ( ... )"
+    Conditional { name: String, enabled: bool, children: Vec<RequiredUse> },
+}
+
+impl RequiredUse {
+    /// Parse a REQUIRED_USE string into a top-level AllOf
+    pub fn parse(expr: &str) -> Result<Self, InvalidData> {
+        let tokens: Vec<&str> = expr.split_whitespace().collect();
+        let mut pos = 0;
+        let children = Self::parse_list(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(InvalidData::new(&format!("Unbalanced ')' in REQUIRED_USE: {}", expr), None));
+        }
+        Ok(RequiredUse::AllOf(children))
+    }
+
+    fn parse_list(tokens: &[&str], pos: &mut usize) -> Result<Vec<RequiredUse>, InvalidData> {
+        let mut nodes = Vec::new();
+        while *pos < tokens.len() {
+            let token = tokens[*pos];
+            if token == ")" {
+                break;
+            }
+            *pos += 1;
+            let node = match token {
+                "(" => RequiredUse::AllOf(Self::parse_group(tokens, pos)?),
+                "||" | "^^" | "??" => {
+                    Self::expect_open(tokens, pos, token)?;
+                    let children = Self::parse_group(tokens, pos)?;
+                    match token {
+                        "||" => RequiredUse::AnyOf(children),
+                        "^^" => RequiredUse::ExactlyOne(children),
+                        _ => RequiredUse::AtMostOne(children),
+                    }
+                }
+                _ if token.ends_with('?') => {
+                    Self::expect_open(tokens, pos, token)?;
+                    let flag = &token[..token.len() - 1];
+                    let (name, enabled) = Self::split_flag(flag)?;
+                    RequiredUse::Conditional { name, enabled, children: Self::parse_group(tokens, pos)? }
+                }
+                _ => {
+                    let (name, enabled) = Self::split_flag(token)?;
+                    RequiredUse::Flag { name, enabled }
+                }
+            };
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    fn expect_open(tokens: &[&str], pos: &mut usize, after: &str) -> Result<(), InvalidData> {
+        if tokens.get(*pos) != Some(&"(") {
+            return Err(InvalidData::new(&format!("Expected '(' after '{}' in REQUIRED_USE", after), None));
+        }
+        *pos += 1;
+        Ok(())
+    }
+
+    /// Parse children up to and including the closing ")"
+    fn parse_group(tokens: &[&str], pos: &mut usize) -> Result<Vec<RequiredUse>, InvalidData> {
+        let children = Self::parse_list(tokens, pos)?;
+        if tokens.get(*pos) != Some(&")") {
+            return Err(InvalidData::new("Missing ')' in REQUIRED_USE", None));
+        }
+        *pos += 1;
+        Ok(children)
+    }
+
+    fn split_flag(token: &str) -> Result<(String, bool), InvalidData> {
+        let (name, enabled) = match token.strip_prefix('!') {
+            Some(name) => (name, false),
+            None => (token, true),
+        };
+        if name.is_empty() || name.contains(['(', ')', '?', '!']) {
+            return Err(InvalidData::new(&format!("Invalid flag '{}' in REQUIRED_USE", token), None));
+        }
+        Ok((name.to_string(), enabled))
+    }
+
+    pub fn is_satisfied(&self, flags: &HashMap<String, bool>) -> bool {
+        let count = |children: &[RequiredUse]| children.iter().filter(|c| c.is_satisfied(flags)).count();
+        match self {
+            RequiredUse::Flag { name, enabled } => flag_state(flags, name) == *enabled,
+            RequiredUse::AllOf(children) => children.iter().all(|c| c.is_satisfied(flags)),
+            RequiredUse::AnyOf(children) => children.is_empty() || count(children) > 0,
+            RequiredUse::ExactlyOne(children) => children.is_empty() || count(children) == 1,
+            RequiredUse::AtMostOne(children) => count(children) <= 1,
+            RequiredUse::Conditional { name, enabled, children } => {
+                flag_state(flags, name) != *enabled || children.iter().all(|c| c.is_satisfied(flags))
+            }
+        }
+    }
+
+    /// The constraints that fail, looking inside plain groups
+    pub fn violations(&self, flags: &HashMap<String, bool>) -> Vec<&RequiredUse> {
+        match self {
+            RequiredUse::AllOf(children) => children.iter().flat_map(|c| c.violations(flags)).collect(),
+            _ if self.is_satisfied(flags) => vec![],
+            _ => vec![self],
+        }
+    }
+
+    /// Alternative sets of flag changes ("flag" / "-flag") that would satisfy this node
+    pub fn suggestions(&self, flags: &HashMap<String, bool>) -> Vec<Vec<String>> {
+        if self.is_satisfied(flags) {
+            return vec![vec![]];
+        }
+        match self {
+            RequiredUse::Flag { name, enabled } => vec![vec![change(name, *enabled)]],
+            RequiredUse::AllOf(children) => {
+                // Combine the first alternative of every failing child
+                let combined: Vec<String> = children.iter()
+                    .filter(|c| !c.is_satisfied(flags))
+                    .flat_map(|c| c.suggestions(flags).into_iter().next().unwrap_or_default())
+                    .collect();
+                vec![combined]
+            }
+            RequiredUse::AnyOf(children) => children.iter()
+                .flat_map(|c| c.suggestions(flags))
+                .collect(),
+            RequiredUse::ExactlyOne(children) => {
+                let enabled: Vec<&RequiredUse> = children.iter().filter(|c| c.is_satisfied(flags)).collect();
+                if enabled.is_empty() {
+                    children.iter().flat_map(|c| c.suggestions(flags)).collect()
+                } else {
+                    Self::keep_one(&enabled)
+                }
+            }
+            RequiredUse::AtMostOne(children) => {
+                let enabled: Vec<&RequiredUse> = children.iter().filter(|c| c.is_satisfied(flags)).collect();
+                Self::keep_one(&enabled)
+            }
+            RequiredUse::Conditional { name, enabled, children } => {
+                let mut alternatives = vec![vec![change(name, !*enabled)]];
+                alternatives.extend(RequiredUse::AllOf(children.clone()).suggestions(flags));
+                alternatives
+            }
+        }
+    }
+
+    /// Every flag named in the expression, in order of appearance
+    pub fn flag_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_flags(&mut names);
+        names
+    }
+
+    fn collect_flags<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            RequiredUse::Flag { name, .. } => {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+            RequiredUse::Conditional { name, children, .. } => {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+                children.iter().for_each(|c| c.collect_flags(names));
+            }
+            RequiredUse::AllOf(children) | RequiredUse::AnyOf(children)
+            | RequiredUse::ExactlyOne(children) | RequiredUse::AtMostOne(children) => {
+                children.iter().for_each(|c| c.collect_flags(names));
+            }
+        }
+    }
+
+    /// For each satisfied child, turn off all the others
+    fn keep_one(satisfied: &[&RequiredUse]) -> Vec<Vec<String>> {
+        (0..satisfied.len())
+            .map(|keep| {
+                satisfied.iter().enumerate()
+                    .filter(|(i, _)| *i != keep)
+                    .filter_map(|(_, c)| match c {
+                        RequiredUse::Flag { name, enabled } => Some(change(name, !*enabled)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn flag_state(flags: &HashMap<String, bool>, name: &str) -> bool {
+    flags.get(name).copied().unwrap_or(false)
+}
+
+fn change(name: &str, enabled: bool) -> String {
+    if enabled { name.to_string() } else { format!("-{}", name) }
+}
+
+impl std::fmt::Display for RequiredUse {
+    /// Rendered the way emerge reports unsatisfied constraints
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let group = |f: &mut std::fmt::Formatter<'_>, prefix: &str, children: &[RequiredUse]| {
+            write!(f, "{}( ", prefix)?;
+            for child in children {
+                write!(f, "{} ", child)?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            RequiredUse::Flag { name, enabled } => write!(f, "{}{}", if *enabled { "" } else { "!" }, name),
+            RequiredUse::AllOf(children) => group(f, "", children),
+            RequiredUse::AnyOf(children) => group(f, "any-of ", children),
+            RequiredUse::ExactlyOne(children) => group(f, "exactly-one-of ", children),
+            RequiredUse::AtMostOne(children) => group(f, "at-most-one-of ", children),
+            RequiredUse::Conditional { name, enabled, children } => {
+                group(f, &format!("{}{}? ", if *enabled { "" } else { "!" }, name), children)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(enabled: &[&str]) -> HashMap<String, bool> {
+        enabled.iter().map(|f| (f.to_string(), true)).collect()
+    }
+
+    #[test]
+    fn test_parse_and_evaluate() {
+        let expr = RequiredUse::parse("^^ ( python3_11 python3_12 ) gui? ( || ( qt5 gtk ) ) ?? ( static !shared )").unwrap();
+        assert!(expr.is_satisfied(&flags(&["python3_12", "shared"])));
+        assert!(!expr.is_satisfied(&flags(&["python3_11", "python3_12", "shared"])));
+        assert!(!expr.is_satisfied(&flags(&["python3_12", "gui", "shared"])));
+        assert!(expr.is_satisfied(&flags(&["python3_12", "gui", "gtk", "shared"])));
+        assert!(!expr.is_satisfied(&flags(&["python3_12", "static"])));
+
+        assert!(RequiredUse::parse("|| ( a b").is_err());
+        assert!(RequiredUse::parse("a )").is_err());
+        assert!(RequiredUse::parse("^^ a b").is_err());
+    }
+
+    #[test]
+    fn test_violations_and_suggestions() {
+        let expr = RequiredUse::parse("^^ ( a b ) gui? ( || ( qt5 gtk ) )").unwrap();
+        let current = flags(&["a", "b", "gui"]);
+        let violations = expr.violations(&current);
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].to_string(), "exactly-one-of ( a b )");
+        assert_eq!(violations[1].to_string(), "gui? ( any-of ( qt5 gtk ) )");
+
+        assert_eq!(violations[0].suggestions(&current), vec![vec!["-b".to_string()], vec!["-a".to_string()]]);
+        assert_eq!(violations[1].suggestions(&current), vec![vec!["-gui".to_string()], vec!["qt5".to_string()]]);
+        assert!(RequiredUse::parse("").unwrap().violations(&current).is_empty());
+        assert_eq!(expr.flag_names(), vec!["a", "b", "gui", "qt5", "gtk"]);
+    }
+
+    #[test]
+    fn test_multiline_required_use_in_ebuild() {
+        let content = "EAPI=8\nREQUIRED_USE=\"\n\t^^ ( a b )\n\tgui? ( qt5 )\n\"\nSLOT=\"0\"\n";
+        let metadata = crate::doebuild::Ebuild::parse_metadata(content).unwrap();
+        assert_eq!(metadata.required_use.as_deref(), Some("^^ ( a b ) gui? ( qt5 )"));
+        assert_eq!(metadata.slot, "0");
+    }
+}
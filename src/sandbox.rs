@@ -0,0 +1,369 @@
+// sandbox.rs -- Native build sandbox: user, mount and network namespaces plus a seccomp filter
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00f3);
+/// No seccomp filter is written for other architectures, so builds there run without one
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscall numbers at or above this belong to the x32 ABI
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Syscalls a sandboxed build could use to undo or escape its namespaces
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_mount_setattr,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+];
+
+/// Isolation applied to processes a build runs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sandbox {
+    /// Make everything outside `write_paths` read-only (FEATURES=sandbox/usersandbox)
+    pub filesystem: bool,
+    /// Run in an empty network namespace with only loopback (FEATURES=network-sandbox)
+    pub network: bool,
+    /// Paths the build may write to, typically WORKDIR and D
+    pub write_paths: Vec<PathBuf>,
+    /// uid and gid to drop to once the namespaces are set up (FEATURES=userpriv)
+    pub user: Option<(u32, u32)>,
+}
+
+impl Sandbox {
+    /// The sandbox FEATURES ask for; None when they ask for none
+    pub fn from_features(features: &[String], write_paths: Vec<PathBuf>) -> Option<Self> {
+        let has = |name: &str| features.iter().any(|f| f == name);
+        let sandbox = Sandbox {
+            filesystem: has("sandbox") || has("usersandbox"),
+            network: has("network-sandbox"),
+            write_paths,
+            user: None,
+        };
+        (sandbox.filesystem || sandbox.network).then_some(sandbox)
+    }
+
+    /// Whether this architecture has a seccomp filter and this kernel (and our privileges)
+    /// allow creating the namespaces at all
+    pub fn is_supported() -> bool {
+        static SUPPORTED: OnceLock<bool> = OnceLock::new();
+        *SUPPORTED.get_or_init(|| {
+            if AUDIT_ARCH.is_none() {
+                return false;
+            }
+            let probe = Sandbox { filesystem: true, network: true, write_paths: vec![], user: None };
+            let mut command = Command::new("true");
+            probe.apply(&mut command);
+            command.status().is_ok_and(|status| status.success())
+        })
+    }
+
+    /// Drop to `uid`/`gid` inside the sandbox; needs root, which sets the namespaces up first
+    pub fn with_user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Run `command` inside the sandbox; the namespaces are entered between fork and exec
+    pub fn apply(&self, command: &mut Command) {
+        let plan = Plan::new(self);
+        // SAFETY: Plan::enter only makes raw syscalls on data prepared before the fork
+        unsafe {
+            command.pre_exec(move || plan.enter());
+        }
+    }
+}
+
+/// Everything the child needs, built up front so nothing allocates after fork
+struct Plan {
+    unshare_flags: libc::c_int,
+    /// (file, contents) for setgroups, uid_map and gid_map when a user namespace is created
+    id_maps: Vec<(CString, Vec<u8>)>,
+    network: bool,
+    write_binds: Vec<CString>,
+    /// Mount points to remount read-only, with the flags they must keep
+    readonly: Vec<(CString, libc::c_ulong)>,
+    user: Option<(u32, u32)>,
+    filter: Vec<libc::sock_filter>,
+}
+
+impl Plan {
+    fn new(sandbox: &Sandbox) -> Self {
+        let root = unsafe { libc::geteuid() } == 0;
+        let mut unshare_flags = libc::CLONE_NEWNS;
+        let mut id_maps = Vec::new();
+        if !root {
+            // Unprivileged: keep our ids inside a user namespace that grants the mount capabilities
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            unshare_flags |= libc::CLONE_NEWUSER;
+            id_maps.push((c_path(Path::new("/proc/self/setgroups")), b"deny".to_vec()));
+            id_maps.push((c_path(Path::new("/proc/self/uid_map")), format!("{} {} 1", uid, uid).into_bytes()));
+            id_maps.push((c_path(Path::new("/proc/self/gid_map")), format!("{} {} 1", gid, gid).into_bytes()));
+        }
+        if sandbox.network {
+            unshare_flags |= libc::CLONE_NEWNET;
+        }
+
+        let (mut write_binds, mut readonly) = (Vec::new(), Vec::new());
+        if sandbox.filesystem {
+            let writable: Vec<&PathBuf> = sandbox.write_paths.iter().filter(|p| p.exists()).collect();
+            write_binds = writable.iter().map(|p| c_path(p)).collect();
+            let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+            readonly = parse_mountinfo(&mountinfo)
+                .into_iter()
+                .filter(|(point, _)| !writable.iter().any(|w| point.starts_with(w)))
+                .map(|(point, flags)| (c_path(&point), flags))
+                .collect();
+        }
+
+        // Only root can switch users; in a user namespace we already are the invoking user
+        let user = sandbox.user.filter(|_| root);
+        Plan { unshare_flags, id_maps, network: sandbox.network, write_binds, readonly, user, filter: seccomp_filter() }
+    }
+
+    /// Runs in the forked child: no allocation, only syscalls
+    fn enter(&self) -> io::Result<()> {
+        unsafe {
+            check(libc::unshare(self.unshare_flags))?;
+            for (file, contents) in &self.id_maps {
+                write_file(file, contents)?;
+            }
+            if self.network {
+                loopback_up()?;
+            }
+
+            if !self.readonly.is_empty() {
+                // Keep our mounts from propagating back to the host
+                check(libc::mount(c"none".as_ptr(), c"/".as_ptr(), std::ptr::null(), libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()))?;
+                for path in &self.write_binds {
+                    check(libc::mount(path.as_ptr(), path.as_ptr(), std::ptr::null(), libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+                }
+                for (point, flags) in &self.readonly {
+                    let ret = libc::mount(std::ptr::null(), point.as_ptr(), std::ptr::null(), libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | flags, std::ptr::null());
+                    // Mounts we can't see (shadowed, restricted) are skipped, but / has to work
+                    if ret != 0 && point.as_bytes() == b"/" {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+
+            if let Some((uid, gid)) = self.user {
+                check(libc::setgroups(0, std::ptr::null()))?;
+                check(libc::setgid(gid))?;
+                check(libc::setuid(uid))?;
+            }
+
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            if self.filter.is_empty() {
+                return Ok(());
+            }
+            let program = libc::sock_fprog { len: self.filter.len() as libc::c_ushort, filter: self.filter.as_ptr() as *mut _ };
+            check(libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog))?;
+        }
+        Ok(())
+    }
+}
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap_or_default()
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+unsafe fn write_file(path: &CString, contents: &[u8]) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        let result = if written < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+        libc::close(fd);
+        result
+    }
+}
+
+/// A new network namespace starts with loopback down
+unsafe fn loopback_up() -> io::Result<()> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        check(fd)?;
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        let mut result = check(libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request));
+        if result.is_ok() {
+            request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            result = check(libc::ioctl(fd, libc::SIOCSIFFLAGS, &request));
+        }
+        libc::close(fd);
+        result
+    }
+}
+
+/// Mount points and the flags a read-only remount must preserve, from /proc/self/mountinfo
+pub fn parse_mountinfo(content: &str) -> Vec<(PathBuf, libc::c_ulong)> {
+    content.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let point = unescape_mountinfo(fields.get(4)?);
+            let flags = fields.get(5)?.split(',').fold(0, |flags, option| flags | match option {
+                "nosuid" => libc::MS_NOSUID,
+                "nodev" => libc::MS_NODEV,
+                "noexec" => libc::MS_NOEXEC,
+                "noatime" => libc::MS_NOATIME,
+                "nodiratime" => libc::MS_NODIRATIME,
+                "relatime" => libc::MS_RELATIME,
+                _ => 0,
+            });
+            Some((PathBuf::from(point), flags))
+        })
+        .collect()
+}
+
+/// mountinfo escapes space, tab, newline and backslash as \ooo
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(octal) = field.get(i + 1..i + 4)
+            && let Ok(byte) = u8::from_str_radix(octal, 8)
+        {
+            out.push(byte);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt, jf, k }
+}
+
+/// seccomp program refusing DENIED_SYSCALLS with EPERM; other ABIs (i386, x32) are refused
+/// entirely. Empty on architectures without an AUDIT_ARCH here
+pub fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let Some(arch) = AUDIT_ARCH else {
+        return Vec::new();
+    };
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    // Instructions between the syscall number load and the final ALLOW/deny pair
+    let checks = DENIED_SYSCALLS.len() + 1;
+
+    let mut program = vec![
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_ARCH),
+        bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        bpf_stmt(libc::BPF_RET | libc::BPF_K, deny),
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+    ];
+    for i in 0..checks {
+        // Distance from the instruction after this one to the deny return
+        let to_deny = (checks - i) as u8;
+        let nr = match DENIED_SYSCALLS.get(i) {
+            Some(&nr) => (libc::BPF_JEQ, nr as u32),
+            None => (libc::BPF_JGE, X32_SYSCALL_BIT),
+        };
+        program.push(bpf_jump(libc::BPF_JMP | nr.0 | libc::BPF_K, nr.1, to_deny, 0));
+    }
+    program.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    program.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, deny));
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let content = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                       23 22 0:5 / /dev rw,nosuid,noexec shared:2 - devtmpfs udev rw\n\
+                       24 22 0:6 / /mnt/my\\040disk ro,nodev - vfat /dev/sdb1 ro\n";
+        assert_eq!(parse_mountinfo(content), vec![
+            (PathBuf::from("/"), libc::MS_RELATIME),
+            (PathBuf::from("/dev"), libc::MS_NOSUID | libc::MS_NOEXEC),
+            (PathBuf::from("/mnt/my disk"), libc::MS_NODEV),
+        ]);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64"))]
+    fn test_seccomp_filter_jumps_to_deny() {
+        let program = seccomp_filter();
+        let deny = program.len() - 1;
+        for (i, instruction) in program.iter().enumerate().skip(4).take(DENIED_SYSCALLS.len() + 1) {
+            assert_eq!(i + 1 + instruction.jt as usize, deny);
+        }
+    }
+
+    #[test]
+    fn test_sandbox_restricts_writes() {
+        if !Sandbox::is_supported() {
+            eprintln!("namespaces unavailable, skipping");
+            return;
+        }
+        let (inside, outside) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let sandbox = Sandbox::from_features(&["sandbox".to_string(), "network-sandbox".to_string()], vec![inside.path().to_path_buf()]).unwrap();
+
+        let run = |script: String| {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            sandbox.apply(&mut command);
+            command.status().unwrap().success()
+        };
+        assert!(run(format!("echo ok > {}/file", inside.path().display())));
+        assert!(!run(format!("echo ok > {}/file", outside.path().display())));
+        assert!(!outside.path().join("file").exists());
+        assert!(run("test \"$(grep -c : /proc/net/dev)\" = 1".to_string()));
+
+        if unsafe { libc::geteuid() } == 0 {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("test \"$(id -u):$(id -g)\" = 65534:65534");
+            sandbox.clone().with_user(65534, 65534).apply(&mut command);
+            assert!(command.status().unwrap().success());
+        }
+    }
+}
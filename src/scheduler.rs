@@ -0,0 +1,468 @@
+// scheduler.rs -- Dependency-ordered parallel build scheduler
+
+use crate::depgraph::DepGraph;
+use crate::exception::InvalidData;
+use crate::progress::{self, BuildTimes};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    /// Dropped because a dependency failed (--keep-going)
+    Skipped,
+}
+
+/// One package of the merge list
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub cpv: String,
+    pub deps: Vec<usize>,
+    pub state: JobState,
+    /// PROPERTIES=interactive: built alone, with the terminal to itself
+    pub interactive: bool,
+}
+
+/// Outcome of a scheduler run
+#[derive(Debug, Default)]
+pub struct ScheduleResult {
+    pub merged: Vec<String>,
+    pub failed: Vec<String>,
+    /// Packages never started because the run was aborted
+    pub pending: Vec<String>,
+    /// Packages dropped because a dependency failed
+    pub skipped: Vec<String>,
+}
+
+type BuildFuture<'a> = Pin<Box<dyn Future<Output = Result<(), InvalidData>> + 'a>>;
+
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    index: HashMap<String, usize>,
+    max_jobs: usize,
+    load_average: Option<f64>,
+    keep_going: bool,
+    build_times: BuildTimes,
+}
+
+impl Scheduler {
+    pub fn new(max_jobs: usize) -> Self {
+        Scheduler {
+            jobs: Vec::new(),
+            index: HashMap::new(),
+            max_jobs: max_jobs.max(1),
+            load_average: None,
+            keep_going: false,
+            build_times: BuildTimes::default(),
+        }
+    }
+
+    /// Do not start new jobs while the 1-minute load average is at or above `limit`
+    pub fn with_load_average(mut self, limit: Option<f64>) -> Self {
+        self.load_average = limit;
+        self
+    }
+
+    /// After a failure, drop the failed package's dependents and continue with the rest
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Estimate how long each running job has left from `times`
+    pub fn with_build_times(mut self, times: BuildTimes) -> Self {
+        self.build_times = times;
+        self
+    }
+
+    /// Build a scheduler for `merge_list` ((graph key, cpv) pairs) ordered by the graph's edges
+    pub fn from_depgraph(graph: &DepGraph, merge_list: &[(String, String)], max_jobs: usize) -> Self {
+        let mut scheduler = Scheduler::new(max_jobs);
+        let keys: HashMap<&str, &str> = merge_list.iter().map(|(k, c)| (k.as_str(), c.as_str())).collect();
+        for (_, cpv) in merge_list {
+            scheduler.add_job(cpv);
+        }
+
+        for (key, cpv) in merge_list {
+            // Look through dependencies that are not being merged (already installed)
+            let mut visited = HashSet::new();
+            let mut stack: Vec<(&str, &str)> = graph.edges.get(key).into_iter().flatten().map(|d| (key.as_str(), d.as_str())).collect();
+            while let Some((from, dep)) = stack.pop() {
+                // PDEPEND, and edges that break a cycle, may be merged after the package
+                if graph.ignorable_edge(from, dep) || !visited.insert(dep) {
+                    continue;
+                }
+                match keys.get(dep) {
+                    Some(dep_cpv) => scheduler.add_dependency(cpv, dep_cpv),
+                    None => stack.extend(graph.edges.get(dep).into_iter().flatten().map(|d| (dep, d.as_str()))),
+                }
+            }
+        }
+        scheduler
+    }
+
+    pub fn add_job(&mut self, cpv: &str) {
+        if !self.index.contains_key(cpv) {
+            self.index.insert(cpv.to_string(), self.jobs.len());
+            self.jobs.push(Job {
+                cpv: cpv.to_string(),
+                deps: Vec::new(),
+                state: JobState::Pending,
+                interactive: false,
+            });
+        }
+    }
+
+    /// Mark a package as interactive: it runs only while nothing else does
+    pub fn set_interactive(&mut self, cpv: &str) {
+        if let Some(&job) = self.index.get(cpv) {
+            self.jobs[job].interactive = true;
+        }
+    }
+
+    /// Require `dep` to be merged before `cpv`; ignored unless both are jobs
+    pub fn add_dependency(&mut self, cpv: &str, dep: &str) {
+        if let (Some(&job), Some(&dep)) = (self.index.get(cpv), self.index.get(dep))
+            && job != dep && !self.jobs[job].deps.contains(&dep)
+        {
+            self.jobs[job].deps.push(dep);
+        }
+    }
+
+    /// Mark a package as already merged, e.g. by an interrupted run being resumed
+    pub fn mark_done(&mut self, cpv: &str) {
+        if let Some(&job) = self.index.get(cpv) {
+            self.jobs[job].state = JobState::Done;
+        }
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub fn running(&self) -> Vec<String> {
+        self.with_state(JobState::Running)
+    }
+
+    fn with_state(&self, state: JobState) -> Vec<String> {
+        self.jobs.iter().filter(|j| j.state == state).map(|j| j.cpv.clone()).collect()
+    }
+
+    fn count(&self, state: JobState) -> usize {
+        self.jobs.iter().filter(|j| j.state == state).count()
+    }
+
+    fn is_ready(&self, job: usize) -> bool {
+        self.jobs[job].state == JobState::Pending
+            && self.jobs[job].deps.iter().all(|&d| self.jobs[d].state == JobState::Done)
+    }
+
+    /// Skip every pending job that depends, directly or not, on `failed`
+    fn skip_dependents(&mut self, failed: usize) -> Vec<String> {
+        let mut skipped = Vec::new();
+        let mut broken = vec![failed];
+        while let Some(job) = broken.pop() {
+            for dependent in 0..self.jobs.len() {
+                if self.jobs[dependent].state == JobState::Pending && self.jobs[dependent].deps.contains(&job) {
+                    self.jobs[dependent].state = JobState::Skipped;
+                    skipped.push(self.jobs[dependent].cpv.clone());
+                    broken.push(dependent);
+                }
+            }
+        }
+        skipped
+    }
+
+    /// Pending job with the fewest unmerged dependencies, used to break dependency cycles
+    fn least_blocked(&self) -> Option<usize> {
+        (0..self.jobs.len())
+            .filter(|&j| self.jobs[j].state == JobState::Pending)
+            .min_by_key(|&j| self.jobs[j].deps.iter().filter(|&&d| self.jobs[d].state != JobState::Done).count())
+    }
+
+    fn load_exceeded(&self) -> bool {
+        match (self.load_average, load_average()) {
+            (Some(limit), Some(load)) => load[0] >= limit,
+            _ => false,
+        }
+    }
+
+    /// Portage-style progress line, e.g. "Jobs: 3 of 10 complete, 2 running"
+    pub fn status_line(&self) -> String {
+        let mut line = format!("Jobs: {} of {} complete", self.count(JobState::Done), self.jobs.len());
+        let running = self.count(JobState::Running);
+        if running > 0 {
+            line.push_str(&format!(", {} running", running));
+        }
+        let failed = self.count(JobState::Failed);
+        if failed > 0 {
+            line.push_str(&format!(", {} failed", failed));
+        }
+        if let Some(load) = load_average() {
+            line.push_str(&format!("    Load avg: {:.2}, {:.2}, {:.2}", load[0], load[1], load[2]));
+        }
+        line
+    }
+
+    /// Merge every job with `build`, running up to `max_jobs` at once in dependency order.
+    /// `on_update` is called whenever a job starts or finishes.
+    pub async fn run<'a, F, Fut>(&mut self, build: F, mut on_update: impl FnMut(&Scheduler)) -> ScheduleResult
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<(), InvalidData>> + 'a,
+    {
+        let total = self.jobs.len();
+        let mut result = ScheduleResult::default();
+        let mut running: Vec<(usize, BuildFuture<'a>)> = Vec::new();
+        let mut aborted = false;
+        let _merging = crate::signals::MergeGuard::enter();
+
+        loop {
+            // Let the running builds die from the interrupt, but start nothing new
+            aborted |= crate::signals::interrupted();
+            let mut load_limited = false;
+            while !aborted && running.len() < self.max_jobs {
+                if !running.is_empty() && self.load_exceeded() {
+                    load_limited = true;
+                    break;
+                }
+                // An interactive job has the terminal to itself
+                if running.iter().any(|(job, _)| self.jobs[*job].interactive) {
+                    break;
+                }
+                let next = (0..self.jobs.len()).find(|&j| self.is_ready(j) && (running.is_empty() || !self.jobs[j].interactive));
+                let next = match next {
+                    Some(job) => job,
+                    None if running.is_empty() => match self.least_blocked() {
+                        Some(job) => {
+                            eprintln!("Warning: circular dependencies, merging {} first", self.jobs[job].cpv);
+                            job
+                        }
+                        None => break,
+                    },
+                    None => break,
+                };
+
+                self.jobs[next].state = JobState::Running;
+                let position = total - self.count(JobState::Pending);
+                let cpv = &self.jobs[next].cpv;
+                progress::clear();
+                println!(">>> Emerging ({} of {}) {}", position, total, cpv);
+                progress::begin(cpv, position, total, self.build_times.estimate(cpv));
+                running.push((next, Box::pin(build(self.jobs[next].cpv.clone()))));
+                on_update(self);
+            }
+
+            if running.is_empty() {
+                break;
+            }
+
+            // The status line is redrawn every second, except over an interactive build
+            let ticking = progress::enabled() && !running.iter().any(|(job, _)| self.jobs[*job].interactive);
+            let finished = if load_limited || ticking {
+                tokio::select! {
+                    finished = next_finished(&mut running) => Some(finished),
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => None,
+                }
+            } else {
+                Some(next_finished(&mut running).await)
+            };
+            let Some((job, outcome)) = finished else {
+                if ticking {
+                    progress::draw(self.count(JobState::Done));
+                }
+                continue;
+            };
+
+            let cpv = self.jobs[job].cpv.clone();
+            let elapsed = progress::end(&cpv).map(progress::format_duration).unwrap_or_default();
+            progress::clear();
+            match outcome {
+                Ok(()) => {
+                    self.jobs[job].state = JobState::Done;
+                    println!(">>> Completed {} in {}", cpv, elapsed);
+                    result.merged.push(cpv);
+                }
+                Err(e) => {
+                    self.jobs[job].state = JobState::Failed;
+                    eprintln!(">>> Failed to emerge {} after {}: {}", cpv, elapsed, e);
+                    result.failed.push(cpv);
+                    if self.keep_going && !crate::signals::interrupted() {
+                        for dependent in self.skip_dependents(job) {
+                            eprintln!(">>> Dropping {} because {} failed", dependent, self.jobs[job].cpv);
+                            result.skipped.push(dependent);
+                        }
+                    } else {
+                        aborted = true;
+                    }
+                }
+            }
+            println!(">>> {}", self.status_line());
+            on_update(self);
+        }
+
+        result.pending = self.with_state(JobState::Pending);
+        if !result.skipped.is_empty() {
+            println!("\n * The following packages were skipped because of failed dependencies:");
+            for cpv in &result.skipped {
+                println!(" *   {}", cpv);
+            }
+        }
+        result
+    }
+}
+
+/// Wait for the first of the running builds to finish
+async fn next_finished<'a>(running: &mut Vec<(usize, BuildFuture<'a>)>) -> (usize, Result<(), InvalidData>) {
+    std::future::poll_fn(|cx| {
+        for i in 0..running.len() {
+            if let Poll::Ready(outcome) = running[i].1.as_mut().poll(cx) {
+                let (job, _) = running.swap_remove(i);
+                return Poll::Ready((job, outcome));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// The 1, 5 and 15 minute load averages from /proc/loadavg
+pub fn load_average() -> Option<[f64; 3]> {
+    let content = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let mut fields = content.split_whitespace().map(|f| f.parse::<f64>().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[tokio::test]
+    async fn test_dependencies_merge_first() {
+        let mut scheduler = Scheduler::new(4);
+        for cpv in ["app-misc/foo-1.0", "dev-libs/bar-1.0", "sys-libs/baz-1.0"] {
+            scheduler.add_job(cpv);
+        }
+        scheduler.add_dependency("app-misc/foo-1.0", "dev-libs/bar-1.0");
+        scheduler.add_dependency("dev-libs/bar-1.0", "sys-libs/baz-1.0");
+
+        let order = RefCell::new(Vec::new());
+        let result = scheduler.run(|cpv| {
+            order.borrow_mut().push(cpv);
+            async { Ok(()) }
+        }, |_| {}).await;
+
+        assert_eq!(*order.borrow(), vec!["sys-libs/baz-1.0", "dev-libs/bar-1.0", "app-misc/foo-1.0"]);
+        assert_eq!(result.merged.len(), 3);
+        assert!(scheduler.status_line().starts_with("Jobs: 3 of 3 complete"));
+    }
+
+    #[tokio::test]
+    async fn test_interactive_runs_alone() {
+        let mut scheduler = Scheduler::new(4);
+        for cpv in ["app-misc/a-1.0", "app-misc/ask-1.0", "app-misc/b-1.0", "app-misc/c-1.0"] {
+            scheduler.add_job(cpv);
+        }
+        scheduler.set_interactive("app-misc/ask-1.0");
+
+        // Record how many builds were running whenever one started
+        let running = RefCell::new(0);
+        let started = RefCell::new(Vec::new());
+        let result = scheduler.run(|cpv| {
+            *running.borrow_mut() += 1;
+            started.borrow_mut().push((cpv, *running.borrow()));
+            let running = &running;
+            async move {
+                tokio::task::yield_now().await;
+                *running.borrow_mut() -= 1;
+                Ok(())
+            }
+        }, |_| {}).await;
+
+        assert_eq!(result.merged.len(), 4);
+        let started = started.into_inner();
+        assert_eq!(started[0], ("app-misc/a-1.0".to_string(), 1));
+        assert!(started.contains(&("app-misc/ask-1.0".to_string(), 1)));
+        // The others do run in parallel
+        assert!(started.iter().any(|(_, count)| *count > 1));
+    }
+
+    #[tokio::test]
+    async fn test_failure_stops_dependents() {
+        let mut scheduler = Scheduler::new(1);
+        for cpv in ["dev-libs/bar-1.0", "app-misc/foo-1.0"] {
+            scheduler.add_job(cpv);
+        }
+        scheduler.add_dependency("app-misc/foo-1.0", "dev-libs/bar-1.0");
+
+        let result = scheduler.run(|cpv| async move {
+            Err(InvalidData::new(&format!("{} failed", cpv), None))
+        }, |_| {}).await;
+
+        assert_eq!(result.failed, vec!["dev-libs/bar-1.0"]);
+        assert_eq!(result.pending, vec!["app-misc/foo-1.0"]);
+    }
+
+    #[tokio::test]
+    async fn test_keep_going_skips_dependents() {
+        let mut scheduler = Scheduler::new(1).with_keep_going(true);
+        for cpv in ["dev-libs/bar-1.0", "app-misc/foo-1.0", "app-misc/qux-1.0", "sys-libs/zlib-1.3"] {
+            scheduler.add_job(cpv);
+        }
+        scheduler.add_dependency("app-misc/foo-1.0", "dev-libs/bar-1.0");
+        scheduler.add_dependency("app-misc/qux-1.0", "app-misc/foo-1.0");
+
+        let result = scheduler.run(|cpv| async move {
+            if cpv == "dev-libs/bar-1.0" {
+                Err(InvalidData::new("build failed", None))
+            } else {
+                Ok(())
+            }
+        }, |_| {}).await;
+
+        assert_eq!(result.failed, vec!["dev-libs/bar-1.0"]);
+        assert_eq!(result.skipped, vec!["app-misc/foo-1.0", "app-misc/qux-1.0"]);
+        assert_eq!(result.merged, vec!["sys-libs/zlib-1.3"]);
+        assert!(result.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_from_depgraph_breaks_cycles() {
+        use crate::atom::Atom;
+        use crate::depgraph::{DepNode, DepType};
+        let dep = |atom: &str, dep_type: DepType| DepNode {
+            atom: Atom::new(atom).unwrap(),
+            dep_type,
+            blockers: vec![],
+            use_conditional: None,
+            slot: None,
+            subslot: None,
+            slot_operator: None,
+        };
+
+        // foo needs bar to build, bar needs foo only at runtime, and baz comes after foo (PDEPEND)
+        let mut graph = DepGraph::new();
+        let foo = graph.add_target(&Atom::new("app-misc/foo").unwrap(), vec![dep("dev-libs/bar", DepType::Build), dep("app-misc/baz", DepType::Post)], vec![]);
+        graph.add_dependencies("dev-libs/bar", vec![dep("app-misc/foo", DepType::Runtime)]);
+        graph.add_dependencies("app-misc/baz", vec![dep("app-misc/foo", DepType::Runtime)]);
+        let merge_list: Vec<(String, String)> = [(foo.as_str(), "app-misc/foo-1.0"), ("dev-libs/bar", "dev-libs/bar-1.0"), ("app-misc/baz", "app-misc/baz-1.0")]
+            .iter()
+            .map(|(key, cpv)| (key.to_string(), cpv.to_string()))
+            .collect();
+
+        let mut scheduler = Scheduler::from_depgraph(&graph, &merge_list, 1);
+        let order = RefCell::new(Vec::new());
+        scheduler.run(|cpv| {
+            order.borrow_mut().push(cpv);
+            async { Ok(()) }
+        }, |_| {}).await;
+        assert_eq!(*order.borrow(), vec!["dev-libs/bar-1.0", "app-misc/foo-1.0", "app-misc/baz-1.0"]);
+    }
+}
@@ -0,0 +1,487 @@
+// search_index.rs -- Persistent package index for fast --search
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use regex::Regex;
+
+use crate::exception::InvalidData;
+use crate::porttree::PortTree;
+
+/// Index location below ROOT
+pub const SEARCH_INDEX_PATH: &str = "var/cache/edb/emerge-rs-search.bin";
+
+const MAGIC: &[u8; 4] = b"ERSI";
+const FORMAT_VERSION: u32 = 1;
+
+/// One package (newest version) in the index
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub category: String,
+    pub name: String,
+    pub version: String,
+    pub repo: String,
+    pub description: String,
+}
+
+impl IndexEntry {
+    pub fn cp(&self) -> String {
+        format!("{}/{}", self.category, self.name)
+    }
+
+    pub fn cpv(&self) -> String {
+        format!("{}/{}-{}", self.category, self.name, self.version)
+    }
+}
+
+/// Modification stamp of a repository when the index was built
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepoStamp {
+    pub name: String,
+    pub location: String,
+    pub mtime: u64,
+}
+
+/// How a search pattern is interpreted.
+/// "%name" matches the package name exactly and "cat/pkg" restricts the category.
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub pattern: String,
+    category: Option<Regex>,
+    name: Regex,
+    exact: bool,
+    regex: bool,
+    pub fuzzy: bool,
+    pub search_description: bool,
+}
+
+impl SearchQuery {
+    /// Case-insensitive substring search
+    pub fn new(pattern: &str) -> Self {
+        Self::compile(pattern, false).expect("escaped search pattern is a valid regex")
+    }
+
+    /// Treat the pattern as a regular expression
+    pub fn regex(pattern: &str) -> Result<Self, InvalidData> {
+        Self::compile(pattern, true)
+    }
+
+    /// Use a regex when the pattern contains regex metacharacters (--regex-search-auto)
+    pub fn auto(pattern: &str) -> Result<Self, InvalidData> {
+        let regex = pattern.contains(['^', '$', '*', '[', ']', '{', '}', '|', '?', '+', '(', ')', '\\']);
+        Self::compile(pattern, regex)
+    }
+
+    fn compile(pattern: &str, regex: bool) -> Result<Self, InvalidData> {
+        let (exact, rest) = match pattern.strip_prefix('%') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let build = |part: &str| {
+            let part = if regex { part.to_string() } else { regex::escape(part) };
+            let part = if exact { format!("^(?:{})$", part) } else { part };
+            Regex::new(&format!("(?i){}", part))
+                .map_err(|e| InvalidData::new(&format!("Invalid search regex '{}': {}", pattern, e), None))
+        };
+        let (category, name) = match rest.split_once('/') {
+            Some((category, name)) => (Some(build(category)?), name),
+            None => (None, rest),
+        };
+        Ok(SearchQuery {
+            pattern: pattern.to_string(),
+            category,
+            name: build(name)?,
+            exact,
+            regex,
+            fuzzy: false,
+            search_description: false,
+        })
+    }
+
+    pub fn with_fuzzy(mut self, fuzzy: bool) -> Self {
+        self.fuzzy = fuzzy;
+        self
+    }
+
+    pub fn with_description(mut self, search_description: bool) -> Self {
+        self.search_description = search_description;
+        self
+    }
+
+    /// The name part of the pattern, without '%' or a category
+    fn name_pattern(&self) -> &str {
+        let rest = self.pattern.trim_start_matches('%');
+        rest.split_once('/').map_or(rest, |(_, name)| name)
+    }
+
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        if let Some(category) = &self.category && !category.is_match(&entry.category) {
+            return false;
+        }
+        if self.name.is_match(&entry.name) {
+            return true;
+        }
+        if self.search_description && !self.exact && self.name.is_match(&entry.description) {
+            return true;
+        }
+        self.fuzzy && !self.regex && !self.exact && !self.name_pattern().is_empty()
+            && similarity(&self.name_pattern().to_lowercase(), &entry.name.to_lowercase()) >= 0.8
+    }
+
+    /// Byte ranges of the package name that matched, for highlighting
+    pub fn name_ranges(&self, entry: &IndexEntry) -> Vec<(usize, usize)> {
+        ranges(&self.name, &entry.name)
+    }
+
+    /// Byte ranges of the description that matched; empty unless searching descriptions
+    pub fn description_ranges(&self, entry: &IndexEntry) -> Vec<(usize, usize)> {
+        if self.search_description && !self.exact {
+            ranges(&self.name, &entry.description)
+        } else {
+            vec![]
+        }
+    }
+}
+
+fn ranges(regex: &Regex, text: &str) -> Vec<(usize, usize)> {
+    regex.find_iter(text)
+        .filter(|m| !m.is_empty())
+        .map(|m| (m.start(), m.end()))
+        .collect()
+}
+
+/// Wrap the given byte ranges of `text` in ANSI bold green
+pub fn highlight(text: &str, ranges: &[(usize, usize)]) -> String {
+    let mut out = String::new();
+    let mut pos = 0;
+    for &(start, end) in ranges {
+        out.push_str(&text[pos..start]);
+        out.push_str(&format!("\x1b[1;32m{}\x1b[0m", &text[start..end]));
+        pos = end;
+    }
+    out.push_str(&text[pos..]);
+    out
+}
+
+/// Similarity ratio in [0, 1]: twice the longest common subsequence over the total length
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let mut previous = vec![0usize; b.len() + 1];
+    for ca in &a {
+        let mut current = vec![0usize; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            current[j + 1] = if ca == cb { previous[j] + 1 } else { previous[j + 1].max(current[j]) };
+        }
+        previous = current;
+    }
+    2.0 * previous[b.len()] as f64 / (a.len() + b.len()) as f64
+}
+
+/// Name, version and description of every package, keyed to repository stamps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchIndex {
+    pub stamps: Vec<RepoStamp>,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl SearchIndex {
+    pub fn path(root: &str) -> PathBuf {
+        Path::new(root).join(SEARCH_INDEX_PATH)
+    }
+
+    /// Walk every repository; this is the slow path the index exists to avoid
+    pub fn build(porttree: &PortTree) -> Self {
+        let mut index = SearchIndex::default();
+        let mut repos: Vec<_> = porttree.repositories.values().collect();
+        repos.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for repo in repos {
+            let location = Path::new(&repo.location);
+            index.stamps.push(RepoStamp {
+                name: repo.name.clone(),
+                location: repo.location.clone(),
+                mtime: repo_mtime(location),
+            });
+
+            for category in list_dirs(location) {
+                if category.starts_with('.') || category == "metadata" || category == "profiles"
+                    || category == "eclass" || category == "licenses" || !category.contains('-') && category != "virtual" {
+                    continue;
+                }
+                for name in list_dirs(&location.join(&category)) {
+                    if let Some(entry) = Self::index_package(location, &repo.name, &category, &name) {
+                        index.entries.push(entry);
+                    }
+                }
+            }
+        }
+        index.entries.sort_by(|a, b| a.cp().cmp(&b.cp()).then(a.repo.cmp(&b.repo)));
+        index
+    }
+
+    fn index_package(location: &Path, repo: &str, category: &str, name: &str) -> Option<IndexEntry> {
+        let package_dir = location.join(category).join(name);
+        let best = fs::read_dir(&package_dir).ok()?
+            .flatten()
+            .filter_map(|e| e.file_name().to_str().and_then(|f| f.strip_suffix(".ebuild")).map(|s| s.to_string()))
+            .filter_map(|pf| {
+                let version = crate::versions::cpv_getversion(&format!("{}/{}", category, pf))?;
+                pf.starts_with(&format!("{}-", name)).then_some(version)
+            })
+            .max_by(|a, b| crate::versions::vercmp(a, b).unwrap_or(0).cmp(&0))?;
+
+        let pf = format!("{}-{}", name, best);
+        // Prefer the md5-cache entry shipped with the tree over parsing the ebuild
+        let description = read_variable(&location.join("metadata/md5-cache").join(category).join(&pf), "DESCRIPTION")
+            .or_else(|| read_variable(&package_dir.join(format!("{}.ebuild", pf)), "DESCRIPTION"))
+            .unwrap_or_default();
+
+        Some(IndexEntry {
+            category: category.to_string(),
+            name: name.to_string(),
+            version: best,
+            repo: repo.to_string(),
+            description,
+        })
+    }
+
+    /// True when a repository was added, removed or modified since the index was built
+    pub fn is_stale(&self, porttree: &PortTree) -> bool {
+        if self.stamps.len() != porttree.repositories.len() {
+            return true;
+        }
+        self.stamps.iter().any(|stamp| match porttree.repositories.get(&stamp.name) {
+            Some(repo) => repo.location != stamp.location || repo_mtime(Path::new(&repo.location)) != stamp.mtime,
+            None => true,
+        })
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> Vec<&IndexEntry> {
+        self.entries.iter().filter(|e| query.matches(e)).collect()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), InvalidData> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&(self.stamps.len() as u32).to_le_bytes());
+        for stamp in &self.stamps {
+            write_str(&mut buf, &stamp.name);
+            write_str(&mut buf, &stamp.location);
+            buf.extend_from_slice(&stamp.mtime.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            for field in [&entry.category, &entry.name, &entry.version, &entry.repo, &entry.description] {
+                write_str(&mut buf, field);
+            }
+        }
+
+        let parent = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(parent)
+            .map_err(|e| InvalidData::new(&format!("Failed to create {}: {}", parent.display(), e), None))?;
+        // Write to a temporary file first so readers never see a partial index
+        let mut tmp = tempfile::NamedTempFile::new_in(parent)
+            .map_err(|e| InvalidData::new(&format!("Failed to create temporary index: {}", e), None))?;
+        tmp.write_all(&buf)
+            .map_err(|e| InvalidData::new(&format!("Failed to write search index: {}", e), None))?;
+        tmp.persist(path)
+            .map_err(|e| InvalidData::new(&format!("Failed to save search index {}: {}", path.display(), e), None))?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, InvalidData> {
+        let mut data = Vec::new();
+        fs::File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| InvalidData::new(&format!("Failed to read search index {}: {}", path.display(), e), None))?;
+
+        let mut reader = Reader { data: &data, pos: 0 };
+        if reader.take(4)? != MAGIC || reader.u32()? != FORMAT_VERSION {
+            return Err(InvalidData::new(&format!("{} is not a search index of this version", path.display()), None));
+        }
+        let mut index = SearchIndex::default();
+        for _ in 0..reader.u32()? {
+            index.stamps.push(RepoStamp { name: reader.string()?, location: reader.string()?, mtime: reader.u64()? });
+        }
+        for _ in 0..reader.u32()? {
+            index.entries.push(IndexEntry {
+                category: reader.string()?,
+                name: reader.string()?,
+                version: reader.string()?,
+                repo: reader.string()?,
+                description: reader.string()?,
+            });
+        }
+        Ok(index)
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], InvalidData> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| InvalidData::new("Truncated search index", None))?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32, InvalidData> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, InvalidData> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, InvalidData> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| InvalidData::new("Invalid UTF-8 in search index", None))
+    }
+}
+
+fn list_dirs(dir: &Path) -> Vec<String> {
+    let mut dirs: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries.flatten()
+                .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
+}
+
+/// Newest modification time of the repository root, its category and package directories,
+/// metadata/timestamp.chk and the md5-cache
+fn repo_mtime(location: &Path) -> u64 {
+    let mtime = |path: &Path| {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    };
+    list_dirs(location).iter()
+        .flat_map(|category| {
+            let category = location.join(category);
+            let packages = list_dirs(&category).into_iter().map(|name| mtime(&category.join(name)));
+            std::iter::once(mtime(&category)).chain(packages).collect::<Vec<_>>()
+        })
+        .chain([
+            mtime(location),
+            mtime(&location.join("metadata/timestamp.chk")),
+            mtime(&location.join("metadata/md5-cache")),
+        ])
+        .max()
+        .unwrap_or(0)
+}
+
+/// Value of a KEY=value line in an ebuild or md5-cache entry
+fn read_variable(path: &Path, key: &str) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    let prefix = format!("{}=", key);
+    content.lines()
+        .find_map(|line| line.trim().strip_prefix(&prefix))
+        .map(|value| value.trim().trim_matches('"').trim_matches('\'').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn tree(temp: &TempDir) -> PortTree {
+        let repo = temp.path().join("repo");
+        for (cp, pf, content) in [
+            ("app-editors/vim", "vim-9.0", "DESCRIPTION=\"Vim, an improved vi-style text editor\"\n"),
+            ("app-editors/vim", "vim-9.1", "DESCRIPTION=\"Vim, an improved vi-style text editor\"\n"),
+            ("app-editors/nano", "nano-7.2", "DESCRIPTION=\"GNU GPL'd Pico clone\"\n"),
+            ("dev-lang/python", "python-3.12.1", "DESCRIPTION=\"An interpreted language\"\n"),
+        ] {
+            fs::create_dir_all(repo.join(cp)).unwrap();
+            fs::write(repo.join(cp).join(format!("{}.ebuild", pf)), content).unwrap();
+        }
+        let mut porttree = PortTree::new("/");
+        porttree.parse_repos_conf(&format!("[gentoo]\nlocation = {}\n", repo.display()));
+        porttree
+    }
+
+    #[test]
+    fn test_build_save_load() {
+        let temp = TempDir::new().unwrap();
+        let porttree = tree(&temp);
+        let index = SearchIndex::build(&porttree);
+        assert_eq!(index.entries.len(), 3);
+        let vim = index.entries.iter().find(|e| e.name == "vim").unwrap();
+        assert_eq!(vim.cpv(), "app-editors/vim-9.1");
+        assert_eq!(vim.description, "Vim, an improved vi-style text editor");
+        assert!(!index.is_stale(&porttree));
+
+        // A new ebuild only touches its package directory
+        let vim = temp.path().join("repo/app-editors/vim");
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::open(&vim).unwrap().set_modified(later).unwrap();
+        assert!(index.is_stale(&porttree));
+
+        let path = SearchIndex::path(temp.path().to_str().unwrap());
+        index.save(&path).unwrap();
+        assert_eq!(SearchIndex::load(&path).unwrap(), index);
+
+        fs::write(&path, b"ERSI").unwrap();
+        assert!(SearchIndex::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_search_modes() {
+        let temp = TempDir::new().unwrap();
+        let index = SearchIndex::build(&tree(&temp));
+        let names = |query: &SearchQuery| index.search(query).iter().map(|e| e.cp()).collect::<Vec<_>>();
+
+        assert_eq!(names(&SearchQuery::new("VIM")), vec!["app-editors/vim"]);
+        assert_eq!(names(&SearchQuery::auto("^(nano|vim)$").unwrap()), vec!["app-editors/nano", "app-editors/vim"]);
+        assert_eq!(names(&SearchQuery::new("editors/")), vec!["app-editors/nano", "app-editors/vim"]);
+        assert!(names(&SearchQuery::new("pyhton")).is_empty());
+        assert_eq!(names(&SearchQuery::new("pyhton").with_fuzzy(true)), vec!["dev-lang/python"]);
+        assert_eq!(names(&SearchQuery::new("pico").with_description(true)), vec!["app-editors/nano"]);
+        assert!(SearchQuery::regex("(").is_err());
+    }
+
+    #[test]
+    fn test_exact_category_and_description() {
+        let temp = TempDir::new().unwrap();
+        let index = SearchIndex::build(&tree(&temp));
+        let names = |query: &SearchQuery| index.search(query).iter().map(|e| e.cp()).collect::<Vec<_>>();
+
+        assert!(names(&SearchQuery::new("%vi")).is_empty());
+        assert_eq!(names(&SearchQuery::new("%VIM")), vec!["app-editors/vim"]);
+        assert_eq!(names(&SearchQuery::new("dev-lang/py")), vec!["dev-lang/python"]);
+        assert!(names(&SearchQuery::new("app-editors/py")).is_empty());
+        assert_eq!(names(&SearchQuery::auto("%(nano|vim)").unwrap()), vec!["app-editors/nano", "app-editors/vim"]);
+        assert!(names(&SearchQuery::new("editor")).is_empty());
+        assert_eq!(names(&SearchQuery::new("editor").with_description(true)), vec!["app-editors/vim"]);
+
+        let query = SearchQuery::new("vi").with_description(true);
+        let vim = index.entries.iter().find(|e| e.name == "vim").unwrap();
+        assert_eq!(query.name_ranges(vim), vec![(0, 2)]);
+        assert_eq!(query.description_ranges(vim), vec![(0, 2), (17, 19)]);
+        assert_eq!(highlight("vim", &query.name_ranges(vim)), "\x1b[1;32mvi\x1b[0mm");
+    }
+}
@@ -1,19 +1,286 @@
 use std::collections::HashMap;
 use std::fs;
+use std::future::Future;
 use std::os::unix::fs as unix_fs;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
 use crate::exception::InvalidData;
 use crate::atom::Atom;
+use crate::preserved_libs::PreservedLibsRegistry;
 use crate::profile::ProfileManager;
+use crate::vartree::VarTree;
 
-/// Package set types
-#[derive(Debug, Clone, PartialEq)]
-pub enum PackageSet {
+/// Eclasses whose packages build from a VCS checkout (@live-rebuild)
+pub const LIVE_ECLASSES: &[&str] = &["bzr", "cvs", "darcs", "git-r3", "golang-vcs", "mercurial", "subversion"];
+
+/// Whether an ebuild builds from a VCS checkout: it inherits a VCS eclass or sets
+/// PROPERTIES=live
+pub fn is_live_ebuild(content: &str) -> bool {
+    let source = crate::eclass::ShellSource::parse(content);
+    source.inherits.iter().any(|eclass| LIVE_ECLASSES.contains(&eclass.as_str()))
+        || source.value("PROPERTIES").is_some_and(|p| p.split_whitespace().any(|p| p == "live"))
+}
+
+/// Atoms of a set, resolved asynchronously
+pub type SetAtoms<'a> = Pin<Box<dyn Future<Output = Result<Vec<String>, InvalidData>> + Send + 'a>>;
+
+/// A set usable as @name; more can be added with PackageSetManager::register
+pub trait PackageSet: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    /// The set's atoms, resolved against the manager's root
+    fn atoms<'a>(&'a self, manager: &'a PackageSetManager) -> SetAtoms<'a>;
+    /// Whether installing the set records it in world_sets
+    fn world_candidate(&self) -> bool {
+        false
+    }
+}
+
+/// The sets Portage ships
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuiltinSet {
     World,
-    System,
     Selected,
+    Installed,
+    System,
     Profile,
-    Custom(String),
+    ModuleRebuild,
+    X11ModuleRebuild,
+    PreservedRebuild,
+    LiveRebuild,
+}
+
+impl BuiltinSet {
+    pub const ALL: [BuiltinSet; 9] = [
+        BuiltinSet::World,
+        BuiltinSet::Selected,
+        BuiltinSet::Installed,
+        BuiltinSet::System,
+        BuiltinSet::Profile,
+        BuiltinSet::ModuleRebuild,
+        BuiltinSet::X11ModuleRebuild,
+        BuiltinSet::PreservedRebuild,
+        BuiltinSet::LiveRebuild,
+    ];
+}
+
+impl PackageSet for BuiltinSet {
+    fn name(&self) -> &str {
+        match self {
+            BuiltinSet::World => "world",
+            BuiltinSet::Selected => "selected",
+            BuiltinSet::Installed => "installed",
+            BuiltinSet::System => "system",
+            BuiltinSet::Profile => "profile",
+            BuiltinSet::ModuleRebuild => "module-rebuild",
+            BuiltinSet::X11ModuleRebuild => "x11-module-rebuild",
+            BuiltinSet::PreservedRebuild => "preserved-rebuild",
+            BuiltinSet::LiveRebuild => "live-rebuild",
+        }
+    }
+
+    fn description(&self) -> &str {
+        match self {
+            BuiltinSet::World => "All user-installed packages",
+            BuiltinSet::Selected => "Packages explicitly selected for installation",
+            BuiltinSet::Installed => "Every installed package",
+            BuiltinSet::System => "Essential system packages required for basic operation",
+            BuiltinSet::Profile => "Packages defined in the current profile",
+            BuiltinSet::ModuleRebuild => "Packages installing kernel modules",
+            BuiltinSet::X11ModuleRebuild => "Packages installing X server modules",
+            BuiltinSet::PreservedRebuild => "Packages linking against preserved or missing libraries",
+            BuiltinSet::LiveRebuild => "Packages built from a VCS checkout that has changed upstream",
+        }
+    }
+
+    fn atoms<'a>(&'a self, manager: &'a PackageSetManager) -> SetAtoms<'a> {
+        Box::pin(async move {
+            match self {
+                BuiltinSet::World => manager.get_world_set_packages().await,
+                BuiltinSet::Selected => manager.get_selected_set_packages().await,
+                BuiltinSet::Installed => Ok(manager.installed_atoms(|_| true)),
+                BuiltinSet::System => manager.get_system_packages().await,
+                BuiltinSet::Profile => manager.get_profile_packages().await,
+                BuiltinSet::ModuleRebuild => Ok(manager.owner_atoms(&["/lib/modules/"], None)),
+                BuiltinSet::X11ModuleRebuild => Ok(manager.owner_atoms(
+                    &["/usr/lib/xorg/modules/", "/usr/lib64/xorg/modules/", "/usr/lib32/xorg/modules/"],
+                    Some("x11-base/xorg-server"),
+                )),
+                BuiltinSet::PreservedRebuild => manager.get_preserved_rebuild_packages(),
+                BuiltinSet::LiveRebuild => Ok(manager.live_atoms()),
+            }
+        })
+    }
+}
+
+/// A user set: a file of atoms, or a directory of such files. Lines starting with @
+/// pull in other sets and may use set arithmetic (@kde-@kde-games).
+#[derive(Debug, Clone)]
+pub struct FileSet {
+    pub name: String,
+    pub path: PathBuf,
+    pub world_candidate: bool,
+    description: String,
+}
+
+impl FileSet {
+    pub fn new(name: &str, path: &Path, world_candidate: bool) -> Self {
+        FileSet {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            world_candidate,
+            description: format!("User set from {}", path.display()),
+        }
+    }
+}
+
+impl PackageSet for FileSet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn atoms<'a>(&'a self, manager: &'a PackageSetManager) -> SetAtoms<'a> {
+        Box::pin(async move {
+            let mut atoms = Vec::new();
+            for line in read_set_lines(&self.path)? {
+                let more = match line.strip_prefix('@') {
+                    Some(expr) => manager.resolve_expression(expr).await?,
+                    None => vec![line],
+                };
+                for atom in more {
+                    if !atoms.contains(&atom) {
+                        atoms.push(atom);
+                    }
+                }
+            }
+            Ok(atoms)
+        })
+    }
+
+    fn world_candidate(&self) -> bool {
+        self.world_candidate
+    }
+}
+
+/// Entries of a set file, or of every file below a set directory
+fn read_set_lines(path: &Path) -> Result<Vec<String>, InvalidData> {
+    if path.is_dir() {
+        let mut lines = Vec::new();
+        for file in set_files(path) {
+            lines.extend(read_set_lines(&path.join(file))?);
+        }
+        return Ok(lines);
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|e| InvalidData::new(&format!("Failed to read set file {}: {}", path.display(), e), None))?;
+    Ok(content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Files below `dir` as sorted relative paths, skipping hidden and backup files
+fn set_files(dir: &Path) -> Vec<String> {
+    fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with('~') {
+                continue;
+            }
+            let relative = format!("{}{}", prefix, name);
+            if entry.path().is_dir() {
+                walk(&entry.path(), &format!("{}/", relative), files);
+            } else {
+                files.push(relative);
+            }
+        }
+    }
+    let mut files = Vec::new();
+    walk(dir, "", &mut files);
+    files.sort();
+    files
+}
+
+/// Sets defined by sets.conf sections. Only portage.sets.files.StaticFileSet is
+/// understood: `filename` names one set after the section, `multiset = true` makes a
+/// set of each file below `directory` (named by `name_pattern`, default `${name}`).
+pub fn parse_sets_conf(content: &str, root: &Path) -> Vec<FileSet> {
+    let mut sections: Vec<(String, HashMap<String, String>)> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((section.trim().to_string(), HashMap::new()));
+        } else if let Some((key, value)) = line.split_once('=')
+            && let Some((_, options)) = sections.last_mut()
+        {
+            options.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+
+    let root_str = root.to_string_lossy();
+    let root_str = root_str.trim_end_matches('/');
+    let conf_path = |value: &str| -> PathBuf {
+        if value.contains("%(") {
+            PathBuf::from(value.replace("%(PORTAGE_CONFIGROOT)s", root_str).replace("%(EROOT)s", root_str).replace("%(ROOT)s", root_str))
+        } else {
+            root.join(value.trim_start_matches('/'))
+        }
+    };
+    let boolean = |value: Option<&String>| value.is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "yes" | "true"));
+
+    let mut sets = Vec::new();
+    for (section, options) in sections {
+        let class = options.get("class").map(|c| c.as_str()).unwrap_or("");
+        if !class.ends_with("StaticFileSet") {
+            eprintln!("!!! Unsupported set class '{}' for [{}] in sets.conf", class, section);
+            continue;
+        }
+        let world_candidate = boolean(options.get("world-candidate"));
+        if boolean(options.get("multiset")) {
+            let Some(directory) = options.get("directory") else {
+                eprintln!("!!! Missing directory for multiset [{}] in sets.conf", section);
+                continue;
+            };
+            let directory = conf_path(directory);
+            let pattern = options.get("name_pattern").map(|p| p.as_str()).unwrap_or("${name}");
+            for file in set_files(&directory) {
+                let name = pattern.replace("${name}", &file).replace("$name", &file);
+                sets.push(FileSet::new(&name, &directory.join(&file), world_candidate));
+            }
+        } else if let Some(filename) = options.get("filename") {
+            sets.push(FileSet::new(&section, &conf_path(filename), world_candidate));
+        } else {
+            eprintln!("!!! Missing filename for [{}] in sets.conf", section);
+        }
+    }
+    sets
+}
+
+/// Split set arithmetic like `a-@b+@c` into names, each with the operator before it
+/// ('+' for the first). Only `-@`, `+@` and `/@` are operators, so set names may contain dashes.
+fn parse_set_expression(expr: &str) -> Vec<(char, String)> {
+    let mut operands = Vec::new();
+    let mut op = '+';
+    let mut rest = expr.trim_start_matches('@');
+    while let Some(at) = ["-@", "+@", "/@"].iter().filter_map(|sep| rest.find(sep)).min() {
+        operands.push((op, rest[..at].to_string()));
+        op = rest[at..].chars().next().unwrap_or('+');
+        rest = &rest[at + 2..];
+    }
+    operands.push((op, rest.to_string()));
+    operands
 }
 
 /// Information about a package set
@@ -106,28 +373,191 @@ pub struct PackageSetManager {
     sets_dir: PathBuf,
     profile_manager: ProfileManager,
     selected_manager: SelectedPackages,
+    sets: Vec<Box<dyn PackageSet>>,
+    /// Sets being resolved, to catch sets that include themselves
+    resolving: Mutex<Vec<String>>,
 }
 
 impl PackageSetManager {
     pub fn new(root: &str) -> Self {
         let root_path = Path::new(root);
-        Self {
+        let mut manager = Self {
             root: root.to_string(),
             sets_dir: root_path.join("etc/portage/sets"),
             profile_manager: ProfileManager::new(root),
             selected_manager: SelectedPackages::new(root),
+            sets: BuiltinSet::ALL.iter().map(|set| Box::new(*set) as Box<dyn PackageSet>).collect(),
+            resolving: Mutex::new(Vec::new()),
+        };
+        for set in manager.load_user_sets() {
+            manager.register(Box::new(set));
         }
+        manager
+    }
+
+    /// Every file below /etc/portage/sets, then the sets.conf definitions
+    fn load_user_sets(&self) -> Vec<FileSet> {
+        let mut sets: Vec<FileSet> = set_files(&self.sets_dir)
+            .into_iter()
+            .map(|file| FileSet::new(&file, &self.sets_dir.join(&file), true))
+            .collect();
+
+        let conf = Path::new(&self.root).join("etc/portage/sets.conf");
+        let files = if conf.is_dir() {
+            set_files(&conf).into_iter().filter(|f| f.ends_with(".conf")).map(|f| conf.join(f)).collect()
+        } else {
+            vec![conf]
+        };
+        for file in files {
+            if let Ok(content) = fs::read_to_string(&file) {
+                sets.extend(parse_sets_conf(&content, Path::new(&self.root)));
+            }
+        }
+        sets
+    }
+
+    /// Add a set; it takes precedence over a built-in or custom set of the same name
+    pub fn register(&mut self, set: Box<dyn PackageSet>) {
+        self.sets.retain(|existing| existing.name() != set.name());
+        self.sets.push(set);
+    }
+
+    fn registered(&self, set_name: &str) -> Option<&dyn PackageSet> {
+        self.sets.iter().find(|set| set.name() == set_name).map(|set| set.as_ref())
     }
 
     /// Resolve a set name to a list of package atoms
     pub async fn resolve_set(&self, set_name: &str) -> Result<Vec<String>, InvalidData> {
-        match set_name {
-            "world" => self.get_world_packages(),
-            "system" => self.get_system_packages().await,
-            "selected" => self.selected_manager.get_selected_packages(),
-            "profile" => self.get_profile_packages().await,
-            custom => self.get_custom_set(custom),
+        let Some(set) = self.registered(set_name) else {
+            return self.get_custom_set(set_name);
+        };
+        {
+            let mut resolving = self.resolving.lock().unwrap_or_else(|e| e.into_inner());
+            if resolving.iter().any(|name| name == set_name) {
+                return Err(InvalidData::new(&format!("Set @{} includes itself", set_name), None));
+            }
+            resolving.push(set_name.to_string());
+        }
+        let atoms = set.atoms(self).await;
+        self.resolving.lock().unwrap_or_else(|e| e.into_inner()).retain(|name| name != set_name);
+        atoms
+    }
+
+    /// Resolve a set name or set arithmetic, left to right: `a-@b` drops b's atoms,
+    /// `a/@b` keeps only atoms also in b and `a+@b` adds b's atoms
+    pub async fn resolve_expression(&self, expr: &str) -> Result<Vec<String>, InvalidData> {
+        let mut atoms: Vec<String> = Vec::new();
+        for (op, name) in parse_set_expression(expr) {
+            let other = self.resolve_set(&name).await?;
+            match op {
+                '-' => atoms.retain(|atom| !other.contains(atom)),
+                '/' => atoms.retain(|atom| other.contains(atom)),
+                _ => {
+                    for atom in other {
+                        if !atoms.contains(&atom) {
+                            atoms.push(atom);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(atoms)
+    }
+
+    /// Whether emerge records the set in world_sets when it's installed
+    pub fn is_world_candidate(&self, set_name: &str) -> bool {
+        self.registered(set_name).is_some_and(|set| set.world_candidate())
+    }
+
+    /// @selected: the world file, the legacy selected file and the sets in world_sets
+    pub async fn get_selected_set_packages(&self) -> Result<Vec<String>, InvalidData> {
+        let mut packages = self.get_world_packages()?;
+        let mut extend = |more: Vec<String>| {
+            for package in more {
+                if !packages.contains(&package) {
+                    packages.push(package);
+                }
+            }
+        };
+        extend(self.selected_manager.get_selected_packages()?);
+        for set in crate::world::WorldManager::new(&self.root).load_sets()? {
+            extend(self.resolve_expression(&set).await?);
+        }
+        Ok(packages)
+    }
+
+    /// cat/pkg:slot of installed packages accepted by `filter`
+    fn installed_atoms(&self, filter: impl Fn(&str) -> bool) -> Vec<String> {
+        let vartree = VarTree::new(&self.root);
+        let mut atoms = Vec::new();
+        for cpv in vartree.list_packages().into_iter().filter(|cpv| filter(cpv)) {
+            let Some(cp) = crate::versions::cpv_getkey(&cpv) else {
+                continue;
+            };
+            let atom = match vartree.slot(&cpv) {
+                Some(slot) => format!("{}:{}", cp, slot.split('/').next().unwrap_or(&slot)),
+                None => cp,
+            };
+            if !atoms.contains(&atom) {
+                atoms.push(atom);
+            }
+        }
+        atoms
+    }
+
+    /// Installed packages with files under any of `prefixes`, except `exclude`
+    fn owner_atoms(&self, prefixes: &[&str], exclude: Option<&str>) -> Vec<String> {
+        let vartree = VarTree::new(&self.root);
+        self.installed_atoms(|cpv| {
+            crate::versions::cpv_getkey(cpv).as_deref() != exclude
+                && vartree.contents(cpv).iter().any(|entry| {
+                    !matches!(entry, crate::contents::ContentsEntry::Dir { .. })
+                        && prefixes.iter().any(|prefix| entry.path().starts_with(prefix))
+                })
+        })
+    }
+
+    /// Installed packages inheriting a VCS eclass or with PROPERTIES=live, leaving out
+    /// git-r3 checkouts whose upstream still has the commit they were built from
+    fn live_atoms(&self) -> Vec<String> {
+        let vartree = VarTree::new(&self.root);
+        self.installed_atoms(|cpv| {
+            let words = |key: &str| vartree.entry(cpv, key).unwrap_or_default();
+            let live = words("INHERITED").split_whitespace().any(|eclass| LIVE_ECLASSES.contains(&eclass))
+                || words("PROPERTIES").split_whitespace().any(|p| p == "live");
+            if !live {
+                return false;
+            }
+            let record: HashMap<String, String> = crate::git_r3::RECORD_KEYS.iter()
+                .filter_map(|key| vartree.entry(cpv, key).map(|value| (key.to_string(), value)))
+                .collect();
+            record.is_empty() || crate::git_r3::upstream_changed(&record)
+        })
+    }
+
+    /// Get packages in @preserved-rebuild: consumers of preserved libraries, and owners of
+    /// objects linking against libraries that are gone
+    pub fn get_preserved_rebuild_packages(&self) -> Result<Vec<String>, InvalidData> {
+        let registry = PreservedLibsRegistry::load(&self.root)?;
+        let vartree = VarTree::new(&self.root);
+        let mut cpvs = if registry.has_entries() { registry.consumers(&vartree) } else { Vec::new() };
+        for cpv in crate::linkage::broken_packages(&crate::linkage::find_broken(&vartree)) {
+            if !cpvs.contains(&cpv) {
+                cpvs.push(cpv);
+            }
         }
+        Ok(cpvs.iter().map(|cpv| format!("={}", cpv)).collect())
+    }
+
+    /// @world as emerge updates it: @selected, @system and @profile
+    pub async fn get_world_set_packages(&self) -> Result<Vec<String>, InvalidData> {
+        let mut packages = self.get_selected_set_packages().await?;
+        for package in self.get_system_packages().await?.into_iter().chain(self.get_profile_packages().await?) {
+            if !packages.contains(&package) {
+                packages.push(package);
+            }
+        }
+        Ok(packages)
     }
 
     /// Get packages in @world set
@@ -231,6 +661,18 @@ impl PackageSetManager {
         Ok(all_packages)
     }
 
+    /// @system for depclean: a profile that cannot be read or lists no system packages is
+    /// an error rather than an empty set, which would leave all of @system unprotected
+    pub async fn require_system_packages(&self) -> Result<Vec<String>, InvalidData> {
+        self.profile_manager.get_current_profile().await
+            .map_err(|e| InvalidData::new(&format!("Cannot resolve the profile: {}", e), None))?;
+        let system = self.get_system_packages().await?;
+        if system.is_empty() {
+            return Err(InvalidData::new("The profile lists no @system packages", None));
+        }
+        Ok(system)
+    }
+
     /// Parse a packages file content (only required packages with *)
     fn parse_packages_file(&self, content: &str) -> Result<Vec<String>, InvalidData> {
         let mut packages = Vec::new();
@@ -375,38 +817,30 @@ impl PackageSetManager {
 
     /// List all available sets (built-in + custom)
     pub fn list_all_sets(&self) -> Result<Vec<String>, InvalidData> {
-        let mut sets = vec![
-            "world".to_string(),
-            "system".to_string(),
-            "selected".to_string(),
-            "profile".to_string(),
-        ];
+        let mut sets: Vec<String> = self.sets.iter().map(|set| set.name().to_string()).collect();
 
-        // Add custom sets
-        sets.extend(self.list_custom_sets()?);
+        // Add custom sets created since the manager was loaded
+        for set in self.list_custom_sets()? {
+            if !sets.contains(&set) {
+                sets.push(set);
+            }
+        }
 
         Ok(sets)
     }
 
     /// Check if a set exists
     pub fn set_exists(&self, set_name: &str) -> bool {
-        match set_name {
-            "world" | "system" | "selected" | "profile" => true,
-            custom => self.sets_dir.join(custom).exists(),
-        }
+        self.registered(set_name).is_some() || self.sets_dir.join(set_name).exists()
     }
 
     /// Get set information (description, etc.)
     pub async fn get_set_info(&self, set_name: &str) -> Result<SetInfo, InvalidData> {
         let packages = self.resolve_set(set_name).await?;
 
-        let description = match set_name {
-            "world" => "All user-installed packages",
-            "system" => "Essential system packages required for basic operation",
-            "selected" => "Packages explicitly selected for installation",
-            "profile" => "Packages defined in the current profile",
-            _ => "Custom user-defined package set",
-        };
+        let description = self.registered(set_name)
+            .map(|set| set.description())
+            .unwrap_or("Custom user-defined package set");
 
         Ok(SetInfo {
             name: set_name.to_string(),
@@ -422,10 +856,9 @@ pub async fn resolve_targets(targets: &[String], root: &str) -> Result<Vec<Strin
     let mut resolved = Vec::new();
 
     for target in targets {
-        if target.starts_with('@') {
-            // It's a set
-            let set_name = &target[1..];
-            let packages = set_manager.resolve_set(set_name).await?;
+        if let Some(expression) = target.strip_prefix('@') {
+            // It's a set, or set arithmetic
+            let packages = set_manager.resolve_expression(expression).await?;
             resolved.extend(packages);
         } else {
             // Regular package
@@ -461,6 +894,38 @@ mod tests {
         assert!(world_packages.contains(&"dev-lang/rust".to_string()));
     }
 
+    #[tokio::test]
+    async fn test_world_set_includes_system() {
+        let temp_dir = TempDir::new().unwrap();
+        let profile = temp_dir.path().join("profile");
+        fs::create_dir_all(&profile).unwrap();
+        fs::write(profile.join("packages"), "*sys-apps/baselayout\n*app-misc/hello\n").unwrap();
+        fs::create_dir_all(temp_dir.path().join("etc/portage")).unwrap();
+        std::os::unix::fs::symlink(&profile, temp_dir.path().join("etc/portage/make.profile")).unwrap();
+
+        let set_manager = PackageSetManager::new(temp_dir.path().to_str().unwrap());
+        set_manager.add_to_world(&["app-misc/hello".to_string()]).unwrap();
+        assert_eq!(set_manager.resolve_set("world").await.unwrap(), vec!["app-misc/hello", "sys-apps/baselayout"]);
+        assert_eq!(set_manager.get_world_packages().unwrap(), vec!["app-misc/hello"]);
+    }
+
+    #[tokio::test]
+    async fn test_require_system_packages() {
+        let temp_dir = TempDir::new().unwrap();
+        let set_manager = PackageSetManager::new(temp_dir.path().to_str().unwrap());
+        // No make.profile: @system is unknown, not empty
+        assert!(set_manager.get_system_packages().await.unwrap().is_empty());
+        assert!(set_manager.require_system_packages().await.is_err());
+
+        let profile = temp_dir.path().join("profile");
+        fs::create_dir_all(&profile).unwrap();
+        fs::create_dir_all(temp_dir.path().join("etc/portage")).unwrap();
+        std::os::unix::fs::symlink(&profile, temp_dir.path().join("etc/portage/make.profile")).unwrap();
+        assert!(set_manager.require_system_packages().await.is_err());
+        fs::write(profile.join("packages"), "*sys-libs/glibc\n").unwrap();
+        assert_eq!(set_manager.require_system_packages().await.unwrap(), vec!["sys-libs/glibc"]);
+    }
+
     #[tokio::test]
     async fn test_custom_sets() {
         let temp_dir = TempDir::new().unwrap();
@@ -560,6 +1025,118 @@ mod tests {
         assert!(!set_manager.set_exists("nonexistent"));
     }
 
+    #[tokio::test]
+    async fn test_builtin_installed_sets() {
+        let temp_dir = TempDir::new().unwrap();
+        let vdb = temp_dir.path().join("var/db/pkg");
+        let install = |cpv: &str, slot: &str, files: &[(&str, &str)]| {
+            let dir = vdb.join(cpv);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("SLOT"), format!("{}\n", slot)).unwrap();
+            let contents: String = files.iter().map(|(kind, path)| match *kind {
+                "dir" => format!("dir {}\n", path),
+                _ => format!("obj {} d41d8cd98f00b204e9800998ecf8427e 0\n", path),
+            }).collect();
+            fs::write(dir.join("CONTENTS"), contents).unwrap();
+        };
+        install("sys-fs/zfs-kmod-2.2.0", "0", &[("obj", "/lib/modules/6.6.0/extra/zfs.ko")]);
+        install("x11-drivers/xf86-input-libinput-1.4.0", "0", &[("obj", "/usr/lib64/xorg/modules/input/libinput_drv.so")]);
+        install("x11-base/xorg-server-21.1.11", "0/21.1.11", &[("obj", "/usr/lib64/xorg/modules/libglamoregl.so")]);
+        install("app-editors/neovim-9999", "0", &[("dir", "/lib/modules")]);
+        fs::write(vdb.join("app-editors/neovim-9999/INHERITED"), "cmake git-r3 lua-single\n").unwrap();
+        // A checkout whose upstream has not moved since it was built
+        let upstream = temp_dir.path().join("upstream");
+        let git = |args: &[&str]| assert!(std::process::Command::new("git").args(args).current_dir(&upstream)
+            .env("GIT_AUTHOR_NAME", "dev").env("GIT_AUTHOR_EMAIL", "dev@example.org")
+            .env("GIT_COMMITTER_NAME", "dev").env("GIT_COMMITTER_EMAIL", "dev@example.org")
+            .status().unwrap().success());
+        fs::create_dir_all(&upstream).unwrap();
+        git(&["init", "--quiet"]);
+        git(&["commit", "--quiet", "--allow-empty", "-m", "initial"]);
+        let uri = format!("file://{}", upstream.display());
+        install("app-misc/unchanged-9999", "0", &[]);
+        fs::write(vdb.join("app-misc/unchanged-9999/INHERITED"), "git-r3\n").unwrap();
+        fs::write(vdb.join("app-misc/unchanged-9999/EGIT_REPO_URI"), format!("{}\n", uri)).unwrap();
+        fs::write(vdb.join("app-misc/unchanged-9999/EGIT_VERSION"), crate::git_r3::remote_head(&uri, None).unwrap()).unwrap();
+
+        let set_manager = PackageSetManager::new(temp_dir.path().to_str().unwrap());
+        assert_eq!(set_manager.resolve_set("installed").await.unwrap(), vec![
+            "app-editors/neovim:0", "app-misc/unchanged:0", "sys-fs/zfs-kmod:0", "x11-base/xorg-server:0", "x11-drivers/xf86-input-libinput:0",
+        ]);
+        assert_eq!(set_manager.resolve_set("module-rebuild").await.unwrap(), vec!["sys-fs/zfs-kmod:0"]);
+        assert_eq!(set_manager.resolve_set("x11-module-rebuild").await.unwrap(), vec!["x11-drivers/xf86-input-libinput:0"]);
+        assert_eq!(set_manager.resolve_set("live-rebuild").await.unwrap(), vec!["app-editors/neovim:0"]);
+        assert!(is_live_ebuild("EAPI=8\ninherit git-r3 meson\nEGIT_REPO_URI=\"https://example.org/foo.git\"\n"));
+        assert!(!is_live_ebuild("EAPI=8\ninherit meson\n"));
+    }
+
+    #[tokio::test]
+    async fn test_register_set() {
+        struct Toolchain;
+        impl PackageSet for Toolchain {
+            fn name(&self) -> &str {
+                "toolchain"
+            }
+            fn description(&self) -> &str {
+                "Compilers and binutils"
+            }
+            fn atoms<'a>(&'a self, _manager: &'a PackageSetManager) -> SetAtoms<'a> {
+                Box::pin(async { Ok(vec!["sys-devel/gcc".to_string(), "sys-devel/binutils".to_string()]) })
+            }
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut set_manager = PackageSetManager::new(temp_dir.path().to_str().unwrap());
+        assert!(!set_manager.set_exists("toolchain"));
+        set_manager.register(Box::new(Toolchain));
+        assert!(set_manager.set_exists("toolchain"));
+        assert_eq!(set_manager.resolve_set("toolchain").await.unwrap(), vec!["sys-devel/gcc", "sys-devel/binutils"]);
+        assert_eq!(set_manager.get_set_info("toolchain").await.unwrap().description, "Compilers and binutils");
+    }
+
+    #[tokio::test]
+    async fn test_user_sets_and_arithmetic() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let sets_dir = root.join("etc/portage/sets");
+        fs::create_dir_all(sets_dir.join("desktop")).unwrap();
+        fs::write(sets_dir.join("desktop/editors"), "# editors\napp-editors/vim\napp-editors/emacs\n").unwrap();
+        fs::write(sets_dir.join("desktop/audio"), "media-sound/pulseaudio\n@desktop/editors\n").unwrap();
+        fs::write(sets_dir.join("loop"), "@loop\n").unwrap();
+        fs::create_dir_all(root.join("srv/sets/toolchain")).unwrap();
+        fs::write(root.join("srv/sets/toolchain/gcc"), "sys-devel/gcc\n").unwrap();
+        fs::write(root.join("srv/sets/toolchain/binutils"), "sys-devel/binutils\n").unwrap();
+        fs::write(root.join("etc/portage/sets.conf"), concat!(
+            "[toolchain]\nclass = portage.sets.files.StaticFileSet\nfilename = /srv/sets/toolchain\n\n",
+            "[team]\nclass = portage.sets.files.StaticFileSet\nmultiset = true\n",
+            "directory = %(PORTAGE_CONFIGROOT)s/srv/sets/toolchain\nname_pattern = team-${name}\nworld-candidate = True\n\n",
+            "[security]\nclass = portage.sets.security.NewAffectedSet\n",
+        )).unwrap();
+
+        let set_manager = PackageSetManager::new(root.to_str().unwrap());
+        assert_eq!(set_manager.resolve_set("desktop/audio").await.unwrap(),
+            vec!["media-sound/pulseaudio", "app-editors/vim", "app-editors/emacs"]);
+        assert_eq!(set_manager.resolve_set("toolchain").await.unwrap(), vec!["sys-devel/binutils", "sys-devel/gcc"]);
+        assert_eq!(set_manager.resolve_set("team-gcc").await.unwrap(), vec!["sys-devel/gcc"]);
+        assert!(set_manager.resolve_set("loop").await.is_err());
+        assert!(!set_manager.set_exists("security"));
+
+        assert!(set_manager.is_world_candidate("desktop/editors"));
+        assert!(set_manager.is_world_candidate("team-gcc"));
+        assert!(!set_manager.is_world_candidate("toolchain"));
+        assert!(!set_manager.is_world_candidate("system"));
+
+        assert_eq!(set_manager.resolve_expression("desktop/audio-@desktop/editors").await.unwrap(), vec!["media-sound/pulseaudio"]);
+        assert_eq!(set_manager.resolve_expression("@desktop/audio/@desktop/editors+@team-gcc").await.unwrap(),
+            vec!["app-editors/vim", "app-editors/emacs", "sys-devel/gcc"]);
+        assert_eq!(parse_set_expression("x11-module-rebuild-@module-rebuild"),
+            vec![('+', "x11-module-rebuild".to_string()), ('-', "module-rebuild".to_string())]);
+
+        // Sets in world_sets are part of @selected
+        crate::world::WorldManager::new(root.to_str().unwrap()).add_sets(&["@toolchain".to_string()]).unwrap();
+        assert_eq!(set_manager.resolve_set("selected").await.unwrap(), vec!["sys-devel/binutils", "sys-devel/gcc"]);
+    }
+
     #[tokio::test]
     async fn test_parse_packages_file_required() {
         let set_manager = PackageSetManager::new("/");
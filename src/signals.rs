@@ -0,0 +1,185 @@
+// signals.rs -- SIGINT/SIGTERM handling: stop the merge, kill the build process groups and
+// remove what they leave behind before exiting
+
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Exit status after an interrupt, as for a process killed by SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static MERGING: AtomicUsize = AtomicUsize::new(0);
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { groups: Vec::new(), paths: Vec::new() });
+
+/// What an interrupt has to deal with
+struct Registry {
+    /// Process groups of running build commands, by leader pid
+    groups: Vec<u32>,
+    /// Work directories and lock files of the builds and fetches in progress
+    paths: Vec<PathBuf>,
+}
+
+impl Registry {
+    fn kill_groups(&self) {
+        for &pid in &self.groups {
+            // SAFETY: kill has no memory effects; a negative pid signals the whole group
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+            }
+        }
+    }
+
+    fn remove_paths(&mut self) {
+        for path in self.paths.drain(..) {
+            let _ = if path.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+        }
+    }
+}
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Whether SIGINT or SIGTERM has been received
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Handle SIGINT and SIGTERM: the first one kills the running build commands and, while a
+/// merge is in progress, lets it wind down and save its resume state; otherwise, or on a
+/// second signal, clean up and exit right away
+pub fn install() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (Ok(mut interrupt), Ok(mut terminate)) = (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) else {
+        eprintln!("Warning: Failed to install signal handlers");
+        return;
+    };
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        eprintln!("\nExiting on signal, stopping running builds...");
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        registry().kill_groups();
+        if MERGING.load(Ordering::SeqCst) > 0 {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        exit_interrupted();
+    });
+}
+
+/// Remove the registered work directories and lock files, then exit with EXIT_INTERRUPTED
+pub fn exit_interrupted() -> ! {
+    registry().remove_paths();
+    std::process::exit(EXIT_INTERRUPTED)
+}
+
+/// Held while a merge runs, so an interrupt waits for it to stop instead of exiting at once
+pub struct MergeGuard;
+
+impl MergeGuard {
+    pub fn enter() -> Self {
+        MERGING.fetch_add(1, Ordering::SeqCst);
+        MergeGuard
+    }
+}
+
+impl Drop for MergeGuard {
+    fn drop(&mut self) {
+        MERGING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A running command's process group, killed on interrupt until dropped
+pub struct ProcessGroup(u32);
+
+impl ProcessGroup {
+    pub fn register(pid: u32) -> Self {
+        let mut registry = registry();
+        registry.groups.push(pid);
+        // Started just as the interrupt came in
+        if interrupted() {
+            Registry { groups: vec![pid], paths: Vec::new() }.kill_groups();
+        }
+        ProcessGroup(pid)
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        registry().groups.retain(|&pid| pid != self.0);
+    }
+}
+
+/// A path removed if the run is interrupted while this is alive
+pub struct RemoveOnInterrupt(PathBuf);
+
+impl RemoveOnInterrupt {
+    pub fn register(path: &Path) -> Self {
+        registry().paths.push(path.to_path_buf());
+        RemoveOnInterrupt(path.to_path_buf())
+    }
+}
+
+impl Drop for RemoveOnInterrupt {
+    fn drop(&mut self) {
+        let mut registry = registry();
+        registry.paths.retain(|path| *path != self.0);
+        if interrupted() {
+            Registry { groups: Vec::new(), paths: vec![self.0.clone()] }.remove_paths();
+        }
+    }
+}
+
+/// Run `command` in a process group of its own with stdin closed, so an interrupt reaches
+/// everything it starts
+pub fn isolate(command: &mut std::process::Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0).stdin(Stdio::null());
+}
+
+/// Like tokio's `Command::output`, with the command in its own process group that an
+/// interrupt kills
+pub async fn output(command: &mut tokio::process::Command) -> std::io::Result<Output> {
+    command.process_group(0).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let child = command.spawn()?;
+    let _group = child.id().map(ProcessGroup::register);
+    child.wait_with_output().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_registry_kills_and_removes() {
+        let temp = TempDir::new().unwrap();
+        let workdir = temp.path().join("work");
+        std::fs::create_dir_all(workdir.join("temp")).unwrap();
+        let lock = temp.path().join("foo.portage_lockfile");
+        std::fs::write(&lock, "").unwrap();
+
+        // The shell and the sleep it starts share the group
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "sleep 30; true"]);
+        isolate(&mut command);
+        let mut child = command.spawn().unwrap();
+
+        let mut registry = Registry { groups: vec![child.id()], paths: vec![workdir.clone(), lock.clone()] };
+        let started = std::time::Instant::now();
+        registry.kill_groups();
+        assert!(!child.wait().unwrap().success());
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+        registry.remove_paths();
+        assert!(!workdir.exists() && !lock.exists());
+        assert!(registry.paths.is_empty());
+    }
+}
@@ -54,6 +54,7 @@ impl SyncBackend for CvsSync {
             success: true,
             message: format!("Successfully created {} via cvs", repo.name),
             changes: true,
+            stats: None,
         })
     }
 
@@ -83,6 +84,7 @@ impl SyncBackend for CvsSync {
             success: true,
             message: format!("Successfully synced {} via cvs", repo.name),
             changes,
+            stats: None,
         })
     }
 }
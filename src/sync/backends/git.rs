@@ -2,12 +2,162 @@ use crate::sync::{SyncBackend, SyncError, SyncResult};
 use tokio::process::Command;
 use std::path::Path;
 
+/// Submodules `--sync-submodule` can restrict a sync to, with their paths
+pub const SUBMODULES: &[(&str, &str)] = &[("glsa", "metadata/glsa"), ("news", "metadata/news"), ("profiles", "profiles")];
+
+/// Split a `uri#branch` sync-uri into the URI and branch
+pub fn split_branch(sync_uri: &str) -> (&str, Option<&str>) {
+    match sync_uri.rsplit_once('#') {
+        Some((uri, branch)) if !branch.is_empty() => (uri, Some(branch)),
+        _ => (sync_uri, None),
+    }
+}
+
+/// History to keep: sync-depth, 1 when unset, everything for 0
+fn depth(repo: &crate::porttree::Repository) -> Option<i32> {
+    match repo.sync_depth {
+        Some(depth) if depth <= 0 => None,
+        Some(depth) => Some(depth),
+        None => Some(1),
+    }
+}
+
+/// Whether git's complaint means the clone itself is broken, not the network
+fn is_corruption(stderr: &str) -> bool {
+    ["corrupt", "bad object", "broken link", "not a git repository", "unable to read", "loose object", "invalid object",
+        "invalid sha1 pointer", "missing blob", "missing tree", "missing commit"]
+        .iter()
+        .any(|marker| stderr.contains(marker))
+}
+
+/// Complaints that say nothing about the objects: git refusing the repository or the
+/// user, which a fresh clone would not fix
+fn is_refusal(stderr: &str) -> bool {
+    ["dubious ownership", "safe.directory", "Permission denied", "Expected git repo version"]
+        .iter()
+        .any(|marker| stderr.contains(marker))
+}
+
+/// What git fsck found broken in a clone, or None when git could not check it or found
+/// it sound
+async fn diagnose_corruption(repo_path: &Path) -> Option<String> {
+    let output = git(repo_path, &["fsck", "--no-progress", "--no-dangling"]).await.ok()?;
+    if output.status.success() {
+        return None;
+    }
+    let report = format!("{}{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    if is_refusal(&report) || !is_corruption(&report) {
+        return None;
+    }
+    report.lines().find(|line| is_corruption(line)).map(|line| line.trim().to_string())
+}
+
+async fn git(repo_path: &Path, args: &[&str]) -> Result<std::process::Output, SyncError> {
+    Ok(Command::new("git")
+        .args(args)
+        .current_dir(repo_path)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .await?)
+}
+
+/// Run git, failing with its stderr
+async fn git_checked(repo_path: &Path, args: &[&str]) -> Result<String, SyncError> {
+    let output = git(repo_path, args).await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(SyncError::Command(format!("git {} failed: {}", args[0], stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+async fn head(repo_path: &Path) -> Option<String> {
+    git_checked(repo_path, &["rev-parse", "--verify", "--quiet", "HEAD^{commit}"]).await.ok()
+}
+
 pub struct GitSync;
 
 impl GitSync {
     pub fn new() -> Self {
         GitSync
     }
+
+    /// Check out the submodules of the tree, only the --sync-submodule ones when given
+    async fn update_submodules(&self, repo: &crate::porttree::Repository) -> Result<bool, SyncError> {
+        let repo_path = Path::new(&repo.location);
+        if !repo_path.join(".gitmodules").exists() {
+            return Ok(false);
+        }
+        let depth = depth(repo).map(|d| d.to_string());
+        let mut args = vec!["submodule", "--quiet", "update", "--init", "--recursive"];
+        if let Some(depth) = &depth {
+            args.extend(["--depth", depth]);
+        }
+        args.push("--");
+        args.extend(repo.sync_submodules.iter().map(|path| path.as_str()));
+        git_checked(repo_path, &args).await?;
+        Ok(true)
+    }
+
+    /// Fetch and fast-forward an existing clone, reshaping its history to sync-depth
+    async fn update(&self, repo: &crate::porttree::Repository, notes: &mut Vec<String>) -> Result<bool, SyncError> {
+        let repo_path = Path::new(&repo.location);
+        let before = head(repo_path).await
+            .ok_or_else(|| SyncError::Repository(format!("{} has no valid HEAD: corrupt clone", repo.location)))?;
+        let shallow = git_checked(repo_path, &["rev-parse", "--is-shallow-repository"]).await? == "true";
+        let (_, branch) = split_branch(repo.sync_uri.as_deref().unwrap_or(""));
+
+        let depth_arg = depth(repo).map(|d| format!("--depth={}", d));
+        let mut args = vec!["fetch", "--quiet"];
+        match &depth_arg {
+            Some(depth_arg) => args.push(depth_arg),
+            None if shallow => args.push("--unshallow"),
+            None => {}
+        }
+        args.push("origin");
+        let refspec = branch.map(|b| format!("+refs/heads/{}:refs/remotes/origin/{}", b, b));
+        args.extend(refspec.as_deref());
+        git_checked(repo_path, &args).await?;
+
+        let upstream = branch.map_or_else(|| "@{u}".to_string(), |b| format!("refs/remotes/origin/{}", b));
+        if depth_arg.is_some() {
+            // Shallow history cannot always fast-forward; the tree follows upstream
+            git_checked(repo_path, &["reset", "--quiet", "--merge", &upstream]).await?;
+        } else {
+            let output = git(repo_path, &["merge", "--ff-only", "--quiet", &upstream]).await?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if stderr.contains("diverged") || stderr.contains("Not possible to fast-forward") {
+                    return Err(SyncError::Repository(
+                        format!("Repository has diverged from upstream: {}", repo.name)
+                    ));
+                }
+                return Err(SyncError::Command(format!("git merge failed: {}", stderr.trim())));
+            }
+        }
+
+        match (depth(repo), shallow) {
+            (Some(depth), false) => {
+                // Drop the history the shallow fetch no longer references
+                git_checked(repo_path, &["reflog", "expire", "--expire=all", "--all"]).await?;
+                git_checked(repo_path, &["gc", "--quiet", "--prune=all"]).await?;
+                notes.push(format!("converted to a shallow clone of depth {}", depth));
+            }
+            (None, true) => notes.push("fetched the full history".to_string()),
+            _ => {}
+        }
+        Ok(head(repo_path).await.as_deref() != Some(before.as_str()))
+    }
+}
+
+fn result(repo: &crate::porttree::Repository, action: &str, notes: &[String], changes: bool) -> SyncResult {
+    let notes = if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) };
+    SyncResult {
+        success: true,
+        message: format!("Successfully {} {} via git{}", action, repo.name, notes),
+        changes,
+        stats: None,
+    }
 }
 
 #[async_trait::async_trait]
@@ -34,35 +184,24 @@ impl SyncBackend for GitSync {
         let sync_uri = repo.sync_uri.as_deref().ok_or_else(|| {
             SyncError::Repository("No sync URI configured for git repository".to_string())
         })?;
+        let (uri, branch) = split_branch(sync_uri);
 
-        let mut clone_cmd = Command::new("git");
-        clone_cmd.arg("clone");
-        
-        if let Some(depth) = repo.sync_depth {
-            if depth > 0 {
-                clone_cmd.arg("--depth").arg(depth.to_string());
-            }
-        } else {
-            clone_cmd.arg("--depth").arg("1");
+        let depth = depth(repo).map(|d| d.to_string());
+        let mut args = vec!["clone", "--quiet"];
+        if let Some(depth) = &depth {
+            args.extend(["--depth", depth]);
         }
-
-        clone_cmd.arg("--quiet")
-            .arg(sync_uri)
-            .arg(".")
-            .current_dir(repo_path);
-
-        let output = clone_cmd.output().await?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(SyncError::Command(format!("git clone failed: {}", stderr)));
+        if let Some(branch) = branch {
+            args.extend(["--branch", branch]);
         }
+        args.extend(["--", uri, "."]);
+        git_checked(repo_path, &args).await?;
 
-        Ok(SyncResult {
-            success: true,
-            message: format!("Successfully cloned {}", repo.name),
-            changes: true,
-        })
+        let mut notes = Vec::new();
+        if self.update_submodules(repo).await? {
+            notes.push("checked out submodules".to_string());
+        }
+        Ok(result(repo, "cloned", &notes, true))
     }
 
     async fn sync(&self, repo: &crate::porttree::Repository) -> Result<SyncResult, SyncError> {
@@ -72,43 +211,41 @@ impl SyncBackend for GitSync {
             return self.new_repo(repo).await;
         }
 
-        let mut fetch_cmd = Command::new("git");
-        fetch_cmd.arg("fetch")
-            .arg("--quiet")
-            .current_dir(repo_path);
-
-        let output = fetch_cmd.output().await?;
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(SyncError::Command(format!("git fetch failed: {}", stderr)));
-        }
-
-        let mut merge_cmd = Command::new("git");
-        merge_cmd.arg("merge")
-            .arg("--ff-only")
-            .arg("--quiet")
-            .arg("@{u}")
-            .current_dir(repo_path);
-
-        let merge_output = merge_cmd.output().await?;
-        
-        let changes = !merge_output.stdout.is_empty() || !merge_output.stderr.is_empty();
-        
-        if !merge_output.status.success() {
-            let stderr = String::from_utf8_lossy(&merge_output.stderr);
-            if stderr.contains("diverged") {
-                return Err(SyncError::Repository(
-                    format!("Repository has diverged from upstream: {}", repo.name)
-                ));
+        let mut notes = Vec::new();
+        let changes = match self.update(repo, &mut notes).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                // Only a clone git fsck finds broken is replaced, and the old one is kept
+                // aside until the fresh clone is there
+                let Some(diagnosis) = diagnose_corruption(repo_path).await else {
+                    return Err(e);
+                };
+                let name = repo_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                let aside = repo_path.with_file_name(format!(".{}.corrupt", name));
+                if aside.exists() {
+                    tokio::fs::remove_dir_all(&aside).await?;
+                }
+                tokio::fs::rename(repo_path, &aside).await?;
+                return match self.new_repo(repo).await {
+                    Ok(cloned) => {
+                        let _ = tokio::fs::remove_dir_all(&aside).await;
+                        Ok(SyncResult {
+                            message: format!("{} after the old clone was found corrupt ({})", cloned.message, diagnosis),
+                            ..cloned
+                        })
+                    }
+                    Err(clone_error) => {
+                        let _ = tokio::fs::remove_dir_all(repo_path).await;
+                        tokio::fs::rename(&aside, repo_path).await?;
+                        Err(clone_error)
+                    }
+                };
             }
-            return Err(SyncError::Command(format!("git merge failed: {}", stderr)));
+        };
+        if self.update_submodules(repo).await? {
+            notes.push("updated submodules".to_string());
         }
-
-        Ok(SyncResult {
-            success: true,
-            message: format!("Successfully synced {} via git", repo.name),
-            changes,
-        })
+        Ok(result(repo, "synced", &notes, changes))
     }
 }
 
@@ -144,19 +281,23 @@ mod tests {
         assert!(sync.exists(temp_dir.path()).await);
     }
 
-    #[tokio::test]
-    async fn test_new_repo_no_uri() {
-        let temp_dir = TempDir::new().unwrap();
-        let sync = GitSync::new();
-        
-        let repo = Repository {
+    fn repo(location: &Path, sync_uri: Option<String>, sync_depth: Option<i32>) -> Repository {
+        Repository {
             name: "test".to_string(),
-            location: temp_dir.path().to_str().unwrap().to_string(),
+            location: location.to_str().unwrap().to_string(),
             sync_type: Some("git".to_string()),
-            sync_uri: None,
+            sync_uri,
             auto_sync: true,
-            sync_depth: None,
+            sync_depth,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: crate::porttree::SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -165,7 +306,14 @@ mod tests {
             },
             eclass_cache: HashMap::new(),
             metadata_cache: HashMap::new(),
-        };
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_repo_no_uri() {
+        let temp_dir = TempDir::new().unwrap();
+        let sync = GitSync::new();
+        let repo = repo(temp_dir.path(), None, None);
 
         let result = sync.new_repo(&repo).await;
         assert!(result.is_err());
@@ -176,4 +324,85 @@ mod tests {
             _ => panic!("Expected Repository error"),
         }
     }
+
+    fn run(dir: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "dev").env("GIT_AUTHOR_EMAIL", "dev@example.org")
+            .env("GIT_COMMITTER_NAME", "dev").env("GIT_COMMITTER_EMAIL", "dev@example.org")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn commit(upstream: &Path, content: &str) {
+        std::fs::write(upstream.join("profiles.desc"), content).unwrap();
+        run(upstream, &["add", "profiles.desc"]);
+        run(upstream, &["commit", "--quiet", "-m", content]);
+    }
+
+    #[test]
+    fn test_split_branch() {
+        assert_eq!(split_branch("https://example.org/guru.git#dev"), ("https://example.org/guru.git", Some("dev")));
+        assert_eq!(split_branch("https://example.org/guru.git"), ("https://example.org/guru.git", None));
+        assert!(is_corruption("error: object file .git/objects/ab/cd is empty\nfatal: loose object abcd is corrupt"));
+        assert!(!is_corruption("fatal: unable to access 'https://example.org/': Could not resolve host"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_depth_branch_and_recovery() {
+        let temp = TempDir::new().unwrap();
+        let upstream = temp.path().join("upstream");
+        std::fs::create_dir_all(&upstream).unwrap();
+        run(&upstream, &["init", "--quiet", "-b", "master"]);
+        commit(&upstream, "one");
+        commit(&upstream, "two");
+        run(&upstream, &["checkout", "--quiet", "-b", "stable"]);
+        commit(&upstream, "stable");
+        run(&upstream, &["checkout", "--quiet", "master"]);
+        let uri = format!("file://{}", upstream.display());
+
+        // A full clone of the branch in the sync-uri fragment
+        let location = temp.path().join("repo");
+        let sync = GitSync::new();
+        let full = repo(&location, Some(format!("{}#stable", uri)), Some(0));
+        let result = sync.sync(&full).await.unwrap();
+        assert!(result.changes);
+        assert_eq!(std::fs::read_to_string(location.join("profiles.desc")).unwrap(), "stable");
+        assert_eq!(run(&location, &["rev-list", "--count", "HEAD"]), "3");
+        assert!(!sync.sync(&full).await.unwrap().changes);
+
+        // Switching to sync-depth shallows the existing clone
+        run(&upstream, &["checkout", "--quiet", "stable"]);
+        commit(&upstream, "stable two");
+        let shallow = repo(&location, Some(format!("{}#stable", uri)), Some(1));
+        let result = sync.sync(&shallow).await.unwrap();
+        assert!(result.changes);
+        assert!(result.message.contains("shallow clone of depth 1"), "{}", result.message);
+        assert_eq!(run(&location, &["rev-list", "--count", "HEAD"]), "1");
+        assert_eq!(std::fs::read_to_string(location.join("profiles.desc")).unwrap(), "stable two");
+
+        // A clone without a usable HEAD is cloned again
+        let head = run(&location, &["rev-parse", "HEAD"]);
+        std::fs::write(location.join(".git/refs/heads/stable"), "0000000000000000000000000000000000000000\n").unwrap();
+        let result = sync.sync(&shallow).await.unwrap();
+        assert!(result.message.contains("corrupt"), "{}", result.message);
+        assert_eq!(run(&location, &["rev-parse", "HEAD"]), head);
+        assert!(!temp.path().join(".repo.corrupt").exists());
+
+        // A corrupt clone is restored when cloning again fails
+        std::fs::write(location.join(".git/refs/heads/stable"), "0000000000000000000000000000000000000000\n").unwrap();
+        std::fs::write(location.join("local-change"), "kept").unwrap();
+        let unreachable = repo(&location, Some(format!("file://{}", temp.path().join("gone").display())), Some(1));
+        assert!(sync.sync(&unreachable).await.is_err());
+        assert_eq!(std::fs::read_to_string(location.join("local-change")).unwrap(), "kept");
+
+        // A repository git refuses to open is not corrupt, and is left alone
+        run(&location, &["config", "core.repositoryformatversion", "99"]);
+        assert!(sync.sync(&shallow).await.is_err());
+        assert_eq!(std::fs::read_to_string(location.join("local-change")).unwrap(), "kept");
+        assert!(location.join(".git").is_dir());
+    }
 }
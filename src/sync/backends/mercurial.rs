@@ -53,6 +53,7 @@ impl SyncBackend for MercurialSync {
             success: true,
             message: format!("Successfully created {} via mercurial", repo.name),
             changes: true,
+            stats: None,
         })
     }
 
@@ -82,6 +83,7 @@ impl SyncBackend for MercurialSync {
             success: true,
             message: format!("Successfully synced {} via mercurial", repo.name),
             changes,
+            stats: None,
         })
     }
 }
@@ -2,6 +2,7 @@ pub mod cvs;
 pub mod git;
 pub mod mercurial;
 pub mod rsync;
+pub mod sqfs;
 pub mod svn;
 pub mod webrsync;
 
@@ -14,6 +15,7 @@ pub enum Backend {
     Git(git::GitSync),
     Mercurial(mercurial::MercurialSync),
     Rsync(rsync::RsyncSync),
+    Squashfs(sqfs::SquashfsSync),
     Svn(svn::SvnSync),
     WebRsync(webrsync::WebRsyncSync),
 }
@@ -25,6 +27,7 @@ impl Backend {
             "git" => Some(Backend::Git(git::GitSync::new())),
             "mercurial" | "hg" => Some(Backend::Mercurial(mercurial::MercurialSync::new())),
             "rsync" => Some(Backend::Rsync(rsync::RsyncSync::new())),
+            "sqfs" | "squashfs" => Some(Backend::Squashfs(sqfs::SquashfsSync::new())),
             "svn" => Some(Backend::Svn(svn::SvnSync::new())),
             "webrsync" => Some(Backend::WebRsync(webrsync::WebRsyncSync::new())),
             _ => None,
@@ -37,6 +40,7 @@ impl Backend {
             Backend::Git(b) => b.name(),
             Backend::Mercurial(b) => b.name(),
             Backend::Rsync(b) => b.name(),
+            Backend::Squashfs(b) => b.name(),
             Backend::Svn(b) => b.name(),
             Backend::WebRsync(b) => b.name(),
         }
@@ -48,6 +52,7 @@ impl Backend {
             Backend::Git(b) => b.short_desc(),
             Backend::Mercurial(b) => b.short_desc(),
             Backend::Rsync(b) => b.short_desc(),
+            Backend::Squashfs(b) => b.short_desc(),
             Backend::Svn(b) => b.short_desc(),
             Backend::WebRsync(b) => b.short_desc(),
         }
@@ -59,6 +64,7 @@ impl Backend {
             Backend::Git(b) => b.exists(repo_path).await,
             Backend::Mercurial(b) => b.exists(repo_path).await,
             Backend::Rsync(b) => b.exists(repo_path).await,
+            Backend::Squashfs(b) => b.exists(repo_path).await,
             Backend::Svn(b) => b.exists(repo_path).await,
             Backend::WebRsync(b) => b.exists(repo_path).await,
         }
@@ -70,6 +76,7 @@ impl Backend {
             Backend::Git(b) => b.sync(repo).await,
             Backend::Mercurial(b) => b.sync(repo).await,
             Backend::Rsync(b) => b.sync(repo).await,
+            Backend::Squashfs(b) => b.sync(repo).await,
             Backend::Svn(b) => b.sync(repo).await,
             Backend::WebRsync(b) => b.sync(repo).await,
         }
@@ -81,6 +88,7 @@ impl Backend {
             Backend::Git(b) => b.new_repo(repo).await,
             Backend::Mercurial(b) => b.new_repo(repo).await,
             Backend::Rsync(b) => b.new_repo(repo).await,
+            Backend::Squashfs(b) => b.new_repo(repo).await,
             Backend::Svn(b) => b.new_repo(repo).await,
             Backend::WebRsync(b) => b.new_repo(repo).await,
         }
@@ -111,6 +119,12 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_backend_creation_sqfs() {
+        assert!(matches!(Backend::new("sqfs"), Some(Backend::Squashfs(_))));
+        assert!(matches!(Backend::new("squashfs"), Some(Backend::Squashfs(_))));
+    }
+
     #[test]
     fn test_backend_creation_webrsync() {
         let backend = Backend::new("webrsync");
@@ -1,12 +1,244 @@
-use crate::sync::{SyncBackend, SyncError, SyncResult};
-use tokio::process::Command;
-use std::path::Path;
+use crate::sync::{SyncBackend, SyncError, SyncResult, TransferStats};
+use md4::{Digest, Md4};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 
-pub struct RsyncSync;
+/// Protocol version we speak: MD4 checksums and a single, non-incremental file list
+const PROTOCOL_VERSION: i32 = 29;
+const DEFAULT_PORT: u16 = 873;
+
+const MPLEX_BASE: u32 = 7;
+const MSG_DATA: u32 = 0;
+const MSG_ERROR_XFER: u32 = 1;
+const MSG_INFO: u32 = 2;
+const MSG_ERROR: u32 = 3;
+const MSG_WARNING: u32 = 4;
+
+const NDX_DONE: i32 = -1;
+const ITEM_BASIS_TYPE_FOLLOWS: u16 = 1 << 11;
+const ITEM_XNAME_FOLLOWS: u16 = 1 << 12;
+const ITEM_TRANSFER: u16 = 1 << 15;
+
+const XMIT_SAME_MODE: u16 = 1 << 1;
+const XMIT_EXTENDED_FLAGS: u16 = 1 << 2;
+const XMIT_SAME_NAME: u16 = 1 << 5;
+const XMIT_LONG_NAME: u16 = 1 << 6;
+const XMIT_SAME_TIME: u16 = 1 << 7;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const BLOCK_SIZE: usize = 700;
+const MAX_BLOCK_SIZE: usize = 1 << 17;
+const SUM_LENGTH: usize = 16;
+const CHUNK_SIZE: usize = 32 * 1024;
+const MAX_PATH: usize = 4096;
+
+/// Paths never transferred nor deleted
+const EXCLUDES: &[&str] = &["/.git"];
+
+pub struct RsyncSync {
+    connect_timeout: Duration,
+    io_timeout: Duration,
+    retries: usize,
+}
 
 impl RsyncSync {
     pub fn new() -> Self {
-        RsyncSync
+        RsyncSync {
+            connect_timeout: Duration::from_secs(60),
+            io_timeout: Duration::from_secs(180),
+            retries: 3,
+        }
+    }
+
+    pub fn with_timeouts(mut self, connect: Duration, io: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.io_timeout = io;
+        self
+    }
+
+    /// Number of attempts, rotating through the configured mirrors
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries.max(1);
+        self
+    }
+
+    /// Bring `dest` up to date from `url`, collecting the daemon's message of the day in
+    /// `motd` and what the transfer skipped in `notes`
+    async fn transfer(&self, url: &RsyncUrl, dest: &Path, motd: &mut Vec<String>, notes: &mut Vec<String>) -> Result<TransferStats, SyncError> {
+        let stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect((url.host.as_str(), url.port)))
+            .await
+            .map_err(|_| SyncError::Timeout(format!("connection to {} timed out", url.host)))?
+            .map_err(|e| SyncError::Network(format!("cannot connect to {}:{}: {}", url.host, url.port, e)))?;
+        let (read_half, mut writer) = stream.into_split();
+        let mut reader = MuxReader::new(read_half, self.io_timeout);
+
+        // Daemon handshake and module selection
+        send(&mut writer, format!("@RSYNCD: {}.0\n", PROTOCOL_VERSION).as_bytes(), self.io_timeout).await?;
+        let greeting = reader.read_line().await?;
+        let remote_version = parse_greeting(&greeting)
+            .ok_or_else(|| SyncError::Network(format!("unexpected rsync greeting: {}", greeting)))?;
+        if remote_version < PROTOCOL_VERSION {
+            return Err(SyncError::Network(format!("rsync daemon protocol {} is too old", remote_version)));
+        }
+
+        send(&mut writer, format!("{}\n", url.module).as_bytes(), self.io_timeout).await?;
+        loop {
+            let line = reader.read_line().await?;
+            if line == "@RSYNCD: OK" {
+                break;
+            } else if line.starts_with("@RSYNCD: AUTHREQD") {
+                return Err(SyncError::Repository(format!("rsync module {} requires authentication", url.module)));
+            } else if line.starts_with("@RSYNCD: EXIT") {
+                return Err(SyncError::Repository(format!("rsync module {} is not available", url.module)));
+            } else if let Some(message) = line.strip_prefix("@ERROR") {
+                return Err(SyncError::Repository(message.trim_start_matches(':').trim().to_string()));
+            }
+            motd.push(line);
+        }
+
+        let mut args = String::new();
+        for arg in ["--server", "--sender", "-rlpt", ".", &url.source()] {
+            args.push_str(arg);
+            args.push('\n');
+        }
+        args.push('\n');
+        send(&mut writer, args.as_bytes(), self.io_timeout).await?;
+
+        let seed = reader.read_i32().await?;
+        reader.multiplexed = true;
+
+        let mut filters = Vec::new();
+        for exclude in EXCLUDES {
+            let rule = format!("- {}", exclude);
+            put_i32(&mut filters, rule.len() as i32);
+            filters.extend_from_slice(rule.as_bytes());
+        }
+        put_i32(&mut filters, 0);
+        send(&mut writer, &filters, self.io_timeout).await?;
+
+        let mut entries = recv_file_list(&mut reader).await?;
+        let io_error = reader.read_i32().await?;
+        entries.sort_by(compare_entries);
+        if let Some(entry) = entries.iter().find(|e| !is_safe_name(&e.name)) {
+            return Err(SyncError::Validation(format!("unsafe path in rsync file list: {}", entry.name)));
+        }
+
+        let mut stats = TransferStats {
+            files_total: entries.len(),
+            ..TransferStats::default()
+        };
+
+        // Bring directories and symlinks up to date and request changed files
+        let (requests, queue) = mpsc::unbounded_channel();
+        for (ndx, entry) in entries.iter().enumerate() {
+            let path = entry_path(dest, entry);
+            if entry.is_dir() {
+                prepare_dir(&path)?;
+            } else if entry.is_symlink() {
+                if update_symlink(entry, &path, notes)? {
+                    stats.files_transferred += 1;
+                }
+            } else if entry.is_file() && !is_up_to_date(entry, &path) {
+                let basis = path.is_file().then(|| path.clone());
+                let _ = requests.send(Request::File(ndx as i32, basis));
+            }
+        }
+        let _ = requests.send(Request::Done);
+        let generator = tokio::spawn(run_generator(writer, queue, seed, self.io_timeout));
+
+        // The sender answers every request, then echoes the end of each phase
+        let mut phase = 0;
+        let mut redo = Vec::new();
+        let mut failed = Vec::new();
+        loop {
+            let ndx = reader.read_i32().await?;
+            if ndx == NDX_DONE {
+                phase += 1;
+                if phase > 2 {
+                    break;
+                }
+                // Files failing verification are fetched again in full
+                for ndx in redo.drain(..) {
+                    let _ = requests.send(Request::File(ndx, None));
+                }
+                let _ = requests.send(Request::Done);
+                continue;
+            }
+
+            let iflags = reader.read_u16().await?;
+            if iflags & ITEM_BASIS_TYPE_FOLLOWS != 0 {
+                reader.read_u8().await?;
+            }
+            if iflags & ITEM_XNAME_FOLLOWS != 0 {
+                let mut len = reader.read_u8().await? as usize;
+                if len & 0x80 != 0 {
+                    len = ((len & 0x7f) << 8) | reader.read_u8().await? as usize;
+                }
+                reader.read_bytes(len).await?;
+            }
+            if iflags & ITEM_TRANSFER == 0 {
+                continue;
+            }
+
+            let entry = usize::try_from(ndx).ok().and_then(|i| entries.get(i))
+                .ok_or_else(|| SyncError::Validation(format!("rsync sender used invalid file index {}", ndx)))?;
+            let path = entry_path(dest, entry);
+            let count = reader.read_i32().await?;
+            let block_len = reader.read_i32().await?.max(0) as usize;
+            reader.read_i32().await?;
+            reader.read_i32().await?;
+            let basis = if count > 0 { std::fs::read(&path).unwrap_or_default() } else { Vec::new() };
+
+            let data = recv_tokens(&mut reader, &basis, block_len, &mut stats).await?;
+            let checksum = reader.read_bytes(SUM_LENGTH).await?;
+            if file_checksum(&data, seed)[..] != checksum[..] {
+                if phase == 0 {
+                    redo.push(ndx);
+                } else {
+                    failed.push(entry.name.clone());
+                }
+                continue;
+            }
+
+            install_file(entry, &path, &data)?;
+            stats.files_transferred += 1;
+        }
+
+        // Sender statistics: bytes read, bytes written, total size, file list times
+        reader.read_i64().await?;
+        stats.bytes_received = reader.read_i64().await?.max(0) as u64;
+        for _ in 0..3 {
+            reader.read_i64().await?;
+        }
+        let _ = requests.send(Request::Done);
+        drop(requests);
+        generator.await.map_err(|e| SyncError::Command(format!("rsync generator failed: {}", e)))??;
+
+        for entry in entries.iter().rev().filter(|e| e.is_dir()) {
+            set_attributes(entry, &entry_path(dest, entry))?;
+        }
+
+        if !failed.is_empty() {
+            return Err(SyncError::Validation(format!("checksum mismatch for {}", failed.join(", "))));
+        }
+        if io_error != 0 || reader.errors > 0 {
+            notes.push("IO error encountered -- skipping file deletion".to_string());
+        } else {
+            stats.files_deleted = delete_extraneous(dest, &entries)?;
+        }
+
+        Ok(stats)
     }
 }
 
@@ -30,47 +262,807 @@ impl SyncBackend for RsyncSync {
 
     async fn sync(&self, repo: &crate::porttree::Repository) -> Result<SyncResult, SyncError> {
         let repo_path = Path::new(&repo.location);
-        
+
         tokio::fs::create_dir_all(repo_path).await?;
 
         let sync_uri = repo.sync_uri.as_deref().ok_or_else(|| {
             SyncError::Repository("No sync URI configured for rsync repository".to_string())
         })?;
 
-        let mut rsync_cmd = Command::new("rsync");
-        rsync_cmd
-            .arg("--recursive")
-            .arg("--links")
-            .arg("--safe-links")
-            .arg("--perms")
-            .arg("--times")
-            .arg("--compress")
-            .arg("--force")
-            .arg("--whole-file")
-            .arg("--delete")
-            .arg("--stats")
-            .arg("--human-readable")
-            .arg("--timeout=180")
-            .arg("--exclude=/.git")
-            .arg("--quiet")
-            .arg(sync_uri)
-            .arg(&repo.location);
-
-        let output = rsync_cmd.output().await?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(SyncError::Command(format!("rsync failed: {}", stderr)));
-        }
-
-        let changes = !output.stdout.is_empty();
-
-        Ok(SyncResult {
-            success: true,
-            message: format!("Successfully synced {} via rsync", repo.name),
-            changes: true,
+        let mirrors = mirror_list(sync_uri);
+        if mirrors.is_empty() {
+            return Err(SyncError::Repository("No sync URI configured for rsync repository".to_string()));
+        }
+
+        // A verified tree is updated in a hardlinked copy that replaces it only once its
+        // Manifests verify
+        let staging = (repo.sync_verify_metamanifest && repo.sync_allow_hardlinks).then(|| staging_dir(repo_path));
+        if let Some(staging) = &staging {
+            link_tree(repo_path, staging)?;
+        }
+        let dest = staging.as_deref().unwrap_or(repo_path);
+
+        let mut last_error = None;
+        let mut failed_attempts = Vec::new();
+        for attempt in 0..self.retries {
+            let mirror = &mirrors[attempt % mirrors.len()];
+            let Some(url) = RsyncUrl::parse(mirror) else {
+                last_error = Some(SyncError::Repository(format!("Unsupported rsync URI: {}", mirror)));
+                continue;
+            };
+
+            let (mut motd, mut notes) = (Vec::new(), Vec::new());
+            match self.transfer(&url, dest, &mut motd, &mut notes).await {
+                Ok(mut stats) => {
+                    if let Some(staging) = &staging {
+                        let key_path = crate::sync::openpgp::configured_keys(repo);
+                        if let Err(e) = crate::manifest::ManifestVerifier::new(staging).with_key(&key_path).verify().await {
+                            let _ = std::fs::remove_dir_all(staging);
+                            return Err(SyncError::Validation(format!("{}; {} was left unchanged", e, repo.location)));
+                        }
+                        swap_in(staging, repo_path)?;
+                    }
+                    stats.mirror = mirror.clone();
+                    // Earlier failed attempts and anything skipped, then the message of the day
+                    let mut message = format!(
+                        "Successfully synced {} via rsync from {} ({} of {} files transferred, {} deleted",
+                        repo.name, mirror, stats.files_transferred, stats.files_total, stats.files_deleted
+                    );
+                    for note in failed_attempts.iter().chain(&notes) {
+                        message.push_str(&format!(", {}", note));
+                    }
+                    message.push(')');
+                    for line in &motd {
+                        message.push_str(&format!("\n{}", line));
+                    }
+                    return Ok(SyncResult {
+                        success: true,
+                        message,
+                        changes: stats.files_transferred > 0 || stats.files_deleted > 0,
+                        stats: Some(stats),
+                    });
+                }
+                Err(e) => {
+                    failed_attempts.push(format!("rsync from {} failed (attempt {}/{}): {}", mirror, attempt + 1, self.retries, e));
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if let Some(staging) = &staging {
+            let _ = std::fs::remove_dir_all(staging);
+        }
+        // With several attempts the error tells of each of them
+        if failed_attempts.len() > 1 {
+            return Err(SyncError::Network(failed_attempts.join("; ")));
+        }
+        Err(last_error.unwrap_or_else(|| SyncError::Network("rsync failed".to_string())))
+    }
+}
+
+/// Where a tree is updated before it is verified
+fn staging_dir(repo_path: &Path) -> PathBuf {
+    let name = repo_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    repo_path.with_file_name(format!(".{}.unverified", name))
+}
+
+/// Recreate the tree at `src` in `dest`, hardlinking its files; files the transfer
+/// updates are replaced by rename, so `src` itself is never written through the links
+fn link_tree(src: &Path, dest: &Path) -> std::io::Result<()> {
+    remove_path(dest)?;
+    std::fs::create_dir_all(dest)?;
+    if !src.is_dir() {
+        return Ok(());
+    }
+    let mut pending = vec![(src.to_path_buf(), dest.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        for dirent in std::fs::read_dir(&from)? {
+            let dirent = dirent?;
+            let target = to.join(dirent.file_name());
+            let file_type = dirent.file_type()?;
+            if file_type.is_dir() {
+                std::fs::create_dir(&target)?;
+                pending.push((dirent.path(), target));
+            } else if file_type.is_symlink() {
+                std::os::unix::fs::symlink(std::fs::read_link(dirent.path())?, &target)?;
+            } else {
+                std::fs::hard_link(dirent.path(), &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the tree at `repo_path` with the verified `staging` tree
+fn swap_in(staging: &Path, repo_path: &Path) -> std::io::Result<()> {
+    let name = repo_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let old = repo_path.with_file_name(format!(".{}.old", name));
+    remove_path(&old)?;
+    if repo_path.exists() {
+        std::fs::rename(repo_path, &old)?;
+    }
+    std::fs::rename(staging, repo_path)?;
+    remove_path(&old)
+}
+
+/// Location of a module on an rsync daemon
+#[derive(Debug, Clone, PartialEq)]
+pub struct RsyncUrl {
+    pub host: String,
+    pub port: u16,
+    pub module: String,
+    pub path: String,
+}
+
+impl RsyncUrl {
+    /// Parse `rsync://[user@]host[:port]/module[/path]` or `host::module[/path]`
+    pub fn parse(uri: &str) -> Option<Self> {
+        let (authority, rest) = match uri.strip_prefix("rsync://") {
+            Some(rest) => rest.split_once('/')?,
+            None => uri.split_once("::")?,
+        };
+        let authority = authority.rsplit_once('@').map(|(_, host)| host).unwrap_or(authority);
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, port) = bracketed.split_once(']')?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => DEFAULT_PORT,
+        };
+
+        let rest = rest.trim_matches('/');
+        let (module, path) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() || module.is_empty() {
+            return None;
+        }
+
+        Some(RsyncUrl {
+            host: host.to_string(),
+            port,
+            module: module.to_string(),
+            path: path.to_string(),
         })
     }
+
+    /// Source argument for the daemon; the trailing slash transfers the directory contents
+    fn source(&self) -> String {
+        if self.path.is_empty() {
+            format!("{}/", self.module)
+        } else {
+            format!("{}/{}/", self.module, self.path)
+        }
+    }
+}
+
+/// Split a sync-uri setting into the mirrors to try, in order
+pub fn mirror_list(sync_uri: &str) -> Vec<String> {
+    sync_uri.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+fn parse_greeting(line: &str) -> Option<i32> {
+    let version = line.strip_prefix("@RSYNCD: ")?.split_whitespace().next()?;
+    version.split('.').next()?.parse().ok()
+}
+
+/// rsync's weak checksum; bytes are summed as signed chars like the C implementation
+pub fn rolling_checksum(data: &[u8]) -> u32 {
+    Rolling::new(data).digest()
+}
+
+/// MD4 block checksum with the session seed appended
+pub fn block_checksum(data: &[u8], seed: i32) -> [u8; SUM_LENGTH] {
+    let mut md = Md4::new();
+    md.update(data);
+    if seed != 0 {
+        md.update(seed.to_le_bytes());
+    }
+    md.finalize().into()
+}
+
+/// MD4 whole-file checksum with the session seed prepended
+pub fn file_checksum(data: &[u8], seed: i32) -> [u8; SUM_LENGTH] {
+    let mut md = Md4::new();
+    md.update(seed.to_le_bytes());
+    md.update(data);
+    md.finalize().into()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    s1: u32,
+    s2: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let mut rolling = Rolling { s1: 0, s2: 0, len: window.len() as u32 };
+        for &b in window {
+            rolling.s1 = rolling.s1.wrapping_add(b as i8 as u32);
+            rolling.s2 = rolling.s2.wrapping_add(rolling.s1);
+        }
+        rolling
+    }
+
+    fn roll(&mut self, out: u8, inp: u8) {
+        let (out, inp) = (out as i8 as u32, inp as i8 as u32);
+        self.s1 = self.s1.wrapping_sub(out).wrapping_add(inp);
+        self.s2 = self.s2.wrapping_sub(self.len.wrapping_mul(out)).wrapping_add(self.s1);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.s1 & 0xffff).wrapping_add(self.s2 << 16)
+    }
+}
+
+/// Block checksums of a basis file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub block_len: usize,
+    pub remainder: usize,
+    pub blocks: Vec<(u32, [u8; SUM_LENGTH])>,
+}
+
+impl Signature {
+    pub fn generate(data: &[u8], seed: i32) -> Self {
+        let block_len = Self::block_len_for(data.len());
+        Signature {
+            block_len,
+            remainder: data.len() % block_len,
+            blocks: data.chunks(block_len)
+                .map(|block| (rolling_checksum(block), block_checksum(block, seed)))
+                .collect(),
+        }
+    }
+
+    /// Block length for a file of the given size: its square root, rounded to a multiple of 8
+    pub fn block_len_for(size: usize) -> usize {
+        if size <= BLOCK_SIZE * BLOCK_SIZE {
+            return BLOCK_SIZE;
+        }
+        (((size as f64).sqrt() as usize) & !7).min(MAX_BLOCK_SIZE)
+    }
+
+    fn block_size(&self, index: usize) -> usize {
+        if index + 1 == self.blocks.len() && self.remainder > 0 {
+            self.remainder
+        } else {
+            self.block_len
+        }
+    }
+}
+
+/// One instruction of a delta: literal data or a copy of a basis block
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Literal(Vec<u8>),
+    Block(usize),
+}
+
+/// Compute the delta turning the file described by `signature` into `data`
+pub fn delta(signature: &Signature, data: &[u8], seed: i32) -> Vec<Token> {
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, (weak, _)) in signature.blocks.iter().enumerate() {
+        index.entry(*weak).or_default().push(i);
+    }
+
+    let mut tokens = Vec::new();
+    let mut literal = Vec::new();
+    let mut rolling: Option<Rolling> = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let window = signature.block_len.min(data.len() - pos);
+        let weak = match rolling {
+            Some(r) if window == signature.block_len => r.digest(),
+            _ => {
+                let r = Rolling::new(&data[pos..pos + window]);
+                rolling = Some(r);
+                r.digest()
+            }
+        };
+
+        let found = index.get(&weak).and_then(|candidates| {
+            let strong = block_checksum(&data[pos..pos + window], seed);
+            candidates.iter().copied()
+                .find(|&i| signature.block_size(i) == window && signature.blocks[i].1 == strong)
+        });
+
+        match found {
+            Some(i) => {
+                if !literal.is_empty() {
+                    tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                }
+                tokens.push(Token::Block(i));
+                pos += window;
+                rolling = None;
+            }
+            None => {
+                literal.push(data[pos]);
+                match rolling.as_mut() {
+                    Some(r) if window == signature.block_len && pos + window < data.len() => {
+                        r.roll(data[pos], data[pos + window]);
+                    }
+                    _ => rolling = None,
+                }
+                pos += 1;
+            }
+        }
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    tokens
+}
+
+/// Rebuild a file from its basis and a delta
+pub fn patch(basis: &[u8], block_len: usize, tokens: &[Token]) -> Result<Vec<u8>, SyncError> {
+    let mut data = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Literal(literal) => data.extend_from_slice(literal),
+            Token::Block(i) => data.extend_from_slice(basis_block(basis, block_len, *i)?),
+        }
+    }
+    Ok(data)
+}
+
+fn basis_block(basis: &[u8], block_len: usize, index: usize) -> Result<&[u8], SyncError> {
+    let start = index.checked_mul(block_len)
+        .filter(|&start| block_len > 0 && start < basis.len())
+        .ok_or_else(|| SyncError::Validation(format!("block {} is outside the basis file", index)))?;
+    Ok(&basis[start..(start + block_len).min(basis.len())])
+}
+
+/// One entry of the file list sent by the daemon
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub name: String,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: i64,
+    pub link_target: Option<String>,
+}
+
+impl FileEntry {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    fn components(&self) -> Vec<&str> {
+        if self.name == "." {
+            Vec::new()
+        } else {
+            self.name.split('/').collect()
+        }
+    }
+}
+
+/// Order file list entries like rsync so indices agree with the sender:
+/// within a directory, files come before subdirectories
+pub fn compare_entries(a: &FileEntry, b: &FileEntry) -> Ordering {
+    let (ca, cb) = (a.components(), b.components());
+    for i in 0.. {
+        let (x, y) = match (ca.get(i), cb.get(i)) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => (x, y),
+        };
+        let x_dir = i + 1 < ca.len() || a.is_dir();
+        let y_dir = i + 1 < cb.len() || b.is_dir();
+        let ordering = match (x_dir, y_dir) {
+            (false, true) => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (true, true) => x.bytes().chain(Some(b'/')).cmp(y.bytes().chain(Some(b'/'))),
+            (false, false) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn is_safe_name(name: &str) -> bool {
+    name == "." || name.split('/').all(|c| !c.is_empty() && c != "." && c != "..")
+}
+
+/// Like --safe-links: relative targets that stay inside the tree
+fn is_safe_link(name: &str, target: &str) -> bool {
+    if target.starts_with('/') {
+        return false;
+    }
+    let mut depth = name.split('/').count() as i64 - 1;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            _ => depth += 1,
+        }
+    }
+    true
+}
+
+fn is_excluded(name: &str) -> bool {
+    EXCLUDES.iter().any(|exclude| {
+        let exclude = exclude.trim_start_matches('/');
+        name == exclude || name.strip_prefix(exclude).is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+/// Reader for the daemon's stream; once multiplexed, log messages are split from the data
+struct MuxReader {
+    inner: BufReader<OwnedReadHalf>,
+    timeout: Duration,
+    multiplexed: bool,
+    remaining: usize,
+    errors: usize,
+}
+
+impl MuxReader {
+    fn new(inner: OwnedReadHalf, timeout: Duration) -> Self {
+        MuxReader {
+            inner: BufReader::new(inner),
+            timeout,
+            multiplexed: false,
+            remaining: 0,
+            errors: 0,
+        }
+    }
+
+    async fn read_raw(&mut self, buf: &mut [u8]) -> Result<(), SyncError> {
+        match tokio::time::timeout(self.timeout, self.inner.read_exact(buf)).await {
+            Ok(result) => result.map(|_| ()).map_err(SyncError::from),
+            Err(_) => Err(SyncError::Timeout(format!("no data from rsync daemon for {}s", self.timeout.as_secs()))),
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String, SyncError> {
+        let mut line = Vec::new();
+        loop {
+            let mut byte = [0u8];
+            self.read_raw(&mut byte).await?;
+            if byte[0] == b'\n' {
+                break;
+            }
+            line.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string())
+    }
+
+    async fn read_message(&mut self) -> Result<(), SyncError> {
+        let mut header = [0u8; 4];
+        self.read_raw(&mut header).await?;
+        let header = u32::from_le_bytes(header);
+        let tag = (header >> 24).wrapping_sub(MPLEX_BASE);
+        let len = (header & 0xffffff) as usize;
+        if tag == MSG_DATA {
+            self.remaining = len;
+            return Ok(());
+        }
+
+        let mut payload = vec![0u8; len];
+        self.read_raw(&mut payload).await?;
+        let text = String::from_utf8_lossy(&payload);
+        match tag {
+            MSG_INFO => print!("{}", text),
+            MSG_WARNING => eprint!("rsync: {}", text),
+            MSG_ERROR | MSG_ERROR_XFER => {
+                eprint!("rsync: {}", text);
+                self.errors += 1;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(), SyncError> {
+        if !self.multiplexed {
+            return self.read_raw(buf).await;
+        }
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.remaining == 0 {
+                self.read_message().await?;
+                continue;
+            }
+            let n = (buf.len() - filled).min(self.remaining);
+            self.read_raw(&mut buf[filled..filled + n]).await?;
+            self.remaining -= n;
+            filled += n;
+        }
+        Ok(())
+    }
+
+    async fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, SyncError> {
+        let mut buf = vec![0u8; len];
+        self.read(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn read_u8(&mut self) -> Result<u8, SyncError> {
+        let mut buf = [0u8; 1];
+        self.read(&mut buf).await?;
+        Ok(buf[0])
+    }
+
+    async fn read_u16(&mut self) -> Result<u16, SyncError> {
+        let mut buf = [0u8; 2];
+        self.read(&mut buf).await?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    async fn read_i32(&mut self) -> Result<i32, SyncError> {
+        let mut buf = [0u8; 4];
+        self.read(&mut buf).await?;
+        Ok(i32::from_le_bytes(buf))
+    }
+
+    /// 32-bit value, or -1 followed by a 64-bit value
+    async fn read_i64(&mut self) -> Result<i64, SyncError> {
+        let value = self.read_i32().await?;
+        if value != -1 {
+            return Ok(value as i64);
+        }
+        let mut buf = [0u8; 8];
+        self.read(&mut buf).await?;
+        Ok(i64::from_le_bytes(buf))
+    }
+}
+
+async fn recv_file_list(reader: &mut MuxReader) -> Result<Vec<FileEntry>, SyncError> {
+    let mut entries = Vec::new();
+    let mut last_name: Vec<u8> = Vec::new();
+    let (mut mode, mut mtime) = (0u32, 0i64);
+    loop {
+        let mut flags = reader.read_u8().await? as u16;
+        if flags == 0 {
+            break;
+        }
+        if flags & XMIT_EXTENDED_FLAGS != 0 {
+            flags |= (reader.read_u8().await? as u16) << 8;
+        }
+
+        let prefix = if flags & XMIT_SAME_NAME != 0 { reader.read_u8().await? as usize } else { 0 };
+        let suffix = if flags & XMIT_LONG_NAME != 0 {
+            reader.read_i32().await?.max(0) as usize
+        } else {
+            reader.read_u8().await? as usize
+        };
+        if prefix > last_name.len() || prefix + suffix > MAX_PATH {
+            return Err(SyncError::Validation("corrupt rsync file list".to_string()));
+        }
+        let mut name = last_name[..prefix].to_vec();
+        name.extend(reader.read_bytes(suffix).await?);
+
+        let size = reader.read_i64().await?.max(0) as u64;
+        if flags & XMIT_SAME_TIME == 0 {
+            mtime = reader.read_i32().await? as i64;
+        }
+        if flags & XMIT_SAME_MODE == 0 {
+            mode = reader.read_i32().await? as u32;
+        }
+        let link_target = if mode & S_IFMT == S_IFLNK {
+            let len = reader.read_i32().await?.max(0) as usize;
+            if len > MAX_PATH {
+                return Err(SyncError::Validation("corrupt rsync file list".to_string()));
+            }
+            Some(String::from_utf8_lossy(&reader.read_bytes(len).await?).into_owned())
+        } else {
+            None
+        };
+
+        entries.push(FileEntry {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            mode,
+            size,
+            mtime,
+            link_target,
+        });
+        last_name = name;
+    }
+    Ok(entries)
+}
+
+async fn recv_tokens(
+    reader: &mut MuxReader,
+    basis: &[u8],
+    block_len: usize,
+    stats: &mut TransferStats,
+) -> Result<Vec<u8>, SyncError> {
+    let mut data = Vec::new();
+    loop {
+        let token = reader.read_i32().await?;
+        match token.cmp(&0) {
+            Ordering::Equal => return Ok(data),
+            Ordering::Greater => {
+                let len = token as usize;
+                if len > CHUNK_SIZE {
+                    return Err(SyncError::Validation(format!("oversized rsync data chunk ({} bytes)", len)));
+                }
+                data.extend(reader.read_bytes(len).await?);
+                stats.literal_bytes += len as u64;
+            }
+            Ordering::Less => {
+                let block = basis_block(basis, block_len, -(token + 1) as usize)?;
+                data.extend_from_slice(block);
+                stats.matched_bytes += block.len() as u64;
+            }
+        }
+    }
+}
+
+/// Requests for the generator task, which talks to the sender while we receive
+enum Request {
+    File(i32, Option<PathBuf>),
+    Done,
+}
+
+async fn run_generator(
+    writer: OwnedWriteHalf,
+    mut queue: mpsc::UnboundedReceiver<Request>,
+    seed: i32,
+    timeout: Duration,
+) -> Result<(), SyncError> {
+    let mut writer = BufWriter::new(writer);
+    while let Some(request) = queue.recv().await {
+        let mut buf = Vec::new();
+        encode_request(&mut buf, request, seed);
+        while let Ok(request) = queue.try_recv() {
+            encode_request(&mut buf, request, seed);
+        }
+        send(&mut writer, &buf, timeout).await?;
+    }
+    Ok(())
+}
+
+fn encode_request(buf: &mut Vec<u8>, request: Request, seed: i32) {
+    match request {
+        Request::File(ndx, basis) => {
+            put_i32(buf, ndx);
+            buf.extend_from_slice(&ITEM_TRANSFER.to_le_bytes());
+            let data = basis.and_then(|path| std::fs::read(path).ok()).unwrap_or_default();
+            if data.is_empty() {
+                for _ in 0..4 {
+                    put_i32(buf, 0);
+                }
+                return;
+            }
+            let signature = Signature::generate(&data, seed);
+            put_i32(buf, signature.blocks.len() as i32);
+            put_i32(buf, signature.block_len as i32);
+            put_i32(buf, SUM_LENGTH as i32);
+            put_i32(buf, signature.remainder as i32);
+            for (weak, strong) in &signature.blocks {
+                put_i32(buf, *weak as i32);
+                buf.extend_from_slice(strong);
+            }
+        }
+        Request::Done => put_i32(buf, NDX_DONE),
+    }
+}
+
+fn put_i32(buf: &mut Vec<u8>, value: i32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+async fn send<W: AsyncWriteExt + Unpin>(writer: &mut W, data: &[u8], timeout: Duration) -> Result<(), SyncError> {
+    let write = async {
+        writer.write_all(data).await?;
+        writer.flush().await
+    };
+    match tokio::time::timeout(timeout, write).await {
+        Ok(result) => result.map_err(SyncError::from),
+        Err(_) => Err(SyncError::Timeout(format!("rsync daemon stopped reading for {}s", timeout.as_secs()))),
+    }
+}
+
+fn entry_path(dest: &Path, entry: &FileEntry) -> PathBuf {
+    if entry.name == "." {
+        dest.to_path_buf()
+    } else {
+        dest.join(&entry.name)
+    }
+}
+
+fn is_up_to_date(entry: &FileEntry, path: &Path) -> bool {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return false;
+    };
+    let mtime = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+    metadata.is_file() && metadata.len() == entry.size && mtime == Some(entry.mtime)
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+fn prepare_dir(path: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+        remove_path(path)?;
+    }
+    std::fs::create_dir_all(path)
+}
+
+fn update_symlink(entry: &FileEntry, path: &Path, notes: &mut Vec<String>) -> std::io::Result<bool> {
+    let Some(target) = &entry.link_target else {
+        return Ok(false);
+    };
+    if !is_safe_link(&entry.name, target) {
+        notes.push(format!("ignoring unsafe symlink {} -> {}", entry.name, target));
+        return Ok(false);
+    }
+    if std::fs::read_link(path).is_ok_and(|current| current == Path::new(target)) {
+        return Ok(false);
+    }
+    remove_path(path)?;
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(true)
+}
+
+fn set_attributes(entry: &FileEntry, path: &Path) -> std::io::Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode & 0o7777))?;
+    let mtime = UNIX_EPOCH + Duration::from_secs(entry.mtime.max(0) as u64);
+    std::fs::File::open(path)?.set_modified(mtime)
+}
+
+fn install_file(entry: &FileEntry, path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.rsync-tmp", file_name));
+    std::fs::write(&tmp, data)?;
+    set_attributes(entry, &tmp)?;
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+        std::fs::remove_dir_all(path)?;
+    }
+    std::fs::rename(&tmp, path)
+}
+
+/// Remove local files missing from the file list, returning how many were removed
+fn delete_extraneous(dest: &Path, entries: &[FileEntry]) -> std::io::Result<usize> {
+    let keep: HashSet<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+    let mut deleted = 0;
+    let mut pending = vec![(dest.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for dirent in std::fs::read_dir(&dir)? {
+            let dirent = dirent?;
+            let name = format!("{}{}", prefix, dirent.file_name().to_string_lossy());
+            if is_excluded(&name) {
+                continue;
+            }
+            if !keep.contains(name.as_str()) {
+                remove_path(&dirent.path())?;
+                deleted += 1;
+            } else if dirent.file_type()?.is_dir() {
+                pending.push((dirent.path(), format!("{}/", name)));
+            }
+        }
+    }
+    Ok(deleted)
 }
 
 #[cfg(test)]
@@ -92,7 +1084,7 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         let non_existent = temp_dir.path().join("nonexistent");
         let sync = RsyncSync::new();
-        
+
         assert!(!sync.exists(&non_existent).await);
     }
 
@@ -100,7 +1092,7 @@ mod tests {
     async fn test_rsync_exists_with_dir() {
         let temp_dir = TempDir::new().unwrap();
         let sync = RsyncSync::new();
-        
+
         assert!(sync.exists(temp_dir.path()).await);
     }
 
@@ -108,7 +1100,7 @@ mod tests {
     async fn test_new_repo_no_uri() {
         let temp_dir = TempDir::new().unwrap();
         let sync = RsyncSync::new();
-        
+
         let repo = Repository {
             name: "test".to_string(),
             location: temp_dir.path().to_str().unwrap().to_string(),
@@ -117,6 +1109,14 @@ mod tests {
             auto_sync: true,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: crate::porttree::SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -136,4 +1136,95 @@ mod tests {
             _ => panic!("Expected Repository error"),
         }
     }
+
+    #[test]
+    fn test_staged_tree() {
+        use std::os::unix::fs::MetadataExt;
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("gentoo");
+        std::fs::create_dir_all(repo_path.join("app-misc/foo")).unwrap();
+        std::fs::write(repo_path.join("app-misc/foo/foo-1.ebuild"), "EAPI=8\n").unwrap();
+        std::os::unix::fs::symlink("app-misc", repo_path.join("misc")).unwrap();
+
+        let staging = staging_dir(&repo_path);
+        assert_eq!(staging, temp.path().join(".gentoo.unverified"));
+        link_tree(&repo_path, &staging).unwrap();
+        let ebuild = staging.join("app-misc/foo/foo-1.ebuild");
+        assert_eq!(std::fs::read_link(staging.join("misc")).unwrap(), Path::new("app-misc"));
+        assert_eq!(std::fs::metadata(&ebuild).unwrap().ino(), std::fs::metadata(repo_path.join("app-misc/foo/foo-1.ebuild")).unwrap().ino());
+
+        // Updating the staged copy leaves the tree alone until it is swapped in
+        let entry = FileEntry { name: "app-misc/foo/foo-1.ebuild".to_string(), mode: 0o100644, size: 7, mtime: 0, link_target: None };
+        install_file(&entry, &ebuild, b"EAPI=7\n").unwrap();
+        assert_eq!(std::fs::read_to_string(repo_path.join("app-misc/foo/foo-1.ebuild")).unwrap(), "EAPI=8\n");
+        swap_in(&staging, &repo_path).unwrap();
+        assert_eq!(std::fs::read_to_string(repo_path.join("app-misc/foo/foo-1.ebuild")).unwrap(), "EAPI=7\n");
+        assert!(!staging.exists());
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_rsync_url() {
+        let url = RsyncUrl::parse("rsync://rsync.gentoo.org/gentoo-portage").unwrap();
+        assert_eq!(url.host, "rsync.gentoo.org");
+        assert_eq!(url.port, 873);
+        assert_eq!(url.source(), "gentoo-portage/");
+
+        let url = RsyncUrl::parse("rsync://user@[::1]:8873/mirror/gentoo/").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 8873);
+        assert_eq!(url.source(), "mirror/gentoo/");
+
+        let url = RsyncUrl::parse("mirror.example.org::gentoo-portage").unwrap();
+        assert_eq!(url.module, "gentoo-portage");
+        assert!(RsyncUrl::parse("https://example.org/gentoo").is_none());
+
+        assert_eq!(mirror_list(" rsync://a/gentoo\n rsync://b/gentoo "), vec!["rsync://a/gentoo", "rsync://b/gentoo"]);
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old[..1400].to_vec();
+        new.extend_from_slice(b"inserted data");
+        new.extend_from_slice(&old[2100..]);
+
+        let signature = Signature::generate(&old, 1234);
+        assert_eq!(signature.block_len, BLOCK_SIZE);
+        let tokens = delta(&signature, &new, 1234);
+        assert!(tokens.contains(&Token::Block(0)));
+        assert!(tokens.contains(&Token::Block(signature.blocks.len() - 1)));
+        assert_eq!(patch(&old, signature.block_len, &tokens).unwrap(), new);
+
+        let mut rolling = Rolling::new(&new[..BLOCK_SIZE]);
+        rolling.roll(new[0], new[BLOCK_SIZE]);
+        assert_eq!(rolling.digest(), rolling_checksum(&new[1..BLOCK_SIZE + 1]));
+    }
+
+    #[test]
+    fn test_file_list_order() {
+        let entry = |name: &str, mode: u32| FileEntry {
+            name: name.to_string(),
+            mode,
+            size: 0,
+            mtime: 0,
+            link_target: None,
+        };
+        let mut entries = vec![
+            entry("a/x", S_IFREG),
+            entry("a-b", S_IFDIR),
+            entry("z", S_IFREG),
+            entry("a", S_IFDIR),
+            entry(".", S_IFDIR),
+            entry("a/b", S_IFDIR),
+        ];
+        entries.sort_by(compare_entries);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec![".", "z", "a-b", "a", "a/x", "a/b"]);
+
+        assert!(is_safe_link("a/b/link", "../c"));
+        assert!(!is_safe_link("link", "../../etc/passwd"));
+        assert!(!is_safe_name("a/../../etc"));
+        assert!(is_excluded(".git/config"));
+    }
 }
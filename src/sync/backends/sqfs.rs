@@ -0,0 +1,309 @@
+// sqfs.rs -- squashfs snapshots of a repository: the newest image the signed sha512sum.txt
+// of the sync-uri lists, fetched whole or rebuilt from the previous one with a squashdelta,
+// then extracted into (or with sync-squashfs-mount, mounted over) the repository location
+
+use crate::sync::{SyncBackend, SyncError, SyncResult};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// The signed checksum list published next to the snapshots
+pub const SUMS_FILE: &str = "sha512sum.txt";
+
+/// A file the checksum list names
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub name: String,
+    pub sha512: String,
+}
+
+/// Entries of a `<sha512>  <file>` checksum list
+pub fn parse_sums(body: &str) -> Vec<Listing> {
+    body.lines()
+        .filter_map(|line| {
+            let (sha512, name) = line.split_once(char::is_whitespace)?;
+            let name = name.trim().trim_start_matches('*');
+            (sha512.len() == 128 && !name.is_empty()).then(|| Listing { name: name.to_string(), sha512: sha512.to_lowercase() })
+        })
+        .collect()
+}
+
+/// The prefix and date of a dated image, `<prefix>-YYYYMMDD.<compression>.sqfs`
+pub fn image_date(name: &str) -> Option<(&str, &str)> {
+    let stem = name.strip_suffix(".sqfs")?;
+    let (stem, _) = stem.split_once('.')?;
+    let (prefix, date) = stem.rsplit_once('-')?;
+    (date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit())).then_some((prefix, date))
+}
+
+/// The newest dated image in a checksum list
+pub fn latest_image(listings: &[Listing]) -> Option<&Listing> {
+    listings.iter()
+        .filter_map(|listing| image_date(&listing.name).map(|(_, date)| (date, listing)))
+        .max_by_key(|(date, _)| *date)
+        .map(|(_, listing)| listing)
+}
+
+/// The squashdelta turning image `from` into image `to`
+pub fn delta_name(from: &str, to: &str) -> Option<String> {
+    let (prefix, from_date) = image_date(from)?;
+    let (to_prefix, to_date) = image_date(to)?;
+    (prefix == to_prefix).then(|| format!("{}-{}-{}.sqdelta", prefix, from_date, to_date))
+}
+
+/// Where the images of a repository are kept between syncs
+fn store_dir(repo_path: &Path) -> PathBuf {
+    let name = repo_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    repo_path.with_file_name(format!(".{}.sqfs", name))
+}
+
+/// The image the repository was last synced from
+fn current_image(store: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(store).ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "sqfs"))
+}
+
+fn sha512_matches(path: &Path, sha512: &str) -> bool {
+    crate::checksum::hash_file(path, &["SHA512"]).ok()
+        .is_some_and(|checksums| checksums.hashes["SHA512"] == sha512)
+}
+
+async fn run(program: &str, args: &[&std::ffi::OsStr]) -> Result<(), SyncError> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| SyncError::Command(format!("Failed to execute {}: {}", program, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(SyncError::Command(format!("{} failed: {}", program, stderr.trim())));
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct SquashfsSync;
+
+impl SquashfsSync {
+    pub fn new() -> Self {
+        SquashfsSync
+    }
+
+    async fn download(uri: &str, name: &str, dest: &Path) -> Result<(), SyncError> {
+        let url = format!("{}/{}", uri.trim_end_matches('/'), name);
+        let output = Command::new("wget")
+            .arg("--quiet")
+            .arg("--timeout=180")
+            .arg("--tries=3")
+            .arg("-O")
+            .arg(dest)
+            .arg(&url)
+            .output()
+            .await
+            .map_err(|e| SyncError::Command(format!("Failed to execute wget: {}", e)))?;
+        if !output.status.success() {
+            let _ = std::fs::remove_file(dest);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(SyncError::Network(format!("Failed to download {}: {}", url, stderr)));
+        }
+        Ok(())
+    }
+
+    /// The checksum list, once its signature checks out against the repository's keys
+    async fn fetch_sums(repo: &crate::porttree::Repository, uri: &str, store: &Path) -> Result<Vec<Listing>, SyncError> {
+        let sums = store.join(SUMS_FILE);
+        Self::download(uri, SUMS_FILE, &sums).await?;
+        crate::manifest::ManifestVerifier::new(store)
+            .with_key(&crate::sync::openpgp::configured_keys(repo))
+            .verify_signature(&sums)
+            .await
+            .map_err(|e| SyncError::Validation(e.to_string()))?;
+        let content = std::fs::read_to_string(&sums)?;
+        let body = crate::manifest::strip_signature(&content)
+            .ok_or_else(|| SyncError::Validation(format!("{} is not signed", SUMS_FILE)))?;
+        Ok(parse_sums(&body))
+    }
+
+    /// Rebuild `image` from the current one with the listed delta; Err(None) when there is
+    /// no delta, Err(Some) when it fails or does not produce the listed image
+    async fn apply_delta(uri: &str, store: &Path, current: &Path, image: &Listing, listings: &[Listing]) -> Result<(), Option<SyncError>> {
+        let Some(delta) = current.file_name()
+            .and_then(|name| delta_name(&name.to_string_lossy(), &image.name))
+            .and_then(|name| listings.iter().find(|listing| listing.name == name)) else {
+            return Err(None);
+        };
+        let delta_path = store.join(&delta.name);
+        let target = store.join(&image.name);
+        let applied = async {
+            Self::download(uri, &delta.name, &delta_path).await?;
+            if !sha512_matches(&delta_path, &delta.sha512) {
+                return Err(SyncError::Validation(format!("Checksum mismatch for {}", delta.name)));
+            }
+            run("squashmerge", &[current.as_os_str(), delta_path.as_os_str(), target.as_os_str()]).await?;
+            if !sha512_matches(&target, &image.sha512) {
+                return Err(SyncError::Validation(format!("{} rebuilt from {} does not match its checksum", image.name, delta.name)));
+            }
+            Ok(())
+        }.await;
+        let _ = std::fs::remove_file(&delta_path);
+        if applied.is_err() {
+            let _ = std::fs::remove_file(&target);
+        }
+        applied.map_err(Some)
+    }
+
+    /// Put the contents of `image` at the repository location
+    async fn deploy(repo: &crate::porttree::Repository, image: &Path) -> Result<(), SyncError> {
+        let repo_path = Path::new(&repo.location);
+        if repo.sync_squashfs_mount {
+            std::fs::create_dir_all(repo_path)?;
+            // The previous image may still be mounted there
+            let _ = run("umount", &[repo_path.as_os_str()]).await;
+            return run("mount", &["-t".as_ref(), "squashfs".as_ref(), "-o".as_ref(), "loop,ro".as_ref(), image.as_os_str(), repo_path.as_os_str()]).await;
+        }
+
+        let name = repo_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let staging = repo_path.with_file_name(format!(".{}.unsquashfs", name));
+        let old = repo_path.with_file_name(format!(".{}.old", name));
+        for dir in [&staging, &old] {
+            if dir.exists() {
+                std::fs::remove_dir_all(dir)?;
+            }
+        }
+        if let Err(e) = run("unsquashfs", &["-no-progress".as_ref(), "-quiet".as_ref(), "-d".as_ref(), staging.as_os_str(), image.as_os_str()]).await {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        if repo_path.exists() {
+            std::fs::rename(repo_path, &old)?;
+        }
+        std::fs::rename(&staging, repo_path)?;
+        if old.exists() {
+            std::fs::remove_dir_all(&old)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl SyncBackend for SquashfsSync {
+    fn name(&self) -> &'static str {
+        "SquashfsSync"
+    }
+
+    fn short_desc(&self) -> &'static str {
+        "Perform sync operations on squashfs snapshot based repositories"
+    }
+
+    async fn exists(&self, repo_path: &Path) -> bool {
+        current_image(&store_dir(repo_path)).is_some()
+    }
+
+    async fn new_repo(&self, repo: &crate::porttree::Repository) -> Result<SyncResult, SyncError> {
+        self.sync(repo).await
+    }
+
+    async fn sync(&self, repo: &crate::porttree::Repository) -> Result<SyncResult, SyncError> {
+        let uri = repo.sync_uri.as_deref()
+            .ok_or_else(|| SyncError::Repository("No sync-uri specified".to_string()))?;
+        let repo_path = Path::new(&repo.location);
+        let store = store_dir(repo_path);
+        std::fs::create_dir_all(&store)?;
+
+        let listings = Self::fetch_sums(repo, uri, &store).await?;
+        let image = latest_image(&listings)
+            .ok_or_else(|| SyncError::Repository(format!("No squashfs snapshot listed at {}", uri)))?;
+        let target = store.join(&image.name);
+        let current = current_image(&store);
+        let deployed = std::fs::read_dir(repo_path).is_ok_and(|mut entries| entries.next().is_some());
+
+        if current.as_deref() == Some(target.as_path()) && deployed {
+            return Ok(SyncResult {
+                success: true,
+                message: format!("{} is already at snapshot {}", repo.name, image.name),
+                changes: false,
+                stats: None,
+            });
+        }
+
+        let mut via_delta = false;
+        let mut delta_error = None;
+        if current.as_deref() != Some(target.as_path()) {
+            if let Some(current) = &current {
+                match Self::apply_delta(uri, &store, current, image, &listings).await {
+                    Ok(()) => via_delta = true,
+                    Err(e) => delta_error = e,
+                }
+            }
+            if !via_delta {
+                let partial = store.join(format!("{}.partial", image.name));
+                Self::download(uri, &image.name, &partial).await?;
+                if !sha512_matches(&partial, &image.sha512) {
+                    let _ = std::fs::remove_file(&partial);
+                    return Err(SyncError::Validation(format!("Checksum mismatch for {}", image.name)));
+                }
+                std::fs::rename(&partial, &target)?;
+            }
+        }
+
+        Self::deploy(repo, &target).await?;
+        if let Some(current) = current.filter(|current| *current != target) {
+            let _ = std::fs::remove_file(current);
+        }
+
+        Ok(SyncResult {
+            success: true,
+            message: match delta_error {
+                Some(e) => format!("Successfully synced {} from squashfs snapshot {} (delta failed: {}; fetched the whole image)", repo.name, image.name, e),
+                None => format!(
+                    "Successfully synced {} from squashfs snapshot {}{}",
+                    repo.name, image.name, if via_delta { " (via delta)" } else { "" }
+                ),
+            },
+            changes: true,
+            stats: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_listing() {
+        let sha = |c: char| c.to_string().repeat(128);
+        let body = format!(
+            "{}  gentoo-20240101.xz.sqfs\n{}  gentoo-20240103.xz.sqfs\n{}  gentoo-current.xz.sqfs\n{}  gentoo-20240101-20240103.sqdelta\nnot a checksum line\n",
+            sha('a'), sha('b'), sha('b'), sha('c'));
+        let listings = parse_sums(&body);
+        assert_eq!(listings.len(), 4);
+
+        let latest = latest_image(&listings).unwrap();
+        assert_eq!(latest.name, "gentoo-20240103.xz.sqfs");
+        assert_eq!(latest.sha512, sha('b'));
+        assert_eq!(image_date("gentoo-current.xz.sqfs"), None);
+        assert_eq!(delta_name("gentoo-20240101.xz.sqfs", &latest.name).as_deref(), Some("gentoo-20240101-20240103.sqdelta"));
+        assert_eq!(delta_name("other-20240101.xz.sqfs", &latest.name), None);
+    }
+
+    #[test]
+    fn test_image_store() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let repo_path = temp.path().join("gentoo");
+        let store = store_dir(&repo_path);
+        assert_eq!(store, temp.path().join(".gentoo.sqfs"));
+        assert_eq!(current_image(&store), None);
+
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join(SUMS_FILE), "").unwrap();
+        let image = store.join("gentoo-20240101.xz.sqfs");
+        std::fs::write(&image, b"hsqs").unwrap();
+        assert_eq!(current_image(&store), Some(image.clone()));
+
+        let digest = crate::checksum::hash_bytes(b"hsqs", "SHA512").unwrap();
+        assert!(sha512_matches(&image, &digest));
+        assert!(!sha512_matches(&image, &"0".repeat(128)));
+    }
+}
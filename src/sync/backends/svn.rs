@@ -53,6 +53,7 @@ impl SyncBackend for SvnSync {
             success: true,
             message: format!("Successfully created {} via svn", repo.name),
             changes: true,
+            stats: None,
         })
     }
 
@@ -81,6 +82,7 @@ impl SyncBackend for SvnSync {
             success: true,
             message: format!("Successfully synced {} via svn", repo.name),
             changes,
+            stats: None,
         })
     }
 }
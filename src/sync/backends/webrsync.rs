@@ -1,7 +1,55 @@
+// webrsync.rs -- emerge-webrsync over plain HTTP: the newest dated portage snapshot is
+// downloaded (resuming an interrupted download), checked against its md5sum and OpenPGP
+// signature, extracted beside the repository and renamed into place
+
 use crate::sync::{SyncBackend, SyncError, SyncResult};
 use std::path::{Path, PathBuf};
 use tokio::process::Command;
-use tokio::fs;
+
+/// How many days back to look for a published snapshot
+pub const MAX_SNAPSHOT_AGE_DAYS: i64 = 7;
+
+/// The snapshot published for a YYYYMMDD date
+pub fn snapshot_name(date: &str) -> String {
+    format!("portage-{}.tar.xz", date)
+}
+
+/// The YYYYMMDD date of the snapshot a tree was extracted from, by its metadata/timestamp.x
+pub fn tree_date(repo_path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(repo_path.join("metadata/timestamp.x")).ok()?;
+    let seconds = content.split_whitespace().next()?.parse::<i64>().ok()?;
+    chrono::DateTime::from_timestamp(seconds, 0).map(|time| time.format("%Y%m%d").to_string())
+}
+
+/// The digest an .md5sum file gives for `name`
+pub fn parse_md5sum(content: &str, name: &str) -> Option<String> {
+    content.lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, file)| file.trim().trim_start_matches('*') == name)
+        .map(|(digest, _)| digest.to_lowercase())
+}
+
+fn file_md5(path: &Path) -> std::io::Result<String> {
+    let mut checksums = crate::checksum::hash_file(path, &["MD5"])?;
+    Ok(checksums.hashes.remove("MD5").unwrap_or_default())
+}
+
+/// Replace `repo_path` with the tree extracted to `staging`
+fn swap_in(staging: &Path, repo_path: &Path) -> std::io::Result<()> {
+    let name = repo_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let old = repo_path.with_file_name(format!(".{}.webrsync-old", name));
+    if old.exists() {
+        std::fs::remove_dir_all(&old)?;
+    }
+    if repo_path.exists() {
+        std::fs::rename(repo_path, &old)?;
+    }
+    std::fs::rename(staging, repo_path)?;
+    if old.exists() {
+        std::fs::remove_dir_all(&old)?;
+    }
+    Ok(())
+}
 
 pub struct WebRsyncSync;
 
@@ -10,27 +58,43 @@ impl WebRsyncSync {
         WebRsyncSync
     }
 
-    async fn download_snapshot(uri: &str, dest: &Path) -> Result<PathBuf, SyncError> {
-        let snapshot_url = format!("{}/portage-latest.tar.xz", uri.trim_end_matches('/'));
-        let snapshot_file = dest.join("portage-latest.tar.xz");
-
+    /// Download `name` from `uri` into `dir`, continuing a partial download left there
+    async fn download(uri: &str, name: &str, dir: &Path) -> Result<PathBuf, SyncError> {
+        let url = format!("{}/{}", uri.trim_end_matches('/'), name);
+        let dest = dir.join(name);
         let output = Command::new("wget")
             .arg("--quiet")
+            .arg("--continue")
             .arg("--timeout=180")
             .arg("--tries=3")
             .arg("-O")
-            .arg(&snapshot_file)
-            .arg(&snapshot_url)
+            .arg(&dest)
+            .arg(&url)
             .output()
             .await
             .map_err(|e| SyncError::Command(format!("Failed to execute wget: {}", e)))?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(SyncError::Network(format!("Failed to download snapshot: {}", stderr)));
+            return Err(SyncError::Network(format!("Failed to download {}: {}", url, stderr)));
         }
 
-        Ok(snapshot_file)
+        Ok(dest)
+    }
+
+    /// The newest snapshot published within MAX_SNAPSHOT_AGE_DAYS, with its md5sum file
+    async fn find_snapshot(uri: &str, dir: &Path) -> Result<(String, String, PathBuf), SyncError> {
+        let today = chrono::Utc::now().date_naive();
+        for age in 0..=MAX_SNAPSHOT_AGE_DAYS {
+            let date = (today - chrono::Duration::days(age)).format("%Y%m%d").to_string();
+            let name = snapshot_name(&date);
+            let md5sum = format!("{}.md5sum", name);
+            let _ = std::fs::remove_file(dir.join(&md5sum));
+            if let Ok(path) = Self::download(uri, &md5sum, dir).await {
+                return Ok((date, name, path));
+            }
+        }
+        Err(SyncError::Network(format!("No snapshot from the last {} days at {}", MAX_SNAPSHOT_AGE_DAYS, uri)))
     }
 
     async fn extract_snapshot(snapshot: &Path, dest: &Path) -> Result<(), SyncError> {
@@ -52,38 +116,21 @@ impl WebRsyncSync {
         Ok(())
     }
 
-    async fn verify_snapshot(snapshot: &Path, signature_uri: &str) -> Result<(), SyncError> {
-        let sig_file = format!("{}.gpgsig", snapshot.display());
-        let sig_url = format!("{}.gpgsig", signature_uri);
-
-        let output = Command::new("wget")
-            .arg("--quiet")
-            .arg("--timeout=60")
-            .arg("-O")
-            .arg(&sig_file)
-            .arg(&sig_url)
-            .output()
-            .await
-            .map_err(|e| SyncError::Command(format!("Failed to download signature: {}", e)))?;
-
-        if !output.status.success() {
-            return Err(SyncError::Validation("Signature file not available".to_string()));
-        }
-
-        let verify_output = Command::new("gpg")
-            .arg("--verify")
-            .arg(&sig_file)
-            .arg(snapshot)
-            .output()
-            .await
-            .map_err(|e| SyncError::Command(format!("Failed to verify signature: {}", e)))?;
-
-        if !verify_output.status.success() {
-            let stderr = String::from_utf8_lossy(&verify_output.stderr);
-            return Err(SyncError::Validation(format!("Signature verification failed: {}", stderr)));
+    /// Check the snapshot against its md5sum file and detached signature
+    async fn verify_snapshot(repo: &crate::porttree::Repository, uri: &str, snapshot: &Path, md5sum: &Path) -> Result<(), SyncError> {
+        let name = snapshot.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let expected = parse_md5sum(&std::fs::read_to_string(md5sum)?, &name)
+            .ok_or_else(|| SyncError::Validation(format!("{} has no digest for {}", md5sum.display(), name)))?;
+        if file_md5(snapshot)? != expected {
+            return Err(SyncError::Validation(format!("MD5 mismatch for {}", name)));
         }
 
-        Ok(())
+        let dir = snapshot.parent().unwrap_or(Path::new("."));
+        let signature = Self::download(uri, &format!("{}.gpgsig", name), dir).await
+            .map_err(|_| SyncError::Validation("Signature file not available".to_string()))?;
+        let verified = crate::sync::openpgp::verify_detached(&crate::sync::openpgp::configured_keys(repo), &signature, snapshot).await;
+        let _ = std::fs::remove_file(&signature);
+        verified
     }
 }
 
@@ -102,35 +149,7 @@ impl SyncBackend for WebRsyncSync {
     }
 
     async fn new_repo(&self, repo: &crate::porttree::Repository) -> Result<SyncResult, SyncError> {
-        let uri = repo.sync_uri.as_ref()
-            .ok_or_else(|| SyncError::Repository("No sync-uri specified".to_string()))?;
-
-        let repo_path = Path::new(&repo.location);
-        fs::create_dir_all(repo_path)
-            .await
-            .map_err(|e| SyncError::IO(e))?;
-
-        let temp_dir = repo_path.parent()
-            .ok_or_else(|| SyncError::Repository("Invalid repository path".to_string()))?
-            .join(".webrsync-temp");
-
-        fs::create_dir_all(&temp_dir)
-            .await
-            .map_err(|e| SyncError::IO(e))?;
-
-        let snapshot = Self::download_snapshot(uri, &temp_dir).await?;
-        
-        Self::extract_snapshot(&snapshot, repo_path).await?;
-
-        fs::remove_file(&snapshot)
-            .await
-            .map_err(|e| SyncError::IO(e))?;
-
-        Ok(SyncResult {
-            success: true,
-            message: format!("Successfully created repository from webrsync snapshot"),
-            changes: true,
-        })
+        self.sync(repo).await
     }
 
     async fn sync(&self, repo: &crate::porttree::Repository) -> Result<SyncResult, SyncError> {
@@ -138,67 +157,49 @@ impl SyncBackend for WebRsyncSync {
             .ok_or_else(|| SyncError::Repository("No sync-uri specified".to_string()))?;
 
         let repo_path = Path::new(&repo.location);
-        
-        if !repo_path.exists() {
-            return self.new_repo(repo).await;
+        let parent = repo_path.parent()
+            .ok_or_else(|| SyncError::Repository("Invalid repository path".to_string()))?;
+        // Partial downloads stay here to be resumed by the next attempt
+        let temp_dir = parent.join(".webrsync-temp");
+        std::fs::create_dir_all(&temp_dir)?;
+
+        let (date, name, md5sum) = Self::find_snapshot(uri, &temp_dir).await?;
+        if tree_date(repo_path).is_some_and(|current| current >= date) {
+            let _ = std::fs::remove_file(&md5sum);
+            return Ok(SyncResult {
+                success: true,
+                message: format!("{} is already at snapshot {}", repo.name, date),
+                changes: false,
+                stats: None,
+            });
         }
 
-        let temp_dir = repo_path.parent()
-            .ok_or_else(|| SyncError::Repository("Invalid repository path".to_string()))?
-            .join(".webrsync-temp");
-
-        fs::create_dir_all(&temp_dir)
-            .await
-            .map_err(|e| SyncError::IO(e))?;
-
-        let snapshot = Self::download_snapshot(uri, &temp_dir).await?;
-
-        let backup_dir = repo_path.parent()
-            .ok_or_else(|| SyncError::Repository("Invalid repository path".to_string()))?
-            .join(format!(".{}-backup", repo.name));
-
-        if backup_dir.exists() {
-            fs::remove_dir_all(&backup_dir)
-                .await
-                .map_err(|e| SyncError::IO(e))?;
+        let snapshot = Self::download(uri, &name, &temp_dir).await?;
+        if let Err(e) = Self::verify_snapshot(repo, uri, &snapshot, &md5sum).await {
+            // A bad download is not worth resuming
+            let _ = std::fs::remove_file(&snapshot);
+            return Err(e);
         }
 
-        fs::rename(repo_path, &backup_dir)
-            .await
-            .map_err(|e| SyncError::IO(e))?;
-
-        fs::create_dir_all(repo_path)
-            .await
-            .map_err(|e| SyncError::IO(e))?;
-
-        match Self::extract_snapshot(&snapshot, repo_path).await {
-            Ok(_) => {
-                fs::remove_dir_all(&backup_dir)
-                    .await
-                    .map_err(|e| SyncError::IO(e))?;
-
-                fs::remove_file(&snapshot)
-                    .await
-                    .map_err(|e| SyncError::IO(e))?;
-
-                Ok(SyncResult {
-                    success: true,
-                    message: format!("Successfully synced repository from webrsync snapshot"),
-                    changes: true,
-                })
-            }
-            Err(e) => {
-                fs::remove_dir_all(repo_path)
-                    .await
-                    .ok();
-                
-                fs::rename(&backup_dir, repo_path)
-                    .await
-                    .map_err(|e| SyncError::IO(e))?;
-
-                Err(e)
-            }
+        let staging = parent.join(format!(".{}.webrsync-new", repo.name));
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
         }
+        std::fs::create_dir_all(&staging)?;
+        if let Err(e) = Self::extract_snapshot(&snapshot, &staging).await {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        swap_in(&staging, repo_path)?;
+
+        let _ = std::fs::remove_file(&snapshot);
+        let _ = std::fs::remove_file(&md5sum);
+        Ok(SyncResult {
+            success: true,
+            message: format!("Successfully synced {} from snapshot {}", repo.name, name),
+            changes: true,
+            stats: None,
+        })
     }
 }
 
@@ -233,6 +234,33 @@ mod tests {
         assert!(sync.exists(temp_dir.path()).await);
     }
 
+    #[test]
+    fn test_snapshot_files() {
+        assert_eq!(snapshot_name("20240103"), "portage-20240103.tar.xz");
+        let md5sum = "d41d8cd98f00b204e9800998ecf8427e  portage-20240103.tar.xz\n";
+        assert_eq!(parse_md5sum(md5sum, "portage-20240103.tar.xz").as_deref(), Some("d41d8cd98f00b204e9800998ecf8427e"));
+        assert_eq!(parse_md5sum(md5sum, "portage-20240102.tar.xz"), None);
+
+        let temp_dir = TempDir::new().unwrap();
+        let empty = temp_dir.path().join("empty");
+        std::fs::write(&empty, "").unwrap();
+        assert_eq!(file_md5(&empty).unwrap(), "d41d8cd98f00b204e9800998ecf8427e");
+
+        let repo_path = temp_dir.path().join("gentoo");
+        assert_eq!(tree_date(&repo_path), None);
+        std::fs::create_dir_all(repo_path.join("metadata")).unwrap();
+        std::fs::write(repo_path.join("metadata/timestamp.x"), "1704240001 Wed 03 Jan 2024 00:00:01 +0000\n").unwrap();
+        assert_eq!(tree_date(&repo_path).as_deref(), Some("20240103"));
+
+        // The extracted tree replaces the old one whole
+        let staging = temp_dir.path().join(".gentoo.webrsync-new");
+        std::fs::create_dir_all(staging.join("profiles")).unwrap();
+        swap_in(&staging, &repo_path).unwrap();
+        assert!(repo_path.join("profiles").is_dir());
+        assert!(!repo_path.join("metadata").exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
     #[tokio::test]
     async fn test_new_repo_no_uri() {
         let temp_dir = TempDir::new().unwrap();
@@ -246,6 +274,14 @@ mod tests {
             auto_sync: true,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: crate::porttree::SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
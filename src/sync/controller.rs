@@ -27,6 +27,14 @@ mod tests {
             auto_sync: true,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -57,6 +65,14 @@ mod tests {
             auto_sync: true,
             sync_depth: None,
             sync_hooks_only_on_change: false,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
             sync_metadata: SyncMetadata {
                 last_sync: None,
                 last_attempt: None,
@@ -0,0 +1,131 @@
+// hooks.rs -- post-sync hooks: /etc/portage/repo.postsync.d scripts run for each synced
+// repository, /etc/portage/postsync.d scripts once after the whole sync
+
+use crate::porttree::Repository;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Hooks run with the name, sync-uri and location of each synced repository
+pub const REPO_POSTSYNC_DIR: &str = "etc/portage/repo.postsync.d";
+
+/// Hooks run once after the sync
+pub const POSTSYNC_DIR: &str = "etc/portage/postsync.d";
+
+/// The executable files in `dir`, in the order they run
+pub fn hook_scripts(dir: &Path) -> Vec<PathBuf> {
+    let mut scripts: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| !path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with('.')))
+                .filter(|path| path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0))
+                .collect()
+        })
+        .unwrap_or_default();
+    scripts.sort();
+    scripts
+}
+
+/// Run every hook in `dir` with `args`; returns why each one that failed did
+pub fn run_hooks(dir: &Path, args: &[&str]) -> Vec<String> {
+    let mut failures = Vec::new();
+    for script in hook_scripts(dir) {
+        match Command::new(&script).args(args).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => failures.push(format!("Hook {} failed with {}", script.display(), status)),
+            Err(e) => failures.push(format!("Failed to run hook {}: {}", script.display(), e)),
+        }
+    }
+    failures
+}
+
+/// Run the repo.postsync.d hooks under `root` for a repository that synced; with
+/// sync-hooks-only-on-change they are skipped when the sync changed nothing
+pub fn run_repo_hooks(root: &Path, repo: &Repository, changes: bool) -> Vec<String> {
+    if repo.sync_hooks_only_on_change && !changes {
+        return Vec::new();
+    }
+    let uri = repo.sync_uri.as_deref().unwrap_or("");
+    run_hooks(&root.join(REPO_POSTSYNC_DIR), &[&repo.name, uri, &repo.location])
+}
+
+/// Whether the postsync.d hooks are due after the repositories that synced, given as
+/// (repository, whether it changed): some repository changed or runs hooks regardless
+pub fn postsync_due(synced: &[(&Repository, bool)]) -> bool {
+    synced.iter().any(|(repo, changes)| *changes || !repo.sync_hooks_only_on_change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::porttree::SyncMetadata;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn repo(name: &str, only_on_change: bool) -> Repository {
+        Repository {
+            name: name.to_string(),
+            location: format!("/var/db/repos/{}", name),
+            sync_type: Some("git".to_string()),
+            sync_uri: Some(format!("https://example.org/{}.git", name)),
+            auto_sync: true,
+            sync_depth: None,
+            sync_hooks_only_on_change: only_on_change,
+            sync_verify_metamanifest: false,
+            sync_openpgp_key_path: None,
+            sync_interval: None,
+            sync_allow_hardlinks: true,
+            sync_openpgp_key_refresh: true,
+            sync_openpgp_keyserver: None,
+            sync_submodules: Vec::new(),
+            sync_squashfs_mount: false,
+            sync_metadata: SyncMetadata {
+                last_sync: None,
+                last_attempt: None,
+                success: false,
+                error_message: None,
+            },
+            eclass_cache: HashMap::new(),
+            metadata_cache: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_repo_hooks() {
+        let temp = TempDir::new().unwrap();
+        let hook_dir = temp.path().join(REPO_POSTSYNC_DIR);
+        std::fs::create_dir_all(&hook_dir).unwrap();
+        let log = temp.path().join("log");
+        for name in ["20-second", "10-first"] {
+            let script = hook_dir.join(name);
+            std::fs::write(&script, format!("#!/bin/sh\necho \"{} $*\" >> {}\n", name, log.display())).unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        // Not executable, so not a hook
+        std::fs::write(hook_dir.join("README"), "").unwrap();
+        assert_eq!(hook_scripts(&hook_dir).len(), 2);
+
+        let guru = repo("guru", true);
+        assert!(run_repo_hooks(temp.path(), &guru, false).is_empty());
+        assert!(!log.exists());
+        assert!(run_repo_hooks(temp.path(), &guru, true).is_empty());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(),
+            "10-first guru https://example.org/guru.git /var/db/repos/guru\n\
+             20-second guru https://example.org/guru.git /var/db/repos/guru\n");
+
+        let gentoo = repo("gentoo", false);
+        assert!(!postsync_due(&[(&guru, false)]));
+        assert!(postsync_due(&[(&guru, false), (&gentoo, false)]));
+        assert!(postsync_due(&[(&guru, true)]));
+
+        // Failures come back to the caller, which reports them
+        let failing = temp.path().join(POSTSYNC_DIR).join("10-fail");
+        std::fs::create_dir_all(failing.parent().unwrap()).unwrap();
+        std::fs::write(&failing, "#!/bin/sh\nexit 3\n").unwrap();
+        std::fs::set_permissions(&failing, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let failures = run_hooks(&temp.path().join(POSTSYNC_DIR), &[]);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with(&format!("Hook {} failed", failing.display())));
+    }
+}
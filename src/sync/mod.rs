@@ -1,5 +1,7 @@
 pub mod backends;
 pub mod controller;
+pub mod hooks;
+pub mod openpgp;
 
 use crate::exception::InvalidData;
 use std::fmt;
@@ -39,6 +41,19 @@ pub struct SyncResult {
     pub success: bool,
     pub message: String,
     pub changes: bool,
+    pub stats: Option<TransferStats>,
+}
+
+/// Transfer statistics reported by backends that track them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferStats {
+    pub mirror: String,
+    pub files_total: usize,
+    pub files_transferred: usize,
+    pub files_deleted: usize,
+    pub bytes_received: u64,
+    pub literal_bytes: u64,
+    pub matched_bytes: u64,
 }
 
 #[async_trait::async_trait]
@@ -80,6 +95,7 @@ mod tests {
             success: true,
             message: "Test message".to_string(),
             changes: true,
+            stats: None,
         };
 
         assert!(result.success);
@@ -0,0 +1,177 @@
+// openpgp.rs -- the keys repository Manifests are verified with, refreshed from WKD or a
+// keyserver into a keyring kept under /var/cache/edb
+
+use crate::porttree::Repository;
+use crate::sync::SyncError;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::process::Command;
+
+/// How long refreshed keys are used before they are refreshed again
+pub const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(86400);
+
+/// Refreshed keyrings, one per repository
+pub const KEYRING_DIR: &str = "var/cache/edb/openpgp";
+
+/// The keys repos.conf names, or the Gentoo release keys
+pub fn configured_keys(repo: &Repository) -> PathBuf {
+    PathBuf::from(repo.sync_openpgp_key_path.as_deref().unwrap_or(crate::manifest::DEFAULT_KEY_PATH))
+}
+
+pub fn refreshed_keyring(root: &Path, repo: &Repository) -> PathBuf {
+    root.join(KEYRING_DIR).join(format!("{}.asc", repo.name))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The keys to verify with: the refreshed keyring, unless the configured keys were
+/// updated after it
+pub fn key_path(root: &Path, repo: &Repository) -> PathBuf {
+    let refreshed = refreshed_keyring(root, repo);
+    let configured = configured_keys(repo);
+    match (modified(&refreshed), modified(&configured)) {
+        (Some(refreshed_at), Some(configured_at)) if configured_at > refreshed_at => configured,
+        (Some(_), _) if repo.sync_openpgp_key_refresh => refreshed,
+        _ => configured,
+    }
+}
+
+/// Whether the keys of `repo` are due for a refresh at `now`
+pub fn refresh_due(root: &Path, repo: &Repository, now: SystemTime) -> bool {
+    if !repo.sync_openpgp_key_refresh {
+        return false;
+    }
+    let Some(refreshed_at) = modified(&refreshed_keyring(root, repo)) else {
+        return true;
+    };
+    if modified(&configured_keys(repo)).is_some_and(|configured_at| configured_at > refreshed_at) {
+        return true;
+    }
+    now.duration_since(refreshed_at).is_ok_and(|age| age >= KEY_REFRESH_INTERVAL)
+}
+
+async fn gpg(home: &Path, args: &[&str]) -> Result<Vec<u8>, SyncError> {
+    let output = Command::new("gpg")
+        .arg("--batch")
+        .arg("--homedir")
+        .arg(home)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| SyncError::Command(format!("Failed to run gpg: {}", e)))?;
+    if !output.status.success() {
+        return Err(SyncError::Command(format!("gpg {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(output.stdout)
+}
+
+/// Check the detached `signature` of `file` against `keys` alone
+pub async fn verify_detached(keys: &Path, signature: &Path, file: &Path) -> Result<(), SyncError> {
+    let home = tempfile::TempDir::new()?;
+    gpg(home.path(), &["--import", &keys.display().to_string()]).await?;
+    let status = gpg(home.path(), &["--status-fd", "1", "--verify", &signature.display().to_string(), &file.display().to_string()]).await
+        .map_err(|_| SyncError::Validation(format!("Bad OpenPGP signature on {}", file.display())))?;
+    if !String::from_utf8_lossy(&status).lines().any(|line| line.starts_with("[GNUPG:] GOODSIG")) {
+        return Err(SyncError::Validation(format!("Bad OpenPGP signature on {}", file.display())));
+    }
+    Ok(())
+}
+
+/// The e-mail addresses of the user IDs in `gpg --with-colons` output, which WKD looks
+/// keys up by
+fn uid_emails(listing: &str) -> Vec<String> {
+    let mut emails: Vec<String> = listing.lines()
+        .filter(|line| line.starts_with("uid:"))
+        .filter_map(|line| line.split(':').nth(9))
+        .filter_map(|uid| uid.rsplit_once('<').and_then(|(_, rest)| rest.split_once('>')).map(|(email, _)| email.to_string()))
+        .collect();
+    emails.sort();
+    emails.dedup();
+    emails
+}
+
+/// Refresh the configured keys of `repo` into its keyring under `root`, from its
+/// sync-openpgp-keyserver or else WKD
+pub async fn refresh_keys(root: &Path, repo: &Repository) -> Result<PathBuf, SyncError> {
+    let configured = configured_keys(repo);
+    let home = tempfile::TempDir::new()?;
+    gpg(home.path(), &["--import", &configured.display().to_string()]).await?;
+
+    match &repo.sync_openpgp_keyserver {
+        Some(keyserver) => {
+            gpg(home.path(), &["--keyserver", keyserver, "--refresh-keys"]).await?;
+        }
+        None => {
+            let listing = gpg(home.path(), &["--with-colons", "--list-keys"]).await?;
+            let emails = uid_emails(&String::from_utf8_lossy(&listing));
+            let mut args = vec!["--auto-key-locate", "clear,nodefault,wkd", "--locate-external-keys"];
+            args.extend(emails.iter().map(|e| e.as_str()));
+            gpg(home.path(), &args).await?;
+        }
+    }
+
+    let keys = gpg(home.path(), &["--armor", "--export"]).await?;
+    if keys.is_empty() {
+        return Err(SyncError::Validation(format!("No keys left after refreshing {}", configured.display())));
+    }
+    let keyring = refreshed_keyring(root, repo);
+    if let Some(parent) = keyring.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = keyring.with_extension("asc.tmp");
+    std::fs::write(&tmp, keys)?;
+    std::fs::rename(&tmp, &keyring)?;
+    Ok(keyring)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::porttree::PortTree;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_refresh_schedule() {
+        let temp = TempDir::new().unwrap();
+        let configured = temp.path().join("gentoo-release.asc");
+        std::fs::write(&configured, "keys").unwrap();
+        let mut porttree = PortTree::new("/");
+        porttree.parse_repos_conf(&format!(
+            "[gentoo]\nlocation = /var/db/repos/gentoo\nsync-openpgp-key-path = {}\n", configured.display()));
+        let repo = &porttree.repositories["gentoo"];
+        let set_mtime = |path: &Path, time: SystemTime| std::fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+        let now = SystemTime::now();
+        set_mtime(&configured, now - Duration::from_secs(30 * 86400));
+
+        // Never refreshed: the configured keys, and a refresh is due
+        assert!(refresh_due(temp.path(), repo, now));
+        assert_eq!(key_path(temp.path(), repo), configured);
+
+        let refreshed = refreshed_keyring(temp.path(), repo);
+        std::fs::create_dir_all(refreshed.parent().unwrap()).unwrap();
+        std::fs::write(&refreshed, "refreshed keys").unwrap();
+        set_mtime(&refreshed, now - Duration::from_secs(3600));
+        assert!(!refresh_due(temp.path(), repo, now));
+        assert_eq!(key_path(temp.path(), repo), refreshed);
+        assert!(refresh_due(temp.path(), repo, now + KEY_REFRESH_INTERVAL));
+
+        // Keys updated by their package take over until refreshed again
+        set_mtime(&configured, now);
+        assert!(refresh_due(temp.path(), repo, now));
+        assert_eq!(key_path(temp.path(), repo), configured);
+
+        let mut no_refresh = repo.clone();
+        no_refresh.sync_openpgp_key_refresh = false;
+        assert!(!refresh_due(temp.path(), &no_refresh, now + KEY_REFRESH_INTERVAL));
+    }
+
+    #[test]
+    fn test_uid_emails() {
+        let listing = "pub:-:4096:1:DB6B8C1F96D8BF6D:1554716208:::-:::scSC::::::23::0:\n\
+            uid:-::::1554716208::2C6B4E5F::Gentoo ebuild repository signing key (Automated Signing Key) <infrastructure@gentoo.org>::::::::::0:\n\
+            uid:-::::1554716208::3D7C5E6A::Gentoo Portage Snapshot Signing Key (Automated Signing Key) <infrastructure@gentoo.org>::::::::::0:\n";
+        assert_eq!(uid_emails(listing), vec!["infrastructure@gentoo.org"]);
+    }
+}
@@ -0,0 +1,214 @@
+// toolchain.rs -- CHOST/CBUILD/CTARGET aware tool selection, the tc-export of
+// toolchain-funcs.eclass, for native and crossdev-style builds
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The variables tc-export knows and the program each names
+pub const TOOLS: &[(&str, &str)] = &[
+    ("CC", "gcc"),
+    ("CXX", "g++"),
+    ("CPP", "cpp"),
+    ("AR", "ar"),
+    ("AS", "as"),
+    ("LD", "ld"),
+    ("NM", "nm"),
+    ("OBJCOPY", "objcopy"),
+    ("OBJDUMP", "objdump"),
+    ("RANLIB", "ranlib"),
+    ("READELF", "readelf"),
+    ("STRIP", "strip"),
+    ("PKG_CONFIG", "pkg-config"),
+];
+
+/// The systems a build runs on (CBUILD), produces code for (CHOST) and, for compilers,
+/// targets (CTARGET)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Toolchain {
+    pub chost: Option<String>,
+    pub cbuild: Option<String>,
+    pub ctarget: Option<String>,
+    path: String,
+}
+
+impl Toolchain {
+    /// The toolchain of a build environment; CBUILD defaults to CHOST and CTARGET to
+    /// nothing, as in Portage
+    pub fn from_env(env: &HashMap<String, String>) -> Self {
+        let get = |var: &str| env.get(var).filter(|v| !v.is_empty()).cloned();
+        let chost = get("CHOST");
+        Toolchain {
+            cbuild: get("CBUILD").or_else(|| chost.clone()),
+            ctarget: get("CTARGET"),
+            chost,
+            path: get("PATH").or_else(|| std::env::var("PATH").ok()).unwrap_or_default(),
+        }
+    }
+
+    /// Whether the build produces code for another system than the one it runs on
+    pub fn is_cross(&self) -> bool {
+        self.chost.is_some() && self.chost != self.cbuild
+    }
+
+    /// The program for `var`: the variable when set, else `tool` prefixed with CHOST when
+    /// cross compiling or when the prefixed one is on PATH, else plain `tool`
+    pub fn tool(&self, env: &HashMap<String, String>, var: &str, tool: &str) -> String {
+        if let Some(value) = env.get(var).filter(|v| !v.is_empty()) {
+            return value.clone();
+        }
+        self.prefixed(self.chost.as_deref(), tool, self.is_cross())
+    }
+
+    /// The program for `BUILD_<var>`, for helpers run during the build, prefixed with CBUILD
+    pub fn build_tool(&self, env: &HashMap<String, String>, var: &str, tool: &str) -> String {
+        if let Some(value) = env.get(&format!("BUILD_{}", var)).filter(|v| !v.is_empty()) {
+            return value.clone();
+        }
+        self.prefixed(self.cbuild.as_deref(), tool, false)
+    }
+
+    fn prefixed(&self, host: Option<&str>, tool: &str, always: bool) -> String {
+        if let Some(host) = host {
+            let prefixed = format!("{}-{}", host, tool);
+            if always || self.on_path(&prefixed) {
+                return prefixed;
+            }
+        }
+        tool.to_string()
+    }
+
+    fn on_path(&self, program: &str) -> bool {
+        self.path.split(':').any(|dir| !dir.is_empty() && Path::new(dir).join(program).is_file())
+    }
+
+    /// tc-export: set the unset variables of TOOLS in `env`, and their BUILD_ counterparts
+    /// when cross compiling
+    pub fn export(&self, env: &mut HashMap<String, String>) {
+        let pkg_config_set = env.get("PKG_CONFIG").is_some_and(|p| !p.is_empty());
+        for &(var, tool) in TOOLS {
+            let value = self.tool(env, var, tool);
+            env.insert(var.to_string(), value);
+            if self.is_cross() {
+                let value = self.build_tool(env, var, tool);
+                env.insert(format!("BUILD_{}", var), value);
+            }
+        }
+        if self.is_cross() && !pkg_config_set {
+            self.export_pkg_config(env);
+        }
+    }
+
+    /// Use the ${CHOST}-pkg-config wrapper crossdev installs, else point a plain pkg-config
+    /// at SYSROOT
+    fn export_pkg_config(&self, env: &mut HashMap<String, String>) {
+        if self.on_path(&env["PKG_CONFIG"]) {
+            return;
+        }
+        env.insert("PKG_CONFIG".to_string(), "pkg-config".to_string());
+        let Some(sysroot) = env.get("SYSROOT").filter(|s| !s.is_empty()).cloned() else {
+            return;
+        };
+        env.entry("PKG_CONFIG_SYSROOT_DIR".to_string()).or_insert_with(|| sysroot.clone());
+        env.entry("PKG_CONFIG_LIBDIR".to_string())
+            .or_insert_with(|| format!("{0}/usr/lib/pkgconfig:{0}/usr/share/pkgconfig", sysroot));
+    }
+
+    /// --build/--host/--target for an autotools ./configure, empty for a native build
+    pub fn configure_args(&self) -> Vec<String> {
+        if !self.is_cross() && self.ctarget.is_none() {
+            return Vec::new();
+        }
+        let mut args = Vec::new();
+        if let Some(cbuild) = &self.cbuild {
+            args.push(format!("--build={}", cbuild));
+        }
+        if let Some(chost) = &self.chost {
+            args.push(format!("--host={}", chost));
+        }
+        if let Some(ctarget) = &self.ctarget {
+            args.push(format!("--target={}", ctarget));
+        }
+        args
+    }
+
+    /// CMake cache entries naming the cross compilers and SYSROOT, empty for a native build
+    pub fn cmake_args(&self, env: &HashMap<String, String>) -> Vec<String> {
+        if !self.is_cross() {
+            return Vec::new();
+        }
+        let mut args = vec![
+            format!("-DCMAKE_C_COMPILER={}", self.tool(env, "CC", "gcc")),
+            format!("-DCMAKE_CXX_COMPILER={}", self.tool(env, "CXX", "g++")),
+            "-DCMAKE_SYSTEM_NAME=Linux".to_string(),
+        ];
+        if let Some(sysroot) = env.get("SYSROOT").filter(|s| !s.is_empty()) {
+            args.push(format!("-DCMAKE_SYSROOT={}", sysroot));
+            args.push(format!("-DCMAKE_FIND_ROOT_PATH={}", sysroot));
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_native_and_cross() {
+        let temp = TempDir::new().unwrap();
+        std::fs::write(temp.path().join("x86_64-pc-linux-gnu-gcc"), "").unwrap();
+        let path = temp.path().display().to_string();
+
+        // Native: prefixed only when installed, nothing for configure
+        let native = HashMap::from([
+            ("CHOST".to_string(), "x86_64-pc-linux-gnu".to_string()),
+            ("PATH".to_string(), path.clone()),
+        ]);
+        let tc = Toolchain::from_env(&native);
+        assert!(!tc.is_cross());
+        assert_eq!(tc.tool(&native, "CC", "gcc"), "x86_64-pc-linux-gnu-gcc");
+        assert_eq!(tc.tool(&native, "AR", "ar"), "ar");
+        assert!(tc.configure_args().is_empty());
+        let mut env = native.clone();
+        env.insert("CXX".to_string(), "clang++".to_string());
+        tc.export(&mut env);
+        assert_eq!(env["CXX"], "clang++");
+        assert!(!env.contains_key("BUILD_CC") && !env.contains_key("PKG_CONFIG_SYSROOT_DIR"));
+
+        // Cross: CHOST-prefixed tools, CBUILD ones for the build, pkg-config in SYSROOT
+        let mut env = HashMap::from([
+            ("CHOST".to_string(), "aarch64-unknown-linux-gnu".to_string()),
+            ("CBUILD".to_string(), "x86_64-pc-linux-gnu".to_string()),
+            ("SYSROOT".to_string(), "/usr/aarch64-unknown-linux-gnu".to_string()),
+            ("PATH".to_string(), path),
+        ]);
+        let tc = Toolchain::from_env(&env);
+        assert!(tc.is_cross());
+        assert_eq!(tc.configure_args(), vec!["--build=x86_64-pc-linux-gnu", "--host=aarch64-unknown-linux-gnu"]);
+        tc.export(&mut env);
+        assert_eq!(env["CC"], "aarch64-unknown-linux-gnu-gcc");
+        assert_eq!(env["STRIP"], "aarch64-unknown-linux-gnu-strip");
+        assert_eq!(env["BUILD_CC"], "x86_64-pc-linux-gnu-gcc");
+        assert_eq!(env["BUILD_AR"], "ar");
+        assert_eq!(env["PKG_CONFIG"], "pkg-config");
+        assert_eq!(env["PKG_CONFIG_SYSROOT_DIR"], "/usr/aarch64-unknown-linux-gnu");
+        assert!(env["PKG_CONFIG_LIBDIR"].starts_with("/usr/aarch64-unknown-linux-gnu/usr/lib/pkgconfig"));
+        assert!(tc.cmake_args(&env).contains(&"-DCMAKE_SYSROOT=/usr/aarch64-unknown-linux-gnu".to_string()));
+    }
+
+    #[test]
+    fn test_cross_pkg_config_wrapper() {
+        let temp = TempDir::new().unwrap();
+        std::fs::write(temp.path().join("aarch64-unknown-linux-gnu-pkg-config"), "").unwrap();
+        let mut env = HashMap::from([
+            ("CHOST".to_string(), "aarch64-unknown-linux-gnu".to_string()),
+            ("CBUILD".to_string(), "x86_64-pc-linux-gnu".to_string()),
+            ("SYSROOT".to_string(), "/usr/aarch64-unknown-linux-gnu".to_string()),
+            ("PATH".to_string(), temp.path().display().to_string()),
+        ]);
+        Toolchain::from_env(&env).export(&mut env);
+        assert_eq!(env["PKG_CONFIG"], "aarch64-unknown-linux-gnu-pkg-config");
+        assert!(!env.contains_key("PKG_CONFIG_SYSROOT_DIR"));
+    }
+}
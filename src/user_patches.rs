@@ -0,0 +1,219 @@
+// user_patches.rs -- user patches from /etc/portage/patches, applied by eapply_user in
+// src_prepare, and the eapply helper that applies them
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::exception::InvalidData;
+
+/// Marker under ${T} once eapply_user has run, so the patches are applied only once
+pub const APPLIED_MARKER: &str = ".user_patches_applied";
+
+/// Directories searched for a version's patches, most specific first:
+/// <category>/<pn>-<pv>-<pr>, <category>/<pn>-<pv> and <category>/<pn>, each first with
+/// :<slot>
+pub fn patch_dirs(config_root: &str, category: &str, pn: &str, version: &str, slot: Option<&str>) -> Vec<PathBuf> {
+    let base = Path::new(config_root).join("etc/portage/patches").join(category);
+    let (pv, pr) = match version.rsplit_once("-r") {
+        Some((pv, revision)) if !revision.is_empty() && revision.chars().all(|c| c.is_ascii_digit()) => (pv, revision),
+        _ => (version, "0"),
+    };
+    let slot = slot.map(|slot| slot.split('/').next().unwrap_or(slot)).filter(|slot| !slot.is_empty());
+    let mut dirs = Vec::new();
+    for name in [format!("{}-{}-r{}", pn, pv, pr), format!("{}-{}", pn, pv), pn.to_string()] {
+        if let Some(slot) = slot {
+            dirs.push(base.join(format!("{}:{}", name, slot)));
+        }
+        dirs.push(base.join(name));
+    }
+    dirs
+}
+
+/// The *.patch and *.diff files of the patch directories, sorted by name; a name in
+/// several directories is taken from the most specific one only
+pub fn find(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut patches = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        let mut files: Vec<PathBuf> = entries.flatten().map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "patch" || ext == "diff"))
+            .collect();
+        files.sort();
+        for file in files {
+            if seen.insert(file.file_name().map(|name| name.to_os_string())) {
+                patches.push(file);
+            }
+        }
+    }
+    patches.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    patches
+}
+
+/// The user patches for the package a build environment is for, from the
+/// PORTAGE_CONFIGROOT it names
+pub fn for_build(env: &HashMap<String, String>, slot: Option<&str>) -> Vec<PathBuf> {
+    let var = |name: &str| env.get(name).map(|value| value.as_str()).unwrap_or_default();
+    let config_root = env.get("PORTAGE_CONFIGROOT").map(|root| root.as_str()).unwrap_or("/");
+    find(&patch_dirs(config_root, var("CATEGORY"), var("PN"), var("PV"), slot))
+}
+
+/// Apply the patches in order in `dir`, saying which
+pub fn apply_all(patches: &[PathBuf], dir: &Path) -> Result<(), InvalidData> {
+    if patches.is_empty() {
+        return Ok(());
+    }
+    crate::eapply::eapply(patches, dir)?;
+    println!(" * User patches applied.");
+    Ok(())
+}
+
+/// The patches as the PORTAGE_USER_PATCHES array eapply_user reads
+pub fn bash_array(patches: &[PathBuf]) -> String {
+    let quoted: Vec<String> = patches.iter()
+        .map(|patch| format!("'{}'", patch.to_string_lossy().replace('\'', "'\\''")))
+        .collect();
+    format!("PORTAGE_USER_PATCHES=({})\n", quoted.join(" "))
+}
+
+/// eapply, eapply_user and the default src_prepare, as bash
+pub const BASH_FUNCTIONS: &str = r#"__eapply_file() {
+    local patch=$1 level tool=(patch -N -f) check=--dry-run
+    shift
+    einfo "Applying ${patch##*/} ..."
+    # Binary diffs only git apply handles, relative to here rather than to a repository
+    if grep -q -e '^GIT binary patch$' -e '^Binary files .* differ$' "${patch}"; then
+        tool=(env GIT_CEILING_DIRECTORIES="${PWD}" git apply)
+        check=--check
+    fi
+    if [[ $# -gt 0 ]]; then
+        "${tool[@]}" "$@" < "${patch}" >&2 || die "eapply: ${patch##*/} does not apply"
+        return 0
+    fi
+    for level in 1 0 2; do
+        if "${tool[@]}" -p${level} ${check} < "${patch}" >/dev/null 2>&1; then
+            "${tool[@]}" -p${level} < "${patch}" >/dev/null || die "eapply: failed to apply ${patch##*/}"
+            return 0
+        fi
+    done
+    # Show the hunks that failed
+    "${tool[@]}" -p1 ${check} < "${patch}" >&2 || true
+    die "eapply: ${patch##*/} does not apply"
+}
+
+# eapply [patch options] [--] patch|dir...: a directory applies its *.diff and *.patch files
+eapply() {
+    local options=() arg path list
+    while [[ $# -gt 0 && $1 == -* ]]; do
+        if [[ $1 == -- ]]; then
+            shift
+            break
+        fi
+        options+=("$1")
+        shift
+    done
+    [[ $# -gt 0 ]] || die "eapply: no patches given"
+    for arg in "$@"; do
+        if [[ -d ${arg} ]]; then
+            mapfile -d '' list < <(find "${arg}" -maxdepth 1 -type f \( -name '*.diff' -o -name '*.patch' \) -print0 | sort -z)
+            [[ ${#list[@]} -gt 0 ]] || die "eapply: no patches in ${arg}"
+            for path in "${list[@]}"; do
+                __eapply_file "${path}" "${options[@]}"
+            done
+        elif [[ -f ${arg} ]]; then
+            __eapply_file "${arg}" "${options[@]}"
+        else
+            die "eapply: ${arg} does not exist"
+        fi
+    done
+}
+
+eapply_user() {
+    local patch
+    if [[ -e ${T}/.user_patches_applied ]]; then
+        return 0
+    fi
+    if [[ ${#PORTAGE_USER_PATCHES[@]} -gt 0 ]]; then
+        for patch in "${PORTAGE_USER_PATCHES[@]}"; do
+            __eapply_file "${patch}"
+        done
+        einfo "User patches applied."
+    fi
+    touch "${T}/.user_patches_applied"
+}
+
+default_src_prepare() {
+    if [[ $(declare -p PATCHES 2>/dev/null) == "declare -a"* ]]; then
+        if [[ ${#PATCHES[@]} -gt 0 ]]; then
+            eapply "${PATCHES[@]}"
+        fi
+    elif [[ -n ${PATCHES} ]]; then
+        eapply ${PATCHES}
+    fi
+    eapply_user
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const PATCH_P1: &str = "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-hello\n+hello, world\n";
+
+    #[test]
+    fn test_find_patches() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().to_str().unwrap();
+        let dirs = patch_dirs(root, "app-misc", "foo", "1.0-r2", Some("0/1.0"));
+        let names: Vec<String> = dirs.iter().map(|dir| dir.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["foo-1.0-r2:0", "foo-1.0-r2", "foo-1.0:0", "foo-1.0", "foo:0", "foo"]);
+        assert!(patch_dirs(root, "app-misc", "foo", "1.0", None)[0].ends_with("app-misc/foo-1.0-r0"));
+
+        let base = temp.path().join("etc/portage/patches/app-misc");
+        for file in ["foo-1.0/20-fix.patch", "foo/20-fix.patch", "foo/10-first.diff", "foo/README", "foo:0/30-slot.patch"] {
+            std::fs::create_dir_all(base.join(file).parent().unwrap()).unwrap();
+            std::fs::write(base.join(file), file).unwrap();
+        }
+        let patches = find(&dirs);
+        let found: Vec<&Path> = patches.iter().map(|patch| patch.strip_prefix(&base).unwrap()).collect();
+        assert_eq!(found, [Path::new("foo/10-first.diff"), Path::new("foo-1.0/20-fix.patch"), Path::new("foo:0/30-slot.patch")]);
+    }
+
+    #[test]
+    fn test_apply_patches() {
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("hello.txt"), "hello\n").unwrap();
+        let p1 = temp.path().join("01-p1.patch");
+        std::fs::write(&p1, PATCH_P1).unwrap();
+        let p0 = temp.path().join("02-p0.patch");
+        std::fs::write(&p0, "--- hello.txt\n+++ hello.txt\n@@ -1 +1 @@\n-hello, world\n+goodbye\n").unwrap();
+
+        apply_all(&[p1.clone(), p0], &source).unwrap();
+        assert_eq!(std::fs::read_to_string(source.join("hello.txt")).unwrap(), "goodbye\n");
+        let error = apply_all(std::slice::from_ref(&p1), &source).unwrap_err();
+        assert!(error.value.contains("01-p1.patch does not apply"), "{}", error);
+
+        // eapply_user in an ebuild applies them once
+        std::fs::write(source.join("hello.txt"), "hello\n").unwrap();
+        let script = format!("set -e\nT='{0}'\ndie() {{ echo \"die: $*\" >&2; exit 1; }}\neinfo() {{ echo \" * $*\" >&2; }}\n{1}{2}cd '{3}'\neapply_user\neapply_user\n",
+            temp.path().display(), BASH_FUNCTIONS, bash_array(std::slice::from_ref(&p1)), source.display());
+        let output = std::process::Command::new("bash").arg("-c").arg(script).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(" * Applying 01-p1.patch ..."));
+        assert_eq!(std::fs::read_to_string(source.join("hello.txt")).unwrap(), "hello, world\n");
+        assert!(temp.path().join(APPLIED_MARKER).exists());
+
+        // eapply shows the hunks of a patch that does not apply
+        let script = format!("T='{0}'\ndie() {{ echo \"die: $*\" >&2; exit 1; }}\neinfo() {{ :; }}\n{1}cd '{2}'\neapply '{3}'\n",
+            temp.path().display(), BASH_FUNCTIONS, source.display(), p1.display());
+        let output = std::process::Command::new("bash").arg("-c").arg(script).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.contains("FAILED") && stderr.contains("die: eapply: 01-p1.patch does not apply"), "{}", stderr);
+    }
+}
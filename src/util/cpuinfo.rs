@@ -10,6 +10,11 @@ pub fn get_cpu_count() -> Option<usize> {
     std::thread::available_parallelism().map(|n| n.get()).ok()
 }
 
+/// Whether MAKEOPTS/NINJAOPTS already carry a load average limit
+pub fn has_load_limit(opts: &str) -> bool {
+    opts.split_whitespace().any(|opt| opt.starts_with("-l") || opt.starts_with("--load-average"))
+}
+
 pub fn makeopts_to_job_count(makeopts: &str) -> Option<usize> {
     if makeopts.is_empty() {
         return get_cpu_count();
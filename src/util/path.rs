@@ -21,4 +21,16 @@ pub fn iter_parents(path: &Path) -> impl Iterator<Item = &Path> {
             None
         }
     })
-}
\ No newline at end of file
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`, or that would
+/// hold it once created
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = std::ffi::CString::new(first_existing(path).as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(existing.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
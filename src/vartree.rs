@@ -1,14 +1,41 @@
 // vartree.rs -- Installed package database (/var/db/pkg)
 
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::fs;
 use std::path::Path;
+use crate::contents::{parse_contents, ContentsEntry};
 use crate::exception::InvalidData;
 
 #[derive(Debug)]
 pub struct VarTree {
     pub root: String,
     pub dbpath: String,
+    /// Installed packages by category/package, read on first use
+    index: Mutex<Option<HashMap<String, Vec<InstalledPkg>>>>,
+    /// Parsed CONTENTS by cpv
+    contents_cache: Mutex<HashMap<String, Arc<Vec<ContentsEntry>>>>,
+}
+
+/// What the index keeps of an installed package
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledPkg {
+    pub cpv: String,
+    /// SLOT, including any "/subslot"; None when the entry has none
+    pub slot: Option<String>,
+    pub use_flags: Vec<String>,
+}
+
+impl InstalledPkg {
+    fn read(dbpath: &Path, cpv: &str) -> Self {
+        let dir = dbpath.join(cpv);
+        let read = |key: &str| std::fs::read_to_string(dir.join(key)).map(|s| s.trim().to_string());
+        InstalledPkg {
+            cpv: cpv.to_string(),
+            slot: read("SLOT").ok(),
+            use_flags: read("USE").map(|s| s.split_whitespace().map(|f| f.to_string()).collect()).unwrap_or_default(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -24,40 +51,61 @@ impl VarTree {
         VarTree {
             root: root.to_string(),
             dbpath: format!("{}/var/db/pkg", root),
+            index: Mutex::new(None),
+            contents_cache: Mutex::new(HashMap::new()),
         }
     }
 
-    pub async fn get_all_installed(&self) -> Result<Vec<String>, InvalidData> {
-        let path = Path::new(&self.dbpath);
-        if !path.exists() {
-            return Ok(vec![]);
-        }
-        let mut cpvs = vec![];
-
-        // Read category directories
-        let mut category_entries = fs::read_dir(path).await.map_err(|e| InvalidData::new(&format!("Failed to read db: {}", e), None))?;
-        while let Some(category_entry) = category_entries.next_entry().await.map_err(|e| InvalidData::new(&format!("Failed to read category entry: {}", e), None))? {
-            let category_path = category_entry.path();
-            let metadata = fs::metadata(&category_path).await.map_err(|e| InvalidData::new(&format!("Failed to read metadata: {}", e), None))?;
-            if metadata.is_dir() {
-                // Read package-version directories within each category
-                let mut pkg_entries = fs::read_dir(&category_path).await.map_err(|e| InvalidData::new(&format!("Failed to read category {}: {}", category_path.display(), e), None))?;
-                while let Some(pkg_entry) = pkg_entries.next_entry().await.map_err(|e| InvalidData::new(&format!("Failed to read package entry: {}", e), None))? {
-                    let pkg_path = pkg_entry.path();
-                    let pkg_metadata = fs::metadata(&pkg_path).await.map_err(|e| InvalidData::new(&format!("Failed to read pkg metadata: {}", e), None))?;
-                    if pkg_metadata.is_dir() {
-                        if let Some(name) = pkg_path.file_name().and_then(|n| n.to_str()) {
-                            // name is like "package-version", we need to prepend "category-"
-                            if let Some(category_name) = category_path.file_name().and_then(|n| n.to_str()) {
-                                let cpv = format!("{}-{}", category_name, name);
-                                cpvs.push(cpv);
-                            }
-                        }
-                    }
-                }
+    /// The index, walking the package database the first time
+    fn index(&self) -> MutexGuard<'_, Option<HashMap<String, Vec<InstalledPkg>>>> {
+        let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if index.is_none() {
+            let dbpath = Path::new(&self.dbpath);
+            let mut packages: HashMap<String, Vec<InstalledPkg>> = HashMap::new();
+            for cpv in self.scan_packages() {
+                let cp = crate::versions::cpv_getkey(&cpv).unwrap_or_else(|| cpv.clone());
+                packages.entry(cp).or_default().push(InstalledPkg::read(dbpath, &cpv));
             }
+            for versions in packages.values_mut() {
+                versions.sort_by(|a, b| a.cpv.cmp(&b.cpv));
+            }
+            *index = Some(packages);
         }
-        Ok(cpvs)
+        index
+    }
+
+    /// Re-read the database entry of `cpv` after it was merged or unmerged
+    pub fn invalidate(&self, cpv: &str) {
+        self.contents_cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(cpv);
+        let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(packages) = index.as_mut() else {
+            return;
+        };
+        let cp = crate::versions::cpv_getkey(cpv).unwrap_or_else(|| cpv.to_string());
+        let versions = packages.entry(cp.clone()).or_default();
+        versions.retain(|pkg| pkg.cpv != cpv);
+        if Path::new(&self.dbpath).join(cpv).is_dir() {
+            versions.push(InstalledPkg::read(Path::new(&self.dbpath), cpv));
+            versions.sort_by(|a, b| a.cpv.cmp(&b.cpv));
+        }
+        if versions.is_empty() {
+            packages.remove(&cp);
+        }
+    }
+
+    /// Drop everything cached, so the next query walks the database again
+    pub fn invalidate_all(&self) {
+        *self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+        self.contents_cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+    }
+
+    pub async fn get_all_installed(&self) -> Result<Vec<String>, InvalidData> {
+        Ok(self.list_packages())
+    }
+
+    /// Installed versions of a category/package with their SLOT and USE
+    pub fn installed_packages(&self, cp: &str) -> Vec<InstalledPkg> {
+        self.index().as_ref().and_then(|packages| packages.get(cp).cloned()).unwrap_or_default()
     }
 
     pub async fn get_pkg_info(&self, cpv: &str) -> Result<Option<VarPkg>, InvalidData> {
@@ -78,13 +126,7 @@ impl VarTree {
             vec![]
         };
 
-        let environment_path = pkg_path.join("environment.bz2");
-        let environment = if environment_path.exists() {
-            // Placeholder: decompress and parse
-            HashMap::new()
-        } else {
-            HashMap::new()
-        };
+        let environment = self.environment(cpv).map(|saved| parse_environment(&saved)).unwrap_or_default();
 
         let slot = fs::read_to_string(pkg_path.join("SLOT"))
             .await
@@ -100,7 +142,358 @@ impl VarTree {
         }))
     }
 
+    /// Installed packages as category/package-version, sorted
+    pub fn list_packages(&self) -> Vec<String> {
+        let mut cpvs: Vec<String> = self.index().iter()
+            .flat_map(|packages| packages.values().flatten().map(|pkg| pkg.cpv.clone()))
+            .collect();
+        cpvs.sort();
+        cpvs
+    }
+
+    fn scan_packages(&self) -> Vec<String> {
+        let mut cpvs = Vec::new();
+        let Ok(categories) = std::fs::read_dir(&self.dbpath) else {
+            return cpvs;
+        };
+        for category in categories.flatten().filter(|e| e.path().is_dir()) {
+            let Ok(packages) = std::fs::read_dir(category.path()) else {
+                continue;
+            };
+            // Entries a merge is still writing are not installed yet
+            for package in packages.flatten().filter(|e| e.path().is_dir() && !e.file_name().to_string_lossy().starts_with(crate::vdb::MERGING_PREFIX)) {
+                cpvs.push(format!("{}/{}", category.file_name().to_string_lossy(), package.file_name().to_string_lossy()));
+            }
+        }
+        cpvs
+    }
+
+    /// Parsed CONTENTS of an installed package
+    pub fn contents(&self, cpv: &str) -> Vec<ContentsEntry> {
+        self.cached_contents(cpv).to_vec()
+    }
+
+    fn cached_contents(&self, cpv: &str) -> Arc<Vec<ContentsEntry>> {
+        let mut cache = self.contents_cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        cache.entry(cpv.to_string())
+            .or_insert_with(|| Arc::new(std::fs::read_to_string(Path::new(&self.dbpath).join(cpv).join("CONTENTS"))
+                .map(|content| parse_contents(&content))
+                .unwrap_or_default()))
+            .clone()
+    }
+
+    /// Map of every recorded file and symlink to the packages owning it
+    pub fn file_owners(&self) -> HashMap<String, Vec<String>> {
+        self.owner_index(false)
+    }
+
+    fn owner_index(&self, include_dirs: bool) -> HashMap<String, Vec<String>> {
+        let mut owners: HashMap<String, Vec<String>> = HashMap::new();
+        for cpv in self.list_packages() {
+            for entry in self.cached_contents(&cpv).iter() {
+                if include_dirs || !matches!(entry, ContentsEntry::Dir { .. }) {
+                    owners.entry(entry.path().to_string()).or_default().push(cpv.clone());
+                }
+            }
+        }
+        owners
+    }
+
+    /// Installed paths matching any of the patterns, with their owners, sorted by path.
+    /// Patterns may use * ? [..] globs; without a leading '/' they match the file name.
+    pub fn find_owners(&self, patterns: &[String]) -> Result<Vec<(String, Vec<String>)>, InvalidData> {
+        let index = self.owner_index(true);
+        let mut found: Vec<(String, Vec<String>)> = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.starts_with('/') && !pattern.contains(['*', '?', '[']) {
+                if let Some(owners) = index.get(pattern) {
+                    found.push((pattern.to_string(), owners.clone()));
+                }
+                continue;
+            }
+            let matcher = glob_to_regex(pattern)?;
+            for (path, owners) in &index {
+                let subject = if pattern.starts_with('/') { path.as_str() } else { path.rsplit('/').next().unwrap_or(path) };
+                if matcher.is_match(subject) {
+                    found.push((path.clone(), owners.clone()));
+                }
+            }
+        }
+        found.sort();
+        found.dedup();
+        Ok(found)
+    }
+
+    /// Installed versions of a category/package
+    pub fn installed_versions(&self, cp: &str) -> Vec<String> {
+        self.installed_packages(cp).into_iter().map(|pkg| pkg.cpv).collect()
+    }
+
+    fn installed_pkg(&self, cpv: &str) -> Option<InstalledPkg> {
+        let cp = crate::versions::cpv_getkey(cpv)?;
+        self.installed_packages(&cp).into_iter().find(|pkg| pkg.cpv == cpv)
+    }
+
+    /// SLOT of an installed package, including any "/subslot"
+    pub fn slot(&self, cpv: &str) -> Option<String> {
+        self.installed_pkg(cpv).and_then(|pkg| pkg.slot)
+    }
+
+    /// SLOT, including any "/subslot", of the highest installed version of `cp` in `slot`,
+    /// or in any slot when `slot` is None
+    pub fn best_installed_slot(&self, cp: &str, slot: Option<&str>) -> Option<String> {
+        let version = |cpv: &str| crate::versions::cpv_getversion(cpv).unwrap_or_default();
+        self.installed_packages(cp).into_iter()
+            .filter_map(|pkg| Some((pkg.slot?, pkg.cpv)))
+            .filter(|(pkg_slot, _)| slot.is_none_or(|wanted| pkg_slot.split('/').next() == Some(wanted)))
+            .max_by(|(_, a), (_, b)| crate::versions::vercmp(&version(a), &version(b)).unwrap_or(0).cmp(&0))
+            .map(|(pkg_slot, _)| pkg_slot)
+    }
+
+    /// `:=` dependencies recorded at build time, bound to the slot/subslot built against
+    pub fn slot_operator_deps(&self, cpv: &str) -> Vec<crate::dep::Atom> {
+        let mut atoms = Vec::new();
+        for key in ["DEPEND", "RDEPEND"] {
+            let Ok(content) = std::fs::read_to_string(Path::new(&self.dbpath).join(cpv).join(key)) else {
+                continue;
+            };
+            for token in content.split_whitespace() {
+                if let Ok(atom) = crate::dep::Atom::new(token)
+                    && atom.blocker.is_none()
+                    && atom.slot_op.as_deref() == Some("=")
+                    && atom.sub_slot.is_some()
+                    && !atoms.iter().any(|a: &crate::dep::Atom| a.to_string() == atom.to_string())
+                {
+                    atoms.push(atom);
+                }
+            }
+        }
+        atoms
+    }
+
+    /// Installed packages whose `:=` dependencies were built against a different subslot
+    /// than the one that will be installed. `new_slots` holds the SLOT of packages about
+    /// to be upgraded; everything else is compared with what is installed now.
+    pub fn slot_operator_rebuilds(&self, new_slots: &HashMap<String, String>) -> Vec<(String, crate::dep::Atom)> {
+        let mut rebuilds = Vec::new();
+        for cpv in self.list_packages() {
+            let cp = crate::versions::cpv_getkey(&cpv).unwrap_or_default();
+            if new_slots.contains_key(&cp) {
+                continue;
+            }
+            for atom in self.slot_operator_deps(&cpv) {
+                let dep_cp = atom.cp();
+                let slot = match new_slots.get(&dep_cp) {
+                    Some(slot) => Some(slot.clone()),
+                    None => self.best_installed_slot(&dep_cp, atom.slot.as_deref()),
+                };
+                let Some(slot) = slot else {
+                    continue;
+                };
+                let (slot, subslot) = slot.split_once('/').unwrap_or((&slot, &slot));
+                if atom.slot.as_deref() == Some(slot) && atom.sub_slot.as_deref() != Some(subslot) {
+                    rebuilds.push((cpv.clone(), atom));
+                    break;
+                }
+            }
+        }
+        rebuilds
+    }
+
+    /// A metadata entry of an installed package (INHERITED, PROPERTIES, ...), trimmed
+    pub fn entry(&self, cpv: &str, key: &str) -> Option<String> {
+        std::fs::read_to_string(Path::new(&self.dbpath).join(cpv).join(key))
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+
+    /// IUSE of an installed package, without +/- default markers
+    pub fn iuse(&self, cpv: &str) -> Option<Vec<String>> {
+        std::fs::read_to_string(Path::new(&self.dbpath).join(cpv).join("IUSE"))
+            .ok()
+            .map(|s| s.split_whitespace().map(|f| f.trim_start_matches(['+', '-']).to_string()).collect())
+    }
+
+    /// USE flags an installed package was built with, from its USE entry
+    pub fn use_flags(&self, cpv: &str) -> Option<Vec<String>> {
+        self.installed_pkg(cpv).map(|pkg| pkg.use_flags)
+    }
+
     pub fn is_installed(&self, cpv: &str) -> bool {
-        Path::new(&self.dbpath).join(cpv).exists()
+        self.installed_pkg(cpv).is_some()
+    }
+
+    /// The build environment saved in the environment.bz2 of an installed package
+    pub fn environment(&self, cpv: &str) -> Option<String> {
+        let file = std::fs::File::open(Path::new(&self.dbpath).join(cpv).join("environment.bz2")).ok()?;
+        let output = std::process::Command::new("bzip2").arg("-dc").stdin(file).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Compress the environment a build saved at `source` into the environment.bz2 `target`
+pub fn compress_environment(source: &Path, target: &Path) -> Result<(), InvalidData> {
+    let input = std::fs::File::open(source)
+        .map_err(|e| InvalidData::new(&format!("Failed to read environment {}: {}", source.display(), e), None))?;
+    let output = std::fs::File::create(target)
+        .map_err(|e| InvalidData::new(&format!("Failed to write {}: {}", target.display(), e), None))?;
+    let status = std::process::Command::new("bzip2").arg("-c").stdin(input).stdout(output).status()
+        .map_err(|e| InvalidData::new(&format!("Failed to run bzip2: {}", e), None))?;
+    if !status.success() {
+        return Err(InvalidData::new(&format!("bzip2 failed to compress {}", source.display()), None));
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// The plain variables of a saved environment: its `declare NAME="value"` lines
+pub fn parse_environment(environment: &str) -> HashMap<String, String> {
+    environment.lines()
+        .filter_map(|line| line.strip_prefix("declare "))
+        .filter_map(|declaration| {
+            let (flags, assignment) = declaration.split_once(' ')?;
+            if flags.contains('a') || flags.contains('A') {
+                return None;
+            }
+            let (name, value) = assignment.split_once('=')?;
+            let value = value.strip_prefix('"')?.strip_suffix('"')?;
+            let mut unescaped = String::new();
+            let mut chars = value.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unescaped.extend(chars.next()),
+                    c => unescaped.push(c),
+                }
+            }
+            Some((name.to_string(), unescaped))
+        })
+        .collect()
+}
+
+/// Translate a shell glob into an anchored regex; wildcards don't cross '/'
+fn glob_to_regex(pattern: &str) -> Result<regex::Regex, InvalidData> {
+    let mut expr = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => expr.push_str("[^/]*"),
+            '?' => expr.push_str("[^/]"),
+            '[' => {
+                expr.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    expr.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '^' {
+                        expr.push('\\');
+                    }
+                    expr.push(c);
+                }
+                expr.push(']');
+            }
+            _ => expr.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expr.push('$');
+    regex::Regex::new(&expr).map_err(|e| InvalidData::new(&format!("Invalid pattern '{}': {}", pattern, e), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_slot_operator_rebuilds() {
+        let root = TempDir::new().unwrap();
+        let vartree = VarTree::new(root.path().to_str().unwrap());
+        for (cpv, files) in [
+            ("dev-libs/openssl-3.0.13", vec![("SLOT", "0/3\n")]),
+            ("dev-libs/openssl-3.0.9", vec![("SLOT", "0/3.0.9\n")]),
+            ("dev-libs/openssl-1.1.1w", vec![("SLOT", "1.1/1.1\n")]),
+            ("net-misc/curl-8.5.0", vec![("SLOT", "0\n"), ("RDEPEND", "dev-libs/openssl:0/3= sys-libs/zlib\n")]),
+            ("app-misc/old-1.0", vec![("SLOT", "0\n"), ("RDEPEND", "dev-libs/openssl:0/1.1=\n")]),
+        ] {
+            let dir = Path::new(&vartree.dbpath).join(cpv);
+            std::fs::create_dir_all(&dir).unwrap();
+            for (name, content) in files {
+                std::fs::write(dir.join(name), content).unwrap();
+            }
+        }
+
+        let rebuilds = vartree.slot_operator_rebuilds(&HashMap::new());
+        assert_eq!(rebuilds.len(), 1);
+        assert_eq!(rebuilds[0].0, "app-misc/old-1.0");
+        assert_eq!(rebuilds[0].1.to_string(), "dev-libs/openssl:0/1.1=");
+        // The highest version in the atom's slot counts, not the last one listed
+        assert_eq!(vartree.best_installed_slot("dev-libs/openssl", Some("0")).as_deref(), Some("0/3"));
+        assert_eq!(vartree.best_installed_slot("dev-libs/openssl", Some("1.1")).as_deref(), Some("1.1/1.1"));
+        assert_eq!(vartree.best_installed_slot("dev-libs/openssl", None).as_deref(), Some("0/3"));
+        assert_eq!(vartree.best_installed_slot("dev-libs/openssl", Some("2")), None);
+
+        let upgrade = HashMap::from([("dev-libs/openssl".to_string(), "0/3.1".to_string())]);
+        let rebuilt: Vec<String> = vartree.slot_operator_rebuilds(&upgrade).into_iter().map(|(cpv, _)| cpv).collect();
+        assert_eq!(rebuilt, vec!["app-misc/old-1.0", "net-misc/curl-8.5.0"]);
+    }
+
+    #[test]
+    fn test_index_invalidation() {
+        let root = TempDir::new().unwrap();
+        let vartree = VarTree::new(root.path().to_str().unwrap());
+        let install = |cpv: &str, slot: &str, use_flags: &str| {
+            let dir = Path::new(&vartree.dbpath).join(cpv);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("SLOT"), slot).unwrap();
+            std::fs::write(dir.join("USE"), use_flags).unwrap();
+        };
+        install("dev-lang/python-3.11.9", "3.11\n", "ssl sqlite\n");
+        install("dev-lang/python-3.12.4", "3.12\n", "ssl\n");
+
+        assert_eq!(vartree.installed_packages("dev-lang/python"), vec![
+            InstalledPkg { cpv: "dev-lang/python-3.11.9".to_string(), slot: Some("3.11".to_string()), use_flags: vec!["ssl".to_string(), "sqlite".to_string()] },
+            InstalledPkg { cpv: "dev-lang/python-3.12.4".to_string(), slot: Some("3.12".to_string()), use_flags: vec!["ssl".to_string()] },
+        ]);
+
+        // Served from the index until told otherwise
+        std::fs::remove_dir_all(Path::new(&vartree.dbpath).join("dev-lang/python-3.11.9")).unwrap();
+        install("app-misc/foo-1.0", "0\n", "");
+        assert!(vartree.is_installed("dev-lang/python-3.11.9"));
+        vartree.invalidate("dev-lang/python-3.11.9");
+        vartree.invalidate("app-misc/foo-1.0");
+        assert_eq!(vartree.list_packages(), vec!["app-misc/foo-1.0", "dev-lang/python-3.12.4"]);
+        assert_eq!(vartree.slot("app-misc/foo-1.0").as_deref(), Some("0"));
+
+        std::fs::remove_dir_all(Path::new(&vartree.dbpath).join("app-misc")).unwrap();
+        vartree.invalidate_all();
+        assert_eq!(vartree.installed_versions("dev-lang/python"), vec!["dev-lang/python-3.12.4"]);
+        assert!(vartree.installed_packages("app-misc/foo").is_empty());
+    }
+
+    #[test]
+    fn test_find_owners() {
+        let root = TempDir::new().unwrap();
+        let vartree = VarTree::new(root.path().to_str().unwrap());
+        for (cpv, contents) in [
+            ("app-editors/vim-9.0", "dir /usr/bin\nobj /usr/bin/vim d41d8cd98f00b204e9800998ecf8427e 1\nsym /usr/bin/vi -> vim 1\n"),
+            ("app-shells/bash-5.2", "dir /usr/bin\nobj /usr/bin/bash d41d8cd98f00b204e9800998ecf8427e 1\n"),
+        ] {
+            std::fs::create_dir_all(Path::new(&vartree.dbpath).join(cpv)).unwrap();
+            std::fs::write(Path::new(&vartree.dbpath).join(cpv).join("CONTENTS"), contents).unwrap();
+        }
+
+        let owners = vartree.find_owners(&["/usr/bin/vim".to_string(), "ba?h".to_string()]).unwrap();
+        assert_eq!(owners, vec![
+            ("/usr/bin/bash".to_string(), vec!["app-shells/bash-5.2".to_string()]),
+            ("/usr/bin/vim".to_string(), vec!["app-editors/vim-9.0".to_string()]),
+        ]);
+        let owners = vartree.find_owners(&["/usr/bin/v[!x]*".to_string()]).unwrap();
+        assert_eq!(owners.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(), vec!["/usr/bin/vi", "/usr/bin/vim"]);
+        let owners = vartree.find_owners(&["/usr/bin/".to_string()]).unwrap();
+        assert_eq!(owners[0].1, vec!["app-editors/vim-9.0".to_string(), "app-shells/bash-5.2".to_string()]);
+        assert!(vartree.find_owners(&["/usr/*/nothing".to_string()]).unwrap().is_empty());
+    }
+}
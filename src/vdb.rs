@@ -0,0 +1,167 @@
+// vdb.rs -- transactional writes of package database entries: a new entry is written
+// aside as <category>/-MERGING-<pf> and swapped in only once its files are merged
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::exception::InvalidData;
+use crate::locks::{dir_lock_path, LockFile};
+use crate::versions::Cpv;
+
+/// Prefix of entries still being written, as Portage names them
+pub const MERGING_PREFIX: &str = "-MERGING-";
+
+/// Where the entry of `cpv` is written before it replaces the installed one
+pub fn merging_path(dbpath: &Path, cpv: &str) -> Result<PathBuf, InvalidData> {
+    let pkg = Cpv::parse(cpv)?;
+    Ok(dbpath.join(pkg.category().to_string()).join(format!("{}{}", MERGING_PREFIX, pkg.pf())))
+}
+
+/// Swap two paths in one step with renameat2(RENAME_EXCHANGE)
+fn exchange(a: &Path, b: &Path) -> std::io::Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let result = unsafe { libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE) };
+    if result == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
+}
+
+/// A package database entry being written. Until `commit`, the installed entry of the
+/// same version, if any, stays as it was; dropping it uncommitted throws the new one away
+#[derive(Debug)]
+pub struct PendingEntry {
+    staging: PathBuf,
+    target: PathBuf,
+}
+
+impl PendingEntry {
+    /// Start the entry of `cpv`, clearing what an earlier attempt left
+    pub fn begin(dbpath: &Path, cpv: &str) -> Result<Self, InvalidData> {
+        let staging = merging_path(dbpath, cpv)?;
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)
+                .map_err(|e| InvalidData::new(&format!("Failed to remove {}: {}", staging.display(), e), None))?;
+        }
+        std::fs::create_dir_all(&staging)
+            .map_err(|e| InvalidData::new(&format!("Failed to create package directory: {}", e), None))?;
+        Ok(PendingEntry { staging, target: dbpath.join(cpv) })
+    }
+
+    /// The directory to write the entry's files into
+    pub fn path(&self) -> &Path {
+        &self.staging
+    }
+
+    /// Put the entry in place of the installed one in a single rename, then remove the old
+    pub fn commit(self) -> Result<(), InvalidData> {
+        let failed = |e: std::io::Error| InvalidData::new(&format!("Failed to move {} into place: {}", self.target.display(), e), None);
+        if !self.target.exists() {
+            std::fs::rename(&self.staging, &self.target).map_err(failed)?;
+        } else if exchange(&self.staging, &self.target).is_err() {
+            // Filesystems without RENAME_EXCHANGE: the old entry goes first
+            std::fs::remove_dir_all(&self.target).map_err(failed)?;
+            std::fs::rename(&self.staging, &self.target).map_err(failed)?;
+        }
+        // After an exchange the old entry is what the staging path holds; Drop removes it
+        Ok(())
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        if self.staging.exists() {
+            let _ = std::fs::remove_dir_all(&self.staging);
+        }
+    }
+}
+
+/// Entries left half-written under `dbpath` by merges that died, as category/name
+pub fn find_incomplete(dbpath: &Path) -> Vec<String> {
+    let mut incomplete = Vec::new();
+    let Ok(categories) = std::fs::read_dir(dbpath) else {
+        return incomplete;
+    };
+    for category in categories.flatten().filter(|e| e.path().is_dir()) {
+        let Ok(entries) = std::fs::read_dir(category.path()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(MERGING_PREFIX) && entry.path().is_dir() {
+                incomplete.push(format!("{}/{}", category.file_name().to_string_lossy(), name));
+            }
+        }
+    }
+    incomplete.sort();
+    incomplete
+}
+
+/// Remove the incomplete entries of the package database of `root`, returning them. A
+/// merge running elsewhere holds the database lock and may still be writing its entry,
+/// so nothing is removed while the lock is taken
+pub fn recover(root: &str) -> Result<Vec<String>, InvalidData> {
+    let dbpath = Path::new(root).join("var/db/pkg");
+    if find_incomplete(&dbpath).is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(_lock) = LockFile::try_acquire(&dir_lock_path(&dbpath))? else {
+        return Ok(Vec::new());
+    };
+    // Listed again under the lock: a merge may have finished in between
+    let incomplete = find_incomplete(&dbpath);
+    for entry in &incomplete {
+        std::fs::remove_dir_all(dbpath.join(entry))
+            .map_err(|e| InvalidData::new(&format!("Failed to remove {}: {}", entry, e), None))?;
+    }
+    Ok(incomplete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pending_entry() {
+        let temp = TempDir::new().unwrap();
+        let dbpath = temp.path().join("var/db/pkg");
+        let installed = dbpath.join("app-misc/foo-1.0");
+        std::fs::create_dir_all(&installed).unwrap();
+        std::fs::write(installed.join("CONTENTS"), "old\n").unwrap();
+
+        // Until the commit the installed entry is untouched
+        let entry = PendingEntry::begin(&dbpath, "app-misc/foo-1.0").unwrap();
+        assert!(entry.path().ends_with("app-misc/-MERGING-foo-1.0"));
+        std::fs::write(entry.path().join("CONTENTS"), "new\n").unwrap();
+        assert_eq!(std::fs::read_to_string(installed.join("CONTENTS")).unwrap(), "old\n");
+        entry.commit().unwrap();
+        assert_eq!(std::fs::read_to_string(installed.join("CONTENTS")).unwrap(), "new\n");
+        assert!(find_incomplete(&dbpath).is_empty());
+
+        // An entry dropped before its commit leaves nothing behind
+        let entry = PendingEntry::begin(&dbpath, "app-misc/bar-2.0").unwrap();
+        std::fs::write(entry.path().join("SLOT"), "0\n").unwrap();
+        drop(entry);
+        assert!(!dbpath.join("app-misc/bar-2.0").exists());
+        assert!(find_incomplete(&dbpath).is_empty());
+    }
+
+    #[test]
+    fn test_recover() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().to_string_lossy().to_string();
+        let dbpath = temp.path().join("var/db/pkg");
+        for dir in ["app-misc/foo-1.0", "app-misc/-MERGING-foo-1.0", "dev-libs/-MERGING-bar-2.0"] {
+            std::fs::create_dir_all(dbpath.join(dir)).unwrap();
+        }
+        assert_eq!(recover(&root).unwrap(), ["app-misc/-MERGING-foo-1.0", "dev-libs/-MERGING-bar-2.0"]);
+        assert!(dbpath.join("app-misc/foo-1.0").is_dir());
+        assert!(find_incomplete(&dbpath).is_empty());
+
+        // Not while another merge holds the database lock
+        std::fs::create_dir_all(dbpath.join("app-misc/-MERGING-foo-1.1")).unwrap();
+        let _lock = LockFile::try_acquire(&dir_lock_path(&dbpath)).unwrap().unwrap();
+        assert!(recover(&root).unwrap().is_empty());
+        assert!(dbpath.join("app-misc/-MERGING-foo-1.1").is_dir());
+    }
+}
@@ -244,6 +244,131 @@ impl PkgStr {
     }
 }
 
+/// A package category such as "app-misc"
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Category(String);
+
+impl Category {
+    pub fn new(category: &str) -> Result<Self, InvalidData> {
+        if !CAT_RE.is_match(category) {
+            return Err(InvalidData::new(&format!("Invalid category: {}", category), None));
+        }
+        Ok(Category(category.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A package name without its version, such as "foo-bar"
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PackageName(String);
+
+impl PackageName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for PackageName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A category/package-version, split with catpkgsplit so names containing '-' and
+/// -rN revisions come apart correctly
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cpv {
+    category: Category,
+    package: PackageName,
+    /// PV without the revision
+    version: String,
+    /// The -rN suffix as given, None without one
+    revision: Option<String>,
+}
+
+impl Cpv {
+    pub fn parse(cpv: &str) -> Result<Self, InvalidData> {
+        if !cpv.contains('/') {
+            return Err(InvalidData::new(&format!("Invalid cpv, no category: {}", cpv), None));
+        }
+        let split = catpkgsplit(cpv).ok_or_else(|| InvalidData::new(&format!("Invalid cpv: {}", cpv), None))?;
+        let given_revision = split[3] != "r0" || cpv.ends_with("-r0");
+        Ok(Cpv {
+            category: Category(split[0].clone()),
+            package: PackageName(split[1].clone()),
+            version: split[2].clone(),
+            revision: given_revision.then(|| split[3].clone()),
+        })
+    }
+
+    /// The cpv of an ebuild at <repo>/<category>/<package>/<package>-<version>.ebuild
+    pub fn from_ebuild_path(path: &std::path::Path) -> Result<Self, InvalidData> {
+        let invalid = || InvalidData::new(&format!("Invalid ebuild path: {}", path.display()), None);
+        let pf = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".ebuild")).ok_or_else(invalid)?;
+        let package_dir = path.parent().ok_or_else(invalid)?;
+        let category = package_dir.parent().and_then(|c| c.file_name()).and_then(|c| c.to_str()).ok_or_else(invalid)?;
+        let cpv = Cpv::parse(&format!("{}/{}", category, pf))?;
+        if package_dir.file_name().and_then(|n| n.to_str()) != Some(cpv.package.as_str()) {
+            return Err(InvalidData::new(&format!("Ebuild {} is not in a directory named {}", path.display(), cpv.package), None));
+        }
+        Ok(cpv)
+    }
+
+    pub fn category(&self) -> &Category {
+        &self.category
+    }
+
+    pub fn package(&self) -> &PackageName {
+        &self.package
+    }
+
+    /// PV: the version without revision
+    pub fn pv(&self) -> &str {
+        &self.version
+    }
+
+    /// PVR: the version with any -rN given
+    pub fn pvr(&self) -> String {
+        match &self.revision {
+            Some(revision) => format!("{}-{}", self.version, revision),
+            None => self.version.clone(),
+        }
+    }
+
+    /// PF: package name and PVR
+    pub fn pf(&self) -> String {
+        format!("{}-{}", self.package, self.pvr())
+    }
+
+    /// category/package
+    pub fn cp(&self) -> String {
+        format!("{}/{}", self.category, self.package)
+    }
+}
+
+impl std::str::FromStr for Cpv {
+    type Err = InvalidData;
+
+    fn from_str(cpv: &str) -> Result<Self, Self::Err> {
+        Cpv::parse(cpv)
+    }
+}
+
+impl std::fmt::Display for Cpv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.category, self.pf())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -269,4 +394,23 @@ mod tests {
         assert!(!ververify(""));
         assert!(!ververify("invalid"));
     }
+
+    #[test]
+    fn test_cpv() {
+        let cpv = Cpv::parse("app-misc/foo-bar-1.2-r1").unwrap();
+        assert_eq!((cpv.category().as_str(), cpv.package().as_str()), ("app-misc", "foo-bar"));
+        assert_eq!((cpv.pv(), cpv.pvr().as_str(), cpv.pf().as_str()), ("1.2", "1.2-r1", "foo-bar-1.2-r1"));
+        assert_eq!(cpv.cp(), "app-misc/foo-bar");
+        assert_eq!(cpv.to_string(), "app-misc/foo-bar-1.2-r1");
+
+        let cpv: Cpv = "dev-libs/libfoo2-2.0_rc1".parse().unwrap();
+        assert_eq!((cpv.package().as_str(), cpv.pvr().as_str()), ("libfoo2", "2.0_rc1"));
+        assert!(Cpv::parse("foo-bar-1.2").is_err());
+        assert!(Cpv::parse("app-misc/foo-bar").is_err());
+
+        let path = std::path::Path::new("/var/db/repos/gentoo/x11-libs/gtk-doc-am-1.33.2-r1.ebuild");
+        assert!(Cpv::from_ebuild_path(path).is_err());
+        let path = std::path::Path::new("/var/db/repos/gentoo/dev-util/gtk-doc-am/gtk-doc-am-1.33.2-r1.ebuild");
+        assert_eq!(Cpv::from_ebuild_path(path).unwrap().to_string(), "dev-util/gtk-doc-am-1.33.2-r1");
+    }
 }
\ No newline at end of file
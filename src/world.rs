@@ -4,11 +4,13 @@ use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use crate::exception::InvalidData;
+use crate::atom::Atom;
 
 /// World file manager for handling the @world set
 pub struct WorldManager {
     root: String,
     world_file: PathBuf,
+    world_sets_file: PathBuf,
 }
 
 impl WorldManager {
@@ -17,6 +19,7 @@ impl WorldManager {
         let world_file = Path::new(root).join("var/lib/portage/world");
         WorldManager {
             root: root.to_string(),
+            world_sets_file: Path::new(root).join("var/lib/portage/world_sets"),
             world_file,
         }
     }
@@ -87,6 +90,98 @@ impl WorldManager {
         self.save(&atoms)
     }
 
+    /// Record explicitly requested atoms, returning the entries that were newly added
+    pub fn add_requested(&self, requested: &[String]) -> Result<Vec<String>, InvalidData> {
+        let mut atoms = self.load()?;
+        let mut added = Vec::new();
+        for atom in requested.iter().filter_map(|a| world_atom(a)) {
+            if atoms.insert(atom.clone()) {
+                added.push(atom);
+            }
+        }
+        if !added.is_empty() {
+            self.save(&atoms)?;
+        }
+        Ok(added)
+    }
+
+    /// Remove every entry for a package (any slot), returning the removed entries
+    pub fn remove_package(&self, cp: &str) -> Result<Vec<String>, InvalidData> {
+        let mut atoms = self.load()?;
+        let mut removed: Vec<String> = atoms.iter()
+            .filter(|entry| Atom::new(entry).map(|a| a.cp() == cp).unwrap_or(false))
+            .cloned()
+            .collect();
+        if !removed.is_empty() {
+            atoms.retain(|entry| !removed.contains(entry));
+            self.save(&atoms)?;
+        }
+        removed.sort();
+        Ok(removed)
+    }
+
+    /// Sets recorded in world_sets, as @name
+    pub fn load_sets(&self) -> Result<Vec<String>, InvalidData> {
+        if !self.world_sets_file.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.world_sets_file)
+            .map_err(|e| InvalidData::new(
+                &format!("Failed to read world_sets file: {}", e),
+                Some(self.world_sets_file.to_string_lossy().to_string())
+            ))?;
+        Ok(content.lines()
+            .map(|line| line.trim())
+            .filter(|line| line.starts_with('@'))
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    fn save_sets(&self, sets: &[String]) -> Result<(), InvalidData> {
+        if let Some(parent) = self.world_sets_file.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| InvalidData::new(
+                    &format!("Failed to create world file directory: {}", e),
+                    Some(parent.to_string_lossy().to_string())
+                ))?;
+        }
+        let content: String = sets.iter().map(|set| format!("{}\n", set)).collect();
+        fs::write(&self.world_sets_file, content)
+            .map_err(|e| InvalidData::new(
+                &format!("Failed to write world_sets file: {}", e),
+                Some(self.world_sets_file.to_string_lossy().to_string())
+            ))
+    }
+
+    /// Record installed sets (@name), returning the entries that were newly added
+    pub fn add_sets(&self, sets: &[String]) -> Result<Vec<String>, InvalidData> {
+        let mut recorded = self.load_sets()?;
+        let mut added = Vec::new();
+        for set in sets.iter().filter(|set| set.starts_with('@')) {
+            if !recorded.contains(set) {
+                recorded.push(set.clone());
+                added.push(set.clone());
+            }
+        }
+        if !added.is_empty() {
+            recorded.sort();
+            self.save_sets(&recorded)?;
+        }
+        Ok(added)
+    }
+
+    /// Drop a set (@name) from world_sets, returning whether it was recorded
+    pub fn remove_set(&self, set: &str) -> Result<bool, InvalidData> {
+        let mut recorded = self.load_sets()?;
+        let before = recorded.len();
+        recorded.retain(|entry| entry != set);
+        if recorded.len() == before {
+            return Ok(false);
+        }
+        self.save_sets(&recorded)?;
+        Ok(true)
+    }
+
     /// Check if an atom is in the world file
     pub fn contains(&self, atom: &str) -> Result<bool, InvalidData> {
         let atoms = self.load()?;
@@ -107,6 +202,19 @@ impl WorldManager {
     }
 }
 
+/// Convert a requested atom into its world file form (category/package[:slot]).
+/// Sets and unparsable atoms are not recorded.
+pub fn world_atom(requested: &str) -> Option<String> {
+    if requested.starts_with('@') {
+        return None;
+    }
+    let atom = Atom::new(requested).ok()?;
+    match &atom.slot {
+        Some(slot) => Some(format!("{}:{}", atom.cp(), slot)),
+        None => Some(atom.cp()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -145,4 +253,41 @@ mod tests {
         // Clean (should work without issues)
         manager.clean().unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_world_requested_atoms() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = WorldManager::new(temp_dir.path().to_str().unwrap());
+
+        assert_eq!(world_atom("=app-editors/vim-9.0"), Some("app-editors/vim".to_string()));
+        assert_eq!(world_atom("dev-lang/python:3.11"), Some("dev-lang/python:3.11".to_string()));
+        assert_eq!(world_atom("@system"), None);
+
+        let added = manager.add_requested(&[
+            "=app-editors/vim-9.0".to_string(),
+            "dev-lang/python:3.11".to_string(),
+            "@system".to_string(),
+        ]).unwrap();
+        assert_eq!(added.len(), 2);
+        assert!(manager.add_requested(&["app-editors/vim".to_string()]).unwrap().is_empty());
+
+        assert_eq!(manager.remove_package("dev-lang/python").unwrap(), vec!["dev-lang/python:3.11".to_string()]);
+        assert!(manager.remove_package("dev-lang/python").unwrap().is_empty());
+        assert!(manager.contains("app-editors/vim").unwrap());
+    }
+
+    #[test]
+    fn test_world_sets() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = WorldManager::new(temp_dir.path().to_str().unwrap());
+
+        assert!(manager.load_sets().unwrap().is_empty());
+        assert_eq!(manager.add_sets(&["@kde".to_string(), "app-misc/foo".to_string()]).unwrap(), vec!["@kde"]);
+        assert_eq!(manager.add_sets(&["@kde".to_string(), "@audio".to_string()]).unwrap(), vec!["@audio"]);
+        assert_eq!(fs::read_to_string(temp_dir.path().join("var/lib/portage/world_sets")).unwrap(), "@audio\n@kde\n");
+
+        assert!(manager.remove_set("@kde").unwrap());
+        assert!(!manager.remove_set("@kde").unwrap());
+        assert_eq!(manager.load_sets().unwrap(), vec!["@audio"]);
+    }
+}